@@ -7,18 +7,20 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::use_self)]
 
-use qlog::QlogStreamer;
-
-use neqo_common::{self as common, hex, qlog::NeqoQlog, Datagram, Role};
+use neqo_common::{hex, qlog::NeqoQlog, Datagram, Role};
 use neqo_crypto::{
     constants::{TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256},
     init, AuthenticationStatus, Cipher,
 };
 use neqo_http3::{self, Error, Header, Http3Client, Http3ClientEvent, Http3State, Output};
 use neqo_qpack::QpackSettings;
-use neqo_transport::{Connection, Error as TransportError, FixedConnectionIdManager, QuicVersion};
+use neqo_transport::{
+    Connection, ConnectionRace, Error as TransportError, FixedConnectionIdManager, QuicVersion,
+    CONNECTION_ATTEMPT_DELAY,
+};
 
 use std::cell::RefCell;
+use std::cmp::min;
 use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::fs::{File, OpenOptions};
@@ -27,8 +29,10 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket}
 use std::path::PathBuf;
 use std::process::exit;
 use std::rc::Rc;
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use neqo_udp::{RecvBuf, Socket};
 use structopt::StructOpt;
 use url::{Origin, Url};
 
@@ -66,6 +70,9 @@ impl From<neqo_transport::Error> for ClientError {
 
 type Res<T> = Result<T, ClientError>;
 
+/// How often sockets are checked while connection attempts are racing.
+const RACE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "neqo-client",
@@ -138,6 +145,22 @@ pub struct Args {
     /// The set of TLS cipher suites to enable.
     /// From: TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256.
     ciphers: Vec<String>,
+
+    #[structopt(name = "quic-version", long, possible_values = &["1", "2", "27", "28", "29"])]
+    /// The QUIC version to use: 1, 2, or a draft number.  By default, this
+    /// follows the ALPN label.
+    quic_version: Option<String>,
+
+    #[structopt(name = "key-log", long)]
+    /// Log TLS secrets to this file, in the format that Wireshark reads.
+    /// This needs NSS to be built with key logging enabled.
+    key_log: Option<PathBuf>,
+
+    #[structopt(name = "happy-eyeballs", long)]
+    /// Race connections to each address that the server name resolves to,
+    /// in the order they resolve, and use the first that the server answers.
+    /// This only works for HTTP/3.
+    happy_eyeballs: bool,
 }
 
 impl Args {
@@ -152,15 +175,25 @@ impl Args {
             })
             .collect::<Vec<_>>()
     }
-}
 
-fn emit_datagram(socket: &UdpSocket, d: Option<Datagram>) -> io::Result<()> {
-    if let Some(d) = d {
-        let sent = socket.send(&d[..])?;
-        if sent != d.len() {
-            eprintln!("Unable to send all {} bytes of datagram", d.len());
+    /// The QUIC version from `--quic-version`, or `alpn_version` if that isn't set.
+    fn get_quic_version(&self, alpn_version: QuicVersion) -> QuicVersion {
+        match self.quic_version.as_deref() {
+            Some("1") => QuicVersion::Version1,
+            Some("2") => QuicVersion::Version2,
+            Some("27") => QuicVersion::Draft27,
+            Some("28") => QuicVersion::Draft28,
+            Some("29") => QuicVersion::Draft29,
+            _ => alpn_version,
         }
     }
+}
+
+fn emit_datagrams(socket: &Socket, dgrams: &[Datagram]) -> io::Result<()> {
+    let mut sent = 0;
+    while sent < dgrams.len() {
+        sent += socket.send(&dgrams[sent..])?;
+    }
     Ok(())
 }
 
@@ -206,13 +239,12 @@ fn get_output_file(
 }
 
 fn process_loop(
-    local_addr: &SocketAddr,
-    remote_addr: &SocketAddr,
-    socket: &UdpSocket,
+    socket: &Socket,
     client: &mut Http3Client,
     handler: &mut Handler,
 ) -> Res<neqo_http3::Http3State> {
-    let buf = &mut [0u8; 2048];
+    let mut buf = RecvBuf::new();
+    let mut dgrams = Vec::new();
     loop {
         if let Http3State::Closed(..) = client.state() {
             return Ok(client.state());
@@ -223,45 +255,39 @@ fn process_loop(
         loop {
             let output = client.process_output(Instant::now());
             match output {
-                Output::Datagram(dgram) => {
-                    if let Err(e) = emit_datagram(&socket, Some(dgram)) {
-                        eprintln!("UDP write error: {}", e);
-                        client.close(Instant::now(), 0, e.to_string());
-                        exiting = true;
-                        break;
-                    }
-                }
+                Output::Datagram(dgram) => dgrams.push(dgram),
                 Output::Callback(duration) => {
-                    socket.set_read_timeout(Some(duration)).unwrap();
+                    socket.socket().set_read_timeout(Some(duration)).unwrap();
                     break;
                 }
                 Output::None => {
                     // Not strictly necessary, since we're about to exit
-                    socket.set_read_timeout(None).unwrap();
+                    socket.socket().set_read_timeout(None).unwrap();
                     exiting = true;
                     break;
                 }
             }
         }
+        if let Err(e) = emit_datagrams(socket, &dgrams) {
+            eprintln!("UDP write error: {}", e);
+            client.close(Instant::now(), 0, e.to_string());
+            exiting = true;
+        }
+        dgrams.clear();
 
         if exiting {
             return Ok(client.state());
         }
 
-        match socket.recv(&mut buf[..]) {
+        match socket.recv(&mut buf) {
             Err(ref err)
                 if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::Interrupted => {}
             Err(err) => {
                 eprintln!("UDP error: {}", err);
                 exit(1)
             }
-            Ok(sz) => {
-                if sz == buf.len() {
-                    eprintln!("Received more than {} bytes", buf.len());
-                    continue;
-                }
-                if sz > 0 {
-                    let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
+            Ok(received) => {
+                for d in received {
                     client.process_input(d, Instant::now());
                 }
             }
@@ -417,25 +443,24 @@ fn to_headers(values: &[impl AsRef<str>]) -> Vec<Header> {
         .collect()
 }
 
-fn client(
+fn new_transport(
     args: &Args,
-    socket: UdpSocket,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
     hostname: &str,
-    urls: &[Url],
-) -> Res<()> {
-    let quic_protocol = match args.alpn.as_str() {
+) -> Res<Connection> {
+    let quic_protocol = args.get_quic_version(match args.alpn.as_str() {
+        "h3" => QuicVersion::Version1,
         "h3-27" => QuicVersion::Draft27,
         "h3-28" => QuicVersion::Draft28,
         "h3-29" => QuicVersion::Draft29,
         _ => QuicVersion::default(),
-    };
+    });
 
     let mut transport = Connection::new_client(
         hostname,
         &[&args.alpn],
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(0)?)),
         local_addr,
         remote_addr,
         quic_protocol,
@@ -444,6 +469,28 @@ fn client(
     if !ciphers.is_empty() {
         transport.set_ciphers(&ciphers)?;
     }
+    Ok(transport)
+}
+
+fn client(
+    args: &Args,
+    socket: &Socket,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    hostname: &str,
+    urls: &[Url],
+) -> Res<()> {
+    let transport = new_transport(args, local_addr, remote_addr, hostname)?;
+    run_client(args, socket, transport, hostname, urls)
+}
+
+fn run_client(
+    args: &Args,
+    socket: &Socket,
+    transport: Connection,
+    hostname: &str,
+    urls: &[Url],
+) -> Res<()> {
     let mut client = Http3Client::new_with_conn(
         transport,
         QpackSettings {
@@ -461,43 +508,136 @@ fn client(
         args: &args,
     };
 
-    process_loop(&local_addr, &remote_addr, &socket, &mut client, &mut h)?;
+    process_loop(socket, &mut client, &mut h)?;
+    if let Some(peer_close) = client.conn().peer_close() {
+        println!("Server closed the connection: {}", peer_close);
+    }
 
     Ok(())
 }
 
+/// Race connections to each of `remote_addrs` and then fetch `urls` using
+/// the first one that the server answers.
+fn race_client(args: &Args, remote_addrs: &[SocketAddr], hostname: &str, urls: &[Url]) -> Res<()> {
+    let mut sockets = Vec::with_capacity(remote_addrs.len());
+    let mut attempts = Vec::with_capacity(remote_addrs.len());
+    for remote_addr in remote_addrs {
+        let socket = connected_socket(*remote_addr)?;
+        socket.socket().set_nonblocking(true)?;
+        attempts.push(new_transport(
+            args,
+            socket.local_addr(),
+            *remote_addr,
+            hostname,
+        )?);
+        sockets.push(socket);
+    }
+
+    let mut race = ConnectionRace::new(attempts, CONNECTION_ATTEMPT_DELAY);
+    let mut buf = RecvBuf::new();
+    let transport = loop {
+        let timeout = loop {
+            match race.process_output(Instant::now()) {
+                Output::Datagram(dgram) => {
+                    if let Some(socket) = sockets.iter().find(|s| s.local_addr() == dgram.source())
+                    {
+                        if let Err(e) = emit_datagrams(socket, &[dgram]) {
+                            eprintln!("UDP write error: {}", e);
+                        }
+                    }
+                }
+                Output::Callback(duration) => break duration,
+                Output::None => {
+                    eprintln!("Unable to connect to any address for {}", hostname);
+                    exit(1)
+                }
+            }
+        };
+        if let Some(transport) = race.take_winner() {
+            break transport;
+        }
+
+        let mut received = false;
+        for socket in &sockets {
+            match socket.recv(&mut buf) {
+                Err(ref err)
+                    if err.kind() == ErrorKind::WouldBlock
+                        || err.kind() == ErrorKind::Interrupted => {}
+                // An address that can't be reached can produce errors here,
+                // but the other attempts carry on.
+                Err(err) => eprintln!("UDP error: {}", err),
+                Ok(dgrams) => {
+                    for d in dgrams {
+                        race.process_input(d, Instant::now());
+                        received = true;
+                    }
+                }
+            }
+        }
+        if !received {
+            thread::sleep(min(timeout, RACE_POLL_INTERVAL));
+        }
+    };
+
+    let path = transport.path().expect("a client connection has a path");
+    let (local_addr, remote_addr) = (*path.local_address(), *path.remote_address());
+    println!(
+        "H3 Client connecting: {:?} -> {:?}",
+        local_addr, remote_addr
+    );
+    let socket = sockets
+        .into_iter()
+        .find(|s| s.local_addr() == local_addr)
+        .unwrap();
+    socket.socket().set_nonblocking(false)?;
+    run_client(args, &socket, transport, hostname, urls)
+}
+
+/// Make a socket that is connected to `remote_addr`.
+fn connected_socket(remote_addr: SocketAddr) -> Res<Socket> {
+    let local_addr = match remote_addr {
+        SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::from([0; 4])), 0),
+        SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), 0),
+    };
+
+    let socket = match UdpSocket::bind(local_addr) {
+        Err(e) => {
+            eprintln!("Unable to bind UDP socket: {}", e);
+            exit(1)
+        }
+        Ok(s) => s,
+    };
+    socket
+        .connect(&remote_addr)
+        .expect("Unable to connect UDP socket");
+    Ok(Socket::new(socket)?)
+}
+
 fn qlog_new(args: &Args, origin: &str) -> Res<Option<NeqoQlog>> {
     if let Some(qlog_dir) = &args.qlog_dir {
         let mut qlog_path = qlog_dir.to_path_buf();
         qlog_path.push(format!("{}.qlog", origin));
 
-        let f = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&qlog_path)?;
-
-        let streamer = QlogStreamer::new(
-            qlog::QLOG_VERSION.to_string(),
+        Ok(Some(NeqoQlog::with_file(
+            qlog_path,
+            Role::Client,
             Some("Example qlog".to_string()),
             Some("Example qlog description".to_string()),
-            None,
-            std::time::Instant::now(),
-            common::qlog::new_trace(Role::Client),
-            Box::new(f),
-        );
-
-        Ok(Some(NeqoQlog::new(streamer, qlog_path)?))
+        )?))
     } else {
         Ok(None)
     }
 }
 
 fn main() -> Res<()> {
-    init();
-
     let mut args = Args::from_args();
 
+    if let Some(key_log) = &args.key_log {
+        // NSS reads this when it is initialized.
+        env::set_var("SSLKEYLOGFILE", key_log);
+    }
+    init();
+
     if args.qns_mode {
         match env::var("TESTCASE") {
             Ok(s) if s == "http3" => {}
@@ -546,35 +686,27 @@ fn main() -> Res<()> {
         }
     }) {
         let addrs: Vec<_> = format!("{}:{}", host, port).to_socket_addrs()?.collect();
+        if args.happy_eyeballs && !args.use_old_http {
+            race_client(&args, &addrs, &format!("{}", host), &urls)?;
+            continue;
+        }
         let remote_addr = *addrs.first().unwrap();
 
-        let local_addr = match remote_addr {
-            SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::from([0; 4])), 0),
-            SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), 0),
-        };
-
-        let socket = match UdpSocket::bind(local_addr) {
-            Err(e) => {
-                eprintln!("Unable to bind UDP socket: {}", e);
-                exit(1)
-            }
-            Ok(s) => s,
-        };
-        socket
-            .connect(&remote_addr)
-            .expect("Unable to connect UDP socket");
+        let socket = connected_socket(remote_addr)?;
+        // Now that the socket is connected, this is the address that is really used.
+        let local_addr = socket.local_addr();
 
         println!(
             "{} Client connecting: {:?} -> {:?}",
             if args.use_old_http { "H9" } else { "H3" },
-            socket.local_addr().unwrap(),
+            local_addr,
             remote_addr
         );
 
         if !args.use_old_http {
             client(
                 &args,
-                socket,
+                &socket,
                 local_addr,
                 remote_addr,
                 &format!("{}", host),
@@ -638,7 +770,7 @@ mod old {
     use std::collections::{HashMap, VecDeque};
     use std::fs::File;
     use std::io::{ErrorKind, Write};
-    use std::net::{SocketAddr, UdpSocket};
+    use std::net::SocketAddr;
     use std::path::PathBuf;
     use std::process::exit;
     use std::rc::Rc;
@@ -648,14 +780,15 @@ mod old {
 
     use super::{qlog_new, Res};
 
-    use neqo_common::Datagram;
     use neqo_crypto::AuthenticationStatus;
     use neqo_transport::{
         Connection, ConnectionEvent, Error, FixedConnectionIdManager, Output, QuicVersion, State,
         StreamType,
     };
 
-    use super::{emit_datagram, get_output_file, Args};
+    use neqo_udp::{RecvBuf, Socket};
+
+    use super::{emit_datagrams, get_output_file, Args};
 
     struct HandlerOld<'b> {
         streams: HashMap<u64, Option<File>>,
@@ -778,13 +911,12 @@ mod old {
     }
 
     fn process_loop_old(
-        local_addr: &SocketAddr,
-        remote_addr: &SocketAddr,
-        socket: &UdpSocket,
+        socket: &Socket,
         client: &mut Connection,
         handler: &mut HandlerOld,
     ) -> Res<State> {
-        let buf = &mut [0u8; 2048];
+        let mut buf = RecvBuf::new();
+        let mut dgrams = Vec::new();
         loop {
             if let State::Closed(..) = client.state() {
                 return Ok(client.state().clone());
@@ -795,50 +927,44 @@ mod old {
             loop {
                 let output = client.process_output(Instant::now());
                 match output {
-                    Output::Datagram(dgram) => {
-                        if let Err(e) = emit_datagram(&socket, Some(dgram)) {
-                            eprintln!("UDP write error: {}", e);
-                            client.close(Instant::now(), 0, e.to_string());
-                            exiting = true;
-                            break;
-                        }
-                    }
+                    Output::Datagram(dgram) => dgrams.push(dgram),
                     Output::Callback(duration) => {
-                        socket.set_read_timeout(Some(duration)).unwrap();
+                        socket.socket().set_read_timeout(Some(duration)).unwrap();
                         break;
                     }
                     Output::None => {
                         // Not strictly necessary, since we're about to exit
-                        socket.set_read_timeout(None).unwrap();
+                        socket.socket().set_read_timeout(None).unwrap();
                         exiting = true;
                         break;
                     }
                 }
             }
+            if let Err(e) = emit_datagrams(socket, &dgrams) {
+                eprintln!("UDP write error: {}", e);
+                client.close(Instant::now(), 0, e.to_string());
+                exiting = true;
+            }
+            dgrams.clear();
 
             if exiting {
                 return Ok(client.state().clone());
             }
 
-            let sz = match socket.recv(&mut buf[..]) {
+            let received = match socket.recv(&mut buf) {
                 Err(ref err)
                     if err.kind() == ErrorKind::WouldBlock
                         || err.kind() == ErrorKind::Interrupted =>
                 {
-                    0
+                    continue;
                 }
                 Err(err) => {
                     eprintln!("UDP error: {}", err);
                     exit(1)
                 }
-                Ok(sz) => sz,
+                Ok(received) => received,
             };
-            if sz == buf.len() {
-                eprintln!("Received more than {} bytes", buf.len());
-                continue;
-            }
-            if sz > 0 {
-                let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
+            for d in received {
                 client.process_input(d, Instant::now());
             }
         }
@@ -846,7 +972,7 @@ mod old {
 
     pub fn old_client(
         args: &Args,
-        socket: &UdpSocket,
+        socket: &Socket,
         local_addr: SocketAddr,
         remote_addr: SocketAddr,
         origin: &str,
@@ -858,11 +984,12 @@ mod old {
             "hq-28" => (QuicVersion::Draft28, "hq-28"),
             _ => (QuicVersion::Draft29, "hq-29"),
         };
+        let quic_protocol = args.get_quic_version(quic_protocol);
 
         let mut client = Connection::new_client(
             origin,
             &[alpn],
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0)?)),
             local_addr,
             remote_addr,
             quic_protocol,
@@ -886,7 +1013,7 @@ mod old {
             args: &args,
         };
 
-        process_loop_old(&local_addr, &remote_addr, &socket, &mut client, &mut h)?;
+        process_loop_old(socket, &mut client, &mut h)?;
 
         Ok(if args.resume {
             client.resumption_token()
@@ -89,6 +89,12 @@ impl<'a> Decoder<'a> {
         Some(res)
     }
 
+    /// Take the next `n` bytes as a separate `Decoder`.  Use this where a structure
+    /// has a known length so that it can be checked that all of the bytes are consumed.
+    pub fn sub_decoder(&mut self, n: usize) -> Option<Decoder<'a>> {
+        self.decode(n).map(Decoder::new)
+    }
+
     /// Decodes an unsigned integer of length 1..8.
     pub fn decode_uint(&mut self, n: usize) -> Option<u64> {
         assert!(n > 0 && n <= 8);
@@ -152,6 +158,11 @@ impl<'a> Decoder<'a> {
         let len = self.decode_varint();
         self.decode_checked(len)
     }
+
+    /// Decodes a QUIC varint-length-prefixed buffer as a separate `Decoder`.
+    pub fn decode_vvec_decoder(&mut self) -> Option<Decoder<'a>> {
+        self.decode_vvec().map(Decoder::new)
+    }
 }
 
 // Implement `Deref` for `Decoder` so that values can be examined without moving the cursor.
@@ -480,6 +491,34 @@ mod tests {
         assert!(dec.decode_vvec().is_none());
     }
 
+    #[test]
+    fn sub_decoder() {
+        let enc = Encoder::from_hex("01234567");
+        let mut dec = enc.as_decoder();
+        let mut sub = dec.sub_decoder(2).expect("two bytes available");
+        assert_eq!(dec.remaining(), 2);
+        assert_eq!(sub.decode_byte().unwrap(), 0x01);
+        assert_eq!(sub.decode_byte().unwrap(), 0x23);
+        // The sub-decoder doesn't read past its end.
+        assert!(sub.decode_byte().is_none());
+        assert!(dec.sub_decoder(3).is_none());
+        assert_eq!(dec.decode_byte().unwrap(), 0x45);
+    }
+
+    #[test]
+    fn decode_vvec_decoder() {
+        let enc = Encoder::from_hex("02012345");
+        let mut dec = enc.as_decoder();
+        let mut sub = dec.decode_vvec_decoder().expect("length is in range");
+        assert_eq!(sub.remaining(), 2);
+        assert_eq!(sub.decode_varint(), Some(1));
+        assert_eq!(sub.decode_remainder(), &[0x23]);
+        assert_eq!(dec.remaining(), 1);
+
+        let enc = Encoder::from_hex("0301");
+        assert!(enc.as_decoder().decode_vvec_decoder().is_none());
+    }
+
     #[test]
     fn skip() {
         let enc = Encoder::from_hex("ffff");
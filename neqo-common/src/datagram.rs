@@ -9,18 +9,46 @@ use std::ops::Deref;
 
 use crate::hex_with_len;
 
+/// The IP TOS value used when nothing else is known: no DSCP marking and
+/// Not-ECT for the ECN bits.
+pub const IPTOS_DEFAULT: u8 = 0;
+/// The ECN codepoints (RFC 3168), which are the low two bits of the TOS byte.
+pub const IPTOS_ECN_NOT_ECT: u8 = 0b00;
+pub const IPTOS_ECN_ECT1: u8 = 0b01;
+pub const IPTOS_ECN_ECT0: u8 = 0b10;
+pub const IPTOS_ECN_CE: u8 = 0b11;
+const IPTOS_ECN_MASK: u8 = 0b11;
+
 #[derive(PartialEq, Clone)]
 pub struct Datagram {
     src: SocketAddr,
     dst: SocketAddr,
+    /// The IP TOS byte (or IPv6 traffic class), which includes the ECN codepoint.
+    tos: u8,
+    /// The IP TTL (or IPv6 hop limit), if known.
+    ttl: Option<u8>,
     d: Vec<u8>,
 }
 
 impl Datagram {
     pub fn new<V: Into<Vec<u8>>>(src: SocketAddr, dst: SocketAddr, d: V) -> Self {
+        Self::new_with_tos_ttl(src, dst, IPTOS_DEFAULT, None, d)
+    }
+
+    /// Create a datagram with the TOS and TTL values that were read from,
+    /// or are to be set on, the IP header.
+    pub fn new_with_tos_ttl<V: Into<Vec<u8>>>(
+        src: SocketAddr,
+        dst: SocketAddr,
+        tos: u8,
+        ttl: Option<u8>,
+        d: V,
+    ) -> Self {
         Self {
             src,
             dst,
+            tos,
+            ttl,
             d: d.into(),
         }
     }
@@ -34,6 +62,22 @@ impl Datagram {
     pub fn destination(&self) -> SocketAddr {
         self.dst
     }
+
+    #[must_use]
+    pub fn tos(&self) -> u8 {
+        self.tos
+    }
+
+    /// The ECN codepoint, which is the low two bits of the TOS byte.
+    #[must_use]
+    pub fn ecn(&self) -> u8 {
+        self.tos & IPTOS_ECN_MASK
+    }
+
+    #[must_use]
+    pub fn ttl(&self) -> Option<u8> {
+        self.ttl
+    }
 }
 
 impl Deref for Datagram {
@@ -48,9 +92,11 @@ impl std::fmt::Debug for Datagram {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Datagram {:?}->{:?}: {}",
+            "Datagram {:?}->{:?} tos={:x} ttl={:?}: {}",
             self.src,
             self.dst,
+            self.tos,
+            self.ttl,
             hex_with_len(&self.d)
         )
     }
@@ -11,12 +11,16 @@ mod codec;
 mod datagram;
 mod incrdecoder;
 pub mod log;
+mod pool;
 pub mod qlog;
 pub mod timer;
 
 pub use self::codec::{Decoder, Encoder};
-pub use self::datagram::Datagram;
+pub use self::datagram::{
+    Datagram, IPTOS_DEFAULT, IPTOS_ECN_CE, IPTOS_ECN_ECT0, IPTOS_ECN_ECT1, IPTOS_ECN_NOT_ECT,
+};
 pub use self::incrdecoder::{IncrementalDecoder, IncrementalDecoderResult};
+pub use self::pool::{BufferPool, BufferPoolStats};
 
 #[macro_use]
 extern crate lazy_static;
@@ -0,0 +1,124 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/// Counters for a `BufferPool`, which can be used to tune its size.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BufferPoolStats {
+    /// The number of buffers that had to be allocated because the pool was empty.
+    pub allocated: usize,
+    /// The number of buffers that were taken from the pool.
+    pub reused: usize,
+    /// The number of buffers that were returned to a pool that was already full.
+    pub dropped: usize,
+}
+
+/// `BufferPool` holds buffers that have been used so that they can be used again
+/// without allocating.  Buffers are handed out empty, but with whatever capacity
+/// they had when they were returned.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+    /// The capacity of newly allocated buffers.
+    buffer_size: usize,
+    /// The maximum number of buffers to hold.
+    limit: usize,
+    stats: BufferPoolStats,
+}
+
+impl BufferPool {
+    /// Create a pool that allocates buffers of `buffer_size` and holds
+    /// at most `limit` of them.
+    #[must_use]
+    pub fn new(buffer_size: usize, limit: usize) -> Self {
+        Self {
+            buffers: Vec::with_capacity(limit),
+            buffer_size,
+            limit,
+            stats: BufferPoolStats::default(),
+        }
+    }
+
+    /// Get an empty buffer.
+    pub fn take(&mut self) -> Vec<u8> {
+        if let Some(b) = self.buffers.pop() {
+            self.stats.reused += 1;
+            b
+        } else {
+            self.stats.allocated += 1;
+            Vec::with_capacity(self.buffer_size)
+        }
+    }
+
+    /// Return a buffer to the pool.
+    pub fn put(&mut self, mut buf: Vec<u8>) {
+        if self.buffers.len() < self.limit {
+            buf.clear();
+            self.buffers.push(buf);
+        } else {
+            self.stats.dropped += 1;
+        }
+    }
+
+    /// The number of buffers that are available.
+    #[must_use]
+    pub fn available(&self) -> usize {
+        self.buffers.len()
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> &BufferPoolStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferPool, BufferPoolStats};
+
+    #[test]
+    fn reuse() {
+        let mut pool = BufferPool::new(10, 1);
+        let mut b = pool.take();
+        assert!(b.is_empty());
+        assert_eq!(b.capacity(), 10);
+        b.extend_from_slice(&[1; 20]);
+        let cap = b.capacity();
+        pool.put(b);
+        assert_eq!(pool.available(), 1);
+
+        // The buffer comes back empty, but retains its capacity.
+        let b = pool.take();
+        assert!(b.is_empty());
+        assert_eq!(b.capacity(), cap);
+        assert_eq!(pool.available(), 0);
+        assert_eq!(
+            pool.stats(),
+            &BufferPoolStats {
+                allocated: 1,
+                reused: 1,
+                dropped: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn limit() {
+        let mut pool = BufferPool::new(10, 1);
+        let a = pool.take();
+        let b = pool.take();
+        pool.put(a);
+        pool.put(b);
+        assert_eq!(pool.available(), 1);
+        assert_eq!(
+            pool.stats(),
+            &BufferPoolStats {
+                allocated: 2,
+                reused: 0,
+                dropped: 1,
+            }
+        );
+    }
+}
@@ -5,8 +5,9 @@
 // except according to those terms.
 
 use std::fmt;
+use std::fs::OpenOptions;
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 use chrono::{DateTime, Utc};
 use qlog::{
@@ -15,10 +16,36 @@ use qlog::{
 };
 
 use crate::Role;
+
+/// The categories of qlog events.  A log only records events from the
+/// categories that it is configured with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum QlogCategory {
+    /// Connection setup and addresses.
+    Connectivity,
+    /// Packets and transport parameters.
+    Transport,
+    /// RTT, congestion control and loss detection.
+    Recovery,
+    /// QPACK instructions.
+    Qpack,
+}
+
+impl QlogCategory {
+    pub const ALL: &'static [Self] = &[
+        Self::Connectivity,
+        Self::Transport,
+        Self::Recovery,
+        Self::Qpack,
+    ];
+}
+
 #[allow(clippy::module_name_repetitions)]
 pub struct NeqoQlog {
     qlog_path: PathBuf,
     streamer: QlogStreamer,
+    categories: Vec<QlogCategory>,
 }
 
 impl NeqoQlog {
@@ -31,9 +58,54 @@ impl NeqoQlog {
         Ok(Self {
             streamer,
             qlog_path,
+            categories: QlogCategory::ALL.to_vec(),
         })
     }
 
+    /// Create a log for one connection, in a new file at `qlog_path`.  Any
+    /// existing file is replaced.
+    ///
+    /// # Errors
+    ///
+    /// Will return `qlog::Error` if the file can't be created or written.
+    pub fn with_file(
+        qlog_path: PathBuf,
+        role: Role,
+        title: Option<String>,
+        description: Option<String>,
+    ) -> Result<Self, qlog::Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&qlog_path)?;
+        let streamer = QlogStreamer::new(
+            qlog::QLOG_VERSION.to_string(),
+            title,
+            description,
+            None,
+            Instant::now(),
+            new_trace(role),
+            Box::new(file),
+        );
+        Self::new(streamer, qlog_path)
+    }
+
+    /// Only record events from `categories`.
+    pub fn set_categories(&mut self, categories: &[QlogCategory]) {
+        self.categories = categories.to_vec();
+    }
+
+    #[must_use]
+    pub fn records(&self, category: QlogCategory) -> bool {
+        self.categories.contains(&category)
+    }
+
+    /// Get the log, if there is one and it records events from `category`.
+    pub fn recording(qlog: &mut Option<Self>, category: QlogCategory) -> Option<&mut Self> {
+        qlog.as_mut().filter(|q| q.records(category))
+    }
+
     pub fn stream(&mut self) -> &mut QlogStreamer {
         &mut self.streamer
     }
@@ -89,3 +161,30 @@ pub fn new_trace(role: Role) -> qlog::Trace {
         events: Vec::new(),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{NeqoQlog, QlogCategory};
+    use crate::Role;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn categories() {
+        let path = env::temp_dir().join(format!("neqo-qlog-{}.qlog", std::process::id()));
+        let mut qlog = Some(NeqoQlog::with_file(path.clone(), Role::Client, None, None).unwrap());
+        assert!(NeqoQlog::recording(&mut qlog, QlogCategory::Transport).is_some());
+
+        qlog.as_mut()
+            .unwrap()
+            .set_categories(&[QlogCategory::Qpack]);
+        assert!(NeqoQlog::recording(&mut qlog, QlogCategory::Transport).is_none());
+        assert!(NeqoQlog::recording(&mut qlog, QlogCategory::Qpack).is_some());
+
+        // The log is completed when it is dropped.
+        drop(qlog);
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("neqo-Client"));
+        fs::remove_file(&path).unwrap();
+    }
+}
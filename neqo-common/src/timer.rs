@@ -233,9 +233,52 @@ impl<T> Timer<T> {
     }
 }
 
+/// Tracks the earliest of several optional deadlines, along with what set it.
+/// Use this where a single callback time needs to be chosen from a number
+/// of independent sources, like the idle, loss recovery, and ACK timers.
+#[derive(Debug)]
+pub struct Deadline<K> {
+    earliest: Option<(K, Instant)>,
+}
+
+impl<K> Deadline<K> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self { earliest: None }
+    }
+
+    /// Consider a deadline of the given kind.  If `time` is `None`,
+    /// then that source has no deadline and this does nothing.
+    pub fn add(&mut self, kind: K, time: Option<Instant>) {
+        if let Some(t) = time {
+            if self.earliest.as_ref().map_or(true, |(_, e)| t < *e) {
+                self.earliest = Some((kind, t));
+            }
+        }
+    }
+
+    /// The earliest deadline that was added.
+    #[must_use]
+    pub fn time(&self) -> Option<Instant> {
+        self.earliest.as_ref().map(|(_, t)| *t)
+    }
+
+    /// What set the earliest deadline.
+    #[must_use]
+    pub fn kind(&self) -> Option<&K> {
+        self.earliest.as_ref().map(|(k, _)| k)
+    }
+}
+
+impl<K> Default for Deadline<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Duration, Instant, Timer};
+    use super::{Deadline, Duration, Instant, Timer};
     use lazy_static::lazy_static;
 
     lazy_static! {
@@ -251,6 +294,25 @@ mod test {
         assert_eq!(None, t.next_time());
     }
 
+    #[test]
+    fn deadline() {
+        let mut d = Deadline::new();
+        assert_eq!(d.time(), None);
+        d.add(1, None);
+        assert_eq!(d.time(), None);
+        d.add(2, Some(*NOW + GRANULARITY));
+        d.add(3, Some(*NOW + GRANULARITY * 2));
+        d.add(4, None);
+        assert_eq!(d.time(), Some(*NOW + GRANULARITY));
+        assert_eq!(d.kind(), Some(&2));
+        // Ties go to the first.
+        d.add(5, Some(*NOW + GRANULARITY));
+        assert_eq!(d.kind(), Some(&2));
+        d.add(6, Some(*NOW));
+        assert_eq!(d.time(), Some(*NOW));
+        assert_eq!(d.kind(), Some(&6));
+    }
+
     #[test]
     fn immediate_entry() {
         let mut t = Timer::new(*NOW, GRANULARITY, CAPACITY);
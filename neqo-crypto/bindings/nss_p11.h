@@ -7,3 +7,5 @@
 #include "cert.h"
 #include "keyhi.h"
 #include "pk11pub.h"
+#include "pk11hpke.h"
+#include "secoid.h"
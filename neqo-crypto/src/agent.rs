@@ -10,14 +10,16 @@ use crate::assert_initialized;
 use crate::auth::AuthenticationStatus;
 pub use crate::cert::CertificateInfo;
 use crate::constants::{
-    Alert, Cipher, Epoch, Extension, Group, SignatureScheme, Version, TLS_VERSION_1_3,
+    Alert, Cipher, Epoch, Extension, Group, SignatureScheme, Version, TLS_CT_HANDSHAKE,
+    TLS_VERSION_1_3,
 };
+use crate::ech;
 use crate::err::{is_blocked, secstatus_to_res, Error, PRErrorCode, Res};
 use crate::ext::{ExtensionHandler, ExtensionTracker};
 use crate::p11;
 use crate::prio;
 use crate::replay::AntiReplay;
-use crate::secrets::SecretHolder;
+use crate::secrets::{KeyLogCallback, SecretHolder};
 use crate::ssl::{self, PRBool};
 use crate::time::TimeHolder;
 
@@ -150,6 +152,7 @@ pub struct SecretAgentInfo {
     early_data: bool,
     alpn: Option<String>,
     signature_scheme: SignatureScheme,
+    ech_accepted: bool,
 }
 
 impl SecretAgentInfo {
@@ -171,6 +174,7 @@ impl SecretAgentInfo {
             early_data: info.earlyDataAccepted != 0,
             alpn: get_alpn(fd, false)?,
             signature_scheme: SignatureScheme::try_from(info.signatureScheme)?,
+            ech_accepted: info.echAccepted != 0,
         })
     }
     #[must_use]
@@ -201,6 +205,11 @@ impl SecretAgentInfo {
     pub fn signature_scheme(&self) -> SignatureScheme {
         self.signature_scheme
     }
+    /// Whether the server accepted encrypted client hello (ECH).
+    #[must_use]
+    pub fn ech_accepted(&self) -> bool {
+        self.ech_accepted
+    }
 }
 
 /// `SecretAgent` holds the common parts of client and server.
@@ -308,6 +317,7 @@ impl SecretAgent {
 
     // Ready this for connecting.
     fn ready(&mut self, is_server: bool) -> Res<()> {
+        self.secrets.set_server(is_server);
         secstatus_to_res(unsafe {
             ssl::SSL_AuthCertificateHook(
                 self.fd,
@@ -410,6 +420,15 @@ impl SecretAgent {
         self.set_option(ssl::Opt::EarlyData, true)
     }
 
+    /// Disable 0-RTT.  A server with 0-RTT disabled rejects early data and
+    /// issues tickets that do not allow it.
+    ///
+    /// # Errors
+    /// See `set_option`.
+    pub fn disable_0rtt(&mut self) -> Res<()> {
+        self.set_option(ssl::Opt::EarlyData, false)
+    }
+
     /// Disable the `EndOfEarlyData` message.
     ///
     /// # Errors
@@ -483,6 +502,21 @@ impl SecretAgent {
         Ok(())
     }
 
+    /// Pass TLS secrets to `callback` in the NSS key log format, so that
+    /// packet captures can be decrypted.  Each call gets one line.
+    /// This has to be set before the handshake starts.
+    pub fn set_key_log(&mut self, callback: KeyLogCallback) {
+        self.secrets.set_key_log(callback);
+    }
+
+    /// Find any handshake message in TLS records, for the key log.
+    fn tls_records(&mut self, records: &[u8]) {
+        const HEADER: usize = 5;
+        if records.first() == Some(&TLS_CT_HANDSHAKE) && records.len() > HEADER {
+            self.secrets.handshake_message(&records[HEADER..]);
+        }
+    }
+
     // This function tracks whether handshake() or handshake_raw() was used
     // and prevents the other from being used.
     fn set_raw(&mut self, r: bool) -> Res<()> {
@@ -542,11 +576,12 @@ impl SecretAgent {
     }
 
     fn capture_error<T>(&mut self, res: Res<T>) -> Res<T> {
-        if let Err(e) = &res {
+        res.map_err(|e| {
+            let e = ech::convert_ech_error(self.fd, e);
             qwarn!([self], "error: {:?}", e);
             self.state = HandshakeState::Failed(e.clone());
-        }
-        res
+            e
+        })
     }
 
     fn update_state(&mut self, res: Res<()>) -> Res<()> {
@@ -579,6 +614,7 @@ impl SecretAgent {
         self.now.set(now)?;
         self.set_raw(false)?;
 
+        self.tls_records(input);
         let rv = {
             // Within this scope, _h maintains a mutable reference to self.io.
             let _h = self.io.wrap(input);
@@ -592,6 +628,7 @@ impl SecretAgent {
         // Take before updating state so that we leave the output buffer empty
         // even if there is an error.
         let output = self.io.take_output();
+        self.tls_records(&output);
         self.update_state(secstatus_to_res(rv))?;
         Ok(output)
     }
@@ -626,11 +663,17 @@ impl SecretAgent {
 
         // Feed in any records.
         if let Some(rec) = input {
+            if rec.ct == TLS_CT_HANDSHAKE {
+                self.secrets.handshake_message(&rec.data);
+            }
             self.capture_error(rec.write(self.fd))?;
         }
 
         // Drive the handshake once more.
         let rv = secstatus_to_res(unsafe { ssl::SSL_ForceHandshake(self.fd) });
+        for rec in records.iter().filter(|r| r.ct == TLS_CT_HANDSHAKE) {
+            self.secrets.handshake_message(&rec.data);
+        }
         self.update_state(rv)?;
 
         Ok(*Pin::into_inner(records))
@@ -690,6 +733,8 @@ pub struct Client {
 
     /// Records the last resumption token.
     resumption: Pin<Box<Option<Vec<u8>>>>,
+    /// The nickname of the client certificate, which NSS reads when the server asks for it.
+    client_certificate: Option<CString>,
 }
 
 impl Client {
@@ -705,6 +750,7 @@ impl Client {
         let mut client = Self {
             agent,
             resumption: Box::pin(None),
+            client_certificate: None,
         };
         client.ready()?;
         Ok(client)
@@ -761,6 +807,39 @@ impl Client {
             )
         }
     }
+
+    /// Set the certificate that is used if the server requests client authentication.
+    /// The certificate and its private key are loaded from the NSS database using `nickname`.
+    ///
+    /// # Errors
+    /// Error returned when NSS fails.
+    pub fn set_client_certificate(&mut self, nickname: &str) -> Res<()> {
+        let nickname = CString::new(nickname)?;
+        // NSS holds onto this pointer, so keep the string alive in `self`.
+        let arg = nickname.as_ptr() as *mut c_void;
+        secstatus_to_res(unsafe {
+            ssl::SSL_GetClientAuthDataHook(self.agent.fd, Some(ssl::NSS_GetClientAuthData), arg)
+        })?;
+        self.client_certificate = Some(nickname);
+        Ok(())
+    }
+
+    /// Enable encrypted client hello (ECH), using the encoded `ECHConfigList`.
+    ///
+    /// # Errors
+    /// Error returned when the configuration is invalid or when the version
+    /// of NSS in use doesn't support ECH.
+    pub fn enable_ech(&mut self, ech_config_list: impl AsRef<[u8]>) -> Res<()> {
+        let config = ech_config_list.as_ref();
+        qdebug!([self.agent], "Enable ECH: {}", hex_snip_middle(config));
+        unsafe {
+            ech::SSL_SetClientEchConfigs(
+                self.agent.fd,
+                config.as_ptr(),
+                c_uint::try_from(config.len())?,
+            )
+        }
+    }
 }
 
 impl Deref for Client {
@@ -815,6 +894,8 @@ pub struct Server {
     agent: SecretAgent,
     /// This holds the HRR callback context.
     zero_rtt_check: Option<Pin<Box<ZeroRttCheckState>>>,
+    /// The encoded configuration for encrypted client hello (ECH), if enabled.
+    ech_config: Vec<u8>,
 }
 
 impl Server {
@@ -848,9 +929,23 @@ impl Server {
         Ok(Self {
             agent,
             zero_rtt_check: None,
+            ech_config: Vec::new(),
         })
     }
 
+    /// Ask the client for a certificate.  If `required` is set, the handshake fails
+    /// if the client doesn't provide one.  The client certificate is checked in the
+    /// same way as a server certificate: the handshake pauses in
+    /// `HandshakeState::AuthenticationPending` until `authenticated()` is called.
+    /// Use `peer_certificate()` to access the certificate.
+    ///
+    /// # Errors
+    /// Error returned when NSS fails.
+    pub fn request_client_certificate(&mut self, required: bool) -> Res<()> {
+        self.set_option(ssl::Opt::RequestCertificate, true)?;
+        self.set_option(ssl::Opt::RequireCertificate, required)
+    }
+
     unsafe extern "C" fn hello_retry_cb(
         first_hello: PRBool,
         client_token: *const u8,
@@ -931,6 +1026,43 @@ impl Server {
 
         Ok(*Pin::into_inner(records))
     }
+
+    /// Enable encrypted client hello (ECH), with a configuration that has
+    /// the identifier `config` and names `public_name`.  The keys come from
+    /// `ech::generate_keys`.  Clients need the configuration from
+    /// `ech_config` to use ECH.
+    ///
+    /// # Errors
+    /// When the configuration can't be encoded, or when the version of NSS
+    /// in use doesn't support ECH.
+    pub fn enable_ech(
+        &mut self,
+        config: u8,
+        public_name: &str,
+        sk: &p11::PrivateKey,
+        pk: &p11::PublicKey,
+    ) -> Res<()> {
+        let cfg = ech::encode_config(config, public_name, pk)?;
+        qdebug!([self.agent], "Enable ECH: {}", hex_snip_middle(&cfg));
+        unsafe {
+            ech::SSL_SetServerEchConfigs(
+                self.agent.fd,
+                **pk,
+                **sk,
+                cfg.as_ptr(),
+                c_uint::try_from(cfg.len())?,
+            )
+        }?;
+        self.ech_config = cfg;
+        Ok(())
+    }
+
+    /// The encoded configuration for encrypted client hello (ECH), which
+    /// is empty until `enable_ech` is called.
+    #[must_use]
+    pub fn ech_config(&self) -> &[u8] {
+        &self.ech_config
+    }
 }
 
 impl Deref for Server {
@@ -0,0 +1,175 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Encrypted client hello (ECH).
+//
+// A server makes a key pair and encodes the public key into a configuration,
+// which clients get some other way, usually from DNS.  A client that uses the
+// configuration encrypts the real ClientHello.  A server that can't decrypt it
+// completes the handshake with the outer ClientHello instead, which names the
+// public name of the configuration, and sends the configurations that the
+// client should retry with.
+
+use crate::err::ssl::{SSL_ERROR_ECH_RETRY_WITHOUT_ECH, SSL_ERROR_ECH_RETRY_WITH_ECH};
+use crate::err::{Error, Res};
+use crate::p11::{
+    self, PrivateKey, PublicKey, SECITEM_FreeItem, SECItem, SECItemType, SECKEYPrivateKey,
+    SECKEYPublicKey, Slot,
+};
+use crate::ssl::{HpkeSymmetricSuite, PRBool, PRFileDesc};
+
+use neqo_common::qinfo;
+
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr::{null_mut, NonNull};
+
+experimental_api!(SSL_GetEchRetryConfigs(
+    fd: *mut PRFileDesc,
+    config: *mut SECItem,
+));
+experimental_api!(SSL_SetClientEchConfigs(
+    fd: *mut PRFileDesc,
+    config_list: *const u8,
+    config_list_len: c_uint,
+));
+experimental_api!(SSL_SetServerEchConfigs(
+    fd: *mut PRFileDesc,
+    pk: *const SECKEYPublicKey,
+    sk: *const SECKEYPrivateKey,
+    record: *const u8,
+    record_len: c_uint,
+));
+experimental_api!(SSL_EncodeEchConfigId(
+    config_id: u8,
+    public_name: *const c_char,
+    max_name_len: c_uint,
+    kem_id: p11::HpkeKemId::Type,
+    pk: *const SECKEYPublicKey,
+    hpke_suites: *const HpkeSymmetricSuite,
+    hpke_suite_count: c_uint,
+    out: *mut u8,
+    out_len: *mut c_uint,
+    max_len: c_uint,
+));
+
+/// The longest public name that a configuration can have.
+const MAX_PUBLIC_NAME_LEN: c_uint = 255;
+
+/// Turn the error that NSS reports when a server rejects ECH into
+/// `Error::EchRetry`, with the configurations that the server offered.
+/// Other errors are unchanged.
+pub(crate) fn convert_ech_error(fd: *mut PRFileDesc, err: Error) -> Error {
+    match &err {
+        Error::NssError { code, .. } if *code == SSL_ERROR_ECH_RETRY_WITHOUT_ECH => {
+            Error::EchRetry(Vec::new())
+        }
+        Error::NssError { code, .. } if *code == SSL_ERROR_ECH_RETRY_WITH_ECH => {
+            let mut item = SECItem {
+                type_: SECItemType::siBuffer,
+                data: null_mut(),
+                len: 0,
+            };
+            if unsafe { SSL_GetEchRetryConfigs(fd, &mut item) }.is_err() {
+                return Error::InternalError;
+            }
+            let configs = unsafe {
+                let v = std::slice::from_raw_parts(item.data, item.len as usize).to_vec();
+                SECITEM_FreeItem(&mut item, PRBool::from(false));
+                v
+            };
+            qinfo!("ECH rejected, got {} bytes of retry configs", configs.len());
+            Error::EchRetry(configs)
+        }
+        _ => err,
+    }
+}
+
+/// Generate an X25519 key pair for a server to use for ECH.
+///
+/// # Errors
+/// When NSS can't make the keys.
+pub fn generate_keys() -> Res<(PrivateKey, PublicKey)> {
+    let slot = match NonNull::new(unsafe { p11::PK11_GetInternalSlot() }) {
+        Some(p) => Slot::new(p),
+        None => return Err(Error::InternalError),
+    };
+
+    // The parameters are the DER encoding of the curve OID.
+    let oid = unsafe { p11::SECOID_FindOIDByTag(p11::SECOidTag::SEC_OID_CURVE25519).as_ref() }
+        .ok_or(Error::InternalError)?;
+    let oid = unsafe { std::slice::from_raw_parts(oid.oid.data, oid.oid.len as usize) };
+    let mut params = Vec::with_capacity(oid.len() + 2);
+    params.push(u8::try_from(p11::SEC_ASN1_OBJECT_ID)?);
+    params.push(u8::try_from(oid.len())?);
+    params.extend_from_slice(oid);
+    let mut param_item = SECItem {
+        type_: SECItemType::siBuffer,
+        data: params.as_mut_ptr(),
+        len: c_uint::try_from(params.len())?,
+    };
+
+    let mut pk: *mut SECKEYPublicKey = null_mut();
+    let sk = unsafe {
+        p11::PK11_GenerateKeyPairWithOpFlags(
+            *slot,
+            p11::CK_MECHANISM_TYPE::from(p11::CKM_EC_KEY_PAIR_GEN),
+            &mut param_item as *mut SECItem as *mut c_void,
+            &mut pk,
+            p11::PK11_ATTR_SESSION | p11::PK11_ATTR_SENSITIVE | p11::PK11_ATTR_PRIVATE,
+            p11::CK_FLAGS::from(p11::CKF_DERIVE),
+            p11::CK_FLAGS::from(p11::CKF_DERIVE),
+            null_mut(),
+        )
+    };
+    // Either key is dropped if the other is missing.
+    match (
+        NonNull::new(sk).map(PrivateKey::new),
+        NonNull::new(pk).map(PublicKey::new),
+    ) {
+        (Some(sk), Some(pk)) => Ok((sk, pk)),
+        _ => Err(Error::InternalError),
+    }
+}
+
+/// Encode an `ECHConfig` with the identifier `config`, for a server that
+/// uses `public_name` and the key from `generate_keys`.  This offers
+/// AES-128-GCM and ChaCha20Poly1305, both with HKDF-SHA256.
+///
+/// # Errors
+/// When the name is too long or NSS can't encode the configuration.
+pub fn encode_config(config: u8, public_name: &str, pk: &PublicKey) -> Res<Vec<u8>> {
+    const SUITES: &[HpkeSymmetricSuite] = &[
+        HpkeSymmetricSuite {
+            kdfId: p11::HpkeKdfId::HpkeKdfHkdfSha256,
+            aeadId: p11::HpkeAeadId::HpkeAeadAes128Gcm,
+        },
+        HpkeSymmetricSuite {
+            kdfId: p11::HpkeKdfId::HpkeKdfHkdfSha256,
+            aeadId: p11::HpkeAeadId::HpkeAeadChaCha20Poly1305,
+        },
+    ];
+
+    let name = CString::new(public_name)?;
+    let mut encoded = [0; 1024];
+    let mut encoded_len: c_uint = 0;
+    unsafe {
+        SSL_EncodeEchConfigId(
+            config,
+            name.as_ptr(),
+            MAX_PUBLIC_NAME_LEN,
+            p11::HpkeKemId::HpkeDhKemX25519Sha256,
+            **pk,
+            SUITES.as_ptr(),
+            c_uint::try_from(SUITES.len())?,
+            encoded.as_mut_ptr(),
+            &mut encoded_len,
+            c_uint::try_from(encoded.len())?,
+        )?;
+    }
+    Ok(encoded[..usize::try_from(encoded_len)?].to_vec())
+}
@@ -33,6 +33,10 @@ pub enum Error {
     AeadError,
     CertificateLoading,
     CreateSslSocket,
+    /// The server rejected encrypted client hello (ECH).  This holds the
+    /// configurations that it offered for a new attempt.  If there are none,
+    /// the server doesn't support ECH and a new attempt shouldn't use it.
+    EchRetry(Vec<u8>),
     HkdfError,
     InternalError,
     IntegerOverflow,
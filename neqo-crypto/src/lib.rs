@@ -23,6 +23,7 @@ mod agentio;
 mod auth;
 mod cert;
 pub mod constants;
+pub mod ech;
 mod err;
 pub mod ext;
 pub mod hkdf;
@@ -43,9 +44,9 @@ pub use self::auth::AuthenticationStatus;
 pub use self::constants::*;
 pub use self::err::{Error, PRErrorCode, Res};
 pub use self::ext::{ExtensionHandler, ExtensionHandlerResult, ExtensionWriterResult};
-pub use self::p11::{random, SymKey};
+pub use self::p11::{random, PrivateKey, PublicKey, SymKey};
 pub use self::replay::AntiReplay;
-pub use self::secrets::SecretDirection;
+pub use self::secrets::{KeyLogCallback, SecretDirection};
 pub use self::ssl::Opt;
 
 use self::once::OnceResult;
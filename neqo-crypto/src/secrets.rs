@@ -5,12 +5,16 @@
 // except according to those terms.
 
 use crate::agentio::as_c_void;
-use crate::constants::Epoch;
+use crate::constants::{
+    Epoch, TLS_EPOCH_APPLICATION_DATA, TLS_EPOCH_HANDSHAKE, TLS_EPOCH_ZERO_RTT, TLS_HS_CLIENT_HELLO,
+};
 use crate::err::Res;
 use crate::p11::{PK11SymKey, PK11_ReferenceSymKey, SymKey};
 use crate::ssl::{PRFileDesc, SSLSecretCallback, SSLSecretDirection};
 
-use neqo_common::qdebug;
+use neqo_common::{hex, matches, qdebug, qwarn};
+use std::fmt;
+use std::mem;
 use std::os::raw::c_void;
 use std::pin::Pin;
 use std::ptr::NonNull;
@@ -62,10 +66,70 @@ impl DirectionalSecrets {
     }
 }
 
+/// Receives TLS secrets in the NSS key log format, one line at a time,
+/// without a line ending.
+pub type KeyLogCallback = Box<dyn FnMut(&str)>;
+
+/// Keys are logged against the random from the ClientHello, so they are held
+/// until that is known.  At a client, NSS provides the 0-RTT secret before
+/// the ClientHello is written.
+struct KeyLog {
+    callback: KeyLogCallback,
+    client_random: Option<Vec<u8>>,
+    pending: Vec<(Epoch, SecretDirection, Vec<u8>)>,
+}
+
+impl KeyLog {
+    /// The label for a secret, which depends on whether it is used by the client.
+    fn label(epoch: Epoch, client: bool) -> &'static str {
+        match (epoch, client) {
+            (TLS_EPOCH_ZERO_RTT, _) => "CLIENT_EARLY_TRAFFIC_SECRET",
+            (TLS_EPOCH_HANDSHAKE, true) => "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+            (TLS_EPOCH_HANDSHAKE, false) => "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+            (TLS_EPOCH_APPLICATION_DATA, true) => "CLIENT_TRAFFIC_SECRET_0",
+            (TLS_EPOCH_APPLICATION_DATA, false) => "SERVER_TRAFFIC_SECRET_0",
+            _ => unreachable!(),
+        }
+    }
+
+    fn log(&mut self, server: bool, epoch: Epoch, dir: SecretDirection, secret: &[u8]) {
+        if let Some(random) = &self.client_random {
+            let client = matches!(dir, SecretDirection::Write) != server;
+            let line = format!(
+                "{} {} {}",
+                Self::label(epoch, client),
+                hex(random),
+                hex(secret)
+            );
+            (self.callback)(&line);
+        } else {
+            self.pending.push((epoch, dir, secret.to_vec()));
+        }
+    }
+
+    fn set_client_random(&mut self, server: bool, random: &[u8]) {
+        if self.client_random.is_some() {
+            return;
+        }
+        self.client_random = Some(random.to_vec());
+        for (epoch, dir, secret) in mem::replace(&mut self.pending, Vec::new()) {
+            self.log(server, epoch, dir, &secret);
+        }
+    }
+}
+
+impl fmt::Debug for KeyLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyLog")
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Secrets {
     r: DirectionalSecrets,
     w: DirectionalSecrets,
+    server: bool,
+    key_log: Option<KeyLog>,
 }
 
 impl Secrets {
@@ -93,6 +157,12 @@ impl Secrets {
 
     fn put(&mut self, dir: SecretDirection, epoch: Epoch, key: SymKey) {
         qdebug!("{:?} secret available for {:?}", dir, epoch);
+        if let Some(key_log) = &mut self.key_log {
+            match key.as_bytes() {
+                Ok(secret) => key_log.log(self.server, epoch, dir, secret),
+                Err(e) => qwarn!("Unable to export {:?} secret: {:?}", dir, e),
+            }
+        }
         let keys = match dir {
             SecretDirection::Read => &mut self.r,
             SecretDirection::Write => &mut self.w,
@@ -121,6 +191,33 @@ impl SecretHolder {
     pub fn take_write(&mut self, epoch: Epoch) -> Option<SymKey> {
         self.secrets.w.take(epoch)
     }
+
+    /// Note which side of the connection this is, which determines how secrets are logged.
+    pub fn set_server(&mut self, server: bool) {
+        self.secrets.server = server;
+    }
+
+    /// Pass secrets to `callback` as they become available.
+    pub fn set_key_log(&mut self, callback: KeyLogCallback) {
+        self.secrets.key_log = Some(KeyLog {
+            callback,
+            client_random: None,
+            pending: Vec::new(),
+        });
+    }
+
+    /// Look at a handshake message that was sent or received.  The random
+    /// from the ClientHello is needed to log secrets.
+    pub fn handshake_message(&mut self, msg: &[u8]) {
+        // The random follows the message header and legacy_version.
+        const RANDOM: std::ops::Range<usize> = 6..38;
+        let server = self.secrets.server;
+        if let Some(key_log) = &mut self.secrets.key_log {
+            if msg.first() == Some(&TLS_HS_CLIENT_HELLO) && msg.len() >= RANDOM.end {
+                key_log.set_client_random(server, &msg[RANDOM]);
+            }
+        }
+    }
 }
 
 impl Default for SecretHolder {
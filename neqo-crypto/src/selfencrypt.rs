@@ -40,6 +40,19 @@ impl SelfEncrypt {
         })
     }
 
+    /// Make a self-encryption object that uses `key`, rather than a random key.
+    /// Objects made with the same key can open each other's output.
+    #[must_use]
+    pub fn with_key(version: Version, cipher: Cipher, key: SymKey) -> Self {
+        Self {
+            version,
+            cipher,
+            key_id: 0,
+            key,
+            old_key: None,
+        }
+    }
+
     fn make_aead(&self, k: &SymKey, salt: &[u8]) -> Res<Aead> {
         debug_assert_eq!(salt.len(), Self::SALT_LENGTH);
         let salt = hkdf::import_key(self.version, self.cipher, salt)?;
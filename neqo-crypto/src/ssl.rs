@@ -40,6 +40,8 @@ pub enum Opt {
     Tls13CompatMode,
     HelloDowngradeCheck,
     SuppressEndOfEarlyData,
+    RequestCertificate,
+    RequireCertificate,
 }
 
 impl Opt {
@@ -58,6 +60,8 @@ impl Opt {
             Self::Tls13CompatMode => SSLOption::SSL_ENABLE_TLS13_COMPAT_MODE,
             Self::HelloDowngradeCheck => SSLOption::SSL_ENABLE_HELLO_DOWNGRADE_CHECK,
             Self::SuppressEndOfEarlyData => SSLOption::SSL_SUPPRESS_END_OF_EARLY_DATA,
+            Self::RequestCertificate => SSLOption::SSL_REQUEST_CERTIFICATE,
+            Self::RequireCertificate => SSLOption::SSL_REQUIRE_CERTIFICATE,
         };
         i as PRInt32
     }
@@ -2,12 +2,14 @@
 #![warn(clippy::pedantic)]
 
 use neqo_crypto::{
-    AuthenticationStatus, Client, HandshakeState, SecretAgentPreInfo, Server, ZeroRttCheckResult,
-    ZeroRttChecker, TLS_AES_128_GCM_SHA256, TLS_CHACHA20_POLY1305_SHA256, TLS_GRP_EC_SECP256R1,
-    TLS_VERSION_1_3,
+    ech, AuthenticationStatus, Client, HandshakeState, SecretAgent, SecretAgentPreInfo, Server,
+    ZeroRttCheckResult, ZeroRttChecker, TLS_AES_128_GCM_SHA256, TLS_CHACHA20_POLY1305_SHA256,
+    TLS_GRP_EC_SECP256R1, TLS_VERSION_1_3,
 };
 
 use std::boxed::Box;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 mod handshake;
 use crate::handshake::{
@@ -135,6 +137,50 @@ fn raw() {
     assert!(server.peer_certificate().is_none());
 }
 
+/// Collect key log lines.
+fn key_log(agent: &mut SecretAgent) -> Rc<RefCell<Vec<String>>> {
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let l = Rc::clone(&lines);
+    agent.set_key_log(Box::new(move |line| l.borrow_mut().push(line.to_owned())));
+    lines
+}
+
+#[test]
+fn key_log_lines() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let client_lines = key_log(&mut client);
+    let server_lines = key_log(&mut server);
+    connect(&mut client, &mut server);
+
+    let mut client_lines = client_lines.borrow().clone();
+    let mut server_lines = server_lines.borrow().clone();
+    client_lines.sort();
+    server_lines.sort();
+    assert_eq!(client_lines, server_lines);
+
+    let labels: Vec<&str> = client_lines
+        .iter()
+        .map(|l| l.split(' ').next().unwrap())
+        .collect();
+    assert_eq!(
+        labels,
+        [
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+            "CLIENT_TRAFFIC_SECRET_0",
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+            "SERVER_TRAFFIC_SECRET_0",
+        ]
+    );
+    // Each line has the same client random, which is 32 bytes.
+    let random = client_lines[0].split(' ').nth(1).unwrap();
+    assert_eq!(random.len(), 64);
+    assert!(client_lines
+        .iter()
+        .all(|l| l.split(' ').nth(1) == Some(random)));
+}
+
 #[test]
 fn chacha_client() {
     fixture_init();
@@ -366,6 +412,42 @@ fn reject_zero_rtt() {
     assert!(!server.info().unwrap().early_data_accepted());
 }
 
+#[test]
+fn client_certificate() {
+    fixture_init();
+    let mut client = Client::new("server.example").expect("should create client");
+    client
+        .set_client_certificate("key")
+        .expect("should set client certificate");
+    let mut server = Server::new(&["key"]).expect("should create server");
+    server
+        .request_client_certificate(true)
+        .expect("should request client certificate");
+    assert!(server.peer_certificate().is_none());
+
+    connect(&mut client, &mut server);
+    assert!(server.peer_certificate().is_some());
+}
+
+#[test]
+fn encrypted_client_hello() {
+    fixture_init();
+    let mut server = Server::new(&["key"]).expect("should create server");
+    let (sk, pk) = ech::generate_keys().expect("should make ECH keys");
+    server
+        .enable_ech(1, "public.example", &sk, &pk)
+        .expect("should enable ECH");
+    assert!(!server.ech_config().is_empty());
+    let mut client = Client::new("server.example").expect("should create client");
+    client
+        .enable_ech(server.ech_config())
+        .expect("should enable ECH");
+
+    connect(&mut client, &mut server);
+    assert!(client.info().unwrap().ech_accepted());
+    assert!(server.info().unwrap().ech_accepted());
+}
+
 #[test]
 fn close() {
     fixture_init();
@@ -0,0 +1,323 @@
+/* Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+ * http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+ * <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+ * option. This file may not be copied, modified, or distributed
+ * except according to those terms. */
+
+#ifndef NEQO_FFI_H
+#define NEQO_FFI_H
+
+/* This file is generated by cbindgen.  Do not edit it. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum NeqoStatus {
+  NeqoStatus_Ok,
+  /**
+   * A pointer was null, a string wasn't UTF-8, or an address didn't parse.
+   */
+  NeqoStatus_InvalidArgument,
+  /**
+   * The stream doesn't exist, or can't be used in that direction.
+   */
+  NeqoStatus_InvalidStream,
+  /**
+   * The connection isn't in a state that allows this.
+   */
+  NeqoStatus_InvalidState,
+  /**
+   * The peer doesn't allow more streams of that type yet.
+   */
+  NeqoStatus_StreamLimit,
+  /**
+   * Anything else, including a panic.
+   */
+  NeqoStatus_Error,
+} NeqoStatus;
+
+typedef enum NeqoState {
+  NeqoState_Init,
+  NeqoState_WaitInitial,
+  NeqoState_Handshaking,
+  NeqoState_Connected,
+  NeqoState_Confirmed,
+  NeqoState_Closing,
+  NeqoState_Draining,
+  NeqoState_Closed,
+} NeqoState;
+
+typedef enum NeqoOutputType {
+  /**
+   * The connection is done; there is nothing more to wait for.
+   */
+  NeqoOutputType_None,
+  /**
+   * Send a datagram to the server.
+   */
+  NeqoOutputType_Datagram,
+  /**
+   * Call again after `timeout_us`, or sooner if a datagram arrives.
+   */
+  NeqoOutputType_Callback,
+} NeqoOutputType;
+
+typedef enum NeqoEventType {
+  /**
+   * Check the server certificate, then call `neqo_connection_authenticated`.
+   */
+  NeqoEventType_AuthenticationNeeded,
+  NeqoEventType_NewStream,
+  NeqoEventType_SendStreamWritable,
+  NeqoEventType_RecvStreamReadable,
+  NeqoEventType_RecvStreamReset,
+  NeqoEventType_SendStreamStopSending,
+  NeqoEventType_SendStreamComplete,
+  NeqoEventType_SendStreamCreatable,
+  NeqoEventType_StateChange,
+  NeqoEventType_ZeroRttRejected,
+  NeqoEventType_Datagram,
+  NeqoEventType_EchAccepted,
+  /**
+   * The server rejected ECH.  `data` holds the configurations to retry with.
+   */
+  NeqoEventType_EchRejected,
+  /**
+   * There is a new resumption token, which `data` holds.
+   */
+  NeqoEventType_ResumptionToken,
+} NeqoEventType;
+
+typedef uint64_t AppError;
+
+/**
+ * A client connection, which is only used through a pointer.
+ */
+typedef struct NeqoConnection NeqoConnection;
+
+typedef struct NeqoOutput {
+  NeqoOutputType output_type;
+  /**
+   * The datagram, which is only valid until the connection is next used.
+   */
+  const uint8_t *data;
+  uintptr_t len;
+  uint64_t timeout_us;
+} NeqoOutput;
+
+/**
+ * An event.  Fields that don't apply to the type of event are zero.
+ */
+typedef struct NeqoEvent {
+  NeqoEventType event_type;
+  uint64_t stream_id;
+  AppError app_error;
+  /**
+   * For `SendStreamCreatable`, whether bidirectional streams can be made.
+   */
+  bool bidi;
+  /**
+   * For `StateChange`, the new state.
+   */
+  NeqoState state;
+  /**
+   * For `Datagram`, the contents, for `EchRejected`, the retry
+   * configurations, and for `ResumptionToken`, the token.  These are only
+   * valid until the connection is next used.
+   */
+  const uint8_t *data;
+  uintptr_t len;
+} NeqoEvent;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Initialize NSS without a certificate database.  Call this, or
+ * `neqo_init_db`, once before making a connection.
+ */
+NeqoStatus neqo_init(void);
+
+/**
+ * Initialize NSS with the certificate database in `dir`.
+ *
+ * # Safety
+ *
+ * `dir` is a nul-terminated string.
+ */
+NeqoStatus neqo_init_db(const char *dir);
+
+/**
+ * Make a client connection.  `alpn` is a comma-separated list of protocols,
+ * and the addresses are like "127.0.0.1:443" or "[::1]:443".  `local_addr`
+ * has to be the address that datagrams from the server arrive on.
+ *
+ * # Safety
+ *
+ * The strings are nul-terminated.  On success, `conn` is set to a connection
+ * that has to be released with `neqo_connection_free`.
+ */
+NeqoStatus neqo_connection_new_client(const char *server_name,
+                                      const char *alpn,
+                                      const char *local_addr,
+                                      const char *remote_addr,
+                                      NeqoConnection **conn);
+
+/**
+ * Release a connection.  This doesn't send anything, so close it and wait
+ * for `NeqoOutputType::None` first to tell the server.
+ *
+ * # Safety
+ *
+ * `conn` came from `neqo_connection_new_client`, or is null.  It can't be used
+ * after this.
+ */
+void neqo_connection_free(NeqoConnection *conn);
+
+/**
+ * Pass in a datagram that arrived from the server.
+ *
+ * # Safety
+ *
+ * `conn` is valid, and `data` points at `len` bytes, or is null if `len` is
+ * zero.
+ */
+NeqoStatus neqo_connection_process_input(NeqoConnection *conn, const uint8_t *data, uintptr_t len);
+
+/**
+ * Get the next thing to do.  Call this until it returns a callback, which
+ * is after every call to `neqo_connection_process_input` and any change to a
+ * stream.  Datagrams go to the remote address of the connection.
+ *
+ * # Safety
+ *
+ * `conn` and `out` are valid.
+ */
+NeqoStatus neqo_connection_process_output(NeqoConnection *conn, NeqoOutput *out);
+
+/**
+ * Get the next event.  This returns false if there are none.
+ *
+ * # Safety
+ *
+ * `conn` and `event` are valid.
+ */
+bool neqo_connection_next_event(NeqoConnection *conn, NeqoEvent *event);
+
+/**
+ * Report the result of checking the server certificate, as an NSPR error
+ * code.  Zero means that the certificate is good.
+ *
+ * # Safety
+ *
+ * `conn` is valid.
+ */
+NeqoStatus neqo_connection_authenticated(NeqoConnection *conn, int32_t error);
+
+/**
+ * Start closing the connection.  `reason` can be null.
+ *
+ * # Safety
+ *
+ * `conn` is valid, and `reason` is null or a nul-terminated string.
+ */
+NeqoStatus neqo_connection_close(NeqoConnection *conn, AppError app_error, const char *reason);
+
+/**
+ * Get the state of the connection.
+ *
+ * # Safety
+ *
+ * `conn` and `state` are valid.
+ */
+NeqoStatus neqo_connection_state(NeqoConnection *conn, NeqoState *state);
+
+/**
+ * Get the error that closed the connection.  `app` is set if the error came
+ * from an application.  This returns `InvalidState` for an open connection.
+ *
+ * # Safety
+ *
+ * `conn`, `app`, and `code` are valid.
+ */
+NeqoStatus neqo_connection_close_error(NeqoConnection *conn, bool *app, uint64_t *code);
+
+/**
+ * Make a stream.
+ *
+ * # Safety
+ *
+ * `conn` and `stream_id` are valid.
+ */
+NeqoStatus neqo_connection_stream_create(NeqoConnection *conn, bool bidi, uint64_t *stream_id);
+
+/**
+ * Queue data on a stream.  `written` is set to how much was taken, which can
+ * be less than `len`, or zero; more can be sent after `SendStreamWritable`.
+ *
+ * # Safety
+ *
+ * `conn` and `written` are valid, and `data` points at `len` bytes, or is null
+ * if `len` is zero.
+ */
+NeqoStatus neqo_connection_stream_send(NeqoConnection *conn,
+                                       uint64_t stream_id,
+                                       const uint8_t *data,
+                                       uintptr_t len,
+                                       uintptr_t *written);
+
+/**
+ * Read from a stream into `buf`.  `fin` is set when the end of the stream is
+ * read, after which the stream can't be read again.
+ *
+ * # Safety
+ *
+ * `conn`, `read`, and `fin` are valid, and `buf` points at `len` bytes, or is
+ * null if `len` is zero.
+ */
+NeqoStatus neqo_connection_stream_recv(NeqoConnection *conn,
+                                       uint64_t stream_id,
+                                       uint8_t *buf,
+                                       uintptr_t len,
+                                       uintptr_t *read,
+                                       bool *fin);
+
+/**
+ * Finish sending on a stream.
+ *
+ * # Safety
+ *
+ * `conn` is valid.
+ */
+NeqoStatus neqo_connection_stream_close_send(NeqoConnection *conn, uint64_t stream_id);
+
+/**
+ * Abandon sending on a stream.
+ *
+ * # Safety
+ *
+ * `conn` is valid.
+ */
+NeqoStatus neqo_connection_stream_reset_send(NeqoConnection *conn,
+                                             uint64_t stream_id,
+                                             AppError app_error);
+
+/**
+ * Ask the server to stop sending on a stream.
+ *
+ * # Safety
+ *
+ * `conn` is valid.
+ */
+NeqoStatus neqo_connection_stream_stop_sending(NeqoConnection *conn,
+                                               uint64_t stream_id,
+                                               AppError app_error);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* NEQO_FFI_H */
@@ -0,0 +1,875 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::pedantic)]
+
+// A C API for embedding a client connection.
+//
+// A connection is an opaque pointer that `neqo_connection_new_client` makes
+// and `neqo_connection_free` releases.  Functions return a `NeqoStatus`, and
+// anything else through pointer arguments.  The application owns the socket
+// and the timer: it passes received datagrams to
+// `neqo_connection_process_input`, then calls `neqo_connection_process_output`
+// until that asks for a callback, and polls for events with
+// `neqo_connection_next_event`.
+//
+// A panic doesn't cross into C: a function that panics returns `Error`, or
+// false, and the connection shouldn't be used again.
+//
+// neqo_ffi.h is made with `cbindgen --config cbindgen.toml -o neqo_ffi.h`;
+// make it again after changing anything here.
+
+use neqo_common::Datagram;
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::{
+    AppError, Connection, ConnectionError, ConnectionEvent, Error as TransportError,
+    FixedConnectionIdManager, Output, QuicVersion, State, StreamType,
+};
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::net::SocketAddr;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use std::time::Instant;
+
+#[repr(C)]
+#[must_use]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoStatus {
+    Ok,
+    /// A pointer was null, a string wasn't UTF-8, or an address didn't parse.
+    InvalidArgument,
+    /// The stream doesn't exist, or can't be used in that direction.
+    InvalidStream,
+    /// The connection isn't in a state that allows this.
+    InvalidState,
+    /// The peer doesn't allow more streams of that type yet.
+    StreamLimit,
+    /// Anything else, including a panic.
+    Error,
+}
+
+impl From<&TransportError> for NeqoStatus {
+    fn from(e: &TransportError) -> Self {
+        match e {
+            TransportError::InvalidStreamId
+            | TransportError::StreamStateError
+            | TransportError::NoMoreData => Self::InvalidStream,
+            TransportError::ConnectionState | TransportError::NotConnected => Self::InvalidState,
+            TransportError::StreamLimitError => Self::StreamLimit,
+            _ => Self::Error,
+        }
+    }
+}
+
+fn status(res: Result<(), TransportError>) -> NeqoStatus {
+    match res {
+        Ok(()) => NeqoStatus::Ok,
+        Err(e) => NeqoStatus::from(&e),
+    }
+}
+
+/// Take a reference from a pointer argument, or return `InvalidArgument`.
+macro_rules! arg {
+    ($p:expr) => {
+        match $p.as_mut() {
+            Some(v) => v,
+            None => return NeqoStatus::InvalidArgument,
+        }
+    };
+}
+
+/// Check that a pointer to a result isn't null, or return `InvalidArgument`.
+/// What it points at might not be initialized, so only write to it, with
+/// `ptr::write`.
+macro_rules! out {
+    ($p:expr) => {
+        if $p.is_null() {
+            return NeqoStatus::InvalidArgument;
+        } else {
+            $p
+        }
+    };
+}
+
+/// Run `f`, or return `fallback` if it panics, as unwinding into C is
+/// undefined behavior.
+fn guard<T>(fallback: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(fallback)
+}
+
+unsafe fn c_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+unsafe fn c_addr(s: *const c_char) -> Option<SocketAddr> {
+    c_str(s)?.parse().ok()
+}
+
+unsafe fn c_slice<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[][..])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data, len))
+    }
+}
+
+unsafe fn c_slice_mut<'a>(data: *mut u8, len: usize) -> Option<&'a mut [u8]> {
+    if len == 0 {
+        Some(&mut [][..])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts_mut(data, len))
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoState {
+    Init,
+    WaitInitial,
+    Handshaking,
+    Connected,
+    Confirmed,
+    Closing,
+    Draining,
+    Closed,
+}
+
+impl From<&State> for NeqoState {
+    fn from(state: &State) -> Self {
+        match state {
+            State::Init => Self::Init,
+            State::WaitInitial => Self::WaitInitial,
+            State::Handshaking => Self::Handshaking,
+            State::Connected => Self::Connected,
+            State::Confirmed => Self::Confirmed,
+            State::Closing { .. } => Self::Closing,
+            State::Draining { .. } => Self::Draining,
+            State::Closed(_) => Self::Closed,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoOutputType {
+    /// The connection is done; there is nothing more to wait for.
+    None,
+    /// Send a datagram to the server.
+    Datagram,
+    /// Call again after `timeout_us`, or sooner if a datagram arrives.
+    Callback,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct NeqoOutput {
+    pub output_type: NeqoOutputType,
+    /// The datagram, which is only valid until the connection is next used.
+    pub data: *const u8,
+    pub len: usize,
+    pub timeout_us: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeqoEventType {
+    /// Check the server certificate, then call `neqo_connection_authenticated`.
+    AuthenticationNeeded,
+    NewStream,
+    SendStreamWritable,
+    RecvStreamReadable,
+    RecvStreamReset,
+    SendStreamStopSending,
+    SendStreamComplete,
+    SendStreamCreatable,
+    StateChange,
+    ZeroRttRejected,
+    Datagram,
+    EchAccepted,
+    /// The server rejected ECH.  `data` holds the configurations to retry with.
+    EchRejected,
+    /// There is a new resumption token, which `data` holds.
+    ResumptionToken,
+}
+
+/// An event.  Fields that don't apply to the type of event are zero.
+#[repr(C)]
+#[derive(Debug)]
+pub struct NeqoEvent {
+    pub event_type: NeqoEventType,
+    pub stream_id: u64,
+    pub app_error: AppError,
+    /// For `SendStreamCreatable`, whether bidirectional streams can be made.
+    pub bidi: bool,
+    /// For `StateChange`, the new state.
+    pub state: NeqoState,
+    /// For `Datagram`, the contents, for `EchRejected`, the retry
+    /// configurations, and for `ResumptionToken`, the token.  These are only
+    /// valid until the connection is next used.
+    pub data: *const u8,
+    pub len: usize,
+}
+
+impl NeqoEvent {
+    fn new(event_type: NeqoEventType) -> Self {
+        Self {
+            event_type,
+            stream_id: 0,
+            app_error: 0,
+            bidi: false,
+            state: NeqoState::Init,
+            data: ptr::null(),
+            len: 0,
+        }
+    }
+
+    fn stream(event_type: NeqoEventType, stream_id: u64) -> Self {
+        Self {
+            stream_id,
+            ..Self::new(event_type)
+        }
+    }
+}
+
+/// A client connection, which is only used through a pointer.
+pub struct NeqoConnection {
+    conn: Connection,
+    local: SocketAddr,
+    remote: SocketAddr,
+    /// The datagram that `neqo_connection_process_output` last returned.
+    output: Option<Datagram>,
+    /// The contents of the last `Datagram` event.
+    event_data: Vec<u8>,
+}
+
+impl NeqoConnection {
+    fn event(&mut self, event: ConnectionEvent) -> NeqoEvent {
+        match event {
+            ConnectionEvent::AuthenticationNeeded => {
+                NeqoEvent::new(NeqoEventType::AuthenticationNeeded)
+            }
+            ConnectionEvent::NewStream { stream_id } => {
+                NeqoEvent::stream(NeqoEventType::NewStream, stream_id.as_u64())
+            }
+            ConnectionEvent::SendStreamWritable { stream_id } => {
+                NeqoEvent::stream(NeqoEventType::SendStreamWritable, stream_id.as_u64())
+            }
+            ConnectionEvent::RecvStreamReadable { stream_id } => {
+                NeqoEvent::stream(NeqoEventType::RecvStreamReadable, stream_id)
+            }
+            ConnectionEvent::RecvStreamReset {
+                stream_id,
+                app_error,
+            } => NeqoEvent {
+                app_error,
+                ..NeqoEvent::stream(NeqoEventType::RecvStreamReset, stream_id)
+            },
+            ConnectionEvent::SendStreamStopSending {
+                stream_id,
+                app_error,
+            } => NeqoEvent {
+                app_error,
+                ..NeqoEvent::stream(NeqoEventType::SendStreamStopSending, stream_id)
+            },
+            ConnectionEvent::SendStreamComplete { stream_id } => {
+                NeqoEvent::stream(NeqoEventType::SendStreamComplete, stream_id)
+            }
+            ConnectionEvent::SendStreamCreatable { stream_type } => NeqoEvent {
+                bidi: stream_type == StreamType::BiDi,
+                ..NeqoEvent::new(NeqoEventType::SendStreamCreatable)
+            },
+            ConnectionEvent::StateChange(state) => NeqoEvent {
+                state: NeqoState::from(&state),
+                ..NeqoEvent::new(NeqoEventType::StateChange)
+            },
+            ConnectionEvent::ZeroRttRejected => NeqoEvent::new(NeqoEventType::ZeroRttRejected),
+            ConnectionEvent::Datagram(data) => {
+                self.event_data = data;
+                NeqoEvent {
+                    data: self.event_data.as_ptr(),
+                    len: self.event_data.len(),
+                    ..NeqoEvent::new(NeqoEventType::Datagram)
+                }
+            }
+            ConnectionEvent::EchAccepted => NeqoEvent::new(NeqoEventType::EchAccepted),
+            ConnectionEvent::EchRejected(retry_configs) => {
+                self.event_data = retry_configs;
+                NeqoEvent {
+                    data: self.event_data.as_ptr(),
+                    len: self.event_data.len(),
+                    ..NeqoEvent::new(NeqoEventType::EchRejected)
+                }
+            }
+            ConnectionEvent::ResumptionToken(token) => {
+                self.event_data = token;
+                NeqoEvent {
+                    data: self.event_data.as_ptr(),
+                    len: self.event_data.len(),
+                    ..NeqoEvent::new(NeqoEventType::ResumptionToken)
+                }
+            }
+        }
+    }
+}
+
+impl ::std::fmt::Debug for NeqoConnection {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "NeqoConnection {}", self.conn)
+    }
+}
+
+/// Initialize NSS without a certificate database.  Call this, or
+/// `neqo_init_db`, once before making a connection.
+#[no_mangle]
+pub extern "C" fn neqo_init() -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        neqo_crypto::init();
+        NeqoStatus::Ok
+    })
+}
+
+/// Initialize NSS with the certificate database in `dir`.
+///
+/// # Safety
+///
+/// `dir` is a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_init_db(dir: *const c_char) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        if let Some(dir) = c_str(dir) {
+            neqo_crypto::init_db(dir);
+            NeqoStatus::Ok
+        } else {
+            NeqoStatus::InvalidArgument
+        }
+    })
+}
+
+/// Make a client connection.  `alpn` is a comma-separated list of protocols,
+/// and the addresses are like "127.0.0.1:443" or "[::1]:443".  `local_addr`
+/// has to be the address that datagrams from the server arrive on.
+///
+/// # Safety
+///
+/// The strings are nul-terminated.  On success, `conn` is set to a connection
+/// that has to be released with `neqo_connection_free`.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_new_client(
+    server_name: *const c_char,
+    alpn: *const c_char,
+    local_addr: *const c_char,
+    remote_addr: *const c_char,
+    conn: *mut *mut NeqoConnection,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let conn = out!(conn);
+        let (server_name, alpn, local, remote) = match (
+            c_str(server_name),
+            c_str(alpn),
+            c_addr(local_addr),
+            c_addr(remote_addr),
+        ) {
+            (Some(s), Some(a), Some(l), Some(r)) => (s, a, l, r),
+            _ => return NeqoStatus::InvalidArgument,
+        };
+        let protocols = alpn.split(',').collect::<Vec<_>>();
+        match FixedConnectionIdManager::new(0).and_then(|cid_mgr| {
+            Connection::new_client(
+                server_name,
+                &protocols,
+                Rc::new(RefCell::new(cid_mgr)),
+                local,
+                remote,
+                QuicVersion::default(),
+            )
+        }) {
+            Ok(c) => {
+                conn.write(Box::into_raw(Box::new(NeqoConnection {
+                    conn: c,
+                    local,
+                    remote,
+                    output: None,
+                    event_data: Vec::new(),
+                })));
+                NeqoStatus::Ok
+            }
+            Err(e) => NeqoStatus::from(&e),
+        }
+    })
+}
+
+/// Release a connection.  This doesn't send anything, so close it and wait
+/// for `NeqoOutputType::None` first to tell the server.
+///
+/// # Safety
+///
+/// `conn` came from `neqo_connection_new_client`, or is null.  It can't be used
+/// after this.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_free(conn: *mut NeqoConnection) {
+    guard((), || {
+        if !conn.is_null() {
+            drop(Box::from_raw(conn));
+        }
+    })
+}
+
+/// Pass in a datagram that arrived from the server.
+///
+/// # Safety
+///
+/// `conn` is valid, and `data` points at `len` bytes, or is null if `len` is
+/// zero.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_process_input(
+    conn: *mut NeqoConnection,
+    data: *const u8,
+    len: usize,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let c = arg!(conn);
+        let data = match c_slice(data, len) {
+            Some(d) => d,
+            None => return NeqoStatus::InvalidArgument,
+        };
+        let d = Datagram::new(c.remote, c.local, data);
+        c.conn.process_input(d, Instant::now());
+        NeqoStatus::Ok
+    })
+}
+
+/// Get the next thing to do.  Call this until it returns a callback, which
+/// is after every call to `neqo_connection_process_input` and any change to a
+/// stream.  Datagrams go to the remote address of the connection.
+///
+/// # Safety
+///
+/// `conn` and `out` are valid.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_process_output(
+    conn: *mut NeqoConnection,
+    out: *mut NeqoOutput,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let c = arg!(conn);
+        let out = out!(out);
+        out.write(match c.conn.process_output(Instant::now()) {
+            Output::Datagram(d) => {
+                c.output = Some(d);
+                let d = c.output.as_ref().unwrap();
+                NeqoOutput {
+                    output_type: NeqoOutputType::Datagram,
+                    data: d.as_ptr(),
+                    len: d.len(),
+                    timeout_us: 0,
+                }
+            }
+            Output::Callback(t) => NeqoOutput {
+                output_type: NeqoOutputType::Callback,
+                data: ptr::null(),
+                len: 0,
+                timeout_us: u64::try_from(t.as_micros()).unwrap_or(u64::MAX),
+            },
+            Output::None => NeqoOutput {
+                output_type: NeqoOutputType::None,
+                data: ptr::null(),
+                len: 0,
+                timeout_us: 0,
+            },
+        });
+        NeqoStatus::Ok
+    })
+}
+
+/// Get the next event.  This returns false if there are none.
+///
+/// # Safety
+///
+/// `conn` and `event` are valid.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_next_event(
+    conn: *mut NeqoConnection,
+    event: *mut NeqoEvent,
+) -> bool {
+    guard(false, || match conn.as_mut() {
+        Some(c) if !event.is_null() => {
+            if let Some(e) = c.conn.next_event() {
+                event.write(c.event(e));
+                true
+            } else {
+                false
+            }
+        }
+        _ => false,
+    })
+}
+
+/// Report the result of checking the server certificate, as an NSPR error
+/// code.  Zero means that the certificate is good.
+///
+/// # Safety
+///
+/// `conn` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_authenticated(
+    conn: *mut NeqoConnection,
+    error: i32,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let c = arg!(conn);
+        c.conn
+            .authenticated(AuthenticationStatus::from(error), Instant::now());
+        NeqoStatus::Ok
+    })
+}
+
+/// Start closing the connection.  `reason` can be null.
+///
+/// # Safety
+///
+/// `conn` is valid, and `reason` is null or a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_close(
+    conn: *mut NeqoConnection,
+    app_error: AppError,
+    reason: *const c_char,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let c = arg!(conn);
+        let reason = if reason.is_null() {
+            ""
+        } else if let Some(r) = c_str(reason) {
+            r
+        } else {
+            return NeqoStatus::InvalidArgument;
+        };
+        c.conn.close(Instant::now(), app_error, reason);
+        NeqoStatus::Ok
+    })
+}
+
+/// Get the state of the connection.
+///
+/// # Safety
+///
+/// `conn` and `state` are valid.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_state(
+    conn: *mut NeqoConnection,
+    state: *mut NeqoState,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let c = arg!(conn);
+        out!(state).write(NeqoState::from(c.conn.state()));
+        NeqoStatus::Ok
+    })
+}
+
+/// Get the error that closed the connection.  `app` is set if the error came
+/// from an application.  This returns `InvalidState` for an open connection.
+///
+/// # Safety
+///
+/// `conn`, `app`, and `code` are valid.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_close_error(
+    conn: *mut NeqoConnection,
+    app: *mut bool,
+    code: *mut u64,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let c = arg!(conn);
+        let app = out!(app);
+        let code = out!(code);
+        let error = match c.conn.state() {
+            State::Closing { error, .. } | State::Draining { error, .. } | State::Closed(error) => {
+                error
+            }
+            _ => return NeqoStatus::InvalidState,
+        };
+        match error {
+            ConnectionError::Transport(e) => {
+                app.write(false);
+                code.write(e.code());
+            }
+            ConnectionError::Application(e) => {
+                app.write(true);
+                code.write(*e);
+            }
+        }
+        NeqoStatus::Ok
+    })
+}
+
+/// Make a stream.
+///
+/// # Safety
+///
+/// `conn` and `stream_id` are valid.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_create(
+    conn: *mut NeqoConnection,
+    bidi: bool,
+    stream_id: *mut u64,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let c = arg!(conn);
+        let stream_id = out!(stream_id);
+        let st = if bidi {
+            StreamType::BiDi
+        } else {
+            StreamType::UniDi
+        };
+        match c.conn.stream_create(st) {
+            Ok(id) => {
+                stream_id.write(id);
+                NeqoStatus::Ok
+            }
+            Err(e) => NeqoStatus::from(&e),
+        }
+    })
+}
+
+/// Queue data on a stream.  `written` is set to how much was taken, which can
+/// be less than `len`, or zero; more can be sent after `SendStreamWritable`.
+///
+/// # Safety
+///
+/// `conn` and `written` are valid, and `data` points at `len` bytes, or is null
+/// if `len` is zero.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_send(
+    conn: *mut NeqoConnection,
+    stream_id: u64,
+    data: *const u8,
+    len: usize,
+    written: *mut usize,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let c = arg!(conn);
+        let written = out!(written);
+        let data = match c_slice(data, len) {
+            Some(d) => d,
+            None => return NeqoStatus::InvalidArgument,
+        };
+        match c.conn.stream_send(stream_id, data) {
+            Ok(sz) => {
+                written.write(sz);
+                NeqoStatus::Ok
+            }
+            Err(e) => NeqoStatus::from(&e),
+        }
+    })
+}
+
+/// Read from a stream into `buf`.  `fin` is set when the end of the stream is
+/// read, after which the stream can't be read again.
+///
+/// # Safety
+///
+/// `conn`, `read`, and `fin` are valid, and `buf` points at `len` bytes, or is
+/// null if `len` is zero.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_recv(
+    conn: *mut NeqoConnection,
+    stream_id: u64,
+    buf: *mut u8,
+    len: usize,
+    read: *mut usize,
+    fin: *mut bool,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        let c = arg!(conn);
+        let read = out!(read);
+        let fin = out!(fin);
+        let buf = match c_slice_mut(buf, len) {
+            Some(b) => b,
+            None => return NeqoStatus::InvalidArgument,
+        };
+        match c.conn.stream_recv(stream_id, buf) {
+            Ok((sz, f)) => {
+                read.write(sz);
+                fin.write(f);
+                NeqoStatus::Ok
+            }
+            Err(e) => NeqoStatus::from(&e),
+        }
+    })
+}
+
+/// Finish sending on a stream.
+///
+/// # Safety
+///
+/// `conn` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_close_send(
+    conn: *mut NeqoConnection,
+    stream_id: u64,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        status(arg!(conn).conn.stream_close_send(stream_id))
+    })
+}
+
+/// Abandon sending on a stream.
+///
+/// # Safety
+///
+/// `conn` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_reset_send(
+    conn: *mut NeqoConnection,
+    stream_id: u64,
+    app_error: AppError,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        status(arg!(conn).conn.stream_reset_send(stream_id, app_error))
+    })
+}
+
+/// Ask the server to stop sending on a stream.
+///
+/// # Safety
+///
+/// `conn` is valid.
+#[no_mangle]
+pub unsafe extern "C" fn neqo_connection_stream_stop_sending(
+    conn: *mut NeqoConnection,
+    stream_id: u64,
+    app_error: AppError,
+) -> NeqoStatus {
+    guard(NeqoStatus::Error, || {
+        status(arg!(conn).conn.stream_stop_sending(stream_id, app_error))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use test_fixture::fixture_init;
+
+    fn client() -> *mut NeqoConnection {
+        fixture_init();
+        let name = CString::new("example.com").unwrap();
+        let alpn = CString::new("h3,alpn").unwrap();
+        let local = CString::new("127.0.0.1:4433").unwrap();
+        let remote = CString::new("127.0.0.1:443").unwrap();
+        let mut conn = ptr::null_mut();
+        let res = unsafe {
+            neqo_connection_new_client(
+                name.as_ptr(),
+                alpn.as_ptr(),
+                local.as_ptr(),
+                remote.as_ptr(),
+                &mut conn,
+            )
+        };
+        assert_eq!(res, NeqoStatus::Ok);
+        assert!(!conn.is_null());
+        conn
+    }
+
+    #[test]
+    fn initial() {
+        let conn = client();
+        let mut out = MaybeUninit::<NeqoOutput>::uninit();
+        unsafe {
+            assert_eq!(
+                neqo_connection_process_output(conn, out.as_mut_ptr()),
+                NeqoStatus::Ok
+            );
+            let out = out.assume_init();
+            assert_eq!(out.output_type, NeqoOutputType::Datagram);
+            assert!(out.len >= 1200);
+            // The first byte is that of a long header.
+            assert_eq!(*out.data & 0x80, 0x80);
+
+            let mut state = NeqoState::Init;
+            assert_eq!(neqo_connection_state(conn, &mut state), NeqoStatus::Ok);
+            assert_eq!(state, NeqoState::WaitInitial);
+            neqo_connection_free(conn);
+        }
+    }
+
+    #[test]
+    fn bad_arguments() {
+        let name = CString::new("example.com").unwrap();
+        let bad = CString::new("not an address").unwrap();
+        let mut conn = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                neqo_connection_new_client(
+                    name.as_ptr(),
+                    name.as_ptr(),
+                    bad.as_ptr(),
+                    bad.as_ptr(),
+                    &mut conn,
+                ),
+                NeqoStatus::InvalidArgument
+            );
+            assert!(conn.is_null());
+            assert_eq!(
+                neqo_connection_process_input(ptr::null_mut(), ptr::null(), 0),
+                NeqoStatus::InvalidArgument
+            );
+        }
+    }
+
+    #[test]
+    fn streams() {
+        let conn = client();
+        let mut stream_id = 0;
+        let mut read = 0;
+        let mut fin = false;
+        let mut buf = [0; 16];
+        unsafe {
+            // No streams before the handshake.
+            assert_eq!(
+                neqo_connection_stream_create(conn, true, &mut stream_id),
+                NeqoStatus::InvalidState
+            );
+            assert_eq!(
+                neqo_connection_stream_recv(
+                    conn,
+                    0,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut read,
+                    &mut fin
+                ),
+                NeqoStatus::InvalidStream
+            );
+            // A null buffer is fine if it is empty.
+            assert_eq!(
+                neqo_connection_stream_recv(conn, 0, ptr::null_mut(), 0, &mut read, &mut fin),
+                NeqoStatus::InvalidStream
+            );
+            assert_eq!(
+                neqo_connection_stream_recv(conn, 0, ptr::null_mut(), 1, &mut read, &mut fin),
+                NeqoStatus::InvalidArgument
+            );
+            neqo_connection_free(conn);
+        }
+    }
+}
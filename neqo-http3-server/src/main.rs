@@ -11,12 +11,13 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::process::exit;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
-use mio::net::UdpSocket;
+use mio::unix::EventedFd;
 use mio::{Events, Poll, PollOpt, Ready, Token};
 use mio_extras::timer::{Builder, Timeout, Timer};
 use structopt::StructOpt;
@@ -26,6 +27,7 @@ use neqo_crypto::{init_db, AntiReplay};
 use neqo_http3::{Error, Http3Server, Http3ServerEvent};
 use neqo_qpack::QpackSettings;
 use neqo_transport::{FixedConnectionIdManager, Output};
+use neqo_udp::{RecvBuf, Socket};
 
 const TIMER_TOKEN: Token = Token(0xffff_ffff);
 
@@ -77,6 +79,14 @@ struct Args {
     #[structopt(name = "qlog-dir", long)]
     /// Enable QLOG logging and QLOG traces to this directory
     qlog_dir: Option<PathBuf>,
+
+    #[structopt(name = "anti-replay-window", long, default_value = "10")]
+    /// The anti-replay window for 0-RTT, in seconds
+    anti_replay_window: u64,
+
+    #[structopt(name = "no-0rtt", long)]
+    /// Reject 0-RTT
+    no_0rtt: bool,
 }
 
 impl Args {
@@ -136,16 +146,12 @@ fn process_events(server: &mut Http3Server) {
     }
 }
 
-fn emit_packets(sockets: &mut Vec<UdpSocket>, out_dgrams: &HashMap<SocketAddr, Vec<Datagram>>) {
+fn emit_packets(sockets: &[Socket], out_dgrams: &HashMap<SocketAddr, Vec<Datagram>>) {
     for s in sockets {
-        if let Some(dgrams) = out_dgrams.get(&s.local_addr().unwrap()) {
-            for d in dgrams {
-                let sent = s
-                    .send_to(d, &d.destination())
-                    .expect("Error sending datagram");
-                if sent != d.len() {
-                    eprintln!("Unable to send all {} bytes of datagram", d.len());
-                }
+        if let Some(dgrams) = out_dgrams.get(&s.local_addr()) {
+            let mut sent = 0;
+            while sent < dgrams.len() {
+                sent += s.send(&dgrams[sent..]).expect("Error sending datagram");
             }
         }
     }
@@ -200,23 +206,17 @@ fn main() -> Result<(), io::Error> {
     poll.register(&timer, TIMER_TOKEN, Ready::readable(), PollOpt::edge())?;
 
     for (i, host) in hosts.iter().enumerate() {
-        let socket = match UdpSocket::bind(&host) {
+        let socket = match Socket::bind(*host) {
             Err(err) => {
                 eprintln!("Unable to bind UDP socket: {}", err);
                 exit(1)
             }
             Ok(s) => s,
         };
+        socket.socket().set_nonblocking(true)?;
+        let local_addr = socket.local_addr();
 
-        let local_addr = match socket.local_addr() {
-            Err(err) => {
-                eprintln!("Socket local address not bound: {}", err);
-                exit(1)
-            }
-            Ok(s) => s,
-        };
-
-        let res = socket.only_v6();
+        let res = socket.socket().only_v6();
         let also_v4 = if res.is_ok() && !res.unwrap() {
             " as well as V4"
         } else {
@@ -228,7 +228,7 @@ fn main() -> Result<(), io::Error> {
         );
 
         poll.register(
-            &socket,
+            &EventedFd(&socket.as_raw_fd()),
             Token(i),
             Ready::readable() | Ready::writable(),
             PollOpt::edge(),
@@ -243,9 +243,16 @@ fn main() -> Result<(), io::Error> {
                         Instant::now(),
                         &[args.key.clone()],
                         &[args.alpn.clone()],
-                        AntiReplay::new(Instant::now(), Duration::from_secs(10), 7, 14)
-                            .expect("unable to setup anti-replay"),
-                        Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+                        AntiReplay::new(
+                            Instant::now(),
+                            Duration::from_secs(args.anti_replay_window),
+                            7,
+                            14,
+                        )
+                        .expect("unable to setup anti-replay"),
+                        Rc::new(RefCell::new(
+                            FixedConnectionIdManager::new(10).expect("valid connection ID length"),
+                        )),
                         QpackSettings {
                             max_table_size_encoder: args.max_table_size_encoder,
                             max_table_size_decoder: args.max_table_size_decoder,
@@ -254,6 +261,7 @@ fn main() -> Result<(), io::Error> {
                     )
                     .expect("We cannot make a server!");
                     svr.set_qlog_dir(args.qlog_dir.clone());
+                    svr.set_allow_0rtt(!args.no_0rtt);
                     svr
                 },
                 None,
@@ -261,7 +269,7 @@ fn main() -> Result<(), io::Error> {
         );
     }
 
-    let buf = &mut [0u8; 2048];
+    let mut buf = RecvBuf::new();
 
     let mut events = Events::with_capacity(1024);
 
@@ -273,16 +281,14 @@ fn main() -> Result<(), io::Error> {
                 while let Some(inx) = timer.poll() {
                     if let Some(socket) = sockets.get(inx) {
                         qinfo!("Timer expired for {:?}", socket);
-                        if let Some((server, svr_timeout)) =
-                            servers.get_mut(&socket.local_addr().unwrap())
-                        {
+                        if let Some((server, svr_timeout)) = servers.get_mut(&socket.local_addr()) {
                             process(
                                 server,
                                 svr_timeout,
                                 inx,
                                 None,
                                 &mut out_dgrams
-                                    .entry(socket.local_addr().unwrap())
+                                    .entry(socket.local_addr())
                                     .or_insert_with(Vec::new),
                                 &mut timer,
                             );
@@ -290,42 +296,38 @@ fn main() -> Result<(), io::Error> {
                     }
                 }
             } else if let Some(socket) = sockets.get(event.token().0) {
-                let local_addr = hosts[event.token().0];
-
                 if !event.readiness().is_readable() {
                     continue;
                 }
 
                 loop {
-                    let (sz, remote_addr) = match socket.recv_from(&mut buf[..]) {
+                    let dgrams = match socket.recv(&mut buf) {
                         Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
                         Err(err) => {
                             eprintln!("UDP recv error: {:?}", err);
                             exit(1);
                         }
-                        Ok(res) => res,
+                        Ok(dgrams) => dgrams,
                     };
 
-                    if sz == buf.len() {
-                        eprintln!("Might have received more than {} bytes", buf.len());
-                    }
-
-                    if sz == 0 {
-                        eprintln!("zero length datagram received?");
-                    } else if let Some((server, svr_timeout)) =
-                        servers.get_mut(&socket.local_addr().unwrap())
-                    {
+                    if let Some((server, svr_timeout)) = servers.get_mut(&socket.local_addr()) {
                         let out = out_dgrams
-                            .entry(socket.local_addr().unwrap())
+                            .entry(socket.local_addr())
                             .or_insert_with(Vec::new);
-                        process(
-                            server,
-                            svr_timeout,
-                            event.token().0,
-                            Some(Datagram::new(remote_addr, local_addr, &buf[..sz])),
-                            out,
-                            &mut timer,
-                        );
+                        for dgram in dgrams {
+                            if dgram.is_empty() {
+                                eprintln!("zero length datagram received?");
+                                continue;
+                            }
+                            process(
+                                server,
+                                svr_timeout,
+                                event.token().0,
+                                Some(dgram),
+                                out,
+                                &mut timer,
+                            );
+                        }
                         process_events(server);
                         process(server, svr_timeout, event.token().0, None, out, &mut timer);
                     }
@@ -333,6 +335,6 @@ fn main() -> Result<(), io::Error> {
             }
         }
 
-        emit_packets(&mut sockets, &out_dgrams);
+        emit_packets(&sockets, &out_dgrams);
     }
 }
@@ -7,6 +7,7 @@
 #![allow(clippy::module_name_repetitions)]
 
 use crate::connection::Http3State;
+use crate::headers_checks;
 use crate::recv_message::RecvMessageEvents;
 use crate::send_message::SendMessageEvents;
 use crate::Header;
@@ -25,6 +26,17 @@ pub enum Http3ClientEvent {
         headers: Option<Vec<Header>>,
         fin: bool,
     },
+    /// Headers of an informational (1xx) response, like 103 Early Hints.
+    /// A `HeaderReady` event with the final response follows.
+    InformationalHeaders {
+        stream_id: u64,
+        headers: Vec<Header>,
+    },
+    /// Trailers that follow the response body.
+    Trailers {
+        stream_id: u64,
+        headers: Vec<Header>,
+    },
     /// A stream can accept new data.
     DataWritable { stream_id: u64 },
     /// New bytes available for reading.
@@ -41,8 +53,37 @@ pub enum Http3ClientEvent {
     AuthenticationNeeded,
     /// Zero Rtt has been rejected.
     ZeroRttRejected,
+    /// The server accepted encrypted client hello (ECH).
+    EchAccepted,
+    /// The server rejected ECH and the connection is closing.  A new
+    /// connection can use these configurations instead; if there are none,
+    /// it shouldn't use ECH.
+    EchRejected(Vec<u8>),
+    /// There is a new resumption token, which is what
+    /// `Http3Client::resumption_token` returns.
+    ResumptionToken(Vec<u8>),
     /// Client has received a GOAWAY frame
     GoawayReceived,
+    /// The server will not process the request on `stream_id`, because it
+    /// came after a GOAWAY.  A `Reset` event for the stream comes first.
+    /// The server did nothing with the request, so it can be sent again,
+    /// with the same `headers`, on a new connection.
+    RequestRejected {
+        stream_id: u64,
+        headers: Vec<Header>,
+    },
+    /// An HTTP datagram was received for a request.
+    Datagram { stream_id: u64, data: Vec<u8> },
+    /// The server responded to a WebTransport session request.  The session
+    /// is established if `status` is 2xx.
+    WebTransportSession { session_id: u64, status: u16 },
+    /// The server opened a stream for a WebTransport session.  Data that
+    /// follows the session ID may already be available to read.
+    WebTransportNewStream { stream_id: u64, session_id: u64 },
+    /// A stream of a WebTransport session has data to read.
+    WebTransportDataReadable { stream_id: u64 },
+    /// A datagram was received for a WebTransport session.
+    WebTransportDatagram { session_id: u64, data: Vec<u8> },
     /// Connection state change.
     StateChange(Http3State),
 }
@@ -62,12 +103,59 @@ impl RecvMessageEvents for Http3ClientEvents {
         });
     }
 
+    /// Add a new `InformationalHeaders` event.
+    fn informational_headers(&self, stream_id: u64, headers: Vec<Header>) {
+        self.insert(Http3ClientEvent::InformationalHeaders { stream_id, headers });
+    }
+
+    /// Add a new `Trailers` event.
+    fn trailers_ready(&self, stream_id: u64, headers: Vec<Header>) {
+        self.insert(Http3ClientEvent::Trailers { stream_id, headers });
+    }
+
     /// Add a new `DataReadable` event
     fn data_readable(&self, stream_id: u64) {
         self.insert(Http3ClientEvent::DataReadable { stream_id });
     }
 }
 
+/// Reports the response to a WebTransport session request as a
+/// `WebTransportSession` event.  Everything else is passed on.
+#[derive(Debug)]
+pub(crate) struct WebTransportSessionEvents {
+    events: Http3ClientEvents,
+}
+
+impl WebTransportSessionEvents {
+    pub fn new(events: Http3ClientEvents) -> Self {
+        Self { events }
+    }
+}
+
+impl RecvMessageEvents for WebTransportSessionEvents {
+    fn header_ready(&self, stream_id: u64, headers: Option<Vec<Header>>, fin: bool) {
+        match headers.as_ref().and_then(|h| headers_checks::status(h)) {
+            Some(status) => self.events.insert(Http3ClientEvent::WebTransportSession {
+                session_id: stream_id,
+                status,
+            }),
+            None => self.events.header_ready(stream_id, headers, fin),
+        }
+    }
+
+    fn informational_headers(&self, stream_id: u64, headers: Vec<Header>) {
+        self.events.informational_headers(stream_id, headers);
+    }
+
+    fn trailers_ready(&self, stream_id: u64, trailers: Vec<Header>) {
+        self.events.trailers_ready(stream_id, trailers);
+    }
+
+    fn data_readable(&self, stream_id: u64) {
+        self.events.data_readable(stream_id);
+    }
+}
+
 impl SendMessageEvents for Http3ClientEvents {
     /// Add a new `DataWritable` event.
     fn data_writable(&self, stream_id: u64) {
@@ -108,12 +196,56 @@ impl Http3ClientEvents {
         self.insert(Http3ClientEvent::ZeroRttRejected);
     }
 
+    /// Add a new `EchAccepted` event.
+    pub(crate) fn ech_accepted(&self) {
+        self.insert(Http3ClientEvent::EchAccepted);
+    }
+
+    /// Add a new `EchRejected` event.
+    pub(crate) fn ech_rejected(&self, retry_configs: Vec<u8>) {
+        self.insert(Http3ClientEvent::EchRejected(retry_configs));
+    }
+
+    /// Add a new `ResumptionToken` event, replacing any older one.
+    pub(crate) fn resumption_token(&self, token: Vec<u8>) {
+        self.remove(|evt| matches!(evt, Http3ClientEvent::ResumptionToken(_)));
+        self.insert(Http3ClientEvent::ResumptionToken(token));
+    }
+
+    /// Add a new `Datagram` event.
+    pub(crate) fn datagram(&self, stream_id: u64, data: Vec<u8>) {
+        self.insert(Http3ClientEvent::Datagram { stream_id, data });
+    }
+
+    /// Add a new `WebTransportNewStream` event.
+    pub(crate) fn webtransport_new_stream(&self, stream_id: u64, session_id: u64) {
+        self.insert(Http3ClientEvent::WebTransportNewStream {
+            stream_id,
+            session_id,
+        });
+    }
+
+    /// Add a new `WebTransportDataReadable` event.
+    pub(crate) fn webtransport_data_readable(&self, stream_id: u64) {
+        self.insert(Http3ClientEvent::WebTransportDataReadable { stream_id });
+    }
+
+    /// Add a new `WebTransportDatagram` event.
+    pub(crate) fn webtransport_datagram(&self, session_id: u64, data: Vec<u8>) {
+        self.insert(Http3ClientEvent::WebTransportDatagram { session_id, data });
+    }
+
     /// Add a new `GoawayReceived` event.
     pub(crate) fn goaway_received(&self) {
         self.remove(|evt| matches!(evt, Http3ClientEvent::RequestsCreatable));
         self.insert(Http3ClientEvent::GoawayReceived);
     }
 
+    /// Add a new `RequestRejected` event.
+    pub(crate) fn request_rejected(&self, stream_id: u64, headers: Vec<Header>) {
+        self.insert(Http3ClientEvent::RequestRejected { stream_id, headers });
+    }
+
     /// Take all events currently in the queue.
     pub(crate) fn events(&self) -> impl Iterator<Item = Http3ClientEvent> {
         self.events.replace(VecDeque::new()).into_iter()
@@ -161,10 +293,18 @@ impl Http3ClientEvents {
         self.remove(|evt| {
             matches!(evt,
                 Http3ClientEvent::HeaderReady { stream_id: x, .. }
+                | Http3ClientEvent::InformationalHeaders { stream_id: x, .. }
+                | Http3ClientEvent::Trailers { stream_id: x, .. }
+                | Http3ClientEvent::Datagram { stream_id: x, .. }
+                | Http3ClientEvent::WebTransportSession { session_id: x, .. }
+                | Http3ClientEvent::WebTransportNewStream { stream_id: x, .. }
+                | Http3ClientEvent::WebTransportDataReadable { stream_id: x }
+                | Http3ClientEvent::WebTransportDatagram { session_id: x, .. }
                 | Http3ClientEvent::DataWritable { stream_id: x }
                 | Http3ClientEvent::DataReadable { stream_id: x }
                 | Http3ClientEvent::NewPushStream { stream_id: x }
                 | Http3ClientEvent::Reset { stream_id: x, .. }
+                | Http3ClientEvent::RequestRejected { stream_id: x, .. }
                 | Http3ClientEvent::StopSending { stream_id: x, .. } if *x == stream_id)
         });
     }
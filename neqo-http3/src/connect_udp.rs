@@ -0,0 +1,119 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Proxying UDP in HTTP (CONNECT-UDP).  The tunnel is an extended CONNECT
+// request and UDP payloads are carried in HTTP datagrams, prefixed with a
+// context ID.
+
+use neqo_common::{Decoder, Encoder};
+
+/// The value of `:protocol` for a CONNECT-UDP request.
+pub const CONNECT_UDP_PROTOCOL: &str = "connect-udp";
+const MASQUE_UDP_PATH_PREFIX: &str = "/.well-known/masque/udp/";
+/// HTTP datagrams with this context ID carry a UDP payload.
+const UDP_PAYLOAD_CONTEXT_ID: u64 = 0;
+
+/// The `:path` of a CONNECT-UDP request for `target_host` and `target_port`.
+/// The colons of an IPv6 address are percent-encoded.
+#[must_use]
+pub fn masque_udp_path(target_host: &str, target_port: u16) -> String {
+    format!(
+        "{}{}/{}/",
+        MASQUE_UDP_PATH_PREFIX,
+        target_host.replace(':', "%3A"),
+        target_port
+    )
+}
+
+/// Get the target host and port from the `:path` of a CONNECT-UDP request.
+#[must_use]
+pub fn parse_masque_udp_path(path: &str) -> Option<(String, u16)> {
+    let mut parts = path.split('/');
+    // The path starts with `MASQUE_UDP_PATH_PREFIX`.
+    for expected in &["", ".well-known", "masque", "udp"] {
+        if parts.next() != Some(*expected) {
+            return None;
+        }
+    }
+    let host = parts.next().filter(|h| !h.is_empty())?;
+    let port = parts.next()?.parse().ok()?;
+    match (parts.next(), parts.next()) {
+        (None, None) | (Some(""), None) => {
+            Some((host.replace("%3A", ":").replace("%3a", ":"), port))
+        }
+        _ => None,
+    }
+}
+
+/// Make an HTTP datagram payload that carries `payload` as a UDP payload.
+#[must_use]
+pub fn encode_udp_payload(payload: &[u8]) -> Vec<u8> {
+    let mut enc = Encoder::with_capacity(payload.len() + 1);
+    enc.encode_varint(UDP_PAYLOAD_CONTEXT_ID);
+    enc.encode(payload);
+    enc.into()
+}
+
+/// Get the UDP payload from an HTTP datagram payload.  This returns `None`
+/// for datagrams with an unknown context ID, which are to be dropped.
+#[must_use]
+pub fn decode_udp_payload(data: &[u8]) -> Option<&[u8]> {
+    let mut dec = Decoder::from(data);
+    if dec.decode_varint()? == UDP_PAYLOAD_CONTEXT_ID {
+        Some(dec.decode_remainder())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_udp_payload, encode_udp_payload, masque_udp_path, parse_masque_udp_path};
+
+    #[test]
+    fn path() {
+        let path = masque_udp_path("192.0.2.6", 443);
+        assert_eq!(path, "/.well-known/masque/udp/192.0.2.6/443/");
+        assert_eq!(
+            parse_masque_udp_path(&path),
+            Some((String::from("192.0.2.6"), 443))
+        );
+    }
+
+    #[test]
+    fn path_ipv6() {
+        let path = masque_udp_path("2001:db8::42", 53);
+        assert_eq!(path, "/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/");
+        assert_eq!(
+            parse_masque_udp_path(&path),
+            Some((String::from("2001:db8::42"), 53))
+        );
+    }
+
+    #[test]
+    fn bad_path() {
+        assert_eq!(parse_masque_udp_path("/"), None);
+        assert_eq!(parse_masque_udp_path("/.well-known/masque/udp//443/"), None);
+        assert_eq!(
+            parse_masque_udp_path("/.well-known/masque/udp/example.com/http/"),
+            None
+        );
+        assert_eq!(
+            parse_masque_udp_path("/.well-known/masque/udp/example.com/443/extra"),
+            None
+        );
+    }
+
+    #[test]
+    fn payload() {
+        let dgram = encode_udp_payload(&[1, 2, 3]);
+        assert_eq!(dgram, [0, 1, 2, 3]);
+        assert_eq!(decode_udp_payload(&dgram), Some(&[1, 2, 3][..]));
+        // Other context IDs are dropped.
+        assert_eq!(decode_udp_payload(&[2, 1, 2, 3]), None);
+        assert_eq!(decode_udp_payload(&[]), None);
+    }
+}
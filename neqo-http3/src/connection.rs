@@ -13,24 +13,39 @@ use crate::hsettings_frame::{HSetting, HSettingType, HSettings};
 use crate::recv_message::RecvMessage;
 use crate::send_message::SendMessage;
 use crate::stream_type_reader::NewStreamTypeReader;
-use neqo_common::{matches, qdebug, qerror, qinfo, qtrace, qwarn};
+use crate::webtransport::{self, SessionStream, StreamHeaderReader, WEBTRANSPORT_UNI_STREAM_TYPE};
+use neqo_common::{matches, qdebug, qerror, qinfo, qtrace, qwarn, Decoder, Encoder};
 use neqo_qpack::decoder::{QPackDecoder, QPACK_UNI_STREAM_TYPE_DECODER};
 use neqo_qpack::encoder::{QPackEncoder, QPACK_UNI_STREAM_TYPE_ENCODER};
 use neqo_qpack::QpackSettings;
 use neqo_transport::{AppError, CloseError, Connection, State, StreamType};
+use std::cmp::max;
 use std::collections::{BTreeSet, HashMap};
 use std::fmt::Debug;
 use std::mem;
+use std::time::{Duration, Instant};
 
 use crate::{Error, Res};
 
 const HTTP3_UNI_STREAM_TYPE_PUSH: u64 = 0x1;
 const QPACK_TABLE_SIZE_LIMIT: u64 = 1 << 30;
+/// The largest DATAGRAM frame accepted when HTTP datagrams are enabled.
+pub(crate) const LOCAL_MAX_DATAGRAM_FRAME_SIZE: u64 = 65535;
 
 pub(crate) enum HandleReadableOutput {
     NoOutput,
     PushStream,
     ControlFrames(Vec<HFrame>),
+    /// A malformed message has been received and its stream has been reset.
+    MalformedMessage,
+    /// The peer opened a stream that belongs to a WebTransport session.
+    WebTransportStream {
+        session_id: u64,
+    },
+    /// A stream that belongs to a WebTransport session has data to read.
+    WebTransportData {
+        session_id: u64,
+    },
 }
 
 #[derive(Debug)]
@@ -57,6 +72,30 @@ impl Http3State {
     }
 }
 
+/// Sends PING frames while there are no requests, so that the transport
+/// doesn't time out between requests.
+#[derive(Debug)]
+struct KeepAlive {
+    /// How long to keep an idle connection alive for.
+    budget: Duration,
+    /// When there stopped being requests, or `None` while there are requests.
+    idle_since: Option<Instant>,
+    last_ping: Option<Instant>,
+}
+
+impl KeepAlive {
+    /// When the next PING is due, if the budget allows for another one.
+    fn next_ping(&self, interval: Duration) -> Option<Instant> {
+        let idle_since = self.idle_since?;
+        let next = self.last_ping.map_or(idle_since, |t| max(t, idle_since)) + interval;
+        if next <= idle_since + self.budget {
+            Some(next)
+        } else {
+            None
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Http3Connection {
     pub state: Http3State,
@@ -67,9 +106,21 @@ pub(crate) struct Http3Connection {
     pub qpack_encoder: QPackEncoder,
     pub qpack_decoder: QPackDecoder,
     settings_state: Http3RemoteSettingsState,
+    /// Whether WebTransport is offered to the peer.
+    webtransport: bool,
+    /// Whether HTTP datagrams are offered to the peer.
+    datagrams: bool,
+    /// Whether extended CONNECT (`SETTINGS_ENABLE_CONNECT_PROTOCOL`) is offered to the peer.
+    extended_connect: bool,
     streams_have_data_to_send: BTreeSet<u64>,
     pub send_streams: HashMap<u64, SendMessage>,
     pub recv_streams: HashMap<u64, RecvMessage>,
+    /// WebTransport sessions, by the stream ID of their request.
+    webtransport_sessions: BTreeSet<u64>,
+    webtransport_streams: HashMap<u64, SessionStream>,
+    /// Streams that the peer opened for a session, whose session ID hasn't been read yet.
+    webtransport_new_streams: HashMap<u64, StreamHeaderReader>,
+    keep_alive: Option<KeepAlive>,
 }
 
 impl ::std::fmt::Display for Http3Connection {
@@ -95,10 +146,343 @@ impl Http3Connection {
             qpack_encoder: QPackEncoder::new(local_qpack_settings, true),
             qpack_decoder: QPackDecoder::new(local_qpack_settings),
             settings_state: Http3RemoteSettingsState::NotReceived,
+            webtransport: false,
+            datagrams: false,
+            extended_connect: false,
             streams_have_data_to_send: BTreeSet::new(),
             send_streams: HashMap::new(),
             recv_streams: HashMap::new(),
+            webtransport_sessions: BTreeSet::new(),
+            webtransport_streams: HashMap::new(),
+            webtransport_new_streams: HashMap::new(),
+            keep_alive: None,
+        }
+    }
+
+    /// Keep the transport alive while there are no requests, for up to `budget`.
+    pub fn set_keep_alive(&mut self, budget: Option<Duration>) {
+        self.keep_alive = budget.map(|budget| KeepAlive {
+            budget,
+            idle_since: None,
+            last_ping: None,
+        });
+    }
+
+    /// PINGs are sent often enough that the transport never reaches its idle timeout.
+    fn keep_alive_interval(conn: &Connection) -> Duration {
+        conn.idle_timeout() / 2
+    }
+
+    /// Send a PING if one is needed to keep the connection alive.
+    pub fn process_keep_alive(&mut self, conn: &mut Connection, now: Instant) {
+        if self.state != Http3State::Connected {
+            return;
+        }
+        let idle = self.send_streams.is_empty() && self.recv_streams.is_empty();
+        let ka = if let Some(ka) = &mut self.keep_alive {
+            ka
+        } else {
+            return;
+        };
+        if !idle {
+            ka.idle_since = None;
+            return;
         }
+        ka.idle_since.get_or_insert(now);
+        let due = ka
+            .next_ping(Self::keep_alive_interval(conn))
+            .map_or(false, |t| t <= now);
+        if due && conn.send_ping().is_ok() {
+            ka.last_ping = Some(now);
+            qdebug!([self], "Sending a keep-alive PING.");
+        }
+    }
+
+    /// When the next keep-alive PING is due.
+    pub fn next_keep_alive(&self, conn: &Connection) -> Option<Instant> {
+        if self.state != Http3State::Connected {
+            return None;
+        }
+        self.keep_alive
+            .as_ref()
+            .and_then(|ka| ka.next_ping(Self::keep_alive_interval(conn)))
+    }
+
+    /// Offer WebTransport to the peer.  This only has an effect before SETTINGS are sent.
+    pub fn set_webtransport(&mut self, enabled: bool) -> Res<()> {
+        if self.state != Http3State::Initializing {
+            return Err(Error::InvalidState);
+        }
+        self.webtransport = enabled;
+        Ok(())
+    }
+
+    /// Whether both endpoints support WebTransport.  WebTransport sessions use
+    /// extended CONNECT, so the peer has to enable that too.
+    pub fn webtransport_enabled(&self) -> bool {
+        let settings = match &self.settings_state {
+            Http3RemoteSettingsState::Received(s) | Http3RemoteSettingsState::ZeroRtt(s) => s,
+            Http3RemoteSettingsState::NotReceived => return false,
+        };
+        self.webtransport
+            && settings.get(HSettingType::EnableConnectProtocol) == 1
+            && settings.get(HSettingType::EnableWebTransport) == 1
+    }
+
+    /// Offer HTTP datagrams to the peer.  This only has an effect before SETTINGS are sent.
+    pub fn set_datagrams(&mut self, enabled: bool) -> Res<()> {
+        if self.state != Http3State::Initializing {
+            return Err(Error::InvalidState);
+        }
+        self.datagrams = enabled;
+        Ok(())
+    }
+
+    /// Whether both endpoints sent `SETTINGS_H3_DATAGRAM`.  Sending datagrams
+    /// also depends on the transport negotiating DATAGRAM frames.
+    pub fn datagrams_enabled(&self) -> bool {
+        let settings = match &self.settings_state {
+            Http3RemoteSettingsState::Received(s) | Http3RemoteSettingsState::ZeroRtt(s) => s,
+            Http3RemoteSettingsState::NotReceived => return false,
+        };
+        self.datagrams && settings.get(HSettingType::H3Datagram) == 1
+    }
+
+    /// Offer extended CONNECT to the peer.  This only has an effect before SETTINGS are sent.
+    pub fn set_extended_connect(&mut self, enabled: bool) -> Res<()> {
+        if self.state != Http3State::Initializing {
+            return Err(Error::InvalidState);
+        }
+        self.extended_connect = enabled;
+        Ok(())
+    }
+
+    /// Whether the peer allows extended CONNECT requests, i.e. requests with `:protocol`.
+    pub fn peer_extended_connect(&self) -> bool {
+        match &self.settings_state {
+            Http3RemoteSettingsState::Received(s) | Http3RemoteSettingsState::ZeroRtt(s) => {
+                s.get(HSettingType::EnableConnectProtocol) == 1
+            }
+            Http3RemoteSettingsState::NotReceived => false,
+        }
+    }
+
+    /// Send an HTTP datagram for the request on `stream_id`.  The datagram starts
+    /// with the quarter stream ID, which identifies the request.
+    pub fn send_datagram(&self, conn: &mut Connection, stream_id: u64, data: &[u8]) -> Res<()> {
+        if !self.datagrams_enabled() {
+            return Err(Error::Unavailable);
+        }
+        if !self.send_streams.contains_key(&stream_id)
+            && !self.recv_streams.contains_key(&stream_id)
+        {
+            return Err(Error::InvalidStreamId);
+        }
+        let mut enc = Encoder::default();
+        enc.encode_varint(stream_id / 4);
+        enc.encode(data);
+        conn.send_datagram(&enc)?;
+        Ok(())
+    }
+
+    /// Handle a datagram from the transport.  This returns the request stream ID
+    /// and the payload, or `None` if the datagram should be dropped.
+    pub fn handle_datagram<'a>(&self, dgram: &'a [u8]) -> Res<Option<(u64, &'a [u8])>> {
+        if matches!(self.settings_state, Http3RemoteSettingsState::NotReceived) {
+            // The datagram overtook the SETTINGS frame.
+            qinfo!([self], "Dropping a datagram received before SETTINGS.");
+            return Ok(None);
+        }
+        if !self.datagrams_enabled() {
+            return Err(Error::HttpDatagram);
+        }
+        let mut dec = Decoder::from(dgram);
+        let stream_id = dec
+            .decode_varint()
+            .and_then(|q| q.checked_mul(4))
+            .ok_or(Error::HttpDatagram)?;
+        if self.send_streams.contains_key(&stream_id) || self.recv_streams.contains_key(&stream_id)
+        {
+            Ok(Some((stream_id, dec.decode_remainder())))
+        } else {
+            qdebug!([self], "Dropping a datagram for stream {}.", stream_id);
+            Ok(None)
+        }
+    }
+
+    /// Start a WebTransport session on the request stream `session_id`.  The client
+    /// does this when it sends the request, the server when it accepts the request.
+    pub fn webtransport_add_session(&mut self, session_id: u64) {
+        self.webtransport_sessions.insert(session_id);
+    }
+
+    pub fn is_webtransport_session(&self, stream_id: u64) -> bool {
+        self.webtransport_sessions.contains(&stream_id)
+    }
+
+    /// Open a stream that belongs to the session `session_id`.  The stream starts
+    /// with the session ID, so this needs the peer to allow some data on new streams.
+    pub fn webtransport_create_stream(
+        &mut self,
+        conn: &mut Connection,
+        session_id: u64,
+        stream_type: StreamType,
+    ) -> Res<u64> {
+        if !self.is_webtransport_session(session_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        let stream_id = conn.stream_create(stream_type)?;
+        let header = webtransport::stream_header(stream_type, session_id);
+        if conn.stream_send(stream_id, &header)? != header.len() {
+            let _ = conn.stream_reset_send(stream_id, Error::HttpInternal.code());
+            return Err(Error::Unavailable);
+        }
+        qinfo!(
+            [self],
+            "New stream {} for WebTransport session {}.",
+            stream_id,
+            session_id
+        );
+        self.webtransport_streams
+            .insert(stream_id, SessionStream::new(session_id, stream_id, true));
+        Ok(stream_id)
+    }
+
+    /// Send data on a stream that belongs to a WebTransport session.
+    pub fn webtransport_send(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        buf: &[u8],
+    ) -> Res<usize> {
+        match self.webtransport_streams.get(&stream_id) {
+            Some(s) if s.can_send() => Ok(conn.stream_send(stream_id, buf)?),
+            _ => Err(Error::InvalidStreamId),
+        }
+    }
+
+    /// Read data from a stream that belongs to a WebTransport session.
+    pub fn webtransport_recv(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        buf: &mut [u8],
+    ) -> Res<(usize, bool)> {
+        let stream = match self.webtransport_streams.get_mut(&stream_id) {
+            Some(s) if s.can_recv() => s,
+            _ => return Err(Error::InvalidStreamId),
+        };
+        let (amount, fin) = conn.stream_recv(stream_id, buf)?;
+        if fin {
+            stream.recv_done();
+            if stream.done() {
+                self.webtransport_streams.remove(&stream_id);
+            }
+        }
+        Ok((amount, fin))
+    }
+
+    /// The peer sent STOP_SENDING for a stream that belongs to a WebTransport session.
+    /// This returns `false` if the stream is not one of those.
+    pub fn webtransport_stop_sending(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        app_err: AppError,
+    ) -> bool {
+        if let Some(s) = self.webtransport_streams.get_mut(&stream_id) {
+            let _ = conn.stream_reset_send(stream_id, app_err);
+            s.send_done();
+            if s.done() {
+                self.webtransport_streams.remove(&stream_id);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The peer opened a bidirectional stream that is not a request, which can only be
+    /// a stream for a WebTransport session.
+    pub fn webtransport_new_bidi_stream(&mut self, stream_id: u64) {
+        self.webtransport_new_streams
+            .insert(stream_id, StreamHeaderReader::new(StreamType::BiDi));
+    }
+
+    /// Read the session ID from a stream that the peer opened for a session.
+    fn webtransport_read_header(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+    ) -> HandleReadableOutput {
+        let reader = match self.webtransport_new_streams.get_mut(&stream_id) {
+            Some(r) => r,
+            None => return HandleReadableOutput::NoOutput,
+        };
+        let res = reader.session_id(conn, stream_id);
+        let fin = reader.fin();
+        match res {
+            Ok(Some(session_id)) => {
+                self.webtransport_new_streams.remove(&stream_id);
+                self.webtransport_stream_started(conn, stream_id, session_id)
+            }
+            Ok(None) if !fin => HandleReadableOutput::NoOutput,
+            _ => {
+                qinfo!([self], "Bad start of WebTransport stream {}.", stream_id);
+                self.webtransport_new_streams.remove(&stream_id);
+                let _ = conn.stream_stop_sending(stream_id, Error::HttpStreamCreation.code());
+                let _ = conn.stream_reset_send(stream_id, Error::HttpStreamCreation.code());
+                HandleReadableOutput::NoOutput
+            }
+        }
+    }
+
+    /// The peer opened a stream for the session `session_id`.
+    fn webtransport_stream_started(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        session_id: u64,
+    ) -> HandleReadableOutput {
+        if self.is_webtransport_session(session_id) {
+            qinfo!(
+                [self],
+                "The peer opened stream {} for WebTransport session {}.",
+                stream_id,
+                session_id
+            );
+            self.webtransport_streams
+                .insert(stream_id, SessionStream::new(session_id, stream_id, false));
+            HandleReadableOutput::WebTransportStream { session_id }
+        } else {
+            qinfo!(
+                [self],
+                "Stream {} is for an unknown WebTransport session {}.",
+                stream_id,
+                session_id
+            );
+            let _ = conn.stream_stop_sending(stream_id, Error::HttpStreamCreation.code());
+            let _ = conn.stream_reset_send(stream_id, Error::HttpStreamCreation.code());
+            HandleReadableOutput::NoOutput
+        }
+    }
+
+    /// Reset the streams of a WebTransport session that has ended.
+    fn webtransport_session_closed(&mut self, conn: &mut Connection, session_id: u64) {
+        if !self.webtransport_sessions.remove(&session_id) {
+            return;
+        }
+        qinfo!([self], "WebTransport session {} has ended.", session_id);
+        let error = Error::HttpRequestCancelled.code();
+        self.webtransport_streams.retain(|&id, s| {
+            if s.session_id == session_id {
+                let _ = conn.stream_reset_send(id, error);
+                let _ = conn.stream_stop_sending(id, error);
+                false
+            } else {
+                true
+            }
+        });
     }
 
     fn initialize_http3_connection(&mut self, conn: &mut Connection) -> Res<()> {
@@ -112,17 +496,27 @@ impl Http3Connection {
 
     fn send_settings(&mut self) {
         qdebug!([self], "Send settings.");
+        let mut settings = vec![
+            HSetting {
+                setting_type: HSettingType::MaxTableCapacity,
+                value: self.qpack_decoder.get_max_table_size(),
+            },
+            HSetting {
+                setting_type: HSettingType::BlockedStreams,
+                value: self.qpack_decoder.get_blocked_streams().into(),
+            },
+        ];
+        if self.webtransport || self.extended_connect {
+            settings.push(HSetting::new(HSettingType::EnableConnectProtocol, 1));
+        }
+        if self.webtransport {
+            settings.push(HSetting::new(HSettingType::EnableWebTransport, 1));
+        }
+        if self.datagrams {
+            settings.push(HSetting::new(HSettingType::H3Datagram, 1));
+        }
         self.control_stream_local.queue_frame(&HFrame::Settings {
-            settings: HSettings::new(&[
-                HSetting {
-                    setting_type: HSettingType::MaxTableCapacity,
-                    value: self.qpack_decoder.get_max_table_size(),
-                },
-                HSetting {
-                    setting_type: HSettingType::BlockedStreams,
-                    value: self.qpack_decoder.get_blocked_streams().into(),
-                },
-            ]),
+            settings: HSettings::new(&settings),
         });
     }
 
@@ -141,6 +535,11 @@ impl Http3Connection {
     }
 
     /// Return true if there is a stream that needs to send data.
+    /// Queue a frame to be sent on the local control stream.
+    pub fn queue_control_frame(&mut self, frame: &HFrame) {
+        self.control_stream_local.queue_frame(frame);
+    }
+
     pub fn has_data_to_send(&self) -> bool {
         !self.streams_have_data_to_send.is_empty()
     }
@@ -169,6 +568,15 @@ impl Http3Connection {
         Ok(())
     }
 
+    /// Find the streams that were blocked when sending body data, but that now
+    /// have space, so that the application can be told to send more.
+    pub fn writable_streams(&mut self, conn: &Connection) -> Vec<u64> {
+        self.send_streams
+            .iter_mut()
+            .filter_map(|(id, s)| if s.writable(conn) { Some(*id) } else { None })
+            .collect()
+    }
+
     /// We have a resumption token which remembers previous settings. Update the setting.
     pub fn set_0rtt_settings(&mut self, conn: &mut Connection, settings: HSettings) -> Res<()> {
         self.state = Http3State::ZeroRtt;
@@ -279,9 +687,9 @@ impl Http3Connection {
         qtrace!([self], "Readable stream {}.", stream_id);
 
         let label = ::neqo_common::log_subject!(::log::Level::Debug, self);
-        if self.handle_read_stream(conn, stream_id)? {
+        if let Some(output) = self.handle_read_stream(conn, stream_id)? {
             qdebug!([label], "Request/response stream {} read.", stream_id);
-            Ok(HandleReadableOutput::NoOutput)
+            Ok(output)
         } else if self.control_stream_remote.is_recv_stream(stream_id) {
             self.recv_control(conn, stream_id)
         } else if self.qpack_encoder.recv_if_encoder_stream(conn, stream_id)? {
@@ -293,6 +701,12 @@ impl Http3Connection {
             Ok(HandleReadableOutput::NoOutput)
         } else if self.recv_decoder(conn, stream_id)? {
             Ok(HandleReadableOutput::NoOutput)
+        } else if self.webtransport_new_streams.contains_key(&stream_id) {
+            Ok(self.webtransport_read_header(conn, stream_id))
+        } else if let Some(s) = self.webtransport_streams.get(&stream_id) {
+            Ok(HandleReadableOutput::WebTransportData {
+                session_id: s.session_id,
+            })
         } else if let Some(ns) = self.new_streams.get_mut(&stream_id) {
             let stream_type = ns.get_type(conn, stream_id);
             let fin = ns.fin();
@@ -314,6 +728,9 @@ impl Http3Connection {
                         Ok(HandleReadableOutput::NoOutput)
                     }
                     HTTP3_UNI_STREAM_TYPE_PUSH => Ok(HandleReadableOutput::PushStream),
+                    WEBTRANSPORT_UNI_STREAM_TYPE => {
+                        Ok(self.webtransport_read_header(conn, stream_id))
+                    }
                     _ => Ok(HandleReadableOutput::NoOutput),
                 };
             }
@@ -357,9 +774,12 @@ impl Http3Connection {
             app_err
         );
 
-        // We want to execute both statements, therefore we use | instead of ||.
-        let found = self.recv_streams.remove(&stream_id).is_some()
-            | self.send_streams.remove(&stream_id).is_some();
+        // We want to execute all statements, therefore we use | instead of ||.
+        let found = self.remove_recv_stream(stream_id)
+            | self.send_streams.remove(&stream_id).is_some()
+            | self.webtransport_streams.remove(&stream_id).is_some();
+        self.webtransport_new_streams.remove(&stream_id);
+        self.webtransport_session_closed(conn, stream_id);
 
         // close sending side of the transport stream as well. The server may have done
         // it as well, but just to be sure.
@@ -424,6 +844,9 @@ impl Http3Connection {
             // TODO: investigate whether this code can automatically retry failed transactions.
             self.send_streams.clear();
             self.recv_streams.clear();
+            self.webtransport_sessions.clear();
+            self.webtransport_streams.clear();
+            self.webtransport_new_streams.clear();
             Ok(())
         } else {
             debug_assert!(false, "Zero rtt rejected in the wrong state.");
@@ -431,13 +854,18 @@ impl Http3Connection {
         }
     }
 
-    fn handle_read_stream(&mut self, conn: &mut Connection, stream_id: u64) -> Res<bool> {
+    // Returns `None` if this is not a request/response stream.
+    fn handle_read_stream(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+    ) -> Res<Option<HandleReadableOutput>> {
         let label = ::neqo_common::log_subject!(::log::Level::Info, self);
 
         let r = self.recv_streams.get_mut(&stream_id);
 
         if r.is_none() {
-            return Ok(false);
+            return Ok(None);
         }
 
         let recv_stream = r.unwrap();
@@ -446,11 +874,33 @@ impl Http3Connection {
             "Request/response stream {} is readable.",
             stream_id
         );
-        recv_stream.receive(conn, &mut self.qpack_decoder)?;
-        if recv_stream.done() {
-            self.recv_streams.remove(&stream_id);
+        match recv_stream.receive(conn, &mut self.qpack_decoder) {
+            Ok(()) => {
+                if let Some(session_id) = recv_stream.webtransport_session() {
+                    // This is not a request, the rest of the stream belongs to the session.
+                    self.recv_streams.remove(&stream_id);
+                    self.send_streams.remove(&stream_id);
+                    return Ok(Some(
+                        self.webtransport_stream_started(conn, stream_id, session_id),
+                    ));
+                }
+                if recv_stream.done() {
+                    self.recv_streams.remove(&stream_id);
+                }
+                Ok(Some(HandleReadableOutput::NoOutput))
+            }
+            Err(Error::HttpMessageError) => {
+                self.reset_malformed_stream(conn, stream_id);
+                Ok(Some(HandleReadableOutput::MalformedMessage))
+            }
+            Err(e) => Err(e),
         }
-        Ok(true)
+    }
+
+    /// A malformed message is a stream error; reset both sides of the stream.
+    pub fn reset_malformed_stream(&mut self, conn: &mut Connection, stream_id: u64) {
+        qinfo!([self], "Malformed message on stream {}.", stream_id);
+        let _ = self.stream_reset(conn, stream_id, Error::HttpMessageError.code());
     }
 
     // Returns true if it is a push stream.
@@ -470,6 +920,12 @@ impl Http3Connection {
                 qinfo!([self], "A new push stream {}.", stream_id);
                 Ok(true)
             }
+            WEBTRANSPORT_UNI_STREAM_TYPE if self.webtransport_enabled() => {
+                qinfo!([self], "A new WebTransport stream {}.", stream_id);
+                self.webtransport_new_streams
+                    .insert(stream_id, StreamHeaderReader::new(StreamType::UniDi));
+                Ok(false)
+            }
             QPACK_UNI_STREAM_TYPE_ENCODER => {
                 qinfo!([self], "A new remote qpack encoder stream {}", stream_id);
                 self.qpack_decoder
@@ -500,6 +956,9 @@ impl Http3Connection {
         }
         self.send_streams.clear();
         self.recv_streams.clear();
+        self.webtransport_sessions.clear();
+        self.webtransport_streams.clear();
+        self.webtransport_new_streams.clear();
     }
 
     /// This is called when an application resets a stream.
@@ -511,9 +970,11 @@ impl Http3Connection {
     ) -> Res<()> {
         qinfo!([self], "Reset stream {} error={}.", stream_id, error);
 
-        // We want to execute both statements, therefore we use | instead of ||.
+        // We want to execute all statements, therefore we use | instead of ||.
         let found = self.send_streams.remove(&stream_id).is_some()
-            | self.recv_streams.remove(&stream_id).is_some();
+            | self.remove_recv_stream(stream_id)
+            | self.webtransport_streams.remove(&stream_id).is_some();
+        self.webtransport_session_closed(conn, stream_id);
 
         // Stream maybe already be closed and we may get an error here, but we do not care.
         let _ = conn.stream_reset_send(stream_id, error);
@@ -526,10 +987,32 @@ impl Http3Connection {
         }
     }
 
+    /// Remove a receive stream that will not be read any more.  QPACK forgets the
+    /// stream too, so that the peer's encoder does not wait for acknowledgments for it.
+    pub fn remove_recv_stream(&mut self, stream_id: u64) -> bool {
+        if self.recv_streams.remove(&stream_id).is_some() {
+            self.qpack_decoder.cancel_stream(stream_id);
+            true
+        } else {
+            false
+        }
+    }
+
     /// This is called when an application wants to close the sending side of a stream.
     pub fn stream_close_send(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
         qinfo!([self], "Close the sending side for stream {}.", stream_id);
         debug_assert!(self.state.active());
+        if let Some(s) = self.webtransport_streams.get_mut(&stream_id) {
+            if !s.can_send() {
+                return Err(Error::InvalidStreamId);
+            }
+            conn.stream_close_send(stream_id)?;
+            s.send_done();
+            if s.done() {
+                self.webtransport_streams.remove(&stream_id);
+            }
+            return Ok(());
+        }
         let send_stream = self
             .send_streams
             .get_mut(&stream_id)
@@ -595,6 +1078,9 @@ impl Http3Connection {
                     HSettingType::MaxHeaderListSize,
                     HSettingType::MaxTableCapacity,
                     HSettingType::BlockedStreams,
+                    HSettingType::EnableConnectProtocol,
+                    HSettingType::EnableWebTransport,
+                    HSettingType::H3Datagram,
                 ] {
                     let zero_rtt_value = settings.get(*st);
                     let new_value = new_settings.get(*st);
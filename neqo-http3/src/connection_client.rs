@@ -4,13 +4,18 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::client_events::{Http3ClientEvent, Http3ClientEvents};
-use crate::connection::{HandleReadableOutput, Http3Connection, Http3State};
+use crate::client_events::{Http3ClientEvent, Http3ClientEvents, WebTransportSessionEvents};
+use crate::connect_udp::{masque_udp_path, CONNECT_UDP_PROTOCOL};
+use crate::connection::{
+    HandleReadableOutput, Http3Connection, Http3State, LOCAL_MAX_DATAGRAM_FRAME_SIZE,
+};
+use crate::headers_checks::MessageType;
 use crate::hframe::HFrame;
 use crate::hsettings_frame::HSettings;
 use crate::push_controller::PushController;
-use crate::recv_message::RecvMessage;
+use crate::recv_message::{RecvMessage, RecvMessageEvents};
 use crate::send_message::{SendMessage, SendMessageEvents};
+use crate::webtransport::WEBTRANSPORT_PROTOCOL;
 use crate::Header;
 use neqo_common::{
     hex, hex_with_len, matches, qdebug, qinfo, qlog::NeqoQlog, qtrace, Datagram, Decoder, Encoder,
@@ -20,13 +25,15 @@ use neqo_crypto::{agent::CertificateInfo, AuthenticationStatus, SecretAgentInfo}
 use neqo_qpack::{stats::Stats, QpackSettings};
 use neqo_transport::{
     AppError, Connection, ConnectionEvent, ConnectionIdManager, Output, QuicVersion, StreamId,
-    StreamType, ZeroRttState,
+    StreamType, TransportParameter, ZeroRttState, MAX_DATAGRAM_FRAME_SIZE,
 };
 use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::{Error, Res};
 
@@ -50,6 +57,12 @@ pub struct Http3Client {
     base_handler: Http3Connection,
     events: Http3ClientEvents,
     push_handler: Rc<RefCell<PushController>>,
+    /// The headers of each request, so that the requests that a GOAWAY
+    /// rejects can be handed back to the application to send again.
+    requests: HashMap<u64, Vec<Header>>,
+    /// Whether there is a new resumption token that can't be used until the
+    /// server's settings arrive.
+    resumption_token_pending: bool,
 }
 
 impl Display for Http3Client {
@@ -91,6 +104,8 @@ impl Http3Client {
             base_handler: Http3Connection::new(qpack_settings),
             events: Http3ClientEvents::default(),
             push_handler: Rc::new(RefCell::new(PushController::new())),
+            requests: HashMap::new(),
+            resumption_token_pending: false,
         }
     }
 
@@ -109,6 +124,64 @@ impl Http3Client {
         self.conn.tls_info()
     }
 
+    /// Enable encrypted client hello (ECH), with configurations that the
+    /// server published.
+    /// # Errors
+    /// `TransportError` if the connection has already started or the
+    /// configurations can't be used.
+    pub fn enable_ech(&mut self, ech_config_list: impl AsRef<[u8]>) -> Res<()> {
+        self.conn.client_enable_ech(ech_config_list)?;
+        Ok(())
+    }
+
+    /// Offer WebTransport to the server.
+    /// # Errors
+    /// `InvalidState` if the connection has already started.
+    pub fn enable_webtransport(&mut self) -> Res<()> {
+        self.base_handler.set_webtransport(true)
+    }
+
+    /// Whether WebTransport can be used.  This is only true after the
+    /// server's SETTINGS frame has been received and it supports WebTransport.
+    #[must_use]
+    pub fn webtransport_enabled(&self) -> bool {
+        self.base_handler.webtransport_enabled()
+    }
+
+    /// Offer HTTP datagrams to the server.  This also enables DATAGRAM frames
+    /// in the transport.
+    /// # Errors
+    /// `InvalidState` if the connection has already started.
+    pub fn enable_datagrams(&mut self) -> Res<()> {
+        self.base_handler.set_datagrams(true)?;
+        self.conn
+            .set_local_tparam(
+                MAX_DATAGRAM_FRAME_SIZE,
+                TransportParameter::Integer(LOCAL_MAX_DATAGRAM_FRAME_SIZE),
+            )
+            .map_err(|_| Error::InvalidState)
+    }
+
+    /// Send an HTTP datagram associated with the request on `stream_id`.
+    /// Datagrams are unreliable, they are not retransmitted if they are lost.
+    /// # Errors
+    /// `Unavailable` if the server does not support datagrams,
+    /// `InvalidStreamId` if the request is not active,
+    /// `TransportError` if the datagram is too large.
+    pub fn send_datagram(&mut self, stream_id: u64, data: &[u8]) -> Res<()> {
+        self.base_handler
+            .send_datagram(&mut self.conn, stream_id, data)
+    }
+
+    /// Keep the connection alive with PING frames while there are no
+    /// requests, so that it can be used for later requests.  This stops once
+    /// the connection has had no requests for `budget`, after which the
+    /// connection closes when the idle timeout expires.  `None` turns this
+    /// off, which is the default.
+    pub fn set_keep_alive(&mut self, budget: Option<Duration>) {
+        self.base_handler.set_keep_alive(budget);
+    }
+
     /// Get the peer's certificate.
     #[must_use]
     pub fn peer_certificate(&self) -> Option<CertificateInfo> {
@@ -150,12 +223,11 @@ impl Http3Client {
             return Err(Error::InvalidState);
         }
         let mut dec = Decoder::from(token);
-        let settings_slice = match dec.decode_vvec() {
+        let mut dec_settings = match dec.decode_vvec_decoder() {
             Some(v) => v,
             _ => return Err(Error::InvalidResumptionToken),
         };
-        qtrace!([self], "  settings {}", hex_with_len(&settings_slice));
-        let mut dec_settings = Decoder::from(settings_slice);
+        qtrace!([self], "  settings {}", hex_with_len(&dec_settings[..]));
         let mut settings = HSettings::default();
         settings.decode_frame_contents(&mut dec_settings)?;
         let tok = dec.decode_remainder();
@@ -202,6 +274,149 @@ impl Http3Client {
             host,
             path
         );
+        // Transform pseudo-header fields
+        let mut final_headers = Vec::new();
+        final_headers.push((":method".into(), method.to_owned()));
+        final_headers.push((":scheme".into(), scheme.to_owned()));
+        final_headers.push((":authority".into(), host.to_owned()));
+        final_headers.push((":path".into(), path.to_owned()));
+        final_headers.extend_from_slice(headers);
+        self.create_request(final_headers)
+    }
+
+    /// Open a tunnel to `authority` (host and port) through a proxy using the CONNECT method.
+    /// Once a 2xx response arrives, the tunnel carries a byte stream in both directions:
+    /// `send_request_body` writes to it and `read_response_data` reads from it.
+    /// # Errors
+    /// If a new stream cannot be created an error will be return.
+    pub fn connect(&mut self, authority: &str, headers: &[Header]) -> Res<u64> {
+        qinfo!([self], "Connect authority={}", authority);
+        let mut final_headers = Vec::new();
+        final_headers.push((":method".into(), "CONNECT".to_owned()));
+        final_headers.push((":authority".into(), authority.to_owned()));
+        final_headers.extend_from_slice(headers);
+        self.create_request(final_headers)
+    }
+
+    /// Ask the proxy at `proxy_host` to forward UDP to `target_host` and `target_port`
+    /// (CONNECT-UDP).  This needs HTTP datagrams and extended CONNECT from the server.
+    /// UDP payloads are sent and received as HTTP datagrams for the returned stream, see
+    /// `connect_udp::encode_udp_payload` and `connect_udp::decode_udp_payload`.
+    /// # Errors
+    /// `Unavailable` if the server does not support CONNECT-UDP, otherwise as for `fetch`.
+    pub fn connect_udp(
+        &mut self,
+        proxy_host: &str,
+        target_host: &str,
+        target_port: u16,
+        headers: &[Header],
+    ) -> Res<u64> {
+        qinfo!(
+            [self],
+            "Connect UDP proxy={}, target={}:{}",
+            proxy_host,
+            target_host,
+            target_port
+        );
+        if !self.base_handler.peer_extended_connect() || !self.base_handler.datagrams_enabled() {
+            return Err(Error::Unavailable);
+        }
+        let mut final_headers = Vec::new();
+        final_headers.push((":method".into(), "CONNECT".to_owned()));
+        final_headers.push((":protocol".into(), CONNECT_UDP_PROTOCOL.to_owned()));
+        final_headers.push((":scheme".into(), "https".to_owned()));
+        final_headers.push((":authority".into(), proxy_host.to_owned()));
+        final_headers.push((":path".into(), masque_udp_path(target_host, target_port)));
+        final_headers.push(("capsule-protocol".into(), "?1".to_owned()));
+        final_headers.extend_from_slice(headers);
+        self.create_request(final_headers)
+    }
+
+    /// Start a WebTransport session with a request for `path` on `authority`.  A
+    /// `WebTransportSession` event carries the response.  The returned session ID
+    /// is used to open streams and to send datagrams.  The session, and all of its
+    /// streams, end when the session is reset with `cancel_fetch`.
+    /// # Errors
+    /// `Unavailable` if the server does not support WebTransport, otherwise as for `fetch`.
+    pub fn webtransport_create_session(
+        &mut self,
+        authority: &str,
+        path: &str,
+        headers: &[Header],
+    ) -> Res<u64> {
+        qinfo!(
+            [self],
+            "WebTransport session authority={}, path={}",
+            authority,
+            path
+        );
+        if !self.base_handler.webtransport_enabled() {
+            return Err(Error::Unavailable);
+        }
+        let mut final_headers = Vec::new();
+        final_headers.push((":method".into(), "CONNECT".to_owned()));
+        final_headers.push((":protocol".into(), WEBTRANSPORT_PROTOCOL.to_owned()));
+        final_headers.push((":scheme".into(), "https".to_owned()));
+        final_headers.push((":authority".into(), authority.to_owned()));
+        final_headers.push((":path".into(), path.to_owned()));
+        final_headers.extend_from_slice(headers);
+        let events = Box::new(WebTransportSessionEvents::new(self.events.clone()));
+        let id = self.create_request_with_events(final_headers, events)?;
+        self.base_handler.webtransport_add_session(id);
+        Ok(id)
+    }
+
+    /// Open a stream for a WebTransport session.  This should wait for a
+    /// `WebTransportSession` event that accepts the session.
+    /// # Errors
+    /// `InvalidStreamId` if the session is not active, or a transport error
+    /// if the stream cannot be created.
+    pub fn webtransport_create_stream(
+        &mut self,
+        session_id: u64,
+        stream_type: StreamType,
+    ) -> Res<u64> {
+        self.base_handler
+            .webtransport_create_stream(&mut self.conn, session_id, stream_type)
+    }
+
+    /// Send data on a stream of a WebTransport session.  `stream_close_send` closes
+    /// the stream and `stream_reset` resets it.
+    /// # Errors
+    /// `InvalidStreamId` if the stream is not a WebTransport stream that can send.
+    pub fn webtransport_send_data(&mut self, stream_id: u64, buf: &[u8]) -> Res<usize> {
+        self.base_handler
+            .webtransport_send(&mut self.conn, stream_id, buf)
+    }
+
+    /// Read data from a stream of a WebTransport session.
+    /// # Errors
+    /// `InvalidStreamId` if the stream is not a WebTransport stream that can receive.
+    pub fn webtransport_read_data(&mut self, stream_id: u64, buf: &mut [u8]) -> Res<(usize, bool)> {
+        self.base_handler
+            .webtransport_recv(&mut self.conn, stream_id, buf)
+    }
+
+    /// Send a datagram for a WebTransport session.
+    /// # Errors
+    /// `InvalidStreamId` if the session is not active, otherwise as for `send_datagram`.
+    pub fn webtransport_send_datagram(&mut self, session_id: u64, data: &[u8]) -> Res<()> {
+        if !self.base_handler.is_webtransport_session(session_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        self.send_datagram(session_id, data)
+    }
+
+    fn create_request(&mut self, headers: Vec<Header>) -> Res<u64> {
+        let events = Box::new(self.events.clone());
+        self.create_request_with_events(headers, events)
+    }
+
+    fn create_request_with_events(
+        &mut self,
+        headers: Vec<Header>,
+        recv_events: Box<dyn RecvMessageEvents>,
+    ) -> Res<u64> {
         // Requests cannot be created when a connection is in states: Initializing, GoingAway, Closing and Closed.
         match self.base_handler.state() {
             Http3State::GoingAway(..) | Http3State::Closing(..) | Http3State::Closed(..) => {
@@ -212,38 +427,47 @@ impl Http3Client {
         }
 
         let id = self.conn.stream_create(StreamType::BiDi)?;
-
-        // Transform pseudo-header fields
-        let mut final_headers = Vec::new();
-        final_headers.push((":method".into(), method.to_owned()));
-        final_headers.push((":scheme".into(), scheme.to_owned()));
-        final_headers.push((":authority".into(), host.to_owned()));
-        final_headers.push((":path".into(), path.to_owned()));
-        final_headers.extend_from_slice(headers);
-
+        let head = headers.iter().any(|(n, v)| n == ":method" && v == "HEAD");
+        // Forget the requests that are done.
+        let base_handler = &self.base_handler;
+        self.requests.retain(|id, _| {
+            base_handler.send_streams.contains_key(id) || base_handler.recv_streams.contains_key(id)
+        });
+        self.requests.insert(id, headers.clone());
         self.base_handler.add_streams(
             id,
-            SendMessage::new_with_headers(id, final_headers, Box::new(self.events.clone())),
+            SendMessage::new_with_headers(id, headers, Box::new(self.events.clone())),
             RecvMessage::new(
+                MessageType::Response { head },
                 id,
-                Box::new(self.events.clone()),
+                recv_events,
                 Some(self.push_handler.clone()),
             ),
         );
         Ok(id)
     }
 
-    /// An application may reset a stream(request).
+    /// Cancel a request.  This sends RESET_STREAM and STOP_SENDING with `error`, which is
+    /// usually `Error::HttpRequestCancelled.code()`.  Events for the request that have not
+    /// been read yet are dropped and QPACK stops tracking the stream.  The server gets a
+    /// `Reset` event.
     /// # Errors
     /// An error will be return if a stream does not exist.
-    pub fn stream_reset(&mut self, stream_id: u64, error: AppError) -> Res<()> {
-        qinfo!([self], "reset_stream {} error={}.", stream_id, error);
+    pub fn cancel_fetch(&mut self, stream_id: u64, error: AppError) -> Res<()> {
+        qinfo!([self], "cancel_fetch {} error={}.", stream_id, error);
         self.base_handler
             .stream_reset(&mut self.conn, stream_id, error)?;
         self.events.remove_events_for_stream_id(stream_id);
         Ok(())
     }
 
+    /// An application may reset a stream(request).  This is the same as `cancel_fetch`.
+    /// # Errors
+    /// An error will be return if a stream does not exist.
+    pub fn stream_reset(&mut self, stream_id: u64, error: AppError) -> Res<()> {
+        self.cancel_fetch(stream_id, error)
+    }
+
     /// This is call when application is done sending a request.
     /// # Errors
     /// An error will be return if stream does not exist.
@@ -299,6 +523,10 @@ impl Http3Client {
             Err(e) => {
                 if e == Error::HttpFrame {
                     self.close(now, e.code(), "");
+                } else if e == Error::HttpMessageError {
+                    self.base_handler
+                        .reset_malformed_stream(&mut self.conn, stream_id);
+                    self.events.remove_events_for_stream_id(stream_id);
                 }
                 Err(e)
             }
@@ -339,6 +567,18 @@ impl Http3Client {
         self.process_http3(now);
     }
 
+    /// Process a burst of datagrams.
+    /// See `neqo_transport::Connection::process_multiple_input`.
+    pub fn process_multiple_input(
+        &mut self,
+        dgrams: impl IntoIterator<Item = Datagram>,
+        now: Instant,
+    ) {
+        qtrace!([self], "Process multiple datagrams.");
+        self.conn.process_multiple_input(dgrams, now);
+        self.process_http3(now);
+    }
+
     // Only used by neqo-interop
     pub fn conn(&mut self) -> &mut Connection {
         &mut self.conn
@@ -354,7 +594,10 @@ impl Http3Client {
                     return;
                 }
                 let res = self.base_handler.process_sending(&mut self.conn);
-                self.check_result(now, &res);
+                if self.check_result(now, &res) {
+                    return;
+                }
+                self.base_handler.process_keep_alive(&mut self.conn, now);
             }
             Http3State::Closed { .. } => {}
             _ => {
@@ -377,7 +620,12 @@ impl Http3Client {
         // Update H3 for any transport state changes and events
         self.process_http3(now);
 
-        out
+        match (out, self.base_handler.next_keep_alive(&self.conn)) {
+            (Output::Callback(t), Some(ping)) => {
+                Output::Callback(min(t, ping.saturating_duration_since(now)))
+            }
+            (out, _) => out,
+        }
     }
 
     // This function takes the provided result and check for an error.
@@ -409,7 +657,13 @@ impl Http3Client {
             qdebug!([self], "check_connection_events - event {:?}.", e);
             match e {
                 ConnectionEvent::NewStream { stream_id } => match stream_id.stream_type() {
-                    StreamType::BiDi => return Err(Error::HttpStreamCreation),
+                    StreamType::BiDi => {
+                        if !self.base_handler.webtransport_enabled() {
+                            return Err(Error::HttpStreamCreation);
+                        }
+                        self.base_handler
+                            .webtransport_new_bidi_stream(stream_id.as_u64());
+                    }
                     StreamType::UniDi => {
                         if self
                             .base_handler
@@ -464,6 +718,27 @@ impl Http3Client {
                     self.base_handler.handle_zero_rtt_rejected()?;
                     self.events.zero_rtt_rejected();
                 }
+                ConnectionEvent::EchAccepted => self.events.ech_accepted(),
+                ConnectionEvent::EchRejected(retry_configs) => {
+                    self.events.ech_rejected(retry_configs)
+                }
+                ConnectionEvent::ResumptionToken(_) => self.resumption_token_pending = true,
+                ConnectionEvent::Datagram(dgram) => {
+                    if let Some((stream_id, data)) = self.base_handler.handle_datagram(&dgram)? {
+                        if self.base_handler.is_webtransport_session(stream_id) {
+                            self.events.webtransport_datagram(stream_id, data.to_vec());
+                        } else {
+                            self.events.datagram(stream_id, data.to_vec());
+                        }
+                    }
+                }
+            }
+        }
+        // The token includes the server's settings, so it waits for those.
+        if self.resumption_token_pending {
+            if let Some(token) = self.resumption_token() {
+                self.resumption_token_pending = false;
+                self.events.resumption_token(token);
             }
         }
         Ok(())
@@ -489,7 +764,19 @@ impl Http3Client {
                 }
                 Ok(())
             }
-            _ => Ok(()),
+            HandleReadableOutput::MalformedMessage => {
+                self.events.reset(stream_id, Error::HttpMessageError.code());
+                Ok(())
+            }
+            HandleReadableOutput::WebTransportStream { session_id } => {
+                self.events.webtransport_new_stream(stream_id, session_id);
+                Ok(())
+            }
+            HandleReadableOutput::WebTransportData { .. } => {
+                self.events.webtransport_data_readable(stream_id);
+                Ok(())
+            }
+            HandleReadableOutput::NoOutput => Ok(()),
         }
     }
 
@@ -501,6 +788,14 @@ impl Http3Client {
             app_err
         );
 
+        if self
+            .base_handler
+            .webtransport_stop_sending(&mut self.conn, stop_stream_id, app_err)
+        {
+            self.events.stop_sending(stop_stream_id, app_err);
+            return Ok(());
+        }
+
         let mut found = false;
         if let Some(s) = self.base_handler.send_streams.remove(&stop_stream_id) {
             // If error is Error::HttpNoError we will post StopSending event,
@@ -513,11 +808,7 @@ impl Http3Client {
 
         // if error is not Error::HttpNoError we will close receiving part as well.
         if app_err != Error::HttpNoError.code() {
-            found |= self
-                .base_handler
-                .recv_streams
-                .remove(&stop_stream_id)
-                .is_some();
+            found |= self.base_handler.remove_recv_stream(stop_stream_id);
             if found {
                 self.events.reset(stop_stream_id, app_err);
             }
@@ -574,6 +865,23 @@ impl Http3Client {
             self.events.reset(id, Error::HttpRequestRejected.code());
         }
 
+        // The requests that weren't processed can be sent again.
+        let base_handler = &self.base_handler;
+        let mut rejected = self
+            .requests
+            .iter()
+            .filter_map(id_gte(goaway_stream_id))
+            .filter(|id| {
+                base_handler.send_streams.contains_key(id)
+                    || base_handler.recv_streams.contains_key(id)
+            })
+            .collect::<Vec<_>>();
+        rejected.sort_unstable();
+        for id in rejected {
+            let headers = self.requests.remove(&id).unwrap();
+            self.events.request_rejected(id, headers);
+        }
+
         self.events.goaway_received();
 
         // Actually remove (i.e. don't retain) these streams
@@ -602,13 +910,13 @@ impl Http3Client {
 #[cfg(test)]
 mod tests {
     use super::{
-        AuthenticationStatus, Connection, Error, HSettings, Header, Http3Client, Http3ClientEvent,
-        Http3State, QpackSettings, Rc, RefCell, StreamType,
+        min, AuthenticationStatus, Connection, Duration, Error, HSettings, Header, Http3Client,
+        Http3ClientEvent, Http3State, Output, QpackSettings, Rc, RefCell, StreamType,
     };
     use crate::hframe::HFrame;
     use crate::hsettings_frame::{HSetting, HSettingType};
     use neqo_common::{matches, Encoder};
-    use neqo_crypto::AntiReplay;
+    use neqo_crypto::{ech, AntiReplay};
     use neqo_qpack::encoder::QPackEncoder;
     use neqo_transport::{
         CloseError, ConnectionEvent, FixedConnectionIdManager, QuicVersion, State,
@@ -632,7 +940,7 @@ mod tests {
         Http3Client::new(
             DEFAULT_SERVER_NAME,
             DEFAULT_ALPN,
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3).unwrap())),
             loopback(),
             loopback(),
             QpackSettings {
@@ -931,6 +1239,62 @@ mod tests {
         (client, server)
     }
 
+    /// Run the client and the server until the client closes.  Returns how long that took.
+    fn run_until_closed(client: &mut Http3Client, server: &mut TestServer) -> Duration {
+        let start = now();
+        let mut now = start;
+        loop {
+            let client_wait = match client.process_output(now) {
+                Output::Datagram(d) => {
+                    server.conn.process_input(d, now);
+                    continue;
+                }
+                Output::Callback(t) => t,
+                Output::None => return now - start,
+            };
+            let server_wait = match server.conn.process_output(now) {
+                Output::Datagram(d) => {
+                    client.process_input(d, now);
+                    continue;
+                }
+                Output::Callback(t) => t,
+                Output::None => client_wait,
+            };
+            now += min(client_wait, server_wait);
+        }
+    }
+
+    #[test]
+    fn idle_without_keep_alive() {
+        let (mut client, mut server) = connect();
+        let idle_timeout = client.conn().idle_timeout();
+        let elapsed = run_until_closed(&mut client, &mut server);
+        assert!(elapsed >= idle_timeout);
+        assert!(elapsed < idle_timeout + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn keep_alive() {
+        const BUDGET: Duration = Duration::from_secs(60);
+        let (mut client, mut server) = connect();
+        client.set_keep_alive(Some(BUDGET));
+        let idle_timeout = client.conn().idle_timeout();
+        // The last PING is sent when the budget runs out, and is acknowledged,
+        // so the connection lasts for the budget and then the idle timeout.
+        let elapsed = run_until_closed(&mut client, &mut server);
+        assert!(elapsed >= BUDGET + idle_timeout);
+        assert!(elapsed < BUDGET + idle_timeout + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn keep_alive_waits_for_requests() {
+        let (mut client, _server) = connect();
+        client.set_keep_alive(Some(Duration::from_secs(60)));
+        let _ = make_request(&mut client, true);
+        let _ = client.process_output(now());
+        assert!(client.base_handler.next_keep_alive(&client.conn).is_none());
+    }
+
     // Fetch request fetch("GET", "https", "something.com", "/", &[]).
     fn make_request(client: &mut Http3Client, close_sending_side: bool) -> u64 {
         let request_stream_id = client
@@ -1004,6 +1368,9 @@ mod tests {
     // The data frame payload from HTTP_RESPONSE_2 is:
     const EXPECTED_RESPONSE_DATA_2_FRAME_1: &[u8] = &[0x61, 0x62, 0x63];
 
+    // Trailers are a HEADERS frame without pseudo-headers; this one carries "age: 0".
+    const HTTP_TRAILERS_FRAME: &[u8] = &[0x01, 0x03, 0x00, 0x00, 0xc2];
+
     fn connect_and_send_request(close_sending_side: bool) -> (Http3Client, TestServer, u64) {
         let (mut client, mut server) = connect();
         let request_stream_id = make_request(&mut client, close_sending_side);
@@ -2106,6 +2473,190 @@ mod tests {
         client.close(now(), 0, "");
     }
 
+    #[test]
+    fn cancel_fetch() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(false);
+
+        // Response headers are ready, but the request is cancelled before they are read.
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, HTTP_RESPONSE_HEADER_ONLY_2);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        assert_eq!(
+            client.cancel_fetch(request_stream_id, Error::HttpRequestCancelled.code()),
+            Ok(())
+        );
+        let header_ready = |e| matches!(e, Http3ClientEvent::HeaderReady { .. });
+        assert!(!client.events().any(header_ready));
+
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+
+        let mut reset = false;
+        let mut stop_sending = false;
+        while let Some(e) = server.conn.next_event() {
+            match e {
+                ConnectionEvent::RecvStreamReset {
+                    stream_id,
+                    app_error,
+                } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    assert_eq!(app_error, Error::HttpRequestCancelled.code());
+                    reset = true;
+                }
+                ConnectionEvent::SendStreamStopSending {
+                    stream_id,
+                    app_error,
+                } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    assert_eq!(app_error, Error::HttpRequestCancelled.code());
+                    stop_sending = true;
+                }
+                _ => {}
+            }
+        }
+        assert!(reset);
+        assert!(stop_sending);
+
+        // The request is gone.
+        assert_eq!(
+            client.cancel_fetch(request_stream_id, Error::HttpRequestCancelled.code()),
+            Err(Error::InvalidStreamId)
+        );
+        client.close(now(), 0, "");
+    }
+
+    // Check that the server got RESET_STREAM and STOP_SENDING with H3_MESSAGE_ERROR
+    // and that the connection is still usable.
+    fn check_malformed_message_reset(
+        client: &mut Http3Client,
+        server: &mut TestServer,
+        request_stream_id: u64,
+    ) {
+        assert_eq!(client.state(), Http3State::Connected);
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+
+        let mut reset = false;
+        let mut stop_sending = false;
+        while let Some(e) = server.conn.next_event() {
+            match e {
+                ConnectionEvent::RecvStreamReset {
+                    stream_id,
+                    app_error,
+                } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    assert_eq!(app_error, Error::HttpMessageError.code());
+                    reset = true;
+                }
+                ConnectionEvent::SendStreamStopSending {
+                    stream_id,
+                    app_error,
+                } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    assert_eq!(app_error, Error::HttpMessageError.code());
+                    stop_sending = true;
+                }
+                _ => {}
+            }
+        }
+        assert!(reset);
+        assert!(stop_sending);
+
+        let mut buf = [0_u8; 100];
+        assert_eq!(
+            client.read_response_data(now(), request_stream_id, &mut buf),
+            Err(Error::InvalidStreamId)
+        );
+    }
+
+    fn test_malformed_response(response: &[u8]) {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        let _ = server.conn.stream_send(request_stream_id, response);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        // The application only learns that the request has been reset.
+        let events: Vec<Http3ClientEvent> = client.events().collect();
+        assert_eq!(
+            events,
+            vec![Http3ClientEvent::Reset {
+                stream_id: request_stream_id,
+                error: Error::HttpMessageError.code(),
+            }]
+        );
+        check_malformed_message_reset(&mut client, &mut server, request_stream_id);
+    }
+
+    #[test]
+    fn response_without_status() {
+        test_malformed_response(HTTP_TRAILERS_FRAME);
+    }
+
+    #[test]
+    fn response_missing_content() {
+        // content-length: 3, but no data.
+        test_malformed_response(HTTP_RESPONSE_HEADER_ONLY_2);
+    }
+
+    #[test]
+    fn response_data_exceeds_content_length() {
+        test_malformed_response(&[
+            // headers with content-length: 3
+            0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x33, // a data frame with 4 bytes
+            0x0, 0x4, 0x61, 0x62, 0x63, 0x64,
+        ]);
+    }
+
+    #[test]
+    fn response_trailers_with_pseudo_header() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_2);
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, HTTP_HEADER_FRAME_0);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        // The trailers are decoded after the data has been read.
+        let mut buf = [0_u8; 100];
+        assert_eq!(
+            client.read_response_data(now(), request_stream_id, &mut buf),
+            Err(Error::HttpMessageError)
+        );
+        check_malformed_message_reset(&mut client, &mut server, request_stream_id);
+    }
+
+    #[test]
+    fn response_data_shorter_than_content_length() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        // content-length: 7, but only 3 bytes of data.
+        let _ = server.conn.stream_send(
+            request_stream_id,
+            &[
+                0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x37, 0x0, 0x3, 0x61, 0x62, 0x63,
+            ],
+        );
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        // The missing data is only noticed while reading.
+        let mut buf = [0_u8; 100];
+        assert_eq!(
+            client.read_response_data(now(), request_stream_id, &mut buf),
+            Err(Error::HttpMessageError)
+        );
+        check_malformed_message_reset(&mut client, &mut server, request_stream_id);
+    }
+
     fn test_incomplet_frame(buf: &[u8], error: &Error) {
         let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
 
@@ -2219,6 +2770,74 @@ mod tests {
         client.close(now(), 0, "");
     }
 
+    #[test]
+    fn goaway_rejected_request_retry() {
+        let (mut client, mut server) = connect();
+        assert_eq!(make_request(&mut client, true), 0);
+        let request_stream_id_2 = make_request(&mut client, true);
+        assert_eq!(request_stream_id_2, 4);
+
+        let out = client.process(None, now());
+        let _ = server.conn.process(out.dgram(), now());
+
+        // The server only processes the first request.
+        let _ = server
+            .conn
+            .stream_send(server.control_stream_id.unwrap(), &[0x7, 0x1, 0x4]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let rejected = client
+            .events()
+            .filter_map(|e| match e {
+                Http3ClientEvent::RequestRejected { stream_id, headers } => {
+                    Some((stream_id, headers))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rejected.len(), 1);
+        let (stream_id, headers) = &rejected[0];
+        assert_eq!(*stream_id, request_stream_id_2);
+        let value = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        assert_eq!(value(":path"), "/");
+
+        // The rejected request can be sent again on a new connection.
+        let (mut client2, mut server2) = connect();
+        let retried = client2
+            .fetch(
+                &value(":method"),
+                &value(":scheme"),
+                &value(":authority"),
+                &value(":path"),
+                &[],
+            )
+            .unwrap();
+        let _ = client2.stream_close_send(retried);
+        let out = client2.process(None, now());
+        let _ = server2.conn.process(out.dgram(), now());
+        let mut received = false;
+        while let Some(e) = server2.conn.next_event() {
+            if let ConnectionEvent::RecvStreamReadable { stream_id } = e {
+                if stream_id == retried {
+                    server2.read_and_check_stream_data(
+                        stream_id,
+                        EXPECTED_REQUEST_HEADER_FRAME,
+                        true,
+                    );
+                    received = true;
+                }
+            }
+        }
+        assert!(received);
+    }
+
     #[test]
     fn multiple_goaways() {
         let (mut client, mut server) = connect();
@@ -2382,7 +3001,7 @@ mod tests {
 
         let _ = server
             .conn
-            .stream_send(request_stream_id, HTTP_RESPONSE_HEADER_ONLY_2);
+            .stream_send(request_stream_id, HTTP_HEADER_FRAME_0);
         // ok NOW send fin
         server.conn.stream_close_send(request_stream_id).unwrap();
 
@@ -2398,7 +3017,7 @@ mod tests {
         } = e
         {
             assert_eq!(stream_id, request_stream_id);
-            check_response_header_2(&headers.unwrap());
+            check_response_header_0(&headers.unwrap());
             assert_eq!(fin, true);
         } else {
             panic!("wrong event type");
@@ -2420,7 +3039,7 @@ mod tests {
         // Send some good data wo fin
         let _ = server
             .conn
-            .stream_send(request_stream_id, HTTP_RESPONSE_HEADER_ONLY_2);
+            .stream_send(request_stream_id, HTTP_HEADER_FRAME_0);
 
         let out = server.conn.process(None, now());
         client.process(out.dgram(), now());
@@ -2434,7 +3053,7 @@ mod tests {
                     fin,
                 } => {
                     assert_eq!(stream_id, request_stream_id);
-                    check_response_header_2(&headers.unwrap());
+                    check_response_header_0(&headers.unwrap());
                     assert_eq!(fin, false);
                 }
                 Http3ClientEvent::DataReadable { .. } => {
@@ -2484,7 +3103,7 @@ mod tests {
         // Send headers.
         let _ = server
             .conn
-            .stream_send(request_stream_id, HTTP_RESPONSE_HEADER_ONLY_2);
+            .stream_send(request_stream_id, HTTP_HEADER_FRAME_0);
         // Send an empty data frame.
         let _ = server.conn.stream_send(request_stream_id, &[0x00, 0x00]);
         // ok NOW send fin
@@ -2502,7 +3121,7 @@ mod tests {
                     fin,
                 } => {
                     assert_eq!(stream_id, request_stream_id);
-                    check_response_header_2(&headers.unwrap());
+                    check_response_header_0(&headers.unwrap());
                     assert_eq!(fin, false);
                 }
                 Http3ClientEvent::DataReadable { stream_id } => {
@@ -2534,7 +3153,7 @@ mod tests {
         // Send headers.
         let _ = server
             .conn
-            .stream_send(request_stream_id, HTTP_RESPONSE_HEADER_ONLY_2);
+            .stream_send(request_stream_id, HTTP_HEADER_FRAME_0);
         // Send an empty data frame.
         let _ = server.conn.stream_send(request_stream_id, &[0x00, 0x00]);
 
@@ -2550,7 +3169,7 @@ mod tests {
                     fin,
                 } => {
                     assert_eq!(stream_id, request_stream_id);
-                    check_response_header_2(&headers.unwrap());
+                    check_response_header_0(&headers.unwrap());
                     assert_eq!(fin, false);
                 }
                 Http3ClientEvent::DataReadable { .. } => {
@@ -2889,6 +3508,28 @@ mod tests {
         (client, server)
     }
 
+    #[test]
+    fn resumption_token_event() {
+        let (mut client, mut server) = connect();
+        let resumption_token = |e| matches!(e, Http3ClientEvent::ResumptionToken(_));
+        assert!(!client.events().any(resumption_token));
+
+        let _ = exchange_token(&mut client, &mut server.conn);
+        let token = client
+            .events()
+            .find_map(|e| match e {
+                Http3ClientEvent::ResumptionToken(token) => Some(token),
+                _ => None,
+            })
+            .expect("should have a resumption token event");
+
+        let mut client = default_http3_client();
+        client
+            .set_resumption_token(now(), &token)
+            .expect("Set resumption token.");
+        assert_eq!(client.state(), Http3State::ZeroRtt);
+    }
+
     #[test]
     fn zero_rtt_negotiated() {
         let (mut client, mut server) = start_with_0rtt();
@@ -2984,7 +3625,7 @@ mod tests {
             test_fixture::DEFAULT_KEYS,
             test_fixture::DEFAULT_ALPN,
             &ar,
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(10).unwrap())),
             QuicVersion::default(),
         )
         .unwrap();
@@ -3367,7 +4008,7 @@ mod tests {
         // Send trailers
         let _ = server
             .conn
-            .stream_send(request_stream_id, HTTP_HEADER_FRAME_0);
+            .stream_send(request_stream_id, HTTP_TRAILERS_FRAME);
         server.conn.stream_close_send(request_stream_id).unwrap();
 
         let out = server.conn.process(None, now());
@@ -3425,7 +4066,7 @@ mod tests {
         // Send trailers
         let _ = server
             .conn
-            .stream_send(request_stream_id, HTTP_HEADER_FRAME_0);
+            .stream_send(request_stream_id, HTTP_TRAILERS_FRAME);
 
         let out = server.conn.process(None, now());
         client.process(out.dgram(), now());
@@ -3458,6 +4099,81 @@ mod tests {
         assert_eq!(fin, true);
     }
 
+    #[test]
+    fn test_trailers_reported() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        // Send the response and trailers.
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_2);
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, HTTP_TRAILERS_FRAME);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let mut buf = [0_u8; 100];
+        let (len, fin) = client
+            .read_response_data(now(), request_stream_id, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..len], EXPECTED_RESPONSE_DATA_2_FRAME_1);
+        assert_eq!(fin, true);
+
+        let mut trailers = false;
+        while let Some(e) = client.next_event() {
+            if let Http3ClientEvent::Trailers { stream_id, headers } = e {
+                assert_eq!(stream_id, request_stream_id);
+                assert_eq!(headers, &[(String::from("age"), String::from("0"))]);
+                trailers = true;
+            }
+        }
+        assert!(trailers);
+    }
+
+    #[test]
+    fn test_informational_response() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        // Send a 103 response followed by the final response.
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, &[0x01, 0x03, 0x00, 0x00, 0xd8]);
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_2);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let events: Vec<Http3ClientEvent> = client.events().collect();
+        let informational = events
+            .iter()
+            .position(|e| matches!(e, Http3ClientEvent::InformationalHeaders { .. }))
+            .unwrap();
+        let final_headers = events
+            .iter()
+            .position(|e| matches!(e, Http3ClientEvent::HeaderReady { .. }))
+            .unwrap();
+        assert!(informational < final_headers);
+
+        if let Http3ClientEvent::InformationalHeaders { stream_id, headers } =
+            &events[informational]
+        {
+            assert_eq!(*stream_id, request_stream_id);
+            assert_eq!(headers, &[(String::from(":status"), String::from("103"))]);
+        }
+        if let Http3ClientEvent::HeaderReady { headers, .. } = &events[final_headers] {
+            check_response_header_2(headers.as_ref().unwrap());
+        }
+
+        let mut buf = [0_u8; 100];
+        let (len, fin) = client
+            .read_response_data(now(), request_stream_id, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..len], EXPECTED_RESPONSE_DATA_2_FRAME_1);
+        assert_eq!(fin, true);
+    }
+
     #[test]
     fn test_data_after_trailers_after_headers() {
         // Make a new connection.
@@ -3491,7 +4207,7 @@ mod tests {
         // Send trailers
         let _ = server
             .conn
-            .stream_send(request_stream_id, HTTP_HEADER_FRAME_0);
+            .stream_send(request_stream_id, HTTP_TRAILERS_FRAME);
 
         let out = server.conn.process(None, now());
         client.process(out.dgram(), now());
@@ -3679,4 +4395,76 @@ mod tests {
 
         assert!(client.events().any(header_ready_event));
     }
+
+    const ECH_PUBLIC_NAME: &str = "public.example";
+
+    /// Run the handshake until the client has finished with it, which
+    /// is when it either connects or closes.
+    fn ech_handshake(client: &mut Http3Client, server: &mut TestServer) {
+        let authentication_needed = |e| matches!(e, Http3ClientEvent::AuthenticationNeeded);
+        let mut out = client.process(None, now());
+        while client.state() == Http3State::Initializing {
+            out = server.conn.process(out.dgram(), now());
+            if client.events().any(authentication_needed) {
+                client.authenticated(AuthenticationStatus::Ok, now());
+            }
+            out = client.process(out.dgram(), now());
+        }
+        let _ = server.conn.process(out.dgram(), now());
+    }
+
+    #[test]
+    fn ech_accepted() {
+        let mut server = TestServer::new();
+        let (sk, pk) = ech::generate_keys().expect("should make ECH keys");
+        server
+            .conn
+            .server_enable_ech(1, ECH_PUBLIC_NAME, &sk, &pk)
+            .expect("should enable ECH");
+        let mut client = default_http3_client();
+        client
+            .enable_ech(server.conn.ech_config())
+            .expect("should enable ECH");
+
+        ech_handshake(&mut client, &mut server);
+        assert_eq!(client.state(), Http3State::Connected);
+        assert!(client.events().any(|e| e == Http3ClientEvent::EchAccepted));
+    }
+
+    #[test]
+    fn ech_rejected() {
+        let mut server = TestServer::new();
+        let (sk, pk) = ech::generate_keys().expect("should make ECH keys");
+        server
+            .conn
+            .server_enable_ech(1, ECH_PUBLIC_NAME, &sk, &pk)
+            .expect("should enable ECH");
+        // The client has a configuration that the server doesn't know about.
+        let (_, other_pk) = ech::generate_keys().expect("should make ECH keys");
+        let config =
+            ech::encode_config(2, ECH_PUBLIC_NAME, &other_pk).expect("should encode ECH config");
+        let mut client = default_http3_client();
+        client.enable_ech(&config).expect("should enable ECH");
+
+        ech_handshake(&mut client, &mut server);
+        assert!(matches!(client.state(), Http3State::Closing(_)));
+        let retry_configs = server.conn.ech_config().to_vec();
+        assert!(client
+            .events()
+            .any(|e| e == Http3ClientEvent::EchRejected(retry_configs.clone())));
+
+        // The retry configurations work on a new connection.
+        let mut server = TestServer::new();
+        server
+            .conn
+            .server_enable_ech(1, ECH_PUBLIC_NAME, &sk, &pk)
+            .expect("should enable ECH");
+        let mut client = default_http3_client();
+        client
+            .enable_ech(&retry_configs)
+            .expect("should enable ECH");
+        ech_handshake(&mut client, &mut server);
+        assert_eq!(client.state(), Http3State::Connected);
+        assert!(client.events().any(|e| e == Http3ClientEvent::EchAccepted));
+    }
 }
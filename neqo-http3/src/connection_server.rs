@@ -5,6 +5,7 @@
 // except according to those terms.
 
 use crate::connection::{HandleReadableOutput, Http3Connection, Http3State};
+use crate::headers_checks::MessageType;
 use crate::hframe::HFrame;
 use crate::recv_message::RecvMessage;
 use crate::send_message::SendMessage;
@@ -13,6 +14,7 @@ use crate::{Error, Header, Res};
 use neqo_common::{matches, qdebug, qinfo, qtrace};
 use neqo_qpack::QpackSettings;
 use neqo_transport::{AppError, Connection, ConnectionEvent, StreamType};
+use std::cmp::max;
 use std::time::Instant;
 
 #[derive(Debug)]
@@ -20,6 +22,9 @@ pub struct Http3ServerHandler {
     base_handler: Http3Connection,
     events: Http3ServerConnEvents,
     needs_processing: bool,
+    /// The lowest request stream ID that the client has not used yet.
+    /// This is what goes in a GOAWAY frame.
+    next_request_stream_id: u64,
 }
 
 impl ::std::fmt::Display for Http3ServerHandler {
@@ -29,31 +34,148 @@ impl ::std::fmt::Display for Http3ServerHandler {
 }
 
 impl Http3ServerHandler {
-    pub(crate) fn new(qpack_settings: QpackSettings) -> Self {
+    pub(crate) fn new(qpack_settings: QpackSettings, webtransport: bool, datagrams: bool) -> Self {
+        let mut base_handler = Http3Connection::new(qpack_settings);
+        base_handler
+            .set_webtransport(webtransport)
+            .expect("a new connection is initializing");
+        base_handler
+            .set_datagrams(datagrams)
+            .expect("a new connection is initializing");
         Self {
-            base_handler: Http3Connection::new(qpack_settings),
+            base_handler,
             events: Http3ServerConnEvents::default(),
             needs_processing: false,
+            next_request_stream_id: 0,
         }
     }
 
-    /// Supply a response for a request.
+    /// Supply a response for a request.  `trailers` are sent after `data`.
     pub(crate) fn set_response(
         &mut self,
         stream_id: u64,
         headers: &[Header],
         data: &[u8],
+        trailers: Option<&[Header]>,
     ) -> Res<()> {
         self.base_handler
             .send_streams
             .get_mut(&stream_id)
             .ok_or(Error::InvalidStreamId)?
-            .set_message(headers, Some(data))?;
+            .set_message(headers, Some(data), trailers)?;
         self.base_handler
             .insert_streams_have_data_to_send(stream_id);
         Ok(())
     }
 
+    /// Offer extended CONNECT to the client.  This is used for CONNECT-UDP.
+    pub(crate) fn set_extended_connect(&mut self, enabled: bool) {
+        self.base_handler
+            .set_extended_connect(enabled)
+            .expect("a new connection is initializing");
+    }
+
+    /// Supply only the response headers and keep the stream open, e.g. to accept a
+    /// CONNECT request.  Data is sent afterwards using `send_data`.
+    pub(crate) fn set_response_headers(&mut self, stream_id: u64, headers: &[Header]) -> Res<()> {
+        self.base_handler
+            .send_streams
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?
+            .set_headers(headers)?;
+        self.base_handler
+            .insert_streams_have_data_to_send(stream_id);
+        Ok(())
+    }
+
+    /// Send data on a stream whose response headers were set with `set_response_headers`.
+    /// This returns the amount of data sent, which is 0 if the headers have not been sent yet.
+    /// This is limited by flow control and by the congestion window, so that a large
+    /// body isn't buffered all at once.  `DataWritable` follows when more can be sent.
+    pub(crate) fn send_data(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        buf: &[u8],
+    ) -> Res<usize> {
+        let sent = self
+            .base_handler
+            .send_streams
+            .get_mut(&stream_id)
+            .ok_or(Error::InvalidStreamId)?
+            .send_body_now(conn, buf)?;
+        self.needs_processing = true;
+        Ok(sent)
+    }
+
+    /// Whether the client supports WebTransport too.
+    pub(crate) fn webtransport_enabled(&self) -> bool {
+        self.base_handler.webtransport_enabled()
+    }
+
+    /// Accept a WebTransport session request with a 200 response.
+    pub(crate) fn webtransport_accept(&mut self, session_id: u64) -> Res<()> {
+        let headers = [(String::from(":status"), String::from("200"))];
+        self.set_response_headers(session_id, &headers)?;
+        self.base_handler.webtransport_add_session(session_id);
+        Ok(())
+    }
+
+    /// Open a stream for a WebTransport session.
+    pub(crate) fn webtransport_create_stream(
+        &mut self,
+        conn: &mut Connection,
+        session_id: u64,
+        stream_type: StreamType,
+    ) -> Res<u64> {
+        let stream_id =
+            self.base_handler
+                .webtransport_create_stream(conn, session_id, stream_type)?;
+        self.needs_processing = true;
+        Ok(stream_id)
+    }
+
+    /// Send data on a stream of a WebTransport session.
+    pub(crate) fn webtransport_send_data(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        buf: &[u8],
+    ) -> Res<usize> {
+        let sent = self.base_handler.webtransport_send(conn, stream_id, buf)?;
+        self.needs_processing = true;
+        Ok(sent)
+    }
+
+    /// Read data from a stream of a WebTransport session.
+    pub(crate) fn webtransport_read_data(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        buf: &mut [u8],
+    ) -> Res<(usize, bool)> {
+        self.base_handler.webtransport_recv(conn, stream_id, buf)
+    }
+
+    /// Close the sending side of a stream.
+    pub(crate) fn stream_close_send(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
+        self.base_handler.stream_close_send(conn, stream_id)?;
+        self.needs_processing = true;
+        Ok(())
+    }
+
+    /// Send an HTTP datagram for a request.
+    pub(crate) fn send_datagram(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        data: &[u8],
+    ) -> Res<()> {
+        self.base_handler.send_datagram(conn, stream_id, data)?;
+        self.needs_processing = true;
+        Ok(())
+    }
+
     /// Reset a request.
     pub fn stream_reset(
         &mut self,
@@ -67,6 +189,22 @@ impl Http3ServerHandler {
         Ok(())
     }
 
+    /// Send a GOAWAY frame.  Requests that the client has already started are
+    /// completed as usual, newer requests are rejected.
+    pub(crate) fn goaway(&mut self) {
+        if self.base_handler.state() != Http3State::Connected {
+            return;
+        }
+        let stream_id = self.next_request_stream_id;
+        qinfo!([self], "Send goaway stream_id={}.", stream_id);
+        self.base_handler
+            .queue_control_frame(&HFrame::Goaway { stream_id });
+        self.base_handler.state = Http3State::GoingAway(stream_id);
+        self.events
+            .connection_state_change(self.base_handler.state());
+        self.needs_processing = true;
+    }
+
     /// Process HTTTP3 layer.
     pub fn process_http3(&mut self, conn: &mut Connection, now: Instant) {
         qtrace!([self], "Process http3 internal.");
@@ -77,7 +215,11 @@ impl Http3ServerHandler {
         let res = self.check_connection_events(conn);
         if !self.check_result(conn, now, &res) && self.base_handler.state().active() {
             let res = self.base_handler.process_sending(conn);
-            self.check_result(conn, now, &res);
+            if !self.check_result(conn, now, &res) {
+                for stream_id in self.base_handler.writable_streams(conn) {
+                    self.events.data_writable(stream_id);
+                }
+            }
         }
     }
 
@@ -122,11 +264,30 @@ impl Http3ServerHandler {
             qdebug!([self], "check_connection_events - event {:?}.", e);
             match e {
                 ConnectionEvent::NewStream { stream_id } => match stream_id.stream_type() {
-                    StreamType::BiDi => self.base_handler.add_streams(
-                        stream_id.as_u64(),
-                        SendMessage::new(stream_id.as_u64(), Box::new(self.events.clone())),
-                        RecvMessage::new(stream_id.as_u64(), Box::new(self.events.clone()), None),
-                    ),
+                    StreamType::BiDi => {
+                        let stream_id = stream_id.as_u64();
+                        if let Http3State::GoingAway(goaway_stream_id) = self.base_handler.state() {
+                            if stream_id >= goaway_stream_id {
+                                qinfo!([self], "Reject request {} after goaway.", stream_id);
+                                let err = Error::HttpRequestRejected.code();
+                                let _ = conn.stream_stop_sending(stream_id, err);
+                                let _ = conn.stream_reset_send(stream_id, err);
+                                continue;
+                            }
+                        }
+                        self.next_request_stream_id =
+                            max(self.next_request_stream_id, stream_id + 4);
+                        self.base_handler.add_streams(
+                            stream_id,
+                            SendMessage::new(stream_id, Box::new(self.events.clone())),
+                            RecvMessage::new(
+                                MessageType::Request,
+                                stream_id,
+                                Box::new(self.events.clone()),
+                                None,
+                            ),
+                        )
+                    }
                     StreamType::UniDi => {
                         if self
                             .base_handler
@@ -143,9 +304,12 @@ impl Http3ServerHandler {
                     stream_id,
                     app_error,
                 } => {
-                    let _ = self
+                    if self
                         .base_handler
-                        .handle_stream_reset(conn, stream_id, app_error)?;
+                        .handle_stream_reset(conn, stream_id, app_error)?
+                    {
+                        self.events.reset(stream_id, app_error);
+                    }
                 }
                 ConnectionEvent::SendStreamStopSending {
                     stream_id,
@@ -157,11 +321,29 @@ impl Http3ServerHandler {
                             .connection_state_change(self.base_handler.state());
                     }
                 }
-                ConnectionEvent::AuthenticationNeeded | ConnectionEvent::ZeroRttRejected => {
-                    return Err(Error::HttpInternal)
+                ConnectionEvent::Datagram(dgram) => {
+                    if let Some((stream_id, data)) = self.base_handler.handle_datagram(&dgram)? {
+                        if self.base_handler.is_webtransport_session(stream_id) {
+                            self.events.webtransport_datagram(stream_id, data.to_vec());
+                        } else {
+                            self.events.datagram(stream_id, data.to_vec());
+                        }
+                    }
                 }
-                ConnectionEvent::SendStreamWritable { .. }
-                | ConnectionEvent::SendStreamComplete { .. }
+                ConnectionEvent::AuthenticationNeeded
+                | ConnectionEvent::ZeroRttRejected
+                | ConnectionEvent::EchAccepted
+                | ConnectionEvent::EchRejected(_)
+                | ConnectionEvent::ResumptionToken(_) => return Err(Error::HttpInternal),
+                ConnectionEvent::SendStreamWritable { stream_id } => {
+                    if let Some(s) = self.base_handler.send_streams.get_mut(&stream_id.as_u64()) {
+                        if s.is_state_sending_data() {
+                            s.clear_blocked();
+                            self.events.data_writable(stream_id.as_u64());
+                        }
+                    }
+                }
+                ConnectionEvent::SendStreamComplete { .. }
                 | ConnectionEvent::SendStreamCreatable { .. } => {}
             }
         }
@@ -186,7 +368,20 @@ impl Http3ServerHandler {
                 }
                 Ok(())
             }
-            _ => Ok(()),
+            HandleReadableOutput::MalformedMessage => {
+                self.events.reset(stream_id, Error::HttpMessageError.code());
+                Ok(())
+            }
+            HandleReadableOutput::WebTransportStream { session_id } => {
+                self.events.webtransport_new_stream(stream_id, session_id);
+                Ok(())
+            }
+            HandleReadableOutput::WebTransportData { session_id } => {
+                self.events
+                    .webtransport_data_readable(stream_id, session_id);
+                Ok(())
+            }
+            HandleReadableOutput::NoOutput => Ok(()),
         }
     }
 
@@ -204,7 +399,13 @@ impl Http3ServerHandler {
         {
             // receiving side may be closed already, just ignore an error in the following line.
             let _ = conn.stream_stop_sending(stop_stream_id, app_err);
-            self.base_handler.recv_streams.remove(&stop_stream_id);
+            self.base_handler.remove_recv_stream(stop_stream_id);
+            self.events.reset(stop_stream_id, app_err);
+        } else if self
+            .base_handler
+            .webtransport_stop_sending(conn, stop_stream_id, app_err)
+        {
+            // The stream of a session can't send any more.
         } else if self.base_handler.is_critical_stream(stop_stream_id) {
             return Err(Error::HttpClosedCriticalStream);
         }
@@ -238,6 +439,11 @@ impl Http3ServerHandler {
                         }
                         Ok((amount, fin))
                     }
+                    Err(Error::HttpMessageError) => {
+                        self.base_handler.reset_malformed_stream(conn, stream_id);
+                        self.events.reset(stream_id, Error::HttpMessageError.code());
+                        Err(Error::HttpMessageError)
+                    }
                     Err(e) => {
                         self.close(conn, now, &e);
                         Err(e)
@@ -0,0 +1,291 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Checks of received headers and trailers.  A message that fails them is
+// malformed and its stream is reset with H3_MESSAGE_ERROR.
+
+use crate::{Error, Header, Res};
+
+/// Headers that are only meaningful to HTTP/1.1 connections and must not be
+/// present in an HTTP/3 message.  `te` is handled separately.
+const CONNECTION_SPECIFIC_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const REQUEST_PSEUDO_HEADERS: &[&str] = &[":method", ":scheme", ":authority", ":path", ":protocol"];
+const RESPONSE_PSEUDO_HEADERS: &[&str] = &[":status"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MessageType {
+    Request,
+    /// A response.  `head` is set if the request was a HEAD request, so the response
+    /// has no content whatever its `content-length` says.
+    Response {
+        head: bool,
+    },
+}
+
+fn field_valid(name: &str, value: &str) -> Res<()> {
+    if name.is_empty()
+        || name.bytes().any(|b| b.is_ascii_uppercase())
+        || value.bytes().any(|b| b == 0 || b == b'\r' || b == b'\n')
+        || CONNECTION_SPECIFIC_HEADERS.contains(&name)
+        || (name == "te" && value != "trailers")
+    {
+        return Err(Error::HttpMessageError);
+    }
+    Ok(())
+}
+
+fn find<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// The response status, if it is a valid status code.
+pub(crate) fn status(headers: &[Header]) -> Option<u16> {
+    let value = find(headers, ":status")?;
+    if value.len() != 3 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok().filter(|s| (100..600).contains(s))
+}
+
+/// Check the headers of a request or a response.  Pseudo-headers must come before
+/// all other fields, each at most once, and only those defined for the message type
+/// are allowed.  A CONNECT request has only `:method` and `:authority`, unless it
+/// is an extended CONNECT with `:protocol`.
+pub(crate) fn headers_valid(headers: &[Header], message_type: MessageType) -> Res<()> {
+    let allowed = match message_type {
+        MessageType::Request => REQUEST_PSEUDO_HEADERS,
+        MessageType::Response { .. } => RESPONSE_PSEUDO_HEADERS,
+    };
+    let mut pseudo_headers_done = false;
+    for (i, (name, value)) in headers.iter().enumerate() {
+        if name.starts_with(':') {
+            if pseudo_headers_done
+                || !allowed.contains(&name.as_str())
+                || headers[..i].iter().any(|(n, _)| n == name)
+            {
+                return Err(Error::HttpMessageError);
+            }
+        } else {
+            pseudo_headers_done = true;
+            field_valid(name, value)?;
+        }
+    }
+
+    let valid = match message_type {
+        MessageType::Request => {
+            let method = find(headers, ":method");
+            let protocol = find(headers, ":protocol");
+            if method == Some("CONNECT") && protocol.is_none() {
+                find(headers, ":authority").is_some()
+                    && find(headers, ":scheme").is_none()
+                    && find(headers, ":path").is_none()
+            } else {
+                method.is_some()
+                    && (protocol.is_none()
+                        || (method == Some("CONNECT") && find(headers, ":authority").is_some()))
+                    && find(headers, ":scheme").is_some()
+                    && find(headers, ":path").map_or(false, |p| !p.is_empty())
+            }
+        }
+        // 101 (Switching Protocols) is not used in HTTP/3.
+        MessageType::Response { .. } => status(headers).map_or(false, |s| s != 101),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::HttpMessageError)
+    }
+}
+
+/// Trailers cannot carry pseudo-headers.
+pub(crate) fn trailers_valid(trailers: &[Header]) -> Res<()> {
+    for (name, value) in trailers {
+        if name.starts_with(':') {
+            return Err(Error::HttpMessageError);
+        }
+        field_valid(name, value)?;
+    }
+    Ok(())
+}
+
+/// The value of `content-length`, if the message has content of a known length.
+/// All `content-length` fields must hold the same number.
+pub(crate) fn content_length(headers: &[Header]) -> Res<Option<u64>> {
+    let mut len = None;
+    for (_, value) in headers.iter().filter(|(n, _)| n == "content-length") {
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Error::HttpMessageError);
+        }
+        let v = value.parse().map_err(|_| Error::HttpMessageError)?;
+        if len.map_or(false, |l| l != v) {
+            return Err(Error::HttpMessageError);
+        }
+        len = Some(v);
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_length, headers_valid, trailers_valid, MessageType};
+    use crate::{Error, Header};
+
+    fn h(fields: &[(&str, &str)]) -> Vec<Header> {
+        fields
+            .iter()
+            .map(|(n, v)| (String::from(*n), String::from(*v)))
+            .collect()
+    }
+
+    const RESPONSE: MessageType = MessageType::Response { head: false };
+
+    #[test]
+    fn request() {
+        let get = h(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/"),
+            ("accept", "*/*"),
+        ]);
+        assert_eq!(headers_valid(&get, MessageType::Request), Ok(()));
+        let connect = h(&[(":method", "CONNECT"), (":authority", "example.com:443")]);
+        assert_eq!(headers_valid(&connect, MessageType::Request), Ok(()));
+        let extended = h(&[
+            (":method", "CONNECT"),
+            (":protocol", "connect-udp"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/.well-known/masque/udp/192.0.2.6/443/"),
+        ]);
+        assert_eq!(headers_valid(&extended, MessageType::Request), Ok(()));
+    }
+
+    #[test]
+    fn bad_request() {
+        let bad = [
+            // Missing :path.
+            h(&[(":method", "GET"), (":scheme", "https")]),
+            // An empty :path.
+            h(&[(":method", "GET"), (":scheme", "https"), (":path", "")]),
+            // A pseudo-header after a regular one.
+            h(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                ("accept", "*/*"),
+                (":path", "/"),
+            ]),
+            // A repeated pseudo-header.
+            h(&[
+                (":method", "GET"),
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/"),
+            ]),
+            // A response pseudo-header.
+            h(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/"),
+                (":status", "200"),
+            ]),
+            // CONNECT with :path.
+            h(&[
+                (":method", "CONNECT"),
+                (":authority", "example.com:443"),
+                (":path", "/"),
+            ]),
+            // :protocol without CONNECT.
+            h(&[
+                (":method", "GET"),
+                (":protocol", "connect-udp"),
+                (":scheme", "https"),
+                (":path", "/"),
+            ]),
+        ];
+        for headers in &bad {
+            assert_eq!(
+                headers_valid(headers, MessageType::Request),
+                Err(Error::HttpMessageError)
+            );
+        }
+    }
+
+    #[test]
+    fn response() {
+        assert_eq!(headers_valid(&h(&[(":status", "200")]), RESPONSE), Ok(()));
+        for status in &["", "20", "2000", "abc", "099", "600", "101"] {
+            assert_eq!(
+                headers_valid(&h(&[(":status", *status)]), RESPONSE),
+                Err(Error::HttpMessageError)
+            );
+        }
+        assert_eq!(
+            headers_valid(&h(&[("server", "neqo")]), RESPONSE),
+            Err(Error::HttpMessageError)
+        );
+    }
+
+    #[test]
+    fn connection_specific() {
+        for (name, value) in &[
+            ("connection", "close"),
+            ("keep-alive", "timeout=5"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "h2c"),
+            ("te", "gzip"),
+            ("Server", "neqo"),
+            ("server", "ne\nqo"),
+        ] {
+            assert_eq!(
+                headers_valid(&h(&[(":status", "200"), (*name, *value)]), RESPONSE),
+                Err(Error::HttpMessageError)
+            );
+        }
+        assert_eq!(
+            headers_valid(&h(&[(":status", "200"), ("te", "trailers")]), RESPONSE),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn trailers() {
+        assert_eq!(trailers_valid(&h(&[("grpc-status", "0")])), Ok(()));
+        assert_eq!(
+            trailers_valid(&h(&[(":status", "200")])),
+            Err(Error::HttpMessageError)
+        );
+    }
+
+    #[test]
+    fn length() {
+        assert_eq!(content_length(&h(&[(":status", "200")])), Ok(None));
+        assert_eq!(
+            content_length(&h(&[("content-length", "3"), ("content-length", "3")])),
+            Ok(Some(3))
+        );
+        for len in &["", "+3", "-3", "3 ", "abc"] {
+            assert_eq!(
+                content_length(&h(&[("content-length", *len)])),
+                Err(Error::HttpMessageError)
+            );
+        }
+        assert_eq!(
+            content_length(&h(&[("content-length", "3"), ("content-length", "4")])),
+            Err(Error::HttpMessageError)
+        );
+    }
+}
@@ -23,6 +23,8 @@ const H3_FRAME_TYPE_SETTINGS: HFrameType = 0x4;
 const H3_FRAME_TYPE_PUSH_PROMISE: HFrameType = 0x5;
 const H3_FRAME_TYPE_GOAWAY: HFrameType = 0x7;
 const H3_FRAME_TYPE_MAX_PUSH_ID: HFrameType = 0xd;
+/// Starts a bidirectional stream that belongs to a WebTransport session.
+pub(crate) const H3_FRAME_TYPE_WEBTRANSPORT_STREAM: HFrameType = 0x41;
 
 // data for DATA frame is not read into HFrame::Data.
 #[derive(PartialEq, Debug)]
@@ -49,6 +51,10 @@ pub(crate) enum HFrame {
     MaxPushId {
         push_id: u64,
     },
+    // This has no length, the rest of the stream is data for the session.
+    WebTransportStream {
+        session_id: u64,
+    },
 }
 
 impl HFrame {
@@ -61,6 +67,7 @@ impl HFrame {
             Self::PushPromise { .. } => H3_FRAME_TYPE_PUSH_PROMISE,
             Self::Goaway { .. } => H3_FRAME_TYPE_GOAWAY,
             Self::MaxPushId { .. } => H3_FRAME_TYPE_MAX_PUSH_ID,
+            Self::WebTransportStream { .. } => H3_FRAME_TYPE_WEBTRANSPORT_STREAM,
         }
     }
 
@@ -101,6 +108,9 @@ impl HFrame {
                     enc_inner.encode_varint(*push_id);
                 });
             }
+            Self::WebTransportStream { session_id } => {
+                enc.encode_varint(*session_id);
+            }
         }
     }
 }
@@ -214,6 +224,8 @@ impl HFrameReader {
                             self.state = match self.hframe_type {
                                 // DATA payload are left on the quic stream and picked up separately
                                 H3_FRAME_TYPE_DATA => HFrameReaderState::Done,
+                                // This has a session ID in place of the length.
+                                H3_FRAME_TYPE_WEBTRANSPORT_STREAM => HFrameReaderState::Done,
 
                                 // for other frames get all data before decoding.
                                 H3_FRAME_TYPE_CANCEL_PUSH
@@ -346,6 +358,9 @@ impl HFrameReader {
                     _ => return Err(Error::NotEnoughData),
                 },
             },
+            H3_FRAME_TYPE_WEBTRANSPORT_STREAM => HFrame::WebTransportStream {
+                session_id: self.hframe_len,
+            },
             _ => panic!("We should not be in state Done with unknown frame type!"),
         };
         self.reset();
@@ -422,6 +437,13 @@ mod tests {
         enc_dec(&f, "0003010203", 3);
     }
 
+    #[test]
+    fn test_webtransport_stream_frame() {
+        // The data that follows belongs to the session.
+        let f = HFrame::WebTransportStream { session_id: 4 };
+        enc_dec(&f, "4041040102", 2);
+    }
+
     #[test]
     fn test_headers_frame() {
         let f = HFrame::Headers {
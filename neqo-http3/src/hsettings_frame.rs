@@ -13,18 +13,28 @@ type SettingsType = u64;
 const SETTINGS_MAX_HEADER_LIST_SIZE: SettingsType = 0x6;
 const SETTINGS_QPACK_MAX_TABLE_CAPACITY: SettingsType = 0x1;
 const SETTINGS_QPACK_BLOCKED_STREAMS: SettingsType = 0x7;
+const SETTINGS_ENABLE_CONNECT_PROTOCOL: SettingsType = 0x8;
+const SETTINGS_ENABLE_WEBTRANSPORT: SettingsType = 0x2b60_3742;
+const SETTINGS_H3_DATAGRAM: SettingsType = 0x33;
 
 #[derive(Clone, PartialEq, Debug, Copy)]
 pub(crate) enum HSettingType {
     MaxHeaderListSize,
     MaxTableCapacity,
     BlockedStreams,
+    EnableConnectProtocol,
+    EnableWebTransport,
+    H3Datagram,
 }
 
 fn hsetting_default(setting_type: HSettingType) -> u64 {
     match setting_type {
         HSettingType::MaxHeaderListSize => 1 << 62,
-        HSettingType::MaxTableCapacity | HSettingType::BlockedStreams => 0,
+        HSettingType::MaxTableCapacity
+        | HSettingType::BlockedStreams
+        | HSettingType::EnableConnectProtocol
+        | HSettingType::EnableWebTransport
+        | HSettingType::H3Datagram => 0,
     }
 }
 
@@ -78,6 +88,18 @@ impl HSettings {
                         enc_inner.encode_varint(SETTINGS_QPACK_BLOCKED_STREAMS as u64);
                         enc_inner.encode_varint(iter.value);
                     }
+                    HSettingType::EnableConnectProtocol => {
+                        enc_inner.encode_varint(SETTINGS_ENABLE_CONNECT_PROTOCOL as u64);
+                        enc_inner.encode_varint(iter.value);
+                    }
+                    HSettingType::EnableWebTransport => {
+                        enc_inner.encode_varint(SETTINGS_ENABLE_WEBTRANSPORT as u64);
+                        enc_inner.encode_varint(iter.value);
+                    }
+                    HSettingType::H3Datagram => {
+                        enc_inner.encode_varint(SETTINGS_H3_DATAGRAM as u64);
+                        enc_inner.encode_varint(iter.value);
+                    }
                 }
             }
         });
@@ -98,6 +120,27 @@ impl HSettings {
                 (Some(SETTINGS_QPACK_BLOCKED_STREAMS), Some(value)) => self
                     .settings
                     .push(HSetting::new(HSettingType::BlockedStreams, value)),
+                (Some(SETTINGS_ENABLE_CONNECT_PROTOCOL), Some(value)) => {
+                    if value > 1 {
+                        return Err(Error::HttpSettings);
+                    }
+                    self.settings
+                        .push(HSetting::new(HSettingType::EnableConnectProtocol, value))
+                }
+                (Some(SETTINGS_ENABLE_WEBTRANSPORT), Some(value)) => {
+                    if value > 1 {
+                        return Err(Error::HttpSettings);
+                    }
+                    self.settings
+                        .push(HSetting::new(HSettingType::EnableWebTransport, value))
+                }
+                (Some(SETTINGS_H3_DATAGRAM), Some(value)) => {
+                    if value > 1 {
+                        return Err(Error::HttpSettings);
+                    }
+                    self.settings
+                        .push(HSetting::new(HSettingType::H3Datagram, value))
+                }
                 // other supported settings here
                 (Some(_), Some(_)) => {} // ignore unknown setting, it is fine.
                 _ => return Err(Error::NotEnoughData),
@@ -9,11 +9,13 @@
 #![allow(clippy::pub_enum_variant_names)]
 
 mod client_events;
+pub mod connect_udp;
 mod connection;
 pub mod connection_client;
 mod connection_server;
 mod control_stream_local;
 mod control_stream_remote;
+mod headers_checks;
 pub mod hframe;
 mod hsettings_frame;
 mod push_controller;
@@ -23,6 +25,7 @@ pub mod server;
 mod server_connection_events;
 mod server_events;
 mod stream_type_reader;
+pub mod webtransport;
 
 use neqo_qpack::Error as QpackError;
 pub use neqo_transport::Output;
@@ -53,8 +56,10 @@ pub enum Error {
     HttpRequestRejected,
     HttpRequestCancelled,
     HttpRequestIncomplete,
+    HttpMessageError,
     HttpConnect,
     HttpVersionFallback,
+    HttpDatagram,
     QpackError(neqo_qpack::Error),
 
     // Internal errors from here.
@@ -91,8 +96,10 @@ impl Error {
             Self::HttpRequestRejected => 0x10b,
             Self::HttpRequestCancelled => 0x10c,
             Self::HttpRequestIncomplete => 0x10d,
+            Self::HttpMessageError => 0x10e,
             Self::HttpConnect => 0x10f,
             Self::HttpVersionFallback => 0x110,
+            Self::HttpDatagram => 0x33,
             Self::QpackError(e) => e.code(),
             // These are all internal errors.
             _ => 3,
@@ -128,8 +135,10 @@ impl From<AppError> for Error {
             0x10b => Self::HttpRequestRejected,
             0x10c => Self::HttpRequestCancelled,
             0x10d => Self::HttpRequestIncomplete,
+            0x10e => Self::HttpMessageError,
             0x10f => Self::HttpConnect,
             0x110 => Self::HttpVersionFallback,
+            0x33 => Self::HttpDatagram,
             0x200 => Self::QpackError(QpackError::DecompressionFailed),
             0x201 => Self::QpackError(QpackError::EncoderStream),
             0x202 => Self::QpackError(QpackError::DecoderStream),
@@ -4,6 +4,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::headers_checks::{self, MessageType};
 use crate::hframe::{HFrame, HFrameReader};
 use crate::push_controller::PushController;
 use crate::{Error, Header, Res};
@@ -18,6 +19,8 @@ use std::rc::Rc;
 
 pub(crate) trait RecvMessageEvents: Debug {
     fn header_ready(&self, stream_id: u64, headers: Option<Vec<Header>>, fin: bool);
+    fn informational_headers(&self, stream_id: u64, headers: Vec<Header>);
+    fn trailers_ready(&self, stream_id: u64, trailers: Vec<Header>);
     fn data_readable(&self, stream_id: u64);
 }
 
@@ -27,15 +30,27 @@ pub(crate) trait RecvMessageEvents: Debug {
  *                                also get a PUSH_PROMISE frame.
  *    DecodingHeaders : In this step the headers will be decoded. The stream
  *                      may be blocked in this state on encoder instructions.
+ *                      If the headers are for an informational (1xx) response
+ *                      we go back to WaitingForResponseHeaders.
  *    WaitingForData : we got HEADERS, we are waiting for one or more data
  *                     frames. In this state we can receive one or more
  *                     PUSH_PROMIS frames or a HEADERS frame carrying trailers.
  *    ReadingData : we got a DATA frame, now we letting the app read payload.
  *                  From here we will go back to WaitingForData state to wait
  *                  for more data frames or to CLosed state
+ *    DecodingTrailers : Like DecodingHeaders, but for a HEADERS frame that
+ *                       follows the data.  From here we go to
+ *                       WaitingForFinAfterTrailers.
  *    ClosePending : waiting for app to pick up data, after that we can delete
  * the TransactionClient.
  *    Closed
+ *    WebTransportStream : the request stream started with a WEBTRANSPORT_STREAM
+ *                         frame, so it belongs to a WebTransport session and
+ *                         carries no message.
+ *
+ * Headers and trailers are checked once they are decoded and the amount of
+ * data is checked against `content-length`.  A malformed message results in
+ * `Error::HttpMessageError`, which is a stream error.
  */
 #[derive(PartialEq, Debug)]
 enum RecvMessageState {
@@ -43,18 +58,23 @@ enum RecvMessageState {
     DecodingHeaders { header_block: Vec<u8>, fin: bool },
     WaitingForData,
     ReadingData { remaining_data_len: usize },
+    DecodingTrailers { header_block: Vec<u8>, fin: bool },
     WaitingForFinAfterTrailers,
     ClosePending, // Close must first be read by application
     Closed,
+    WebTransportStream { session_id: u64 },
 }
 
 #[derive(Debug)]
 pub(crate) struct RecvMessage {
     state: RecvMessageState,
+    message_type: MessageType,
     frame_reader: HFrameReader,
     conn_events: Box<dyn RecvMessageEvents>,
     push_handler: Option<Rc<RefCell<PushController>>>,
     stream_id: u64,
+    content_length: Option<u64>,
+    data_len: u64,
 }
 
 impl ::std::fmt::Display for RecvMessage {
@@ -65,16 +85,20 @@ impl ::std::fmt::Display for RecvMessage {
 
 impl RecvMessage {
     pub fn new(
+        message_type: MessageType,
         stream_id: u64,
         conn_events: Box<dyn RecvMessageEvents>,
         push_handler: Option<Rc<RefCell<PushController>>>,
     ) -> Self {
         Self {
             state: RecvMessageState::WaitingForResponseHeaders,
+            message_type,
             frame_reader: HFrameReader::new(),
             conn_events,
             push_handler,
             stream_id,
+            content_length: None,
+            data_len: 0,
         }
     }
 
@@ -82,14 +106,17 @@ impl RecvMessage {
         match self.state {
             RecvMessageState::WaitingForResponseHeaders => {
                 if header_block.is_empty() {
-                    self.add_headers(None, fin);
+                    self.add_headers(None, fin)?;
                 } else {
                     self.state = RecvMessageState::DecodingHeaders { header_block, fin };
                 }
              }
             RecvMessageState::WaitingForData => {
-                // TODO implement trailers, for now just ignore them.
-                self.state = RecvMessageState::WaitingForFinAfterTrailers;
+                if header_block.is_empty() {
+                    self.state = RecvMessageState::WaitingForFinAfterTrailers;
+                } else {
+                    self.state = RecvMessageState::DecodingTrailers { header_block, fin };
+                }
             }
             RecvMessageState::WaitingForFinAfterTrailers => {
                 return Err(Error::HttpFrameUnexpected);
@@ -105,6 +132,10 @@ impl RecvMessage {
                 return Err(Error::HttpFrameUnexpected);
             }
             RecvMessageState::WaitingForData => {
+                self.data_len = self.data_len.saturating_add(len);
+                if self.content_length.map_or(false, |l| self.data_len > l) {
+                    return Err(Error::HttpMessageError);
+                }
                 if len > 0 {
                     if fin {
                         return Err(Error::HttpFrame);
@@ -119,8 +150,29 @@ impl RecvMessage {
         Ok(())
     }
 
-    fn add_headers(&mut self, headers: Option<Vec<Header>>, fin: bool) {
+    /// Check that the message had as much data as its `content-length` said.
+    fn check_content_length(&self) -> Res<()> {
+        if self.content_length.map_or(false, |l| l != self.data_len) {
+            Err(Error::HttpMessageError)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn set_content_length(&mut self, headers: &[Header], status: Option<u16>) -> Res<()> {
+        // Responses to HEAD requests and 204 and 304 responses never have content.
+        let no_content = self.message_type == MessageType::Response { head: true }
+            || status == Some(204)
+            || status == Some(304);
+        if !no_content {
+            self.content_length = headers_checks::content_length(headers)?;
+        }
+        Ok(())
+    }
+
+    fn add_headers(&mut self, headers: Option<Vec<Header>>, fin: bool) -> Res<()> {
         if fin {
+            self.check_content_length()?;
             self.conn_events.header_ready(self.stream_id, headers, true);
             self.state = RecvMessageState::Closed;
         } else {
@@ -128,9 +180,10 @@ impl RecvMessage {
                 .header_ready(self.stream_id, headers, false);
             self.state = RecvMessageState::WaitingForData;
         }
+        Ok(())
     }
 
-    fn set_state_to_close_pending(&mut self) {
+    fn set_state_to_close_pending(&mut self) -> Res<()> {
         // Stream has received fin. Depending on headers state set header_ready
         // or data_readable event so that app can pick up the fin.
         qtrace!(
@@ -138,6 +191,7 @@ impl RecvMessage {
             "set_state_to_close_pending:  state={:?}",
             self.state
         );
+        self.check_content_length()?;
 
         match self.state {
             RecvMessageState::WaitingForResponseHeaders => {
@@ -153,6 +207,7 @@ impl RecvMessage {
         if !matches!(self.state, RecvMessageState::Closed) {
             self.state = RecvMessageState::ClosePending;
         }
+        Ok(())
     }
 
     fn recv_frame(&mut self, conn: &mut Connection) -> Res<(Option<HFrame>, bool)> {
@@ -189,6 +244,7 @@ impl RecvMessage {
                         if *remaining_data_len > 0 {
                             return Err(Error::HttpFrame);
                         }
+                        self.check_content_length()?;
                         self.state = RecvMessageState::Closed;
                         break Ok((written, fin));
                     } else if *remaining_data_len == 0 {
@@ -223,7 +279,7 @@ impl RecvMessage {
                 | RecvMessageState::WaitingForFinAfterTrailers => {
                     match self.recv_frame(conn)? {
                         (None, true) => {
-                            self.set_state_to_close_pending();
+                            self.set_state_to_close_pending()?;
                             break Ok(());
                         }
                         (None, false) => break Ok(()),
@@ -248,13 +304,28 @@ impl RecvMessage {
                                     .ok_or(Error::HttpId)?
                                     .borrow()
                                     .new_push_promise(push_id, header_block)?,
+                                HFrame::WebTransportStream { session_id }
+                                    if self.message_type == MessageType::Request
+                                        && self.state
+                                            == RecvMessageState::WaitingForResponseHeaders =>
+                                {
+                                    self.state =
+                                        RecvMessageState::WebTransportStream { session_id };
+                                    break Ok(());
+                                }
                                 _ => break Err(Error::HttpFrameUnexpected),
                             }
                             if matches!(self.state, RecvMessageState::Closed) {
                                 break Ok(());
                             }
-                            if fin && !matches!(self.state, RecvMessageState::DecodingHeaders{..}) {
-                                self.set_state_to_close_pending();
+                            if fin
+                                && !matches!(
+                                    self.state,
+                                    RecvMessageState::DecodingHeaders { .. }
+                                        | RecvMessageState::DecodingTrailers { .. }
+                                )
+                            {
+                                self.set_state_to_close_pending()?;
                                 break Ok(());
                             }
                         }
@@ -267,12 +338,52 @@ impl RecvMessage {
                     if let Some(headers) =
                         decoder.decode_header_block(header_block, self.stream_id)?
                     {
-                        self.add_headers(Some(headers), fin);
+                        headers_checks::headers_valid(&headers, self.message_type)?;
+                        let status = headers_checks::status(&headers);
+                        // Informational responses have a 1xx status code.  More headers follow them.
+                        if status.map_or(false, |s| s < 200) {
+                            // The final response must follow.
+                            if fin {
+                                break Err(Error::HttpFrame);
+                            }
+                            self.conn_events
+                                .informational_headers(self.stream_id, headers);
+                            self.state = RecvMessageState::WaitingForResponseHeaders;
+                        } else {
+                            self.set_content_length(&headers, status)?;
+                            self.add_headers(Some(headers), fin)?;
+                            if fin {
+                                break Ok(());
+                            }
+                        }
+                    } else {
+                        qinfo!([self], "decoding header is blocked.");
+                        break Ok(());
+                    }
+                }
+                RecvMessageState::DecodingTrailers {
+                    ref header_block,
+                    fin,
+                } => {
+                    if let Some(trailers) =
+                        decoder.decode_header_block(header_block, self.stream_id)?
+                    {
+                        headers_checks::trailers_valid(&trailers)?;
+                        if fin {
+                            self.check_content_length()?;
+                        }
+                        self.conn_events.trailers_ready(self.stream_id, trailers);
                         if fin {
+                            // When called from read_data the fin is returned directly.
+                            if post_readable_event {
+                                self.conn_events.data_readable(self.stream_id);
+                            }
+                            self.state = RecvMessageState::ClosePending;
                             break Ok(());
                         }
+                        self.state = RecvMessageState::WaitingForFinAfterTrailers;
                     } else {
-                        qinfo!([self], "decoding header is blocked.");
+                        qinfo!([self], "decoding trailers is blocked.");
                         break Ok(());
                     }
                 }
@@ -285,6 +396,7 @@ impl RecvMessage {
                 RecvMessageState::ClosePending | RecvMessageState::Closed => {
                     panic!("Stream readable after being closed!");
                 }
+                RecvMessageState::WebTransportStream { .. } => break Ok(()),
             };
         }
     }
@@ -296,4 +408,13 @@ impl RecvMessage {
     pub fn done(&self) -> bool {
         self.state == RecvMessageState::Closed
     }
+
+    /// The session that this stream belongs to, if it is a WebTransport stream.
+    pub fn webtransport_session(&self) -> Option<u64> {
+        if let RecvMessageState::WebTransportStream { session_id } = self.state {
+            Some(session_id)
+        } else {
+            None
+        }
+    }
 }
@@ -11,7 +11,6 @@ use neqo_common::{matches, qdebug, qinfo, qtrace, Encoder};
 use neqo_qpack::encoder::QPackEncoder;
 use neqo_transport::Connection;
 use std::cmp::min;
-use std::convert::TryFrom;
 use std::fmt::Debug;
 
 const MAX_DATA_HEADER_SIZE_2: usize = (1 << 6) - 1; // Maximal amount of data with DATA frame header size 2
@@ -28,10 +27,12 @@ pub(crate) trait SendMessageEvents: Debug {
 /*
  *  SendMessage states:
  *    Uninitialized
- *    Initialized : Headers are present but still not encoded. A message body may be present as well.
+ *    Initialized : Headers are present but still not encoded. A message body and trailers may be
+ *                  present as well.
  *                  The client side sends a message body using the send_body() function that directly
  *                  writes into a transport stream. The server side sets headers and body when
- *                  initializing a send message (TODO: make server use send_body as well)
+ *                  initializing a send message, except for CONNECT responses that use send_body
+ *                  as well.
  *    SendingInitialMessage : sending headers and maybe message body. From here we may switch to
  *                     SendingData or Closed (if the app does not want to send data and
  *                     has already closed the send stream).
@@ -45,6 +46,7 @@ enum SendMessageState {
     Initialized {
         headers: Vec<Header>,
         data: Option<Vec<u8>>,
+        trailers: Option<Vec<Header>>,
         fin: bool,
     },
     SendingInitialMessage {
@@ -78,6 +80,8 @@ pub(crate) struct SendMessage {
     state: SendMessageState,
     stream_id: u64,
     conn_events: Box<dyn SendMessageEvents>,
+    /// Set when `send_body_now` could not take everything it was given.
+    blocked: bool,
 }
 
 impl SendMessage {
@@ -87,6 +91,7 @@ impl SendMessage {
             state: SendMessageState::Uninitialized,
             stream_id,
             conn_events,
+            blocked: false,
         }
     }
 
@@ -100,14 +105,21 @@ impl SendMessage {
             state: SendMessageState::Initialized {
                 headers,
                 data: None,
+                trailers: None,
                 fin: false,
             },
             stream_id,
             conn_events,
+            blocked: false,
         }
     }
 
-    pub fn set_message(&mut self, headers: &[Header], data: Option<&[u8]>) -> Res<()> {
+    pub fn set_message(
+        &mut self,
+        headers: &[Header],
+        data: Option<&[u8]>,
+        trailers: Option<&[Header]>,
+    ) -> Res<()> {
         if !matches!(self.state, SendMessageState::Uninitialized) {
             return Err(Error::AlreadyInitialized);
         }
@@ -119,12 +131,58 @@ impl SendMessage {
             } else {
                 None
             },
+            trailers: trailers.map(<[Header]>::to_vec),
             fin: true,
         };
         Ok(())
     }
 
+    /// Set only the headers.  The stream stays open so that a body can be sent
+    /// using `send_body`, e.g. for a CONNECT tunnel.
+    pub fn set_headers(&mut self, headers: &[Header]) -> Res<()> {
+        if !matches!(self.state, SendMessageState::Uninitialized) {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        self.state = SendMessageState::Initialized {
+            headers: headers.to_vec(),
+            data: None,
+            trailers: None,
+            fin: false,
+        };
+        Ok(())
+    }
+
+    /// Send body data, as much as the space in the stream send buffer allows.
     pub fn send_body(&mut self, conn: &mut Connection, buf: &[u8]) -> Res<usize> {
+        let available = if self.state.is_state_sending_data() {
+            conn.stream_avail_send_space(self.stream_id)?
+        } else {
+            0
+        };
+        self.send_body_limited(conn, buf, available)
+    }
+
+    /// Send body data, but only as much as can be sent straight away, which
+    /// is also limited by the congestion window.  If not all of `buf` is taken,
+    /// a `data_writable` event follows once `writable` finds that there is space.
+    pub fn send_body_now(&mut self, conn: &mut Connection, buf: &[u8]) -> Res<usize> {
+        let available = if self.state.is_state_sending_data() {
+            conn.stream_avail_send_now(self.stream_id)?
+        } else {
+            0
+        };
+        let sent = self.send_body_limited(conn, buf, available)?;
+        self.blocked = sent < buf.len();
+        Ok(sent)
+    }
+
+    fn send_body_limited(
+        &mut self,
+        conn: &mut Connection,
+        buf: &[u8],
+        available: usize,
+    ) -> Res<usize> {
         qinfo!(
             [self],
             "send_request_body: state={:?} len={}",
@@ -136,8 +194,6 @@ impl SendMessage {
             | SendMessageState::Initialized { .. }
             | SendMessageState::SendingInitialMessage { .. } => Ok(0),
             SendMessageState::SendingData => {
-                let available = usize::try_from(conn.stream_avail_send_space(self.stream_id)?)
-                    .unwrap_or(usize::max_value());
                 if available <= 2 {
                     return Ok(0);
                 }
@@ -194,8 +250,31 @@ impl SendMessage {
         self.state.is_state_sending_data()
     }
 
+    /// Whether a stream that `send_body_now` couldn't fill has space again.
+    /// This only returns true once for each time that the stream is blocked.
+    pub fn writable(&mut self, conn: &Connection) -> bool {
+        if !self.blocked || !self.state.is_state_sending_data() {
+            return false;
+        }
+        self.blocked = conn
+            .stream_avail_send_now(self.stream_id)
+            .map_or(true, |avail| avail <= 2);
+        !self.blocked
+    }
+
+    /// Note that the application has been told that the stream is writable.
+    pub fn clear_blocked(&mut self) {
+        self.blocked = false;
+    }
+
     fn ensure_encoded(&mut self, conn: &mut Connection, encoder: &mut QPackEncoder) -> Res<()> {
-        if let SendMessageState::Initialized { headers, data, fin } = &self.state {
+        if let SendMessageState::Initialized {
+            headers,
+            data,
+            trailers,
+            fin,
+        } = &self.state
+        {
             qdebug!([self], "Encoding headers");
             let header_block = encoder.encode_header_block(conn, &headers, self.stream_id)?;
             let hframe = HFrame::Headers {
@@ -211,6 +290,14 @@ impl SendMessage {
                 d_frame.encode(&mut d);
                 d.encode(&buf);
             }
+            if let Some(t) = trailers {
+                qdebug!([self], "Encoding trailers");
+                let header_block = encoder.encode_header_block(conn, t, self.stream_id)?;
+                HFrame::Headers {
+                    header_block: header_block.to_vec(),
+                }
+                .encode(&mut d);
+            }
 
             self.state = SendMessageState::SendingInitialMessage {
                 buf: d.into(),
@@ -6,12 +6,13 @@
 
 #![allow(clippy::module_name_repetitions)]
 
-use crate::connection::Http3State;
+use crate::connection::{Http3State, LOCAL_MAX_DATAGRAM_FRAME_SIZE};
 use crate::connection_server::Http3ServerHandler;
 use crate::server_connection_events::Http3ServerConnEvent;
 use crate::server_events::{ClientRequestStream, Http3ServerEvent, Http3ServerEvents};
-use crate::Res;
-use neqo_common::{qtrace, Datagram};
+use crate::webtransport;
+use crate::{Error, Res};
+use neqo_common::{qlog::QlogCategory, qtrace, Datagram};
 use neqo_crypto::AntiReplay;
 use neqo_qpack::QpackSettings;
 use neqo_transport::server::{ActiveConnectionRef, Server};
@@ -21,7 +22,7 @@ use std::cell::RefMut;
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 type HandlerRef = Rc<RefCell<Http3ServerHandler>>;
 
@@ -30,6 +31,9 @@ const MAX_EVENT_DATA_SIZE: usize = 1024;
 pub struct Http3Server {
     server: Server,
     qpack_settings: QpackSettings,
+    webtransport: bool,
+    datagrams: bool,
+    extended_connect: bool,
     http3_handlers: HashMap<ActiveConnectionRef, HandlerRef>,
     events: Http3ServerEvents,
 }
@@ -55,6 +59,9 @@ impl Http3Server {
         Ok(Self {
             server: Server::new(now, certs, protocols, anti_replay, cid_manager)?,
             qpack_settings,
+            webtransport: false,
+            datagrams: false,
+            extended_connect: false,
             http3_handlers: HashMap::new(),
             events: Http3ServerEvents::default(),
         })
@@ -64,6 +71,68 @@ impl Http3Server {
         self.server.set_qlog_dir(dir)
     }
 
+    /// Set the categories of events that qlog traces for new connections record.
+    pub fn set_qlog_categories(&mut self, categories: &[QlogCategory]) {
+        self.server.set_qlog_categories(categories)
+    }
+
+    /// Derive the keys for Retry and stateless reset tokens from `key`.
+    /// See `neqo_transport::server::Server::set_server_key`.
+    ///
+    /// # Errors
+    /// A crypto error if the key can't be imported.
+    pub fn set_server_key(&mut self, key: &[u8]) -> Res<()> {
+        self.server.set_server_key(key)?;
+        Ok(())
+    }
+
+    /// Accept or reject 0-RTT on new connections.
+    pub fn set_allow_0rtt(&mut self, allow: bool) {
+        self.server.set_allow_0rtt(allow)
+    }
+
+    /// Offer WebTransport on new connections.
+    pub fn enable_webtransport(&mut self) {
+        self.webtransport = true;
+    }
+
+    /// Offer HTTP datagrams on new connections.
+    pub fn enable_datagrams(&mut self) {
+        self.datagrams = true;
+        self.server
+            .set_max_datagram_frame_size(LOCAL_MAX_DATAGRAM_FRAME_SIZE);
+    }
+
+    /// Accept CONNECT-UDP requests on new connections.  This offers extended CONNECT
+    /// and HTTP datagrams.
+    pub fn enable_connect_udp(&mut self) {
+        self.extended_connect = true;
+        self.enable_datagrams();
+    }
+
+    /// Send GOAWAY on all connections.  Requests that are in progress are
+    /// completed, new requests are rejected.
+    pub fn goaway(&mut self) {
+        for handler in self.http3_handlers.values() {
+            handler.borrow_mut().goaway();
+        }
+    }
+
+    /// Start to shut down.  This sends GOAWAY on all connections and refuses new
+    /// ones.  Connections that are still open after `grace_period` are closed.
+    /// See `neqo_transport::server::Server::shutdown`.
+    pub fn shutdown(&mut self, now: Instant, grace_period: Duration) {
+        self.goaway();
+        self.server
+            .shutdown(now, grace_period, Error::HttpNoError.code());
+    }
+
+    /// Whether `shutdown` was called and every connection has closed since.
+    #[must_use]
+    pub fn shutdown_complete(&self) -> bool {
+        self.server.shutdown_complete()
+    }
+
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
         qtrace!([self], "Process.");
         let out = self.server.process(dgram, now);
@@ -104,11 +173,15 @@ impl Http3Server {
             .iter()
             .for_each(|conn| self.server.add_to_waiting(conn.clone()));
         let qpack_settings = self.qpack_settings;
+        let webtransport = self.webtransport;
+        let datagrams = self.datagrams;
+        let extended_connect = self.extended_connect;
         for mut conn in active_conns {
-            let handler = self
-                .http3_handlers
-                .entry(conn.clone())
-                .or_insert_with(|| Rc::new(RefCell::new(Http3ServerHandler::new(qpack_settings))));
+            let handler = self.http3_handlers.entry(conn.clone()).or_insert_with(|| {
+                let mut handler = Http3ServerHandler::new(qpack_settings, webtransport, datagrams);
+                handler.set_extended_connect(extended_connect);
+                Rc::new(RefCell::new(handler))
+            });
 
             handler
                 .borrow_mut()
@@ -118,6 +191,18 @@ impl Http3Server {
                 let mut handler_borrowed = handler.borrow_mut();
                 while let Some(e) = handler_borrowed.next_event() {
                     match e {
+                        Http3ServerConnEvent::Headers {
+                            stream_id,
+                            headers: Some(headers),
+                            fin: false,
+                        } if handler_borrowed.webtransport_enabled()
+                            && webtransport::is_session_request(&headers) =>
+                        {
+                            self.events.webtransport_session(
+                                ClientRequestStream::new(conn.clone(), handler.clone(), stream_id),
+                                headers,
+                            )
+                        }
                         Http3ServerConnEvent::Headers {
                             stream_id,
                             headers,
@@ -137,6 +222,41 @@ impl Http3Server {
                                 &mut self.events,
                             );
                         }
+                        Http3ServerConnEvent::Reset { stream_id, error } => self.events.reset(
+                            ClientRequestStream::new(conn.clone(), handler.clone(), stream_id),
+                            error,
+                        ),
+                        Http3ServerConnEvent::DataWritable { stream_id } => {
+                            self.events.data_writable(ClientRequestStream::new(
+                                conn.clone(),
+                                handler.clone(),
+                                stream_id,
+                            ))
+                        }
+                        Http3ServerConnEvent::Datagram { stream_id, data } => self.events.datagram(
+                            ClientRequestStream::new(conn.clone(), handler.clone(), stream_id),
+                            data,
+                        ),
+                        Http3ServerConnEvent::WebTransportNewStream {
+                            stream_id,
+                            session_id,
+                        } => self.events.webtransport_new_stream(
+                            ClientRequestStream::new(conn.clone(), handler.clone(), session_id),
+                            stream_id,
+                        ),
+                        Http3ServerConnEvent::WebTransportDataReadable {
+                            stream_id,
+                            session_id,
+                        } => self.events.webtransport_data_readable(
+                            ClientRequestStream::new(conn.clone(), handler.clone(), session_id),
+                            stream_id,
+                        ),
+                        Http3ServerConnEvent::WebTransportDatagram { session_id, data } => {
+                            self.events.webtransport_datagram(
+                                ClientRequestStream::new(conn.clone(), handler.clone(), session_id),
+                                data,
+                            )
+                        }
                         Http3ServerConnEvent::StateChange(state) => {
                             self.events
                                 .connection_state_change(conn.clone(), state.clone());
@@ -229,7 +349,7 @@ mod tests {
             DEFAULT_KEYS,
             DEFAULT_ALPN,
             anti_replay(),
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(5).unwrap())),
             QpackSettings {
                 max_table_size_encoder: 100,
                 max_table_size_decoder: 100,
@@ -724,6 +844,48 @@ mod tests {
         assert_eq!(data_received, 1);
     }
 
+    #[test]
+    fn test_server_malformed_request() {
+        let (mut hconn, mut peer_conn) = connect();
+
+        // A request with the headers of a response: ":status: 200", "content-length: 0".
+        let stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn
+            .conn
+            .stream_send(stream_id, &[0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x30])
+            .unwrap();
+        peer_conn.conn.stream_close_send(stream_id).unwrap();
+
+        let out = peer_conn.conn.process(None, now());
+        let out = hconn.process(out.dgram(), now());
+
+        // The request is not passed to the application.
+        let mut reset = false;
+        while let Some(event) = hconn.next_event() {
+            match event {
+                Http3ServerEvent::Headers { .. } => panic!("Malformed request headers"),
+                Http3ServerEvent::Reset { error, .. } => {
+                    assert_eq!(error, Error::HttpMessageError.code());
+                    reset = true;
+                }
+                Http3ServerEvent::StateChange {
+                    state: Http3State::Closing(..),
+                    ..
+                } => panic!("A malformed request is not a connection error"),
+                _ => {}
+            }
+        }
+        assert!(reset);
+
+        // The client gets a stream error.
+        let _ = peer_conn.conn.process(out.dgram(), now());
+        let stop_sending = |e| {
+            matches!(e, ConnectionEvent::SendStreamStopSending { stream_id: id, app_error }
+                if id == stream_id && app_error == Error::HttpMessageError.code())
+        };
+        assert!(peer_conn.conn.events().any(stop_sending));
+    }
+
     #[test]
     fn test_server_request_with_body_send_stop_sending() {
         let (mut hconn, mut peer_conn) = connect();
@@ -9,6 +9,7 @@ use crate::recv_message::RecvMessageEvents;
 use crate::send_message::SendMessageEvents;
 use crate::Header;
 use neqo_common::matches;
+use neqo_transport::AppError;
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
@@ -24,9 +25,18 @@ pub(crate) enum Http3ServerConnEvent {
     },
     /// Request data is ready.
     DataReadable { stream_id: u64 },
-    //TODO: This is never used. Do we need it?
-    // Peer reset the stream.
-    //Reset { stream_id: u64, error: AppError },
+    /// More data can be sent on a stream whose response headers have been sent.
+    DataWritable { stream_id: u64 },
+    /// An HTTP datagram was received for a request.
+    Datagram { stream_id: u64, data: Vec<u8> },
+    /// The client opened a stream for a WebTransport session.
+    WebTransportNewStream { stream_id: u64, session_id: u64 },
+    /// A stream of a WebTransport session has data to read.
+    WebTransportDataReadable { stream_id: u64, session_id: u64 },
+    /// A datagram was received for a WebTransport session.
+    WebTransportDatagram { session_id: u64, data: Vec<u8> },
+    /// Peer reset the stream or asked to stop sending, i.e. the request was cancelled.
+    Reset { stream_id: u64, error: AppError },
     /// Connection state change.
     StateChange(Http3State),
 }
@@ -46,6 +56,14 @@ impl RecvMessageEvents for Http3ServerConnEvents {
         });
     }
 
+    fn informational_headers(&self, _stream_id: u64, _headers: Vec<Header>) {
+        // Requests don't have a status, so this is not reachable for a valid request.
+    }
+
+    fn trailers_ready(&self, _stream_id: u64, _trailers: Vec<Header>) {
+        // Request trailers are not reported to the application.
+    }
+
     /// Add a new `DataReadable` event
     fn data_readable(&self, stream_id: u64) {
         self.insert(Http3ServerConnEvent::DataReadable { stream_id });
@@ -53,8 +71,9 @@ impl RecvMessageEvents for Http3ServerConnEvents {
 }
 
 impl SendMessageEvents for Http3ServerConnEvents {
-    fn data_writable(&self, _stream_id: u64) {
-        // Curently not used on the server side.
+    /// Add a new `DataWritable` event.
+    fn data_writable(&self, stream_id: u64) {
+        self.insert(Http3ServerConnEvent::DataWritable { stream_id });
     }
 }
 
@@ -78,6 +97,33 @@ impl Http3ServerConnEvents {
         self.events.borrow_mut().pop_front()
     }
 
+    pub fn datagram(&self, stream_id: u64, data: Vec<u8>) {
+        self.insert(Http3ServerConnEvent::Datagram { stream_id, data });
+    }
+
+    pub fn webtransport_new_stream(&self, stream_id: u64, session_id: u64) {
+        self.insert(Http3ServerConnEvent::WebTransportNewStream {
+            stream_id,
+            session_id,
+        });
+    }
+
+    pub fn webtransport_data_readable(&self, stream_id: u64, session_id: u64) {
+        self.insert(Http3ServerConnEvent::WebTransportDataReadable {
+            stream_id,
+            session_id,
+        });
+    }
+
+    pub fn webtransport_datagram(&self, session_id: u64, data: Vec<u8>) {
+        self.insert(Http3ServerConnEvent::WebTransportDatagram { session_id, data });
+    }
+
+    pub fn reset(&self, stream_id: u64, error: AppError) {
+        self.remove_events_for_stream_id(stream_id);
+        self.insert(Http3ServerConnEvent::Reset { stream_id, error });
+    }
+
     pub fn connection_state_change(&self, state: Http3State) {
         self.insert(Http3ServerConnEvent::StateChange(state));
     }
@@ -85,7 +131,7 @@ impl Http3ServerConnEvents {
     pub fn remove_events_for_stream_id(&self, stream_id: u64) {
         self.remove(|evt| {
             matches!(evt,
-                Http3ServerConnEvent::Headers { stream_id: x, .. } | Http3ServerConnEvent::DataReadable { stream_id: x, .. } if *x == stream_id)
+                Http3ServerConnEvent::Headers { stream_id: x, .. } | Http3ServerConnEvent::DataReadable { stream_id: x, .. } | Http3ServerConnEvent::DataWritable { stream_id: x } if *x == stream_id)
         });
     }
 }
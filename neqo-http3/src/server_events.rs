@@ -11,10 +11,11 @@ use crate::connection_server::Http3ServerHandler;
 use crate::{Header, Res};
 use neqo_common::{qdebug, qinfo};
 use neqo_transport::server::ActiveConnectionRef;
-use neqo_transport::{AppError, Connection};
+use neqo_transport::{AppError, Connection, StreamType};
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::io::{self, Write};
 use std::rc::Rc;
 
 #[derive(Debug, Clone)]
@@ -53,7 +54,54 @@ impl ClientRequestStream {
         qinfo!([self], "Set new response.");
         self.handler
             .borrow_mut()
-            .set_response(self.stream_id, headers, data)
+            .set_response(self.stream_id, headers, data, None)
+    }
+
+    /// Supply a response to a request, with trailers that follow the body.
+    pub fn set_response_with_trailers(
+        &mut self,
+        headers: &[Header],
+        data: &[u8],
+        trailers: &[Header],
+    ) -> Res<()> {
+        qinfo!([self], "Set new response with trailers.");
+        self.handler
+            .borrow_mut()
+            .set_response(self.stream_id, headers, data, Some(trailers))
+    }
+
+    /// Supply only the response headers, e.g. a 2xx response that accepts a CONNECT
+    /// request or one with a large body.  The stream stays open and `send_data`
+    /// (or `write`) sends data on it.
+    pub fn set_response_headers(&mut self, headers: &[Header]) -> Res<()> {
+        qinfo!([self], "Set new response headers.");
+        self.handler
+            .borrow_mut()
+            .set_response_headers(self.stream_id, headers)
+    }
+
+    /// Send data after the headers supplied with `set_response_headers`.  This returns
+    /// the amount of data sent, which is limited by flow control and the congestion
+    /// window.  When it is less than `buf.len()`, wait for a `DataWritable` event
+    /// before sending more.
+    /// # Errors
+    /// `InvalidStreamId` if the request is no longer active,
+    /// `AlreadyClosed` if the sending side has been closed.
+    pub fn send_data(&mut self, buf: &[u8]) -> Res<usize> {
+        qdebug!([self], "send data len={}.", buf.len());
+        self.handler
+            .borrow_mut()
+            .send_data(&mut self.conn.borrow_mut(), self.stream_id, buf)
+    }
+
+    /// Close the sending side of a response that was started with `set_response_headers`.
+    /// # Errors
+    /// `InvalidStreamId` if the request is no longer active.
+    pub fn stream_close_send(&mut self) -> Res<()> {
+        qdebug!([self], "close sending side.");
+        self.handler
+            .borrow_mut()
+            .stream_close_send(&mut self.conn.borrow_mut(), self.stream_id)
     }
 
     /// Request a peer to stop sending a request.
@@ -70,6 +118,81 @@ impl ClientRequestStream {
         Ok(())
     }
 
+    /// Send an HTTP datagram associated with this request.
+    /// # Errors
+    /// `Unavailable` if the client does not support datagrams,
+    /// `InvalidStreamId` if the request is no longer active,
+    /// `TransportError` if the datagram is too large.
+    pub fn send_datagram(&mut self, data: &[u8]) -> Res<()> {
+        qdebug!([self], "send datagram len={}.", data.len());
+        self.handler
+            .borrow_mut()
+            .send_datagram(&mut self.conn.borrow_mut(), self.stream_id, data)
+    }
+
+    /// Accept a WebTransport session request with a 200 response.  This request
+    /// is then the session, which ends when it is reset.
+    /// # Errors
+    /// `InvalidStreamId` if the request is no longer active.
+    pub fn webtransport_accept(&mut self) -> Res<()> {
+        qinfo!([self], "Accept WebTransport session.");
+        self.handler
+            .borrow_mut()
+            .webtransport_accept(self.stream_id)
+    }
+
+    /// Open a stream for this WebTransport session.
+    /// # Errors
+    /// `InvalidStreamId` if the session has not been accepted, or a transport
+    /// error if the stream cannot be created.
+    pub fn webtransport_create_stream(&mut self, stream_type: StreamType) -> Res<u64> {
+        qdebug!([self], "create WebTransport stream type={:?}.", stream_type);
+        self.handler.borrow_mut().webtransport_create_stream(
+            &mut self.conn.borrow_mut(),
+            self.stream_id,
+            stream_type,
+        )
+    }
+
+    /// Send data on a stream of this WebTransport session.
+    /// # Errors
+    /// `InvalidStreamId` if the stream is not a WebTransport stream that can send.
+    pub fn webtransport_send_data(&mut self, stream_id: u64, buf: &[u8]) -> Res<usize> {
+        qdebug!(
+            [self],
+            "send data on stream {} len={}.",
+            stream_id,
+            buf.len()
+        );
+        self.handler.borrow_mut().webtransport_send_data(
+            &mut self.conn.borrow_mut(),
+            stream_id,
+            buf,
+        )
+    }
+
+    /// Read data from a stream of this WebTransport session.
+    /// # Errors
+    /// `InvalidStreamId` if the stream is not a WebTransport stream that can receive.
+    pub fn webtransport_read_data(&mut self, stream_id: u64, buf: &mut [u8]) -> Res<(usize, bool)> {
+        qdebug!([self], "read data from stream {}.", stream_id);
+        self.handler.borrow_mut().webtransport_read_data(
+            &mut self.conn.borrow_mut(),
+            stream_id,
+            buf,
+        )
+    }
+
+    /// Close the sending side of a stream of this WebTransport session.
+    /// # Errors
+    /// `InvalidStreamId` if the stream is not active.
+    pub fn webtransport_stream_close_send(&mut self, stream_id: u64) -> Res<()> {
+        qdebug!([self], "close sending side of stream {}.", stream_id);
+        self.handler
+            .borrow_mut()
+            .stream_close_send(&mut self.conn.borrow_mut(), stream_id)
+    }
+
     /// Reset a stream/request.
     pub fn stream_reset(&mut self, app_error: AppError) -> Res<()> {
         qdebug!([self], "reset error:{}.", app_error);
@@ -81,6 +204,23 @@ impl ClientRequestStream {
     }
 }
 
+/// Writing to a request stream sends a response body, as `send_data` does.
+/// This fails with `WouldBlock` until a `DataWritable` event if nothing
+/// can be sent.
+impl Write for ClientRequestStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.send_data(buf) {
+            Ok(0) if !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            Ok(sent) => Ok(sent),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Http3ServerEvent {
     /// Headers are ready.
@@ -95,6 +235,40 @@ pub enum Http3ServerEvent {
         data: Vec<u8>,
         fin: bool,
     },
+    /// The client cancelled the request, it reset the stream or asked to stop sending.
+    Reset {
+        request: ClientRequestStream,
+        error: AppError,
+    },
+    /// More data can be sent with `ClientRequestStream::send_data`.
+    DataWritable { request: ClientRequestStream },
+    /// An HTTP datagram was received for a request.
+    Datagram {
+        request: ClientRequestStream,
+        data: Vec<u8>,
+    },
+    /// A request for a WebTransport session.  Accept it with
+    /// `ClientRequestStream::webtransport_accept` or respond with an error.
+    WebTransportSession {
+        session: ClientRequestStream,
+        headers: Vec<Header>,
+    },
+    /// The client opened a stream for a WebTransport session.  Data that
+    /// follows the session ID may already be available to read.
+    WebTransportNewStream {
+        session: ClientRequestStream,
+        stream_id: u64,
+    },
+    /// A stream of a WebTransport session has data to read.
+    WebTransportDataReadable {
+        session: ClientRequestStream,
+        stream_id: u64,
+    },
+    /// A datagram was received for a WebTransport session.
+    WebTransportDatagram {
+        session: ClientRequestStream,
+        data: Vec<u8>,
+    },
     /// When individual connection change state. It is only used for tests.
     StateChange {
         conn: ActiveConnectionRef,
@@ -146,6 +320,41 @@ impl Http3ServerEvents {
         self.insert(Http3ServerEvent::StateChange { conn, state });
     }
 
+    /// Insert a `Reset` event.
+    pub(crate) fn reset(&self, request: ClientRequestStream, error: AppError) {
+        self.insert(Http3ServerEvent::Reset { request, error });
+    }
+
+    /// Insert a `DataWritable` event.
+    pub(crate) fn data_writable(&self, request: ClientRequestStream) {
+        self.insert(Http3ServerEvent::DataWritable { request });
+    }
+
+    /// Insert a `Datagram` event.
+    pub(crate) fn datagram(&self, request: ClientRequestStream, data: Vec<u8>) {
+        self.insert(Http3ServerEvent::Datagram { request, data });
+    }
+
+    /// Insert a `WebTransportSession` event.
+    pub(crate) fn webtransport_session(&self, session: ClientRequestStream, headers: Vec<Header>) {
+        self.insert(Http3ServerEvent::WebTransportSession { session, headers });
+    }
+
+    /// Insert a `WebTransportNewStream` event.
+    pub(crate) fn webtransport_new_stream(&self, session: ClientRequestStream, stream_id: u64) {
+        self.insert(Http3ServerEvent::WebTransportNewStream { session, stream_id });
+    }
+
+    /// Insert a `WebTransportDataReadable` event.
+    pub(crate) fn webtransport_data_readable(&self, session: ClientRequestStream, stream_id: u64) {
+        self.insert(Http3ServerEvent::WebTransportDataReadable { session, stream_id });
+    }
+
+    /// Insert a `WebTransportDatagram` event.
+    pub(crate) fn webtransport_datagram(&self, session: ClientRequestStream, data: Vec<u8>) {
+        self.insert(Http3ServerEvent::WebTransportDatagram { session, data });
+    }
+
     /// Insert a `Data` event.
     pub(crate) fn data(&self, request: ClientRequestStream, data: Vec<u8>, fin: bool) {
         self.insert(Http3ServerEvent::Data { request, data, fin });
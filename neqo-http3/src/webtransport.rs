@@ -0,0 +1,181 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// WebTransport over HTTP/3.  A session is an extended CONNECT request with
+// `:protocol` set to `webtransport` and the stream ID of that request is the
+// session ID.  Streams of a session start with the session ID, after the
+// stream type for a unidirectional stream or after a WEBTRANSPORT_STREAM
+// frame type for a bidirectional stream.  Datagrams of a session are HTTP
+// datagrams for the request.
+
+use crate::hframe::{HFrame, H3_FRAME_TYPE_WEBTRANSPORT_STREAM};
+use crate::stream_type_reader::NewStreamTypeReader;
+use crate::{Error, Header, Res};
+use neqo_common::Encoder;
+use neqo_transport::{Connection, StreamId, StreamType};
+
+/// The value of `:protocol` for a WebTransport session request.
+pub const WEBTRANSPORT_PROTOCOL: &str = "webtransport";
+/// The type of a unidirectional stream that belongs to a session.
+pub(crate) const WEBTRANSPORT_UNI_STREAM_TYPE: u64 = 0x54;
+
+/// Whether `headers` are a request for a new session.
+pub(crate) fn is_session_request(headers: &[Header]) -> bool {
+    let has = |name: &str, value: &str| headers.iter().any(|(n, v)| n == name && v == value);
+    has(":method", "CONNECT") && has(":protocol", WEBTRANSPORT_PROTOCOL)
+}
+
+/// The start of a new stream that belongs to `session_id`.
+pub(crate) fn stream_header(stream_type: StreamType, session_id: u64) -> Vec<u8> {
+    let mut enc = Encoder::default();
+    match stream_type {
+        StreamType::UniDi => {
+            enc.encode_varint(WEBTRANSPORT_UNI_STREAM_TYPE);
+            enc.encode_varint(session_id);
+        }
+        StreamType::BiDi => HFrame::WebTransportStream { session_id }.encode(&mut enc),
+    }
+    enc.into()
+}
+
+/// Reads the session ID from the start of a stream.  On a bidirectional
+/// stream the frame type comes first.  For a unidirectional stream the
+/// type has already been read.
+#[derive(Debug)]
+pub(crate) struct StreamHeaderReader {
+    frame_type: bool,
+    reader: NewStreamTypeReader,
+}
+
+impl StreamHeaderReader {
+    pub fn new(stream_type: StreamType) -> Self {
+        Self {
+            frame_type: stream_type == StreamType::BiDi,
+            reader: NewStreamTypeReader::new(),
+        }
+    }
+
+    /// Get the session ID, if it has arrived.
+    /// # Errors
+    /// `HttpStreamCreation` if a bidirectional stream starts with something else.
+    pub fn session_id(&mut self, conn: &mut Connection, stream_id: u64) -> Res<Option<u64>> {
+        while let Some(v) = self.reader.get_type(conn, stream_id) {
+            self.reader = NewStreamTypeReader::new();
+            if !self.frame_type {
+                return Ok(Some(v));
+            }
+            if v != H3_FRAME_TYPE_WEBTRANSPORT_STREAM {
+                return Err(Error::HttpStreamCreation);
+            }
+            self.frame_type = false;
+        }
+        Ok(None)
+    }
+
+    pub fn fin(&self) -> bool {
+        self.reader.fin()
+    }
+}
+
+/// A stream that belongs to a session.
+#[derive(Debug)]
+pub(crate) struct SessionStream {
+    pub session_id: u64,
+    recv_done: bool,
+    send_done: bool,
+}
+
+impl SessionStream {
+    /// A stream that `local` (or the peer) opened.  A unidirectional stream
+    /// only goes one way, so the other way is done from the start.
+    pub fn new(session_id: u64, stream_id: u64, local: bool) -> Self {
+        let uni = StreamId::from(stream_id).is_uni();
+        Self {
+            session_id,
+            recv_done: uni && local,
+            send_done: uni && !local,
+        }
+    }
+
+    pub fn recv_done(&mut self) {
+        self.recv_done = true;
+    }
+
+    pub fn send_done(&mut self) {
+        self.send_done = true;
+    }
+
+    pub fn can_send(&self) -> bool {
+        !self.send_done
+    }
+
+    pub fn can_recv(&self) -> bool {
+        !self.recv_done
+    }
+
+    /// Whether both directions are done and the stream can be forgotten.
+    pub fn done(&self) -> bool {
+        self.recv_done && self.send_done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_session_request, stream_header, SessionStream, WEBTRANSPORT_PROTOCOL};
+    use neqo_transport::StreamType;
+
+    fn header(name: &str, value: &str) -> (String, String) {
+        (String::from(name), String::from(value))
+    }
+
+    #[test]
+    fn session_request() {
+        let mut headers = vec![
+            header(":method", "CONNECT"),
+            header(":protocol", WEBTRANSPORT_PROTOCOL),
+            header(":scheme", "https"),
+            header(":authority", "example.com"),
+            header(":path", "/wt"),
+        ];
+        assert!(is_session_request(&headers));
+        headers[1].1 = String::from("connect-udp");
+        assert!(!is_session_request(&headers));
+        headers.remove(1);
+        assert!(!is_session_request(&headers));
+    }
+
+    #[test]
+    fn header_uni() {
+        // The stream type is a two byte varint.
+        assert_eq!(stream_header(StreamType::UniDi, 8), [0x40, 0x54, 0x08]);
+    }
+
+    #[test]
+    fn header_bidi() {
+        assert_eq!(stream_header(StreamType::BiDi, 4), [0x40, 0x41, 0x04]);
+    }
+
+    #[test]
+    fn session_stream() {
+        // A local unidirectional stream.
+        let mut s = SessionStream::new(0, 2, true);
+        assert!(s.can_send());
+        assert!(!s.can_recv());
+        s.send_done();
+        assert!(s.done());
+
+        // A remote unidirectional stream.
+        let s = SessionStream::new(0, 3, false);
+        assert!(!s.can_send());
+        assert!(s.can_recv());
+
+        let mut s = SessionStream::new(0, 1, false);
+        s.recv_done();
+        assert!(!s.done());
+        s.send_done();
+        assert!(s.done());
+    }
+}
@@ -8,7 +8,13 @@
 
 use neqo_common::{matches, Datagram};
 use neqo_crypto::AuthenticationStatus;
-use neqo_http3::{Http3Client, Http3ClientEvent, Http3Server, Http3ServerEvent, Http3State};
+use neqo_http3::{
+    connect_udp, webtransport, Error, Http3Client, Http3ClientEvent, Http3Server, Http3ServerEvent,
+    Http3State, Output,
+};
+use neqo_transport::StreamType;
+use std::io::{ErrorKind, Write};
+use std::time::Duration;
 use test_fixture::*;
 
 const RESPONSE_DATA: &[u8] = &[0x61, 0x62, 0x63];
@@ -79,9 +85,13 @@ fn process_client_events(conn: &mut Http3Client) {
 }
 
 fn connect() -> (Http3Client, Http3Server, Option<Datagram>) {
-    let mut hconn_c = default_http3_client();
-    let mut hconn_s = default_http3_server();
+    connect_with(default_http3_client(), default_http3_server())
+}
 
+fn connect_with(
+    mut hconn_c: Http3Client,
+    mut hconn_s: Http3Server,
+) -> (Http3Client, Http3Server, Option<Datagram>) {
     assert_eq!(hconn_c.state(), Http3State::Initializing);
     let out = hconn_c.process(None, now()); // Initial
     let out = hconn_s.process(out.dgram(), now()); // Initial + Handshake
@@ -107,6 +117,167 @@ fn test_connect() {
     let (_hconn_c, _hconn_s, _d) = connect();
 }
 
+fn webtransport_negotiation(client: bool, server: bool) -> bool {
+    let mut hconn_c = default_http3_client();
+    if client {
+        hconn_c.enable_webtransport().unwrap();
+    }
+    let mut hconn_s = default_http3_server();
+    if server {
+        hconn_s.enable_webtransport();
+    }
+    let (mut hconn_c, mut hconn_s, mut dgram) = connect_with(hconn_c, hconn_s);
+    // Make sure that SETTINGS from the server are received.
+    while dgram.is_some() {
+        let out = hconn_s.process(dgram, now());
+        dgram = hconn_c.process(out.dgram(), now()).dgram();
+    }
+    assert!(hconn_c.enable_webtransport().is_err());
+    hconn_c.webtransport_enabled()
+}
+
+#[test]
+fn test_webtransport() {
+    assert!(webtransport_negotiation(true, true));
+    assert!(!webtransport_negotiation(true, false));
+    assert!(!webtransport_negotiation(false, true));
+    assert!(!webtransport_negotiation(false, false));
+}
+
+#[test]
+fn test_webtransport_session() {
+    let mut hconn_c = default_http3_client();
+    hconn_c.enable_webtransport().unwrap();
+    hconn_c.enable_datagrams().unwrap();
+    let mut hconn_s = default_http3_server();
+    hconn_s.enable_webtransport();
+    hconn_s.enable_datagrams();
+    let (mut hconn_c, mut hconn_s, mut dgram) = connect_with(hconn_c, hconn_s);
+    // Make sure that SETTINGS are received.
+    while dgram.is_some() {
+        let out = hconn_s.process(dgram, now());
+        dgram = hconn_c.process(out.dgram(), now()).dgram();
+    }
+
+    let session_id = hconn_c
+        .webtransport_create_session("something.com", "/wt", &[])
+        .unwrap();
+    let out = hconn_c.process(None, now());
+    let out = hconn_s.process(out.dgram(), now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let mut session = None;
+    while let Some(event) = hconn_s.next_event() {
+        if let Http3ServerEvent::WebTransportSession {
+            session: s,
+            headers,
+        } = event
+        {
+            assert!(headers.contains(&(
+                String::from(":protocol"),
+                String::from(webtransport::WEBTRANSPORT_PROTOCOL)
+            )));
+            session = Some(s);
+        }
+    }
+    let mut session = session.unwrap();
+    session.webtransport_accept().unwrap();
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let accepted = |e| {
+        matches!(e, Http3ClientEvent::WebTransportSession { session_id: id, status: 200 }
+                 if id == session_id)
+    };
+    assert!(hconn_c.events().any(accepted));
+
+    // A unidirectional stream from the client.
+    let uni = hconn_c
+        .webtransport_create_stream(session_id, StreamType::UniDi)
+        .unwrap();
+    assert_eq!(hconn_c.webtransport_send_data(uni, &[1, 2, 3]).unwrap(), 3);
+    hconn_c.stream_close_send(uni).unwrap();
+    let out = hconn_c.process(None, now());
+    let _ = hconn_s.process(out.dgram(), now());
+    let new_stream = |e| {
+        matches!(e, Http3ServerEvent::WebTransportNewStream { stream_id, .. }
+                 if stream_id == uni)
+    };
+    assert!(hconn_s.events().any(new_stream));
+    let mut buf = [0; 10];
+    let (amount, fin) = session.webtransport_read_data(uni, &mut buf).unwrap();
+    assert_eq!(&buf[..amount], &[1, 2, 3]);
+    assert_eq!(fin, true);
+
+    // A bidirectional stream from the server.
+    let bidi = session
+        .webtransport_create_stream(StreamType::BiDi)
+        .unwrap();
+    assert_eq!(session.webtransport_send_data(bidi, &[4, 5]).unwrap(), 2);
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let new_stream = |e| {
+        matches!(e, Http3ClientEvent::WebTransportNewStream { stream_id, session_id: id }
+                 if stream_id == bidi && id == session_id)
+    };
+    assert!(hconn_c.events().any(new_stream));
+    let (amount, fin) = hconn_c.webtransport_read_data(bidi, &mut buf).unwrap();
+    assert_eq!(&buf[..amount], &[4, 5]);
+    assert_eq!(fin, false);
+
+    assert_eq!(hconn_c.webtransport_send_data(bidi, &[6]).unwrap(), 1);
+    let out = hconn_c.process(None, now());
+    let _ = hconn_s.process(out.dgram(), now());
+    let readable = |e| {
+        matches!(e, Http3ServerEvent::WebTransportDataReadable { stream_id, .. }
+                 if stream_id == bidi)
+    };
+    assert!(hconn_s.events().any(readable));
+    let (amount, fin) = session.webtransport_read_data(bidi, &mut buf).unwrap();
+    assert_eq!(&buf[..amount], &[6]);
+    assert_eq!(fin, false);
+
+    // Datagrams in both directions.
+    hconn_c
+        .webtransport_send_datagram(session_id, &[7])
+        .unwrap();
+    let out = hconn_c.process(None, now());
+    let _ = hconn_s.process(out.dgram(), now());
+    let datagram =
+        |e| matches!(e, Http3ServerEvent::WebTransportDatagram { data, .. } if data == [7]);
+    assert!(hconn_s.events().any(datagram));
+
+    session.send_datagram(&[8]).unwrap();
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let datagram = |e| {
+        matches!(e, Http3ClientEvent::WebTransportDatagram { session_id: id, data }
+                 if id == session_id && data == [8])
+    };
+    assert!(hconn_c.events().any(datagram));
+
+    // The streams end with the session.
+    hconn_c
+        .cancel_fetch(session_id, Error::HttpRequestCancelled.code())
+        .unwrap();
+    assert_eq!(
+        hconn_c.webtransport_send_data(bidi, &[9]),
+        Err(Error::InvalidStreamId)
+    );
+    assert_eq!(
+        hconn_c.webtransport_create_stream(session_id, StreamType::UniDi),
+        Err(Error::InvalidStreamId)
+    );
+}
+
+#[test]
+fn test_webtransport_unavailable() {
+    let (mut hconn_c, _, _) = connect();
+    assert_eq!(
+        hconn_c.webtransport_create_session("something.com", "/wt", &[]),
+        Err(Error::Unavailable)
+    );
+}
+
 #[test]
 fn test_fetch() {
     let (mut hconn_c, mut hconn_s, dgram) = connect();
@@ -130,3 +301,399 @@ fn test_fetch() {
     let _ = hconn_c.process(out.dgram(), now());
     process_client_events(&mut hconn_c);
 }
+
+#[test]
+fn test_fetch_with_trailers() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+
+    let req = hconn_c
+        .fetch("GET", "https", "something.com", "/", &[])
+        .unwrap();
+    hconn_c.stream_close_send(req).unwrap();
+    let out = hconn_c.process(dgram, now());
+    let out = hconn_s.process(out.dgram(), now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let trailers = vec![(String::from("grpc-status"), String::from("0"))];
+    while let Some(event) = hconn_s.next_event() {
+        if let Http3ServerEvent::Headers { mut request, .. } = event {
+            request
+                .set_response_with_trailers(
+                    &[
+                        (String::from(":status"), String::from("200")),
+                        (String::from("content-length"), String::from("3")),
+                    ],
+                    RESPONSE_DATA,
+                    &trailers,
+                )
+                .unwrap();
+        }
+    }
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let mut trailers_found = false;
+    while let Some(event) = hconn_c.next_event() {
+        match event {
+            Http3ClientEvent::DataReadable { stream_id } => {
+                let mut buf = [0u8; 100];
+                let (amount, fin) = hconn_c
+                    .read_response_data(now(), stream_id, &mut buf)
+                    .unwrap();
+                assert_eq!(fin, true);
+                assert_eq!(&buf[..amount], RESPONSE_DATA);
+            }
+            Http3ClientEvent::Trailers { stream_id, headers } => {
+                assert_eq!(stream_id, req);
+                assert_eq!(headers, trailers);
+                trailers_found = true;
+            }
+            _ => {}
+        }
+    }
+    assert!(trailers_found);
+}
+
+#[test]
+fn test_goaway() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+
+    let req = hconn_c
+        .fetch("GET", "https", "something.com", "/", &[])
+        .unwrap();
+    hconn_c.stream_close_send(req).unwrap();
+    let out = hconn_c.process(dgram, now());
+    let out = hconn_s.process(out.dgram(), now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    // The request has been received, so it is not affected by the GOAWAY.
+    hconn_s.goaway();
+    process_server_events(&mut hconn_s);
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    assert_eq!(hconn_c.state(), Http3State::GoingAway(4));
+    process_client_events(&mut hconn_c);
+    assert!(hconn_c
+        .fetch("GET", "https", "something.com", "/", &[])
+        .is_err());
+}
+
+#[test]
+fn test_shutdown() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    let _ = hconn_c.process(dgram, now());
+
+    // The client hears about the GOAWAY straight away.
+    hconn_s.shutdown(now(), Duration::from_secs(1));
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    assert_eq!(hconn_c.state(), Http3State::GoingAway(0));
+
+    // The connection is closed after the grace period.
+    let mut t = now() + Duration::from_secs(1);
+    while let Some(d) = hconn_s.process(None, t).dgram() {
+        let _ = hconn_c.process(Some(d), t);
+    }
+    assert!(matches!(
+        hconn_c.state(),
+        Http3State::Closing(..) | Http3State::Closed(..)
+    ));
+
+    while !hconn_s.shutdown_complete() {
+        match hconn_s.process(None, t) {
+            Output::Datagram(_) => {}
+            Output::Callback(d) => t += d,
+            Output::None => panic!("the connection is still open"),
+        }
+    }
+}
+
+#[test]
+fn test_datagrams() {
+    let mut hconn_c = default_http3_client();
+    hconn_c.enable_datagrams().unwrap();
+    let mut hconn_s = default_http3_server();
+    hconn_s.enable_datagrams();
+    let (mut hconn_c, mut hconn_s, mut dgram) = connect_with(hconn_c, hconn_s);
+    // Make sure that SETTINGS are received.
+    while dgram.is_some() {
+        let out = hconn_s.process(dgram, now());
+        dgram = hconn_c.process(out.dgram(), now()).dgram();
+    }
+
+    // Datagrams are bound to a request.
+    let req = hconn_c
+        .fetch("GET", "https", "something.com", "/", &[])
+        .unwrap();
+    let out = hconn_c.process(None, now());
+    let out = hconn_s.process(out.dgram(), now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let mut request = None;
+    while let Some(event) = hconn_s.next_event() {
+        if let Http3ServerEvent::Headers { request: r, .. } = event {
+            request = Some(r);
+        }
+    }
+    let mut request = request.unwrap();
+    request.send_datagram(&[1, 2, 3]).unwrap();
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let datagram = |e| {
+        matches!(e, Http3ClientEvent::Datagram { stream_id, data }
+                 if stream_id == req && data == [1, 2, 3])
+    };
+    assert!(hconn_c.events().any(datagram));
+
+    hconn_c.send_datagram(req, &[4, 5]).unwrap();
+    let out = hconn_c.process(None, now());
+    let _ = hconn_s.process(out.dgram(), now());
+    let datagram = |e| matches!(e, Http3ServerEvent::Datagram { data, .. } if data == [4, 5]);
+    assert!(hconn_s.events().any(datagram));
+
+    // Datagrams need the request to be active.
+    assert!(hconn_c.send_datagram(req + 4, &[1]).is_err());
+}
+
+#[test]
+fn test_connect_tunnel() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+
+    let req = hconn_c.connect("example.com:443", &[]).unwrap();
+    let out = hconn_c.process(dgram, now());
+    let out = hconn_s.process(out.dgram(), now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let mut request = None;
+    while let Some(event) = hconn_s.next_event() {
+        if let Http3ServerEvent::Headers {
+            request: r,
+            headers,
+            fin,
+        } = event
+        {
+            assert_eq!(
+                headers,
+                Some(vec![
+                    (String::from(":method"), String::from("CONNECT")),
+                    (String::from(":authority"), String::from("example.com:443")),
+                ])
+            );
+            assert_eq!(fin, false);
+            request = Some(r);
+        }
+    }
+    let mut request = request.unwrap();
+    request
+        .set_response_headers(&[(String::from(":status"), String::from("200"))])
+        .unwrap();
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let header_ready = |e| matches!(e, Http3ClientEvent::HeaderReady { fin: false, .. });
+    assert!(hconn_c.events().any(header_ready));
+
+    // The tunnel carries data in both directions.
+    assert_eq!(hconn_c.send_request_body(req, b"ping").unwrap(), 4);
+    let out = hconn_c.process(None, now());
+    let _ = hconn_s.process(out.dgram(), now());
+    let data = |e| matches!(e, Http3ServerEvent::Data { data, fin: false, .. } if data == b"ping");
+    assert!(hconn_s.events().any(data));
+
+    assert_eq!(request.send_data(b"pong").unwrap(), 4);
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let mut buf = [0; 10];
+    let (amount, fin) = hconn_c.read_response_data(now(), req, &mut buf).unwrap();
+    assert_eq!(&buf[..amount], b"pong");
+    assert_eq!(fin, false);
+
+    // Closing the tunnel is reported as the end of the data.
+    request.stream_close_send().unwrap();
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let (amount, fin) = hconn_c.read_response_data(now(), req, &mut buf).unwrap();
+    assert_eq!(amount, 0);
+    assert_eq!(fin, true);
+}
+
+#[test]
+fn test_large_response_body() {
+    const BODY_SIZE: usize = 100_000;
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+    let mut now = now();
+
+    let req = hconn_c
+        .fetch("GET", "https", "something.com", "/", &[])
+        .unwrap();
+    hconn_c.stream_close_send(req).unwrap();
+    let out = hconn_c.process(dgram, now);
+    let _ = hconn_s.process(out.dgram(), now);
+
+    let mut request = None;
+    while let Some(event) = hconn_s.next_event() {
+        if let Http3ServerEvent::Headers { request: r, .. } = event {
+            request = Some(r);
+        }
+    }
+    let mut request = request.unwrap();
+    request
+        .set_response_headers(&[(String::from(":status"), String::from("200"))])
+        .unwrap();
+
+    // The server writes what it can each time it is told that the stream is
+    // writable, so the body is never buffered all at once.
+    let body = vec![0x42; BODY_SIZE];
+    let mut written = 0;
+    let mut writes = 0;
+    let mut received = Vec::new();
+    let mut buf = vec![0; 4096];
+    let mut to_client = Vec::new();
+    let mut to_server = Vec::new();
+    let mut fin = false;
+    for _ in 0..100 {
+        for d in to_server.drain(..) {
+            to_client.extend(hconn_s.process(Some(d), now).dgram());
+        }
+        let writable = |e| matches!(e, Http3ServerEvent::DataWritable { .. });
+        if written < BODY_SIZE && hconn_s.events().any(writable) {
+            writes += 1;
+            loop {
+                match request.write(&body[written..]) {
+                    Ok(sent) => written += sent,
+                    Err(e) => {
+                        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+                        break;
+                    }
+                }
+                if written == BODY_SIZE {
+                    request.stream_close_send().unwrap();
+                    break;
+                }
+            }
+        }
+        while let Some(d) = hconn_s.process(None, now).dgram() {
+            to_client.push(d);
+        }
+
+        for d in to_client.drain(..) {
+            to_server.extend(hconn_c.process(Some(d), now).dgram());
+        }
+        while let Some(e) = hconn_c.next_event() {
+            if let Http3ClientEvent::DataReadable { stream_id } = e {
+                assert_eq!(stream_id, req);
+                while !fin {
+                    let (amount, f) = hconn_c.read_response_data(now, req, &mut buf).unwrap();
+                    received.extend_from_slice(&buf[..amount]);
+                    fin = f;
+                    if amount == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        while let Some(d) = hconn_c.process(None, now).dgram() {
+            to_server.push(d);
+        }
+        if fin {
+            break;
+        }
+        now += Duration::from_millis(10);
+    }
+    assert!(fin);
+    assert!(writes > 1);
+    assert_eq!(received, body);
+}
+
+#[test]
+fn test_connect_udp() {
+    let mut hconn_c = default_http3_client();
+    hconn_c.enable_datagrams().unwrap();
+    let mut hconn_s = default_http3_server();
+    hconn_s.enable_connect_udp();
+    let (mut hconn_c, mut hconn_s, mut dgram) = connect_with(hconn_c, hconn_s);
+    // Make sure that SETTINGS are received.
+    while dgram.is_some() {
+        let out = hconn_s.process(dgram, now());
+        dgram = hconn_c.process(out.dgram(), now()).dgram();
+    }
+
+    let req = hconn_c
+        .connect_udp("proxy.example", "192.0.2.6", 53, &[])
+        .unwrap();
+    let out = hconn_c.process(None, now());
+    let out = hconn_s.process(out.dgram(), now());
+    let _ = hconn_c.process(out.dgram(), now());
+
+    let mut request = None;
+    while let Some(event) = hconn_s.next_event() {
+        if let Http3ServerEvent::Headers {
+            request: r,
+            headers,
+            ..
+        } = event
+        {
+            let headers = headers.unwrap();
+            let path = headers.iter().find(|(n, _)| n == ":path").unwrap();
+            assert_eq!(
+                connect_udp::parse_masque_udp_path(&path.1),
+                Some((String::from("192.0.2.6"), 53))
+            );
+            assert!(headers.contains(&(String::from(":protocol"), String::from("connect-udp"))));
+            request = Some(r);
+        }
+    }
+    let mut request = request.unwrap();
+    request
+        .set_response_headers(&[(String::from(":status"), String::from("200"))])
+        .unwrap();
+
+    hconn_c
+        .send_datagram(req, &connect_udp::encode_udp_payload(&[1, 2, 3]))
+        .unwrap();
+    let out = hconn_c.process(None, now());
+    let _ = hconn_s.process(out.dgram(), now());
+    let datagram = |e| {
+        matches!(e, Http3ServerEvent::Datagram { data, .. }
+                 if connect_udp::decode_udp_payload(&data) == Some(&[1, 2, 3][..]))
+    };
+    assert!(hconn_s.events().any(datagram));
+}
+
+#[test]
+fn test_connect_udp_unavailable() {
+    let (mut hconn_c, _, _) = connect();
+    assert_eq!(
+        hconn_c.connect_udp("proxy.example", "192.0.2.6", 53, &[]),
+        Err(Error::Unavailable)
+    );
+}
+
+#[test]
+fn test_cancel_fetch() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+
+    let req = hconn_c
+        .fetch("GET", "https", "something.com", "/", &[])
+        .unwrap();
+    let out = hconn_c.process(dgram, now());
+    let _ = hconn_s.process(out.dgram(), now());
+    let headers = |e| matches!(e, Http3ServerEvent::Headers { .. });
+    assert!(hconn_s.events().any(headers));
+
+    hconn_c
+        .cancel_fetch(req, Error::HttpRequestCancelled.code())
+        .unwrap();
+    let out = hconn_c.process(None, now());
+    let _ = hconn_s.process(out.dgram(), now());
+    let reset = |e| {
+        matches!(e, Http3ServerEvent::Reset { error, .. }
+                 if error == Error::HttpRequestCancelled.code())
+    };
+    assert!(hconn_s.events().any(reset));
+}
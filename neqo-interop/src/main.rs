@@ -455,7 +455,9 @@ fn test_connect(nctx: &NetworkCtx, test: &Test, peer: &Peer) -> Result<Connectio
     let mut client = Connection::new_client(
         peer.host,
         &test.alpn(),
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+        Rc::new(RefCell::new(
+            FixedConnectionIdManager::new(0).expect("valid connection ID length"),
+        )),
         nctx.local_addr,
         nctx.remote_addr,
         QuicVersion::default(),
@@ -596,7 +598,9 @@ fn test_h3_rz(
     let handler = Http3Client::new(
         peer.host,
         &test.alpn(),
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+        Rc::new(RefCell::new(
+            FixedConnectionIdManager::new(0).expect("valid connection ID length"),
+        )),
         nctx.local_addr,
         nctx.remote_addr,
         QpackSettings {
@@ -677,7 +681,9 @@ fn test_vn(nctx: &NetworkCtx, peer: &Peer) -> Result<Connection, String> {
     let mut client = Connection::new_client(
         peer.host,
         &["hq-28"],
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+        Rc::new(RefCell::new(
+            FixedConnectionIdManager::new(0).expect("valid connection ID length"),
+        )),
         nctx.local_addr,
         nctx.remote_addr,
         QuicVersion::default(),
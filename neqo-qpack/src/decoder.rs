@@ -150,8 +150,15 @@ impl QPackDecoder {
         }
     }
 
+    /// Forget a stream that has been reset or is no longer read, and tell the encoder
+    /// with a Stream Cancellation instruction.  The instruction is not needed if the
+    /// stream is not blocked and nothing has been inserted into the dynamic table.
     pub fn cancel_stream(&mut self, stream_id: u64) {
-        DecoderInstruction::StreamCancellation { stream_id }.marshal(&mut self.send_buf);
+        let blocked = self.blocked_streams.len();
+        self.blocked_streams.retain(|(id, _)| *id != stream_id);
+        if blocked != self.blocked_streams.len() || self.table.base() > 0 {
+            DecoderInstruction::StreamCancellation { stream_id }.marshal(&mut self.send_buf);
+        }
     }
 
     /// # Errors
@@ -786,4 +793,40 @@ mod tests {
 
         decode_headers(&mut decoder, HEADER_BLOCK, &headers, 0);
     }
+
+    #[test]
+    fn test_cancel_blocked_stream() {
+        const ENCODER_INST: &[u8] = &[
+            0x4a, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61, 0x64, 0x65, 0x72, 0x61, 0x09, 0x6d, 0x79,
+            0x2d, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x61, 0x4a, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61,
+            0x64, 0x65, 0x72, 0x62, 0x09, 0x6d, 0x79, 0x2d, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x62,
+        ];
+        const HEADER_BLOCK: &[u8] = &[0x03, 0x81, 0x10, 0x11];
+
+        let mut decoder = connect();
+
+        assert!(decoder.decoder.set_capacity(200).is_ok());
+
+        // The header block refers to entries that have not been inserted yet.
+        assert_eq!(
+            decoder.decoder.decode_header_block(HEADER_BLOCK, 0),
+            Ok(None)
+        );
+
+        decoder.decoder.cancel_stream(0);
+        send_instructions_and_check(&mut decoder, &[0x03, 0x40]);
+
+        // The cancelled stream is not unblocked by the inserts.
+        let _ = decoder
+            .peer_conn
+            .stream_send(decoder.recv_stream_id, ENCODER_INST);
+        let out = decoder.peer_conn.process(None, now());
+        let _ = decoder.conn.process(out.dgram(), now());
+        assert_eq!(
+            decoder
+                .decoder
+                .receive(&mut decoder.conn, decoder.recv_stream_id),
+            Ok(Vec::new())
+        );
+    }
 }
@@ -262,6 +262,41 @@ impl QPackEncoder {
         }
     }
 
+    /// Duplicates an entry that is close to being evicted and sends the Duplicate instruction.
+    /// ### Errors
+    /// `EncoderStreamBlocked` if the encoder stream is blocked by the flow control.
+    /// `DynamicTableFull` if the dynamic table does not have enough space for the copy.
+    fn send_and_duplicate(&mut self, conn: &mut Connection, index: u64) -> Res<u64> {
+        qdebug!([self], "duplicate entry {}.", index);
+        self.send(conn)?;
+        if self.send_buf.len() != 0 {
+            return Err(Error::EncoderStreamBlocked);
+        }
+
+        if !self.table.duplicate_possible(index) {
+            return Err(Error::DynamicTableFull);
+        }
+
+        // The instruction uses an index relative to the last insert.
+        let relative_index = self.table.base() - index - 1;
+        let mut buf = QPData::default();
+        EncoderInstruction::Duplicate {
+            index: relative_index,
+        }
+        .marshal(&mut buf, self.use_huffman);
+
+        let stream_id = self.local_stream_id.ok_or(Error::Internal)?;
+
+        let sent = conn.stream_send_atomic(stream_id, &buf)?;
+        if !sent {
+            return Err(Error::EncoderStreamBlocked);
+        }
+
+        self.stats.dynamic_table_inserts += 1;
+
+        self.table.duplicate(relative_index)
+    }
+
     fn change_capacity(&mut self, value: u64) -> Res<()> {
         qdebug!([self], "change capacity: {}", value);
         self.table.set_capacity(value)?;
@@ -326,11 +361,26 @@ impl QPackEncoder {
             qtrace!("encoding {:x?} {:x?}.", name, value);
 
             if let Some(LookupResult {
-                index,
+                mut index,
                 static_table,
                 value_matches,
             }) = self.table.lookup(&name, &value, can_block)
             {
+                if !static_table
+                    && value_matches
+                    && can_block
+                    && !encoder_blocked
+                    && self.table.is_draining(index)
+                {
+                    // Refer to a fresh copy, so that the old entry can be evicted.
+                    match self.send_and_duplicate(conn, index) {
+                        Ok(new_index) => index = new_index,
+                        Err(Error::EncoderStreamBlocked) | Err(Error::DynamicTableFull) => {
+                            encoder_blocked = true;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
                 qtrace!(
                     [self],
                     "found a {} entry, value-match={}",
@@ -457,8 +507,7 @@ fn map_error(err: &Error) -> Error {
 mod tests {
     use super::{Connection, Error, Header, QPackEncoder};
     use crate::QpackSettings;
-    use neqo_transport::tparams::{self, TransportParameter};
-    use neqo_transport::StreamType;
+    use neqo_transport::{StreamType, TransportParameter, INITIAL_MAX_DATA};
     use test_fixture::{default_client, default_server, handshake, now};
 
     struct TestEncoder {
@@ -510,10 +559,7 @@ mod tests {
     fn connect_flow_control(max_data: u64) -> TestEncoder {
         connect_generic(true, |client, server| {
             server
-                .set_local_tparam(
-                    tparams::INITIAL_MAX_DATA,
-                    TransportParameter::Integer(max_data),
-                )
+                .set_local_tparam(INITIAL_MAX_DATA, TransportParameter::Integer(max_data))
                 .unwrap();
 
             handshake(client, server);
@@ -1605,4 +1651,42 @@ mod tests {
             )
             .is_ok());
     }
+
+    #[test]
+    fn test_duplicate_draining_entry() {
+        let mut encoder = connect(false);
+
+        encoder.encoder.set_max_blocked_streams(100).unwrap();
+        assert!(encoder.encoder.set_max_capacity(200).is_ok());
+        send_instructions(&mut encoder, CAP_INSTRUCTION_200);
+
+        // Each entry takes 40 bytes, so the first one ends up in the oldest quarter of the table.
+        for value in &[b"vala", b"valb", b"valc", b"vald"] {
+            encoder
+                .encoder
+                .send_and_insert(&mut encoder.conn, b"name", *value)
+                .unwrap();
+        }
+        send_instructions(
+            &mut encoder,
+            &[
+                0x44, 0x6e, 0x61, 0x6d, 0x65, 0x04, 0x76, 0x61, 0x6c, 0x61, 0x44, 0x6e, 0x61, 0x6d,
+                0x65, 0x04, 0x76, 0x61, 0x6c, 0x62, 0x44, 0x6e, 0x61, 0x6d, 0x65, 0x04, 0x76, 0x61,
+                0x6c, 0x63, 0x44, 0x6e, 0x61, 0x6d, 0x65, 0x04, 0x76, 0x61, 0x6c, 0x64,
+            ],
+        );
+
+        // The header block refers to a copy of the first entry, a post-base index.
+        let buf = encoder
+            .encoder
+            .encode_header_block(
+                &mut encoder.conn,
+                &[(String::from("name"), String::from("vala"))],
+                1,
+            )
+            .unwrap();
+        assert_eq!(&buf[..], &[0x06, 0x80, 0x10]);
+        // Duplicate with relative index 3.
+        send_instructions(&mut encoder, &[0x03]);
+    }
 }
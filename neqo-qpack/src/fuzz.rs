@@ -0,0 +1,140 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Entry points for fuzzing the parsers of what a peer sends.  These take
+// arbitrary bytes and must never panic.
+
+use crate::decoder_instructions::{DecoderInstruction, DecoderInstructionReader};
+use crate::encoder_instructions::{DecodedEncoderInstruction, EncoderInstructionReader};
+use crate::header_block::HeaderDecoder;
+use crate::reader::{ReadByte, Reader};
+use crate::table::HeaderTable;
+use crate::{Error, Res};
+use std::cmp::min;
+
+/// The dynamic table capacity that the fuzzed peer may use.
+const FUZZ_MAX_TABLE_SIZE: u64 = 4096;
+/// The number of inserts that header blocks can refer to.
+const FUZZ_MAX_ENTRIES: u64 = FUZZ_MAX_TABLE_SIZE / 32;
+
+/// A reader of a fixed buffer.  It runs out of data like a stream does.
+struct SliceReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> ReadByte for SliceReader<'a> {
+    fn read_byte(&mut self) -> Res<u8> {
+        let (b, rest) = self.buf.split_first().ok_or(Error::NeedMoreData)?;
+        self.buf = rest;
+        Ok(*b)
+    }
+}
+
+impl<'a> Reader for SliceReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Res<usize> {
+        let amount = min(buf.len(), self.buf.len());
+        buf[..amount].copy_from_slice(&self.buf[..amount]);
+        self.buf = &self.buf[amount..];
+        Ok(amount)
+    }
+}
+
+/// Read encoder stream instructions and apply them to a dynamic table, as a
+/// decoder does.
+pub fn decode_encoder_instructions(data: &[u8]) {
+    let mut table = HeaderTable::new(false);
+    let mut reader = EncoderInstructionReader::new();
+    let mut recv = SliceReader { buf: data };
+    while let Ok(instruction) = reader.read_instructions(&mut recv) {
+        let res = match instruction {
+            DecodedEncoderInstruction::Capacity { value } if value <= FUZZ_MAX_TABLE_SIZE => {
+                table.set_capacity(value)
+            }
+            DecodedEncoderInstruction::Capacity { .. } => Err(Error::EncoderStream),
+            DecodedEncoderInstruction::InsertWithNameRefStatic { index, value } => {
+                table.insert_with_name_ref(true, index, &value).map(|_| ())
+            }
+            DecodedEncoderInstruction::InsertWithNameRefDynamic { index, value } => {
+                table.insert_with_name_ref(false, index, &value).map(|_| ())
+            }
+            DecodedEncoderInstruction::InsertWithNameLiteral { name, value } => {
+                table.insert(&name, &value).map(|_| ())
+            }
+            DecodedEncoderInstruction::Duplicate { index } => table.duplicate(index).map(|_| ()),
+            DecodedEncoderInstruction::NoInstruction => Ok(()),
+        };
+        if res.is_err() {
+            return;
+        }
+    }
+}
+
+/// Read decoder stream instructions, as an encoder does.
+pub fn decode_decoder_instructions(data: &[u8]) {
+    let mut table = HeaderTable::new(true);
+    let mut reader = DecoderInstructionReader::new();
+    let mut recv = SliceReader { buf: data };
+    while let Ok(instruction) = reader.read_instructions(&mut recv) {
+        if let DecoderInstruction::InsertCountIncrement { increment } = instruction {
+            if table.increment_acked(increment).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Decode a header block, with an empty dynamic table.
+pub fn decode_header_block(data: &[u8]) {
+    let table = HeaderTable::new(false);
+    let _ = HeaderDecoder::new(data).decode_header_block(&table, FUZZ_MAX_ENTRIES, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_decoder_instructions, decode_encoder_instructions, decode_header_block};
+
+    fn all(f: fn(&[u8])) {
+        f(&[]);
+        for a in 0..=255 {
+            f(&[a]);
+            for b in 0..=255 {
+                f(&[a, b]);
+            }
+        }
+    }
+
+    #[test]
+    fn short_inputs() {
+        all(decode_encoder_instructions);
+        all(decode_decoder_instructions);
+        all(decode_header_block);
+    }
+
+    #[test]
+    fn static_index_past_end() {
+        // The static table has 99 entries, so index 99 doesn't exist.
+        decode_header_block(&[0x00, 0x00, 0xff, 0x24]);
+        decode_encoder_instructions(&[0xff, 0x24, 0x00]);
+    }
+
+    #[test]
+    fn large_base_delta() {
+        // A base delta of 2^64-1, with a required insert count of 1.
+        decode_header_block(&[
+            0x02, 0x7f, 0x80, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+        ]);
+    }
+
+    #[test]
+    fn large_literal() {
+        // An insert with a name literal that claims to be about 2^36 bytes long.
+        decode_encoder_instructions(&[0x5f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        // A literal name that claims to be 2^64-1 bytes long.
+        decode_header_block(&[
+            0x00, 0x00, 0x27, 0xf8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+        ]);
+    }
+}
@@ -275,7 +275,9 @@ impl<'a> HeaderDecoder<'a> {
             }
             self.req_insert_cnt - base_delta - 1
         } else {
-            self.req_insert_cnt + base_delta
+            self.req_insert_cnt
+                .checked_add(base_delta)
+                .ok_or(Error::DecompressionFailed)?
         };
         qtrace!(
             [self],
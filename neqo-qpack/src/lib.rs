@@ -15,6 +15,8 @@ pub mod decoder;
 mod decoder_instructions;
 pub mod encoder;
 mod encoder_instructions;
+#[cfg(any(test, fuzzing))]
+pub mod fuzz;
 mod header_block;
 pub mod huffman;
 mod huffman_decode_helper;
@@ -32,8 +34,13 @@ type Res<T> = Result<T, Error>;
 
 #[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Clone, Copy)]
 pub struct QpackSettings {
+    /// The dynamic table capacity that the peer's encoder may use. This is sent as
+    /// `SETTINGS_QPACK_MAX_TABLE_CAPACITY`.
     pub max_table_size_decoder: u64,
+    /// The largest dynamic table that the local encoder uses, even if the peer allows more.
     pub max_table_size_encoder: u64,
+    /// The number of streams that may be blocked waiting for dynamic table inserts. This is
+    /// sent as `SETTINGS_QPACK_BLOCKED_STREAMS`. The local encoder follows the peer's limit.
     pub max_blocked_streams: u16,
 }
 
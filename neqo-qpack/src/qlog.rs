@@ -8,7 +8,7 @@
 
 use crate::Res;
 use neqo_common::hex;
-use neqo_common::qlog::NeqoQlog;
+use neqo_common::qlog::{NeqoQlog, QlogCategory};
 use qlog::{event::Event, QPackInstruction, QpackInstructionTypeName};
 
 pub fn qpack_read_insert_count_increment_instruction(
@@ -16,7 +16,7 @@ pub fn qpack_read_insert_count_increment_instruction(
     increment: u64,
     data: &[u8],
 ) -> Res<()> {
-    if let Some(qlog) = qlog {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Qpack) {
         let event = Event::qpack_instruction_received(
             QPackInstruction::InsertCountIncrementInstruction {
                 instruction_type: QpackInstructionTypeName::InsertCountIncrementInstruction,
@@ -9,6 +9,7 @@ use crate::prefix::Prefix;
 use crate::{Error, Res};
 use neqo_common::{qdebug, qerror};
 use neqo_transport::Connection;
+use std::cmp::min;
 use std::convert::TryInto;
 use std::mem;
 use std::str;
@@ -137,7 +138,7 @@ impl<'a> ReceiverBufferWrapper<'a> {
     }
 
     fn slice(&mut self, len: usize) -> Res<&[u8]> {
-        if self.offset + len > self.buf.len() {
+        if len > self.buf.len() - self.offset {
             Err(Error::DecompressionFailed)
         } else {
             let start = self.offset;
@@ -214,11 +215,14 @@ impl IntReader {
     }
 }
 
+/// How much a `LiteralReader` grows its buffer by at a time.
+const LITERAL_READ_SIZE: usize = 4096;
+
 #[derive(Debug)]
 enum LiteralReaderState {
     ReadHuffman,
     ReadLength { reader: IntReader },
-    ReadLiteral { offset: usize },
+    ReadLiteral { offset: usize, len: usize },
     Done,
 }
 
@@ -279,14 +283,24 @@ impl LiteralReader {
                 }
                 LiteralReaderState::ReadLength { reader } => {
                     let v = reader.read(s)?;
-                    self.literal
-                        .resize(v.try_into().or(Err(Error::Decoding))?, 0x0);
-                    self.state = LiteralReaderState::ReadLiteral { offset: 0 };
+                    self.state = LiteralReaderState::ReadLiteral {
+                        offset: 0,
+                        len: v.try_into().or(Err(Error::Decoding))?,
+                    };
                 }
-                LiteralReaderState::ReadLiteral { offset } => {
-                    let amount = s.read(&mut self.literal[*offset..])?;
-                    *offset += amount;
-                    if *offset == self.literal.len() {
+                LiteralReaderState::ReadLiteral { offset, len } => {
+                    // The length comes from the peer, so the buffer only grows as
+                    // data arrives.
+                    while *offset < *len {
+                        let end = min(*len, offset.saturating_add(LITERAL_READ_SIZE));
+                        self.literal.resize(end, 0x0);
+                        let amount = s.read(&mut self.literal[*offset..])?;
+                        *offset += amount;
+                        if *offset < end {
+                            break;
+                        }
+                    }
+                    if *offset == *len {
                         self.state = LiteralReaderState::Done;
                         if self.use_huffman {
                             break Ok(decode_huffman(&self.literal)?);
@@ -123,7 +123,7 @@ impl HeaderTable {
     /// `HeaderLookup` if the index does not exist in the static table.
     pub fn get_static(index: u64) -> Res<&'static StaticTableEntry> {
         let inx = usize::try_from(index).or(Err(Error::HeaderLookup))?;
-        if inx > HEADER_STATIC_TABLE.len() {
+        if inx >= HEADER_STATIC_TABLE.len() {
             return Err(Error::HeaderLookup);
         }
         Ok(&HEADER_STATIC_TABLE[inx])
@@ -154,17 +154,17 @@ impl HeaderTable {
     /// ### Errors
     /// `HeaderLookup` if entry does not exist.
     pub fn get_dynamic(&self, index: u64, base: u64, post: bool) -> Res<&DynamicTableEntry> {
+        // The index and base come from the peer, so they might overflow.
         let inx = if post {
-            if self.base < (base + index + 1) {
-                return Err(Error::HeaderLookup);
-            }
-            self.base - (base + index + 1)
+            base.checked_add(index)
+                .and_then(|i| i.checked_add(1))
+                .and_then(|i| self.base.checked_sub(i))
         } else {
-            if (self.base + index) < base {
-                return Err(Error::HeaderLookup);
-            }
-            (self.base + index) - base
-        };
+            self.base
+                .checked_add(index)
+                .and_then(|i| i.checked_sub(base))
+        }
+        .ok_or(Error::HeaderLookup)?;
 
         self.get_dynamic_with_relative_index(inx)
     }
@@ -279,6 +279,38 @@ impl HeaderTable {
             && self.test_evict_to(self.capacity - u64::try_from(size).unwrap())
     }
 
+    /// Whether the entry with the absolute `index` is in the oldest quarter of the table
+    /// and therefore close to being evicted.  Referring to such an entry would prevent its
+    /// eviction, so the encoder duplicates it instead.
+    pub fn is_draining(&self, index: u64) -> bool {
+        let mut newer: u64 = 0;
+        for e in &self.dynamic {
+            newer = match u64::try_from(e.size()) {
+                Ok(size) => newer.saturating_add(size),
+                Err(_) => return false,
+            };
+            if e.index() == index {
+                return newer.saturating_mul(4) > self.capacity.saturating_mul(3);
+            }
+        }
+        false
+    }
+
+    /// Whether the entry with the absolute `index` can be duplicated without evicting the
+    /// entry itself.
+    pub fn duplicate_possible(&mut self, index: u64) -> bool {
+        let size = match self.get_dynamic_with_abs_index(index) {
+            Ok(e) => {
+                e.add_ref();
+                e.size()
+            }
+            Err(_) => return false,
+        };
+        let possible = self.insert_possible(size);
+        self.remove_ref(index);
+        possible
+    }
+
     /// Insert a new entry.
     /// ### Errors
     /// `DynamicTableFull` if an entry cannot be added to the table because there is not enough space and/or
@@ -359,11 +391,13 @@ impl HeaderTable {
     /// `IncrementAck` if ack is greater than actual number of inserts.
     pub fn increment_acked(&mut self, increment: u64) -> Res<()> {
         qtrace!([self], "increment acked by {}", increment);
-        self.acked_inserts_cnt += increment;
-        if self.base < self.acked_inserts_cnt {
-            return Err(Error::IncrementAck);
+        match self.acked_inserts_cnt.checked_add(increment) {
+            Some(c) if c <= self.base => {
+                self.acked_inserts_cnt = c;
+                Ok(())
+            }
+            _ => Err(Error::IncrementAck),
         }
-        Ok(())
     }
 
     /// Return number of acknowledge inserts.
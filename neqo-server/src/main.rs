@@ -9,20 +9,32 @@
 
 use neqo_common::Datagram;
 use neqo_crypto::{init_db, AntiReplay};
-use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, QuicVersion, State};
+use neqo_transport::server::{ActiveConnectionRef, Server};
+use neqo_transport::{Connection, ConnectionEvent, FixedConnectionIdManager, Output, State};
 use regex::Regex;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::env;
 use std::fmt;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
+
+use mio::unix::EventedFd;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio_extras::timer::{Builder, Timeout, Timer};
+use neqo_udp::{RecvBuf, Socket};
 use structopt::StructOpt;
 
+const TIMER_TOKEN: Token = Token(0xffff_ffff);
+
 #[derive(Debug, StructOpt)]
-#[structopt(name = "neqo-client", about = "A basic QUIC client.")]
+#[structopt(name = "neqo-server", about = "A basic QUIC HTTP/0.9 server.")]
 struct Args {
     #[structopt(short = "h", long)]
     /// Optional local address to bind to, defaults to the unspecified address.
@@ -57,6 +69,30 @@ struct Args {
     #[structopt(short = "6", long)]
     /// Restrict to IPv6.
     ipv6: bool,
+
+    #[structopt(short = "r", long)]
+    /// Require a Retry before accepting connections.
+    retry: bool,
+
+    #[structopt(name = "qlog-dir", long)]
+    /// Enable QLOG logging and QLOG traces to this directory
+    qlog_dir: Option<PathBuf>,
+
+    #[structopt(short = "e", long)]
+    /// Echo data on bidirectional streams instead of serving HTTP/0.9.
+    echo: bool,
+
+    #[structopt(name = "preferred-address-v4", long)]
+    /// An IPv4 address to listen on and advertise as the preferred address.
+    preferred_address_v4: Option<SocketAddrV4>,
+
+    #[structopt(name = "preferred-address-v6", long)]
+    /// An IPv6 address to listen on and advertise as the preferred address.
+    preferred_address_v6: Option<SocketAddrV6>,
+
+    #[structopt(name = "key-log", long)]
+    /// Log TLS secrets to this file, in the format that Wireshark reads.
+    key_log: Option<PathBuf>,
 }
 
 impl Args {
@@ -121,80 +157,270 @@ fn http_serve(server: &mut Connection, stream: u64) {
     server.stream_close_send(stream).expect("Stream closed");
 }
 
-fn emit_datagram(socket: &UdpSocket, d: Datagram) {
-    let sent = socket
-        .send_to(&d[..], d.destination())
-        .expect("Error sending datagram");
-    if sent != d.len() {
-        eprintln!("Unable to send all {} bytes of datagram", d.len());
-    }
+/// What an echo stream has read but not yet sent, and whether the client
+/// has finished sending.
+#[derive(Default)]
+struct EchoBuffer {
+    unsent: Vec<u8>,
+    fin: bool,
 }
 
-fn main() {
-    let args = Args::from_args();
-    assert!(!args.key.is_empty(), "Need at least one key");
-
-    init_db(args.db.clone());
-    let anti_replay = AntiReplay::new(Instant::now(), Duration::from_secs(10), 7, 14)
-        .expect("unable to setup anti-replay");
-
-    // TODO(mt): listen on both v4 and v6.
-    let socket = UdpSocket::bind(args.bind()).expect("Unable to bind UDP socket");
+/// The echo streams that are waiting to send, by connection.
+type EchoBuffers = HashMap<ActiveConnectionRef, HashMap<u64, EchoBuffer>>;
 
-    let local_addr = socket.local_addr().expect("Socket local address not bound");
+/// Send stream data straight back, closing when the client does.  What
+/// doesn't fit in the send buffer is kept until the stream is writable, and
+/// nothing more is read from the stream until then.
+fn echo_serve(server: &mut Connection, stream: u64, buffers: &mut HashMap<u64, EchoBuffer>) {
+    let buf = buffers.entry(stream).or_default();
+    let mut data = vec![0; 4000];
+    loop {
+        if !buf.unsent.is_empty() {
+            match server.stream_send(stream, &buf.unsent) {
+                Ok(sent) => {
+                    buf.unsent.drain(..sent);
+                }
+                Err(e) => {
+                    eprintln!("Unable to echo on stream {}: {:?}", stream, e);
+                    buffers.remove(&stream);
+                    return;
+                }
+            }
+            if !buf.unsent.is_empty() {
+                return;
+            }
+        }
+        if buf.fin {
+            server.stream_close_send(stream).expect("Stream closed");
+            buffers.remove(&stream);
+            return;
+        }
+        let (sz, fin) = server
+            .stream_recv(stream, &mut data)
+            .expect("Read should succeed");
+        buf.unsent.extend_from_slice(&data[..sz]);
+        buf.fin = fin;
+        if sz == 0 && !fin {
+            buffers.remove(&stream);
+            return;
+        }
+    }
+}
 
-    println!("Server waiting for connection on: {:?}", local_addr);
+/// A socket, and the datagrams that it couldn't send yet.
+struct ServerSocket {
+    socket: Socket,
+    unsent: Vec<Datagram>,
+}
 
-    let buf = &mut [0u8; 2048];
-    let mut connections: HashMap<SocketAddr, Connection> = HashMap::new();
-    loop {
-        // TODO use timer to set socket.set_read_timeout.
-        let (sz, remote_addr) = socket.recv_from(&mut buf[..]).expect("UDP error");
-        if sz == buf.len() {
-            eprintln!("Discarding packet that might be truncated");
-            continue;
+impl ServerSocket {
+    /// Send the datagrams that are waiting.  Those that the socket won't take
+    /// now are kept until it is writable again.
+    fn send_all(&mut self) {
+        while !self.unsent.is_empty() {
+            match self.socket.send(&self.unsent) {
+                Ok(n) => {
+                    self.unsent.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    // Skip the datagram that can't be sent.
+                    eprintln!("Unable to send datagram: {}", e);
+                    self.unsent.remove(0);
+                }
+            }
         }
+    }
+}
 
-        let mut server = connections.entry(remote_addr).or_insert_with(|| {
-            println!("New connection from {:?}", remote_addr);
-            Connection::new_server(
-                &args.key,
-                &args.alpn,
-                &anti_replay,
-                Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
-                QuicVersion::default(),
-            )
-            .expect("can't create connection")
-        });
+/// Send datagrams from the socket with the matching local address, or
+/// the first socket if none match.
+fn emit_datagrams(sockets: &mut [ServerSocket], mut dgrams: Vec<Datagram>) {
+    for socket in &mut sockets[1..] {
+        let local = socket.socket.local_addr();
+        let (mine, rest): (Vec<_>, Vec<_>) = dgrams.into_iter().partition(|d| d.source() == local);
+        socket.unsent.extend(mine);
+        socket.send_all();
+        dgrams = rest;
+    }
+    sockets[0].unsent.extend(dgrams);
+    sockets[0].send_all();
+}
 
-        if sz > 0 {
-            let dgram = Datagram::new(remote_addr, local_addr, &buf[..sz]);
-            server.process_input(dgram, Instant::now());
+/// Handle events on connections.  This returns true if any connection has
+/// something new to send.
+fn serve_connections(server: &mut Server, echo: bool, buffers: &mut EchoBuffers) -> bool {
+    let mut served = false;
+    for mut c in server.active_connections() {
+        let mut waiting = buffers.remove(&c).unwrap_or_default();
+        let mut streams = Vec::new();
+        while let Some(event) = c.borrow_mut().next_event() {
+            match event {
+                ConnectionEvent::RecvStreamReadable { stream_id } => streams.push(stream_id),
+                // Echo streams that are waiting to send can continue.
+                ConnectionEvent::SendStreamWritable { stream_id }
+                    if waiting.contains_key(&stream_id) =>
+                {
+                    streams.push(stream_id)
+                }
+                ConnectionEvent::StateChange(State::Closing { error, .. }) => {
+                    eprintln!("Closing connection: {:?}", error);
+                }
+                ConnectionEvent::StateChange(State::Closed(e)) => {
+                    eprintln!("Closed connection: {:?}", e);
+                    waiting.clear();
+                }
+                _ => {}
+            }
         }
-        if let State::Closed(e) = server.state() {
-            eprintln!("Closed connection from {:?}: {:?}", remote_addr, e);
-            connections.remove(&remote_addr);
-            continue;
+        if !streams.is_empty() {
+            for stream_id in streams {
+                if echo {
+                    echo_serve(&mut c.borrow_mut(), stream_id, &mut waiting);
+                } else {
+                    http_serve(&mut c.borrow_mut(), stream_id);
+                }
+            }
+            server.add_to_waiting(c.clone());
+            served = true;
         }
-        if let State::Closing { error, .. } = server.state() {
-            eprintln!("Closing connection from {:?}: {:?}", remote_addr, error);
-            // TOOD(ekr@rtfm.com): Do I need to remove?
-            continue;
+        if !waiting.is_empty() {
+            buffers.insert(c, waiting);
         }
-        let mut streams = Vec::new();
-        while let Some(event) = server.next_event() {
-            if let ConnectionEvent::RecvStreamReadable { stream_id } = event {
-                streams.push(stream_id)
+    }
+    served
+}
+
+fn process(
+    server: &mut Server,
+    dgrams: Vec<Datagram>,
+    sockets: &mut [ServerSocket],
+    timer: &mut Timer<()>,
+    timeout: &mut Option<Timeout>,
+    echo: bool,
+    buffers: &mut EchoBuffers,
+) {
+    let mut input = dgrams.into_iter();
+    let mut output = Vec::new();
+    loop {
+        loop {
+            match server.process(input.next(), Instant::now()) {
+                Output::Datagram(d) => output.push(d),
+                Output::Callback(delay) if input.as_slice().is_empty() => {
+                    if let Some(t) = timeout.take() {
+                        timer.cancel_timeout(&t);
+                    }
+                    *timeout = Some(timer.set_timeout(delay, ()));
+                    break;
+                }
+                Output::None if input.as_slice().is_empty() => break,
+                _ => {}
             }
         }
-
-        for stream_id in streams {
-            http_serve(&mut server, stream_id);
+        // Send everything at once, so that it can be batched.
+        emit_datagrams(sockets, mem::take(&mut output));
+        if !serve_connections(server, echo, buffers) {
+            return;
         }
+    }
+}
 
-        let out = server.process_output(Instant::now());
-        if let Some(dgram) = out.dgram() {
-            emit_datagram(&socket, dgram);
+fn main() -> Result<(), io::Error> {
+    let args = Args::from_args();
+    assert!(!args.key.is_empty(), "Need at least one key");
+
+    if let Some(key_log) = &args.key_log {
+        // NSS reads this when it is initialized.
+        env::set_var("SSLKEYLOGFILE", key_log);
+    }
+    init_db(args.db.clone());
+    let anti_replay = AntiReplay::new(Instant::now(), Duration::from_secs(10), 7, 14)
+        .expect("unable to setup anti-replay");
+    let mut server = Server::new(
+        Instant::now(),
+        &args.key,
+        &args.alpn,
+        anti_replay,
+        Rc::new(RefCell::new(
+            FixedConnectionIdManager::new(10).expect("valid connection ID length"),
+        )),
+    )
+    .expect("Unable to create server");
+    server.set_retry_required(args.retry);
+    server.set_qlog_dir(args.qlog_dir.clone());
+    server.set_preferred_address(args.preferred_address_v4, args.preferred_address_v6);
+
+    // TODO(mt): listen on both v4 and v6.
+    let mut addrs = vec![args.bind()];
+    addrs.extend(args.preferred_address_v4.map(SocketAddr::V4));
+    addrs.extend(args.preferred_address_v6.map(SocketAddr::V6));
+
+    let poll = Poll::new()?;
+    let mut sockets = Vec::new();
+    for (i, addr) in addrs.iter().enumerate() {
+        let socket = Socket::bind(*addr)?;
+        socket.socket().set_nonblocking(true)?;
+        println!(
+            "Server waiting for connection on: {:?}",
+            socket.local_addr()
+        );
+        poll.register(
+            &EventedFd(&socket.as_raw_fd()),
+            Token(i),
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge(),
+        )?;
+        sockets.push(ServerSocket {
+            socket,
+            unsent: Vec::new(),
+        });
+    }
+    let mut timer = Builder::default().build::<()>();
+    poll.register(&timer, TIMER_TOKEN, Ready::readable(), PollOpt::edge())?;
+    let mut timeout = None;
+    let mut buffers = EchoBuffers::new();
+
+    let mut buf = RecvBuf::new();
+    let mut events = Events::with_capacity(1024);
+    loop {
+        poll.poll(&mut events, None)?;
+        for event in &events {
+            if event.token() == TIMER_TOKEN {
+                while timer.poll().is_some() {}
+                process(
+                    &mut server,
+                    Vec::new(),
+                    &mut sockets,
+                    &mut timer,
+                    &mut timeout,
+                    args.echo,
+                    &mut buffers,
+                );
+            } else if event.token().0 < sockets.len() {
+                let i = event.token().0;
+                if event.readiness().is_writable() {
+                    sockets[i].send_all();
+                }
+                if !event.readiness().is_readable() {
+                    continue;
+                }
+                loop {
+                    let dgrams = match sockets[i].socket.recv(&mut buf) {
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err),
+                        Ok(dgrams) => dgrams,
+                    };
+                    process(
+                        &mut server,
+                        dgrams,
+                        &mut sockets,
+                        &mut timer,
+                        &mut timeout,
+                        args.echo,
+                        &mut buffers,
+                    );
+                }
+            }
         }
     }
 }
@@ -0,0 +1,280 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The task that owns the socket and moves datagrams and timers in and out of
+// the connection.
+
+use neqo_common::{qdebug, qwarn, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::{Connection, ConnectionEvent, ConnectionRace, Output};
+
+use std::cell::{RefCell, RefMut};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::time;
+
+/// With GRO or jumbo frames, a datagram can be as large as UDP allows.
+const RECV_BUFFER_SIZE: usize = 0xffff;
+
+pub(crate) type Authenticate = Box<dyn FnMut(&Connection) -> AuthenticationStatus>;
+
+/// The connection and the tasks that are waiting on it.
+pub(crate) struct Shared {
+    pub(crate) conn: Connection,
+    authenticate: Authenticate,
+    readers: HashMap<u64, Waker>,
+    writers: HashMap<u64, Waker>,
+    /// Streams that the peer opened, which haven't been accepted yet.
+    pub(crate) incoming: VecDeque<u64>,
+    /// Tasks that wait for new streams or for the state to change.
+    waiters: Vec<Waker>,
+}
+
+impl Shared {
+    pub(crate) fn new(conn: Connection, authenticate: Authenticate) -> Self {
+        Self {
+            conn,
+            authenticate,
+            readers: HashMap::new(),
+            writers: HashMap::new(),
+            incoming: VecDeque::new(),
+            waiters: Vec::new(),
+        }
+    }
+
+    pub(crate) fn wait_readable(&mut self, stream_id: u64, waker: &Waker) {
+        self.readers.insert(stream_id, waker.clone());
+    }
+
+    pub(crate) fn wait_writable(&mut self, stream_id: u64, waker: &Waker) {
+        self.writers.insert(stream_id, waker.clone());
+    }
+
+    pub(crate) fn wait(&mut self, waker: &Waker) {
+        self.waiters.push(waker.clone());
+    }
+
+    fn wake(wakers: &mut HashMap<u64, Waker>, stream_id: u64) {
+        if let Some(w) = wakers.remove(&stream_id) {
+            w.wake();
+        }
+    }
+
+    fn wake_waiters(&mut self) {
+        for w in self.waiters.drain(..) {
+            w.wake();
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for (_, w) in self.readers.drain().chain(self.writers.drain()) {
+            w.wake();
+        }
+        self.wake_waiters();
+    }
+
+    fn handle_events(&mut self) {
+        while let Some(event) = self.conn.next_event() {
+            qdebug!([self.conn], "Event {:?}", event);
+            match event {
+                ConnectionEvent::AuthenticationNeeded => {
+                    let status = (self.authenticate)(&self.conn);
+                    self.conn.authenticated(status, Instant::now());
+                }
+                ConnectionEvent::NewStream { stream_id } => {
+                    self.incoming.push_back(stream_id.as_u64());
+                    self.wake_waiters();
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id }
+                | ConnectionEvent::RecvStreamReset { stream_id, .. } => {
+                    Self::wake(&mut self.readers, stream_id);
+                }
+                ConnectionEvent::SendStreamWritable { stream_id } => {
+                    Self::wake(&mut self.writers, stream_id.as_u64());
+                }
+                ConnectionEvent::SendStreamStopSending { stream_id, .. } => {
+                    Self::wake(&mut self.writers, stream_id);
+                }
+                ConnectionEvent::StateChange(state) => {
+                    if state.closed() {
+                        self.wake_all();
+                    } else {
+                        self.wake_waiters();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Handle events and collect output until the connection has nothing more
+    /// to do.  This returns the datagrams to send and how long to wait before
+    /// calling again, or `None` if the connection is done.
+    fn process_output(&mut self) -> (Vec<Datagram>, Option<Duration>) {
+        let mut dgrams = Vec::new();
+        // Handling events can create output, like authenticating does.
+        let timeout = loop {
+            self.handle_events();
+            match self.conn.process_output(Instant::now()) {
+                Output::Datagram(d) => dgrams.push(d),
+                Output::Callback(t) if !self.conn.has_events() => break Some(t),
+                Output::None if !self.conn.has_events() => break None,
+                _ => {}
+            }
+        };
+        (dgrams, timeout)
+    }
+}
+
+/// What the connection handle and its streams share with the driver.
+#[derive(Clone)]
+pub(crate) struct Handle {
+    shared: Rc<RefCell<Shared>>,
+    notify: Rc<Notify>,
+}
+
+impl Handle {
+    pub(crate) fn new(shared: Shared) -> Self {
+        Self {
+            shared: Rc::new(RefCell::new(shared)),
+            notify: Rc::new(Notify::new()),
+        }
+    }
+
+    pub(crate) fn borrow_mut(&self) -> RefMut<Shared> {
+        self.shared.borrow_mut()
+    }
+
+    /// Tell the driver that the connection might have something to send.
+    pub(crate) fn notify(&self) {
+        self.notify.notify();
+    }
+}
+
+/// The same as `futures::future::poll_fn`.
+pub(crate) struct PollFn<F>(F);
+
+impl<T, F: FnMut(&mut Context<'_>) -> Poll<T> + Unpin> Future for PollFn<F> {
+    type Output = T;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        (self.0)(cx)
+    }
+}
+
+pub(crate) fn poll_fn<T, F: FnMut(&mut Context<'_>) -> Poll<T> + Unpin>(f: F) -> PollFn<F> {
+    PollFn(f)
+}
+
+/// Run the connection until it closes.  `socket` is connected to `remote`.
+#[allow(clippy::needless_pass_by_value)] // This is spawned, so it can't borrow.
+pub(crate) async fn drive(
+    handle: Handle,
+    mut socket: UdpSocket,
+    local: SocketAddr,
+    remote: SocketAddr,
+) {
+    let mut buf = vec![0; RECV_BUFFER_SIZE];
+    loop {
+        let (dgrams, timeout) = handle.borrow_mut().process_output();
+        for d in dgrams {
+            if let Err(e) = socket.send(&d).await {
+                qwarn!("UDP send error: {}", e);
+            }
+        }
+        let timeout = match timeout {
+            Some(t) => t,
+            None => {
+                handle.borrow_mut().wake_all();
+                return;
+            }
+        };
+
+        let received = tokio::select! {
+            res = socket.recv(&mut buf) => Some(res),
+            _ = time::delay_for(timeout) => None,
+            _ = handle.notify.notified() => None,
+        };
+        match received {
+            Some(Ok(sz)) => {
+                let d = Datagram::new(remote, local, &buf[..sz]);
+                handle.borrow_mut().conn.process_input(d, Instant::now());
+            }
+            Some(Err(e)) => qwarn!("UDP receive error: {}", e),
+            None => {}
+        }
+    }
+}
+
+/// Receive on whichever of `sockets` has a datagram first.  This returns the
+/// index of that socket and how much was received into its buffer.
+async fn recv_any(sockets: &mut [UdpSocket], bufs: &mut [Vec<u8>]) -> (usize, io::Result<usize>) {
+    let mut recvs: Vec<_> = sockets
+        .iter_mut()
+        .zip(bufs.iter_mut())
+        .map(|(s, b)| Box::pin(s.recv(b)))
+        .collect();
+    poll_fn(|cx| {
+        for (i, r) in recvs.iter_mut().enumerate() {
+            if let Poll::Ready(res) = r.as_mut().poll(cx) {
+                return Poll::Ready((i, res));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Run `race` until an attempt wins, and return it.  `sockets[i]` is
+/// connected from `locals[i]` to `remotes[i]`, which are the addresses that
+/// attempt `i` uses.  This returns `None` if every attempt fails.
+pub(crate) async fn race(
+    mut race: ConnectionRace,
+    sockets: &mut [UdpSocket],
+    locals: &[SocketAddr],
+    remotes: &[SocketAddr],
+) -> Option<Connection> {
+    let mut bufs = vec![vec![0; RECV_BUFFER_SIZE]; sockets.len()];
+    loop {
+        let timeout = loop {
+            match race.process_output(Instant::now()) {
+                Output::Datagram(d) => {
+                    if let Some(i) = locals.iter().position(|a| *a == d.source()) {
+                        if let Err(e) = sockets[i].send(&d).await {
+                            qwarn!("UDP send error: {}", e);
+                        }
+                    }
+                }
+                Output::Callback(t) => break t,
+                Output::None => return None,
+            }
+        };
+        if let Some(winner) = race.take_winner() {
+            return Some(winner);
+        }
+
+        let received = tokio::select! {
+            res = recv_any(sockets, &mut bufs) => Some(res),
+            _ = time::delay_for(timeout) => None,
+        };
+        match received {
+            Some((i, Ok(sz))) => {
+                let d = Datagram::new(remotes[i], locals[i], &bufs[i][..sz]);
+                race.process_input(d, Instant::now());
+            }
+            Some((_, Err(e))) => qwarn!("UDP receive error: {}", e),
+            None => {}
+        }
+    }
+}
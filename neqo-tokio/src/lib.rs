@@ -0,0 +1,301 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::pedantic)]
+
+// Run a QUIC connection in a tokio task.
+//
+// `quic_connect` makes a client connection and spawns a task that owns the
+// socket.  That task feeds datagrams into the connection, sends what it
+// produces, and sleeps until the time that `process_output` asks for, or
+// until a stream is used.  Streams implement `AsyncRead` and `AsyncWrite`.
+//
+// `Connection` isn't `Send`, so the task is spawned with `spawn_local` and
+// all of this has to run on a `tokio::task::LocalSet`.
+
+mod driver;
+mod stream;
+
+pub use stream::QuicStream;
+
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::{
+    AppError, Connection, ConnectionError, ConnectionRace, Error as TransportError,
+    FixedConnectionIdManager, QuicVersion, State, StreamType, CONNECTION_ATTEMPT_DELAY,
+};
+
+use std::cell::RefCell;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::Instant;
+
+use tokio::net::UdpSocket;
+use tokio::task;
+
+use driver::{poll_fn, Handle, Shared};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Transport(TransportError),
+    /// The connection closed before it was established.
+    Closed(ConnectionError),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<TransportError> for Error {
+    fn from(err: TransportError) -> Self {
+        Self::Transport(err)
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Transport(e) => Some(e),
+            Self::Closed(_) => None,
+        }
+    }
+}
+
+impl ::std::fmt::Display for Error {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "QUIC task error: {:?}", self)
+    }
+}
+
+pub type Res<T> = Result<T, Error>;
+
+/// Make a socket that is connected to `remote`, and return its local address.
+async fn connected_socket(remote: SocketAddr) -> Res<(UdpSocket, SocketAddr)> {
+    let local = match remote {
+        SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(remote).await?;
+    let local = socket.local_addr()?;
+    Ok((socket, local))
+}
+
+fn new_client(
+    server_name: &str,
+    alpn: &[impl AsRef<str>],
+    local: SocketAddr,
+    remote: SocketAddr,
+    version: QuicVersion,
+) -> Res<Connection> {
+    Ok(Connection::new_client(
+        server_name,
+        alpn,
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(0)?)),
+        local,
+        remote,
+        version,
+    )?)
+}
+
+/// Spawn the task that runs `conn`, then wait for the handshake.
+async fn run(
+    conn: Connection,
+    socket: UdpSocket,
+    authenticate: impl FnMut(&Connection) -> AuthenticationStatus + 'static,
+) -> Res<QuicConnection> {
+    let path = conn.path().expect("a client connection has a path");
+    let (local, remote) = (*path.local_address(), *path.remote_address());
+    let handle = Handle::new(Shared::new(conn, Box::new(authenticate)));
+    task::spawn_local(driver::drive(handle.clone(), socket, local, remote));
+
+    let c = QuicConnection { handle };
+    c.established().await?;
+    Ok(c)
+}
+
+/// Connect to `remote`, using `server_name` for the certificate and SNI.
+/// `authenticate` decides whether the server certificate is acceptable.  It
+/// must not use the `QuicConnection`, which is busy when it is called.
+///
+/// # Errors
+///
+/// When a socket can't be made, the connection can't be created, or the
+/// connection closes before the handshake completes.
+pub async fn quic_connect(
+    server_name: &str,
+    alpn: &[impl AsRef<str>],
+    remote: SocketAddr,
+    authenticate: impl FnMut(&Connection) -> AuthenticationStatus + 'static,
+) -> Res<QuicConnection> {
+    let (socket, local) = connected_socket(remote).await?;
+    let conn = new_client(server_name, alpn, local, remote, QuicVersion::default())?;
+    run(conn, socket, authenticate).await
+}
+
+/// Connect to whichever of `remotes` answers first, with whichever of
+/// `versions` the server takes, in the style of Happy Eyeballs.  Both are in
+/// order of preference, as from name resolution, and there is an attempt for
+/// each version at each address.  Each attempt is tried
+/// `CONNECTION_ATTEMPT_DELAY` after the one before, or sooner if the earlier
+/// ones fail.  Otherwise, this is like `quic_connect`.
+///
+/// # Errors
+///
+/// As for `quic_connect`, or an `InvalidInput` I/O error when `remotes` or
+/// `versions` is empty or none of the attempts get an answer.
+pub async fn quic_connect_race(
+    server_name: &str,
+    alpn: &[impl AsRef<str>],
+    remotes: &[SocketAddr],
+    versions: &[QuicVersion],
+    authenticate: impl FnMut(&Connection) -> AuthenticationStatus + 'static,
+) -> Res<QuicConnection> {
+    let mut sockets = Vec::with_capacity(remotes.len());
+    let mut locals = Vec::with_capacity(remotes.len());
+    let mut attempts = Vec::with_capacity(remotes.len() * versions.len());
+    for remote in remotes {
+        let (socket, local) = connected_socket(*remote).await?;
+        for version in versions {
+            attempts.push(new_client(server_name, alpn, local, *remote, *version)?);
+        }
+        sockets.push(socket);
+        locals.push(local);
+    }
+    if attempts.is_empty() {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no addresses or versions to connect with",
+        )));
+    }
+
+    let race = ConnectionRace::new(attempts, CONNECTION_ATTEMPT_DELAY);
+    let conn = driver::race(race, &mut sockets, &locals, remotes)
+        .await
+        .ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no attempt got an answer",
+            ))
+        })?;
+    let local = *conn
+        .path()
+        .expect("a client connection has a path")
+        .local_address();
+    let i = locals.iter().position(|a| *a == local).unwrap();
+    run(conn, sockets.swap_remove(i), authenticate).await
+}
+
+/// A QUIC connection that is run by a task.
+#[derive(Clone)]
+pub struct QuicConnection {
+    handle: Handle,
+}
+
+impl QuicConnection {
+    async fn established(&self) -> Res<()> {
+        poll_fn(|cx| {
+            let mut shared = self.handle.borrow_mut();
+            match shared.conn.state() {
+                State::Connected | State::Confirmed => Poll::Ready(Ok(())),
+                State::Closing { error, .. }
+                | State::Draining { error, .. }
+                | State::Closed(error) => Poll::Ready(Err(Error::Closed(error.clone()))),
+                _ => {
+                    shared.wait(cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    fn open(&self, st: StreamType) -> Res<QuicStream> {
+        let stream_id = self.handle.borrow_mut().conn.stream_create(st)?;
+        Ok(QuicStream::new(self.handle.clone(), stream_id))
+    }
+
+    /// Open a bidirectional stream.
+    ///
+    /// # Errors
+    ///
+    /// When the peer doesn't allow more streams, or the connection is closed.
+    pub fn open_bidi(&self) -> Res<QuicStream> {
+        self.open(StreamType::BiDi)
+    }
+
+    /// Open a unidirectional stream, which can only be written.
+    ///
+    /// # Errors
+    ///
+    /// When the peer doesn't allow more streams, or the connection is closed.
+    pub fn open_uni(&self) -> Res<QuicStream> {
+        self.open(StreamType::UniDi)
+    }
+
+    /// Wait for the peer to open a stream.  This returns `None` once the
+    /// connection closes.
+    pub async fn accept(&self) -> Option<QuicStream> {
+        let stream_id = poll_fn(|cx| {
+            let mut shared = self.handle.borrow_mut();
+            if let Some(stream_id) = shared.incoming.pop_front() {
+                Poll::Ready(Some(stream_id))
+            } else if shared.conn.state().closed() {
+                Poll::Ready(None)
+            } else {
+                shared.wait(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await?;
+        Some(QuicStream::new(self.handle.clone(), stream_id))
+    }
+
+    /// Start closing the connection.
+    pub fn close(&self, app_error: AppError, msg: &str) {
+        self.handle
+            .borrow_mut()
+            .conn
+            .close(Instant::now(), app_error, msg);
+        self.handle.notify();
+    }
+
+    /// Wait for the connection to close, whichever side closes it.
+    pub async fn closed(&self) -> ConnectionError {
+        poll_fn(|cx| {
+            let mut shared = self.handle.borrow_mut();
+            match shared.conn.state() {
+                State::Closing { error, .. }
+                | State::Draining { error, .. }
+                | State::Closed(error) => Poll::Ready(error.clone()),
+                _ => {
+                    shared.wait(cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Use the connection directly, for anything that isn't covered here.
+    pub fn with_connection<T>(&self, f: impl FnOnce(&mut Connection) -> T) -> T {
+        let res = f(&mut self.handle.borrow_mut().conn);
+        self.handle.notify();
+        res
+    }
+}
+
+impl ::std::fmt::Debug for QuicConnection {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "QuicConnection {}", self.handle.borrow_mut().conn)
+    }
+}
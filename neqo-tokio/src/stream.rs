@@ -0,0 +1,151 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_transport::{AppError, Error as TransportError};
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::driver::Handle;
+use crate::Res;
+
+fn io_error(e: TransportError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// A QUIC stream.  Reading a stream that the peer can't send on, or writing
+/// a stream that this endpoint can't send on, fails.
+///
+/// Writes are queued in the connection, which sends them as soon as flow
+/// and congestion control allow, so flushing does nothing.
+pub struct QuicStream {
+    handle: Handle,
+    stream_id: u64,
+    /// The stream is removed once its end is read, so that is tracked here.
+    fin_read: bool,
+}
+
+impl QuicStream {
+    pub(crate) fn new(handle: Handle, stream_id: u64) -> Self {
+        Self {
+            handle,
+            stream_id,
+            fin_read: false,
+        }
+    }
+
+    #[must_use]
+    pub fn stream_id(&self) -> u64 {
+        self.stream_id
+    }
+
+    /// Abandon sending, telling the peer `err`.
+    ///
+    /// # Errors
+    ///
+    /// When the stream can't be sent on.
+    pub fn reset(&self, err: AppError) -> Res<()> {
+        self.handle
+            .borrow_mut()
+            .conn
+            .stream_reset_send(self.stream_id, err)?;
+        self.handle.notify();
+        Ok(())
+    }
+
+    /// Ask the peer to stop sending, telling it `err`.
+    ///
+    /// # Errors
+    ///
+    /// When the stream can't be received on.
+    pub fn stop_sending(&self, err: AppError) -> Res<()> {
+        self.handle
+            .borrow_mut()
+            .conn
+            .stream_stop_sending(self.stream_id, err)?;
+        self.handle.notify();
+        Ok(())
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.fin_read || buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let stream_id = self.stream_id;
+        let res = {
+            let mut shared = self.handle.borrow_mut();
+            match shared.conn.stream_recv(stream_id, buf) {
+                Ok((0, false)) => {
+                    shared.wait_readable(stream_id, cx.waker());
+                    return Poll::Pending;
+                }
+                res => res,
+            }
+        };
+        // Reading can free up flow control credit for the peer.
+        self.handle.notify();
+        match res {
+            Ok((sz, fin)) => {
+                self.fin_read = fin;
+                Poll::Ready(Ok(sz))
+            }
+            Err(e) => Poll::Ready(Err(io_error(e))),
+        }
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let res = {
+            let mut shared = self.handle.borrow_mut();
+            match shared.conn.stream_send(self.stream_id, buf) {
+                Ok(0) => {
+                    shared.wait_writable(self.stream_id, cx.waker());
+                    return Poll::Pending;
+                }
+                res => res,
+            }
+        };
+        self.handle.notify();
+        Poll::Ready(res.map_err(io_error))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = self
+            .handle
+            .borrow_mut()
+            .conn
+            .stream_close_send(self.stream_id);
+        self.handle.notify();
+        Poll::Ready(res.map_err(io_error))
+    }
+}
+
+impl ::std::fmt::Debug for QuicStream {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "QuicStream {}", self.stream_id)
+    }
+}
@@ -0,0 +1,129 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::pedantic)]
+
+use neqo_common::Datagram;
+use neqo_crypto::AuthenticationStatus;
+use neqo_tokio::{quic_connect, quic_connect_race, QuicConnection};
+use neqo_transport::{Connection, ConnectionEvent, Output, QuicVersion};
+use test_fixture::{default_server, fixture_init, DEFAULT_ALPN, DEFAULT_SERVER_NAME};
+
+use std::cmp::max;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::LocalSet;
+
+/// Send back whatever arrives on each stream.
+fn echo(server: &mut Connection) {
+    let mut buf = vec![0; 4096];
+    while let Some(event) = server.next_event() {
+        if let ConnectionEvent::RecvStreamReadable { stream_id } = event {
+            let (sz, fin) = server.stream_recv(stream_id, &mut buf).unwrap();
+            assert_eq!(server.stream_send(stream_id, &buf[..sz]).unwrap(), sz);
+            if fin {
+                server.stream_close_send(stream_id).unwrap();
+            }
+        }
+    }
+}
+
+/// Run a server connection on a plain socket until it closes.
+fn serve(socket: &UdpSocket) {
+    let mut server = default_server();
+    let local = socket.local_addr().unwrap();
+    let mut buf = vec![0; 2048];
+    let mut dgram = None;
+    loop {
+        if let Some(d) = dgram.take() {
+            server.process_input(d, Instant::now());
+        }
+        echo(&mut server);
+        let timeout = loop {
+            match server.process_output(Instant::now()) {
+                Output::Datagram(d) => {
+                    socket.send_to(&d, d.destination()).unwrap();
+                }
+                Output::Callback(t) => break t,
+                Output::None => return,
+            }
+        };
+        socket
+            .set_read_timeout(Some(max(timeout, Duration::from_millis(1))))
+            .unwrap();
+        match socket.recv_from(&mut buf) {
+            Ok((sz, src)) => dgram = Some(Datagram::new(src, local, &buf[..sz])),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => panic!("UDP error: {}", e),
+        }
+    }
+}
+
+async fn echo_and_close(conn: QuicConnection) {
+    let mut stream = conn.open_bidi().unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"hello");
+
+    conn.close(0, "done");
+    conn.closed().await;
+}
+
+#[tokio::test]
+async fn connect_and_echo() {
+    fixture_init();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let server = thread::spawn(move || serve(&socket));
+
+    LocalSet::new()
+        .run_until(async move {
+            let conn = quic_connect(DEFAULT_SERVER_NAME, DEFAULT_ALPN, addr, |_| {
+                AuthenticationStatus::Ok
+            })
+            .await
+            .unwrap();
+            echo_and_close(conn).await;
+        })
+        .await;
+
+    server.join().unwrap();
+}
+
+#[tokio::test]
+async fn race_past_silent_address() {
+    fixture_init();
+    // Nothing answers on this socket.
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addrs = [silent.local_addr().unwrap(), socket.local_addr().unwrap()];
+    let server = thread::spawn(move || serve(&socket));
+
+    LocalSet::new()
+        .run_until(async move {
+            let conn = quic_connect_race(
+                DEFAULT_SERVER_NAME,
+                DEFAULT_ALPN,
+                &addrs,
+                &[QuicVersion::default()],
+                |_| AuthenticationStatus::Ok,
+            )
+            .await
+            .unwrap();
+            echo_and_close(conn).await;
+        })
+        .await;
+
+    server.join().unwrap();
+    drop(silent);
+}
@@ -0,0 +1,445 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// A model-based congestion controller, in the style of BBR.
+#![deny(clippy::pedantic)]
+
+use std::cmp::{max, min, Ordering};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+use std::time::{Duration, Instant};
+
+use neqo_common::{qdebug, qinfo, qtrace};
+
+use crate::rate::RateSample;
+
+/// The number of rounds over which the maximum delivery rate is tracked.
+const BTL_BW_FILTER_LEN: u64 = 10;
+/// How long a minimum RTT sample remains valid before probing for a new one.
+const MIN_RTT_FILTER_LEN: Duration = Duration::from_secs(10);
+/// The minimum time to spend in `ProbeRtt`.
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+/// Gains are expressed in units of `1 / GAIN_UNIT`.
+const GAIN_UNIT: u64 = 100;
+/// The gain used in `Startup`, approximately 2/ln(2).
+const STARTUP_GAIN: u64 = 289;
+/// The pacing gain used in `Drain`, which is the inverse of `STARTUP_GAIN`.
+const DRAIN_GAIN: u64 = 35;
+/// The gain applied to the estimated BDP to get the congestion window.
+const CWND_GAIN: u64 = 200;
+/// The sequence of pacing gains that are cycled through in `ProbeBw`.
+const PROBE_BW_GAINS: [u64; 8] = [125, 75, 100, 100, 100, 100, 100, 100];
+/// During `Startup`, the delivery rate has to grow by at least this much
+/// (as a gain) each round, or the pipe is considered to be full.
+const FULL_BW_THRESH: u64 = 125;
+/// The number of rounds without growth before the pipe is considered full.
+const FULL_BW_COUNT: u32 = 3;
+/// The smallest congestion window, in packets.
+const MIN_PIPE_CWND_PKTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BbrState {
+    /// Grow the sending rate quickly to find the bottleneck bandwidth.
+    Startup,
+    /// Drain the queue that was created in `Startup`.
+    Drain,
+    /// Cycle the pacing gain to probe for more bandwidth.
+    ProbeBw,
+    /// Reduce the amount of data in flight to measure the minimum RTT.
+    ProbeRtt,
+}
+
+#[derive(Debug)]
+pub struct Bbr {
+    state: BbrState,
+    /// The packet size, used for determining the minimum window.
+    mss: usize,
+    initial_cwnd: usize,
+    cwnd: usize,
+    /// The congestion window from before `ProbeRtt`, which is restored after.
+    prior_cwnd: usize,
+    pacing_gain: u64,
+    cwnd_gain: u64,
+
+    /// Delivery rate samples as (round, bytes per second), in decreasing order of rate.
+    /// The first entry is the estimate of the bottleneck bandwidth.
+    bw_samples: VecDeque<(u64, u64)>,
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Option<Instant>,
+
+    /// The total bytes delivered, as of the latest rate sample.
+    delivered: u64,
+
+    /// Round counting.  A round ends when a packet that was sent after the
+    /// previous round ended is acknowledged.
+    round_count: u64,
+    next_round_delivered: u64,
+
+    /// Detecting when `Startup` has filled the pipe.
+    full_bw: u64,
+    full_bw_count: u32,
+    filled_pipe: bool,
+
+    /// The position in `PROBE_BW_GAINS` and when it was reached.
+    cycle_index: usize,
+    cycle_stamp: Option<Instant>,
+
+    /// When to leave `ProbeRtt`, once that has been determined.
+    probe_rtt_done: Option<Instant>,
+    probe_rtt_round_done: bool,
+}
+
+impl Bbr {
+    pub fn new(initial_cwnd: usize, mss: usize) -> Self {
+        Self {
+            state: BbrState::Startup,
+            mss,
+            initial_cwnd,
+            cwnd: initial_cwnd,
+            prior_cwnd: initial_cwnd,
+            pacing_gain: STARTUP_GAIN,
+            cwnd_gain: STARTUP_GAIN,
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: None,
+            delivered: 0,
+            round_count: 0,
+            next_round_delivered: 0,
+            full_bw: 0,
+            full_bw_count: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_stamp: None,
+            probe_rtt_done: None,
+            probe_rtt_round_done: false,
+        }
+    }
+
+    #[must_use]
+    pub fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    /// Whether the controller is still searching for the bottleneck bandwidth.
+    #[must_use]
+    pub fn in_startup(&self) -> bool {
+        self.state == BbrState::Startup
+    }
+
+    /// The estimated bottleneck bandwidth, in bytes per second.
+    #[must_use]
+    pub fn btl_bw(&self) -> u64 {
+        self.bw_samples.front().map_or(0, |(_, bw)| *bw)
+    }
+
+    /// The rate at which to pace packets, in bytes per second.
+    /// This is `None` until the delivery rate has been sampled.
+    #[must_use]
+    pub fn pacing_rate(&self) -> Option<u64> {
+        match self.btl_bw() {
+            0 => None,
+            bw => Some(bw.saturating_mul(self.pacing_gain) / GAIN_UNIT),
+        }
+    }
+
+    fn min_pipe_cwnd(&self) -> usize {
+        MIN_PIPE_CWND_PKTS * self.mss
+    }
+
+    /// The amount of data that we want in flight: the estimated
+    /// bandwidth-delay product, scaled by `gain`.
+    fn inflight(&self, gain: u64) -> usize {
+        let bw = self.btl_bw();
+        match self.min_rtt {
+            Some(rtt) if bw > 0 => {
+                let bdp = u128::from(bw) * rtt.as_micros() / 1_000_000;
+                let target = bdp * u128::from(gain) / u128::from(GAIN_UNIT);
+                usize::try_from(target).unwrap_or(usize::max_value())
+            }
+            _ => self.initial_cwnd,
+        }
+    }
+
+    /// Update the model with a delivery rate sample.
+    pub fn on_packets_acked(&mut self, sample: &RateSample, bytes_in_flight: usize, now: Instant) {
+        self.delivered = sample.delivered;
+
+        let round_start = sample.prior_delivered >= self.next_round_delivered;
+        if round_start {
+            self.next_round_delivered = self.delivered;
+            self.round_count += 1;
+        }
+
+        if let Some(rate) = sample.rate() {
+            // An application-limited sample only shows what the application
+            // sent, so it only counts if it is higher than the estimate.
+            if !sample.app_limited || rate >= self.btl_bw() {
+                self.update_btl_bw(rate);
+            }
+        }
+
+        self.update_min_rtt(sample.rtt, now);
+        self.update_state(round_start, bytes_in_flight, now);
+        self.update_cwnd(sample.acked_bytes);
+        qtrace!([self], "acked {} bytes", sample.acked_bytes);
+    }
+
+    /// With persistent congestion, the model is no longer trustworthy.
+    pub fn on_persistent_congestion(&mut self) {
+        self.prior_cwnd = self.cwnd;
+        self.cwnd = self.min_pipe_cwnd();
+        qinfo!([self], "persistent congestion");
+    }
+
+    fn update_btl_bw(&mut self, rate: u64) {
+        while self.bw_samples.front().map_or(false, |(round, _)| {
+            round + BTL_BW_FILTER_LEN <= self.round_count
+        }) {
+            self.bw_samples.pop_front();
+        }
+        // Smaller samples can't become the maximum before this one expires.
+        while self.bw_samples.back().map_or(false, |(_, bw)| *bw <= rate) {
+            self.bw_samples.pop_back();
+        }
+        self.bw_samples.push_back((self.round_count, rate));
+    }
+
+    fn update_min_rtt(&mut self, rtt: Duration, now: Instant) {
+        let expired = self.min_rtt_stamp.map_or(false, |t| {
+            now.saturating_duration_since(t) > MIN_RTT_FILTER_LEN
+        });
+        if expired || self.min_rtt.map_or(true, |m| rtt <= m) {
+            self.min_rtt = Some(rtt);
+            self.min_rtt_stamp = Some(now);
+        }
+        if expired && self.state != BbrState::ProbeRtt {
+            self.enter_probe_rtt();
+        }
+    }
+
+    fn update_state(&mut self, round_start: bool, bytes_in_flight: usize, now: Instant) {
+        self.check_full_pipe(round_start);
+        match self.state {
+            BbrState::Startup => {
+                if self.filled_pipe {
+                    self.set_state(BbrState::Drain);
+                    self.pacing_gain = DRAIN_GAIN;
+                    self.cwnd_gain = STARTUP_GAIN;
+                }
+            }
+            BbrState::Drain => {
+                if bytes_in_flight <= self.inflight(GAIN_UNIT) {
+                    self.enter_probe_bw(now);
+                }
+            }
+            BbrState::ProbeBw => self.advance_cycle(bytes_in_flight, now),
+            BbrState::ProbeRtt => self.handle_probe_rtt(round_start, bytes_in_flight, now),
+        }
+    }
+
+    fn check_full_pipe(&mut self, round_start: bool) {
+        if self.filled_pipe || !round_start {
+            return;
+        }
+        let bw = self.btl_bw();
+        if bw.saturating_mul(GAIN_UNIT) >= self.full_bw.saturating_mul(FULL_BW_THRESH) {
+            self.full_bw = bw;
+            self.full_bw_count = 0;
+            return;
+        }
+        self.full_bw_count += 1;
+        if self.full_bw_count >= FULL_BW_COUNT {
+            qdebug!([self], "pipe filled at {} bytes/s", bw);
+            self.filled_pipe = true;
+        }
+    }
+
+    fn set_state(&mut self, state: BbrState) {
+        qdebug!([self], "{:?} -> {:?}", self.state, state);
+        self.state = state;
+    }
+
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.set_state(BbrState::ProbeBw);
+        self.cwnd_gain = CWND_GAIN;
+        self.cycle_index = 0;
+        self.cycle_stamp = Some(now);
+        self.pacing_gain = PROBE_BW_GAINS[self.cycle_index];
+    }
+
+    fn advance_cycle(&mut self, bytes_in_flight: usize, now: Instant) {
+        let elapsed = match (self.cycle_stamp, self.min_rtt) {
+            (Some(stamp), Some(rtt)) => now.saturating_duration_since(stamp) > rtt,
+            _ => true,
+        };
+        let next = match self.pacing_gain.cmp(&GAIN_UNIT) {
+            // Keep probing until the extra data is actually in flight.
+            Ordering::Greater => elapsed && bytes_in_flight >= self.inflight(self.pacing_gain),
+            // Stop draining early if the queue is already gone.
+            Ordering::Less => elapsed || bytes_in_flight <= self.inflight(GAIN_UNIT),
+            Ordering::Equal => elapsed,
+        };
+        if next {
+            self.cycle_index = (self.cycle_index + 1) % PROBE_BW_GAINS.len();
+            self.cycle_stamp = Some(now);
+            self.pacing_gain = PROBE_BW_GAINS[self.cycle_index];
+        }
+    }
+
+    fn enter_probe_rtt(&mut self) {
+        self.set_state(BbrState::ProbeRtt);
+        self.prior_cwnd = max(self.prior_cwnd, self.cwnd);
+        self.pacing_gain = GAIN_UNIT;
+        self.cwnd_gain = GAIN_UNIT;
+        self.probe_rtt_done = None;
+        self.probe_rtt_round_done = false;
+    }
+
+    fn handle_probe_rtt(&mut self, round_start: bool, bytes_in_flight: usize, now: Instant) {
+        if let Some(done) = self.probe_rtt_done {
+            if round_start {
+                self.probe_rtt_round_done = true;
+            }
+            if self.probe_rtt_round_done && now >= done {
+                self.min_rtt_stamp = Some(now);
+                self.cwnd = max(self.cwnd, self.prior_cwnd);
+                if self.filled_pipe {
+                    self.enter_probe_bw(now);
+                } else {
+                    self.set_state(BbrState::Startup);
+                    self.pacing_gain = STARTUP_GAIN;
+                    self.cwnd_gain = STARTUP_GAIN;
+                }
+            }
+        } else if bytes_in_flight <= self.min_pipe_cwnd() {
+            self.probe_rtt_done = Some(now + PROBE_RTT_DURATION);
+            self.probe_rtt_round_done = false;
+            self.next_round_delivered = self.delivered;
+        }
+    }
+
+    fn update_cwnd(&mut self, acked_bytes: usize) {
+        let target = max(self.inflight(self.cwnd_gain), self.min_pipe_cwnd());
+        if self.filled_pipe {
+            self.cwnd = min(self.cwnd + acked_bytes, target);
+        } else if self.cwnd < target || self.delivered < u64::try_from(self.initial_cwnd).unwrap() {
+            self.cwnd += acked_bytes;
+        }
+        self.cwnd = max(self.cwnd, self.min_pipe_cwnd());
+        if self.state == BbrState::ProbeRtt {
+            self.cwnd = min(self.cwnd, self.min_pipe_cwnd());
+        }
+    }
+}
+
+impl Display for Bbr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BBR {:?} bw {} min_rtt {:?} cwnd {}",
+            self.state,
+            self.btl_bw(),
+            self.min_rtt,
+            self.cwnd
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bbr, BbrState, PROBE_BW_GAINS};
+    use crate::packet::PacketType;
+    use crate::rate::DeliveryRate;
+    use crate::tracking::SentPacket;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+    use test_fixture::now;
+
+    const MSS: usize = 1000;
+    const INITIAL_CWND: usize = 10 * MSS;
+    const RTT: Duration = Duration::from_millis(100);
+
+    fn sent(pn: u64, t: Instant) -> SentPacket {
+        SentPacket::new(PacketType::Short, pn, t, true, Rc::default(), MSS, true)
+    }
+
+    /// Send `count` packets at `t`, then acknowledge them all one RTT later.
+    /// Returns the time of the acknowledgment.
+    fn round(
+        bbr: &mut Bbr,
+        dr: &mut DeliveryRate,
+        pn: &mut u64,
+        count: usize,
+        t: Instant,
+    ) -> Instant {
+        let mut pkts = Vec::new();
+        for i in 0..count {
+            let pkt = sent(*pn, t);
+            dr.on_packet_sent(&pkt, i * MSS);
+            pkts.push(pkt);
+            *pn += 1;
+        }
+        let ack_time = t + RTT;
+        let sample = dr.on_packets_acked(&pkts, ack_time).unwrap();
+        bbr.on_packets_acked(&sample, 0, ack_time);
+        ack_time
+    }
+
+    #[test]
+    fn startup_grows() {
+        let mut bbr = Bbr::new(INITIAL_CWND, MSS);
+        assert!(bbr.pacing_rate().is_none());
+        let mut dr = DeliveryRate::default();
+        let mut pn = 0;
+        round(&mut bbr, &mut dr, &mut pn, 10, now());
+        assert!(bbr.in_startup());
+        assert_eq!(bbr.cwnd(), 2 * INITIAL_CWND);
+        // 10 packets over one RTT.
+        assert_eq!(bbr.btl_bw(), 100_000);
+        assert!(bbr.pacing_rate().unwrap() > bbr.btl_bw());
+    }
+
+    #[test]
+    fn full_pipe_leaves_startup() {
+        let mut bbr = Bbr::new(INITIAL_CWND, MSS);
+        let mut dr = DeliveryRate::default();
+        let mut pn = 0;
+        let mut t = now();
+        // Keep sending at the same rate, so the bandwidth stops increasing.
+        for _ in 0..5 {
+            t = round(&mut bbr, &mut dr, &mut pn, 10, t);
+        }
+        assert!(bbr.filled_pipe);
+        // With nothing in flight at the end of a round, drain completes immediately.
+        assert_eq!(bbr.state, BbrState::ProbeBw);
+        assert_eq!(bbr.pacing_gain, PROBE_BW_GAINS[0]);
+        // The window is the BDP times the gain, which is 2 * 10 packets.
+        assert_eq!(bbr.cwnd(), 2 * INITIAL_CWND);
+    }
+
+    #[test]
+    fn app_limited_samples() {
+        let mut bbr = Bbr::new(INITIAL_CWND, MSS);
+        let mut dr = DeliveryRate::default();
+        let mut pn = 0;
+        let t = round(&mut bbr, &mut dr, &mut pn, 10, now());
+        assert_eq!(bbr.btl_bw(), 100_000);
+
+        // A slower sample doesn't lower the estimate if it was application limited.
+        dr.on_app_limited(0);
+        round(&mut bbr, &mut dr, &mut pn, 2, t);
+        assert_eq!(bbr.btl_bw(), 100_000);
+        assert_eq!(bbr.bw_samples.len(), 1);
+    }
+
+    #[test]
+    fn persistent_congestion() {
+        let mut bbr = Bbr::new(INITIAL_CWND, MSS);
+        bbr.on_persistent_congestion();
+        assert_eq!(bbr.cwnd(), 4 * MSS);
+    }
+}
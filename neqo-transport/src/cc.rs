@@ -7,11 +7,14 @@
 // Congestion control
 
 use std::cmp::max;
+use std::convert::TryFrom;
 use std::fmt::{self, Display};
 use std::time::{Duration, Instant};
 
-use crate::pace::Pacer;
+use crate::bbr::Bbr;
+use crate::pace::{Pacer, PACER_SPEEDUP};
 use crate::path::PATH_MTU_V6;
+use crate::rate::DeliveryRate;
 use crate::tracking::SentPacket;
 use neqo_common::{const_max, const_min, qdebug, qinfo, qtrace};
 
@@ -26,6 +29,44 @@ pub const MIN_CONG_WINDOW: usize = MAX_DATAGRAM_SIZE * 2;
 pub(crate) const PACING_BURST_SIZE: usize = 2;
 const PERSISTENT_CONG_THRESH: u32 = 3;
 
+/// The algorithm used to determine the congestion window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionControlAlgorithm {
+    /// Loss-based congestion control, following -recovery.
+    NewReno,
+    /// Model-based congestion control, which estimates the bottleneck bandwidth
+    /// and minimum RTT.  This is less sensitive to random loss than `NewReno`.
+    Bbr,
+}
+
+impl Default for CongestionControlAlgorithm {
+    fn default() -> Self {
+        Self::NewReno
+    }
+}
+
+/// The phase that the congestion controller is in.
+/// This is only tracked so that changes can be reported in qlog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionState {
+    SlowStart,
+    CongestionAvoidance,
+    Recovery,
+    PersistentCongestion,
+}
+
+impl CongestionState {
+    #[must_use]
+    pub fn to_qlog_string(self) -> &'static str {
+        match self {
+            Self::SlowStart => "slow_start",
+            Self::CongestionAvoidance => "congestion_avoidance",
+            Self::Recovery => "recovery",
+            Self::PersistentCongestion => "persistent_congestion",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct CongestionControl {
     congestion_window: usize, // = kInitialWindow
@@ -33,17 +74,17 @@ pub struct CongestionControl {
     congestion_recovery_start_time: Option<Instant>,
     ssthresh: usize,
     pacer: Option<Pacer>,
+    state: CongestionState,
+    /// The state that was last reported by `take_state_change`.
+    reported_state: Option<CongestionState>,
+    /// When using BBR, this sets the congestion window and pacing rate.
+    bbr: Option<Bbr>,
+    rate: DeliveryRate,
 }
 
 impl Default for CongestionControl {
     fn default() -> Self {
-        Self {
-            congestion_window: INITIAL_WINDOW,
-            bytes_in_flight: 0,
-            congestion_recovery_start_time: None,
-            ssthresh: std::usize::MAX,
-            pacer: None,
-        }
+        Self::new(CongestionControlAlgorithm::default())
     }
 }
 
@@ -54,6 +95,9 @@ impl Display for CongestionControl {
             "CongCtrl {}/{} ssthresh {}",
             self.bytes_in_flight, self.congestion_window, self.ssthresh,
         )?;
+        if let Some(b) = &self.bbr {
+            write!(f, " {}", b)?;
+        }
         if let Some(p) = &self.pacer {
             write!(f, " {}", p)?;
         }
@@ -62,18 +106,63 @@ impl Display for CongestionControl {
 }
 
 impl CongestionControl {
-    #[cfg(test)]
+    #[must_use]
+    pub fn new(algorithm: CongestionControlAlgorithm) -> Self {
+        Self {
+            congestion_window: INITIAL_WINDOW,
+            bytes_in_flight: 0,
+            congestion_recovery_start_time: None,
+            ssthresh: std::usize::MAX,
+            pacer: None,
+            state: CongestionState::SlowStart,
+            reported_state: None,
+            bbr: match algorithm {
+                CongestionControlAlgorithm::NewReno => None,
+                CongestionControlAlgorithm::Bbr => {
+                    Some(Bbr::new(INITIAL_WINDOW, MAX_DATAGRAM_SIZE))
+                }
+            },
+            rate: DeliveryRate::default(),
+        }
+    }
+
     #[must_use]
     pub fn cwnd(&self) -> usize {
         self.congestion_window
     }
 
-    #[cfg(test)]
     #[must_use]
     pub fn ssthresh(&self) -> usize {
         self.ssthresh
     }
 
+    #[must_use]
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    #[must_use]
+    pub fn delivery_rate(&self) -> &DeliveryRate {
+        &self.rate
+    }
+
+    /// Note that there is nothing to send, even though the congestion window
+    /// has space, which makes delivery rate samples unreliable for a while.
+    pub fn on_app_limited(&mut self) {
+        self.rate.on_app_limited(self.bytes_in_flight);
+    }
+
+    /// If the congestion state changed since this was last called, return the
+    /// previously reported state (if any) and the current state.
+    pub fn take_state_change(&mut self) -> Option<(Option<CongestionState>, CongestionState)> {
+        if self.reported_state == Some(self.state) {
+            None
+        } else {
+            let old = self.reported_state.replace(self.state);
+            Some((old, self.state))
+        }
+    }
+
     #[must_use]
     pub fn cwnd_avail(&self) -> usize {
         // BIF can be higher than cwnd due to PTO packets, which are sent even
@@ -82,11 +171,28 @@ impl CongestionControl {
     }
 
     // Multi-packet version of OnPacketAckedCC
-    pub fn on_packets_acked(&mut self, acked_pkts: &[SentPacket]) {
+    pub fn on_packets_acked(&mut self, acked_pkts: &[SentPacket], now: Instant) {
+        let sample = self.rate.on_packets_acked(acked_pkts, now);
         for pkt in acked_pkts.iter().filter(|pkt| pkt.cc_outstanding()) {
             assert!(self.bytes_in_flight >= pkt.size);
             self.bytes_in_flight -= pkt.size;
+        }
 
+        if let Some(bbr) = &mut self.bbr {
+            if let Some(sample) = &sample {
+                bbr.on_packets_acked(sample, self.bytes_in_flight, now);
+            }
+            self.congestion_window = bbr.cwnd();
+            self.state = if bbr.in_startup() {
+                CongestionState::SlowStart
+            } else {
+                CongestionState::CongestionAvoidance
+            };
+            qdebug!([self], "BBR update");
+            return;
+        }
+
+        for pkt in acked_pkts.iter().filter(|pkt| pkt.cc_outstanding()) {
             if self.in_congestion_recovery(pkt.time_sent) {
                 // Do not increase congestion window in recovery period.
                 continue;
@@ -98,9 +204,11 @@ impl CongestionControl {
 
             if self.congestion_window < self.ssthresh {
                 self.congestion_window += pkt.size;
+                self.state = CongestionState::SlowStart;
                 qinfo!([self], "slow start");
             } else {
                 self.congestion_window += (MAX_DATAGRAM_SIZE * pkt.size) / self.congestion_window;
+                self.state = CongestionState::CongestionAvoidance;
                 qinfo!([self], "congestion avoidance");
             }
         }
@@ -124,8 +232,12 @@ impl CongestionControl {
 
         qdebug!([self], "Pkts lost {}", lost_packets.len());
 
+        self.rate.on_packets_lost(lost_packets);
         let last_lost_pkt = lost_packets.last().unwrap();
-        self.on_congestion_event(now, last_lost_pkt.time_sent);
+        // BBR doesn't treat loss as a signal of congestion.
+        if self.bbr.is_none() {
+            self.on_congestion_event(now, last_lost_pkt.time_sent);
+        }
 
         let congestion_period = pto * PERSISTENT_CONG_THRESH;
 
@@ -135,13 +247,30 @@ impl CongestionControl {
             .find(|p| Some(p.time_sent) > prev_largest_acked_sent)
         {
             if last_lost_pkt.time_sent.duration_since(first.time_sent) > congestion_period {
-                self.congestion_window = MIN_CONG_WINDOW;
+                // BBR sets its own minimum window.
+                self.congestion_window = if let Some(bbr) = &mut self.bbr {
+                    bbr.on_persistent_congestion();
+                    bbr.cwnd()
+                } else {
+                    MIN_CONG_WINDOW
+                };
+                self.state = CongestionState::PersistentCongestion;
                 qinfo!([self], "persistent congestion");
             }
         }
     }
 
+    /// The peer reported more packets with ECN-CE marks, the latest of
+    /// which was sent at `sent_time`.
+    pub fn on_ecn_ce(&mut self, now: Instant, sent_time: Instant) {
+        // Like loss, BBR doesn't use this.
+        if self.bbr.is_none() {
+            self.on_congestion_event(now, sent_time);
+        }
+    }
+
     pub fn discard(&mut self, pkt: &SentPacket) {
+        self.rate.discard(pkt);
         if pkt.cc_outstanding() {
             assert!(self.bytes_in_flight >= pkt.size);
             self.bytes_in_flight -= pkt.size;
@@ -150,15 +279,18 @@ impl CongestionControl {
     }
 
     pub fn on_packet_sent(&mut self, pkt: &SentPacket, rtt: Duration) {
+        let pacing_window = self.pacing_window(rtt);
         self.pacer
             .as_mut()
             .unwrap()
-            .spend(pkt.time_sent, rtt, self.congestion_window, pkt.size);
+            .spend(pkt.time_sent, rtt, pacing_window, pkt.size);
 
         if !pkt.cc_in_flight() {
             return;
         }
 
+        self.rate.on_packet_sent(pkt, self.bytes_in_flight);
+
         self.bytes_in_flight += pkt.size;
         qdebug!(
             [self],
@@ -185,6 +317,7 @@ impl CongestionControl {
             self.congestion_window /= 2; // kLossReductionFactor = 0.5
             self.congestion_window = max(self.congestion_window, MIN_CONG_WINDOW);
             self.ssthresh = self.congestion_window;
+            self.state = CongestionState::Recovery;
             qinfo!(
                 [self],
                 "Cong event -> recovery; cwnd {}, ssthresh {}",
@@ -217,10 +350,101 @@ impl CongestionControl {
                 self.pacer
                     .as_ref()
                     .unwrap()
-                    .next(rtt, self.congestion_window),
+                    .next(rtt, self.pacing_window(rtt)),
             )
         } else {
             None
         }
     }
+
+    /// The value that is given to the pacer in place of the congestion window.
+    /// BBR has its own pacing rate, which is converted into the window
+    /// that the pacer would use to produce that rate.
+    fn pacing_window(&self, rtt: Duration) -> usize {
+        match self.bbr.as_ref().and_then(Bbr::pacing_rate) {
+            Some(rate) => {
+                let w = u128::from(rate) * rtt.as_micros()
+                    / (1_000_000 * u128::try_from(PACER_SPEEDUP).unwrap());
+                max(
+                    usize::try_from(w).unwrap_or(usize::max_value()),
+                    MAX_DATAGRAM_SIZE,
+                )
+            }
+            None => self.congestion_window,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CongestionControl, CongestionControlAlgorithm, CongestionState, MAX_DATAGRAM_SIZE,
+        MIN_CONG_WINDOW,
+    };
+    use crate::packet::PacketType;
+    use crate::tracking::SentPacket;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use test_fixture::now;
+
+    const PTO: Duration = Duration::from_millis(100);
+    const RTT: Duration = Duration::from_millis(10);
+
+    /// Lose two packets that were sent much more than 3 PTOs apart.
+    fn persistent_congestion(algorithm: CongestionControlAlgorithm) -> CongestionControl {
+        let mut cc = CongestionControl::new(algorithm);
+        cc.start_pacer(now());
+        let lost: Vec<_> = [0, 10]
+            .iter()
+            .map(|&i| {
+                let t = now() + PTO * i;
+                SentPacket::new(
+                    PacketType::Short,
+                    u64::from(i),
+                    t,
+                    true,
+                    Rc::default(),
+                    MAX_DATAGRAM_SIZE,
+                    true,
+                )
+            })
+            .collect();
+        for pkt in &lost {
+            cc.on_packet_sent(pkt, RTT);
+        }
+        cc.on_packets_lost(now() + PTO * 20, None, PTO, &lost);
+        assert_eq!(cc.state, CongestionState::PersistentCongestion);
+        cc
+    }
+
+    #[test]
+    fn persistent_congestion_newreno() {
+        let cc = persistent_congestion(CongestionControlAlgorithm::NewReno);
+        assert_eq!(cc.cwnd(), MIN_CONG_WINDOW);
+    }
+
+    #[test]
+    fn persistent_congestion_bbr() {
+        let cc = persistent_congestion(CongestionControlAlgorithm::Bbr);
+        let bbr_cwnd = cc.bbr.as_ref().unwrap().cwnd();
+        assert_eq!(cc.cwnd(), bbr_cwnd);
+        assert!(bbr_cwnd > MIN_CONG_WINDOW);
+    }
+
+    #[test]
+    fn ecn_ce_newreno() {
+        let mut cc = CongestionControl::new(CongestionControlAlgorithm::NewReno);
+        let cwnd = cc.cwnd();
+        cc.on_ecn_ce(now() + RTT, now());
+        assert_eq!(cc.cwnd(), cwnd / 2);
+        assert_eq!(cc.state, CongestionState::Recovery);
+    }
+
+    #[test]
+    fn ecn_ce_bbr() {
+        let mut cc = CongestionControl::new(CongestionControlAlgorithm::Bbr);
+        let cwnd = cc.cwnd();
+        cc.on_ecn_ce(now() + RTT, now());
+        assert_eq!(cc.cwnd(), cwnd);
+    }
 }
@@ -11,7 +11,7 @@ use neqo_crypto::random;
 
 use std::borrow::Borrow;
 use std::cmp::max;
-use std::convert::AsRef;
+use std::convert::{AsRef, TryFrom};
 
 pub const MAX_CONNECTION_ID_LEN: usize = 20;
 
@@ -137,6 +137,83 @@ pub trait ConnectionIdDecoder {
 pub trait ConnectionIdManager: ConnectionIdDecoder {
     fn generate_cid(&mut self) -> ConnectionId;
     fn as_decoder(&self) -> &dyn ConnectionIdDecoder;
+    /// The stateless reset token for a connection ID from `generate_cid`.
+    /// By default this is random, so it can't be made again after a restart.
+    fn reset_token(&self, _cid: &[u8]) -> [u8; 16] {
+        random_reset_token()
+    }
+}
+
+/// A stateless reset token that nothing else can recreate.
+pub(crate) fn random_reset_token() -> [u8; 16] {
+    <[u8; 16]>::try_from(&random(16)[..]).unwrap()
+}
+
+/// A `QuicLbConnectionIdManager` makes connection IDs that a load balancer can
+/// route, using the plaintext algorithm from QUIC-LB.  The first octet holds a
+/// 2-bit configuration ID and the length of the rest of the connection ID, which
+/// is the server ID followed by a random nonce.  Because the length is encoded, a
+/// load balancer can decode connection IDs from servers with different settings.
+#[derive(Debug)]
+pub struct QuicLbConnectionIdManager {
+    config_id: u8,
+    server_id: Vec<u8>,
+    nonce_len: usize,
+}
+
+impl QuicLbConnectionIdManager {
+    const CONFIG_ID_SHIFT: u8 = 6;
+    const LENGTH_MASK: u8 = 0x3f;
+    /// Config ID 3 is reserved for connection IDs that can't be routed.
+    pub const MAX_CONFIG_ID: u8 = 2;
+    pub const MIN_NONCE_LEN: usize = 4;
+
+    /// # Panics
+    /// If `config_id` is larger than `MAX_CONFIG_ID`, `nonce_len` is smaller than
+    /// `MIN_NONCE_LEN`, or the connection ID would be too long.
+    pub fn new(config_id: u8, server_id: &[u8], nonce_len: usize) -> Self {
+        assert!(config_id <= Self::MAX_CONFIG_ID);
+        assert!(nonce_len >= Self::MIN_NONCE_LEN);
+        assert!(1 + server_id.len() + nonce_len <= MAX_CONNECTION_ID_LEN);
+        Self {
+            config_id,
+            server_id: server_id.to_vec(),
+            nonce_len,
+        }
+    }
+
+    /// Get the server ID from a connection ID, for a load balancer with a matching
+    /// configuration.  This returns `None` if the connection ID uses another
+    /// configuration or is too short.
+    pub fn server_id<'a>(&self, cid: &'a [u8]) -> Option<&'a [u8]> {
+        let first = *cid.first()?;
+        if first >> Self::CONFIG_ID_SHIFT != self.config_id {
+            return None;
+        }
+        cid.get(1..=self.server_id.len())
+    }
+}
+
+impl ConnectionIdDecoder for QuicLbConnectionIdManager {
+    fn decode_cid<'a>(&self, dec: &mut Decoder<'a>) -> Option<ConnectionIdRef<'a>> {
+        let len = usize::from(dec.peek_byte()? & Self::LENGTH_MASK) + 1;
+        dec.decode(len).map(ConnectionIdRef::from)
+    }
+}
+
+impl ConnectionIdManager for QuicLbConnectionIdManager {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let len = self.server_id.len() + self.nonce_len;
+        // `new` ensures that this fits in `LENGTH_MASK`.
+        let mut cid = vec![(self.config_id << Self::CONFIG_ID_SHIFT) | (len as u8)];
+        cid.extend_from_slice(&self.server_id);
+        cid.extend_from_slice(&random(self.nonce_len));
+        ConnectionId { cid }
+    }
+
+    fn as_decoder(&self) -> &dyn ConnectionIdDecoder {
+        self
+    }
 }
 
 #[cfg(test)]
@@ -155,4 +232,27 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn quic_lb() {
+        fixture_init();
+        let mut mgr = QuicLbConnectionIdManager::new(1, &[7, 8, 9], 5);
+        let cid = mgr.generate_cid();
+        assert_eq!(cid.len(), 9);
+        assert_eq!(cid[0], 0x48);
+        assert_eq!(mgr.server_id(&cid), Some(&[7, 8, 9][..]));
+
+        // The length is taken from the first octet.
+        let mut packet = cid.to_vec();
+        packet.extend_from_slice(&[0xff; 4]);
+        let mut dec = Decoder::from(&packet[..]);
+        assert_eq!(mgr.decode_cid(&mut dec), Some(cid.as_cid_ref()));
+        assert_eq!(dec.remaining(), 4);
+
+        // Another configuration uses a different server ID length.
+        let other = QuicLbConnectionIdManager::new(2, &[1], 4);
+        assert_eq!(other.server_id(&cid), None);
+        let mut dec = Decoder::from(&packet[..]);
+        assert_eq!(other.decode_cid(&mut dec), Some(cid.as_cid_ref()));
+    }
 }
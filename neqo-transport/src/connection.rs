@@ -8,7 +8,7 @@
 
 use std::cell::RefCell;
 use std::cmp::{max, min, Ordering};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::fmt::{self, Debug};
 use std::mem;
@@ -16,21 +16,23 @@ use std::net::SocketAddr;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
-use smallvec::SmallVec;
-
 use neqo_common::{
-    hex, hex_snip_middle, matches, qdebug, qerror, qinfo, qlog::NeqoQlog, qtrace, qwarn, Datagram,
-    Decoder, Encoder, Role,
+    hex, hex_snip_middle, matches, qdebug, qerror, qinfo, qlog::NeqoQlog, qtrace, qwarn,
+    timer::Deadline, BufferPool, BufferPoolStats, Datagram, Decoder, Encoder, Role,
 };
 use neqo_crypto::agent::CertificateInfo;
 use neqo_crypto::{
-    Agent, AntiReplay, AuthenticationStatus, Cipher, Client, HandshakeState, SecretAgentInfo,
-    Server,
+    random, Agent, AntiReplay, AuthenticationStatus, Cipher, Client, HandshakeState, PrivateKey,
+    PublicKey, SecretAgentInfo, Server,
 };
 
-use crate::cid::{ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef};
+use crate::cc::CongestionControlAlgorithm;
+use crate::cid::{
+    ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef, MAX_CONNECTION_ID_LEN,
+};
 use crate::crypto::{Crypto, CryptoDxState};
 use crate::dump::*;
+use crate::ecn::EcnCount;
 use crate::events::{ConnectionEvent, ConnectionEvents};
 use crate::flow_mgr::FlowMgr;
 use crate::frame::{
@@ -39,16 +41,20 @@ use crate::frame::{
 };
 use crate::packet::{
     DecryptedPacket, PacketBuilder, PacketNumber, PacketType, PublicPacket, QuicVersion,
+    PACKET_NUMBER_LEN,
 };
-use crate::path::Path;
+use crate::path::{Path, PATH_MTU_MIN, PATH_MTU_V4};
 use crate::qlog;
+use crate::quic_datagrams::QuicDatagrams;
 use crate::recovery::{LossRecovery, RecoveryToken, SendProfile, GRANULARITY};
 use crate::recv_stream::{RecvStream, RecvStreams, RX_STREAM_DATA_WINDOW};
 use crate::send_stream::{SendStream, SendStreams};
+use crate::server::MIN_INITIAL_PACKET_SIZE;
 use crate::stats::Stats;
 use crate::stream_id::{StreamId, StreamIndex, StreamIndexes};
 use crate::tparams::{
-    self, TransportParameter, TransportParameterId, TransportParameters, TransportParametersHandler,
+    self, PreferredAddress, TransportParameter, TransportParameterId, TransportParameters,
+    TransportParametersHandler,
 };
 use crate::tracking::{AckTracker, PNSpace, SentPacket};
 use crate::{AppError, ConnectionError, Error, Res, LOCAL_IDLE_TIMEOUT};
@@ -60,6 +66,32 @@ pub const LOCAL_STREAM_LIMIT_BIDI: u64 = 16;
 pub const LOCAL_STREAM_LIMIT_UNI: u64 = 16;
 
 const LOCAL_MAX_DATA: u64 = 0x3FFF_FFFF_FFFF_FFFF; // 2^62-1
+/// The number of buffers to keep for decrypting packets.  Packets are
+/// processed one at a time, so this doesn't need to be large.
+const RX_BUFFER_POOL_LIMIT: usize = 2;
+/// A `max_ack_delay` of this many milliseconds or more isn't valid.
+const MAX_ACK_DELAY_LIMIT: u64 = 1 << 14;
+/// The largest `ack_delay_exponent` that is valid.
+const MAX_ACK_DELAY_EXPONENT: u64 = 20;
+/// The largest value that can be encoded as a varint.
+const MAX_VARINT: u64 = (1 << 62) - 1;
+/// The most streams of one type that can be allowed, which keeps stream IDs
+/// within the range of a varint.
+const MAX_STREAM_LIMIT: u64 = 1 << 60;
+/// The most datagrams that are saved while waiting for keys.
+const MAX_SAVED_DATAGRAMS: usize = 4;
+/// The number of packets that can fail authentication before the connection is
+/// closed.  This is the integrity limit for AEAD_CHACHA20_POLY1305, which is
+/// lower than the limit for the AES-GCM functions (-tls 6.6).
+const UNDECRYPTABLE_LIMIT: u64 = 1 << 36;
+/// The expansion from packet protection, for packets that don't have keys yet.
+/// All of the AEAD functions that QUIC uses have a 16 byte tag.
+const AEAD_EXPANSION: usize = 16;
+/// The most characters of a reason phrase from the peer that are kept.
+const MAX_PEER_REASON: usize = 256;
+/// The most connection IDs that are given to the peer at once, including the
+/// one from the handshake.
+const MAX_ISSUED_CIDS: u64 = 4;
 
 #[derive(Clone, Debug, PartialEq, Ord, Eq)]
 /// The state of the Connection.
@@ -119,6 +151,73 @@ impl PartialOrd for State {
     }
 }
 
+/// What the peer said when it closed the connection.  This is for diagnosing
+/// problems, such as when an implementation objects to something we sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerClose {
+    /// The error space and code.
+    pub error: CloseError,
+    /// The type of the frame that caused the error, if the peer said.  This
+    /// is always 0 for application errors.
+    pub frame_type: u64,
+    /// The reason phrase, with anything that isn't UTF-8 and any control
+    /// characters replaced.  This is cut to `MAX_PEER_REASON` characters, as
+    /// the peer can put anything here and it might be logged or shown.
+    pub reason: String,
+}
+
+impl PeerClose {
+    fn new(error: CloseError, frame_type: u64, reason: &[u8]) -> Self {
+        let reason = String::from_utf8_lossy(reason)
+            .chars()
+            .take(MAX_PEER_REASON)
+            .map(|c| {
+                if c.is_control() {
+                    std::char::REPLACEMENT_CHARACTER
+                } else {
+                    c
+                }
+            })
+            .collect();
+        Self {
+            error,
+            frame_type,
+            reason,
+        }
+    }
+}
+
+impl ::std::fmt::Display for PeerClose {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.error {
+            CloseError::Transport(code) => write!(f, "transport error {:#x}", code)?,
+            CloseError::Application(code) => write!(f, "application error {:#x}", code)?,
+        }
+        if self.frame_type != 0 {
+            write!(f, " in frame type {:#x}", self.frame_type)?;
+        }
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a connection handles changes to the addresses that it uses.  Moving to
+/// a preferred address that the server provides is allowed with any policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrationPolicy {
+    /// Ask the peer not to migrate, and ignore packets from any new address.
+    /// This endpoint doesn't migrate either.
+    Disabled,
+    /// Follow the peer when it moves to a new address, as it might after a
+    /// NAT rebinding, but don't migrate.
+    PassiveOnly,
+    /// Follow the peer, and allow `Connection::migrate` if the peer doesn't
+    /// ask us not to migrate.
+    Active,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ZeroRttState {
     Init,
@@ -183,16 +282,35 @@ impl Default for SendOption {
     }
 }
 
+/// The timers that contribute to the time returned from `Connection::process`.
+#[derive(Debug)]
+enum ConnectionTimer {
+    Ack,
+    Handshake,
+    Idle,
+    LossRecovery,
+    KeyUpdate,
+    Pacing,
+    StreamExpiry,
+}
+
 /// Alias the common form for ConnectionIdManager.
 type CidMgr = Rc<RefCell<dyn ConnectionIdManager>>;
 
 /// An FixedConnectionIdManager produces random connection IDs of a fixed length.
+/// The length can be zero, which only works for a client, as a server needs a
+/// connection ID to tell connections apart.
 pub struct FixedConnectionIdManager {
     len: usize,
 }
 impl FixedConnectionIdManager {
-    pub fn new(len: usize) -> Self {
-        Self { len }
+    /// # Errors
+    /// `InvalidInput` if `len` is more than `MAX_CONNECTION_ID_LEN`.
+    pub fn new(len: usize) -> Res<Self> {
+        if len > MAX_CONNECTION_ID_LEN {
+            return Err(Error::InvalidInput);
+        }
+        Ok(Self { len })
     }
 }
 impl ConnectionIdDecoder for FixedConnectionIdManager {
@@ -209,6 +327,33 @@ impl ConnectionIdManager for FixedConnectionIdManager {
     }
 }
 
+/// A client moves to the server's preferred address once the handshake is
+/// confirmed.  It sends a PATH_CHALLENGE on the new path and only uses that path
+/// once the server answers.
+#[derive(Debug)]
+enum PreferredAddressMigration {
+    /// Waiting for the handshake to be confirmed.
+    Pending(Path),
+    /// The challenge was sent.  The new path is abandoned at `timeout`.
+    Probing {
+        path: Path,
+        data: [u8; 8],
+        timeout: Instant,
+    },
+}
+
+/// A new path, after one of the peers migrated.  The new path is used right
+/// away, but it is abandoned if the peer doesn't answer a PATH_CHALLENGE on it.
+#[derive(Debug)]
+struct PathValidation {
+    /// The path to go back to.
+    previous: Path,
+    /// The sequence number of the peer's connection ID on the previous path.
+    previous_cid_seqno: u64,
+    /// The data in the challenge, once it is sent, and when to give up.
+    challenge: Option<([u8; 8], Instant)>,
+}
+
 struct RetryInfo {
     token: Vec<u8>,
     retry_source_cid: ConnectionId,
@@ -424,6 +569,12 @@ pub struct Connection {
     /// During the handshake at the server, it also includes the randomized DCID pick by the client.
     valid_cids: Vec<ConnectionId>,
     retry_info: Option<RetryInfo>,
+    /// At a server, the preferred address that it advertises.
+    local_preferred_address: Option<PreferredAddress>,
+    /// At a client, the move to the server's preferred address.
+    preferred_address_migration: Option<PreferredAddressMigration>,
+    /// The validation of a new path after a migration.
+    path_validation: Option<PathValidation>,
 
     /// Since we need to communicate this to our peer in tparams, setting this
     /// value is part of constructing the struct.
@@ -433,31 +584,63 @@ pub struct Connection {
     /// Checked against tparam value from peer
     remote_original_destination_cid: Option<ConnectionId>,
 
-    /// We sometimes save a datagram (just one) against the possibility that keys
-    /// will later become available.
+    /// We sometimes save datagrams (up to `MAX_SAVED_DATAGRAMS`) against the
+    /// possibility that keys will later become available.
     /// One example of this is the case where a Handshake packet containing a
     /// certificate is received coalesced with a 1-RTT packet.  The certificate
     /// might need to authenticated by the application before the 1-RTT packet can
-    /// be processed.
+    /// be processed.  Another is where packets arrive out of order, so that a
+    /// Handshake packet arrives before the Initial that carries the keys for it.
     /// The boolean indicates whether processing should be deferred: after saving,
     /// we usually immediately try to process this, but that won't work until after
     /// returning to the application.
-    saved_datagram: Option<(Datagram, bool)>,
+    saved_datagrams: VecDeque<(Datagram, bool)>,
+    /// The connection is closed once this many packets fail authentication.
+    undecryptable_limit: u64,
 
     pub(crate) crypto: Crypto,
     pub(crate) acks: AckTracker,
     idle_timeout: IdleTimeout,
+    /// Whether the application asked for a PING to be sent.
+    ping_pending: bool,
+    /// What the peer sent in its CONNECTION_CLOSE, if it sent one.
+    peer_close: Option<PeerClose>,
+    migration_policy: MigrationPolicy,
+    /// Whether packets are sent with ECN marks.
+    ecn: bool,
+    /// How large the receive window of a stream can grow.
+    max_stream_window: u64,
+    /// How long the handshake can take, if it is limited.
+    handshake_timeout: Option<Duration>,
+    /// When the handshake has to be done by, which is set when it starts.
+    handshake_deadline: Option<Instant>,
     pub(crate) indexes: StreamIndexes,
     connection_ids: HashMap<u64, (Vec<u8>, [u8; 16])>, // (sequence number, (connection id, reset token))
+    /// The sequence number of the peer's connection ID that is in use.
+    remote_cid_seqno: u64,
+    /// The largest Retire Prior To that the peer sent.
+    retire_prior_to: u64,
+    /// The connection IDs that we gave to the peer, by sequence number.
+    issued_cids: HashMap<u64, ConnectionId>,
+    /// The sequence number of the next connection ID that we give to the peer.
+    next_cid_seqno: u64,
     pub(crate) send_streams: SendStreams,
     pub(crate) recv_streams: RecvStreams,
     pub(crate) flow_mgr: Rc<RefCell<FlowMgr>>,
     state_signaling: StateSignaling,
     loss_recovery: LossRecovery,
     events: ConnectionEvents,
+    /// At a client, the token from the last NEW_TOKEN frame.
     token: Option<Vec<u8>>,
+    /// At a client, a token from an earlier connection, which goes in Initial packets.
+    initial_token: Vec<u8>,
+    /// At a server, a token to send in a NEW_TOKEN frame once the handshake is confirmed.
+    pending_new_token: Option<Vec<u8>>,
     stats: Stats,
     qlog: Option<NeqoQlog>,
+    /// Buffers for holding decrypted packets.
+    rx_buffers: BufferPool,
+    quic_datagrams: QuicDatagrams,
 
     quic_version: QuicVersion,
 }
@@ -554,7 +737,13 @@ impl Connection {
             local_initial_source_cid.to_vec(),
         );
 
-        let crypto = Crypto::new(agent, protocols, tphandler.clone(), anti_replay)?;
+        let crypto = Crypto::new(
+            agent,
+            quic_version,
+            protocols,
+            tphandler.clone(),
+            anti_replay,
+        )?;
 
         let mut c = Self {
             role,
@@ -565,15 +754,30 @@ impl Connection {
             tps: tphandler,
             zero_rtt_state: ZeroRttState::Init,
             retry_info: None,
+            local_preferred_address: None,
+            preferred_address_migration: None,
+            path_validation: None,
             local_initial_source_cid,
             remote_initial_source_cid: None,
             remote_original_destination_cid: None,
-            saved_datagram: None,
+            saved_datagrams: VecDeque::new(),
+            undecryptable_limit: UNDECRYPTABLE_LIMIT,
             crypto,
             acks: AckTracker::default(),
             idle_timeout: IdleTimeout::default(),
+            ping_pending: false,
+            peer_close: None,
+            migration_policy: MigrationPolicy::Disabled,
+            ecn: false,
+            max_stream_window: 0,
+            handshake_timeout: None,
+            handshake_deadline: None,
             indexes: StreamIndexes::new(),
             connection_ids: HashMap::new(),
+            remote_cid_seqno: 0,
+            retire_prior_to: 0,
+            issued_cids: HashMap::new(),
+            next_cid_seqno: 0,
             send_streams: SendStreams::default(),
             recv_streams: RecvStreams::default(),
             flow_mgr: Rc::new(RefCell::new(FlowMgr::default())),
@@ -581,8 +785,12 @@ impl Connection {
             loss_recovery: LossRecovery::new(),
             events: ConnectionEvents::default(),
             token: None,
+            initial_token: Vec::new(),
+            pending_new_token: None,
             stats: Stats::default(),
             qlog: None,
+            rx_buffers: BufferPool::new(PATH_MTU_V4, RX_BUFFER_POOL_LIMIT),
+            quic_datagrams: QuicDatagrams::default(),
             quic_version,
         };
         c.stats.init(format!("{}", c));
@@ -616,6 +824,180 @@ impl Connection {
         }
     }
 
+    /// Select the congestion control algorithm.  This has to be done before
+    /// the connection starts.
+    pub fn set_congestion_control(&mut self, algorithm: CongestionControlAlgorithm) -> Res<()> {
+        if *self.state() == State::Init {
+            self.loss_recovery.set_congestion_control(algorithm);
+            Ok(())
+        } else {
+            qerror!([self], "Cannot change congestion control after starting");
+            Err(Error::ConnectionState)
+        }
+    }
+
+    /// Send packets marked as ECN-capable, and treat ECN-CE marks that the peer
+    /// reports as congestion.  Marking stops if ECN doesn't work on the path.
+    /// This has to be done before the connection starts.
+    pub fn enable_ecn(&mut self) -> Res<()> {
+        if *self.state() == State::Init {
+            self.ecn = true;
+            Ok(())
+        } else {
+            qerror!([self], "Cannot enable ECN after starting");
+            Err(Error::ConnectionState)
+        }
+    }
+
+    /// Set the longest time that this endpoint waits before acknowledging
+    /// packets, which is sent to the peer as `max_ack_delay`.  A shorter delay
+    /// helps the peer detect loss sooner, at the cost of sending more ACKs.
+    /// This has to be done before the connection starts.
+    pub fn set_max_ack_delay(&mut self, delay: Duration) -> Res<()> {
+        // The transport parameter is in milliseconds, so round up.
+        let ms = u64::try_from((delay.as_micros() + 999) / 1000).unwrap_or(u64::MAX);
+        if ms >= MAX_ACK_DELAY_LIMIT {
+            return Err(Error::InvalidInput);
+        }
+        if *self.state() != State::Init {
+            qerror!([self], "Cannot change the ACK delay after starting");
+            return Err(Error::ConnectionState);
+        }
+        self.tps
+            .borrow_mut()
+            .local
+            .set_integer(tparams::MAX_ACK_DELAY, ms);
+        self.acks.set_ack_delay(delay);
+        Ok(())
+    }
+
+    /// Set the exponent that this endpoint uses to encode the delay in ACK frames,
+    /// which is sent to the peer as `ack_delay_exponent`.  Each unit of delay is
+    /// 2 to the power of this many microseconds.  The default is 3.
+    /// This has to be done before the connection starts.
+    pub fn set_ack_delay_exponent(&mut self, exponent: u64) -> Res<()> {
+        if exponent > MAX_ACK_DELAY_EXPONENT {
+            return Err(Error::InvalidInput);
+        }
+        if *self.state() != State::Init {
+            qerror!(
+                [self],
+                "Cannot change the ACK delay exponent after starting"
+            );
+            return Err(Error::ConnectionState);
+        }
+        self.tps
+            .borrow_mut()
+            .local
+            .set_integer(tparams::ACK_DELAY_EXPONENT, exponent);
+        self.acks.set_ack_delay_exponent(exponent);
+        Ok(())
+    }
+
+    /// Acknowledge packets without any delay once `packets` ACK-eliciting
+    /// packets have arrived since the last ACK.  The default is 2, which
+    /// acknowledges every second packet straight away; 1 doesn't delay ACKs.
+    pub fn set_ack_every(&mut self, packets: u64) -> Res<()> {
+        if packets == 0 {
+            return Err(Error::InvalidInput);
+        }
+        self.acks.set_ack_every(packets);
+        Ok(())
+    }
+
+    /// Set how many streams of `stream_type` the peer can open at once.
+    /// Unidirectional and bidirectional streams have separate limits.
+    /// This has to be done before the connection starts.
+    pub fn set_stream_limit(&mut self, stream_type: StreamType, limit: u64) -> Res<()> {
+        if limit > MAX_STREAM_LIMIT {
+            return Err(Error::InvalidInput);
+        }
+        if *self.state() != State::Init {
+            qerror!([self], "Cannot change stream limits after starting");
+            return Err(Error::ConnectionState);
+        }
+        let (tp, local_max) = match stream_type {
+            StreamType::BiDi => (
+                tparams::INITIAL_MAX_STREAMS_BIDI,
+                &mut self.indexes.local_max_stream_bidi,
+            ),
+            StreamType::UniDi => (
+                tparams::INITIAL_MAX_STREAMS_UNI,
+                &mut self.indexes.local_max_stream_uni,
+            ),
+        };
+        *local_max = StreamIndex::new(limit);
+        self.tps.borrow_mut().local.set_integer(tp, limit);
+        Ok(())
+    }
+
+    /// Set how much the peer can send on each stream of `stream_type` before
+    /// it needs more credit.  For bidirectional streams, this applies to the
+    /// streams that either endpoint opens.
+    /// This has to be done before the connection starts.
+    pub fn set_stream_window(&mut self, stream_type: StreamType, window: u64) -> Res<()> {
+        if window > MAX_VARINT {
+            return Err(Error::InvalidInput);
+        }
+        if *self.state() != State::Init {
+            qerror!([self], "Cannot change stream flow control after starting");
+            return Err(Error::ConnectionState);
+        }
+        let mut tps = self.tps.borrow_mut();
+        match stream_type {
+            StreamType::BiDi => {
+                tps.local
+                    .set_integer(tparams::INITIAL_MAX_STREAM_DATA_BIDI_LOCAL, window);
+                tps.local
+                    .set_integer(tparams::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE, window);
+            }
+            StreamType::UniDi => tps
+                .local
+                .set_integer(tparams::INITIAL_MAX_STREAM_DATA_UNI, window),
+        }
+        Ok(())
+    }
+
+    /// Let the receive window of each stream grow up to `max_window`.  The window
+    /// starts at the size set with `set_stream_window` and doubles each time
+    /// the application reads a window of data within two round trips, which
+    /// shows that the window is what limits the peer.
+    /// This has to be done before the connection starts.
+    pub fn set_max_stream_window(&mut self, max_window: u64) -> Res<()> {
+        if max_window > MAX_VARINT {
+            return Err(Error::InvalidInput);
+        }
+        if *self.state() != State::Init {
+            qerror!([self], "Cannot change stream flow control after starting");
+            return Err(Error::ConnectionState);
+        }
+        self.max_stream_window = max_window;
+        Ok(())
+    }
+
+    /// Close the connection if the handshake isn't complete within `timeout` of
+    /// starting.  Unlike the idle timeout, this applies even if packets keep
+    /// arriving.  By default, the handshake is only limited by the idle timeout.
+    /// This has to be done before the connection starts.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) -> Res<()> {
+        if *self.state() == State::Init {
+            self.handshake_timeout = Some(timeout);
+            Ok(())
+        } else {
+            qerror!([self], "Cannot set the handshake timeout after starting");
+            Err(Error::ConnectionState)
+        }
+    }
+
+    /// The time that the handshake has to be done by, if it isn't done already.
+    fn handshake_deadline(&self) -> Option<Instant> {
+        if matches!(self.state, State::Init | State::WaitInitial | State::Handshaking) {
+            self.handshake_deadline
+        } else {
+            None
+        }
+    }
+
     /// `odcid` is their original choice for our CID, which we get from the Retry token.
     /// `remote_cid` is the value from the Source Connection ID field of
     ///   an incoming packet: what the peer wants us to use now.
@@ -658,6 +1040,240 @@ impl Connection {
         Ok(())
     }
 
+    /// Disable 0-RTT.  A client will not send early data and a server will
+    /// reject it.  This has to be done before the connection starts.
+    pub fn disable_0rtt(&mut self) -> Res<()> {
+        if self.state != State::Init {
+            qerror!([self], "Cannot disable 0-RTT in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        self.crypto.tls.disable_0rtt()?;
+        Ok(())
+    }
+
+    /// Advertise a preferred address.  Clients move to this address once the
+    /// handshake is confirmed.  This has to be done before the connection starts.
+    pub fn set_preferred_address(&mut self, preferred_address: PreferredAddress) -> Res<()> {
+        if self.role != Role::Server {
+            return Err(Error::ConnectionState);
+        }
+        self.set_local_tparam(
+            tparams::PREFERRED_ADDRESS,
+            TransportParameter::Bytes(preferred_address.encode()),
+        )?;
+        self.local_preferred_address = Some(preferred_address);
+        Ok(())
+    }
+
+    /// Choose how address changes are handled.  The default is
+    /// `MigrationPolicy::Disabled`.  This has to be done before the connection starts.
+    pub fn set_migration_policy(&mut self, policy: MigrationPolicy) -> Res<()> {
+        if *self.state() != State::Init {
+            qerror!([self], "Cannot change the migration policy after starting");
+            return Err(Error::ConnectionState);
+        }
+        let mut tps = self.tps.borrow_mut();
+        if policy == MigrationPolicy::Disabled {
+            tps.local.set_empty(tparams::DISABLE_MIGRATION);
+        } else {
+            tps.local.remove(tparams::DISABLE_MIGRATION);
+        }
+        drop(tps);
+        self.migration_policy = policy;
+        Ok(())
+    }
+
+    /// Start sending from `local`, after moving to a new socket or network.
+    /// The new path uses a connection ID from the peer that wasn't used before.
+    /// A PATH_CHALLENGE checks that the new path works, and the connection goes
+    /// back to the old path if there is no answer.  This also sends a PING.
+    /// This needs `MigrationPolicy::Active`, a confirmed handshake, a peer that
+    /// didn't send `disable_migration`, and a spare connection ID from the peer.
+    pub fn migrate(&mut self, local: SocketAddr) -> Res<()> {
+        if self.migration_policy != MigrationPolicy::Active {
+            return Err(Error::InvalidMigration);
+        }
+        if self.state != State::Confirmed {
+            return Err(Error::ConnectionState);
+        }
+        if self
+            .tps
+            .borrow()
+            .remote()
+            .get_empty(tparams::DISABLE_MIGRATION)
+            .is_some()
+        {
+            qwarn!([self], "Peer does not allow migration");
+            return Err(Error::InvalidMigration);
+        }
+        let (seqno, remote_cid, reset_token) = self.take_remote_cid().ok_or_else(|| {
+            qwarn!([self], "No connection ID from the peer for a new path");
+            Error::InvalidMigration
+        })?;
+        let current = self.path.as_ref().ok_or(Error::NotConnected)?;
+        qinfo!(
+            [self],
+            "Migrating from {} to {}",
+            current.local_address(),
+            local
+        );
+        let mut path = current.migrate(local, *current.remote_address(), remote_cid);
+        if let Some(token) = reset_token {
+            path.set_reset_token(token);
+        }
+        // The peer's address doesn't change, so it is still valid.
+        if current.is_valid() {
+            path.set_valid();
+        }
+        self.start_path_validation(path, seqno);
+        self.ping_pending = true;
+        Ok(())
+    }
+
+    /// Take a connection ID that the peer provided and that wasn't used yet,
+    /// for a new path.  A peer that uses a zero-length connection ID has no
+    /// others, so that is used on every path.
+    fn take_remote_cid(&mut self) -> Option<(u64, ConnectionId, Option<[u8; 16]>)> {
+        let current = self.path.as_ref()?;
+        if current.remote_cid().is_empty() {
+            return Some((
+                self.remote_cid_seqno,
+                current.remote_cid().clone(),
+                current.reset_token().copied(),
+            ));
+        }
+        let seqno = *self.connection_ids.keys().min()?;
+        let (cid, token) = self.connection_ids.remove(&seqno).unwrap();
+        Some((seqno, ConnectionId::from(&cid[..]), Some(token)))
+    }
+
+    /// Move to a new path, keeping the current one in case the new one can't
+    /// be validated.  If the current path is still being validated itself,
+    /// the one before it is kept instead.
+    fn start_path_validation(&mut self, mut path: Path, seqno: u64) {
+        if self.ecn {
+            path.enable_ecn();
+        }
+        let current = self.path.replace(path).unwrap();
+        let current_seqno = mem::replace(&mut self.remote_cid_seqno, seqno);
+        let (previous, previous_cid_seqno, dropped) = match self.path_validation.take() {
+            Some(v) if !current.is_valid() => {
+                (v.previous, v.previous_cid_seqno, Some(current_seqno))
+            }
+            Some(v) => (current, current_seqno, Some(v.previous_cid_seqno)),
+            None => (current, current_seqno, None),
+        };
+        // The connection ID from a path that is dropped isn't used again.
+        if let Some(dropped) = dropped.filter(|&d| d != previous_cid_seqno && d != seqno) {
+            self.flow_mgr.borrow_mut().retire_connection_id(dropped);
+        }
+        self.path_validation = Some(PathValidation {
+            previous,
+            previous_cid_seqno,
+            challenge: None,
+        });
+    }
+
+    /// The peer answered on the new path, so the connection ID from the
+    /// previous path is no longer needed.
+    fn path_validated(&mut self) {
+        let v = self.path_validation.take().unwrap();
+        let path = self.path.as_mut().unwrap();
+        path.set_valid();
+        let remote = *path.remote_address();
+        qinfo!([self], "Validated the path to {}", remote);
+        if v.previous_cid_seqno != self.remote_cid_seqno {
+            self.flow_mgr
+                .borrow_mut()
+                .retire_connection_id(v.previous_cid_seqno);
+        }
+    }
+
+    /// Go back to the path from before a migration.  The connection ID from
+    /// the new path isn't used again.
+    fn abandon_path_validation(&mut self) {
+        if let Some(v) = self.path_validation.take() {
+            if v.previous_cid_seqno != self.remote_cid_seqno {
+                self.flow_mgr
+                    .borrow_mut()
+                    .retire_connection_id(self.remote_cid_seqno);
+            }
+            self.remote_cid_seqno = v.previous_cid_seqno;
+            self.path = Some(v.previous);
+        }
+    }
+
+    /// Retire the peer's connection IDs that are below `retire_prior_to`.
+    /// If the one in use is among them, the next one is used instead.
+    fn retire_remote_cids(&mut self) {
+        let retire_prior = self.retire_prior_to;
+        let mut flow_mgr = self.flow_mgr.borrow_mut();
+        self.connection_ids.retain(|&seqno, _| {
+            if seqno < retire_prior {
+                flow_mgr.retire_connection_id(seqno);
+                false
+            } else {
+                true
+            }
+        });
+        if self.remote_cid_seqno >= retire_prior {
+            return;
+        }
+        let seqno = match self.connection_ids.keys().min() {
+            Some(&seqno) => seqno,
+            None => return,
+        };
+        let (cid, token) = self.connection_ids.remove(&seqno).unwrap();
+        if let Some(path) = self.path.as_mut() {
+            path.set_remote_cid(&ConnectionIdRef::from(&cid[..]));
+            path.set_reset_token(token);
+        }
+        flow_mgr.retire_connection_id(self.remote_cid_seqno);
+        qinfo!([self], "Retired the CID in use, now using {}", seqno);
+        self.remote_cid_seqno = seqno;
+    }
+
+    /// Give the peer spare connection IDs, so that it can use a new one for
+    /// each new path.  This isn't done if migration is disabled, or if the
+    /// connection ID from the handshake is zero-length.
+    fn issue_connection_ids(&mut self) {
+        if self.migration_policy == MigrationPolicy::Disabled
+            || self
+                .path
+                .as_ref()
+                .map_or(true, |p| p.local_cid().is_empty())
+        {
+            return;
+        }
+        if self.next_cid_seqno == 0 {
+            // The peer already has the connection ID from the handshake,
+            // and the one for the preferred address.
+            let path = self.path.as_ref().unwrap();
+            self.issued_cids.insert(0, path.local_cid().clone());
+            if let Some(pa) = &self.local_preferred_address {
+                self.issued_cids.insert(1, pa.cid().clone());
+            }
+            self.next_cid_seqno = self.issued_cids.len() as u64;
+        }
+        let limit = min(
+            self.tps
+                .borrow()
+                .remote()
+                .get_integer(tparams::ACTIVE_CONNECTION_ID_LIMIT),
+            MAX_ISSUED_CIDS,
+        );
+        while (self.issued_cids.len() as u64) < limit {
+            let cid = self.cid_manager.borrow_mut().generate_cid();
+            let token = self.cid_manager.borrow().reset_token(&cid);
+            self.flow_mgr
+                .borrow_mut()
+                .new_connection_id(self.next_cid_seqno, cid.to_vec(), token);
+            self.valid_cids.push(cid.clone());
+            self.issued_cids.insert(self.next_cid_seqno, cid);
+            self.next_cid_seqno += 1;
+        }
+    }
+
     /// Enable a set of ciphers.
     pub fn set_ciphers(&mut self, ciphers: &[Cipher]) -> Res<()> {
         if self.state != State::Init {
@@ -668,6 +1284,81 @@ impl Connection {
         Ok(())
     }
 
+    /// Enable encrypted client hello (ECH).
+    pub fn client_enable_ech(&mut self, ech_config_list: impl AsRef<[u8]>) -> Res<()> {
+        if self.state != State::Init {
+            qerror!([self], "Cannot enable ECH in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        match self.crypto.tls {
+            Agent::Client(ref mut c) => c.enable_ech(ech_config_list)?,
+            Agent::Server(_) => return Err(Error::WrongRole),
+        }
+        Ok(())
+    }
+
+    /// Set the certificate that a client uses if the server asks for one.
+    pub fn client_set_certificate(&mut self, nickname: &str) -> Res<()> {
+        if self.state != State::Init {
+            qerror!([self], "Cannot set certificate in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        match self.crypto.tls {
+            Agent::Client(ref mut c) => c.set_client_certificate(nickname)?,
+            Agent::Server(_) => return Err(Error::WrongRole),
+        }
+        Ok(())
+    }
+
+    /// Enable encrypted client hello (ECH) on the server, with a configuration
+    /// that has the identifier `config` and names `public_name`.  The keys come
+    /// from `neqo_crypto::ech::generate_keys`.  Clients need the configuration
+    /// from `ech_config()`.
+    pub fn server_enable_ech(
+        &mut self,
+        config: u8,
+        public_name: &str,
+        sk: &PrivateKey,
+        pk: &PublicKey,
+    ) -> Res<()> {
+        if self.state != State::Init {
+            qerror!([self], "Cannot enable ECH in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => s.enable_ech(config, public_name, sk, pk)?,
+            Agent::Client(_) => return Err(Error::WrongRole),
+        }
+        Ok(())
+    }
+
+    /// The ECH configuration that clients of this server use, which is empty
+    /// for a client or a server that doesn't have ECH enabled.
+    pub fn ech_config(&self) -> &[u8] {
+        match self.crypto.tls {
+            Agent::Server(ref s) => s.ech_config(),
+            Agent::Client(_) => &[],
+        }
+    }
+
+    /// Ask the client for a certificate.  The client certificate is available from
+    /// `peer_certificate()` when the `AuthenticationNeeded` event is received.
+    pub fn server_request_certificate(&mut self, required: bool) -> Res<()> {
+        if self.state != State::Init {
+            qerror!(
+                [self],
+                "Cannot request certificate in state {:?}",
+                self.state
+            );
+            return Err(Error::ConnectionState);
+        }
+        match self.crypto.tls {
+            Agent::Server(ref mut s) => s.request_client_certificate(required)?,
+            Agent::Client(_) => return Err(Error::WrongRole),
+        }
+        Ok(())
+    }
+
     /// Access the latest resumption token on the connection.
     pub fn resumption_token(&self) -> Option<Vec<u8>> {
         if self.state < State::Connected {
@@ -716,12 +1407,11 @@ impl Connection {
             _ => return Err(Error::InvalidResumptionToken),
         };
 
-        let tp_slice = match dec.decode_vvec() {
+        let mut dec_tp = match dec.decode_vvec_decoder() {
             Some(v) => v,
             _ => return Err(Error::InvalidResumptionToken),
         };
-        qtrace!([self], "  transport parameters {}", hex(&tp_slice));
-        let mut dec_tp = Decoder::from(tp_slice);
+        qtrace!([self], "  transport parameters {}", hex(&dec_tp[..]));
         let tp = TransportParameters::decode(&mut dec_tp)?;
 
         let tok = dec.decode_remainder();
@@ -742,6 +1432,45 @@ impl Connection {
         self.client_start(now)
     }
 
+    /// The token from the last NEW_TOKEN frame that the server sent.  A new
+    /// connection to the same server can use it with `set_initial_token`, so
+    /// that the server doesn't have to validate the client's address again.
+    pub fn new_token(&self) -> Option<&[u8]> {
+        self.token.as_deref()
+    }
+
+    /// Put a token from an earlier connection, taken from `new_token`, in
+    /// Initial packets.  This can only be called on the client, before it
+    /// starts.
+    pub fn set_initial_token(&mut self, token: Vec<u8>) -> Res<()> {
+        if self.role == Role::Server {
+            return Err(Error::WrongRole);
+        }
+        if self.state != State::Init {
+            qerror!([self], "Cannot set initial token in state {:?}", self.state);
+            return Err(Error::ConnectionState);
+        }
+        self.initial_token = token;
+        Ok(())
+    }
+
+    /// Give the client a token in a NEW_TOKEN frame, which it can use on the
+    /// next connection.  This is sent once the handshake is confirmed.
+    pub fn send_new_token(&mut self, token: Vec<u8>) -> Res<()> {
+        if self.role == Role::Client {
+            return Err(Error::WrongRole);
+        }
+        if self.state.closed() {
+            return Err(Error::ConnectionState);
+        }
+        if self.state == State::Confirmed {
+            self.flow_mgr.borrow_mut().new_token(token);
+        } else {
+            self.pending_new_token = Some(token);
+        }
+        Ok(())
+    }
+
     /// Send a TLS session ticket.
     pub fn send_ticket(&mut self, now: Instant, extra: &[u8]) -> Res<()> {
         let tps = &self.tps;
@@ -790,6 +1519,11 @@ impl Connection {
         &self.state
     }
 
+    /// Get the QUIC version that the connection uses.
+    pub fn quic_version(&self) -> QuicVersion {
+        self.quic_version
+    }
+
     /// Get the 0-RTT state of the connection.
     pub fn zero_rtt_state(&self) -> &ZeroRttState {
         &self.zero_rtt_state
@@ -800,13 +1534,24 @@ impl Connection {
         &self.stats
     }
 
+    /// Get statistics for the buffers used to decrypt packets.
+    pub fn rx_buffer_stats(&self) -> &BufferPoolStats {
+        self.rx_buffers.stats()
+    }
+
     // This function wraps a call to another function and sets the connection state
     // properly if that call fails.
     fn capture_error<T>(&mut self, now: Instant, frame_type: FrameType, res: Res<T>) -> Res<T> {
         if let Err(v) = &res {
+            // Say what went wrong so that the peer can work out why, but only
+            // in debug builds.  The frame type is sent regardless.
             #[cfg(debug_assertions)]
-            let msg = format!("{:?}", v);
-            #[cfg(not(debug_assertions))]
+            let msg = if frame_type == 0 {
+                format!("{:?}", v)
+            } else {
+                format!("{:?} in frame type {:#x}", v, frame_type)
+            };
+            #[cfg(not(debug_assertions))]
             let msg = "";
             let error = ConnectionError::Transport(v.clone());
             match &self.state {
@@ -829,6 +1574,10 @@ impl Connection {
                     });
                 }
             }
+            // This goes after the state change, which clears other events.
+            if let Error::EchRetry(retry_configs) = v {
+                self.events.ech_rejected(retry_configs.clone());
+            }
         }
         res
     }
@@ -863,13 +1612,63 @@ impl Connection {
             return;
         }
 
+        if self.handshake_deadline().map_or(false, |t| t <= now) {
+            qinfo!([self], "handshake timeout expired");
+            self.set_state(State::Closed(ConnectionError::Transport(
+                Error::HandshakeTimeout,
+            )));
+            return;
+        }
+
         self.process_saved(now, false);
+        self.send_streams.expire(now);
 
         let res = self.crypto.states.check_key_update(now);
         self.absorb_error(now, res);
 
+        self.update_pto_conditions();
         let lost = self.loss_recovery.timeout(now);
         self.handle_lost_packets(&lost);
+        if !lost.is_empty() {
+            let res = self.qlog_recovery_update(&lost);
+            self.absorb_error(now, res);
+        }
+    }
+
+    /// Tell loss recovery about the address validation state, which determines
+    /// whether the PTO timer can be armed during the handshake.
+    fn update_pto_conditions(&mut self) {
+        let blocked = self
+            .path
+            .as_ref()
+            .map_or(false, Path::amplification_blocked);
+        self.loss_recovery.set_amplification_blocked(blocked);
+        // A client keeps probing until it knows that the server validated its
+        // address, either from an acknowledgment of a Handshake packet or from
+        // the handshake being confirmed.
+        let probes = self.role == Role::Client
+            && matches!(
+                self.state,
+                State::WaitInitial | State::Handshaking | State::Connected
+            )
+            && self
+                .loss_recovery
+                .largest_acknowledged_pn(PNSpace::Handshake)
+                .is_none();
+        self.loss_recovery.set_handshake_probes(probes);
+    }
+
+    /// Log lost packets and the state of loss recovery and congestion control.
+    fn qlog_recovery_update(&mut self, lost: &[SentPacket]) -> Res<()> {
+        if self.qlog.is_none() {
+            return Ok(());
+        }
+        qlog::packets_lost(&mut self.qlog, lost)?;
+        qlog::metrics_updated(&mut self.qlog, &self.loss_recovery.metrics())?;
+        if let Some((old, new)) = self.loss_recovery.congestion_state_change() {
+            qlog::congestion_state_updated(&mut self.qlog, old, new)?;
+        }
+        Ok(())
     }
 
     /// Call in to process activity on the connection. Either new packets have
@@ -880,7 +1679,23 @@ impl Connection {
         self.cleanup_streams();
     }
 
-    /// Just like above but returns frames parsed from the datagram
+    /// Process a burst of datagrams, such as those that the socket delivers
+    /// together with GRO or `recvmmsg`.  Nothing is sent until they have all
+    /// been processed, so a call to `process_output` after this can send one
+    /// ACK for all of them, rather than one for each.
+    pub fn process_multiple_input(
+        &mut self,
+        dgrams: impl IntoIterator<Item = Datagram>,
+        now: Instant,
+    ) {
+        for d in dgrams {
+            let res = self.input(d, now);
+            self.absorb_error(now, res);
+        }
+        self.cleanup_streams();
+    }
+
+    /// Just like `process_input` but returns frames parsed from the datagram
     #[cfg(test)]
     pub fn test_process_input(&mut self, dgram: Datagram, now: Instant) -> Vec<(Frame, PNSpace)> {
         let res = self.input(dgram, now);
@@ -898,37 +1713,39 @@ impl Connection {
             return timeout.duration_since(now);
         }
 
-        let mut delays = SmallVec::<[_; 5]>::new();
-        if let Some(ack_time) = self.acks.ack_time(now) {
-            qtrace!([self], "Delayed ACK timer {:?}", ack_time);
-            delays.push(ack_time);
-        }
-
-        if let Some(idle_time) = self.idle_timeout.expiry(self.loss_recovery.raw_pto()) {
-            qtrace!([self], "Idle timer {:?}", idle_time);
-            delays.push(idle_time);
-        }
-
-        if let Some(lr_time) = self.loss_recovery.next_timeout() {
-            qtrace!([self], "Loss recovery timer {:?}", lr_time);
-            delays.push(lr_time);
-        }
+        let mut deadline = Deadline::new();
+        deadline.add(ConnectionTimer::Ack, self.acks.ack_time(now));
+        deadline.add(ConnectionTimer::Handshake, self.handshake_deadline());
+        deadline.add(
+            ConnectionTimer::Idle,
+            self.idle_timeout.expiry(self.loss_recovery.raw_pto()),
+        );
 
-        if let Some(key_update_time) = self.crypto.states.update_time() {
-            qtrace!([self], "Key update timer {:?}", key_update_time);
-            delays.push(key_update_time);
+        self.update_pto_conditions();
+        let lr_time = self.loss_recovery.next_timeout();
+        deadline.add(ConnectionTimer::LossRecovery, lr_time);
+        if self.qlog.is_some() && self.loss_recovery.timeout_changed(lr_time) {
+            let res = qlog::loss_timer_updated(&mut self.qlog, lr_time, now);
+            self.absorb_error(now, res);
         }
 
+        deadline.add(ConnectionTimer::KeyUpdate, self.crypto.states.update_time());
+        deadline.add(
+            ConnectionTimer::StreamExpiry,
+            self.send_streams.next_expiry(),
+        );
         if paced {
-            if let Some(pace_time) = self.loss_recovery.next_paced() {
-                qtrace!([self], "Pacing timer {:?}", pace_time);
-                delays.push(pace_time);
-            }
+            deadline.add(ConnectionTimer::Pacing, self.loss_recovery.next_paced());
         }
 
         // Should always at least have idle timeout, once connected
-        assert!(!delays.is_empty());
-        let earliest = delays.into_iter().min().unwrap();
+        let earliest = deadline.time().expect("should have an idle timeout");
+        qtrace!(
+            [self],
+            "{:?} timer {:?}",
+            deadline.kind().unwrap(),
+            earliest
+        );
 
         // TODO(agrover, mt) - need to analyze and fix #47
         // rather than just clamping to zero here.
@@ -979,7 +1796,12 @@ impl Connection {
     }
 
     fn is_valid_cid(&self, cid: &ConnectionIdRef) -> bool {
-        self.valid_cids.iter().any(|c| c == cid) || self.path.iter().any(|p| p.valid_local_cid(cid))
+        self.valid_cids.iter().any(|c| c == cid)
+            || self.path.iter().any(|p| p.valid_local_cid(cid))
+            || self
+                .local_preferred_address
+                .as_ref()
+                .map_or(false, |pa| pa.cid() == cid)
     }
 
     fn handle_retry(&mut self, packet: PublicPacket) -> Res<()> {
@@ -1079,40 +1901,62 @@ impl Connection {
         }
     }
 
-    /// Process any saved packet.
-    /// When `always` is false, the packet is only processed if time has
-    /// passed since it was saved.  Otherwise saved packets are saved and then
+    /// Process any saved packets.
+    /// When `always` is false, packets are only processed if time has
+    /// passed since they were saved.  Otherwise saved packets are saved and then
     /// dropped instantly.
     fn process_saved(&mut self, now: Instant, always: bool) {
-        if let Some((_, ref mut defer)) = self.saved_datagram {
-            if always || !*defer {
-                let d = self.saved_datagram.take().unwrap().0;
-                qdebug!([self], "process saved datagram: {:?}", d);
-                self.process_input(d, now);
+        let mut ready = Vec::new();
+        for (d, defer) in mem::take(&mut self.saved_datagrams) {
+            if always || !defer {
+                ready.push(d);
             } else {
-                *defer = false;
+                self.saved_datagrams.push_back((d, false));
+            }
+        }
+        for d in ready {
+            qdebug!([self], "process saved datagram: {:?}", d);
+            self.process_input(d, now);
+        }
+    }
+
+    /// Whether keys for packets of type `pt` might still arrive.  Keys are
+    /// pending until they are installed, unless they have already been discarded.
+    /// 0-RTT keys are never pending, as they come with the first Initial.
+    fn keys_pending(&mut self, pt: PacketType) -> bool {
+        match pt {
+            PacketType::Short => !self.state.connected(),
+            PacketType::Initial | PacketType::Handshake => {
+                let space = PNSpace::from(pt);
+                self.acks.get_mut(space).is_some() && self.crypto.states.rx(space, false).is_none()
             }
+            _ => false,
         }
     }
 
-    /// In case a datagram arrives that we can only partially process, save any
+    /// In case a datagram arrives that we can't process yet, save the
     /// part that we don't have keys for.
     fn maybe_save_datagram<'a, 'b>(
         &'a mut self,
         d: &'b Datagram,
         slice: &'b [u8],
+        pt: PacketType,
         now: Instant,
     ) -> bool {
-        // Only save partial datagrams to avoid looping.
-        // It also means that we don't have to worry about it being a stateless reset.
-        if slice.len() < d.len() {
-            let save = Datagram::new(d.source(), d.destination(), slice);
-            qdebug!([self], "saving datagram@{:?} {:?}", now, save);
-            self.saved_datagram = Some((save, true));
-            true
-        } else {
-            false
+        // Only save a packet if its keys will arrive, so that packets aren't
+        // saved forever.  A stateless reset doesn't need to be considered,
+        // as tokens for those are only used once the handshake is done.
+        if !self.keys_pending(pt) {
+            return false;
         }
+        if self.saved_datagrams.len() >= MAX_SAVED_DATAGRAMS {
+            qdebug!([self], "too many saved datagrams, dropping");
+            return false;
+        }
+        let save = Datagram::new_with_tos_ttl(d.source(), d.destination(), d.tos(), d.ttl(), slice);
+        qdebug!([self], "saving datagram@{:?} {:?}", now, save);
+        self.saved_datagrams.push_back((save, true));
+        true
     }
 
     fn input(&mut self, d: Datagram, now: Instant) -> Res<Vec<(Frame, PNSpace)>> {
@@ -1120,6 +1964,9 @@ impl Connection {
         let mut frames = Vec::new();
 
         qtrace!([self], "input {}", hex(&**d));
+        if let Some(p) = self.path.as_mut().filter(|p| p.received_on(&d)) {
+            p.on_datagram_received(d.len());
+        }
 
         // Handle each packet in the datagram
         while !slc.is_empty() {
@@ -1140,6 +1987,10 @@ impl Connection {
                         self.stats.pkt_dropped("Invalid Initial");
                         break;
                     }
+                    if d.len() < MIN_INITIAL_PACKET_SIZE {
+                        self.stats.pkt_dropped("Initial in a short datagram");
+                        break;
+                    }
                     qinfo!(
                         [self],
                         "Received valid Initial packet with scid {:?} dcid {:?}",
@@ -1148,6 +1999,7 @@ impl Connection {
                     );
                     self.set_state(State::WaitInitial);
                     self.loss_recovery.start_pacer(now);
+                    self.handshake_deadline = self.handshake_timeout.map(|t| now + t);
                     self.crypto
                         .states
                         .init(self.quic_version, self.role, &packet.dcid());
@@ -1155,7 +2007,8 @@ impl Connection {
                     // We need to make sure that we set this transport parameter.
                     // This has to happen prior to processing the packet so that
                     // the TLS handshake has all it needs.
-                    if !self.retry_sent() {
+                    // Draft-27 only includes it after a Retry, which sets it.
+                    if !self.retry_sent() && self.quic_version != QuicVersion::Draft27 {
                         self.tps.borrow_mut().local.set_bytes(
                             tparams::ORIGINAL_DESTINATION_CONNECTION_ID,
                             packet.dcid().to_vec(),
@@ -1193,7 +2046,15 @@ impl Connection {
                         .pkt_dropped(format!("{:?}", packet.packet_type()));
                     break;
                 }
-                _ => {}
+                (PacketType::Short, ..) => {}
+                _ => {
+                    // Long header packets have to use the version of the connection.
+                    // (Server connections are created with the version of the first Initial.)
+                    if packet.version() != Some(self.quic_version) {
+                        self.stats.pkt_dropped("Version mismatch");
+                        break;
+                    }
+                }
             };
 
             match self.state {
@@ -1208,10 +2069,6 @@ impl Connection {
                             .pkt_dropped(format!("Ignoring packet with CID {:?}", packet.dcid()));
                         break;
                     }
-                    if self.role == Role::Server && packet.packet_type() == PacketType::Handshake {
-                        // Server has received a Handshake packet -> discard Initial keys and states
-                        self.discard_keys(PNSpace::Initial);
-                    }
                 }
                 State::Closing { .. } => {
                     // Don't bother processing the packet. Instead ask to get a
@@ -1229,8 +2086,14 @@ impl Connection {
             qtrace!([self], "Received unverified packet {:?}", packet);
 
             let pto = self.loss_recovery.pto();
-            match packet.decrypt(&mut self.crypto.states, now + pto) {
+            let buf = self.rx_buffers.take();
+            match packet.decrypt(&mut self.crypto.states, now + pto, buf) {
                 Ok(payload) => {
+                    if !self.process_migrations(&d, payload.packet_type(), payload.pn()) {
+                        self.stats.pkt_dropped("Packet on a new path");
+                        self.rx_buffers.put(payload.into_buffer());
+                        break;
+                    }
                     // TODO(ekr@rtfm.com): Have the server blow away the initial
                     // crypto state if this fails? Otherwise, we will get a panic
                     // on the assert for doesn't exist.
@@ -1241,10 +2104,16 @@ impl Connection {
                         "-> RX",
                         payload.packet_type(),
                         payload.pn(),
+                        packet.dcid(),
+                        if packet.packet_type() == PacketType::Short {
+                            None
+                        } else {
+                            Some(&packet.scid()[..])
+                        },
                         &payload[..],
                     );
                     qlog::packet_received(&mut self.qlog, &payload)?;
-                    let res = self.process_packet(&payload, now);
+                    let res = self.process_packet(&payload, d.ecn(), now);
                     if res.is_err() && self.path.is_none() {
                         // We need to make a path for sending an error message.
                         // But this connection is going to be closed.
@@ -1252,21 +2121,44 @@ impl Connection {
                         self.initialize_path(d.destination(), d.source());
                     }
                     frames.extend(res?);
+                    if self.role == Role::Server && payload.packet_type() == PacketType::Handshake {
+                        // Server has processed a Handshake packet -> discard Initial keys and states.
+                        // This waits until the packet is authenticated, so that an attacker can't
+                        // cause the Initial keys to be discarded.
+                        self.discard_keys(PNSpace::Initial);
+                        // The client could only send this if it received our Initial,
+                        // which validates its address.
+                        if let Some(p) = &mut self.path {
+                            p.set_valid();
+                        }
+                    }
                     if self.state == State::WaitInitial {
                         self.start_handshake(&packet, &d)?;
                     }
-                    self.process_migrations(&d)?;
+                    self.rx_buffers.put(payload.into_buffer());
                 }
                 Err(e) => {
-                    // While connecting we might want to save the remainder of a packet.
-                    if matches!(e, Error::KeysNotFound) && self.maybe_save_datagram(&d, slc, now) {
-                        break;
+                    if matches!(e, Error::KeysNotFound) {
+                        // While connecting we might want to save the rest of the datagram.
+                        if self.maybe_save_datagram(&d, slc, packet.packet_type(), now) {
+                            break;
+                        }
+                    } else {
+                        self.stats.undecryptable_rx += 1;
                     }
                     // Decryption failure, or not having keys is not fatal.
                     // If the state isn't available, or we can't decrypt the packet, drop
                     // the rest of the datagram on the floor, but don't generate an error.
                     self.check_stateless_reset(&d, slc, now)?;
                     self.stats.pkt_dropped("Decryption failure");
+                    // Too many forged packets could allow an attacker to break the
+                    // integrity of the AEAD, so stop before that.
+                    if u64::try_from(self.stats.undecryptable_rx).unwrap()
+                        >= self.undecryptable_limit
+                    {
+                        qwarn!([self], "Too many packets failed authentication");
+                        return Err(Error::AeadLimitReached);
+                    }
                 }
             }
             slc = remainder;
@@ -1275,9 +2167,17 @@ impl Connection {
         Ok(frames)
     }
 
+    /// Decode a frame.  If that fails, the type of the frame is used when closing.
+    fn decode_frame(&mut self, d: &mut Decoder, now: Instant) -> Res<Frame> {
+        let t = Decoder::from(&d[..]).decode_varint().unwrap_or(0);
+        let res = Frame::decode(d);
+        self.capture_error(now, t, res)
+    }
+
     fn process_packet(
         &mut self,
         packet: &DecryptedPacket,
+        ecn: u8,
         now: Instant,
     ) -> Res<Vec<(Frame, PNSpace)>> {
         // TODO(ekr@rtfm.com): Have the server blow away the initial
@@ -1298,12 +2198,12 @@ impl Connection {
         #[allow(unused_mut)]
         let mut frames = Vec::new();
         while d.remaining() > 0 {
-            let mut f = Frame::decode(&mut d)?;
+            let mut f = self.decode_frame(&mut d, now)?;
 
             // Skip padding
             while f == Frame::Padding && d.remaining() > 0 {
                 consecutive_padding += 1;
-                f = Frame::decode(&mut d)?;
+                f = self.decode_frame(&mut d, now)?;
             }
             if consecutive_padding > 0 {
                 qdebug!(
@@ -1322,17 +2222,16 @@ impl Connection {
             let res = self.input_frame(packet.packet_type(), f, now);
             self.capture_error(now, t, res)?;
         }
-        self.acks
-            .get_mut(space)
-            .unwrap()
-            .set_received(now, packet.pn(), ack_eliciting);
+        let acks = self.acks.get_mut(space).unwrap();
+        acks.set_received(now, packet.pn(), ack_eliciting);
+        acks.count_ecn(ecn);
 
         Ok(frames)
     }
 
     fn initialize_path(&mut self, local_addr: SocketAddr, remote_addr: SocketAddr) {
         debug_assert!(self.path.is_none());
-        self.path = Some(Path::new(
+        let mut path = Path::new(
             local_addr,
             remote_addr,
             self.local_initial_source_cid.clone(),
@@ -1343,7 +2242,15 @@ impl Connection {
                 .or_else(|| self.remote_original_destination_cid.as_ref())
                 .unwrap()
                 .clone(),
-        ));
+        );
+        // The anti-amplification limit only applies to servers.
+        if self.role == Role::Client {
+            path.set_valid();
+        }
+        if self.ecn {
+            path.enable_ecn();
+        }
+        self.path = Some(path);
     }
 
     fn start_handshake(&mut self, packet: &PublicPacket, d: &Datagram) -> Res<()> {
@@ -1355,6 +2262,12 @@ impl Connection {
             self.valid_cids.push(ConnectionId::from(packet.dcid()));
             // Install a path.
             self.initialize_path(d.destination(), d.source());
+            let path = self.path.as_mut().unwrap();
+            path.on_datagram_received(d.len());
+            if self.retry_sent() {
+                // A valid Retry token shows that the client can receive at this address.
+                path.set_valid();
+            }
 
             self.zero_rtt_state = match self.crypto.enable_0rtt(self.role) {
                 Ok(true) => {
@@ -1376,18 +2289,206 @@ impl Connection {
         Ok(())
     }
 
-    fn process_migrations(&self, d: &Datagram) -> Res<()> {
-        if self.path.iter().any(|p| p.received_on(&d)) {
-            Ok(())
-        } else {
-            // Right now, we don't support any form of migration.
-            // So generate an error if a packet is received on a new path.
-            Err(Error::InvalidMigration)
+    /// Check the addresses of a packet that was just decrypted.  This returns
+    /// false if the packet arrived on a new path that can't be used, in which
+    /// case it is ignored.
+    fn process_migrations(&mut self, d: &Datagram, pt: PacketType, pn: PacketNumber) -> bool {
+        match &self.path {
+            // A server doesn't have a path until the first packet is processed.
+            None => return true,
+            Some(p) if p.received_on(&d) => return true,
+            _ => {}
+        }
+        if let Some(PreferredAddressMigration::Probing { path, .. }) =
+            &self.preferred_address_migration
+        {
+            if path.received_on(&d) {
+                // The answer to our challenge, which might still be in flight.
+                return true;
+            }
+        }
+        let to_preferred_address = self
+            .local_preferred_address
+            .as_ref()
+            .map_or(false, |pa| pa.contains(&d.destination()))
+            && self
+                .path
+                .as_ref()
+                .map_or(false, |p| *p.remote_address() == d.source());
+        if to_preferred_address {
+            qinfo!(
+                [self],
+                "Client moved to preferred address {}",
+                d.destination()
+            );
+            self.path
+                .as_mut()
+                .unwrap()
+                .set_local_address(d.destination());
+            return true;
+        }
+
+        // A peer can only migrate once the handshake is confirmed.
+        if self.migration_policy == MigrationPolicy::Disabled
+            || pt != PacketType::Short
+            || self.state != State::Confirmed
+        {
+            qinfo!(
+                [self],
+                "Ignoring packet on new path {} -> {}",
+                d.source(),
+                d.destination()
+            );
+            return false;
+        }
+        // Only the packet with the largest packet number moves the connection,
+        // so an old packet that is replayed from another address can't.
+        // Packets that are reordered are still used.
+        if self
+            .acks
+            .get_mut(PNSpace::ApplicationData)
+            .and_then(|r| r.largest_pn())
+            .map_or(false, |largest| pn <= largest)
+        {
+            qdebug!([self], "Not migrating for old packet {}", pn);
+            return true;
+        }
+        if self
+            .path_validation
+            .as_ref()
+            .map_or(false, |v| v.previous.received_on(&d))
+        {
+            qinfo!([self], "Peer went back to {}", d.source());
+            self.abandon_path_validation();
+            return true;
+        }
+
+        qinfo!([self], "Peer moved to {}", d.source());
+        // Without a spare connection ID, the current one is used on the new path.
+        let spare = self.take_remote_cid();
+        let current = self.path.as_ref().unwrap();
+        let (seqno, remote_cid, reset_token) = spare.unwrap_or_else(|| {
+            (
+                self.remote_cid_seqno,
+                current.remote_cid().clone(),
+                current.reset_token().copied(),
+            )
+        });
+        let mut path = current.migrate(d.destination(), d.source(), remote_cid);
+        if let Some(token) = reset_token {
+            path.set_reset_token(token);
+        }
+        // Until the new address is validated, sending on the new path is
+        // limited by what arrived on it.
+        path.on_datagram_received(d.len());
+        self.start_path_validation(path, seqno);
+        true
+    }
+
+    /// Send a PATH_CHALLENGE on a new path, or go back to the previous path if
+    /// the peer didn't answer in time.
+    fn output_path_validation(&mut self, now: Instant) -> Option<SendOption> {
+        if self.state != State::Confirmed {
+            return None;
+        }
+        let challenge = self.path_validation.as_ref()?.challenge;
+        match challenge {
+            Some((_, timeout)) if timeout <= now => {
+                qinfo!([self], "No answer on the new path, going back");
+                self.abandon_path_validation();
+                None
+            }
+            Some(_) => None,
+            None => {
+                let mut path = self.path.take().unwrap();
+                if path.amplification_blocked() {
+                    // Wait until more arrives on the new path.
+                    self.path = Some(path);
+                    return None;
+                }
+                let data = <[u8; 8]>::try_from(&random(8)[..]).unwrap();
+                let timeout = now + self.loss_recovery.pto() * 3;
+                self.path_validation.as_mut().unwrap().challenge = Some((data, timeout));
+                let res = self.output_path_challenge(&mut path, data, now);
+                self.path = Some(path);
+                self.absorb_error(now, res)
+            }
+        }
+    }
+
+    /// Send a PATH_CHALLENGE to the server's preferred address, if that is due.
+    fn output_preferred_address_probe(&mut self, now: Instant) -> Option<SendOption> {
+        if self.state != State::Confirmed {
+            return None;
+        }
+        match self.preferred_address_migration.take() {
+            Some(PreferredAddressMigration::Pending(mut path)) => {
+                let data = <[u8; 8]>::try_from(&random(8)[..]).unwrap();
+                let res = self.output_path_challenge(&mut path, data, now);
+                let timeout = now + self.loss_recovery.pto() * 3;
+                self.preferred_address_migration = Some(PreferredAddressMigration::Probing {
+                    path,
+                    data,
+                    timeout,
+                });
+                self.absorb_error(now, res)
+            }
+            Some(PreferredAddressMigration::Probing { timeout, .. }) if timeout <= now => {
+                qinfo!([self], "No answer from preferred address, staying put");
+                None
+            }
+            other => {
+                self.preferred_address_migration = other;
+                None
+            }
         }
     }
 
+    fn output_path_challenge(
+        &mut self,
+        path: &mut Path,
+        data: [u8; 8],
+        now: Instant,
+    ) -> Res<SendOption> {
+        let tx = self
+            .crypto
+            .states
+            .tx(PNSpace::ApplicationData)
+            .ok_or(Error::InternalError)?;
+        let encoder = Encoder::with_capacity(path.mtu());
+        let (pt, pn, mut builder) = Self::build_packet_header(
+            path,
+            PNSpace::ApplicationData,
+            encoder,
+            tx,
+            &[],
+            self.quic_version,
+        );
+        Frame::PathChallenge { data }.marshal(&mut builder);
+        // Pad the datagram to check that the path carries full-sized packets,
+        // unless that is more than the anti-amplification limit allows.
+        let size = min(PATH_MTU_MIN, path.amplification_limit());
+        builder.set_limit(size.saturating_sub(tx.expansion()));
+        let padding = builder.remaining();
+        builder.encode(&vec![0; padding]);
+        let encoder = builder.build(tx)?;
+        // Track the packet so that acknowledgments for it make sense, but
+        // don't count it against the congestion window of the current path.
+        let sent = SentPacket::new(pt, pn, now, true, Rc::default(), encoder.len(), false);
+        self.loss_recovery
+            .on_packet_sent(PNSpace::ApplicationData, pn, sent);
+        path.on_datagram_sent(encoder.len());
+        Ok(SendOption::Yes(path.datagram(encoder)))
+    }
+
     fn output(&mut self, now: Instant) -> SendOption {
         qtrace!([self], "output {:?}", now);
+        if let Some(probe) = self.output_preferred_address_probe(now) {
+            return probe;
+        }
+        if let Some(challenge) = self.output_path_validation(now) {
+            return challenge;
+        }
         if let Some(mut path) = self.path.take() {
             let res = match &self.state {
                 State::Init
@@ -1416,7 +2517,7 @@ impl Connection {
         space: PNSpace,
         encoder: Encoder,
         tx: &CryptoDxState,
-        retry_info: &Option<RetryInfo>,
+        token: &[u8],
         quic_version: QuicVersion,
     ) -> (PacketType, PacketNumber, PacketBuilder) {
         let pt = match space {
@@ -1450,15 +2551,11 @@ impl Connection {
             )
         };
         if pt == PacketType::Initial {
-            builder.initial_token(if let Some(info) = retry_info {
-                &info.token
-            } else {
-                &[]
-            });
+            builder.initial_token(token);
         }
         // TODO(mt) work out packet number length based on `4*path CWND/path MTU`.
         let pn = tx.next_pn();
-        builder.pn(pn, 3);
+        builder.pn(pn, PACKET_NUMBER_LEN);
         (pt, pn, builder)
     }
 
@@ -1477,7 +2574,7 @@ impl Connection {
             }
 
             let (_, _, mut builder) =
-                Self::build_packet_header(path, *space, encoder, tx, &None, self.quic_version);
+                Self::build_packet_header(path, *space, encoder, tx, &[], self.quic_version);
             // ConnectionError::Application is only allowed at 1RTT.
             if *space == PNSpace::ApplicationData {
                 frame.marshal(&mut builder);
@@ -1491,18 +2588,18 @@ impl Connection {
         Ok(SendOption::Yes(path.datagram(encoder)))
     }
 
-    /// Add frames to the provided builder and
+    /// Add frames to the provided builder, up to its limit, and
     /// return whether any of them were ACK eliciting.
     fn add_frames(
         &mut self,
         builder: &mut PacketBuilder,
         space: PNSpace,
-        limit: usize,
         profile: &SendProfile,
         now: Instant,
     ) -> (Vec<RecoveryToken>, bool) {
         let mut tokens = Vec::new();
 
+        let max_space = builder.remaining();
         let mut ack_eliciting = if profile.pto() {
             // Add a PING on a PTO.  This might get a more expedient ACK.
             builder.encode_varint(Frame::Ping.get_type());
@@ -1512,10 +2609,13 @@ impl Connection {
         };
 
         // All useful frames are at least 2 bytes.
-        while builder.len() + 2 < limit {
-            let remaining = limit - builder.len();
-            // Try to get a frame from frame sources
-            let mut frame = self.acks.get_frame(now, space);
+        while builder.remaining() > 2 {
+            let remaining = builder.remaining();
+            // Try to get a frame from frame sources.  These are in order of
+            // priority, so that control frames go ahead of stream data when
+            // there isn't much space.  Each frame is picked afresh, so a
+            // control frame that becomes ready goes before more stream data.
+            let mut frame = self.acks.get_frame(now, space, remaining);
             // If we are CC limited we can only send acks!
             if !profile.ack_only(space) {
                 if frame.is_none() && space == PNSpace::ApplicationData && self.role == Role::Server
@@ -1528,14 +2628,25 @@ impl Connection {
                 if frame.is_none() {
                     frame = self.flow_mgr.borrow_mut().get_frame(space, remaining);
                 }
+                if frame.is_none() && space == PNSpace::ApplicationData {
+                    frame = self
+                        .quic_datagrams
+                        .get_frame(remaining, max_space)
+                        .map(|f| (f, None));
+                }
                 if frame.is_none() {
                     frame = self.send_streams.get_frame(space, remaining);
                 }
+                if frame.is_none() && space == PNSpace::ApplicationData && self.ping_pending {
+                    self.ping_pending = false;
+                    frame = Some((Frame::Ping, None));
+                }
             }
 
             if let Some((frame, token)) = frame {
                 ack_eliciting |= frame.ack_eliciting();
                 debug_assert_ne!(frame, Frame::Padding);
+                debug_assert!(frame.encoded_len() <= remaining);
                 frame.marshal(builder);
                 if let Some(t) = token {
                     tokens.push(t);
@@ -1556,10 +2667,12 @@ impl Connection {
         // Determine how we are sending packets (PTO, etc..).
         let profile = self.loss_recovery.send_profile(now, path.mtu());
         qdebug!([self], "output_path send_profile {:?}", profile);
+        // Until the peer's address is validated, the anti-amplification limit applies.
+        let send_limit = min(profile.limit(), path.amplification_limit());
 
         // Frames for different epochs must go in different packets, but then these
         // packets can go in a single datagram
-        let mut encoder = Encoder::with_capacity(profile.limit());
+        let mut encoder = Encoder::with_capacity(send_limit);
         for space in PNSpace::iter() {
             // Ensure we have tx crypto state for this epoch, or skip it.
             let tx = if let Some(tx_state) = self.crypto.states.tx(*space) {
@@ -1574,29 +2687,38 @@ impl Connection {
                 *space,
                 encoder,
                 tx,
-                &self.retry_info,
+                self.retry_info
+                    .as_ref()
+                    .map_or(&self.initial_token[..], |ri| &ri.token[..]),
                 self.quic_version,
             );
             let payload_start = builder.len();
 
             // Work out if we have space left.
-            if builder.len() + tx.expansion() > profile.limit() {
+            builder.set_limit(send_limit.saturating_sub(tx.expansion()));
+            if builder.over_limit() {
                 // No space for a packet of this type.
                 encoder = builder.abort();
                 continue;
             }
 
             // Add frames to the packet.
-            let limit = profile.limit() - tx.expansion();
-            let (tokens, ack_eliciting) =
-                self.add_frames(&mut builder, *space, limit, &profile, now);
+            let (tokens, ack_eliciting) = self.add_frames(&mut builder, *space, &profile, now);
             if builder.is_empty() {
                 // Nothing to include in this packet.
                 encoder = builder.abort();
                 continue;
             }
 
-            dump_packet(self, "TX ->", pt, pn, &builder[payload_start..]);
+            dump_packet(
+                self,
+                "TX ->",
+                pt,
+                pn,
+                path.remote_cid(),
+                Some(&path.local_cid()[..]).filter(|_| pt != PacketType::Short),
+                &builder[payload_start..],
+            );
             qlog::packet_sent(&mut self.qlog, pt, pn, &builder[payload_start..])?;
 
             self.stats.packets_tx += 1;
@@ -1610,6 +2732,8 @@ impl Connection {
                 self.idle_timeout.on_packet_sent(now);
             }
             let sent = SentPacket::new(
+                pt,
+                pn,
                 now,
                 ack_eliciting,
                 Rc::new(tokens),
@@ -1640,6 +2764,10 @@ impl Connection {
         }
 
         if encoder.is_empty() {
+            if !profile.paced() && !profile.pto() && !profile.ack_only(PNSpace::ApplicationData) {
+                // There was space to send, but nothing to send.
+                self.loss_recovery.on_app_limited();
+            }
             Ok(SendOption::No(profile.paced()))
         } else {
             // Pad Initial packets sent by the client to mtu bytes.
@@ -1653,10 +2781,33 @@ impl Connection {
                 self.loss_recovery
                     .on_packet_sent(PNSpace::Initial, initial_pn, initial);
             }
+            path.on_datagram_sent(packets.len());
             Ok(SendOption::Yes(path.datagram(packets)))
         }
     }
 
+    /// Send a PING frame, which the peer has to acknowledge.  This can be
+    /// used to keep an idle connection from timing out.
+    pub fn send_ping(&mut self) -> Res<()> {
+        if self.state.connected() {
+            self.ping_pending = true;
+            Ok(())
+        } else {
+            Err(Error::NotConnected)
+        }
+    }
+
+    /// The idle timeout.  This is the smaller of the local value and the
+    /// value that the peer advertised.
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout.timeout
+    }
+
+    /// What the peer said when it closed the connection, if it did.
+    pub fn peer_close(&self) -> Option<&PeerClose> {
+        self.peer_close.as_ref()
+    }
+
     pub fn initiate_key_update(&mut self) -> Res<()> {
         if self.state == State::Confirmed {
             let la = self
@@ -1679,6 +2830,7 @@ impl Connection {
         debug_assert_eq!(self.role, Role::Client);
         qlog::client_connection_started(&mut self.qlog, self.path.as_ref().unwrap())?;
         self.loss_recovery.start_pacer(now);
+        self.handshake_deadline = self.handshake_timeout.map(|t| now + t);
 
         self.handshake(now, PNSpace::Initial, None)?;
         self.set_state(State::WaitInitial);
@@ -1720,6 +2872,10 @@ impl Connection {
             self.idle_timeout
                 .set_peer_timeout(Duration::from_millis(peer_timeout));
         }
+        let max_ack_delay = Duration::from_millis(remote.get_integer(tparams::MAX_ACK_DELAY));
+        self.loss_recovery.set_peer_max_ack_delay(max_ack_delay);
+        self.loss_recovery
+            .set_peer_ack_delay_exponent(remote.get_integer(tparams::ACK_DELAY_EXPONENT));
     }
 
     /// Process the final set of transport parameters.
@@ -1736,10 +2892,44 @@ impl Connection {
             let reset_token = <[u8; 16]>::try_from(token).unwrap().to_owned();
             self.path.as_mut().unwrap().set_reset_token(reset_token);
         }
+        if self.role == Role::Client {
+            self.setup_preferred_address_migration()?;
+        }
         self.set_initial_limits();
         Ok(())
     }
 
+    fn setup_preferred_address_migration(&mut self) -> Res<()> {
+        let preferred_address = if let Some(pa) = self
+            .tps
+            .borrow()
+            .remote()
+            .get_bytes(tparams::PREFERRED_ADDRESS)
+        {
+            PreferredAddress::decode(pa)?
+        } else {
+            return Ok(());
+        };
+        let current = self.path.as_ref().unwrap();
+        if let Some(remote) = preferred_address.address_for(current.remote_address()) {
+            let mut path = Path::new(
+                *current.local_address(),
+                remote,
+                current.local_cid().clone(),
+                preferred_address.cid().clone(),
+            );
+            path.set_reset_token(*preferred_address.reset_token());
+            // Only a client uses a preferred address, and the anti-amplification
+            // limit only applies to servers.
+            path.set_valid();
+            if self.ecn {
+                path.enable_ecn();
+            }
+            self.preferred_address_migration = Some(PreferredAddressMigration::Pending(path));
+        }
+        Ok(())
+    }
+
     fn validate_cids(&mut self) -> Res<()> {
         match self.quic_version {
             QuicVersion::Draft27 => self.validate_cids_draft_27(),
@@ -1747,23 +2937,34 @@ impl Connection {
         }
     }
 
+    /// In draft-27, the server only sends original_connection_id after a Retry,
+    /// when it has to match the connection ID the client first chose.
     fn validate_cids_draft_27(&mut self) -> Res<()> {
-        if let Some(info) = &self.retry_info {
+        if self.role == Role::Server {
+            return Ok(());
+        }
+        let tph = self.tps.borrow();
+        let tp = tph
+            .remote
+            .as_ref()
+            .unwrap()
+            .get_bytes(tparams::ORIGINAL_DESTINATION_CONNECTION_ID);
+        let expected = if let Some(info) = &self.retry_info {
             debug_assert!(!info.token.is_empty());
-            let tph = self.tps.borrow();
-            let tp = tph
-                .remote
-                .as_ref()
-                .unwrap()
-                .get_bytes(tparams::ORIGINAL_DESTINATION_CONNECTION_ID);
-            if self
-                .remote_original_destination_cid
+            self.remote_original_destination_cid
                 .as_ref()
                 .map(ConnectionId::as_cid_ref)
-                != tp.map(ConnectionIdRef::from)
-            {
-                return Err(Error::InvalidRetry);
-            }
+        } else {
+            None
+        };
+        if expected != tp.map(ConnectionIdRef::from) {
+            qwarn!(
+                "{} ODCID test failed: self cid {:?} != tp cid {:?}",
+                self.role,
+                expected,
+                tp.map(hex),
+            );
+            return Err(Error::TransportParameterError);
         }
         Ok(())
     }
@@ -1785,7 +2986,7 @@ impl Connection {
                 self.remote_initial_source_cid,
                 tp.map(hex),
             );
-            return Err(Error::ProtocolViolation);
+            return Err(Error::TransportParameterError);
         }
 
         if self.role == Role::Client {
@@ -1802,7 +3003,7 @@ impl Connection {
                     self.remote_original_destination_cid,
                     tp.map(hex),
                 );
-                return Err(Error::ProtocolViolation);
+                return Err(Error::TransportParameterError);
             }
 
             let tp = remote_tps.get_bytes(tparams::RETRY_SOURCE_CONNECTION_ID);
@@ -1817,7 +3018,7 @@ impl Connection {
                     expected,
                     tp.map(hex),
                 );
-                return Err(Error::ProtocolViolation);
+                return Err(Error::TransportParameterError);
             }
         }
 
@@ -1828,6 +3029,7 @@ impl Connection {
         qtrace!([self], "Handshake space={} data={:0x?}", space, data);
 
         let try_update = data.is_some();
+        let ticket = self.client_ticket().cloned();
         match self.crypto.handshake(now, space, data)? {
             HandshakeState::Authenticated(_) | HandshakeState::InProgress => (),
             HandshakeState::AuthenticationPending => self.events.authentication_needed(),
@@ -1851,9 +3053,24 @@ impl Connection {
             self.crypto.install_keys(self.role);
         }
 
+        // A session ticket replaces the one that the client had.
+        if self.client_ticket() != ticket.as_ref() {
+            if let Some(token) = self.resumption_token() {
+                self.events.resumption_token(token);
+            }
+        }
+
         Ok(())
     }
 
+    /// The last session ticket that a client received.
+    fn client_ticket(&self) -> Option<&Vec<u8>> {
+        match self.crypto.tls {
+            Agent::Client(ref c) => c.resumption_token(),
+            Agent::Server(_) => None,
+        }
+    }
+
     fn handle_max_data(&mut self, maximum_data: u64) {
         let conn_was_blocked = self.flow_mgr.borrow().conn_credit_avail() == 0;
         let conn_credit_increased = self
@@ -1889,6 +3106,7 @@ impl Connection {
                 ack_delay,
                 first_ack_range,
                 ack_ranges,
+                ecn_count,
             } => {
                 self.handle_ack(
                     PNSpace::from(ptype),
@@ -1896,6 +3114,7 @@ impl Connection {
                     ack_delay,
                     first_ack_range,
                     ack_ranges,
+                    ecn_count,
                     now,
                 )?;
             }
@@ -1904,6 +3123,12 @@ impl Connection {
                 application_error_code,
                 ..
             } => {
+                // Terminate connection with STREAM_STATE_ERROR if send-only
+                // stream (-transport 19.4)
+                if stream_id.is_send_only(self.role()) {
+                    return Err(Error::StreamStateError);
+                }
+
                 // TODO(agrover@mozilla.com): use final_size for connection MaxData calc
                 if let (_, Some(rs)) = self.obtain_stream(stream_id)? {
                     rs.reset(application_error_code);
@@ -1913,6 +3138,12 @@ impl Connection {
                 stream_id,
                 application_error_code,
             } => {
+                // Terminate connection with STREAM_STATE_ERROR if receive-only
+                // stream (-transport 19.5)
+                if stream_id.is_recv_only(self.role()) {
+                    return Err(Error::StreamStateError);
+                }
+
                 self.events
                     .send_stream_stop_sending(stream_id, application_error_code);
                 if let (Some(ss), _) = self.obtain_stream(stream_id)? {
@@ -1936,7 +3167,12 @@ impl Connection {
                     self.handshake(now, space, Some(&buf))?;
                 }
             }
-            Frame::NewToken { token } => self.token = Some(token),
+            Frame::NewToken { token } => {
+                if self.role == Role::Server {
+                    return Err(Error::ProtocolViolation);
+                }
+                self.token = Some(token);
+            }
             Frame::Stream {
                 fin,
                 stream_id,
@@ -1944,8 +3180,16 @@ impl Connection {
                 data,
                 ..
             } => {
+                // Terminate connection with STREAM_STATE_ERROR if send-only
+                // stream (-transport 19.8)
+                if stream_id.is_send_only(self.role()) {
+                    return Err(Error::StreamStateError);
+                }
+
+                let rtt = self.loss_recovery.rtt();
                 if let (_, Some(rs)) = self.obtain_stream(stream_id)? {
                     rs.inbound_stream_frame(fin, offset, data)?;
+                    rs.record_rx(now, rtt);
                 }
             }
             Frame::MaxData { maximum_data } => self.handle_max_data(maximum_data),
@@ -1953,6 +3197,12 @@ impl Connection {
                 stream_id,
                 maximum_stream_data,
             } => {
+                // Terminate connection with STREAM_STATE_ERROR if receive-only
+                // stream (-transport 19.10)
+                if stream_id.is_recv_only(self.role()) {
+                    return Err(Error::StreamStateError);
+                }
+
                 if let (Some(ss), _) = self.obtain_stream(stream_id)? {
                     ss.set_max_stream_data(maximum_stream_data);
                 }
@@ -2018,35 +3268,104 @@ impl Connection {
             }
             Frame::NewConnectionId {
                 sequence_number,
+                retire_prior,
                 connection_id,
                 stateless_reset_token,
-                ..
             } => {
-                self.connection_ids
-                    .insert(sequence_number, (connection_id, stateless_reset_token));
+                // A peer that uses a zero-length connection ID can't have more.
+                if self
+                    .path
+                    .as_ref()
+                    .map_or(false, |p| p.remote_cid().is_empty())
+                {
+                    return Err(Error::ProtocolViolation);
+                }
+                if retire_prior > sequence_number {
+                    return Err(Error::FrameEncodingError);
+                }
+                let entry = (connection_id, stateless_reset_token);
+                match self.connection_ids.get(&sequence_number) {
+                    // A repeat, which is fine if nothing changed.
+                    Some(prev) if *prev != entry => return Err(Error::ProtocolViolation),
+                    _ => {}
+                }
+                self.connection_ids.insert(sequence_number, entry);
+                self.retire_prior_to = max(self.retire_prior_to, retire_prior);
+                self.retire_remote_cids();
+                // The connection IDs that are in use count toward the limit too.
+                let in_use = match &self.path_validation {
+                    Some(v) if v.previous_cid_seqno != self.remote_cid_seqno => 2,
+                    _ => 1,
+                };
+                let limit = self
+                    .tps
+                    .borrow()
+                    .local
+                    .get_integer(tparams::ACTIVE_CONNECTION_ID_LIMIT);
+                if self.connection_ids.len() as u64 + in_use > limit {
+                    return Err(Error::ConnectionIdLimitError);
+                }
             }
             Frame::RetireConnectionId { sequence_number } => {
-                self.connection_ids.remove(&sequence_number);
+                // The handshake connection ID, and the one for a preferred
+                // address, are given out even if no others are.
+                let given = if self.local_preferred_address.is_some() {
+                    2
+                } else {
+                    1
+                };
+                if sequence_number >= max(self.next_cid_seqno, given) {
+                    return Err(Error::ProtocolViolation);
+                }
+                // The peer won't use this one of ours again, so it gets another.
+                if let Some(cid) = self.issued_cids.remove(&sequence_number) {
+                    self.valid_cids.retain(|c| *c != cid);
+                    self.issue_connection_ids();
+                }
             }
             Frame::PathChallenge { data } => self.flow_mgr.borrow_mut().path_response(data),
-            Frame::PathResponse { .. } => {
-                // Should never see this, we don't support migration atm and
-                // do not send path challenges
-                qwarn!([self], "Received Path Response");
+            Frame::PathResponse { data } => {
+                if self
+                    .path_validation
+                    .as_ref()
+                    .and_then(|v| v.challenge)
+                    .map_or(false, |(expected, _)| expected == data)
+                {
+                    self.path_validated();
+                } else {
+                    // The only other path challenges we send are to a preferred address.
+                    match self.preferred_address_migration.take() {
+                        Some(PreferredAddressMigration::Probing {
+                            mut path,
+                            data: expected,
+                            ..
+                        }) if data == expected => {
+                            qinfo!(
+                                [self],
+                                "Moving to preferred address {}",
+                                path.remote_address()
+                            );
+                            path.set_valid();
+                            self.path = Some(path);
+                            // The connection ID for a preferred address is the second one.
+                            self.remote_cid_seqno = 1;
+                        }
+                        other => {
+                            qwarn!([self], "Received unexpected Path Response");
+                            self.preferred_address_migration = other;
+                        }
+                    }
+                }
             }
             Frame::ConnectionClose {
                 error_code,
                 frame_type,
                 reason_phrase,
             } => {
-                let reason_phrase = String::from_utf8_lossy(&reason_phrase);
-                qinfo!(
-                    [self],
-                    "ConnectionClose received. Error code: {:?} frame type {:x} reason {}",
-                    error_code,
-                    frame_type,
-                    reason_phrase
-                );
+                let peer_close = PeerClose::new(error_code, frame_type, &reason_phrase);
+                qinfo!([self], "ConnectionClose received: {}", peer_close);
+                qlog::connection_closed(&mut self.qlog, &peer_close)?;
+                self.peer_close = Some(peer_close);
                 let (detail, frame_type) = if let CloseError::Application(_) = error_code {
                     // Use a transport error here because we want to send
                     // NO_ERROR in this case.
@@ -2073,6 +3392,19 @@ impl Connection {
                 }
                 self.set_state(State::Confirmed);
                 self.discard_keys(PNSpace::Handshake);
+                self.issue_connection_ids();
+            }
+            Frame::Datagram { data, .. } => {
+                let max = self
+                    .tps
+                    .borrow()
+                    .local
+                    .get_integer(tparams::MAX_DATAGRAM_FRAME_SIZE);
+                let size = data.len() + QuicDatagrams::overhead(data.len());
+                if u64::try_from(size).unwrap_or(u64::max_value()) > max {
+                    return Err(Error::ProtocolViolation);
+                }
+                self.events.datagram(data);
             }
         };
 
@@ -2083,6 +3415,9 @@ impl Connection {
     /// is told that they are lost.  This gives the frame generation code a chance
     /// to retransmit the frame as needed.
     fn handle_lost_packets(&mut self, lost_packets: &[SentPacket]) {
+        if let Some(path) = &mut self.path {
+            path.on_packets_lost(lost_packets);
+        }
         for lost in lost_packets {
             for token in lost.tokens.as_ref() {
                 qdebug!([self], "Lost: {:?}", token);
@@ -2102,6 +3437,7 @@ impl Connection {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn handle_ack(
         &mut self,
         space: PNSpace,
@@ -2109,6 +3445,7 @@ impl Connection {
         ack_delay: u64,
         first_ack_range: u64,
         ack_ranges: Vec<AckRange>,
+        ecn_count: Option<EcnCount>,
         now: Instant,
     ) -> Res<()> {
         qinfo!(
@@ -2122,14 +3459,26 @@ impl Connection {
 
         let acked_ranges =
             Frame::decode_ack_frame(largest_acknowledged, first_ack_range, &ack_ranges)?;
+        let ack_delay = self.loss_recovery.ack_delay(space, ack_delay);
         let (acked_packets, lost_packets) = self.loss_recovery.on_ack_received(
             space,
             largest_acknowledged,
             acked_ranges,
-            Duration::from_millis(ack_delay),
+            ack_delay,
             now,
         );
-        for acked in acked_packets {
+        if let Some(path) = &mut self.path {
+            path.on_packets_acked(&acked_packets);
+            if path.on_ecn_count(space, &acked_packets, ecn_count) {
+                let sent_time = acked_packets.iter().map(|p| p.time_sent).max().unwrap();
+                self.loss_recovery.on_ecn_ce(now, sent_time);
+            }
+        }
+        let rate = self.loss_recovery.delivery_rate();
+        self.stats.delivery_rate = rate.rate();
+        self.stats.app_limited = rate.app_limited();
+        self.stats.smoothed_delivery_rate = rate.smoothed_rate();
+        for acked in acked_packets {
             for token in acked.tokens.as_ref() {
                 match token {
                     RecoveryToken::Ack(at) => self.acks.acked(at),
@@ -2143,7 +3492,7 @@ impl Connection {
             }
         }
         self.handle_lost_packets(&lost_packets);
-        Ok(())
+        self.qlog_recovery_update(&lost_packets)
     }
 
     /// When the server rejects 0-RTT we need to drop a bunch of stuff.
@@ -2166,7 +3515,8 @@ impl Connection {
 
     fn set_connected(&mut self, now: Instant) -> Res<()> {
         qinfo!([self], "TLS connection complete");
-        if self.crypto.tls.info().map(SecretAgentInfo::alpn).is_none() {
+        let alpn = self.crypto.tls.info().and_then(SecretAgentInfo::alpn);
+        if alpn.is_none() {
             qwarn!([self], "No ALPN. Closing connection.");
             // 120 = no_application_protocol
             return Err(Error::CryptoAlert(120));
@@ -2184,6 +3534,9 @@ impl Connection {
                 self.client_0rtt_rejected();
                 ZeroRttState::Rejected
             };
+            if self.crypto.tls.info().unwrap().ech_accepted() {
+                self.events.ech_accepted();
+            }
         }
 
         // Setting application keys has to occur after 0-RTT rejection.
@@ -2195,6 +3548,10 @@ impl Connection {
         if self.role == Role::Server {
             self.state_signaling.handshake_done();
             self.set_state(State::Confirmed);
+            self.issue_connection_ids();
+            if let Some(token) = self.pending_new_token.take() {
+                self.flow_mgr.borrow_mut().new_token(token);
+            }
         }
         qinfo!([self], "Connection established");
         qlog::connection_tparams_set(&mut self.qlog, &*self.tps.borrow())?;
@@ -2256,6 +3613,17 @@ impl Connection {
         self.send_streams.clear_terminal();
     }
 
+    fn new_recv_stream(&self, stream_id: StreamId, max_stream_data: u64) -> RecvStream {
+        let mut rs = RecvStream::new(
+            stream_id,
+            max_stream_data,
+            self.flow_mgr.clone(),
+            self.events.clone(),
+        );
+        rs.set_max_window(max(max_stream_data, self.max_stream_window));
+        rs
+    }
+
     /// Get or make a stream, and implicitly open additional streams as
     /// indicated by its stream id.
     fn obtain_stream(
@@ -2282,7 +3650,7 @@ impl Connection {
 
             if stream_idx >= *next_stream_idx {
                 let recv_initial_max_stream_data = if stream_id.is_bidi() {
-                    if stream_idx > self.indexes.local_max_stream_bidi {
+                    if stream_idx >= self.indexes.local_max_stream_bidi {
                         qwarn!(
                             [self],
                             "remote bidi stream create blocked, next={:?} max={:?}",
@@ -2301,7 +3669,7 @@ impl Connection {
                         .local
                         .get_integer(tparams::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE)
                 } else {
-                    if stream_idx > self.indexes.local_max_stream_uni {
+                    if stream_idx >= self.indexes.local_max_stream_uni {
                         qwarn!(
                             [self],
                             "remote uni stream create blocked, next={:?} max={:?}",
@@ -2319,15 +3687,8 @@ impl Connection {
                 loop {
                     let next_stream_id =
                         next_stream_idx.to_stream_id(stream_id.stream_type(), stream_id.role());
-                    self.recv_streams.insert(
-                        next_stream_id,
-                        RecvStream::new(
-                            next_stream_id,
-                            recv_initial_max_stream_data,
-                            self.flow_mgr.clone(),
-                            self.events.clone(),
-                        ),
-                    );
+                    let rs = self.new_recv_stream(next_stream_id, recv_initial_max_stream_data);
+                    self.recv_streams.insert(next_stream_id, rs);
 
                     if next_stream_id.is_uni() {
                         self.events.new_stream(next_stream_id);
@@ -2360,6 +3721,21 @@ impl Connection {
                     }
                 }
             }
+        } else {
+            // The peer can't use one of our streams before we open it.
+            let next_stream_idx = if stream_id.is_bidi() {
+                self.indexes.remote_next_stream_bidi
+            } else {
+                self.indexes.remote_next_stream_uni
+            };
+            if StreamIndex::from(stream_id) >= next_stream_idx {
+                qwarn!(
+                    [self],
+                    "peer used stream {} before it was opened",
+                    stream_id
+                );
+                return Err(Error::StreamStateError);
+            }
         }
 
         Ok((
@@ -2468,15 +3844,8 @@ impl Connection {
                     .local
                     .get_integer(tparams::INITIAL_MAX_STREAM_DATA_BIDI_LOCAL);
 
-                self.recv_streams.insert(
-                    new_id,
-                    RecvStream::new(
-                        new_id,
-                        recv_initial_max_stream_data,
-                        self.flow_mgr.clone(),
-                        self.events.clone(),
-                    ),
-                );
+                let rs = self.new_recv_stream(new_id, recv_initial_max_stream_data);
+                self.recv_streams.insert(new_id, rs);
                 new_id.as_u64()
             }
         })
@@ -2493,6 +3862,23 @@ impl Connection {
         self.send_streams.get_mut(stream_id.into())?.send(data)
     }
 
+    /// Send data on a stream from a buffer that the connection can keep.
+    /// If all of `data` can be sent, the connection takes the buffer, which
+    /// leaves `data` empty, and holds it until the data is acknowledged
+    /// rather than copying it.  Otherwise, this copies as much as it can and
+    /// removes that from the start of `data`.  To avoid copies, don't pass
+    /// more than `stream_avail_send_space` allows.
+    /// Returns how many bytes were successfully sent.
+    /// # Errors
+    /// `InvalidStreamId` the stream does not exist,
+    /// `InvalidInput` if length of `data` is zero,
+    /// `FinalSizeError` if the stream has already been closed.
+    pub fn stream_send_owned(&mut self, stream_id: u64, data: &mut Vec<u8>) -> Res<usize> {
+        self.send_streams
+            .get_mut(stream_id.into())?
+            .send_owned(data)
+    }
+
     /// Send all data or nothing on a stream. May cause DATA_BLOCKED or
     /// STREAM_DATA_BLOCKED frames to be sent.
     /// Returns true if data was successfully sent, otherwise false.
@@ -2523,6 +3909,140 @@ impl Connection {
         Ok(self.send_streams.get(stream_id.into())?.avail())
     }
 
+    /// Bytes that can be written to a stream and sent straight away.  This is
+    /// the same as `stream_avail_send_space`, except that it is also limited
+    /// by what the congestion window can take, less what is already waiting
+    /// to be sent on the stream.
+    pub fn stream_avail_send_now(&self, stream_id: u64) -> Res<usize> {
+        let stream = self.send_streams.get(stream_id.into())?;
+        let cwnd = self.loss_recovery.cwnd_avail();
+        Ok(min(stream.avail(), cwnd.saturating_sub(stream.unsent())))
+    }
+
+    /// How much the peer is allowed to send on a stream.  This fails if the stream
+    /// isn't receiving, or if it has received all the data the peer will send.
+    pub fn max_stream_data(&self, stream_id: u64) -> Res<u64> {
+        self.recv_streams
+            .get(&stream_id.into())
+            .and_then(RecvStream::max_stream_data)
+            .ok_or(Error::InvalidStreamId)
+    }
+
+    /// Let the peer send up to `max_stream_data` bytes on a stream, by sending
+    /// a MAX_STREAM_DATA frame.  The limit can only be increased.
+    pub fn set_max_stream_data(&mut self, stream_id: u64, max_stream_data: u64) -> Res<()> {
+        if max_stream_data > MAX_VARINT {
+            return Err(Error::InvalidInput);
+        }
+        self.recv_streams
+            .get_mut(&stream_id.into())
+            .ok_or(Error::InvalidStreamId)?
+            .set_max_stream_data(max_stream_data)
+    }
+
+    /// How many streams of `stream_type` the peer is allowed to open.
+    pub fn max_streams(&self, stream_type: StreamType) -> u64 {
+        match stream_type {
+            StreamType::BiDi => self.indexes.local_max_stream_bidi,
+            StreamType::UniDi => self.indexes.local_max_stream_uni,
+        }
+        .as_u64()
+    }
+
+    /// Let the peer open up to `limit` streams of `stream_type`, by sending
+    /// a MAX_STREAMS frame.  The limit can only be increased.
+    /// Before the connection starts, use `set_stream_limit` instead.
+    pub fn raise_max_streams(&mut self, stream_type: StreamType, limit: u64) -> Res<()> {
+        if limit > MAX_STREAM_LIMIT {
+            return Err(Error::InvalidInput);
+        }
+        if *self.state() == State::Init || self.state().closed() {
+            qerror!(
+                [self],
+                "Cannot raise stream limit in state {:?}",
+                self.state()
+            );
+            return Err(Error::ConnectionState);
+        }
+        let local_max = match stream_type {
+            StreamType::BiDi => &mut self.indexes.local_max_stream_bidi,
+            StreamType::UniDi => &mut self.indexes.local_max_stream_uni,
+        };
+        if limit <= local_max.as_u64() {
+            return Err(Error::InvalidInput);
+        }
+        *local_max = StreamIndex::new(limit);
+        self.flow_mgr
+            .borrow_mut()
+            .max_streams(*local_max, stream_type);
+        Ok(())
+    }
+
+    /// The largest UDP payload that the connection sends on its current path,
+    /// which is the packetization layer path MTU (PLPMTU).  This is the smallest
+    /// size that QUIC allows until there is a path.
+    pub fn plpmtu(&self) -> usize {
+        self.path.as_ref().map_or(PATH_MTU_MIN, Path::mtu)
+    }
+
+    fn packet_overhead_in(&self, space: PNSpace) -> Option<usize> {
+        let path = self.path.as_ref()?;
+        let tx = self.crypto.states.tx_ref(space);
+        let header = if space == PNSpace::ApplicationData && !tx.map_or(false, |tx| tx.is_0rtt()) {
+            PacketBuilder::short_header_len(path.remote_cid().len())
+        } else {
+            PacketBuilder::long_header_len(path.remote_cid().len(), path.local_cid().len())
+        };
+        Some(header + tx.map_or(AEAD_EXPANSION, CryptoDxState::expansion))
+    }
+
+    /// The bytes in each packet of application data that can't be used for
+    /// frames: the packet header and the expansion from packet protection.
+    /// This is for the type of packet that would be sent now, either 0-RTT
+    /// or 1-RTT.  It is `None` until the connection has a path.
+    pub fn packet_overhead(&self) -> Option<usize> {
+        self.packet_overhead_in(PNSpace::ApplicationData)
+    }
+
+    /// The same as `packet_overhead`, but for Handshake packets.
+    pub fn handshake_packet_overhead(&self) -> Option<usize> {
+        self.packet_overhead_in(PNSpace::Handshake)
+    }
+
+    /// The largest datagram that `send_datagram` accepts.  This is `None` if
+    /// the peer does not accept datagrams or if its transport parameters are
+    /// not known yet.  A datagram of this size fits in a single packet, after
+    /// allowing for `packet_overhead` on a path with an MTU of `plpmtu`.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        let tps = self.tps.borrow();
+        let remote = tps.remote.as_ref().or_else(|| tps.remote_0rtt.as_ref())?;
+        let max_frame = usize::try_from(remote.get_integer(tparams::MAX_DATAGRAM_FRAME_SIZE))
+            .unwrap_or(usize::max_value());
+        let max_packet = self.plpmtu().saturating_sub(self.packet_overhead()?);
+        let max_frame = min(max_frame, max_packet);
+        let max = max_frame.saturating_sub(QuicDatagrams::overhead(max_frame));
+        if max == 0 {
+            None
+        } else {
+            Some(max)
+        }
+    }
+
+    /// Queue a datagram for sending.  Datagrams are not retransmitted if they
+    /// are lost.  If too many datagrams are waiting to be sent the oldest is
+    /// dropped, as is a datagram that is too large for a packet.
+    /// # Errors
+    /// `NotAvailable` if the peer does not accept datagrams,
+    /// `TooMuchData` if `data` is larger than `max_datagram_size`.
+    pub fn send_datagram(&mut self, data: &[u8]) -> Res<()> {
+        let max = self.max_datagram_size().ok_or(Error::NotAvailable)?;
+        if data.len() > max {
+            return Err(Error::TooMuchData);
+        }
+        self.quic_datagrams.add(data);
+        Ok(())
+    }
+
     /// Close the stream. Enqueued data will be sent.
     pub fn stream_close_send(&mut self, stream_id: u64) -> Res<()> {
         self.send_streams.get_mut(stream_id.into())?.close();
@@ -2535,6 +4055,23 @@ impl Connection {
         Ok(())
     }
 
+    /// Give up on sending stream data that hasn't been sent within `ttl` of `now`.
+    /// If the stream hasn't sent all of its data and its FIN by then, it is reset
+    /// with `err`, which also stops anything that is lost from being sent again.
+    /// This suits data that is useless if it arrives late, like real-time media.
+    pub fn stream_set_ttl(
+        &mut self,
+        stream_id: u64,
+        ttl: Duration,
+        err: AppError,
+        now: Instant,
+    ) -> Res<()> {
+        self.send_streams
+            .get_mut(stream_id.into())?
+            .set_expiry(now + ttl, err);
+        Ok(())
+    }
+
     /// Read buffered data from stream. bool says whether read bytes includes
     /// the final data on stream.
     pub fn stream_recv(&mut self, stream_id: u64, data: &mut [u8]) -> Res<(usize, bool)> {
@@ -2579,7 +4116,24 @@ impl Connection {
 
 impl ::std::fmt::Display for Connection {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "{:?} {:p}", self.role, self as *const Self)
+        write!(f, "{:?} {:p}", self.role, self as *const Self)?;
+        // Include the local connection ID so that log lines from different
+        // connections on the same server can be told apart.
+        if let Some(path) = &self.path {
+            write!(f, " {}", path.local_cid())?;
+        }
+        // The packet number space that is being sent in, which is the
+        // last one with keys.
+        if let Some(space) = PNSpace::iter()
+            .filter(|&&s| self.crypto.states.tx_ref(s).is_some())
+            .last()
+        {
+            write!(f, " {}", space)?;
+        }
+        if self.qlog.is_some() {
+            f.write_str(" qlog")?;
+        }
+        Ok(())
     }
 }
 
@@ -2588,6 +4142,7 @@ mod tests {
     use super::*;
     use crate::cc::PACING_BURST_SIZE;
     use crate::cc::{INITIAL_CWND_PKTS, MIN_CONG_WINDOW};
+    use crate::crypto::MAX_CRYPTO_BUFFER;
     use crate::frame::{CloseError, StreamType};
     use crate::packet::PACKET_BIT_LONG;
     use crate::path::PATH_MTU_V6;
@@ -2595,9 +4150,13 @@ mod tests {
     use crate::recovery::PTO_PACKET_COUNT;
     use crate::tracking::{ACK_DELAY, MAX_UNACKED_PKTS};
     use std::convert::TryInto;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 
-    use neqo_common::matches;
-    use neqo_crypto::constants::TLS_CHACHA20_POLY1305_SHA256;
+    use neqo_common::{matches, IPTOS_ECN_CE, IPTOS_ECN_ECT0, IPTOS_ECN_NOT_ECT};
+    use neqo_crypto::constants::{
+        TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256,
+    };
+    use neqo_crypto::ech;
     use std::mem;
     use test_fixture::{self, assertions, fixture_init, loopback, now};
 
@@ -2615,7 +4174,7 @@ mod tests {
         Connection::new_client(
             test_fixture::DEFAULT_SERVER_NAME,
             test_fixture::DEFAULT_ALPN,
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3).unwrap())),
             loopback(),
             loopback(),
             QuicVersion::default(),
@@ -2629,7 +4188,7 @@ mod tests {
             test_fixture::DEFAULT_KEYS,
             test_fixture::DEFAULT_ALPN,
             &test_fixture::anti_replay(),
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(5).unwrap())),
             QuicVersion::default(),
         )
         .expect("create a default server")
@@ -2961,7 +4520,7 @@ mod tests {
         let mut client = Connection::new_client(
             "example.com",
             &["bad-alpn"],
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(9))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(9).unwrap())),
             loopback(),
             loopback(),
             QuicVersion::default(),
@@ -2976,6 +4535,63 @@ mod tests {
         assert_error(&server, ConnectionError::Transport(Error::CryptoAlert(120)));
     }
 
+    const ECH_CONFIG_ID: u8 = 7;
+    const ECH_PUBLIC_NAME: &str = "public.example";
+
+    fn ech_server() -> Connection {
+        let mut server = default_server();
+        let (sk, pk) = ech::generate_keys().expect("should make ECH keys");
+        server
+            .server_enable_ech(ECH_CONFIG_ID, ECH_PUBLIC_NAME, &sk, &pk)
+            .expect("should enable ECH");
+        server
+    }
+
+    #[test]
+    fn ech_accepted() {
+        let mut server = ech_server();
+        let mut client = default_client();
+        client
+            .client_enable_ech(server.ech_config())
+            .expect("should enable ECH");
+
+        let mut dgram = client.process(None, now()).dgram();
+        while *client.state() != State::Connected {
+            dgram = server.process(dgram, now()).dgram();
+            let _ = maybe_authenticate(&mut client);
+            dgram = client.process(dgram, now()).dgram();
+        }
+        assert!(client.events().any(|e| e == ConnectionEvent::EchAccepted));
+
+        let _ = server.process(dgram, now());
+        assert!(client.tls_info().unwrap().ech_accepted());
+        assert!(server.tls_info().unwrap().ech_accepted());
+    }
+
+    #[test]
+    fn ech_rejected() {
+        let mut server = ech_server();
+        // The client has a configuration that the server doesn't know about.
+        let (_, pk) = ech::generate_keys().expect("should make ECH keys");
+        let config = ech::encode_config(ECH_CONFIG_ID + 1, ECH_PUBLIC_NAME, &pk)
+            .expect("should encode ECH config");
+        let mut client = default_client();
+        client
+            .client_enable_ech(&config)
+            .expect("should enable ECH");
+
+        handshake(&mut client, &mut server, now(), Duration::new(0, 0));
+        let retry_configs = server.ech_config().to_vec();
+        assert!(!retry_configs.is_empty());
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::EchRetry(retry_configs.clone())),
+        );
+        assert!(client
+            .events()
+            .any(|e| e == ConnectionEvent::EchRejected(retry_configs.clone())));
+    }
+
     #[test]
     fn test_dup_server_flight1() {
         qdebug!("---- client: generate CH");
@@ -3021,6 +4637,32 @@ mod tests {
         assert_eq!(1, client.stats().dropped_rx);
     }
 
+    /// The server only discards Initial keys once it has processed a Handshake
+    /// packet, which means that the packet has to be authenticated.
+    #[test]
+    fn discard_initial_after_valid_handshake() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let out = client.process(None, now()).dgram();
+        let out = server.process(out, now()).dgram();
+        let _ = client.process(out, now());
+        assert!(maybe_authenticate(&mut client));
+        // The client sends its Finished in a Handshake packet, which comes first.
+        let fin = client.process(None, now()).dgram().unwrap();
+
+        let mut damaged = fin.to_vec();
+        damaged[30] ^= 0xff;
+        let damaged = Datagram::new(fin.source(), fin.destination(), damaged);
+        let _ = server.process(Some(damaged), now());
+        assert!(server.crypto.states.rx(PNSpace::Initial, false).is_some());
+        assert!(server.acks.get_mut(PNSpace::Initial).is_some());
+
+        let _ = server.process(Some(fin), now());
+        assert!(server.crypto.states.rx(PNSpace::Initial, false).is_none());
+        assert!(server.acks.get_mut(PNSpace::Initial).is_none());
+        assert!(server.acks.get_mut(PNSpace::Handshake).is_some());
+    }
+
     fn exchange_ticket(client: &mut Connection, server: &mut Connection, now: Instant) -> Vec<u8> {
         server.send_ticket(now, &[]).expect("can send ticket");
         let ticket = server.process_output(now).dgram();
@@ -3057,123 +4699,550 @@ mod tests {
     }
 
     #[test]
-    fn resume() {
+    fn state_order() {
+        let error = ConnectionError::Application(0);
+        let states = [
+            State::Init,
+            State::WaitInitial,
+            State::Handshaking,
+            State::Connected,
+            State::Confirmed,
+            State::Closing {
+                error: error.clone(),
+                timeout: now(),
+            },
+            State::Draining {
+                error: error.clone(),
+                timeout: now(),
+            },
+            State::Closed(error),
+        ];
+        for (i, a) in states.iter().enumerate() {
+            for (j, b) in states.iter().enumerate() {
+                assert_eq!(a.partial_cmp(b), Some(i.cmp(&j)), "{:?} {:?}", a, b);
+            }
+            assert_eq!(a.connected(), i == 3 || i == 4);
+            assert_eq!(a.closed(), i >= 5);
+        }
+    }
+
+    /// Handle events, saving any new state and authenticating when asked.
+    fn save_states(c: &mut Connection, states: &mut Vec<State>) {
+        while let Some(e) = c.next_event() {
+            match e {
+                ConnectionEvent::StateChange(s) => states.push(s),
+                ConnectionEvent::AuthenticationNeeded => {
+                    c.authenticated(AuthenticationStatus::Ok, now());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn state_change_events() {
         let mut client = default_client();
         let mut server = default_server();
-        connect(&mut client, &mut server);
+        let mut client_states = Vec::new();
+        let mut server_states = Vec::new();
+
+        let mut dgram = None;
+        while !client.state().closed() && *client.state() != State::Confirmed {
+            save_states(&mut client, &mut client_states);
+            dgram = client.process(dgram, now()).dgram();
+            save_states(&mut server, &mut server_states);
+            dgram = server.process(dgram, now()).dgram();
+        }
+        save_states(&mut client, &mut client_states);
+        save_states(&mut server, &mut server_states);
+
+        let handshake = [
+            State::WaitInitial,
+            State::Handshaking,
+            State::Connected,
+            State::Confirmed,
+        ];
+        assert_eq!(client_states, handshake);
+        assert_eq!(server_states, handshake);
+
+        client.close(now(), 0, "");
+        let dgram = client.process(None, now()).dgram();
+        let _ = server.process(dgram, now());
+        save_states(&mut client, &mut client_states);
+        save_states(&mut server, &mut server_states);
+        assert!(matches!(client_states[4], State::Closing { .. }));
+        assert!(matches!(server_states[4], State::Draining { .. }));
+    }
 
-        let token = exchange_ticket(&mut client, &mut server, now());
+    #[test]
+    fn connection_id_limit() {
         let mut client = default_client();
-        client
-            .set_resumption_token(now(), &token[..])
-            .expect("should set token");
         let mut server = default_server();
         connect(&mut client, &mut server);
-        assert!(client.crypto.tls.info().unwrap().resumed());
-        assert!(server.crypto.tls.info().unwrap().resumed());
+
+        let new_cid = |sequence_number, retire_prior| Frame::NewConnectionId {
+            sequence_number,
+            retire_prior,
+            connection_id: vec![1, 2, 3, 4, 5],
+            stateless_reset_token: [0; 16],
+        };
+        // The default limit is 2, and that includes the connection ID in use,
+        // so one more is fine, even if it is repeated.
+        client
+            .input_frame(PacketType::Short, new_cid(1, 0), now())
+            .unwrap();
+        client
+            .input_frame(PacketType::Short, new_cid(1, 0), now())
+            .unwrap();
+        assert_eq!(client.connection_ids.len(), 1);
+        assert_eq!(
+            client.input_frame(PacketType::Short, new_cid(2, 0), now()),
+            Err(Error::ConnectionIdLimitError)
+        );
+        assert_eq!(Error::ConnectionIdLimitError.code(), 9);
+    }
+
+    /// Take the RETIRE_CONNECTION_ID frames that are queued, in order.
+    fn retired_cids(c: &mut Connection) -> Vec<u64> {
+        let mut retired = Vec::new();
+        while let Some(f) = c.flow_mgr.borrow_mut().next() {
+            if let Frame::RetireConnectionId { sequence_number } = f {
+                retired.push(sequence_number);
+            }
+        }
+        retired.sort_unstable();
+        retired
     }
 
     #[test]
-    fn remember_smoothed_rtt() {
+    fn retire_prior_to() {
         let mut client = default_client();
         let mut server = default_server();
+        connect(&mut client, &mut server);
 
-        const RTT1: Duration = Duration::from_millis(130);
-        let now = connect_with_rtt(&mut client, &mut server, now(), RTT1);
-        assert_eq!(client.loss_recovery.rtt(), RTT1);
+        let new_cid = |sequence_number, retire_prior| Frame::NewConnectionId {
+            sequence_number,
+            retire_prior,
+            connection_id: vec![1, 2, 3, 4, 5],
+            stateless_reset_token: [0; 16],
+        };
+        client
+            .input_frame(PacketType::Short, new_cid(1, 0), now())
+            .unwrap();
+        client
+            .input_frame(PacketType::Short, new_cid(2, 2), now())
+            .unwrap();
+        assert_eq!(retired_cids(&mut client), vec![0, 1]);
+        // One that is already retired is retired again.
+        client
+            .input_frame(PacketType::Short, new_cid(1, 0), now())
+            .unwrap();
+        assert_eq!(retired_cids(&mut client), vec![1]);
+        assert!(client.connection_ids.is_empty());
 
-        let token = exchange_ticket(&mut client, &mut server, now);
-        let mut client = default_client();
-        let mut server = default_server();
-        client.set_resumption_token(now, &token[..]).unwrap();
+        // Retiring made room for another.
+        client
+            .input_frame(PacketType::Short, new_cid(3, 0), now())
+            .unwrap();
         assert_eq!(
-            client.loss_recovery.rtt(),
-            RTT1,
-            "client should remember previous RTT"
+            client.input_frame(PacketType::Short, new_cid(4, 0), now()),
+            Err(Error::ConnectionIdLimitError)
         );
-
-        const RTT2: Duration = Duration::from_millis(70);
-        connect_with_rtt(&mut client, &mut server, now, RTT2);
         assert_eq!(
-            client.loss_recovery.rtt(),
-            RTT2,
-            "previous RTT should be completely erased"
+            client.input_frame(PacketType::Short, new_cid(4, 5), now()),
+            Err(Error::FrameEncodingError)
         );
     }
 
     #[test]
-    fn zero_rtt_negotiate() {
-        // Note that the two servers in this test will get different anti-replay filters.
-        // That's OK because we aren't testing anti-replay.
+    fn retire_prior_to_in_use() {
         let mut client = default_client();
         let mut server = default_server();
         connect(&mut client, &mut server);
 
-        let token = exchange_ticket(&mut client, &mut server, now());
-        let mut client = default_client();
         client
-            .set_resumption_token(now(), &token[..])
-            .expect("should set token");
-        let mut server = default_server();
-        connect(&mut client, &mut server);
-        assert!(client.crypto.tls.info().unwrap().early_data_accepted());
-        assert!(server.crypto.tls.info().unwrap().early_data_accepted());
+            .input_frame(
+                PacketType::Short,
+                Frame::NewConnectionId {
+                    sequence_number: 1,
+                    retire_prior: 1,
+                    connection_id: vec![7, 7, 7, 7],
+                    stateless_reset_token: [7; 16],
+                },
+                now(),
+            )
+            .unwrap();
+        // The connection ID in use was retired, so the new one is used.
+        assert_eq!(retired_cids(&mut client), vec![0]);
+        assert_eq!(client.remote_cid_seqno, 1);
+        let path = client.path.as_ref().unwrap();
+        assert_eq!(&path.remote_cid()[..], &[7, 7, 7, 7]);
+        assert_eq!(path.reset_token(), Some(&[7; 16]));
+
+        // Packets go to the new connection ID.
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[1]).unwrap();
+        let dgram = client.process(None, now()).dgram().unwrap();
+        assert_eq!(&dgram[1..5], &[7, 7, 7, 7]);
     }
 
     #[test]
-    fn zero_rtt_send_recv() {
+    fn retire_unknown_cid() {
         let mut client = default_client();
         let mut server = default_server();
         connect(&mut client, &mut server);
 
-        let token = exchange_ticket(&mut client, &mut server, now());
-        let mut client = default_client();
-        client
-            .set_resumption_token(now(), &token[..])
-            .expect("should set token");
-        let mut server = default_server();
-
-        // Send ClientHello.
-        let client_hs = client.process(None, now());
-        assert!(client_hs.as_dgram_ref().is_some());
-
-        // Now send a 0-RTT packet.
-        let client_stream_id = client.stream_create(StreamType::UniDi).unwrap();
-        client.stream_send(client_stream_id, &[1, 2, 3]).unwrap();
-        let client_0rtt = client.process(None, now());
-        assert!(client_0rtt.as_dgram_ref().is_some());
-        // 0-RTT packets on their own shouldn't be padded to 1200.
-        assert!(client_0rtt.as_dgram_ref().unwrap().len() < 1200);
-
-        let server_hs = server.process(client_hs.dgram(), now());
-        assert!(server_hs.as_dgram_ref().is_some()); // ServerHello, etc...
-        let server_process_0rtt = server.process(client_0rtt.dgram(), now());
-        assert!(server_process_0rtt.as_dgram_ref().is_none());
+        // Only the connection ID from the handshake was given out.
+        assert_eq!(
+            server.input_frame(
+                PacketType::Short,
+                Frame::RetireConnectionId { sequence_number: 1 },
+                now()
+            ),
+            Err(Error::ProtocolViolation)
+        );
+        server
+            .input_frame(
+                PacketType::Short,
+                Frame::RetireConnectionId { sequence_number: 0 },
+                now(),
+            )
+            .unwrap();
+    }
 
-        let server_stream_id = server
-            .events()
-            .find_map(|evt| match evt {
-                ConnectionEvent::NewStream { stream_id, .. } => Some(stream_id),
-                _ => None,
-            })
-            .expect("should have received a new stream event");
-        assert_eq!(client_stream_id, server_stream_id.as_u64());
+    /// Makes connection IDs like `FixedConnectionIdManager`, with reset tokens
+    /// that can be made again from the connection ID.
+    struct DerivedResetTokens;
+    impl ConnectionIdDecoder for DerivedResetTokens {
+        fn decode_cid<'a>(&self, dec: &mut Decoder<'a>) -> Option<ConnectionIdRef<'a>> {
+            dec.decode(8).map(ConnectionIdRef::from)
+        }
+    }
+    impl ConnectionIdManager for DerivedResetTokens {
+        fn generate_cid(&mut self) -> ConnectionId {
+            ConnectionId::generate(8)
+        }
+        fn as_decoder(&self) -> &dyn ConnectionIdDecoder {
+            self
+        }
+        fn reset_token(&self, cid: &[u8]) -> [u8; 16] {
+            let mut token = [0xff; 16];
+            token[..cid.len()].copy_from_slice(cid);
+            token
+        }
     }
 
     #[test]
-    fn zero_rtt_send_coalesce() {
-        let mut client = default_client();
-        let mut server = default_server();
-        connect(&mut client, &mut server);
-
-        let token = exchange_ticket(&mut client, &mut server, now());
+    fn issued_cid_reset_tokens() {
         let mut client = default_client();
         client
-            .set_resumption_token(now(), &token[..])
-            .expect("should set token");
-        let mut server = default_server();
-
-        // Write 0-RTT before generating any packets.
+            .set_migration_policy(MigrationPolicy::Active)
+            .unwrap();
+        let mut server = Connection::new_server(
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            &test_fixture::anti_replay(),
+            Rc::new(RefCell::new(DerivedResetTokens)),
+            QuicVersion::default(),
+        )
+        .unwrap();
+        server
+            .set_migration_policy(MigrationPolicy::PassiveOnly)
+            .unwrap();
+        connect(&mut client, &mut server);
+
+        // The tokens in NEW_CONNECTION_ID come from the connection ID manager.
+        assert!(!client.connection_ids.is_empty());
+        for (cid, token) in client.connection_ids.values() {
+            assert_eq!(*token, DerivedResetTokens.reset_token(cid));
+        }
+    }
+
+    #[test]
+    fn connection_id_changed() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let new_cid = |connection_id, stateless_reset_token| Frame::NewConnectionId {
+            sequence_number: 1,
+            retire_prior: 0,
+            connection_id,
+            stateless_reset_token,
+        };
+        client
+            .input_frame(PacketType::Short, new_cid(vec![1, 2, 3], [0; 16]), now())
+            .unwrap();
+        assert_eq!(
+            client.input_frame(PacketType::Short, new_cid(vec![1, 2, 4], [0; 16]), now()),
+            Err(Error::ProtocolViolation)
+        );
+        assert_eq!(
+            client.input_frame(PacketType::Short, new_cid(vec![1, 2, 3], [1; 16]), now()),
+            Err(Error::ProtocolViolation)
+        );
+    }
+
+    /// Connect with connection IDs of the given lengths, then send data both ways.
+    fn connect_with_cid_lengths(client_len: usize, server_len: usize) {
+        fixture_init();
+        let mut client = Connection::new_client(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(
+                FixedConnectionIdManager::new(client_len).unwrap(),
+            )),
+            loopback(),
+            loopback(),
+            QuicVersion::default(),
+        )
+        .expect("create a client");
+        let mut server = Connection::new_server(
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            &test_fixture::anti_replay(),
+            Rc::new(RefCell::new(
+                FixedConnectionIdManager::new(server_len).unwrap(),
+            )),
+            QuicVersion::default(),
+        )
+        .expect("create a server");
+        connect(&mut client, &mut server);
+        assert_eq!(client.path.as_ref().unwrap().remote_cid().len(), server_len);
+        assert_eq!(server.path.as_ref().unwrap().remote_cid().len(), client_len);
+
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, &[6; 100]).unwrap();
+        let dgram = client.process(None, now()).dgram();
+        server.process_input(dgram.unwrap(), now());
+        let mut buf = [0; 100];
+        let (received, _) = server.stream_recv(stream_id, &mut buf).unwrap();
+        assert_eq!(received, 100);
+
+        server.stream_send(stream_id, &[7; 100]).unwrap();
+        let dgram = server.process(None, now()).dgram();
+        client.process_input(dgram.unwrap(), now());
+        let (received, _) = client.stream_recv(stream_id, &mut buf).unwrap();
+        assert_eq!(received, 100);
+    }
+
+    #[test]
+    fn zero_length_client_cid() {
+        connect_with_cid_lengths(0, 8);
+    }
+
+    #[test]
+    fn max_length_cids() {
+        connect_with_cid_lengths(MAX_CONNECTION_ID_LEN, MAX_CONNECTION_ID_LEN);
+    }
+
+    #[test]
+    fn short_cids() {
+        connect_with_cid_lengths(1, 1);
+    }
+
+    #[test]
+    fn cid_too_long() {
+        assert!(FixedConnectionIdManager::new(MAX_CONNECTION_ID_LEN).is_ok());
+        assert_eq!(
+            FixedConnectionIdManager::new(MAX_CONNECTION_ID_LEN + 1).err(),
+            Some(Error::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn new_connection_id_for_zero_length_cid() {
+        fixture_init();
+        let mut client = Connection::new_client(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0).unwrap())),
+            loopback(),
+            loopback(),
+            QuicVersion::default(),
+        )
+        .expect("create a client");
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // The server only has a zero-length connection ID for the client.
+        let frame = Frame::NewConnectionId {
+            sequence_number: 1,
+            retire_prior: 0,
+            connection_id: vec![1, 2, 3, 4, 5],
+            stateless_reset_token: [0; 16],
+        };
+        assert_eq!(
+            server.input_frame(PacketType::Short, frame, now()),
+            Err(Error::ProtocolViolation)
+        );
+    }
+
+    #[test]
+    fn crypto_buffer_exceeded() {
+        let mut client = default_client();
+        let _ = client.process(None, now());
+
+        let crypto = |offset| Frame::Crypto {
+            offset,
+            data: vec![0; 16],
+        };
+        // Out of order, but within the limit.
+        client
+            .input_frame(PacketType::Initial, crypto(100), now())
+            .unwrap();
+        assert_eq!(
+            client.input_frame(PacketType::Initial, crypto(MAX_CRYPTO_BUFFER), now()),
+            Err(Error::CryptoBufferExceeded)
+        );
+        assert_eq!(Error::CryptoBufferExceeded.code(), 13);
+    }
+
+    #[test]
+    fn resume() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server, now());
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert!(client.crypto.tls.info().unwrap().resumed());
+        assert!(server.crypto.tls.info().unwrap().resumed());
+    }
+
+    #[test]
+    fn resumption_token_event() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let resumption_token = |e| matches!(e, ConnectionEvent::ResumptionToken(_));
+        assert!(!client.events().any(resumption_token));
+
+        let _ = exchange_ticket(&mut client, &mut server, now());
+        let token = client
+            .events()
+            .find_map(|e| match e {
+                ConnectionEvent::ResumptionToken(token) => Some(token),
+                _ => None,
+            })
+            .expect("should have a resumption token event");
+        assert!(!server.events().any(resumption_token));
+
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert!(client.crypto.tls.info().unwrap().resumed());
+    }
+
+    #[test]
+    fn remember_smoothed_rtt() {
+        let mut client = default_client();
+        let mut server = default_server();
+
+        const RTT1: Duration = Duration::from_millis(130);
+        let now = connect_with_rtt(&mut client, &mut server, now(), RTT1);
+        assert_eq!(client.loss_recovery.rtt(), RTT1);
+
+        let token = exchange_ticket(&mut client, &mut server, now);
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_resumption_token(now, &token[..]).unwrap();
+        assert_eq!(
+            client.loss_recovery.rtt(),
+            RTT1,
+            "client should remember previous RTT"
+        );
+
+        const RTT2: Duration = Duration::from_millis(70);
+        connect_with_rtt(&mut client, &mut server, now, RTT2);
+        assert_eq!(
+            client.loss_recovery.rtt(),
+            RTT2,
+            "previous RTT should be completely erased"
+        );
+    }
+
+    #[test]
+    fn zero_rtt_negotiate() {
+        // Note that the two servers in this test will get different anti-replay filters.
+        // That's OK because we aren't testing anti-replay.
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server, now());
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert!(client.crypto.tls.info().unwrap().early_data_accepted());
+        assert!(server.crypto.tls.info().unwrap().early_data_accepted());
+    }
+
+    #[test]
+    fn zero_rtt_send_recv() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server, now());
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        let mut server = default_server();
+
+        // Send ClientHello.
+        let client_hs = client.process(None, now());
+        assert!(client_hs.as_dgram_ref().is_some());
+
+        // Now send a 0-RTT packet.
+        let client_stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(client_stream_id, &[1, 2, 3]).unwrap();
+        let client_0rtt = client.process(None, now());
+        assert!(client_0rtt.as_dgram_ref().is_some());
+        // 0-RTT packets on their own shouldn't be padded to 1200.
+        assert!(client_0rtt.as_dgram_ref().unwrap().len() < 1200);
+
+        let server_hs = server.process(client_hs.dgram(), now());
+        assert!(server_hs.as_dgram_ref().is_some()); // ServerHello, etc...
+        let server_process_0rtt = server.process(client_0rtt.dgram(), now());
+        assert!(server_process_0rtt.as_dgram_ref().is_none());
+
+        let server_stream_id = server
+            .events()
+            .find_map(|evt| match evt {
+                ConnectionEvent::NewStream { stream_id, .. } => Some(stream_id),
+                _ => None,
+            })
+            .expect("should have received a new stream event");
+        assert_eq!(client_stream_id, server_stream_id.as_u64());
+    }
+
+    #[test]
+    fn zero_rtt_send_coalesce() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let token = exchange_ticket(&mut client, &mut server, now());
+        let mut client = default_client();
+        client
+            .set_resumption_token(now(), &token[..])
+            .expect("should set token");
+        let mut server = default_server();
+
+        // Write 0-RTT before generating any packets.
         // This should result in a datagram that coalesces Initial and 0-RTT.
         let client_stream_id = client.stream_create(StreamType::UniDi).unwrap();
         client.stream_send(client_stream_id, &[1, 2, 3]).unwrap();
@@ -3220,7 +5289,7 @@ mod tests {
             test_fixture::DEFAULT_KEYS,
             test_fixture::DEFAULT_ALPN,
             &ar,
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(10).unwrap())),
             QuicVersion::default(),
         )
         .unwrap();
@@ -3428,18 +5497,162 @@ mod tests {
     }
 
     #[test]
-    fn idle_send_packet1() {
+    fn handshake_timeout() {
+        const TIMEOUT: Duration = Duration::from_millis(10);
+        let mut client = default_client();
+        client.set_handshake_timeout(TIMEOUT).unwrap();
+        let dgram = client.process(None, now()).dgram();
+        assert!(dgram.is_some()); // This is dropped.
+
+        // The handshake timer is shorter than the PTO.
+        assert_eq!(client.process(None, now()), Output::Callback(TIMEOUT));
+        assert_eq!(client.process(None, now() + TIMEOUT), Output::None);
+        let error = ConnectionError::Transport(Error::HandshakeTimeout);
+        assert_eq!(*client.state(), State::Closed(error.clone()));
+        assert!(client
+            .events()
+            .any(|e| e == ConnectionEvent::StateChange(State::Closed(error.clone()))));
+    }
+
+    #[test]
+    fn handshake_timeout_after_connect() {
+        const TIMEOUT: Duration = Duration::from_millis(10);
         let mut client = default_client();
         let mut server = default_server();
-        connect_force_idle(&mut client, &mut server);
+        client.set_handshake_timeout(TIMEOUT).unwrap();
+        server.set_handshake_timeout(TIMEOUT).unwrap();
+        connect(&mut client, &mut server);
 
-        let now = now();
+        // The handshake is done, so the timeout doesn't apply.
+        let _ = client.process(None, now() + TIMEOUT * 2);
+        let _ = server.process(None, now() + TIMEOUT * 2);
+        assert!(!client.state().closed());
+        assert!(!server.state().closed());
 
-        let res = client.process(None, now);
-        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
+        assert_eq!(
+            client.set_handshake_timeout(TIMEOUT),
+            Err(Error::ConnectionState)
+        );
+    }
 
-        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
-        assert_eq!(client.stream_send(2, b"hello").unwrap(), 5);
+    #[test]
+    fn configured_max_ack_delay() {
+        const DELAY: Duration = Duration::from_millis(5);
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_max_ack_delay(DELAY).unwrap();
+        connect_force_idle(&mut client, &mut server);
+        assert_eq!(
+            server
+                .tps
+                .borrow()
+                .remote()
+                .get_integer(tparams::MAX_ACK_DELAY),
+            5
+        );
+
+        // The client waits for the delay it advertised before sending an ACK.
+        let dgram = send_something(&mut server, now());
+        assert_eq!(client.process(Some(dgram), now()), Output::Callback(DELAY));
+        assert!(client.process(None, now() + DELAY).dgram().is_some());
+    }
+
+    #[test]
+    fn ack_every_packet() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_ack_every(1).unwrap();
+        connect_force_idle(&mut client, &mut server);
+
+        let dgram = send_something(&mut server, now());
+        assert!(client.process(Some(dgram), now()).dgram().is_some());
+    }
+
+    #[test]
+    fn invalid_ack_settings() {
+        let mut client = default_client();
+        assert_eq!(
+            client.set_max_ack_delay(Duration::from_millis(MAX_ACK_DELAY_LIMIT)),
+            Err(Error::InvalidInput)
+        );
+        assert_eq!(client.set_ack_every(0), Err(Error::InvalidInput));
+        assert_eq!(
+            client.set_ack_delay_exponent(MAX_ACK_DELAY_EXPONENT + 1),
+            Err(Error::InvalidInput)
+        );
+
+        let _ = client.process(None, now());
+        assert_eq!(
+            client.set_max_ack_delay(Duration::from_millis(5)),
+            Err(Error::ConnectionState)
+        );
+        assert_eq!(
+            client.set_ack_delay_exponent(0),
+            Err(Error::ConnectionState)
+        );
+    }
+
+    /// Each side decodes the ACK delay with the exponent that the other chose.
+    #[test]
+    fn asymmetric_ack_delay_exponent() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_ack_delay_exponent(0).unwrap();
+        server
+            .set_ack_delay_exponent(MAX_ACK_DELAY_EXPONENT)
+            .unwrap();
+        connect_force_idle(&mut client, &mut server);
+
+        assert_eq!(
+            server
+                .tps
+                .borrow()
+                .remote()
+                .get_integer(tparams::ACK_DELAY_EXPONENT),
+            0
+        );
+        assert_eq!(
+            server.loss_recovery.ack_delay(PNSpace::ApplicationData, 5),
+            Duration::from_micros(5)
+        );
+        assert_eq!(
+            client.loss_recovery.ack_delay(PNSpace::ApplicationData, 5),
+            Duration::from_micros(5 << 20)
+        );
+        // The handshake always uses the default.
+        assert_eq!(
+            client.loss_recovery.ack_delay(PNSpace::Handshake, 5),
+            Duration::from_micros(40)
+        );
+
+        // The client encodes its delay in microseconds.
+        let dgram = send_something(&mut server, now());
+        let delay = client.process(Some(dgram), now()).callback();
+        let ack = client.process(None, now() + delay).dgram().unwrap();
+        let frames = server.test_process_input(ack, now() + delay);
+        let ack_delay = frames
+            .iter()
+            .find_map(|(f, _)| match f {
+                Frame::Ack { ack_delay, .. } => Some(*ack_delay),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(u128::from(ack_delay), delay.as_micros());
+    }
+
+    #[test]
+    fn idle_send_packet1() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        let now = now();
+
+        let res = client.process(None, now);
+        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT));
+
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        assert_eq!(client.stream_send(2, b"hello").unwrap(), 5);
 
         let out = client.process(None, now + Duration::from_secs(10));
         let out = server.process(out.dgram(), now + Duration::from_secs(10));
@@ -3522,6 +5735,28 @@ mod tests {
         assert!(matches!(client.state(), State::Closed(_)));
     }
 
+    #[test]
+    fn ping_keeps_alive() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(client.send_ping(), Err(Error::NotConnected));
+        connect_force_idle(&mut client, &mut server);
+        assert_eq!(client.idle_timeout(), LOCAL_IDLE_TIMEOUT);
+
+        let now = now() + Duration::from_secs(20);
+        client.send_ping().unwrap();
+        let ping = client.process(None, now).dgram();
+        let frames = server.test_process_input(ping.unwrap(), now);
+        assert_eq!(frames, vec![(Frame::Ping, PNSpace::ApplicationData)]);
+
+        // The acknowledgment resets the idle timer.
+        let ack = server.process(None, now + ACK_DELAY).dgram();
+        assert!(ack.is_some());
+        let _ = client.process(ack, now + ACK_DELAY);
+        let _ = client.process(None, now + LOCAL_IDLE_TIMEOUT);
+        assert!(matches!(client.state(), State::Confirmed));
+    }
+
     #[test]
     fn max_data() {
         let mut client = default_client();
@@ -3579,7 +5814,7 @@ mod tests {
             test_fixture::LONG_CERT_KEYS,
             test_fixture::DEFAULT_ALPN,
             &test_fixture::anti_replay(),
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(6))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(6).unwrap())),
             QuicVersion::default(),
         )
         .expect("create a server");
@@ -3719,6 +5954,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn stream_ttl_expired() {
+        const TTL: Duration = Duration::from_millis(10);
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0; 10]).unwrap();
+        client.stream_set_ttl(stream_id, TTL, 77, now()).unwrap();
+
+        // Nothing is sent before the deadline, so the stream is reset instead.
+        let out = client.process(None, now() + TTL).dgram();
+        let _ = server.process(out, now() + TTL);
+        let events = server.events().collect::<Vec<_>>();
+        assert!(events.iter().any(|e| *e
+            == ConnectionEvent::RecvStreamReset {
+                stream_id,
+                app_error: 77
+            }));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, ConnectionEvent::RecvStreamReadable { .. })));
+        assert_eq!(
+            client.stream_send(stream_id, &[0]),
+            Err(Error::FinalSizeError)
+        );
+    }
+
+    #[test]
+    fn stream_ttl_all_sent() {
+        const TTL: Duration = Duration::from_millis(10);
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0; 10]).unwrap();
+        client.stream_close_send(stream_id).unwrap();
+        client.stream_set_ttl(stream_id, TTL, 77, now()).unwrap();
+        let out = client.process(None, now()).dgram();
+        assert!(out.is_some());
+        let _ = server.process(out, now());
+
+        // Everything was sent in time, so the stream isn't reset.
+        let _ = client.process(None, now() + TTL);
+        assert!(client.flow_mgr.borrow().peek().is_none());
+        let mut buf = [0; 16];
+        assert_eq!(server.stream_recv(stream_id, &mut buf), Ok((10, true)));
+    }
+
     #[test]
     fn test_client_fin_reorder() {
         let mut client = default_client();
@@ -3750,7 +6036,8 @@ mod tests {
 
         // Now stream data gets before client_fin
         let server_out = server.process(client_stream_data.dgram(), now());
-        assert!(server_out.as_dgram_ref().is_none()); // the packet will be discarded
+        assert!(server_out.as_dgram_ref().is_none()); // the packet will be saved
+        assert_eq!(server.saved_datagrams.len(), 1);
 
         assert_eq!(*server.state(), State::Handshaking);
         let server_out = server.process(client_fin.dgram(), now());
@@ -4352,6 +6639,70 @@ mod tests {
         assert!(client.loss_recovery.cwnd_avail() < ACK_ONLY_SIZE_LIMIT);
     }
 
+    #[test]
+    fn process_multiple_input() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        let cwnd_avail = client.loss_recovery.cwnd_avail();
+        assert_eq!(client.stream_send(stream_id, &[7; 5000]).unwrap(), 5000);
+        let dgrams: Vec<_> = std::iter::from_fn(|| client.process_output(now()).dgram()).collect();
+        assert!(dgrams.len() > 2);
+
+        // All of the datagrams are acknowledged at once.
+        server.process_multiple_input(dgrams, now());
+        let ack = server.process_output(now()).dgram();
+        assert!(ack.is_some());
+        assert!(server.process_output(now()).dgram().is_none());
+        let mut buf = [0; 6000];
+        assert_eq!(
+            server.stream_recv(stream_id, &mut buf).unwrap(),
+            (5000, false)
+        );
+
+        // Nothing is left in flight.
+        client.process_input(ack.unwrap(), now());
+        assert!(client.loss_recovery.cwnd_avail() >= cwnd_avail);
+    }
+
+    #[test]
+    fn stream_avail_send_now() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        let space = client.stream_avail_send_space(stream_id).unwrap();
+        let avail = client.stream_avail_send_now(stream_id).unwrap();
+        assert_eq!(avail, client.loss_recovery.cwnd_avail());
+        assert!(avail < space);
+
+        // Anything written, but not yet sent, comes out of the congestion window.
+        assert_eq!(client.stream_send(stream_id, &[0; 1000]).unwrap(), 1000);
+        assert_eq!(
+            client.stream_avail_send_now(stream_id).unwrap(),
+            avail - 1000
+        );
+        let rest = vec![0; avail - 1000];
+        assert_eq!(client.stream_send(stream_id, &rest).unwrap(), rest.len());
+        assert_eq!(client.stream_avail_send_now(stream_id).unwrap(), 0);
+
+        // Sending doesn't add any space, but getting the data acknowledged does.
+        let mut dgrams = Vec::new();
+        while let Some(d) = client.process_output(now()).dgram() {
+            dgrams.push(d);
+        }
+        assert_eq!(client.stream_avail_send_now(stream_id).unwrap(), 0);
+        for d in dgrams {
+            server.process_input(d, now());
+        }
+        let ack = server.process_output(now()).dgram();
+        client.process_input(ack.unwrap(), now());
+        assert!(client.stream_avail_send_now(stream_id).unwrap() > 0);
+    }
+
     #[test]
     /// Verify that CC moves to cong avoidance when a packet is marked lost.
     fn cc_slow_start_to_cong_avoidance_recovery_period() {
@@ -4657,6 +7008,65 @@ mod tests {
         assert_eq!(c_tx_dgrams.len(), 4);
     }
 
+    #[test]
+    fn cc_bbr() {
+        let mut client = default_client();
+        client
+            .set_congestion_control(CongestionControlAlgorithm::Bbr)
+            .unwrap();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        assert_eq!(
+            client.set_congestion_control(CongestionControlAlgorithm::NewReno),
+            Err(Error::ConnectionState)
+        );
+
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        let (c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
+        assert!(!c_tx_dgrams.is_empty());
+        let cwnd = client.loss_recovery.cwnd();
+
+        now += Duration::from_millis(100);
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+        assert!(client.loss_recovery.cwnd() >= cwnd);
+    }
+
+    #[test]
+    fn delivery_rate_stats() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        let (c_tx_dgrams, mut now) = fill_cwnd(&mut client, 0, now());
+        let cwnd = u64::try_from(client.loss_recovery.cwnd()).unwrap();
+
+        // Everything that was sent is acknowledged after 100ms, so the
+        // rate can't be more than a congestion window every 100ms.
+        now += Duration::from_millis(100);
+        let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams, now);
+        for dgram in s_tx_dgram {
+            client.test_process_input(dgram, now);
+        }
+        let stats = client.stats();
+        assert!(stats.delivery_rate > 0);
+        assert!(stats.delivery_rate <= cwnd * 10);
+        assert!(stats.smoothed_delivery_rate > 0);
+    }
+
+    #[test]
+    fn rx_buffers_reused() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        let stats = client.rx_buffer_stats();
+        assert!(stats.reused > 0);
+        assert!(stats.allocated < stats.reused);
+    }
+
     fn check_discarded(peer: &mut Connection, pkt: Datagram, dropped: usize, dups: usize) {
         // Make sure to flush any saved datagrams before doing this.
         let _ = peer.process_output(now());
@@ -5017,6 +7427,83 @@ mod tests {
         assert!(client.state().closed());
     }
 
+    #[test]
+    fn peer_close_diagnostics() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // Have the client use a stream that the server doesn't allow.
+        server.indexes.local_max_stream_bidi = StreamIndex::new(0);
+        client.stream_create(StreamType::BiDi).unwrap();
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, &[1; 10]).unwrap();
+        let dgram = client.process(None, now()).dgram();
+        let dgram = server.process(dgram, now()).dgram();
+        assert!(matches!(
+            server.state(),
+            State::Closing {
+                error: ConnectionError::Transport(Error::StreamLimitError),
+                ..
+            }
+        ));
+        assert!(server.peer_close().is_none());
+
+        client.process_input(dgram.unwrap(), now());
+        assert!(matches!(client.state(), State::Draining { .. }));
+        let peer_close = client.peer_close().unwrap();
+        assert_eq!(
+            peer_close.error,
+            CloseError::Transport(Error::StreamLimitError.code())
+        );
+        // This is a STREAM frame, which has flags in the low bits.
+        assert_eq!(peer_close.frame_type & !0x7, 0x08);
+        let described = format!(
+            "transport error 0x4 in frame type {:#x}",
+            peer_close.frame_type
+        );
+        if cfg!(debug_assertions) {
+            let reason = format!(
+                "StreamLimitError in frame type {:#x}",
+                peer_close.frame_type
+            );
+            assert_eq!(peer_close.reason, reason);
+            assert_eq!(peer_close.to_string(), format!("{}: {}", described, reason));
+        } else {
+            assert!(peer_close.reason.is_empty());
+            assert_eq!(peer_close.to_string(), described);
+        }
+    }
+
+    #[test]
+    fn peer_close_reason_sanitized() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let mut reason_phrase = b"bad\x1b[2Jthing\xff".to_vec();
+        reason_phrase.resize(1000, b'x');
+        let frame = Frame::ConnectionClose {
+            error_code: CloseError::Application(1),
+            frame_type: 0,
+            reason_phrase,
+        };
+        let path =
+            std::env::temp_dir().join(format!("neqo-peer-close-{}.qlog", std::process::id()));
+        let qlog = NeqoQlog::with_file(path.clone(), Role::Client, None, None).unwrap();
+        client.set_qlog(Some(qlog));
+        client.input_frame(PacketType::Short, frame, now()).unwrap();
+        client.set_qlog(None);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let reason = &client.peer_close().unwrap().reason;
+        assert_eq!(reason.chars().count(), MAX_PEER_REASON);
+        assert!(reason.starts_with("bad\u{fffd}[2Jthing\u{fffd}xx"));
+        assert!(log.contains("application_error"));
+        assert!(log.contains("thing"));
+    }
+
     #[test]
     fn bad_tls_version() {
         let mut client = default_client();
@@ -5428,82 +7915,333 @@ mod tests {
         assert_eq!(1, client.stats().dropped_rx);
     }
 
-    /// Run a single ChaCha20-Poly1305 test and get a PTO.
-    #[test]
-    fn chacha20poly1305() {
+    /// Connect using only the given cipher suite, then exchange some data.
+    fn connect_with_cipher(cipher: Cipher) {
         let mut server = default_server();
         let mut client = Connection::new_client(
             test_fixture::DEFAULT_SERVER_NAME,
             test_fixture::DEFAULT_ALPN,
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0).unwrap())),
             loopback(),
             loopback(),
             QuicVersion::default(),
         )
         .expect("create a default client");
-        client.set_ciphers(&[TLS_CHACHA20_POLY1305_SHA256]).unwrap();
+        client.set_ciphers(&[cipher]).unwrap();
         connect_force_idle(&mut client, &mut server);
+        assert_eq!(client.tls_info().unwrap().cipher_suite(), cipher);
+        assert_eq!(server.tls_info().unwrap().cipher_suite(), cipher);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[6; 100]).unwrap();
+        let dgram = client.process(None, now()).dgram();
+        server.process_input(dgram.unwrap(), now());
+        let mut buf = [0; 100];
+        let (received, _) = server.stream_recv(stream_id, &mut buf).unwrap();
+        assert_eq!(received, 100);
     }
 
-    /// Test that a client can handle a stateless reset correctly.
     #[test]
-    fn stateless_reset_client() {
-        let mut client = default_client();
-        let mut server = default_server();
-        server
-            .set_local_tparam(
-                tparams::STATELESS_RESET_TOKEN,
-                TransportParameter::Bytes(vec![77; 16]),
-            )
-            .unwrap();
-        connect_force_idle(&mut client, &mut server);
+    fn aes128gcm() {
+        connect_with_cipher(TLS_AES_128_GCM_SHA256);
+    }
 
-        client.process_input(Datagram::new(loopback(), loopback(), vec![77; 21]), now());
-        assert!(matches!(client.state(), State::Draining { .. }));
+    #[test]
+    fn aes256gcm() {
+        connect_with_cipher(TLS_AES_256_GCM_SHA384);
     }
 
-    /// Test that a server can send 0.5 RTT application data.
+    /// Run a single ChaCha20-Poly1305 test.
     #[test]
-    fn send_05rtt() {
-        let mut client = default_client();
-        let mut server = default_server();
+    fn chacha20poly1305() {
+        connect_with_cipher(TLS_CHACHA20_POLY1305_SHA256);
+    }
 
-        let c1 = client.process(None, now()).dgram();
-        assert!(c1.is_some());
-        let s1 = server.process(c1, now()).dgram();
-        assert!(s1.is_some());
+    fn enable_datagrams(c: &mut Connection) {
+        c.set_local_tparam(
+            tparams::MAX_DATAGRAM_FRAME_SIZE,
+            TransportParameter::Integer(1200),
+        )
+        .unwrap();
+    }
 
-        // The server should accept writes at this point.
-        let s2 = send_something(&mut server, now());
+    #[test]
+    fn datagram() {
+        let mut client = default_client();
+        enable_datagrams(&mut client);
+        let mut server = default_server();
+        enable_datagrams(&mut server);
+        connect(&mut client, &mut server);
 
-        // We can't use the standard facility to complete the handshake, so
-        // drive it as aggressively as possible.
-        let _ = client.process(s1, now()).dgram(); // Just an ACK out of this.
-        client.authenticated(AuthenticationStatus::Ok, now());
-        assert_eq!(*client.state(), State::Connected);
+        // The frame header takes 3 bytes.
+        assert_eq!(client.max_datagram_size(), Some(1197));
+        assert_eq!(client.send_datagram(&[0; 1198]), Err(Error::TooMuchData));
 
-        // The client should accept data now.
-        client.process_input(s2, now());
-        let mut buf = vec![0; DEFAULT_STREAM_DATA.len() + 1];
-        let stream_id = client
+        // Two identical datagrams are delivered as two events.
+        client.send_datagram(&[1, 2, 3]).unwrap();
+        client.send_datagram(&[1, 2, 3]).unwrap();
+        let out = client.process(None, now());
+        server.process_input(out.dgram().unwrap(), now());
+        let datagrams = server
             .events()
-            .find_map(|e| {
-                if let ConnectionEvent::RecvStreamReadable { stream_id } = e {
-                    Some(stream_id)
-                } else {
-                    None
-                }
-            })
-            .unwrap();
-        let (l, ended) = client.stream_recv(stream_id, &mut buf).unwrap();
-        assert_eq!(&buf[..l], DEFAULT_STREAM_DATA);
-        assert!(ended);
+            .filter(|e| *e == ConnectionEvent::Datagram(vec![1, 2, 3]))
+            .count();
+        assert_eq!(datagrams, 2);
     }
 
-    /// Test that a server that coalesces 0.5 RTT with handshake packets
-    /// doesn't cause the client to drop application data.
     #[test]
-    fn coalesce_05rtt() {
+    fn datagram_not_negotiated() {
+        let mut client = default_client();
+        let mut server = default_server();
+        enable_datagrams(&mut server);
+        connect(&mut client, &mut server);
+
+        // The client can send to the server, but not the other way around.
+        assert!(client.max_datagram_size().is_some());
+        assert_eq!(server.max_datagram_size(), None);
+        assert_eq!(server.send_datagram(&[1]), Err(Error::NotAvailable));
+    }
+
+    /// A client and server that use `version`.
+    fn version_pair(version: QuicVersion) -> (Connection, Connection) {
+        fixture_init();
+        let client = Connection::new_client(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3).unwrap())),
+            loopback(),
+            loopback(),
+            version,
+        )
+        .unwrap();
+        let server = Connection::new_server(
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            &test_fixture::anti_replay(),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(5).unwrap())),
+            version,
+        )
+        .unwrap();
+        (client, server)
+    }
+
+    /// The types of the extensions in the ClientHello from a client that uses `version`.
+    fn client_hello_extensions(version: QuicVersion) -> Vec<u64> {
+        let (mut client, mut server) = version_pair(version);
+        let dgram = client.process(None, now()).dgram().unwrap();
+        let frames = server.test_process_input(dgram, now());
+        let hello = frames
+            .iter()
+            .find_map(|(f, _)| match f {
+                Frame::Crypto { offset: 0, data } => Some(data),
+                _ => None,
+            })
+            .unwrap();
+
+        let mut dec = Decoder::from(&hello[..]);
+        assert_eq!(dec.decode_byte(), Some(1)); // ClientHello
+        dec.skip(3 + 2 + 32); // Length, version, and random.
+        dec.skip_vec(1); // Session ID.
+        dec.skip_vec(2); // Cipher suites.
+        dec.skip_vec(1); // Compression methods.
+        let mut ext = Decoder::from(dec.decode_vec(2).unwrap());
+        let mut types = Vec::new();
+        while ext.remaining() > 0 {
+            types.push(ext.decode_uint(2).unwrap());
+            ext.skip_vec(2);
+        }
+        types
+    }
+
+    #[test]
+    fn tp_extension_by_version() {
+        let draft = client_hello_extensions(QuicVersion::Draft29);
+        assert!(draft.contains(&0xffa5));
+        assert!(!draft.contains(&0x39));
+
+        let final_version = client_hello_extensions(QuicVersion::Version1);
+        assert!(final_version.contains(&0x39));
+        assert!(!final_version.contains(&0xffa5));
+    }
+
+    #[test]
+    fn connect_version1() {
+        let (mut client, mut server) = version_pair(QuicVersion::Version1);
+        connect(&mut client, &mut server);
+        assert!(client.tps.borrow().remote.is_some());
+        assert!(server.tps.borrow().remote.is_some());
+    }
+
+    #[test]
+    fn packet_overhead() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(server.plpmtu(), PATH_MTU_MIN);
+        assert_eq!(server.packet_overhead(), None);
+        // The client has a path from the start, but no keys.
+        assert_eq!(client.plpmtu(), PATH_MTU_V6);
+        let client_cid = client.path.as_ref().unwrap().local_cid().len();
+        let server_cid = client.path.as_ref().unwrap().remote_cid().len();
+        assert_eq!(
+            client.handshake_packet_overhead(),
+            Some(1 + 4 + 1 + server_cid + 1 + client_cid + 2 + 3 + 16)
+        );
+
+        connect(&mut client, &mut server);
+        let server_cid = client.path.as_ref().unwrap().remote_cid().len();
+        assert_eq!(client.packet_overhead(), Some(1 + server_cid + 3 + 16));
+        assert_eq!(server.plpmtu(), PATH_MTU_V6);
+        assert_eq!(server.packet_overhead(), Some(1 + client_cid + 3 + 16));
+    }
+
+    #[test]
+    fn datagram_fits_in_packet() {
+        let mut client = default_client();
+        client
+            .set_local_tparam(
+                tparams::MAX_DATAGRAM_FRAME_SIZE,
+                TransportParameter::Integer(65535),
+            )
+            .unwrap();
+        let mut server = default_server();
+        server
+            .set_local_tparam(
+                tparams::MAX_DATAGRAM_FRAME_SIZE,
+                TransportParameter::Integer(65535),
+            )
+            .unwrap();
+        connect(&mut client, &mut server);
+
+        // The largest datagram fills a packet, less a 3 byte frame header.
+        let max = client.max_datagram_size().unwrap();
+        assert_eq!(max, client.plpmtu() - client.packet_overhead().unwrap() - 3);
+        assert_eq!(
+            client.send_datagram(&vec![0; max + 1]),
+            Err(Error::TooMuchData)
+        );
+        client.send_datagram(&vec![7; max]).unwrap();
+        while let Some(dgram) = client.process(None, now()).dgram() {
+            assert!(dgram.len() <= client.plpmtu());
+            server.process_input(dgram, now());
+        }
+        assert!(server
+            .events()
+            .any(|e| e == ConnectionEvent::Datagram(vec![7; max])));
+    }
+
+    /// Test that a client can handle a stateless reset correctly.
+    #[test]
+    fn stateless_reset_client() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server
+            .set_local_tparam(
+                tparams::STATELESS_RESET_TOKEN,
+                TransportParameter::Bytes(vec![77; 16]),
+            )
+            .unwrap();
+        connect_force_idle(&mut client, &mut server);
+
+        client.process_input(Datagram::new(loopback(), loopback(), vec![77; 21]), now());
+        assert!(matches!(client.state(), State::Draining { .. }));
+    }
+
+    #[test]
+    fn preferred_address() {
+        let preferred = SocketAddr::new(loopback().ip(), 444);
+        let mut client = default_client();
+        let mut server = default_server();
+        server
+            .set_preferred_address(PreferredAddress::new(
+                None,
+                Some(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 444, 0, 0)),
+                ConnectionId::from(&[6; 5][..]),
+                [7; 16],
+            ))
+            .unwrap();
+        connect(&mut client, &mut server);
+
+        // The client probes the preferred address and the server answers from there.
+        let probe = client.process(None, now()).dgram().unwrap();
+        assert_eq!(probe.destination(), preferred);
+        assert!(probe.len() >= PATH_MTU_MIN);
+        let response = server.process(Some(probe), now()).dgram().unwrap();
+        assert_eq!(response.source(), preferred);
+        client.process_input(response, now());
+        assert_eq!(*client.path().unwrap().remote_address(), preferred);
+        assert_eq!(client.path().unwrap().reset_token(), Some(&[7; 16]));
+
+        // Everything now goes to the preferred address.
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[1, 2, 3]).unwrap();
+        let dgram = client.process(None, now()).dgram().unwrap();
+        assert_eq!(dgram.destination(), preferred);
+        server.process_input(dgram, now());
+        assert_eq!(*server.state(), State::Confirmed);
+        let mut buf = [0; 3];
+        assert_eq!(server.stream_recv(stream_id, &mut buf).unwrap(), (3, false));
+    }
+
+    #[test]
+    fn preferred_address_other_family() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server
+            .set_preferred_address(PreferredAddress::new(
+                Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 444)),
+                None,
+                ConnectionId::from(&[6; 5][..]),
+                [7; 16],
+            ))
+            .unwrap();
+        connect(&mut client, &mut server);
+        // There is no IPv6 address to move to.
+        assert!(client.preferred_address_migration.is_none());
+        assert_eq!(*client.path().unwrap().remote_address(), loopback());
+    }
+
+    /// Test that a server can send 0.5 RTT application data.
+    #[test]
+    fn send_05rtt() {
+        let mut client = default_client();
+        let mut server = default_server();
+
+        let c1 = client.process(None, now()).dgram();
+        assert!(c1.is_some());
+        let s1 = server.process(c1, now()).dgram();
+        assert!(s1.is_some());
+
+        // The server should accept writes at this point.
+        let s2 = send_something(&mut server, now());
+
+        // We can't use the standard facility to complete the handshake, so
+        // drive it as aggressively as possible.
+        let _ = client.process(s1, now()).dgram(); // Just an ACK out of this.
+        client.authenticated(AuthenticationStatus::Ok, now());
+        assert_eq!(*client.state(), State::Connected);
+
+        // The client should accept data now.
+        client.process_input(s2, now());
+        let mut buf = vec![0; DEFAULT_STREAM_DATA.len() + 1];
+        let stream_id = client
+            .events()
+            .find_map(|e| {
+                if let ConnectionEvent::RecvStreamReadable { stream_id } = e {
+                    Some(stream_id)
+                } else {
+                    None
+                }
+            })
+            .unwrap();
+        let (l, ended) = client.stream_recv(stream_id, &mut buf).unwrap();
+        assert_eq!(&buf[..l], DEFAULT_STREAM_DATA);
+        assert!(ended);
+    }
+
+    /// Test that a server that coalesces 0.5 RTT with handshake packets
+    /// doesn't cause the client to drop application data.
+    #[test]
+    fn coalesce_05rtt() {
         const RTT: Duration = Duration::from_millis(100);
         let mut client = default_client();
         let mut server = default_server();
@@ -5539,14 +8277,14 @@ mod tests {
         let _ = client.process(s2, now).dgram();
         // This packet will contain an ACK, but we can ignore it.
         assert_eq!(client.stats.dropped_rx, 0);
-        assert!(client.saved_datagram.is_some());
+        assert_eq!(client.saved_datagrams.len(), 1);
 
         // After (successful) authentication, the packet is processed.
         maybe_authenticate(&mut client);
         let c3 = client.process(None, now).dgram();
         assert!(c3.is_some());
         assert_eq!(client.stats.dropped_rx, 0);
-        assert!(client.saved_datagram.is_none());
+        assert!(client.saved_datagrams.is_empty());
 
         // Allow the handshake to complete.
         now += RTT / 2;
@@ -5722,11 +8460,14 @@ mod tests {
 
         let now = now();
 
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(stream_id, 3);
+
         // Try to say we're blocked beyond the initial data window
         server
             .flow_mgr
             .borrow_mut()
-            .stream_data_blocked(3.into(), RX_STREAM_DATA_WINDOW * 4);
+            .stream_data_blocked(stream_id.into(), RX_STREAM_DATA_WINDOW * 4);
 
         let out = server.process(None, now);
         assert!(out.as_dgram_ref().is_some());
@@ -5747,4 +8488,730 @@ mod tests {
 				   if *maximum_stream_data == RX_STREAM_DATA_WINDOW)
         ));
     }
+
+    #[test]
+    fn uni_stream_limit() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_stream_limit(StreamType::UniDi, 2).unwrap();
+        connect(&mut client, &mut server);
+
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 6);
+        assert_eq!(
+            client.stream_create(StreamType::UniDi),
+            Err(Error::StreamLimitError)
+        );
+        // The limit on bidirectional streams is separate.
+        assert_eq!(client.stream_create(StreamType::BiDi).unwrap(), 0);
+        assert_eq!(server.stream_create(StreamType::UniDi).unwrap(), 3);
+        assert_eq!(server.stream_create(StreamType::BiDi).unwrap(), 1);
+    }
+
+    #[test]
+    fn uni_stream_window() {
+        const WINDOW: u64 = 100;
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_stream_window(StreamType::UniDi, WINDOW).unwrap();
+        connect(&mut client, &mut server);
+
+        let uni = client.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(
+            client.stream_avail_send_space(uni).unwrap(),
+            usize::try_from(WINDOW).unwrap()
+        );
+        let bidi = client.stream_create(StreamType::BiDi).unwrap();
+        assert_eq!(
+            client.stream_avail_send_space(bidi).unwrap(),
+            usize::try_from(RX_STREAM_DATA_WINDOW).unwrap()
+        );
+    }
+
+    #[test]
+    fn raise_max_streams() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_stream_limit(StreamType::UniDi, 1).unwrap();
+        connect(&mut client, &mut server);
+        assert_eq!(server.max_streams(StreamType::UniDi), 1);
+        assert_eq!(
+            server.raise_max_streams(StreamType::UniDi, 1),
+            Err(Error::InvalidInput)
+        );
+
+        server.raise_max_streams(StreamType::UniDi, 2).unwrap();
+        assert_eq!(server.max_streams(StreamType::UniDi), 2);
+        let dgram = server.process(None, now()).dgram();
+        let _ = client.process(dgram, now());
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 2);
+        assert_eq!(client.stream_create(StreamType::UniDi).unwrap(), 6);
+        assert_eq!(
+            client.stream_create(StreamType::UniDi),
+            Err(Error::StreamLimitError)
+        );
+    }
+
+    #[test]
+    fn raise_max_streams_before_start() {
+        let mut server = default_server();
+        assert_eq!(
+            server.raise_max_streams(StreamType::BiDi, LOCAL_STREAM_LIMIT_BIDI + 1),
+            Err(Error::ConnectionState)
+        );
+    }
+
+    #[test]
+    fn set_max_stream_data() {
+        const WINDOW: u64 = 100;
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_stream_window(StreamType::UniDi, WINDOW).unwrap();
+        connect(&mut client, &mut server);
+
+        let uni = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(uni, &[1]).unwrap();
+        let dgram = client.process(None, now()).dgram();
+        let _ = server.process(dgram, now());
+        assert_eq!(server.max_stream_data(uni), Ok(WINDOW));
+        assert_eq!(
+            server.set_max_stream_data(uni, WINDOW),
+            Err(Error::InvalidInput)
+        );
+
+        server.set_max_stream_data(uni, WINDOW * 10).unwrap();
+        assert_eq!(server.max_stream_data(uni), Ok(WINDOW * 10));
+        let dgram = server.process(None, now()).dgram();
+        let _ = client.process(dgram, now());
+        assert_eq!(
+            client.stream_avail_send_space(uni).unwrap(),
+            usize::try_from(WINDOW * 10 - 1).unwrap()
+        );
+        assert_eq!(
+            server.set_max_stream_data(uni + 4, WINDOW * 10),
+            Err(Error::InvalidStreamId)
+        );
+    }
+
+    #[test]
+    fn stream_settings_after_start() {
+        let mut client = default_client();
+        let _ = client.process(None, now());
+        assert_eq!(
+            client.set_stream_limit(StreamType::UniDi, 1),
+            Err(Error::ConnectionState)
+        );
+        assert_eq!(
+            client.set_stream_window(StreamType::UniDi, 1),
+            Err(Error::ConnectionState)
+        );
+        assert_eq!(
+            client.set_max_stream_window(1 << 24),
+            Err(Error::ConnectionState)
+        );
+        assert_eq!(
+            client.set_stream_limit(StreamType::BiDi, MAX_STREAM_LIMIT + 1),
+            Err(Error::InvalidInput)
+        );
+    }
+
+    /// Have `client` send whatever `server` has queued, and check that this
+    /// closes `client` with a stream state error.
+    fn assert_stream_state_error(server: &mut Connection, client: &mut Connection) {
+        let dgram = server.process(None, now()).dgram();
+        let _ = client.process(dgram, now());
+        assert!(matches!(
+            client.state(),
+            State::Closing {
+                error: ConnectionError::Transport(Error::StreamStateError),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn stop_sending_receive_only_stream() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // Only the server sends on this stream, so it can't ask to stop.
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        server
+            .flow_mgr
+            .borrow_mut()
+            .stop_sending(stream_id.into(), 0);
+        assert_stream_state_error(&mut server, &mut client);
+    }
+
+    #[test]
+    fn reset_send_only_stream() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // Only the client sends on this stream, so the server can't reset it.
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        server
+            .flow_mgr
+            .borrow_mut()
+            .stream_reset(stream_id.into(), 0, 0);
+        assert_stream_state_error(&mut server, &mut client);
+    }
+
+    #[test]
+    fn stream_not_yet_opened() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // The client hasn't opened this stream.
+        server
+            .flow_mgr
+            .borrow_mut()
+            .max_stream_data(StreamId::new(0), 1000);
+        assert_stream_state_error(&mut server, &mut client);
+    }
+
+    fn new_port(addr: SocketAddr) -> SocketAddr {
+        SocketAddr::new(addr.ip(), addr.port() + 1)
+    }
+
+    #[test]
+    fn migration_disabled() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert!(server
+            .tps
+            .borrow()
+            .remote()
+            .get_empty(tparams::DISABLE_MIGRATION)
+            .is_some());
+
+        // Packets from a new address are dropped.
+        let dgram = send_something(&mut client, now());
+        let moved = Datagram::new(new_port(dgram.source()), dgram.destination(), &dgram[..]);
+        let dropped = server.stats().dropped_rx;
+        let _ = server.process(Some(moved), now());
+        assert_eq!(server.stats().dropped_rx, dropped + 1);
+        assert_eq!(*server.state(), State::Confirmed);
+
+        assert_eq!(
+            client.migrate(new_port(loopback())),
+            Err(Error::InvalidMigration)
+        );
+    }
+
+    #[test]
+    fn peer_disables_migration() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client
+            .set_migration_policy(MigrationPolicy::Active)
+            .unwrap();
+        connect(&mut client, &mut server);
+        assert_eq!(
+            client.migrate(new_port(loopback())),
+            Err(Error::InvalidMigration)
+        );
+    }
+
+    /// Connect with a client that can migrate and a server that follows.
+    fn migration_connect() -> (Connection, Connection) {
+        let mut client = default_client();
+        let mut server = default_server();
+        client
+            .set_migration_policy(MigrationPolicy::Active)
+            .unwrap();
+        server
+            .set_migration_policy(MigrationPolicy::PassiveOnly)
+            .unwrap();
+        connect(&mut client, &mut server);
+        (client, server)
+    }
+
+    #[test]
+    fn migrate() {
+        let (mut client, mut server) = migration_connect();
+        let old_cid = client.path().unwrap().remote_cid().clone();
+
+        let local = new_port(loopback());
+        client.migrate(local).unwrap();
+        assert_ne!(*client.path().unwrap().remote_cid(), old_cid);
+        let challenge = client.process(None, now()).dgram().unwrap();
+        assert_eq!(challenge.source(), local);
+        assert_eq!(challenge.len(), PATH_MTU_MIN);
+
+        // The server follows, but challenges the new address.
+        let challenge = server.process(Some(challenge), now()).dgram().unwrap();
+        assert_eq!(challenge.destination(), local);
+        assert!(!server.path().unwrap().is_valid());
+        let response = client.process(Some(challenge), now()).dgram().unwrap();
+        assert_eq!(response.source(), local);
+        let response = server.process(Some(response), now()).dgram().unwrap();
+        assert!(server.path().unwrap().is_valid());
+        assert!(server.path_validation.is_none());
+        assert_eq!(response.destination(), local);
+        let _ = client.process(Some(response), now());
+        assert!(client.path_validation.is_none());
+        assert_eq!(*client.path().unwrap().local_address(), local);
+        assert_eq!(*client.state(), State::Confirmed);
+
+        // The server doesn't migrate itself.
+        assert_eq!(server.migrate(local), Err(Error::InvalidMigration));
+    }
+
+    #[test]
+    fn migrate_no_answer() {
+        let (mut client, _server) = migration_connect();
+        let old = *client.path().unwrap().local_address();
+        client.migrate(new_port(old)).unwrap();
+        let _ = client.process(None, now()).dgram().unwrap();
+
+        // Without an answer to the challenge, the client goes back.
+        let pto = client.loss_recovery.pto();
+        let _ = client.process(None, now() + pto * 3);
+        assert_eq!(*client.path().unwrap().local_address(), old);
+        assert!(client.path_validation.is_none());
+    }
+
+    #[test]
+    fn migrate_no_spare_cid() {
+        let (mut client, _server) = migration_connect();
+        client.connection_ids.clear();
+        assert_eq!(
+            client.migrate(new_port(loopback())),
+            Err(Error::InvalidMigration)
+        );
+    }
+
+    #[test]
+    fn migration_replayed_packet() {
+        let (mut client, mut server) = migration_connect();
+        let first = send_something(&mut client, now());
+        let second = send_something(&mut client, now());
+        let _ = server.process(Some(second), now());
+
+        // An old packet from another address doesn't move the connection.
+        let replayed = Datagram::new(new_port(first.source()), first.destination(), &first[..]);
+        let _ = server.process(Some(replayed), now());
+        assert_eq!(*server.path().unwrap().remote_address(), first.source());
+        assert!(server.path_validation.is_none());
+    }
+
+    #[test]
+    fn migration_spoofed_small() {
+        let (mut client, mut server) = migration_connect();
+        let original = *server.path().unwrap().remote_address();
+
+        // The server moves to a spoofed address.
+        let dgram = send_something(&mut client, now());
+        let spoofed = new_port(dgram.source());
+        let size = dgram.len();
+        server.process_input(
+            Datagram::new(spoofed, dgram.destination(), &dgram[..]),
+            now(),
+        );
+        assert_eq!(*server.path().unwrap().remote_address(), spoofed);
+
+        // It can't send more than three times what arrived from there.
+        let mut sent = 0;
+        while let Some(d) = server.process(None, now()).dgram() {
+            assert_eq!(d.destination(), spoofed);
+            sent += d.len();
+        }
+        assert!(sent <= size * 3);
+
+        // The next packet from the client moves the server back.
+        let dgram = send_something(&mut client, now());
+        let _ = server.process(Some(dgram), now());
+        assert_eq!(*server.path().unwrap().remote_address(), original);
+        assert!(server.path().unwrap().is_valid());
+        assert!(server.path_validation.is_none());
+    }
+
+    #[test]
+    fn migration_spoofed_no_answer() {
+        let (mut client, mut server) = migration_connect();
+        let original = *server.path().unwrap().remote_address();
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[0; 2000]).unwrap();
+        let dgram = client.process(None, now()).dgram().unwrap();
+        let spoofed = new_port(dgram.source());
+        let challenge = server
+            .process(
+                Some(Datagram::new(spoofed, dgram.destination(), &dgram[..])),
+                now(),
+            )
+            .dgram()
+            .unwrap();
+        assert_eq!(challenge.destination(), spoofed);
+        assert_eq!(challenge.len(), PATH_MTU_MIN);
+
+        // Nothing answers from the spoofed address, so the server goes back.
+        let pto = server.loss_recovery.pto();
+        let _ = server.process(None, now() + pto * 3);
+        assert_eq!(*server.path().unwrap().remote_address(), original);
+        assert!(server.path().unwrap().is_valid());
+    }
+
+    #[test]
+    fn migration_policy_after_start() {
+        let mut client = default_client();
+        let _ = client.process(None, now());
+        assert_eq!(
+            client.set_migration_policy(MigrationPolicy::Active),
+            Err(Error::ConnectionState)
+        );
+    }
+
+    /// Connect, with both peers marking packets for ECN.
+    fn ecn_connect() -> (Connection, Connection) {
+        let mut client = default_client();
+        let mut server = default_server();
+        client.enable_ecn().unwrap();
+        server.enable_ecn().unwrap();
+        connect_force_idle(&mut client, &mut server);
+        (client, server)
+    }
+
+    #[test]
+    fn ecn_disabled() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        assert_eq!(send_something(&mut client, now()).ecn(), IPTOS_ECN_NOT_ECT);
+    }
+
+    #[test]
+    fn ecn_marking() {
+        let (mut client, mut server) = ecn_connect();
+        // The marks are still being used after the handshake, so each
+        // peer reported them correctly to the other.
+        assert_eq!(send_something(&mut client, now()).ecn(), IPTOS_ECN_ECT0);
+        assert_eq!(send_something(&mut server, now()).ecn(), IPTOS_ECN_ECT0);
+    }
+
+    #[test]
+    fn ecn_ce() {
+        let (mut client, mut server) = ecn_connect();
+        let cwnd = client.loss_recovery.cwnd();
+
+        // The network marks the packet with CE.
+        let d = send_something(&mut client, now());
+        let d = Datagram::new_with_tos_ttl(d.source(), d.destination(), IPTOS_ECN_CE, None, &d[..]);
+        let _ = server.process(Some(d), now());
+        let ack = server.process(None, now() + ACK_DELAY).dgram();
+        assert!(ack.is_some());
+
+        // The client treats that as congestion, but keeps marking packets.
+        let _ = client.process(ack, now() + ACK_DELAY);
+        assert!(client.loss_recovery.cwnd() < cwnd);
+        assert_eq!(
+            send_something(&mut client, now() + ACK_DELAY).ecn(),
+            IPTOS_ECN_ECT0
+        );
+    }
+
+    #[test]
+    fn ecn_bleached() {
+        let (mut client, mut server) = ecn_connect();
+
+        // Something on the path clears the marks.
+        let d = send_something(&mut client, now());
+        let d = Datagram::new(d.source(), d.destination(), &d[..]);
+        let _ = server.process(Some(d), now());
+        let ack = server.process(None, now() + ACK_DELAY).dgram();
+        assert!(ack.is_some());
+
+        // The client stops marking packets.
+        let _ = client.process(ack, now() + ACK_DELAY);
+        assert_eq!(
+            send_something(&mut client, now() + ACK_DELAY).ecn(),
+            IPTOS_ECN_NOT_ECT
+        );
+    }
+
+    #[test]
+    fn ecn_after_start() {
+        let mut client = default_client();
+        let _ = client.process(None, now());
+        assert_eq!(client.enable_ecn(), Err(Error::ConnectionState));
+    }
+
+    #[test]
+    fn display() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        let cid = client.path.as_ref().unwrap().local_cid().to_string();
+        assert!(client.to_string().ends_with(&format!("{} ap", cid)));
+
+        let path = std::env::temp_dir().join(format!("neqo-display-{}.qlog", std::process::id()));
+        let qlog = NeqoQlog::with_file(path.clone(), Role::Client, None, None).unwrap();
+        client.set_qlog(Some(qlog));
+        assert!(client.to_string().ends_with(" ap qlog"));
+        client.set_qlog(None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn handshake_before_initial() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let out = client.process(None, now()).dgram();
+        let out = server.process(out, now()).dgram().unwrap();
+        let (initial, handshake) = split_datagram(out);
+        let handshake = handshake.unwrap();
+
+        // The Handshake packet arrives first, so it is saved until there are keys.
+        let _ = client.process(Some(handshake), now());
+        assert_eq!(client.saved_datagrams.len(), 1);
+        assert_eq!(client.stats().dropped_rx, 0);
+
+        let _ = client.process(Some(initial), now());
+        // The saved datagram is processed the next time the client runs.
+        let _ = client.process(None, now());
+        assert!(maybe_authenticate(&mut client));
+        assert!(client.saved_datagrams.is_empty());
+        assert_eq!(client.stats().dropped_rx, 0);
+        assert_eq!(*client.state(), State::Connected);
+    }
+
+    #[test]
+    fn saved_datagrams_limit() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let out = client.process(None, now()).dgram();
+        let out = server.process(out, now()).dgram().unwrap();
+        let handshake = split_datagram(out).1.unwrap();
+
+        for _ in 0..=MAX_SAVED_DATAGRAMS {
+            client.process_input(handshake.clone(), now());
+        }
+        assert_eq!(client.saved_datagrams.len(), MAX_SAVED_DATAGRAMS);
+        assert_eq!(client.stats().dropped_rx, 1);
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let out = client.process(None, now()).dgram();
+        let out = server.process(out, now()).dgram().unwrap();
+
+        // Switch the version to another one that is supported.
+        let mut other = out.to_vec();
+        other[1..5].copy_from_slice(&QuicVersion::Draft27.as_u32().to_be_bytes());
+        let other = Datagram::new(out.source(), out.destination(), other);
+        let _ = client.process(Some(other), now());
+        assert_eq!(client.stats().dropped_rx, 1);
+        assert_eq!(*client.state(), State::WaitInitial);
+    }
+
+    #[test]
+    fn server_short_initial() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let out = client.process(None, now()).dgram().unwrap();
+
+        // Remove the padding after the Initial packet.
+        let (initial, _) = split_datagram(out);
+        assert!(initial.len() < MIN_INITIAL_PACKET_SIZE);
+        assert_eq!(server.process(Some(initial), now()), Output::None);
+        assert_eq!(server.stats().dropped_rx, 1);
+        assert_eq!(*server.state(), State::Init);
+    }
+
+    #[test]
+    fn undecryptable_limit() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect_force_idle(&mut client, &mut server);
+        client.undecryptable_limit = 2;
+
+        let damage = |server: &mut Connection| {
+            let dgram = send_something(server, now());
+            let mut damaged = dgram.to_vec();
+            let last = damaged.len() - 1;
+            damaged[last] ^= 0xff;
+            Datagram::new(dgram.source(), dgram.destination(), damaged)
+        };
+        client.process_input(damage(&mut server), now());
+        assert_eq!(client.stats().undecryptable_rx, 1);
+        assert_eq!(*client.state(), State::Confirmed);
+
+        client.process_input(damage(&mut server), now());
+        assert_eq!(client.stats().undecryptable_rx, 2);
+        assert_error(&client, ConnectionError::Transport(Error::AeadLimitReached));
+    }
+
+    fn draft27_pair() -> (Connection, Connection) {
+        fixture_init();
+        let client = Connection::new_client(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3).unwrap())),
+            loopback(),
+            loopback(),
+            QuicVersion::Draft27,
+        )
+        .unwrap();
+        let server = Connection::new_server(
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            &test_fixture::anti_replay(),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(5).unwrap())),
+            QuicVersion::Draft27,
+        )
+        .unwrap();
+        (client, server)
+    }
+
+    fn set_original_cid(server: &Connection) {
+        server
+            .set_local_tparam(
+                tparams::ORIGINAL_DESTINATION_CONNECTION_ID,
+                TransportParameter::Bytes(vec![1, 2, 3, 4, 5, 6, 7, 8]),
+            )
+            .unwrap();
+    }
+
+    /// Without a Retry, the server's original_connection_id has to
+    /// match what the client used in its first Initial.
+    #[test]
+    fn original_cid_mismatch() {
+        let mut client = default_client();
+        let mut server = default_server();
+        set_original_cid(&server);
+        // Pretend that a Retry was sent so that the server keeps the bad value.
+        server
+            .set_local_tparam(
+                tparams::RETRY_SOURCE_CONNECTION_ID,
+                TransportParameter::Bytes(vec![8, 7, 6, 5, 4, 3, 2, 1]),
+            )
+            .unwrap();
+
+        handshake(&mut client, &mut server, now(), Duration::new(0, 0));
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::TransportParameterError),
+        );
+    }
+
+    #[test]
+    fn initial_source_cid_mismatch() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server
+            .set_local_tparam(
+                tparams::INITIAL_SOURCE_CONNECTION_ID,
+                TransportParameter::Bytes(vec![1, 2, 3, 4, 5]),
+            )
+            .unwrap();
+
+        handshake(&mut client, &mut server, now(), Duration::new(0, 0));
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::TransportParameterError),
+        );
+    }
+
+    #[test]
+    fn draft27_no_original_cid() {
+        let (mut client, mut server) = draft27_pair();
+        connect(&mut client, &mut server);
+        assert!(server
+            .tps
+            .borrow()
+            .local
+            .get_bytes(tparams::ORIGINAL_DESTINATION_CONNECTION_ID)
+            .is_none());
+    }
+
+    /// In draft-27, original_connection_id is only allowed after a Retry.
+    #[test]
+    fn draft27_original_cid_without_retry() {
+        let (mut client, mut server) = draft27_pair();
+        set_original_cid(&server);
+
+        handshake(&mut client, &mut server, now(), Duration::new(0, 0));
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::TransportParameterError),
+        );
+    }
+
+    #[test]
+    fn address_validated() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        assert!(client.path.as_ref().unwrap().is_valid());
+        // The client's Handshake packets validate its address.
+        assert!(server.path.as_ref().unwrap().is_valid());
+    }
+
+    /// When the server's first flight is lost and it can't send any more
+    /// because of the anti-amplification limit, it waits for the client.
+    #[test]
+    fn amplification_blocked_server_pto() {
+        let mut client = default_client();
+        let mut server = default_server();
+        let now = now();
+
+        let c1 = client.process(None, now).dgram();
+        let s1 = server.process(c1, now).dgram();
+        assert!(s1.is_some()); // This is lost.
+
+        // Use up what is left of the anti-amplification limit.
+        let path = server.path.as_mut().unwrap();
+        path.on_datagram_sent(path.amplification_limit());
+        assert!(path.amplification_blocked());
+
+        // The server doesn't arm the PTO timer, as a probe couldn't be sent.
+        assert!(server.process(None, now).dgram().is_none());
+        assert!(server.loss_recovery.next_timeout().is_none());
+        let later = now + AT_LEAST_PTO;
+        assert!(server.process(None, later).dgram().is_none());
+
+        // The client retransmits its Initial, which gives the server more credit.
+        let c2 = client.process(None, later).dgram().unwrap();
+        assert_eq!(c2.len(), PATH_MTU_V6);
+        let s2 = server.process(Some(c2), later).dgram();
+        assert!(s2.is_some());
+        assert!(s2.unwrap().len() <= PATH_MTU_V6 * 3);
+    }
+
+    /// A client that has nothing in flight still probes during the handshake,
+    /// so that a server that can't send gets more credit.
+    #[test]
+    fn client_probe_without_in_flight() {
+        const RTT: Duration = Duration::from_millis(100);
+        let mut client = default_client();
+        let mut server = default_server();
+        let mut now = now();
+
+        let c1 = client.process(None, now).dgram();
+        now += RTT / 2;
+        let s1 = server.process(c1, now).dgram().unwrap();
+
+        // Only the server's Initial arrives, so the client has an ACK for its
+        // Initial and nothing else in flight.  The ACK from the client is lost.
+        let (s_initial, _) = split_datagram(s1);
+        now += RTT / 2;
+        let c2 = client.process(Some(s_initial), now).dgram();
+        assert!(c2.is_some());
+
+        // The client still has a PTO timer.
+        let cb = client.process(None, now).callback();
+        assert!(cb < LOCAL_IDLE_TIMEOUT);
+        now += cb;
+        let probe = client.process(None, now).dgram().unwrap();
+        let frames = server.test_process_input(probe, now);
+        assert!(frames.iter().any(|(f, _)| *f == Frame::Ping));
+        assert!(!server.path.as_ref().unwrap().amplification_blocked());
+    }
 }
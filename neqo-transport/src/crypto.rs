@@ -31,6 +31,10 @@ use crate::tracking::PNSpace;
 use crate::{Error, Res};
 
 const MAX_AUTH_TAG: usize = 32;
+/// How far past what has been read CRYPTO frames can go.  RFC 9000 requires
+/// that at least 4096 bytes are buffered; a flight with a large certificate
+/// chain is read as it arrives, so it doesn't need much more than that.
+pub(crate) const MAX_CRYPTO_BUFFER: u64 = 0x10000;
 
 #[derive(Debug)]
 pub struct Crypto {
@@ -42,6 +46,7 @@ pub struct Crypto {
 impl Crypto {
     pub fn new(
         mut agent: Agent,
+        quic_version: QuicVersion,
         protocols: &[impl AsRef<str>],
         tphandler: Rc<RefCell<TransportParametersHandler>>,
         anti_replay: Option<&AntiReplay>,
@@ -62,7 +67,7 @@ impl Crypto {
                 TpZeroRttChecker::wrap(tphandler.clone()),
             )?,
         }
-        agent.extension_handler(0xffa5, tphandler)?;
+        agent.extension_handler(quic_version.tp_extension(), tphandler)?;
         Ok(Self {
             tls: agent,
             streams: Default::default(),
@@ -96,6 +101,10 @@ impl Crypto {
                 self.buffer_records(output)?;
                 Ok(self.tls.state())
             }
+            Err(neqo_crypto::Error::EchRetry(configs)) => {
+                qinfo!("Handshake failed, ECH was rejected");
+                Err(Error::EchRetry(configs))
+            }
             Err(e) => {
                 qinfo!("Handshake failed");
                 Err(match self.tls.alert() {
@@ -249,6 +258,8 @@ pub enum CryptoDxDirection {
 #[derive(Debug)]
 pub struct CryptoDxState {
     direction: CryptoDxDirection,
+    /// The version, which determines the labels used to derive keys.
+    quic_version: QuicVersion,
     /// The epoch of this crypto state.  This initially tracks TLS epochs
     /// via DTLS: 0 = initial, 1 = 0-RTT, 2 = handshake, 3 = application.
     /// But we don't need to keep that, and QUIC isn't limited in how
@@ -266,22 +277,27 @@ pub struct CryptoDxState {
 
 impl CryptoDxState {
     pub fn new(
+        quic_version: QuicVersion,
         direction: CryptoDxDirection,
         epoch: Epoch,
         secret: &SymKey,
         cipher: Cipher,
     ) -> Self {
         qinfo!(
-            "Making {:?} {} CryptoDxState, cipher={}",
+            "Making {:?} {} CryptoDxState, cipher={} version={:?}",
             direction,
             epoch,
-            cipher
+            cipher,
+            quic_version,
         );
+        let prefix = quic_version.label_prefix();
         Self {
             direction,
+            quic_version,
             epoch: usize::from(epoch),
-            aead: Aead::new(TLS_VERSION_1_3, cipher, secret, "quic ").unwrap(),
-            hpkey: HpKey::extract(TLS_VERSION_1_3, cipher, secret, "quic hp").unwrap(),
+            aead: Aead::new(TLS_VERSION_1_3, cipher, secret, prefix).unwrap(),
+            hpkey: HpKey::extract(TLS_VERSION_1_3, cipher, secret, &(prefix.to_owned() + "hp"))
+                .unwrap(),
             used_pn: 0..0,
             min_pn: 0,
         }
@@ -294,18 +310,19 @@ impl CryptoDxState {
         dcid: &[u8],
     ) -> Self {
         qtrace!("new_initial for {:?}", quic_version);
-        const INITIAL_SALT_27: &[u8] = &[
-            0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43, 0x2b, 0xb4,
-            0x63, 0x65, 0xbe, 0xf9, 0xf5, 0x02,
-        ];
-        const INITIAL_SALT_29: &[u8] = &[
-            0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1, 0x9c, 0x61,
-            0x11, 0xe0, 0x43, 0x90, 0xa8, 0x99,
-        ];
-        let salt = match quic_version {
-            QuicVersion::Draft27 | QuicVersion::Draft28 => INITIAL_SALT_27,
-            QuicVersion::Draft29 => INITIAL_SALT_29,
-        };
+        let secret = Self::initial_secret(quic_version, label, dcid);
+        Self::new(
+            quic_version,
+            direction,
+            TLS_EPOCH_INITIAL,
+            &secret,
+            TLS_AES_128_GCM_SHA256,
+        )
+    }
+
+    /// Derive the secret for Initial packets sent by one endpoint, which `label` names.
+    pub(crate) fn initial_secret(quic_version: QuicVersion, label: &str, dcid: &[u8]) -> SymKey {
+        let salt = quic_version.initial_salt();
         let cipher = TLS_AES_128_GCM_SHA256;
         let initial_secret = hkdf::extract(
             TLS_VERSION_1_3,
@@ -321,18 +338,22 @@ impl CryptoDxState {
         )
         .unwrap();
 
-        let secret =
-            hkdf::expand_label(TLS_VERSION_1_3, cipher, &initial_secret, &[], label).unwrap();
-
-        Self::new(direction, TLS_EPOCH_INITIAL, &secret, cipher)
+        hkdf::expand_label(TLS_VERSION_1_3, cipher, &initial_secret, &[], label).unwrap()
     }
 
     pub fn next(&self, next_secret: &SymKey, cipher: Cipher) -> Self {
         let pn = self.next_pn();
         Self {
             direction: self.direction,
+            quic_version: self.quic_version,
             epoch: self.epoch + 1,
-            aead: Aead::new(TLS_VERSION_1_3, cipher, next_secret, "quic ").unwrap(),
+            aead: Aead::new(
+                TLS_VERSION_1_3,
+                cipher,
+                next_secret,
+                self.quic_version.label_prefix(),
+            )
+            .unwrap(),
             hpkey: self.hpkey.clone(),
             used_pn: pn..pn,
             min_pn: pn,
@@ -450,7 +471,14 @@ impl CryptoDxState {
         self.aead.expansion()
     }
 
-    pub fn decrypt(&mut self, pn: PacketNumber, hdr: &[u8], body: &[u8]) -> Res<Vec<u8>> {
+    /// Decrypt `body` into `out`, which is resized as needed.
+    pub fn decrypt(
+        &mut self,
+        pn: PacketNumber,
+        hdr: &[u8],
+        body: &[u8],
+        mut out: Vec<u8>,
+    ) -> Res<Vec<u8>> {
         debug_assert_eq!(self.direction, CryptoDxDirection::Read);
         qtrace!(
             [self],
@@ -459,10 +487,12 @@ impl CryptoDxState {
             hex(hdr),
             hex(body)
         );
-        let mut out = vec![0; body.len()];
-        let res = self.aead.decrypt(pn, hdr, body, &mut out)?;
+        out.clear();
+        out.resize(body.len(), 0);
+        let len = self.aead.decrypt(pn, hdr, body, &mut out)?.len();
         self.used(pn)?;
-        Ok(res.to_vec())
+        out.truncate(len);
+        Ok(out)
     }
 
     #[cfg(test)]
@@ -521,16 +551,28 @@ pub(crate) struct CryptoDxAppData {
 }
 
 impl CryptoDxAppData {
-    pub fn new(dir: CryptoDxDirection, secret: SymKey, cipher: Cipher) -> Res<Self> {
+    pub fn new(
+        quic_version: QuicVersion,
+        dir: CryptoDxDirection,
+        secret: SymKey,
+        cipher: Cipher,
+    ) -> Res<Self> {
         Ok(Self {
-            dx: CryptoDxState::new(dir, TLS_EPOCH_APPLICATION_DATA, &secret, cipher),
+            dx: CryptoDxState::new(
+                quic_version,
+                dir,
+                TLS_EPOCH_APPLICATION_DATA,
+                &secret,
+                cipher,
+            ),
             cipher,
-            next_secret: Self::update_secret(cipher, &secret)?,
+            next_secret: Self::update_secret(quic_version, cipher, &secret)?,
         })
     }
 
-    fn update_secret(cipher: Cipher, secret: &SymKey) -> Res<SymKey> {
-        let next = hkdf::expand_label(TLS_VERSION_1_3, cipher, secret, &[], "quic ku")?;
+    fn update_secret(quic_version: QuicVersion, cipher: Cipher, secret: &SymKey) -> Res<SymKey> {
+        let label = quic_version.label_prefix().to_owned() + "ku";
+        let next = hkdf::expand_label(TLS_VERSION_1_3, cipher, secret, &[], &label)?;
         Ok(next)
     }
 
@@ -539,7 +581,8 @@ impl CryptoDxAppData {
             // Guard against too many key updates.
             return Err(Error::KeysExhausted);
         }
-        let next_secret = Self::update_secret(self.cipher, &self.next_secret)?;
+        let next_secret =
+            Self::update_secret(self.dx.quic_version, self.cipher, &self.next_secret)?;
         Ok(Self {
             dx: self.dx.next(&next_secret, self.cipher),
             cipher: self.cipher,
@@ -550,6 +593,8 @@ impl CryptoDxAppData {
 
 #[derive(Debug, Default)]
 pub struct CryptoStates {
+    /// The version that keys are made for, which is set by `init`.
+    quic_version: QuicVersion,
     initial: Option<CryptoState>,
     handshake: Option<CryptoState>,
     zero_rtt: Option<CryptoDxState>, // One direction only!
@@ -585,6 +630,19 @@ impl CryptoStates {
         }
     }
 
+    /// The state that is used to send packets in `space`, if there is one.
+    pub fn tx_ref(&self, space: PNSpace) -> Option<&CryptoDxState> {
+        match space {
+            PNSpace::Initial => self.initial.as_ref().map(|dx| &dx.tx),
+            PNSpace::Handshake => self.handshake.as_ref().map(|dx| &dx.tx),
+            PNSpace::ApplicationData => self.app_write.as_ref().map(|a| &a.dx).or_else(|| {
+                self.zero_rtt
+                    .as_ref()
+                    .filter(|z| z.direction == CryptoDxDirection::Write)
+            }),
+        }
+    }
+
     pub fn rx_hp(&mut self, space: PNSpace) -> Option<&mut CryptoDxState> {
         match space {
             PNSpace::ApplicationData => Self::select_or_0rtt(
@@ -632,6 +690,7 @@ impl CryptoStates {
             Role::Client => (CLIENT_INITIAL_LABEL, SERVER_INITIAL_LABEL),
             Role::Server => (SERVER_INITIAL_LABEL, CLIENT_INITIAL_LABEL),
         };
+        self.quic_version = quic_version;
 
         let mut initial = CryptoState {
             tx: CryptoDxState::new_initial(quic_version, CryptoDxDirection::Write, write, dcid),
@@ -649,7 +708,13 @@ impl CryptoStates {
     }
 
     pub fn set_0rtt_keys(&mut self, dir: CryptoDxDirection, secret: &SymKey, cipher: Cipher) {
-        self.zero_rtt = Some(CryptoDxState::new(dir, TLS_EPOCH_ZERO_RTT, secret, cipher));
+        self.zero_rtt = Some(CryptoDxState::new(
+            self.quic_version,
+            dir,
+            TLS_EPOCH_ZERO_RTT,
+            secret,
+            cipher,
+        ));
     }
 
     /// Discard keys and return true if that happened.
@@ -678,12 +743,14 @@ impl CryptoStates {
         self.cipher = cipher;
         self.handshake = Some(CryptoState {
             tx: CryptoDxState::new(
+                self.quic_version,
                 CryptoDxDirection::Write,
                 TLS_EPOCH_HANDSHAKE,
                 write_secret,
                 cipher,
             ),
             rx: CryptoDxState::new(
+                self.quic_version,
                 CryptoDxDirection::Read,
                 TLS_EPOCH_HANDSHAKE,
                 read_secret,
@@ -695,7 +762,12 @@ impl CryptoStates {
     pub fn set_application_write_key(&mut self, secret: SymKey) -> Res<()> {
         debug_assert!(self.app_write.is_none());
         debug_assert_ne!(self.cipher, 0);
-        let mut app = CryptoDxAppData::new(CryptoDxDirection::Write, secret, self.cipher)?;
+        let mut app = CryptoDxAppData::new(
+            self.quic_version,
+            CryptoDxDirection::Write,
+            secret,
+            self.cipher,
+        )?;
         if let Some(z) = &self.zero_rtt {
             if z.direction == CryptoDxDirection::Write {
                 app.dx.continuation(z)?;
@@ -709,7 +781,12 @@ impl CryptoStates {
     pub fn set_application_read_key(&mut self, secret: SymKey, expire_0rtt: Instant) -> Res<()> {
         debug_assert!(self.app_write.is_some(), "should have write keys installed");
         debug_assert!(self.app_read.is_none());
-        let mut app = CryptoDxAppData::new(CryptoDxDirection::Read, secret, self.cipher)?;
+        let mut app = CryptoDxAppData::new(
+            self.quic_version,
+            CryptoDxDirection::Read,
+            secret,
+            self.cipher,
+        )?;
         if let Some(z) = &self.zero_rtt {
             if z.direction == CryptoDxDirection::Read {
                 app.dx.continuation(z)?;
@@ -849,6 +926,7 @@ impl CryptoStates {
                 .unwrap(),
         };
         Self {
+            quic_version: QuicVersion::default(),
             initial: Some(CryptoState {
                 tx: CryptoDxState::test_default(),
                 rx: read(),
@@ -875,6 +953,7 @@ impl CryptoStates {
         let app_read = || CryptoDxAppData {
             dx: CryptoDxState {
                 direction: CryptoDxDirection::Read,
+                quic_version: QuicVersion::default(),
                 epoch: 0,
                 aead: Aead::new(
                     TLS_VERSION_1_3,
@@ -897,6 +976,7 @@ impl CryptoStates {
             next_secret: secret.clone(),
         };
         Self {
+            quic_version: QuicVersion::default(),
             initial: None,
             handshake: None,
             zero_rtt: None,
@@ -972,7 +1052,11 @@ impl CryptoStreams {
     }
 
     pub fn inbound_frame(&mut self, space: PNSpace, offset: u64, data: Vec<u8>) -> Res<()> {
-        self.get_mut(space).unwrap().rx.inbound_frame(offset, data)
+        let rx = &mut self.get_mut(space).unwrap().rx;
+        if offset + data.len() as u64 > rx.retired() + MAX_CRYPTO_BUFFER {
+            return Err(Error::CryptoBufferExceeded);
+        }
+        rx.inbound_frame(offset, data)
     }
 
     pub fn data_ready(&self, space: PNSpace) -> bool {
@@ -1083,3 +1167,98 @@ pub struct CryptoRecoveryToken {
     offset: u64,
     length: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Take the next CRYPTO frame for `space`, returning its offset, data, and token.
+    fn next_frame(
+        streams: &mut CryptoStreams,
+        space: PNSpace,
+        remaining: usize,
+    ) -> Option<(u64, Vec<u8>, CryptoRecoveryToken)> {
+        match streams.get_frame(space, remaining)? {
+            (Frame::Crypto { offset, data }, Some(RecoveryToken::Crypto(token))) => {
+                Some((offset, data, token))
+            }
+            _ => panic!("expected a CRYPTO frame"),
+        }
+    }
+
+    #[test]
+    fn offsets_per_space() {
+        let mut streams = CryptoStreams::default();
+        streams.send(PNSpace::Initial, &[1; 10]);
+        streams.send(PNSpace::Handshake, &[2; 20]);
+        streams.send(PNSpace::Initial, &[3; 5]);
+
+        let (offset, data, _) = next_frame(&mut streams, PNSpace::Initial, 100).unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(data.len(), 15);
+        let (offset, data, _) = next_frame(&mut streams, PNSpace::Handshake, 100).unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(data, vec![2; 20]);
+        assert!(next_frame(&mut streams, PNSpace::Initial, 100).is_none());
+        assert!(next_frame(&mut streams, PNSpace::ApplicationData, 100).is_none());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut streams = CryptoStreams::default();
+        streams
+            .inbound_frame(PNSpace::Handshake, 3, vec![4, 5, 6])
+            .unwrap();
+        assert!(!streams.data_ready(PNSpace::Handshake));
+        // Data in another space doesn't fill the gap.
+        streams
+            .inbound_frame(PNSpace::Initial, 0, vec![9, 9, 9])
+            .unwrap();
+        assert!(!streams.data_ready(PNSpace::Handshake));
+
+        streams
+            .inbound_frame(PNSpace::Handshake, 0, vec![1, 2, 3])
+            .unwrap();
+        assert!(streams.data_ready(PNSpace::Handshake));
+        let mut buf = Vec::new();
+        assert_eq!(streams.read_to_end(PNSpace::Handshake, &mut buf), 6);
+        assert_eq!(buf, vec![1, 2, 3, 4, 5, 6]);
+
+        // A repeat of data that was already read is ignored.
+        streams
+            .inbound_frame(PNSpace::Handshake, 0, vec![1, 2, 3])
+            .unwrap();
+        assert!(!streams.data_ready(PNSpace::Handshake));
+    }
+
+    #[test]
+    fn resend_lost() {
+        let mut streams = CryptoStreams::default();
+        streams.send(PNSpace::Initial, &[1; 30]);
+        let (_, first, t1) = next_frame(&mut streams, PNSpace::Initial, 13).unwrap();
+        let (offset, _, t2) = next_frame(&mut streams, PNSpace::Initial, 100).unwrap();
+        assert_eq!(offset, first.len() as u64);
+        assert!(next_frame(&mut streams, PNSpace::Initial, 100).is_none());
+
+        // Only the lost range is sent again.
+        streams.acked(&t2);
+        streams.lost(&t1);
+        let (offset, data, t1) = next_frame(&mut streams, PNSpace::Initial, 100).unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(data, first);
+        streams.acked(&t1);
+        assert!(next_frame(&mut streams, PNSpace::Initial, 100).is_none());
+    }
+
+    #[test]
+    fn lost_after_discard() {
+        let mut streams = CryptoStreams::default();
+        streams.send(PNSpace::Initial, &[1; 10]);
+        let (_, _, token) = next_frame(&mut streams, PNSpace::Initial, 100).unwrap();
+        streams.discard(PNSpace::Initial);
+        // The loss of a packet in a discarded space is ignored.
+        streams.lost(&token);
+        assert!(!streams.data_ready(PNSpace::Initial));
+        assert!(next_frame(&mut streams, PNSpace::Handshake, 100).is_none());
+    }
+}
@@ -6,14 +6,79 @@
 
 // Enable just this file for logging to just see packets.
 // e.g. "RUST_LOG=neqo_transport::dump neqo-client ..."
+//
+// With the "packet-dump" feature, every packet is logged at trace level
+// instead, with the header fields and every frame.  The bytes that frames
+// carry are replaced with their length, unless the "packet-dump-payload"
+// feature is also enabled.
 
 use crate::connection::Connection;
 use crate::frame::Frame;
 use crate::packet::{PacketNumber, PacketType};
-use neqo_common::{qdebug, Decoder};
+use neqo_common::{hex, hex_with_len, qdebug, qtrace, Decoder};
 
+/// Log the bytes that a frame carries, or just how many there are.
+fn dump_bytes(data: &[u8]) -> String {
+    if cfg!(feature = "packet-dump-payload") {
+        hex_with_len(data)
+    } else {
+        format!("[{}]: <redacted>", data.len())
+    }
+}
+
+/// All of a frame, except for the bytes that it carries.
+fn dump_frame(f: &Frame) -> String {
+    match f {
+        Frame::Crypto { offset, data } => {
+            format!("Crypto {{ offset: {}, data: {} }}", offset, dump_bytes(data))
+        }
+        Frame::NewToken { token } => format!("NewToken {{ token: {} }}", dump_bytes(token)),
+        Frame::Stream {
+            fin,
+            stream_id,
+            offset,
+            data,
+            fill,
+        } => format!(
+            "Stream {{ stream_id: {}, offset: {}, fin: {}, fill: {}, data: {} }}",
+            stream_id.as_u64(),
+            offset,
+            fin,
+            fill,
+            dump_bytes(data)
+        ),
+        Frame::NewConnectionId {
+            sequence_number,
+            retire_prior,
+            connection_id,
+            stateless_reset_token,
+        } => format!(
+            "NewConnectionId {{ sequence_number: {}, retire_prior: {}, connection_id: {}, stateless_reset_token: {} }}",
+            sequence_number,
+            retire_prior,
+            hex(connection_id),
+            dump_bytes(stateless_reset_token)
+        ),
+        Frame::Datagram { data, fill } => {
+            format!("Datagram {{ fill: {}, data: {} }}", fill, dump_bytes(data))
+        }
+        _ => format!("{:?}", f),
+    }
+}
+
+/// Log a packet.  `dcid` and `scid` are the connection IDs from the header;
+/// short header packets don't have `scid`.
 #[allow(clippy::module_name_repetitions)]
-pub fn dump_packet(conn: &Connection, dir: &str, pt: PacketType, pn: PacketNumber, payload: &[u8]) {
+pub fn dump_packet(
+    conn: &Connection,
+    dir: &str,
+    pt: PacketType,
+    pn: PacketNumber,
+    dcid: &[u8],
+    scid: Option<&[u8]>,
+    payload: &[u8],
+) {
+    let detail = cfg!(feature = "packet-dump");
     let mut s = String::from("");
     let mut d = Decoder::from(payload);
     while d.remaining() > 0 {
@@ -24,9 +89,50 @@ pub fn dump_packet(conn: &Connection, dir: &str, pt: PacketType, pn: PacketNumbe
                 break;
             }
         };
-        if let Some(x) = f.dump() {
+        if detail {
+            if f != Frame::Padding {
+                s.push_str(&format!("\n  {} {}", dir, dump_frame(&f)));
+            }
+        } else if let Some(x) = f.dump() {
             s.push_str(&format!("\n  {} {}", dir, &x));
         }
     }
-    qdebug!([conn], "pn={} type={:?}{}", pn, pt, s);
+    if detail {
+        let scid = scid.map_or_else(String::new, |scid| format!(" scid={}", hex(scid)));
+        qtrace!(
+            [conn],
+            "{} pn={} type={:?} dcid={}{} len={}{}",
+            dir,
+            pn,
+            pt,
+            hex(dcid),
+            scid,
+            payload.len(),
+            s
+        );
+    } else {
+        qdebug!([conn], "pn={} type={:?}{}", pn, pt, s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::dump_frame;
+    use crate::frame::Frame;
+    use crate::stream_id::StreamId;
+
+    #[test]
+    fn frame_data_redacted() {
+        let f = Frame::Stream {
+            fin: true,
+            stream_id: StreamId::new(4),
+            offset: 10,
+            data: vec![0xab; 3],
+            fill: false,
+        };
+        let s = dump_frame(&f);
+        assert!(s.contains("stream_id: 4, offset: 10, fin: true"));
+        assert!(s.contains("[3]"));
+        assert_eq!(s.contains("ababab"), cfg!(feature = "packet-dump-payload"));
+    }
 }
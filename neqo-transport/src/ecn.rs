@@ -0,0 +1,288 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Explicit Congestion Notification (ECN).
+//
+// Received packets are counted by their ECN codepoint and the counts are
+// reported to the peer in ACK frames.  If marking is enabled, packets are sent
+// with ECT(0) until the counts that the peer reports show that either the path
+// or the peer doesn't handle ECN properly (Section 13.4 of RFC 9000).  A rise
+// in the count of CE marks is treated as congestion.
+
+use neqo_common::{matches, qinfo, IPTOS_DEFAULT, IPTOS_ECN_CE, IPTOS_ECN_ECT0, IPTOS_ECN_ECT1};
+
+use crate::tracking::{PNSpace, SentPacket};
+
+use std::cmp::max;
+use std::convert::TryFrom;
+
+/// If this many marked packets are lost before any are acknowledged,
+/// the marking itself might be the reason, so marking stops.
+const ECN_TEST_COUNT: usize = 10;
+
+/// The counts of ECN codepoints on received packets, as carried in
+/// an ACK frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EcnCount {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce: u64,
+}
+
+impl EcnCount {
+    /// Count a packet with the given ECN codepoint.
+    pub fn add(&mut self, ecn: u8) {
+        match ecn {
+            IPTOS_ECN_ECT0 => self.ect0 += 1,
+            IPTOS_ECN_ECT1 => self.ect1 += 1,
+            IPTOS_ECN_CE => self.ce += 1,
+            _ => {}
+        }
+    }
+
+    /// Whether no packet was marked.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EcnValidation {
+    /// Packets aren't marked.
+    Disabled,
+    /// Packets are marked, but no acknowledgment has shown that this works.
+    Testing { lost: usize },
+    /// The peer reported the marks correctly.
+    Capable,
+    /// Packets aren't marked any more, because something went wrong.
+    Failed,
+}
+
+/// The ECN state of a path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EcnInfo {
+    state: EcnValidation,
+    /// The counts from the largest ACK frame in each packet number space.
+    baseline: [EcnCount; 3],
+}
+
+impl Default for EcnInfo {
+    fn default() -> Self {
+        Self {
+            state: EcnValidation::Disabled,
+            baseline: [EcnCount::default(); 3],
+        }
+    }
+}
+
+impl EcnInfo {
+    fn idx(space: PNSpace) -> usize {
+        match space {
+            PNSpace::Initial => 0,
+            PNSpace::Handshake => 1,
+            PNSpace::ApplicationData => 2,
+        }
+    }
+
+    /// Start marking packets.
+    pub fn enable(&mut self) {
+        if self.state == EcnValidation::Disabled {
+            self.state = EcnValidation::Testing { lost: 0 };
+        }
+    }
+
+    fn marking(&self) -> bool {
+        matches!(
+            self.state,
+            EcnValidation::Testing { .. } | EcnValidation::Capable
+        )
+    }
+
+    fn fail(&mut self, reason: &str) {
+        qinfo!("ECN validation failed: {}", reason);
+        self.state = EcnValidation::Failed;
+    }
+
+    /// The TOS byte for packets that are sent on the path.
+    pub fn tos(&self) -> u8 {
+        if self.marking() {
+            IPTOS_ECN_ECT0
+        } else {
+            IPTOS_DEFAULT
+        }
+    }
+
+    /// Check the ECN counts from an ACK frame against the packets that it
+    /// newly acknowledged.  All of those were marked, as marking only ever
+    /// stops.  This returns `true` if the peer saw more CE marks,
+    /// which is a signal of congestion.
+    pub fn on_packets_acked(
+        &mut self,
+        space: PNSpace,
+        acked: &[SentPacket],
+        counts: Option<EcnCount>,
+    ) -> bool {
+        if !self.marking() || acked.is_empty() {
+            return false;
+        }
+        let counts = if let Some(c) = counts {
+            c
+        } else {
+            self.fail("ACK without ECN counts");
+            return false;
+        };
+        let base = &mut self.baseline[Self::idx(space)];
+        let ect0 = counts.ect0.saturating_sub(base.ect0);
+        let ce = counts.ce.saturating_sub(base.ce);
+        let marked = u64::try_from(acked.len()).unwrap();
+        if counts.ect1 > 0 {
+            // Nothing is sent with ECT(1).
+            self.fail("ECT(1) reported");
+            return false;
+        }
+        if ect0 + ce < marked {
+            self.fail("too few marks reported");
+            return false;
+        }
+        // An ACK frame can arrive out of order, so counts only ever increase.
+        base.ect0 = max(base.ect0, counts.ect0);
+        base.ce = max(base.ce, counts.ce);
+        self.state = EcnValidation::Capable;
+        ce > 0
+    }
+
+    /// Note packets that were lost.  During testing, these were all marked.
+    pub fn on_packets_lost(&mut self, lost: &[SentPacket]) {
+        if let EcnValidation::Testing { lost: count } = &mut self.state {
+            *count += lost.len();
+            if *count >= ECN_TEST_COUNT {
+                self.fail("marked packets were lost");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EcnCount, EcnInfo, ECN_TEST_COUNT};
+    use crate::packet::PacketType;
+    use crate::tracking::{PNSpace, SentPacket};
+    use neqo_common::{IPTOS_DEFAULT, IPTOS_ECN_CE, IPTOS_ECN_ECT0};
+    use std::convert::TryFrom;
+    use std::rc::Rc;
+    use test_fixture::now;
+
+    fn sent(count: u64) -> Vec<SentPacket> {
+        (0..count)
+            .map(|pn| SentPacket::new(PacketType::Short, pn, now(), true, Rc::default(), 100, true))
+            .collect()
+    }
+
+    fn counts(ect0: u64, ce: u64) -> Option<EcnCount> {
+        Some(EcnCount { ect0, ect1: 0, ce })
+    }
+
+    fn enabled() -> EcnInfo {
+        let mut ecn = EcnInfo::default();
+        ecn.enable();
+        assert_eq!(ecn.tos(), IPTOS_ECN_ECT0);
+        ecn
+    }
+
+    #[test]
+    fn count() {
+        let mut c = EcnCount::default();
+        assert!(c.is_empty());
+        c.add(IPTOS_DEFAULT);
+        assert!(c.is_empty());
+        c.add(IPTOS_ECN_ECT0);
+        c.add(IPTOS_ECN_CE);
+        assert_eq!(c, counts(1, 1).unwrap());
+    }
+
+    #[test]
+    fn disabled() {
+        let mut ecn = EcnInfo::default();
+        assert_eq!(ecn.tos(), IPTOS_DEFAULT);
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &sent(2), None));
+        assert_eq!(ecn.tos(), IPTOS_DEFAULT);
+    }
+
+    #[test]
+    fn validated() {
+        let mut ecn = enabled();
+        let pkts = sent(4);
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &pkts[..2], counts(2, 0)));
+        // The counts are cumulative.
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &pkts[2..], counts(4, 0)));
+        assert_eq!(ecn.tos(), IPTOS_ECN_ECT0);
+    }
+
+    #[test]
+    fn spaces_are_separate() {
+        let mut ecn = enabled();
+        let pkts = sent(2);
+        assert!(!ecn.on_packets_acked(PNSpace::Initial, &pkts, counts(2, 0)));
+        assert!(!ecn.on_packets_acked(PNSpace::Handshake, &pkts, counts(2, 0)));
+        assert_eq!(ecn.tos(), IPTOS_ECN_ECT0);
+    }
+
+    #[test]
+    fn congestion() {
+        let mut ecn = enabled();
+        let pkts = sent(3);
+        assert!(ecn.on_packets_acked(PNSpace::ApplicationData, &pkts[..2], counts(1, 1)));
+        // The same CE count isn't congestion again.
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &pkts[2..], counts(2, 1)));
+        assert_eq!(ecn.tos(), IPTOS_ECN_ECT0);
+    }
+
+    #[test]
+    fn no_counts() {
+        let mut ecn = enabled();
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &sent(1), None));
+        assert_eq!(ecn.tos(), IPTOS_DEFAULT);
+    }
+
+    #[test]
+    fn bleached() {
+        // A path that clears the marks shows up as too few marks.
+        let mut ecn = enabled();
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &sent(3), counts(2, 0)));
+        assert_eq!(ecn.tos(), IPTOS_DEFAULT);
+    }
+
+    #[test]
+    fn ect1_reported() {
+        let mut ecn = enabled();
+        let c = Some(EcnCount {
+            ect0: 1,
+            ect1: 1,
+            ce: 0,
+        });
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &sent(1), c));
+        assert_eq!(ecn.tos(), IPTOS_DEFAULT);
+    }
+
+    #[test]
+    fn lost_while_testing() {
+        let mut ecn = enabled();
+        let pkts = sent(u64::try_from(ECN_TEST_COUNT).unwrap());
+        ecn.on_packets_lost(&pkts[1..]);
+        assert_eq!(ecn.tos(), IPTOS_ECN_ECT0);
+        ecn.on_packets_lost(&pkts[..1]);
+        assert_eq!(ecn.tos(), IPTOS_DEFAULT);
+    }
+
+    #[test]
+    fn lost_after_testing() {
+        let mut ecn = enabled();
+        let pkts = sent(u64::try_from(ECN_TEST_COUNT).unwrap());
+        assert!(!ecn.on_packets_acked(PNSpace::ApplicationData, &pkts[..1], counts(1, 0)));
+        ecn.on_packets_lost(&pkts);
+        assert_eq!(ecn.tos(), IPTOS_ECN_ECT0);
+    }
+}
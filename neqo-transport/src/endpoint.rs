@@ -0,0 +1,307 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Client connections that share a socket.
+//
+// An `Endpoint` makes client connections and keeps a table of the connection
+// IDs that they use.  Datagrams that arrive on the socket are routed to a
+// connection using the destination connection ID of the first packet, in the
+// same way that `Server` routes datagrams.  This lets a client race
+// connections, probe new paths, or keep a pool of connections, all with one
+// socket.
+
+use neqo_common::{hex, matches, qdebug, qtrace, Datagram, Decoder};
+
+use crate::cid::{ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef};
+use crate::connection::{Connection, Output, State};
+use crate::packet::{PublicPacket, QuicVersion};
+use crate::Res;
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::time::Instant;
+
+pub type ConnectionRef = Rc<RefCell<Connection>>;
+type CidMgr = Rc<RefCell<dyn ConnectionIdManager>>;
+type ConnectionTableRef = Rc<RefCell<HashMap<ConnectionId, Weak<RefCell<Connection>>>>>;
+
+pub struct Endpoint {
+    /// Makes the connection IDs for all of the connections.
+    cid_manager: CidMgr,
+    /// All connections, keyed by ConnectionId.
+    connections: ConnectionTableRef,
+    /// The connections in the order they were made.
+    all: Vec<ConnectionRef>,
+    /// The connection to ask for output first, so that each gets a turn.
+    next_output: usize,
+}
+
+impl Endpoint {
+    /// Construct a new endpoint.  `cid_manager` is responsible for generating
+    /// connection IDs and parsing them; connection IDs produced by the manager
+    /// cannot be zero-length, as datagrams couldn't be routed otherwise.
+    pub fn new(cid_manager: CidMgr) -> Self {
+        Self {
+            cid_manager,
+            connections: Rc::default(),
+            all: Vec::new(),
+            next_output: 0,
+        }
+    }
+
+    /// Make a new client connection that uses this endpoint.  The connection
+    /// can be used as usual, except that datagrams are passed to the endpoint.
+    pub fn connect(
+        &mut self,
+        server_name: &str,
+        protocols: &[impl AsRef<str>],
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+        quic_version: QuicVersion,
+    ) -> Res<ConnectionRef> {
+        // Wrap the connection ID manager so that connection IDs are saved.
+        let cid_mgr = Rc::new(RefCell::new(EndpointConnectionIdManager {
+            c: Weak::new(),
+            connections: Rc::clone(&self.connections),
+            cid_manager: Rc::clone(&self.cid_manager),
+            saved_cids: Vec::new(),
+        }));
+        let c = Rc::new(RefCell::new(Connection::new_client(
+            server_name,
+            protocols,
+            Rc::clone(&cid_mgr) as _,
+            local_addr,
+            remote_addr,
+            quic_version,
+        )?));
+        cid_mgr.borrow_mut().set_connection(&c);
+        self.all.push(Rc::clone(&c));
+        Ok(c)
+    }
+
+    /// The connections that were open the last time `process` was called.
+    pub fn connections(&self) -> &[ConnectionRef] {
+        &self.all
+    }
+
+    fn connection(&self, cid: &ConnectionIdRef) -> Option<ConnectionRef> {
+        self.connections
+            .borrow()
+            .get(&cid[..])
+            .and_then(Weak::upgrade)
+    }
+
+    fn process_input(&mut self, dgram: Datagram, now: Instant) {
+        qtrace!([self], "Process datagram: {}", hex(&dgram[..]));
+
+        // All packets in the datagram are routed to the same connection.
+        let res = PublicPacket::decode(&dgram[..], self.cid_manager.borrow().as_decoder());
+        let c = match res {
+            Ok((packet, _)) => self.connection(packet.dcid()),
+            _ => None,
+        };
+        if let Some(c) = c {
+            c.borrow_mut().process_input(dgram, now);
+        } else {
+            qdebug!([self], "Discarding datagram for an unknown connection");
+        }
+    }
+
+    /// Forget about connections that have closed.
+    fn remove_closed(&mut self) {
+        let closed = |c: &ConnectionRef| matches!(c.borrow().state(), State::Closed(_));
+        if self.all.iter().any(closed) {
+            self.all.retain(|c| !closed(c));
+            self.connections
+                .borrow_mut()
+                .retain(|_, c| c.upgrade().map_or(false, |c| !closed(&c)));
+        }
+    }
+
+    /// Pass a datagram to the connection that it is for, if any, then get the
+    /// next datagram to send for any of the connections, or how long to wait.
+    /// This returns `Output::None` once all of the connections have closed.
+    pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
+        if let Some(d) = dgram {
+            self.process_input(d, now);
+        }
+
+        let mut wait = None;
+        let count = self.all.len();
+        for i in 0..count {
+            let idx = (self.next_output + i) % count;
+            match self.all[idx].borrow_mut().process_output(now) {
+                Output::Datagram(d) => {
+                    self.next_output = idx + 1;
+                    return Output::Datagram(d);
+                }
+                Output::Callback(t) => wait = Some(wait.map_or(t, |w| min(w, t))),
+                Output::None => {}
+            }
+        }
+        self.remove_closed();
+        wait.map_or(Output::None, Output::Callback)
+    }
+}
+
+impl ::std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Endpoint")
+    }
+}
+
+struct EndpointConnectionIdManager {
+    c: Weak<RefCell<Connection>>,
+    connections: ConnectionTableRef,
+    cid_manager: CidMgr,
+    saved_cids: Vec<ConnectionId>,
+}
+
+impl EndpointConnectionIdManager {
+    fn set_connection(&mut self, c: &ConnectionRef) {
+        self.c = Rc::downgrade(c);
+        for cid in std::mem::take(&mut self.saved_cids) {
+            self.insert_cid(cid);
+        }
+    }
+
+    fn insert_cid(&mut self, cid: ConnectionId) {
+        debug_assert!(!cid.is_empty());
+        self.connections
+            .borrow_mut()
+            .insert(cid, Weak::clone(&self.c));
+    }
+}
+
+impl ConnectionIdDecoder for EndpointConnectionIdManager {
+    fn decode_cid<'a>(&self, dec: &mut Decoder<'a>) -> Option<ConnectionIdRef<'a>> {
+        self.cid_manager.borrow_mut().decode_cid(dec)
+    }
+}
+
+impl ConnectionIdManager for EndpointConnectionIdManager {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let cid = self.cid_manager.borrow_mut().generate_cid();
+        if self.c.upgrade().is_some() {
+            self.insert_cid(cid.clone());
+        } else {
+            // The first connection ID is made before the connection is set.
+            self.saved_cids.push(cid.clone());
+        }
+        cid
+    }
+
+    fn as_decoder(&self) -> &dyn ConnectionIdDecoder {
+        self
+    }
+
+    fn reset_token(&self, cid: &[u8]) -> [u8; 16] {
+        self.cid_manager.borrow().reset_token(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Endpoint;
+    use crate::connection::{FixedConnectionIdManager, Output, State};
+    use crate::packet::QuicVersion;
+    use crate::server::Server;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use test_fixture::{self, fixture_init, loopback, maybe_authenticate, now};
+
+    fn endpoint() -> Endpoint {
+        fixture_init();
+        Endpoint::new(Rc::new(RefCell::new(
+            FixedConnectionIdManager::new(6).unwrap(),
+        )))
+    }
+
+    fn server() -> Server {
+        Server::new(
+            now(),
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            test_fixture::anti_replay(),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(5).unwrap())),
+        )
+        .unwrap()
+    }
+
+    fn connect(endpoint: &mut Endpoint) {
+        endpoint
+            .connect(
+                test_fixture::DEFAULT_SERVER_NAME,
+                test_fixture::DEFAULT_ALPN,
+                loopback(),
+                loopback(),
+                QuicVersion::default(),
+            )
+            .unwrap();
+    }
+
+    /// Pass datagrams between the endpoint and server until neither has any.
+    fn exchange(endpoint: &mut Endpoint, server: &mut Server) {
+        let mut dgram = None;
+        loop {
+            for c in endpoint.connections() {
+                let _ = maybe_authenticate(&mut c.borrow_mut());
+            }
+            let out = endpoint.process(dgram.take(), now()).dgram();
+            let idle = out.is_none();
+            dgram = server.process(out, now()).dgram();
+            if idle && dgram.is_none() {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn two_connections() {
+        let mut endpoint = endpoint();
+        let mut server = server();
+        connect(&mut endpoint);
+        connect(&mut endpoint);
+        exchange(&mut endpoint, &mut server);
+
+        // Both connections complete over the one socket.
+        assert_eq!(endpoint.connections().len(), 2);
+        for c in endpoint.connections() {
+            assert_eq!(*c.borrow().state(), State::Confirmed);
+        }
+        assert_eq!(server.active_connections().len(), 2);
+    }
+
+    #[test]
+    fn closed_connections_are_removed() {
+        let mut endpoint = endpoint();
+        let mut server = server();
+        connect(&mut endpoint);
+        connect(&mut endpoint);
+        exchange(&mut endpoint, &mut server);
+
+        let first = Rc::clone(&endpoint.connections()[0]);
+        first.borrow_mut().close(now(), 0, "done");
+        exchange(&mut endpoint, &mut server);
+        // The connection is removed once it is closed.
+        let mut t = now();
+        while endpoint.connections().len() > 1 {
+            match endpoint.process(None, t) {
+                Output::Datagram(_) => {}
+                Output::Callback(d) => t += d,
+                Output::None => panic!("the other connection is still open"),
+            }
+        }
+        assert!(!Rc::ptr_eq(&endpoint.connections()[0], &first));
+        assert_eq!(
+            *endpoint.connections()[0].borrow().state(),
+            State::Confirmed
+        );
+    }
+}
@@ -41,6 +41,18 @@ pub enum ConnectionEvent {
     /// This event invalidates all state in streams that has been created.
     /// Any data written to streams needs to be written again.
     ZeroRttRejected,
+    /// A datagram was received.
+    Datagram(Vec<u8>),
+    /// The server accepted encrypted client hello (ECH).
+    EchAccepted,
+    /// The server rejected ECH and the connection is closing.
+    /// This holds the configurations for a new connection to use; if there
+    /// are none, the new connection shouldn't use ECH.
+    EchRejected(Vec<u8>),
+    /// The client has a new resumption token, which comes from a session
+    /// ticket that the server sent.  This holds the token, which is what
+    /// `Connection::resumption_token` returns.
+    ResumptionToken(Vec<u8>),
 }
 
 #[derive(Debug, Default, Clone)]
@@ -118,6 +130,24 @@ impl ConnectionEvents {
         self.insert(ConnectionEvent::ZeroRttRejected);
     }
 
+    pub fn datagram(&self, data: Vec<u8>) {
+        self.insert(ConnectionEvent::Datagram(data));
+    }
+
+    pub fn ech_accepted(&self) {
+        self.insert(ConnectionEvent::EchAccepted);
+    }
+
+    pub fn ech_rejected(&self, retry_configs: Vec<u8>) {
+        self.insert(ConnectionEvent::EchRejected(retry_configs));
+    }
+
+    pub fn resumption_token(&self, token: Vec<u8>) {
+        // Only the latest token is useful.
+        self.remove(|evt| matches!(evt, ConnectionEvent::ResumptionToken(_)));
+        self.insert(ConnectionEvent::ResumptionToken(token));
+    }
+
     pub fn recv_stream_complete(&self, stream_id: StreamId) {
         // If stopped, no longer readable.
         self.remove(|evt| matches!(evt, ConnectionEvent::RecvStreamReadable { stream_id: x } if *x == stream_id.as_u64()));
@@ -152,6 +182,8 @@ impl ConnectionEvents {
 		    evt, ConnectionEvent::RecvStreamReset { stream_id: x, .. }
 		    if *x == *stream_id)
             }),
+            // Identical datagrams are still distinct.
+            ConnectionEvent::Datagram(_) => false,
             _ => q.contains(&event),
         } {
             // Already in event list.
@@ -10,7 +10,7 @@
 use std::collections::HashMap;
 use std::mem;
 
-use neqo_common::{qinfo, qtrace, qwarn, Encoder};
+use neqo_common::{qinfo, qtrace, qwarn};
 
 use crate::frame::{Frame, StreamType};
 use crate::recovery::RecoveryToken;
@@ -22,6 +22,29 @@ use crate::AppError;
 
 pub type FlowControlRecoveryToken = Frame;
 
+/// Where a queued frame is kept.
+#[derive(Clone, Copy)]
+enum FrameKey {
+    Conn(mem::Discriminant<Frame>),
+    Stream(StreamId, mem::Discriminant<Frame>),
+    StreamType(StreamType, mem::Discriminant<Frame>),
+    Cid(u64, mem::Discriminant<Frame>),
+}
+
+/// The order in which queued frames are sent, lowest first.  A peer that is
+/// waiting on a PATH_RESPONSE or more credit is stalled, so those go before
+/// frames that only report on or end streams.
+fn priority(frame: &Frame) -> usize {
+    match frame {
+        Frame::PathResponse { .. } => 0,
+        Frame::MaxData { .. } => 1,
+        Frame::MaxStreamData { .. } => 2,
+        Frame::MaxStreams { .. } => 3,
+        Frame::ResetStream { .. } | Frame::StopSending { .. } => 4,
+        _ => 5,
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct FlowMgr {
     // Discriminant as key ensures only 1 of every frame type will be queued.
@@ -35,6 +58,10 @@ pub struct FlowMgr {
     // per stream type will be queued.
     from_stream_types: HashMap<(StreamType, mem::Discriminant<Frame>), Frame>,
 
+    // (sequence number, discriminant) as key ensures only 1 NEW_CONNECTION_ID
+    // or RETIRE_CONNECTION_ID for every connection ID will be queued.
+    cids: HashMap<(u64, mem::Discriminant<Frame>), Frame>,
+
     used_data: u64,
     max_data: u64,
 }
@@ -84,6 +111,36 @@ impl FlowMgr {
         self.from_conn.insert(mem::discriminant(&frame), frame);
     }
 
+    /// Give the peer another connection ID that it can use.
+    pub fn new_connection_id(
+        &mut self,
+        sequence_number: u64,
+        connection_id: Vec<u8>,
+        stateless_reset_token: [u8; 16],
+    ) {
+        let frame = Frame::NewConnectionId {
+            sequence_number,
+            retire_prior: 0,
+            connection_id,
+            stateless_reset_token,
+        };
+        self.cids
+            .insert((sequence_number, mem::discriminant(&frame)), frame);
+    }
+
+    /// Give the client a token for its next connection.
+    pub fn new_token(&mut self, token: Vec<u8>) {
+        let frame = Frame::NewToken { token };
+        self.from_conn.insert(mem::discriminant(&frame), frame);
+    }
+
+    /// Tell the peer that we won't use one of its connection IDs again.
+    pub fn retire_connection_id(&mut self, sequence_number: u64) {
+        let frame = Frame::RetireConnectionId { sequence_number };
+        self.cids
+            .insert((sequence_number, mem::discriminant(&frame)), frame);
+    }
+
     // -- frames scoped on stream --
 
     /// Indicate to receiving remote the stream is reset
@@ -162,18 +219,46 @@ impl FlowMgr {
             .insert((stream_type, mem::discriminant(&frame)), frame);
     }
 
-    pub fn peek(&self) -> Option<&Frame> {
-        if let Some(key) = self.from_conn.keys().next() {
-            self.from_conn.get(key)
-        } else if let Some(key) = self.from_streams.keys().next() {
-            self.from_streams.get(key)
-        } else if let Some(key) = self.from_stream_types.keys().next() {
-            self.from_stream_types.get(key)
-        } else {
-            None
+    fn queued(&self) -> impl Iterator<Item = (FrameKey, &Frame)> {
+        let conn = self.from_conn.iter().map(|(d, f)| (FrameKey::Conn(*d), f));
+        let streams = self
+            .from_streams
+            .iter()
+            .map(|((id, d), f)| (FrameKey::Stream(*id, *d), f));
+        let stream_types = self
+            .from_stream_types
+            .iter()
+            .map(|((st, d), f)| (FrameKey::StreamType(*st, *d), f));
+        let cids = self
+            .cids
+            .iter()
+            .map(|((seqno, d), f)| (FrameKey::Cid(*seqno, *d), f));
+        conn.chain(streams).chain(stream_types).chain(cids)
+    }
+
+    /// Find the most important frame that encodes to no more than `remaining` bytes.
+    fn find(&self, remaining: usize) -> Option<(FrameKey, &Frame)> {
+        self.queued()
+            .filter(|(_, f)| f.encoded_len() <= remaining)
+            .min_by_key(|(_, f)| priority(f))
+    }
+
+    fn remove(&mut self, key: FrameKey) -> Option<Frame> {
+        match key {
+            FrameKey::Conn(d) => self.from_conn.remove(&d),
+            FrameKey::Stream(id, d) => self.from_streams.remove(&(id, d)),
+            FrameKey::StreamType(st, d) => self.from_stream_types.remove(&(st, d)),
+            FrameKey::Cid(seqno, d) => self.cids.remove(&(seqno, d)),
         }
     }
 
+    /// The frame that will be sent next, if there is space for it.
+    pub fn peek(&self) -> Option<&Frame> {
+        self.queued()
+            .min_by_key(|(_, f)| priority(f))
+            .map(|(_, f)| f)
+    }
+
     pub(crate) fn acked(
         &mut self,
         token: &FlowControlRecoveryToken,
@@ -270,6 +355,21 @@ impl FlowMgr {
                     }
                 }
             }
+            // Always resend NewConnectionId and RetireConnectionId if lost
+            Frame::NewConnectionId {
+                sequence_number,
+                ref connection_id,
+                stateless_reset_token,
+                ..
+            } => self.new_connection_id(
+                sequence_number,
+                connection_id.clone(),
+                stateless_reset_token,
+            ),
+            Frame::RetireConnectionId { sequence_number } => {
+                self.retire_connection_id(sequence_number)
+            }
+            Frame::NewToken { ref token } => self.new_token(token.clone()),
             Frame::PathResponse { .. } => qinfo!("Path Response lost, not re-sent"),
             _ => qwarn!("Unexpected Flow frame {:?} lost, not re-sent", token),
         }
@@ -284,43 +384,116 @@ impl FlowMgr {
             return None;
         }
 
-        if let Some(frame) = self.peek() {
-            // A suboptimal way to figure out if the frame fits within remaining
-            // space.
-            let mut d = Encoder::default();
-            frame.marshal(&mut d);
-            if d.len() > remaining {
+        // Something smaller can go in if the most important frame doesn't fit.
+        let key = if let Some((key, _)) = self.find(remaining) {
+            key
+        } else {
+            if !self.is_empty() {
                 qtrace!("flowc frame doesn't fit in remaining");
-                return None;
             }
-        } else {
             return None;
-        }
-        // There is enough space we can add this frame to the packet.
-        let frame = self.next().expect("just peeked this");
+        };
+        let frame = self.remove(key).expect("just found this");
         Some((frame.clone(), Some(RecoveryToken::Flow(frame))))
     }
+
+    fn is_empty(&self) -> bool {
+        self.from_conn.is_empty()
+            && self.from_streams.is_empty()
+            && self.from_stream_types.is_empty()
+            && self.cids.is_empty()
+    }
 }
 
 impl Iterator for FlowMgr {
     type Item = Frame;
     /// Used by generator to get a flow control frame.
     fn next(&mut self) -> Option<Frame> {
-        let first_key = self.from_conn.keys().next();
-        if let Some(&first_key) = first_key {
-            return self.from_conn.remove(&first_key);
-        }
+        let key = self.queued().min_by_key(|(_, f)| priority(f))?.0;
+        self.remove(key)
+    }
+}
 
-        let first_key = self.from_streams.keys().next();
-        if let Some(&first_key) = first_key {
-            return self.from_streams.remove(&first_key);
-        }
+#[cfg(test)]
+mod tests {
+    use super::FlowMgr;
+    use crate::frame::{Frame, StreamType};
+    use crate::stream_id::{StreamId, StreamIndex};
+    use crate::tracking::PNSpace;
+    use neqo_common::matches;
+
+    #[test]
+    fn credit_first() {
+        let mut fc = FlowMgr::default();
+        fc.stream_data_blocked(StreamId::new(0), 10);
+        fc.stream_reset(StreamId::new(4), 0, 0);
+        fc.streams_blocked(StreamIndex::new(4), StreamType::BiDi);
+        fc.max_stream_data(StreamId::new(8), 100);
+        fc.path_response([1; 8]);
+        fc.max_data(1000);
+
+        assert!(matches!(fc.next(), Some(Frame::PathResponse { .. })));
+        assert!(matches!(fc.next(), Some(Frame::MaxData { .. })));
+        assert!(matches!(fc.next(), Some(Frame::MaxStreamData { .. })));
+        assert!(matches!(fc.next(), Some(Frame::ResetStream { .. })));
+        assert!(fc.next().is_some());
+        assert!(fc.next().is_some());
+        assert!(fc.next().is_none());
+    }
 
-        let first_key = self.from_stream_types.keys().next();
-        if let Some(&first_key) = first_key {
-            return self.from_stream_types.remove(&first_key);
-        }
+    #[test]
+    fn smaller_frame_fits() {
+        let mut fc = FlowMgr::default();
+        // PATH_RESPONSE takes 9 bytes; MAX_STREAMS here takes 2.
+        fc.path_response([1; 8]);
+        fc.max_streams(StreamIndex::new(4), StreamType::UniDi);
+
+        let (frame, _) = fc.get_frame(PNSpace::ApplicationData, 8).unwrap();
+        assert!(matches!(frame, Frame::MaxStreams { .. }));
+        assert!(fc.get_frame(PNSpace::ApplicationData, 8).is_none());
+        let (frame, _) = fc.get_frame(PNSpace::ApplicationData, 9).unwrap();
+        assert!(matches!(frame, Frame::PathResponse { .. }));
+    }
 
-        None
+    #[test]
+    fn retire_connection_ids() {
+        let mut fc = FlowMgr::default();
+        fc.retire_connection_id(1);
+        fc.retire_connection_id(2);
+        fc.retire_connection_id(1);
+
+        let mut retired = vec![fc.next().unwrap(), fc.next().unwrap()];
+        assert!(fc.next().is_none());
+        retired.sort_by_key(|f| match f {
+            Frame::RetireConnectionId { sequence_number } => *sequence_number,
+            _ => panic!("not a RETIRE_CONNECTION_ID"),
+        });
+        assert_eq!(
+            retired,
+            vec![
+                Frame::RetireConnectionId { sequence_number: 1 },
+                Frame::RetireConnectionId { sequence_number: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn new_connection_ids() {
+        let mut fc = FlowMgr::default();
+        fc.new_connection_id(1, vec![1, 2, 3], [1; 16]);
+        fc.new_connection_id(1, vec![1, 2, 3], [1; 16]);
+        // Retiring the same sequence number is a different frame.
+        fc.retire_connection_id(1);
+
+        let frames = vec![fc.next().unwrap(), fc.next().unwrap()];
+        assert!(fc.next().is_none());
+        assert!(frames.iter().any(|f| matches!(
+            f,
+            Frame::NewConnectionId {
+                sequence_number: 1,
+                ..
+            }
+        )));
+        assert!(frames.contains(&Frame::RetireConnectionId { sequence_number: 1 }));
     }
 }
@@ -9,6 +9,7 @@
 use neqo_common::{matches, qdebug, qtrace, Decoder, Encoder};
 
 use crate::cid::MAX_CONNECTION_ID_LEN;
+use crate::ecn::EcnCount;
 use crate::packet::PacketType;
 use crate::stream_id::{StreamId, StreamIndex};
 use crate::{AppError, ConnectionError, Error, Res, TransportError, ERROR_APPLICATION_CLOSE};
@@ -44,6 +45,8 @@ const FRAME_TYPE_PATH_RESPONSE: FrameType = 0x1b;
 pub const FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT: FrameType = 0x1c;
 pub const FRAME_TYPE_CONNECTION_CLOSE_APPLICATION: FrameType = 0x1d;
 const FRAME_TYPE_HANDSHAKE_DONE: FrameType = 0x1e;
+const FRAME_TYPE_DATAGRAM: FrameType = 0x30;
+const FRAME_TYPE_DATAGRAM_WITH_LEN: FrameType = 0x31;
 
 const STREAM_FRAME_BIT_FIN: u64 = 0x01;
 const STREAM_FRAME_BIT_LEN: u64 = 0x02;
@@ -134,6 +137,7 @@ pub enum Frame {
         ack_delay: u64,
         first_ack_range: u64,
         ack_ranges: Vec<AckRange>,
+        ecn_count: Option<EcnCount>,
     },
     ResetStream {
         stream_id: StreamId,
@@ -201,6 +205,10 @@ pub enum Frame {
         reason_phrase: Vec<u8>,
     },
     HandshakeDone,
+    Datagram {
+        data: Vec<u8>,
+        fill: bool,
+    },
 }
 
 impl Frame {
@@ -208,7 +216,13 @@ impl Frame {
         match self {
             Self::Padding => FRAME_TYPE_PADDING,
             Self::Ping => FRAME_TYPE_PING,
-            Self::Ack { .. } => FRAME_TYPE_ACK, // We don't do ACK ECN.
+            Self::Ack { ecn_count, .. } => {
+                if ecn_count.is_some() {
+                    FRAME_TYPE_ACK_ECN
+                } else {
+                    FRAME_TYPE_ACK
+                }
+            }
             Self::ResetStream { .. } => FRAME_TYPE_RST_STREAM,
             Self::StopSending { .. } => FRAME_TYPE_STOP_SENDING,
             Self::Crypto { .. } => FRAME_TYPE_CRYPTO,
@@ -246,6 +260,13 @@ impl Frame {
                 FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT + error_code.frame_type_bit()
             }
             Self::HandshakeDone => FRAME_TYPE_HANDSHAKE_DONE,
+            Self::Datagram { fill, .. } => {
+                if *fill {
+                    FRAME_TYPE_DATAGRAM
+                } else {
+                    FRAME_TYPE_DATAGRAM_WITH_LEN
+                }
+            }
         }
     }
 
@@ -342,6 +363,7 @@ impl Frame {
                 ack_delay,
                 first_ack_range,
                 ack_ranges,
+                ecn_count,
             } => {
                 enc.encode_varint(*largest_acknowledged);
                 enc.encode_varint(*ack_delay);
@@ -351,6 +373,11 @@ impl Frame {
                     enc.encode_varint(r.gap);
                     enc.encode_varint(r.range);
                 }
+                if let Some(ecn) = ecn_count {
+                    enc.encode_varint(ecn.ect0);
+                    enc.encode_varint(ecn.ect1);
+                    enc.encode_varint(ecn.ce);
+                }
             }
             Self::ResetStream {
                 stream_id,
@@ -451,7 +478,110 @@ impl Frame {
                 enc.encode_vvec(reason_phrase);
             }
             Self::HandshakeDone => (),
+            Self::Datagram { data, fill } => {
+                if *fill {
+                    enc.encode(&data);
+                } else {
+                    enc.encode_vvec(&data);
+                }
+            }
+        }
+    }
+
+    /// The number of bytes that `marshal` will write, worked out without encoding.
+    pub fn encoded_len(&self) -> usize {
+        fn varint(v: u64) -> usize {
+            Encoder::varint_len(v)
         }
+        fn vvec(v: &[u8]) -> usize {
+            varint(u64::try_from(v.len()).unwrap()) + v.len()
+        }
+
+        let body = match self {
+            Self::Padding | Self::Ping | Self::HandshakeDone => 0,
+            Self::Ack {
+                largest_acknowledged,
+                ack_delay,
+                first_ack_range,
+                ack_ranges,
+                ecn_count,
+            } => {
+                varint(*largest_acknowledged)
+                    + varint(*ack_delay)
+                    + varint(ack_ranges.len() as u64)
+                    + varint(*first_ack_range)
+                    + ack_ranges
+                        .iter()
+                        .map(|r| varint(r.gap) + varint(r.range))
+                        .sum::<usize>()
+                    + ecn_count.map_or(0, |ecn| {
+                        varint(ecn.ect0) + varint(ecn.ect1) + varint(ecn.ce)
+                    })
+            }
+            Self::ResetStream {
+                stream_id,
+                application_error_code,
+                final_size,
+            } => varint(stream_id.as_u64()) + varint(*application_error_code) + varint(*final_size),
+            Self::StopSending {
+                stream_id,
+                application_error_code,
+            } => varint(stream_id.as_u64()) + varint(*application_error_code),
+            Self::Crypto { offset, data } => varint(*offset) + vvec(data),
+            Self::NewToken { token } => vvec(token),
+            Self::Stream {
+                stream_id,
+                offset,
+                data,
+                fill,
+                ..
+            } => {
+                let offset_len = if *offset > 0 { varint(*offset) } else { 0 };
+                let data_len = if *fill { data.len() } else { vvec(data) };
+                varint(stream_id.as_u64()) + offset_len + data_len
+            }
+            Self::MaxData { maximum_data } => varint(*maximum_data),
+            Self::MaxStreamData {
+                stream_id,
+                maximum_stream_data,
+            } => varint(stream_id.as_u64()) + varint(*maximum_stream_data),
+            Self::MaxStreams {
+                maximum_streams, ..
+            } => varint(maximum_streams.as_u64()),
+            Self::DataBlocked { data_limit } => varint(*data_limit),
+            Self::StreamDataBlocked {
+                stream_id,
+                stream_data_limit,
+            } => varint(stream_id.as_u64()) + varint(*stream_data_limit),
+            Self::StreamsBlocked { stream_limit, .. } => varint(stream_limit.as_u64()),
+            Self::NewConnectionId {
+                sequence_number,
+                retire_prior,
+                connection_id,
+                stateless_reset_token,
+            } => {
+                varint(*sequence_number)
+                    + varint(*retire_prior)
+                    + 1
+                    + connection_id.len()
+                    + stateless_reset_token.len()
+            }
+            Self::RetireConnectionId { sequence_number } => varint(*sequence_number),
+            Self::PathChallenge { data } | Self::PathResponse { data } => data.len(),
+            Self::ConnectionClose {
+                error_code,
+                frame_type,
+                reason_phrase,
+            } => varint(error_code.code()) + varint(*frame_type) + vvec(reason_phrase),
+            Self::Datagram { data, fill } => {
+                if *fill {
+                    data.len()
+                } else {
+                    vvec(data)
+                }
+            }
+        };
+        varint(self.get_type()) + body
     }
 
     /// Convert a CONNECTION_CLOSE into a nicer CONNECTION_CLOSE.
@@ -535,21 +665,41 @@ impl Frame {
                 data.len(),
                 fin,
             )),
+            Self::Datagram { data, .. } => Some(format!("Datagram {{ len: {} }}", data.len())),
             Self::Padding => None,
             _ => Some(format!("{:?}", self)),
         }
     }
 
+    /// Whether this frame can be carried in a packet of type `pt`.  This follows
+    /// Table 3 of RFC 9000; every frame is listed so that new frames have to be
+    /// added here.
     pub fn is_allowed(&self, pt: PacketType) -> bool {
-        if matches!(self, Self::Padding | Self::Ping) {
-            true
-        } else if matches!(self, Self::Crypto {..} | Self::Ack {..} | Self::ConnectionClose { error_code: CloseError::Transport(_), .. })
-        {
-            pt != PacketType::ZeroRtt
-        } else if matches!(self, Self::NewToken {..} | Self::ConnectionClose {..}) {
-            pt == PacketType::Short
-        } else {
-            pt == PacketType::ZeroRtt || pt == PacketType::Short
+        match self {
+            Self::Padding | Self::Ping => true,
+            Self::Crypto { .. }
+            | Self::Ack { .. }
+            | Self::ConnectionClose {
+                error_code: CloseError::Transport(_),
+                ..
+            } => pt != PacketType::ZeroRtt,
+            Self::NewToken { .. }
+            | Self::PathResponse { .. }
+            | Self::HandshakeDone
+            | Self::ConnectionClose { .. } => pt == PacketType::Short,
+            Self::ResetStream { .. }
+            | Self::StopSending { .. }
+            | Self::Stream { .. }
+            | Self::MaxData { .. }
+            | Self::MaxStreamData { .. }
+            | Self::MaxStreams { .. }
+            | Self::DataBlocked { .. }
+            | Self::StreamDataBlocked { .. }
+            | Self::StreamsBlocked { .. }
+            | Self::NewConnectionId { .. }
+            | Self::RetireConnectionId { .. }
+            | Self::PathChallenge { .. }
+            | Self::Datagram { .. } => pt == PacketType::ZeroRtt || pt == PacketType::Short,
         }
     }
 
@@ -586,7 +736,12 @@ impl Frame {
                 let ad = dv!(dec);
                 let nr = dv!(dec);
                 let fa = dv!(dec);
-                let mut arr: Vec<AckRange> = Vec::with_capacity(nr as usize);
+                // Each range takes at least two bytes, so don't trust `nr` any further than that.
+                let capacity = min(
+                    usize::try_from(nr).unwrap_or(usize::max_value()),
+                    dec.remaining() / 2,
+                );
+                let mut arr: Vec<AckRange> = Vec::with_capacity(capacity);
                 for _ in 0..nr {
                     let ar = AckRange {
                         gap: dv!(dec),
@@ -596,17 +751,22 @@ impl Frame {
                 }
 
                 // Now check for the values for ACK_ECN.
-                if t == FRAME_TYPE_ACK_ECN {
-                    dv!(dec);
-                    dv!(dec);
-                    dv!(dec);
-                }
+                let ecn_count = if t == FRAME_TYPE_ACK_ECN {
+                    Some(EcnCount {
+                        ect0: dv!(dec),
+                        ect1: dv!(dec),
+                        ce: dv!(dec),
+                    })
+                } else {
+                    None
+                };
 
                 Ok(Self::Ack {
                     largest_acknowledged: la,
                     ack_delay: ad,
                     first_ack_range: fa,
                     ack_ranges: arr,
+                    ecn_count,
                 })
             }
             FRAME_TYPE_STOP_SENDING => Ok(Self::StopSending {
@@ -689,7 +849,7 @@ impl Frame {
                 let s = dv!(dec);
                 let retire_prior = dv!(dec);
                 let cid = d!(dec.decode_vec(1)).to_vec(); // TODO(mt) unnecessary copy
-                if cid.len() > MAX_CONNECTION_ID_LEN {
+                if cid.is_empty() || cid.len() > MAX_CONNECTION_ID_LEN {
                     return Err(Error::DecodingFrame);
                 }
                 let srt = d!(dec.decode(16));
@@ -726,6 +886,18 @@ impl Frame {
                 })
             }
             FRAME_TYPE_HANDSHAKE_DONE => Ok(Self::HandshakeDone),
+            FRAME_TYPE_DATAGRAM | FRAME_TYPE_DATAGRAM_WITH_LEN => {
+                let fill = t == FRAME_TYPE_DATAGRAM;
+                let data = if fill {
+                    dec.decode_remainder()
+                } else {
+                    d!(dec.decode_vvec())
+                };
+                Ok(Self::Datagram {
+                    data: data.to_vec(), // TODO(mt) unnecessary copy.
+                    fill,
+                })
+            }
             _ => Err(Error::UnknownFrameType),
         }
     }
@@ -741,6 +913,7 @@ mod tests {
 
         f.marshal(&mut d);
         assert_eq!(d, Encoder::from_hex(s));
+        assert_eq!(f.encoded_len(), d.len());
 
         let f2 = Frame::decode(&mut d.as_decoder()).unwrap();
         assert_eq!(*f, f2);
@@ -767,6 +940,7 @@ mod tests {
             ack_delay: 0x1235,
             first_ack_range: 0x1236,
             ack_ranges: ar,
+            ecn_count: None,
         };
 
         enc_dec(&f, "025234523502523601020304");
@@ -775,11 +949,25 @@ mod tests {
         let enc = Encoder::from_hex("035234523502523601020304");
         let mut dec = enc.as_decoder();
         assert_eq!(Frame::decode(&mut dec).unwrap_err(), Error::NoMoreData);
+    }
 
-        // Try to parse ACK_ECN without ECN values
-        let enc = Encoder::from_hex("035234523502523601020304010203");
-        let mut dec = enc.as_decoder();
-        assert_eq!(Frame::decode(&mut dec).unwrap(), f);
+    #[test]
+    fn test_ack_ecn() {
+        let ar = vec![AckRange { gap: 1, range: 2 }, AckRange { gap: 3, range: 4 }];
+
+        let f = Frame::Ack {
+            largest_acknowledged: 0x1234,
+            ack_delay: 0x1235,
+            first_ack_range: 0x1236,
+            ack_ranges: ar,
+            ecn_count: Some(EcnCount {
+                ect0: 1,
+                ect1: 2,
+                ce: 3,
+            }),
+        };
+
+        enc_dec(&f, "035234523502523601020304010203");
     }
 
     #[test]
@@ -949,6 +1137,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn zero_length_new_connection_id() {
+        let mut enc = Encoder::from_hex("18523400"); // up to the CID
+        enc.encode_vvec(&[]);
+        enc.encode(&[0x11; 16][..]);
+        assert_eq!(
+            Frame::decode(&mut enc.as_decoder()).unwrap_err(),
+            Error::DecodingFrame
+        );
+    }
+
     #[test]
     fn test_retire_connection_id() {
         let f = Frame::RetireConnectionId {
@@ -991,6 +1190,150 @@ mod tests {
         enc_dec(&f, "1d80005678523403010203");
     }
 
+    #[test]
+    fn test_datagram() {
+        let mut f = Frame::Datagram {
+            data: vec![0x01, 0x02, 0x03],
+            fill: false,
+        };
+        enc_dec(&f, "3103010203");
+
+        f = Frame::Datagram {
+            data: vec![0x01, 0x02, 0x03],
+            fill: true,
+        };
+        enc_dec(&f, "30010203");
+    }
+
+    #[test]
+    fn unknown_frame_type() {
+        let enc = Encoder::from_hex("4020");
+        assert_eq!(
+            Frame::decode(&mut enc.as_decoder()).unwrap_err(),
+            Error::UnknownFrameType
+        );
+    }
+
+    #[test]
+    fn truncated_frame() {
+        // A MAX_STREAM_DATA frame without a limit.
+        let enc = Encoder::from_hex("1104");
+        assert_eq!(
+            Frame::decode(&mut enc.as_decoder()).unwrap_err(),
+            Error::NoMoreData
+        );
+    }
+
+    #[test]
+    fn ack_eliciting() {
+        let ack = Frame::Ack {
+            largest_acknowledged: 1,
+            ack_delay: 0,
+            first_ack_range: 0,
+            ack_ranges: Vec::new(),
+            ecn_count: None,
+        };
+        assert!(!ack.ack_eliciting());
+        assert!(!Frame::Padding.ack_eliciting());
+        assert!(!Frame::ConnectionClose {
+            error_code: CloseError::Application(1),
+            frame_type: 0,
+            reason_phrase: Vec::new(),
+        }
+        .ack_eliciting());
+        assert!(Frame::Ping.ack_eliciting());
+        assert!(Frame::HandshakeDone.ack_eliciting());
+        assert!(Frame::MaxData { maximum_data: 1 }.ack_eliciting());
+    }
+
+    /// Check a frame against the packet types that can carry it, in the order
+    /// Initial, Handshake, 0-RTT, 1-RTT.
+    fn allowed(f: &Frame, expected: [bool; 4]) {
+        let types = [
+            PacketType::Initial,
+            PacketType::Handshake,
+            PacketType::ZeroRtt,
+            PacketType::Short,
+        ];
+        for (pt, e) in types.iter().zip(expected.iter()) {
+            assert_eq!(f.is_allowed(*pt), *e, "{:?} in {:?}", f, pt);
+        }
+    }
+
+    #[test]
+    fn frames_allowed() {
+        const ALL: [bool; 4] = [true, true, true, true];
+        const NOT_0RTT: [bool; 4] = [true, true, false, true];
+        const APP: [bool; 4] = [false, false, true, true];
+        const ONLY_1RTT: [bool; 4] = [false, false, false, true];
+
+        allowed(&Frame::Padding, ALL);
+        allowed(&Frame::Ping, ALL);
+        allowed(
+            &Frame::Ack {
+                largest_acknowledged: 0,
+                ack_delay: 0,
+                first_ack_range: 0,
+                ack_ranges: Vec::new(),
+                ecn_count: None,
+            },
+            NOT_0RTT,
+        );
+        allowed(
+            &Frame::Crypto {
+                offset: 0,
+                data: Vec::new(),
+            },
+            NOT_0RTT,
+        );
+        allowed(
+            &Frame::ConnectionClose {
+                error_code: CloseError::Transport(1),
+                frame_type: 0,
+                reason_phrase: Vec::new(),
+            },
+            NOT_0RTT,
+        );
+        allowed(
+            &Frame::ConnectionClose {
+                error_code: CloseError::Application(1),
+                frame_type: 0,
+                reason_phrase: Vec::new(),
+            },
+            ONLY_1RTT,
+        );
+        allowed(&Frame::NewToken { token: vec![1] }, ONLY_1RTT);
+        allowed(&Frame::PathResponse { data: [0; 8] }, ONLY_1RTT);
+        allowed(&Frame::HandshakeDone, ONLY_1RTT);
+        allowed(&Frame::PathChallenge { data: [0; 8] }, APP);
+        allowed(
+            &Frame::Stream {
+                fin: false,
+                stream_id: 0.into(),
+                offset: 0,
+                data: Vec::new(),
+                fill: false,
+            },
+            APP,
+        );
+        allowed(&Frame::MaxData { maximum_data: 0 }, APP);
+        allowed(
+            &Frame::ResetStream {
+                stream_id: 0.into(),
+                application_error_code: 0,
+                final_size: 0,
+            },
+            APP,
+        );
+        allowed(
+            &Frame::Datagram {
+                data: Vec::new(),
+                fill: false,
+            },
+            APP,
+        );
+    }
+
     #[test]
     fn test_compare() {
         let f1 = Frame::Padding;
@@ -1019,6 +1362,50 @@ mod tests {
         assert_ne!(f3, f6);
     }
 
+    #[test]
+    fn encoded_len_large_values() {
+        let frames = [
+            Frame::Ack {
+                largest_acknowledged: 1 << 40,
+                ack_delay: 1 << 20,
+                first_ack_range: 100,
+                ack_ranges: vec![
+                    AckRange { gap: 1, range: 70 },
+                    AckRange {
+                        gap: 20_000,
+                        range: 1 << 31,
+                    },
+                ],
+                ecn_count: Some(EcnCount {
+                    ect0: 1 << 40,
+                    ect1: 0,
+                    ce: 1 << 20,
+                }),
+            },
+            Frame::Stream {
+                fin: false,
+                stream_id: StreamId::new(1 << 30),
+                offset: 1 << 14,
+                data: vec![0; 300],
+                fill: false,
+            },
+            Frame::ConnectionClose {
+                error_code: CloseError::Application(1 << 20),
+                frame_type: 0,
+                reason_phrase: vec![b'x'; 64],
+            },
+            Frame::Datagram {
+                data: vec![0; 16_384],
+                fill: false,
+            },
+        ];
+        for f in &frames {
+            let mut enc = Encoder::default();
+            f.marshal(&mut enc);
+            assert_eq!(f.encoded_len(), enc.len());
+        }
+    }
+
     #[test]
     fn encode_ack_frame() {
         let ack_frame = Frame::Ack {
@@ -1029,6 +1416,7 @@ mod tests {
                 gap: 0,   // 4
                 range: 1, // 3, 2
             }],
+            ecn_count: None,
         };
         let mut enc = Encoder::default();
         ack_frame.marshal(&mut enc);
@@ -1040,6 +1428,7 @@ mod tests {
             ack_delay,
             first_ack_range,
             ack_ranges,
+            ..
         } = f
         {
             assert_eq!(largest_acknowledged, 7);
@@ -0,0 +1,100 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Entry points for fuzzing the code that parses what a peer sends.  These take
+// arbitrary bytes and must never panic.
+
+use crate::connection::FixedConnectionIdManager;
+use crate::frame::Frame;
+use crate::packet::{PacketType, PublicPacket};
+use crate::tparams::{PreferredAddress, TransportParameters, PREFERRED_ADDRESS};
+use neqo_common::Decoder;
+
+/// The length of connection IDs in short header packets.
+const FUZZ_CID_LEN: usize = 8;
+
+/// Split a datagram into packets and do what can be done without keys.
+pub fn decode_packets(data: &[u8]) {
+    let cid_decoder = FixedConnectionIdManager::new(FUZZ_CID_LEN).unwrap();
+    let mut slc = data;
+    while !slc.is_empty() {
+        let (packet, remainder) = match PublicPacket::decode(slc, &cid_decoder) {
+            Ok(res) => res,
+            Err(_) => return,
+        };
+        let _ = packet.is_valid_initial();
+        if packet.packet_type() == PacketType::VersionNegotiation {
+            let _ = packet.supported_versions();
+        }
+        slc = remainder;
+    }
+}
+
+/// Decode frames until the input is used up or a frame can't be decoded.
+pub fn decode_frames(data: &[u8]) {
+    let mut dec = Decoder::from(data);
+    while dec.remaining() > 0 {
+        let frame = match Frame::decode(&mut dec) {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+        if let Frame::Ack {
+            largest_acknowledged,
+            first_ack_range,
+            ack_ranges,
+            ..
+        } = &frame
+        {
+            let _ = Frame::decode_ack_frame(*largest_acknowledged, *first_ack_range, ack_ranges);
+        }
+        let _ = frame.dump();
+    }
+}
+
+/// Decode transport parameters, including the preferred address.
+pub fn decode_transport_parameters(data: &[u8]) {
+    if let Ok(tps) = TransportParameters::decode(&mut Decoder::from(data)) {
+        if let Some(pa) = tps.get_bytes(PREFERRED_ADDRESS) {
+            let _ = PreferredAddress::decode(pa);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_frames, decode_packets, decode_transport_parameters};
+
+    fn all(f: fn(&[u8])) {
+        f(&[]);
+        for a in 0..=255 {
+            f(&[a]);
+            for b in 0..=255 {
+                f(&[a, b]);
+            }
+        }
+    }
+
+    #[test]
+    fn short_inputs() {
+        all(decode_packets);
+        all(decode_frames);
+        all(decode_transport_parameters);
+    }
+
+    #[test]
+    fn ack_with_many_ranges() {
+        // An ACK frame that claims to have 2^62-1 ranges.
+        decode_frames(&[
+            0x02, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn short_retry() {
+        // A Retry packet that is too short for the integrity tag.
+        decode_packets(&[0xff, 0xff, 0x00, 0x00, 0x1d, 0x00, 0x00, 0x01, 0x02]);
+    }
+}
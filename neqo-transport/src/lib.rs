@@ -9,34 +9,63 @@
 
 use neqo_common::qinfo;
 
+mod bbr;
 mod cc;
 mod cid;
 mod connection;
 mod crypto;
 mod dump;
+mod ecn;
+mod endpoint;
 mod events;
 mod flow_mgr;
 mod frame;
+#[cfg(any(test, fuzzing))]
+pub mod fuzz;
 mod pace;
 mod packet;
 mod path;
 mod qlog;
+mod quic_datagrams;
+mod race;
+mod rate;
 mod recovery;
 mod recv_stream;
 mod send_stream;
 pub mod server;
 mod stats;
 mod stream_id;
-pub mod tparams;
+pub(crate) mod tparams;
 mod tracking;
 
-pub use self::cid::{ConnectionId, ConnectionIdManager};
-pub use self::connection::{Connection, FixedConnectionIdManager, Output, State, ZeroRttState};
+pub use self::cc::CongestionControlAlgorithm;
+pub use self::cid::{ConnectionId, ConnectionIdManager, QuicLbConnectionIdManager};
+pub use self::connection::{
+    Connection, FixedConnectionIdManager, MigrationPolicy, Output, PeerClose, State, ZeroRttState,
+};
+pub use self::endpoint::{ConnectionRef, Endpoint};
 pub use self::events::{ConnectionEvent, ConnectionEvents};
 pub use self::frame::CloseError;
 pub use self::frame::StreamType;
 pub use self::packet::QuicVersion;
+pub use self::race::{ConnectionRace, CONNECTION_ATTEMPT_DELAY};
+pub use self::stats::Stats;
 pub use self::stream_id::StreamId;
+pub use self::tparams::{
+    PreferredAddress, TransportParameter, INITIAL_MAX_DATA, MAX_DATAGRAM_FRAME_SIZE,
+};
+
+/// The types that most users of a connection need.
+///
+/// `use neqo_transport::prelude::*;` brings in enough to make a connection,
+/// drive it, and use its streams.  Anything that isn't here is still available
+/// from the crate root, but it is more likely to change between releases.
+pub mod prelude {
+    pub use crate::{
+        AppError, Connection, ConnectionError, ConnectionEvent, Error, Output, QuicVersion, Res,
+        State, Stats, StreamId, StreamType,
+    };
+}
 
 const LOCAL_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30); // 30 second
 
@@ -55,12 +84,19 @@ pub enum Error {
     FinalSizeError,
     FrameEncodingError,
     TransportParameterError,
+    ConnectionIdLimitError,
     ProtocolViolation,
     InvalidToken,
     ApplicationError,
+    CryptoBufferExceeded,
+    /// Too many packets failed authentication.
+    AeadLimitReached,
     CryptoError(neqo_crypto::Error),
     QlogError,
     CryptoAlert(u8),
+    /// The server rejected encrypted client hello (ECH).  This holds the
+    /// configurations to retry with, or nothing if the retry shouldn't use ECH.
+    EchRetry(Vec<u8>),
 
     // All internal errors from here.
     AckedUnsentPacket,
@@ -68,6 +104,9 @@ pub enum Error {
     DecodingFrame,
     DecryptError,
     HandshakeFailed,
+    /// The handshake didn't complete before the deadline set with
+    /// `Connection::set_handshake_timeout`.
+    HandshakeTimeout,
     IdleTimeout,
     IntegerOverflow,
     InvalidInput,
@@ -85,6 +124,8 @@ pub enum Error {
     /// a packet sent with the current keys hasn't been acknowledged.
     KeyUpdateBlocked,
     NoMoreData,
+    /// The peer does not support a feature, such as datagrams.
+    NotAvailable,
     NotConnected,
     PacketNumberOverlap,
     PeerApplicationError(AppError),
@@ -101,6 +142,7 @@ impl Error {
     pub fn code(&self) -> TransportError {
         match self {
             Self::NoError
+            | Self::HandshakeTimeout
             | Self::IdleTimeout
             | Self::PeerError(_)
             | Self::PeerApplicationError(_) => 0,
@@ -111,10 +153,15 @@ impl Error {
             Self::FinalSizeError => 6,
             Self::FrameEncodingError => 7,
             Self::TransportParameterError => 8,
+            Self::ConnectionIdLimitError => 9,
             Self::ProtocolViolation => 10,
             Self::InvalidToken => 11,
             Self::ApplicationError => ERROR_APPLICATION_CLOSE,
+            Self::CryptoBufferExceeded => 13,
+            Self::AeadLimitReached => 15,
             Self::CryptoAlert(a) => 0x100 + u64::from(*a),
+            // 121 = ech_required
+            Self::EchRetry(_) => 0x100 + 121,
             // All the rest are internal errors.
             _ => 1,
         }
@@ -22,7 +22,7 @@ use std::time::{Duration, Instant};
 /// the case the congestion controller increases the congestion window.
 /// This value spaces packets over half the congestion window, which matches
 /// our current congestion controller, which double the window every RTT.
-const PACER_SPEEDUP: usize = 2;
+pub(crate) const PACER_SPEEDUP: usize = 2;
 
 /// A pacer that uses a leaky bucket.
 pub struct Pacer {
@@ -7,11 +7,12 @@
 // Encoding and decoding packets off the wire.
 use crate::cid::{ConnectionId, ConnectionIdDecoder, ConnectionIdRef, MAX_CONNECTION_ID_LEN};
 use crate::crypto::{CryptoDxState, CryptoStates};
+use crate::tparams::{TLS_EXT_QUIC_TRANSPORT_PARAMETERS, TLS_EXT_QUIC_TRANSPORT_PARAMETERS_DRAFT};
 use crate::tracking::PNSpace;
 use crate::{Error, Res};
 
 use neqo_common::{hex, hex_with_len, qtrace, Decoder, Encoder};
-use neqo_crypto::random;
+use neqo_crypto::{constants::Extension, random};
 
 use std::convert::TryFrom;
 use std::fmt;
@@ -25,6 +26,8 @@ const PACKET_TYPE_HANDSHAKE: u8 = 0x2;
 const PACKET_TYPE_RETRY: u8 = 0x03;
 
 pub const PACKET_BIT_LONG: u8 = 0x80;
+/// The length of the packet numbers that are sent.
+pub const PACKET_NUMBER_LEN: usize = 3;
 const PACKET_BIT_SHORT: u8 = 0x00;
 const PACKET_BIT_KEY_PHASE: u8 = 0x04;
 const PACKET_BIT_FIXED_QUIC: u8 = 0x40;
@@ -36,6 +39,8 @@ const SAMPLE_SIZE: usize = 16;
 const SAMPLE_OFFSET: usize = 4;
 
 mod retry;
+#[cfg(test)]
+mod vectors;
 
 pub type PacketNumber = u64;
 type Version = u32;
@@ -52,14 +57,36 @@ pub enum PacketType {
 }
 
 impl PacketType {
+    /// The long header type bits for this type of packet.  Version 2 uses
+    /// different values to earlier versions.
     #[must_use]
-    fn code(self) -> u8 {
-        match self {
+    fn code(self, quic_version: QuicVersion) -> u8 {
+        let code = match self {
             Self::Initial => PACKET_TYPE_INITIAL,
             Self::ZeroRtt => PACKET_TYPE_0RTT,
             Self::Handshake => PACKET_TYPE_HANDSHAKE,
             Self::Retry => PACKET_TYPE_RETRY,
             _ => panic!("shouldn't be here"),
+        };
+        if quic_version == QuicVersion::Version2 {
+            (code + 1) & 3
+        } else {
+            code
+        }
+    }
+
+    fn from_code(code: u8, quic_version: QuicVersion) -> Self {
+        let code = if quic_version == QuicVersion::Version2 {
+            (code + 3) & 3
+        } else {
+            code & 3
+        };
+        match code {
+            PACKET_TYPE_INITIAL => Self::Initial,
+            PACKET_TYPE_0RTT => Self::ZeroRtt,
+            PACKET_TYPE_HANDSHAKE => Self::Handshake,
+            PACKET_TYPE_RETRY => Self::Retry,
+            _ => unreachable!(),
         }
     }
 }
@@ -69,14 +96,73 @@ pub enum QuicVersion {
     Draft27,
     Draft28,
     Draft29,
+    Version1,
+    Version2,
 }
 
 impl QuicVersion {
+    /// All of the versions that are supported, most preferred first.
+    pub const ALL: &'static [Self] = &[
+        Self::Version2,
+        Self::Version1,
+        Self::Draft29,
+        Self::Draft28,
+        Self::Draft27,
+    ];
+
     pub fn as_u32(self) -> Version {
         match self {
             Self::Draft27 => 0xff00_0000 + 27,
             Self::Draft28 => 0xff00_0000 + 28,
             Self::Draft29 => 0xff00_0000 + 29,
+            Self::Version1 => 1,
+            Self::Version2 => 0x6b33_43cf,
+        }
+    }
+
+    /// The salt used to derive Initial secrets.
+    pub(crate) fn initial_salt(self) -> &'static [u8] {
+        const INITIAL_SALT_27: &[u8] = &[
+            0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43, 0x2b, 0xb4,
+            0x63, 0x65, 0xbe, 0xf9, 0xf5, 0x02,
+        ];
+        const INITIAL_SALT_29: &[u8] = &[
+            0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1, 0x9c, 0x61,
+            0x11, 0xe0, 0x43, 0x90, 0xa8, 0x99,
+        ];
+        const INITIAL_SALT_V1: &[u8] = &[
+            0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8,
+            0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+        ];
+        const INITIAL_SALT_V2: &[u8] = &[
+            0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26,
+            0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+        ];
+        match self {
+            Self::Draft27 | Self::Draft28 => INITIAL_SALT_27,
+            Self::Draft29 => INITIAL_SALT_29,
+            Self::Version1 => INITIAL_SALT_V1,
+            Self::Version2 => INITIAL_SALT_V2,
+        }
+    }
+
+    /// The TLS extension that transport parameters are carried in.  Peers
+    /// that use a draft version expect the codepoint from the drafts.
+    pub(crate) fn tp_extension(self) -> Extension {
+        match self {
+            Self::Draft27 | Self::Draft28 | Self::Draft29 => {
+                TLS_EXT_QUIC_TRANSPORT_PARAMETERS_DRAFT
+            }
+            Self::Version1 | Self::Version2 => TLS_EXT_QUIC_TRANSPORT_PARAMETERS,
+        }
+    }
+
+    /// The prefix for the labels used to derive packet protection keys,
+    /// including "key", "iv", "hp", and "ku".
+    pub(crate) fn label_prefix(self) -> &'static str {
+        match self {
+            Self::Version2 => "quicv2 ",
+            _ => "quic ",
         }
     }
 }
@@ -91,15 +177,11 @@ impl TryFrom<Version> for QuicVersion {
     type Error = Error;
 
     fn try_from(ver: Version) -> Res<Self> {
-        if ver == 0xff00_0000 + 27 {
-            Ok(Self::Draft27)
-        } else if ver == 0xff00_0000 + 28 {
-            Ok(Self::Draft28)
-        } else if ver == 0xff00_0000 + 29 {
-            Ok(Self::Draft29)
-        } else {
-            Err(Error::VersionNegotiation)
-        }
+        Self::ALL
+            .iter()
+            .find(|v| v.as_u32() == ver)
+            .copied()
+            .ok_or(Error::VersionNegotiation)
     }
 }
 
@@ -119,6 +201,7 @@ pub struct PacketBuilder {
     pn: PacketNumber,
     header: Range<usize>,
     offsets: PacketBuilderOffsets,
+    limit: usize,
 }
 
 impl PacketBuilder {
@@ -137,9 +220,21 @@ impl PacketBuilder {
                 pn: 0..0,
                 len: 0,
             },
+            limit: usize::max_value(),
         }
     }
 
+    /// The length of a short header with a packet number of `PACKET_NUMBER_LEN` bytes.
+    pub fn short_header_len(dcid_len: usize) -> usize {
+        1 + dcid_len + PACKET_NUMBER_LEN
+    }
+
+    /// The length of a long header with a packet number of `PACKET_NUMBER_LEN` bytes.
+    /// This includes two bytes for the length, but not the token in Initial packets.
+    pub fn long_header_len(dcid_len: usize, scid_len: usize) -> usize {
+        1 + 4 + 1 + dcid_len + 1 + scid_len + 2 + PACKET_NUMBER_LEN
+    }
+
     /// Start building a long header packet.
     /// For an Initial packet you will need to call initial_token(),
     /// even if the token is empty.
@@ -151,7 +246,7 @@ impl PacketBuilder {
         scid: &ConnectionId,
     ) -> Self {
         let header_start = encoder.len();
-        encoder.encode_byte(PACKET_BIT_LONG | PACKET_BIT_FIXED_QUIC | pt.code() << 4);
+        encoder.encode_byte(PACKET_BIT_LONG | PACKET_BIT_FIXED_QUIC | pt.code(quic_version) << 4);
         encoder.encode_uint(4, quic_version.as_u32());
         encoder.encode_vec(1, dcid);
         encoder.encode_vec(1, scid);
@@ -164,15 +259,36 @@ impl PacketBuilder {
                 pn: 0..0,
                 len: 0,
             },
+            limit: usize::max_value(),
         }
     }
 
+    /// Set the size that the encoder can reach before the packet is protected.
+    /// This covers anything that was already in the encoder, so that packets
+    /// can be coalesced into a datagram of a limited size.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// The space that remains before the limit is reached.
+    #[must_use]
+    pub fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.encoder.len())
+    }
+
+    /// Whether the header is already over the limit, leaving no space at all.
+    #[must_use]
+    pub fn over_limit(&self) -> bool {
+        self.encoder.len() > self.limit
+    }
+
     /// For an Initial packet, encode the token.
     /// If you fail to do this, then you will not get a valid packet.
     pub fn initial_token(&mut self, token: &[u8]) {
+        // The type bits depend on the version, so only check for a long header.
         debug_assert_eq!(
-            self.encoder[self.header.start] & 0xb0,
-            PACKET_BIT_LONG | PACKET_TYPE_INITIAL << 4
+            self.encoder[self.header.start] & PACKET_BIT_LONG,
+            PACKET_BIT_LONG
         );
         self.encoder.encode_vvec(token);
     }
@@ -268,7 +384,7 @@ impl PacketBuilder {
         encoder.encode_byte(
             PACKET_BIT_LONG
                 | PACKET_BIT_FIXED_QUIC
-                | (PACKET_TYPE_RETRY << 4)
+                | (PacketType::Retry.code(quic_version) << 4)
                 | (random(1)[0] & 0xf),
         );
         encoder.encode_uint(4, quic_version.as_u32());
@@ -294,9 +410,9 @@ impl PacketBuilder {
         encoder.encode(&[0; 4]); // Zero version == VN.
         encoder.encode_vec(1, dcid);
         encoder.encode_vec(1, scid);
-        encoder.encode_uint(4, QuicVersion::Draft27.as_u32());
-        encoder.encode_uint(4, QuicVersion::Draft28.as_u32());
-        encoder.encode_uint(4, QuicVersion::Draft29.as_u32());
+        for v in QuicVersion::ALL {
+            encoder.encode_uint(4, v.as_u32());
+        }
         // Add a greased version, using the randomness already generated.
         for g in &mut grease[..4] {
             *g = *g & 0xf0 | 0x0a;
@@ -366,6 +482,9 @@ impl<'a> PublicPacket<'a> {
         if packet_type == PacketType::Retry {
             let header_len = decoder.offset();
             let expansion = retry::expansion(quic_version);
+            if decoder.remaining() < expansion {
+                return Err(Error::InvalidPacket);
+            }
             let token = Self::opt(decoder.decode(decoder.remaining() - expansion))?;
             if token.is_empty() {
                 return Err(Error::InvalidPacket);
@@ -380,7 +499,8 @@ impl<'a> PublicPacket<'a> {
         };
         let len = Self::opt(decoder.decode_varint())?;
         let header_len = decoder.offset();
-        let _body = Self::opt(decoder.decode(usize::try_from(len)?))?;
+        // Anything after the body is another packet in the same datagram.
+        let _body = Self::opt(decoder.sub_decoder(usize::try_from(len)?))?;
         Ok((token, header_len))
     }
 
@@ -459,13 +579,7 @@ impl<'a> PublicPacket<'a> {
         if dcid.len() > MAX_CONNECTION_ID_LEN || scid.len() > MAX_CONNECTION_ID_LEN {
             return Err(Error::InvalidPacket);
         }
-        let packet_type = match (first >> 4) & 3 {
-            PACKET_TYPE_INITIAL => PacketType::Initial,
-            PACKET_TYPE_0RTT => PacketType::ZeroRtt,
-            PACKET_TYPE_HANDSHAKE => PacketType::Handshake,
-            PACKET_TYPE_RETRY => PacketType::Retry,
-            _ => unreachable!(),
-        };
+        let packet_type = PacketType::from_code(first >> 4, quic_version);
 
         // The type-specific code includes a token.  This consumes the remainder of the packet.
         let (token, header_len) = Self::decode_long(&mut decoder, packet_type, quic_version)?;
@@ -609,7 +723,13 @@ impl<'a> PublicPacket<'a> {
         ))
     }
 
-    pub fn decrypt(&self, crypto: &mut CryptoStates, release_at: Instant) -> Res<DecryptedPacket> {
+    /// Decrypt the packet.  The plaintext is written into `buf`.
+    pub fn decrypt(
+        &self,
+        crypto: &mut CryptoStates,
+        release_at: Instant,
+        buf: Vec<u8>,
+    ) -> Res<DecryptedPacket> {
         let space = PNSpace::from(self.packet_type);
         // This has to work in two stages because we need to remove header protection
         // before picking the keys to use.
@@ -621,7 +741,7 @@ impl<'a> PublicPacket<'a> {
             let (key_phase, pn, header, body) = self.decrypt_header(rx)?;
             qtrace!([rx], "decoded header: {:?}", header);
             if let Some(rx) = crypto.rx(space, key_phase) {
-                let d = rx.decrypt(pn, &header, body)?;
+                let d = rx.decrypt(pn, &header, body, buf)?;
                 // If this is the first packet ever successfully decrypted
                 // using `rx`, make sure to initiate a key update.
                 if rx.needs_update() {
@@ -679,6 +799,11 @@ impl DecryptedPacket {
     pub fn pn(&self) -> PacketNumber {
         self.pn
     }
+
+    /// Take the buffer holding the plaintext so that it can be reused.
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.data
+    }
 }
 
 impl Deref for DecryptedPacket {
@@ -702,7 +827,7 @@ mod tests {
 
     /// This is a connection ID manager, which is only used for decoding short header packets.
     fn cid_mgr() -> FixedConnectionIdManager {
-        FixedConnectionIdManager::new(SERVER_CID.len())
+        FixedConnectionIdManager::new(SERVER_CID.len()).unwrap()
     }
 
     const SAMPLE_INITIAL_PAYLOAD: &[u8] = &[
@@ -765,9 +890,102 @@ mod tests {
         assert_eq!(remainder, EXTRA);
 
         let decrypted = packet
-            .decrypt(&mut CryptoStates::test_default(), now())
+            .decrypt(&mut CryptoStates::test_default(), now(), Vec::new())
+            .unwrap();
+        assert_eq!(decrypted.pn(), 1);
+    }
+
+    #[test]
+    fn initial_token() {
+        const TOKEN: &[u8] = b"a token for an initial";
+
+        fixture_init();
+        let mut prot = CryptoDxState::test_default();
+        let burn = prot.encrypt(0, &[], &[]).expect("burn OK");
+        assert_eq!(burn.len(), prot.expansion());
+
+        let mut builder = PacketBuilder::long(
+            Encoder::new(),
+            PacketType::Initial,
+            QuicVersion::default(),
+            &ConnectionId::from(&[][..]),
+            &ConnectionId::from(SERVER_CID),
+        );
+        builder.initial_token(TOKEN);
+        builder.pn(1, 2);
+        builder.encode(&SAMPLE_INITIAL_PAYLOAD);
+        let packet = builder.build(&mut prot).expect("build");
+
+        let (packet, remainder) = PublicPacket::decode(&packet, &cid_mgr()).unwrap();
+        assert_eq!(packet.packet_type(), PacketType::Initial);
+        assert_eq!(packet.token(), TOKEN);
+        assert!(remainder.is_empty());
+        let decrypted = packet
+            .decrypt(&mut CryptoStates::test_default(), now(), Vec::new())
             .unwrap();
         assert_eq!(decrypted.pn(), 1);
+        assert_eq!(&decrypted[..], SAMPLE_INITIAL_PAYLOAD);
+    }
+
+    #[test]
+    fn truncated_initial_token() {
+        let mut enc = Encoder::new();
+        enc.encode_byte(PACKET_BIT_LONG | PACKET_BIT_FIXED_QUIC);
+        enc.encode_uint(4, QuicVersion::default().as_u32());
+        enc.encode_vec(1, &[]);
+        enc.encode_vec(1, SERVER_CID);
+        // The token is longer than what remains.
+        enc.encode_varint(100_u64);
+        enc.encode(&[0xff; 40]);
+
+        assert!(PublicPacket::decode(&enc, &cid_mgr()).is_err());
+    }
+
+    #[test]
+    fn packet_type_codes() {
+        const TYPES: &[PacketType] = &[
+            PacketType::Initial,
+            PacketType::ZeroRtt,
+            PacketType::Handshake,
+            PacketType::Retry,
+        ];
+        for &v in QuicVersion::ALL {
+            for &t in TYPES {
+                assert_eq!(PacketType::from_code(t.code(v), v), t);
+            }
+        }
+        assert_eq!(PacketType::Initial.code(QuicVersion::Version1), 0);
+        assert_eq!(PacketType::Initial.code(QuicVersion::Version2), 1);
+        assert_eq!(PacketType::Retry.code(QuicVersion::Version2), 0);
+    }
+
+    #[test]
+    fn initial_v2() {
+        fixture_init();
+        let mut builder = PacketBuilder::long(
+            Encoder::new(),
+            PacketType::Initial,
+            QuicVersion::Version2,
+            &ConnectionId::from(&[][..]),
+            &ConnectionId::from(SERVER_CID),
+        );
+        builder.initial_token(&[]);
+        builder.pn(0, 1);
+        builder.encode(&SAMPLE_INITIAL_PAYLOAD);
+        let packet = builder
+            .build(&mut CryptoDxState::test_default())
+            .expect("build");
+        // The type bits are 0b01 for Initial in version 2.
+        assert_eq!(packet[0] & 0x30, 0x10);
+
+        let (packet, remainder) = PublicPacket::decode(&packet, &cid_mgr()).unwrap();
+        assert_eq!(packet.packet_type(), PacketType::Initial);
+        assert_eq!(packet.version(), Some(QuicVersion::Version2));
+        assert!(remainder.is_empty());
+        let decrypted = packet
+            .decrypt(&mut CryptoStates::test_default(), now(), Vec::new())
+            .unwrap();
+        assert_eq!(&decrypted[..], SAMPLE_INITIAL_PAYLOAD);
     }
 
     #[test]
@@ -820,7 +1038,7 @@ mod tests {
         assert_eq!(packet.packet_type(), PacketType::Short);
         assert!(remainder.is_empty());
         let decrypted = packet
-            .decrypt(&mut CryptoStates::test_default(), now())
+            .decrypt(&mut CryptoStates::test_default(), now(), Vec::new())
             .unwrap();
         assert_eq!(&decrypted[..], SAMPLE_SHORT_PAYLOAD);
     }
@@ -831,13 +1049,13 @@ mod tests {
         fixture_init();
         let (packet, remainder) = PublicPacket::decode(
             SAMPLE_SHORT,
-            &FixedConnectionIdManager::new(SERVER_CID.len() - 1),
+            &FixedConnectionIdManager::new(SERVER_CID.len() - 1).unwrap(),
         )
         .unwrap();
         assert_eq!(packet.packet_type(), PacketType::Short);
         assert!(remainder.is_empty());
         assert!(packet
-            .decrypt(&mut CryptoStates::test_default(), now())
+            .decrypt(&mut CryptoStates::test_default(), now(), Vec::new())
             .is_err());
     }
 
@@ -846,7 +1064,7 @@ mod tests {
     fn decode_short_long_cid() {
         assert!(PublicPacket::decode(
             SAMPLE_SHORT,
-            &FixedConnectionIdManager::new(SERVER_CID.len() + 1)
+            &FixedConnectionIdManager::new(SERVER_CID.len() + 1).unwrap()
         )
         .is_err());
     }
@@ -895,6 +1113,49 @@ mod tests {
         assert!(encoder.is_empty());
     }
 
+    #[test]
+    fn build_limit() {
+        let mut builder =
+            PacketBuilder::short(Encoder::new(), true, &ConnectionId::from(SERVER_CID));
+        builder.pn(0, 1);
+        let header = builder.len();
+        assert_eq!(builder.remaining(), usize::max_value() - header);
+
+        builder.set_limit(header + 10);
+        assert_eq!(builder.remaining(), 10);
+        builder.encode(&[0; 4]);
+        assert_eq!(builder.remaining(), 6);
+        assert!(!builder.over_limit());
+
+        builder.set_limit(header - 1);
+        assert_eq!(builder.remaining(), 0);
+        assert!(builder.over_limit());
+    }
+
+    #[test]
+    fn header_len() {
+        let mut builder =
+            PacketBuilder::short(Encoder::new(), true, &ConnectionId::from(SERVER_CID));
+        builder.pn(0, PACKET_NUMBER_LEN);
+        assert_eq!(
+            builder.len(),
+            PacketBuilder::short_header_len(SERVER_CID.len())
+        );
+
+        let mut builder = PacketBuilder::long(
+            Encoder::new(),
+            PacketType::Handshake,
+            QuicVersion::default(),
+            &ConnectionId::from(SERVER_CID),
+            &ConnectionId::from(CLIENT_CID),
+        );
+        builder.pn(0, PACKET_NUMBER_LEN);
+        assert_eq!(
+            builder.len(),
+            PacketBuilder::long_header_len(SERVER_CID.len(), CLIENT_CID.len())
+        );
+    }
+
     const SAMPLE_RETRY_27: &[u8] = &[
         0xff, 0xff, 0x00, 0x00, 0x1b, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42, 0x62, 0xb5,
         0x74, 0x6f, 0x6b, 0x65, 0x6e, 0xa5, 0x23, 0xcb, 0x5b, 0xa5, 0x24, 0x69, 0x5f, 0x65, 0x69,
@@ -965,7 +1226,7 @@ mod tests {
     fn decode_retry(quic_version: QuicVersion, sample_retry: &[u8]) {
         fixture_init();
         let (packet, remainder) =
-            PublicPacket::decode(sample_retry, &FixedConnectionIdManager::new(5)).unwrap();
+            PublicPacket::decode(sample_retry, &FixedConnectionIdManager::new(5).unwrap()).unwrap();
         assert!(packet.is_valid_retry(&ConnectionId::from(CLIENT_CID)));
         assert_eq!(Some(quic_version), packet.quic_version);
         assert!(packet.dcid().is_empty());
@@ -993,7 +1254,7 @@ mod tests {
     #[test]
     fn invalid_retry() {
         fixture_init();
-        let cid_mgr = FixedConnectionIdManager::new(5);
+        let cid_mgr = FixedConnectionIdManager::new(5).unwrap();
         let odcid = ConnectionId::from(CLIENT_CID);
 
         assert!(PublicPacket::decode(&[], &cid_mgr).is_err());
@@ -1024,8 +1285,9 @@ mod tests {
 
     const SAMPLE_VN: &[u8] = &[
         0x80, 0x00, 0x00, 0x00, 0x00, 0x08, 0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42, 0x62, 0xb5, 0x08,
-        0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08, 0xff, 0x00, 0x00, 0x1b, 0xff, 0x00, 0x00,
-        0x1c, 0xff, 0x00, 0x00, 0x1d, 0x0a, 0x0a, 0x0a, 0x0a,
+        0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08, 0x6b, 0x33, 0x43, 0xcf, 0x00, 0x00, 0x00,
+        0x01, 0xff, 0x00, 0x00, 0x1d, 0xff, 0x00, 0x00, 0x1c, 0xff, 0x00, 0x00, 0x1b, 0x0a, 0x0a,
+        0x0a, 0x0a,
     ];
 
     #[test]
@@ -1044,7 +1306,7 @@ mod tests {
     #[test]
     fn parse_vn() {
         let (packet, remainder) =
-            PublicPacket::decode(SAMPLE_VN, &FixedConnectionIdManager::new(5)).unwrap();
+            PublicPacket::decode(SAMPLE_VN, &FixedConnectionIdManager::new(5).unwrap()).unwrap();
         assert!(remainder.is_empty());
         assert_eq!(&packet.dcid[..], SERVER_CID);
         assert!(packet.scid.is_some());
@@ -1065,7 +1327,7 @@ mod tests {
         enc.encode_uint(4, 0x5a6a_7a8a_u64);
 
         let (packet, remainder) =
-            PublicPacket::decode(&enc, &FixedConnectionIdManager::new(5)).unwrap();
+            PublicPacket::decode(&enc, &FixedConnectionIdManager::new(5).unwrap()).unwrap();
         assert!(remainder.is_empty());
         assert_eq!(&packet.dcid[..], BIG_DCID);
         assert!(packet.scid.is_some());
@@ -1101,10 +1363,10 @@ mod tests {
         ];
         fixture_init();
         let (packet, slice) =
-            PublicPacket::decode(PACKET, &FixedConnectionIdManager::new(0)).unwrap();
+            PublicPacket::decode(PACKET, &FixedConnectionIdManager::new(0).unwrap()).unwrap();
         assert!(slice.is_empty());
         let decrypted = packet
-            .decrypt(&mut CryptoStates::test_chacha(), now())
+            .decrypt(&mut CryptoStates::test_chacha(), now(), Vec::new())
             .unwrap();
         assert_eq!(decrypted.packet_type(), PacketType::Short);
         assert_eq!(decrypted.pn(), 654_360_564);
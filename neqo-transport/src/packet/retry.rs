@@ -22,17 +22,33 @@ const RETRY_SECRET_29: &[u8] = &[
     0x8b, 0x0d, 0x37, 0xeb, 0x85, 0x35, 0x02, 0x2e, 0xbc, 0x8d, 0x76, 0xa2, 0x07, 0xd8, 0x0d, 0xf2,
     0x26, 0x46, 0xec, 0x06, 0xdc, 0x80, 0x96, 0x42, 0xc3, 0x0a, 0x8b, 0xaa, 0x2b, 0xaa, 0xff, 0x4c,
 ];
+const RETRY_SECRET_V1: &[u8] = &[
+    0xd9, 0xc9, 0x94, 0x3e, 0x61, 0x01, 0xfd, 0x20, 0x00, 0x21, 0x50, 0x6b, 0xcc, 0x02, 0x81, 0x4c,
+    0x73, 0x03, 0x0f, 0x25, 0xc7, 0x9d, 0x71, 0xce, 0x87, 0x6e, 0xca, 0x87, 0x6e, 0x6f, 0xca, 0x8e,
+];
+const RETRY_SECRET_V2: &[u8] = &[
+    0xc4, 0xdd, 0x24, 0x84, 0xd6, 0x81, 0xae, 0xfa, 0x4f, 0xf4, 0xd6, 0x9c, 0x2c, 0x20, 0x29, 0x9a,
+    0xe3, 0xa6, 0xbe, 0x4f, 0xe8, 0x9e, 0x8d, 0x4d, 0x3e, 0xc2, 0xc3, 0xf5, 0x4f, 0xa6, 0xe2, 0xf7,
+];
 
 /// The AEAD used for Retry is fixed, so use thread local storage.
-fn make_aead(secret: &[u8]) -> Aead {
+fn make_aead(secret: &[u8], quic_version: QuicVersion) -> Aead {
     #[cfg(debug_assertions)]
     ::neqo_crypto::assert_initialized();
 
     let secret = hkdf::import_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, secret).unwrap();
-    Aead::new(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, &secret, "quic ").unwrap()
+    Aead::new(
+        TLS_VERSION_1_3,
+        TLS_AES_128_GCM_SHA256,
+        &secret,
+        quic_version.label_prefix(),
+    )
+    .unwrap()
 }
-thread_local!(static RETRY_AEAD_27: RefCell<Aead> = RefCell::new(make_aead(RETRY_SECRET_27)));
-thread_local!(static RETRY_AEAD_29: RefCell<Aead> = RefCell::new(make_aead(RETRY_SECRET_29)));
+thread_local!(static RETRY_AEAD_27: RefCell<Aead> = RefCell::new(make_aead(RETRY_SECRET_27, QuicVersion::Draft27)));
+thread_local!(static RETRY_AEAD_29: RefCell<Aead> = RefCell::new(make_aead(RETRY_SECRET_29, QuicVersion::Draft29)));
+thread_local!(static RETRY_AEAD_V1: RefCell<Aead> = RefCell::new(make_aead(RETRY_SECRET_V1, QuicVersion::Version1)));
+thread_local!(static RETRY_AEAD_V2: RefCell<Aead> = RefCell::new(make_aead(RETRY_SECRET_V2, QuicVersion::Version2)));
 
 /// Run a function with the appropriate Retry AEAD.
 pub fn use_aead<F, T>(quic_version: QuicVersion, f: F) -> Res<T>
@@ -42,6 +58,8 @@ where
     match quic_version {
         QuicVersion::Draft27 | QuicVersion::Draft28 => &RETRY_AEAD_27,
         QuicVersion::Draft29 => &RETRY_AEAD_29,
+        QuicVersion::Version1 => &RETRY_AEAD_V1,
+        QuicVersion::Version2 => &RETRY_AEAD_V2,
     }
     .try_with(|aead| f(&aead.borrow()))
     .map_err(|e| {
@@ -0,0 +1,165 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Checks against the sample packet protection in Appendix A of RFC 9001.
+// These cover the derivation of Initial secrets, header protection,
+// AEAD protection of an Initial packet, and the Retry integrity tag.
+#![deny(clippy::pedantic)]
+
+use super::{PacketBuilder, PacketType, PublicPacket, QuicVersion};
+use crate::cid::ConnectionId;
+use crate::crypto::{CryptoDxDirection, CryptoDxState, CryptoStates};
+use crate::FixedConnectionIdManager;
+use neqo_common::{Encoder, Role};
+use test_fixture::{fixture_init, now};
+
+const CLIENT_CID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+const SERVER_CID: &[u8] = &[0xf0, 0x67, 0xa5, 0x50, 0x2a, 0x42, 0x62, 0xb5];
+
+const CLIENT_INITIAL_SECRET: &str =
+    "c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea";
+const SERVER_INITIAL_SECRET: &str =
+    "3c199828fd139efd216c155ad844cc81fb82fa8d7446fa7d78be803acdda951b";
+
+const CLIENT_HP_SAMPLE: &str = "d1b1c98dd7689fb8ec11d242b123dc9b";
+const CLIENT_HP_MASK: &str = "437b9aec36";
+const SERVER_HP_SAMPLE: &str = "2cd0991cd25b0aac406a5816b6394100";
+const SERVER_HP_MASK: &str = "2ec0d8356a";
+
+/// The CRYPTO frame with the ServerHello from Appendix A.3.
+const SERVER_INITIAL_PAYLOAD: &str = "02000000000600405a020000560303eefce7f7b37ba1d1632e966778\
+                                      25ddf73988cfc79825df566dc5430b9a045a1200130100002e003300\
+                                      24001d00209d3c940d89690b84d08a60993c144eca684d1081287c83\
+                                      4d5311bcf32bb9da1a002b00020304";
+const SERVER_INITIAL: &str = "cf000000010008f067a5502a4262b5004075c0d95a482cd0991cd25b0a\
+                              ac406a5816b6394100f37a1c69797554780bb38cc5a99f5ede4cf73c\
+                              3ec2493a1839b3dbcba3f6ea46c5b7684df3548e7ddeb9c3bf9c73cc\
+                              3f3bded74b562bfb19fb84022f8ef4cdd93795d77d06edbb7aaf2f58\
+                              891850abbdca3d20398c276456cbc42158407dd074ee";
+
+const RETRY_TOKEN: &[u8] = b"token";
+const RETRY: &str = "ff000000010008f067a5502a4262b5746f6b656e04a265ba2eff4d829058fb3f0f2496ba";
+
+fn bytes(s: &str) -> Vec<u8> {
+    Encoder::from_hex(s).into()
+}
+
+fn initial(label: &str) -> CryptoDxState {
+    CryptoDxState::new_initial(
+        QuicVersion::Version1,
+        CryptoDxDirection::Write,
+        label,
+        CLIENT_CID,
+    )
+}
+
+#[test]
+fn initial_secrets() {
+    fixture_init();
+    for (label, expected) in &[
+        ("client in", CLIENT_INITIAL_SECRET),
+        ("server in", SERVER_INITIAL_SECRET),
+    ] {
+        let secret = CryptoDxState::initial_secret(QuicVersion::Version1, label, CLIENT_CID);
+        assert_eq!(secret.as_bytes().unwrap(), &bytes(expected)[..]);
+    }
+}
+
+#[test]
+fn header_protection() {
+    fixture_init();
+    for (label, sample, expected) in &[
+        ("client in", CLIENT_HP_SAMPLE, CLIENT_HP_MASK),
+        ("server in", SERVER_HP_SAMPLE, SERVER_HP_MASK),
+    ] {
+        let mask = initial(label).compute_mask(&bytes(sample)).unwrap();
+        assert_eq!(&mask[..5], &bytes(expected)[..]);
+    }
+}
+
+#[test]
+fn protect_server_initial() {
+    fixture_init();
+    let mut prot = initial("server in");
+    // The example uses packet number 1, but packet numbers can't be skipped.
+    let burn = prot.encrypt(0, &[], &[]).unwrap();
+    assert_eq!(burn.len(), prot.expansion());
+
+    let mut builder = PacketBuilder::long(
+        Encoder::new(),
+        PacketType::Initial,
+        QuicVersion::Version1,
+        &ConnectionId::from(&[][..]),
+        &ConnectionId::from(SERVER_CID),
+    );
+    builder.initial_token(&[]);
+    builder.pn(1, 2);
+    builder.encode(&bytes(SERVER_INITIAL_PAYLOAD));
+    let packet = builder.build(&mut prot).unwrap();
+    assert_eq!(&packet[..], &bytes(SERVER_INITIAL)[..]);
+}
+
+#[test]
+fn unprotect_server_initial() {
+    fixture_init();
+    let mut states = CryptoStates::default();
+    states.init(QuicVersion::Version1, Role::Client, CLIENT_CID);
+
+    let packet = bytes(SERVER_INITIAL);
+    let cid_mgr = FixedConnectionIdManager::new(SERVER_CID.len()).unwrap();
+    let (packet, remainder) = PublicPacket::decode(&packet, &cid_mgr).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(packet.packet_type(), PacketType::Initial);
+    assert_eq!(packet.version(), Some(QuicVersion::Version1));
+
+    let decrypted = packet.decrypt(&mut states, now(), Vec::new()).unwrap();
+    assert_eq!(decrypted.pn(), 1);
+    assert_eq!(&decrypted[..], &bytes(SERVER_INITIAL_PAYLOAD)[..]);
+}
+
+#[test]
+fn retry_integrity() {
+    fixture_init();
+    let retry = bytes(RETRY);
+    let cid_mgr = FixedConnectionIdManager::new(SERVER_CID.len()).unwrap();
+    let (packet, remainder) = PublicPacket::decode(&retry, &cid_mgr).unwrap();
+    assert!(remainder.is_empty());
+    assert_eq!(packet.version(), Some(QuicVersion::Version1));
+    assert_eq!(&packet.scid()[..], SERVER_CID);
+    assert_eq!(packet.token(), RETRY_TOKEN);
+    assert!(packet.is_valid_retry(&ConnectionId::from(CLIENT_CID)));
+
+    // Any change to the tag is detected.
+    let mut damaged = retry;
+    *damaged.last_mut().unwrap() ^= 1;
+    let (packet, _) = PublicPacket::decode(&damaged, &cid_mgr).unwrap();
+    assert!(!packet.is_valid_retry(&ConnectionId::from(CLIENT_CID)));
+}
+
+#[test]
+fn build_retry() {
+    fixture_init();
+    let expected = bytes(RETRY);
+    let tag_start = expected.len() - 16;
+    // The unused bits of the first byte are random, and they are covered by the tag.
+    // Keep trying until they match the example.
+    for _ in 0..1000 {
+        let retry = PacketBuilder::retry(
+            QuicVersion::Version1,
+            &[],
+            SERVER_CID,
+            RETRY_TOKEN,
+            CLIENT_CID,
+        )
+        .unwrap();
+        if retry[0] == expected[0] {
+            assert_eq!(retry, expected);
+            return;
+        }
+        assert_eq!(&retry[1..tag_start], &expected[1..tag_start]);
+    }
+    panic!("the first byte of the Retry never matched");
+}
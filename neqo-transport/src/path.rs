@@ -7,8 +7,11 @@
 use std::net::SocketAddr;
 
 use crate::cid::{ConnectionId, ConnectionIdRef};
+use crate::ecn::{EcnCount, EcnInfo};
+use crate::recovery::ACK_ONLY_SIZE_LIMIT;
+use crate::tracking::{PNSpace, SentPacket};
 
-use neqo_common::Datagram;
+use neqo_common::{qinfo, Datagram};
 
 /// This is the MTU that we assume when using IPv6.
 /// We use this size for Initial packets, so we don't need to worry about probing for support.
@@ -18,6 +21,15 @@ use neqo_common::Datagram;
 pub const PATH_MTU_V6: usize = 1337;
 /// The path MTU for IPv4 can be 20 bytes larger than for v6.
 pub const PATH_MTU_V4: usize = PATH_MTU_V6 + 20;
+/// The smallest datagram that a QUIC path has to carry.  A path falls back to this
+/// if it looks like larger packets are being dropped.
+pub const PATH_MTU_MIN: usize = 1200;
+/// How many packets larger than `PATH_MTU_MIN` have to be lost, with none
+/// acknowledged, before deciding that they won't get through.
+const BLACK_HOLE_THRESHOLD: usize = 3;
+/// Until an address is validated, a server can only send this many times
+/// as much as it has received from that address.
+const AMPLIFICATION_FACTOR: usize = 3;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Path {
@@ -26,6 +38,19 @@ pub struct Path {
     local_cids: Vec<ConnectionId>,
     remote_cid: ConnectionId,
     reset_token: Option<[u8; 16]>,
+    mtu: usize,
+    /// The number of large packets lost since a large packet was acknowledged.
+    large_lost: usize,
+    /// Whether a small packet was acknowledged since a large one was.
+    small_acked: bool,
+    /// Whether the peer has shown that it can receive at the remote address.
+    validated: bool,
+    /// The bytes received from and sent to the remote address, which are
+    /// only counted until it is validated.
+    received_bytes: usize,
+    sent_bytes: usize,
+    /// Whether packets are marked for ECN, and whether that works.
+    ecn: EcnInfo,
 }
 
 impl Path {
@@ -42,18 +67,124 @@ impl Path {
             local_cids: vec![local_cid],
             remote_cid,
             reset_token: None,
+            mtu: if local.is_ipv4() {
+                PATH_MTU_V4
+            } else {
+                PATH_MTU_V6 // IPv6
+            },
+            large_lost: 0,
+            small_acked: false,
+            validated: false,
+            received_bytes: 0,
+            sent_bytes: 0,
+            ecn: EcnInfo::default(),
         }
     }
 
+    /// A path that takes the place of this one when either end migrates.
+    /// This keeps the local connection IDs, but none of what was learned
+    /// about this path, so the new path starts out unvalidated.
+    pub fn migrate(&self, local: SocketAddr, remote: SocketAddr, remote_cid: ConnectionId) -> Self {
+        let mut path = Self::new(local, remote, self.local_cid().clone(), remote_cid);
+        path.local_cids = self.local_cids.clone();
+        path
+    }
+
     pub fn received_on(&self, d: &Datagram) -> bool {
         self.local == d.destination() && self.remote == d.source()
     }
 
     pub fn mtu(&self) -> usize {
-        if self.local.is_ipv4() {
-            PATH_MTU_V4
+        self.mtu
+    }
+
+    /// Mark the remote address as valid, which removes the limit on sending.
+    pub fn set_valid(&mut self) {
+        self.validated = true;
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.validated
+    }
+
+    /// Count a datagram received on this path.
+    pub fn on_datagram_received(&mut self, size: usize) {
+        if !self.validated {
+            self.received_bytes += size;
+        }
+    }
+
+    /// Count a datagram sent on this path.
+    pub fn on_datagram_sent(&mut self, size: usize) {
+        if !self.validated {
+            self.sent_bytes += size;
+        }
+    }
+
+    /// How much can be sent before the anti-amplification limit is reached.
+    pub fn amplification_limit(&self) -> usize {
+        if self.validated {
+            usize::max_value()
         } else {
-            PATH_MTU_V6 // IPv6
+            (self.received_bytes * AMPLIFICATION_FACTOR).saturating_sub(self.sent_bytes)
+        }
+    }
+
+    /// Whether the anti-amplification limit leaves too little space for a packet.
+    /// Nothing useful can be sent until more is received from the peer.
+    pub fn amplification_blocked(&self) -> bool {
+        self.amplification_limit() < ACK_ONLY_SIZE_LIMIT
+    }
+
+    /// Note which packets were acknowledged, for black hole detection.
+    pub fn on_packets_acked(&mut self, acked: &[SentPacket]) {
+        for p in acked {
+            if p.size > PATH_MTU_MIN {
+                self.large_lost = 0;
+                self.small_acked = false;
+            } else {
+                self.small_acked = true;
+            }
+        }
+    }
+
+    /// Mark the packets sent on this path as ECN-capable.
+    pub fn enable_ecn(&mut self) {
+        self.ecn.enable();
+    }
+
+    /// Check the ECN counts that acknowledged packets in `space`.
+    /// This returns `true` if the peer reports congestion.
+    pub fn on_ecn_count(
+        &mut self,
+        space: PNSpace,
+        acked: &[SentPacket],
+        counts: Option<EcnCount>,
+    ) -> bool {
+        self.ecn.on_packets_acked(space, acked, counts)
+    }
+
+    /// Note which packets were lost.  If large packets keep getting lost while
+    /// small ones arrive, the path is probably dropping anything over some size,
+    /// so this switches to the smallest size that QUIC allows.
+    /// This returns `true` if that happened.
+    pub fn on_packets_lost(&mut self, lost: &[SentPacket]) -> bool {
+        self.ecn.on_packets_lost(lost);
+        self.large_lost += lost
+            .iter()
+            .filter(|p| p.ack_eliciting() && p.size > PATH_MTU_MIN)
+            .count();
+        if self.mtu > PATH_MTU_MIN && self.small_acked && self.large_lost >= BLACK_HOLE_THRESHOLD {
+            qinfo!(
+                "Black hole detected on path to {}, MTU {} -> {}",
+                self.remote,
+                self.mtu,
+                PATH_MTU_MIN
+            );
+            self.mtu = PATH_MTU_MIN;
+            true
+        } else {
+            false
         }
     }
 
@@ -94,7 +225,12 @@ impl Path {
 
     /// Make a datagram.
     pub fn datagram<V: Into<Vec<u8>>>(&self, payload: V) -> Datagram {
-        Datagram::new(self.local, self.remote, payload)
+        Datagram::new_with_tos_ttl(self.local, self.remote, self.ecn.tos(), None, payload)
+    }
+
+    /// Change the local address, when the peer moves to another of our addresses.
+    pub fn set_local_address(&mut self, local: SocketAddr) {
+        self.local = local;
     }
 
     /// Get local address as `SocketAddr`
@@ -107,3 +243,122 @@ impl Path {
         &self.remote
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::PacketType;
+    use neqo_common::{IPTOS_ECN_ECT0, IPTOS_ECN_NOT_ECT};
+    use std::rc::Rc;
+    use test_fixture::{loopback, now};
+
+    fn path() -> Path {
+        Path::new(
+            loopback(),
+            loopback(),
+            ConnectionId::from(&[1, 2, 3][..]),
+            ConnectionId::from(&[4, 5, 6][..]),
+        )
+    }
+
+    fn sent(pn: u64, size: usize) -> SentPacket {
+        SentPacket::new(
+            PacketType::Short,
+            pn,
+            now(),
+            true,
+            Rc::default(),
+            size,
+            true,
+        )
+    }
+
+    #[test]
+    fn black_hole() {
+        let mut path = path();
+        assert_eq!(path.mtu(), PATH_MTU_V6);
+        path.on_packets_acked(&[sent(0, 100)]);
+        assert!(!path.on_packets_lost(&[sent(1, PATH_MTU_V6), sent(2, PATH_MTU_V6)]));
+        assert!(path.on_packets_lost(&[sent(3, PATH_MTU_V6)]));
+        assert_eq!(path.mtu(), PATH_MTU_MIN);
+        // This only happens once.
+        assert!(!path.on_packets_lost(&[sent(4, PATH_MTU_MIN)]));
+    }
+
+    #[test]
+    fn large_acked() {
+        let mut path = path();
+        path.on_packets_acked(&[sent(0, 100)]);
+        assert!(!path.on_packets_lost(&[sent(1, PATH_MTU_V6), sent(2, PATH_MTU_V6)]));
+        // Getting a large packet through means that there is no black hole.
+        path.on_packets_acked(&[sent(3, PATH_MTU_V6)]);
+        assert!(!path.on_packets_lost(&[sent(4, PATH_MTU_V6), sent(5, PATH_MTU_V6)]));
+        assert_eq!(path.mtu(), PATH_MTU_V6);
+    }
+
+    #[test]
+    fn nothing_acked() {
+        // Losing everything is congestion or a dead path, not a black hole.
+        let mut path = path();
+        let lost = [
+            sent(0, PATH_MTU_V6),
+            sent(1, PATH_MTU_V6),
+            sent(2, PATH_MTU_V6),
+            sent(3, 100),
+        ];
+        assert!(!path.on_packets_lost(&lost));
+        assert_eq!(path.mtu(), PATH_MTU_V6);
+    }
+
+    #[test]
+    fn amplification_limit() {
+        let mut path = path();
+        assert!(path.amplification_blocked());
+        path.on_datagram_received(PATH_MTU_MIN);
+        assert_eq!(path.amplification_limit(), PATH_MTU_MIN * 3);
+        path.on_datagram_sent(PATH_MTU_MIN * 3 - 100);
+        assert_eq!(path.amplification_limit(), 100);
+        assert!(path.amplification_blocked());
+
+        // More from the peer allows more to be sent.
+        path.on_datagram_received(100);
+        assert_eq!(path.amplification_limit(), 400);
+        assert!(!path.amplification_blocked());
+
+        path.set_valid();
+        path.on_datagram_sent(PATH_MTU_MIN * 10);
+        assert!(!path.amplification_blocked());
+        assert_eq!(path.amplification_limit(), usize::max_value());
+    }
+
+    #[test]
+    fn migrate() {
+        let mut path = path();
+        path.add_local_cid(ConnectionId::from(&[7, 8, 9][..]));
+        path.on_datagram_received(PATH_MTU_MIN);
+        path.set_valid();
+        path.set_reset_token([1; 16]);
+
+        let remote = SocketAddr::new(loopback().ip(), 444);
+        let moved = path.migrate(loopback(), remote, ConnectionId::from(&[10][..]));
+        assert_eq!(*moved.remote_address(), remote);
+        assert_eq!(&moved.remote_cid()[..], &[10]);
+        assert!(moved.valid_local_cid(&ConnectionIdRef::from(&[7, 8, 9][..])));
+        // The new address has to be validated again.
+        assert!(!moved.is_valid());
+        assert!(moved.amplification_blocked());
+        assert_eq!(moved.reset_token(), None);
+    }
+
+    #[test]
+    fn ecn_marking() {
+        let mut path = path();
+        assert_eq!(path.datagram(vec![]).ecn(), IPTOS_ECN_NOT_ECT);
+        path.enable_ecn();
+        assert_eq!(path.datagram(vec![]).ecn(), IPTOS_ECN_ECT0);
+
+        // A peer that doesn't report ECN counts stops the marking.
+        assert!(!path.on_ecn_count(PNSpace::ApplicationData, &[sent(0, 100)], None));
+        assert_eq!(path.datagram(vec![]).ecn(), IPTOS_ECN_NOT_ECT);
+    }
+}
@@ -6,23 +6,33 @@
 
 // Functions that handle capturing QLOG traces.
 
+use std::convert::TryFrom;
 use std::string::String;
+use std::time::{Duration, Instant};
 
-use qlog::{self, event::Event, PacketHeader, QuicFrame};
+use qlog::{
+    self, event::Event, ApplicationErrorCode, ConnectionErrorCode, EventCategory, EventData,
+    EventType, GenericEventType, PacketHeader, QuicFrame,
+};
 
-use neqo_common::{hex, qinfo, qlog::NeqoQlog, Decoder};
+use neqo_common::qlog::{NeqoQlog, QlogCategory};
+use neqo_common::{hex, qinfo, Decoder};
 
-use crate::frame::{self, Frame};
+use crate::cc::CongestionState;
+use crate::connection::PeerClose;
+use crate::frame::{self, CloseError, Frame};
 use crate::packet::{DecryptedPacket, PacketNumber, PacketType};
 use crate::path::Path;
+use crate::recovery::RecoveryMetrics;
 use crate::tparams::{self, TransportParametersHandler};
+use crate::tracking::SentPacket;
 use crate::{QuicVersion, Res};
 
 pub fn connection_tparams_set(
     qlog: &mut Option<NeqoQlog>,
     tph: &TransportParametersHandler,
 ) -> Res<()> {
-    if let Some(qlog) = qlog {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Transport) {
         let remote = tph.remote();
         let event = Event::transport_parameters_set(
             None,
@@ -84,6 +94,35 @@ pub fn connection_tparams_set(
     Ok(())
 }
 
+/// The peer closed the connection.
+pub fn connection_closed(qlog: &mut Option<NeqoQlog>, close: &PeerClose) -> Res<()> {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Connectivity) {
+        let description = Some(close.to_string());
+        let (ty, data) = match close.error {
+            CloseError::Transport(code) => (
+                GenericEventType::ConnectionError,
+                EventData::ConnectionError {
+                    code: Some(ConnectionErrorCode::Value(code)),
+                    description,
+                },
+            ),
+            CloseError::Application(code) => (
+                GenericEventType::ApplicationError,
+                EventData::ApplicationError {
+                    code: Some(ApplicationErrorCode::Value(code)),
+                    description,
+                },
+            ),
+        };
+        qlog.stream().add_event(Event {
+            category: EventCategory::Error,
+            ty: EventType::GenericEventType(ty),
+            data,
+        })?;
+    }
+    Ok(())
+}
+
 pub fn server_connection_started(qlog: &mut Option<NeqoQlog>, path: &Path) -> Res<()> {
     connection_started(qlog, path)
 }
@@ -98,7 +137,7 @@ pub fn packet_sent(
     pn: PacketNumber,
     body: &[u8],
 ) -> Res<()> {
-    if let Some(qlog) = qlog {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Transport) {
         let mut d = Decoder::from(body);
 
         qlog.stream().add_event(Event::packet_sent_min(
@@ -123,7 +162,7 @@ pub fn packet_sent(
 }
 
 pub fn packet_received(qlog: &mut Option<NeqoQlog>, payload: &DecryptedPacket) -> Res<()> {
-    if let Some(qlog) = qlog {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Transport) {
         let mut d = Decoder::from(&payload[..]);
 
         qlog.stream().add_event(Event::packet_received(
@@ -150,8 +189,75 @@ pub fn packet_received(qlog: &mut Option<NeqoQlog>, payload: &DecryptedPacket) -
     Ok(())
 }
 
+pub fn packets_lost(qlog: &mut Option<NeqoQlog>, pkts: &[SentPacket]) -> Res<()> {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Recovery) {
+        for pkt in pkts {
+            qlog.stream().add_event(Event::packet_lost_min(
+                to_qlog_pkt_type(pkt.pt),
+                pkt.pn.to_string(),
+                Vec::new(),
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn metrics_updated(qlog: &mut Option<NeqoQlog>, metrics: &RecoveryMetrics) -> Res<()> {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Recovery) {
+        qlog.stream().add_event(Event::metrics_updated(
+            metrics.min_rtt.map(duration_to_ms),
+            metrics.smoothed_rtt.map(duration_to_ms),
+            Some(duration_to_ms(metrics.latest_rtt)),
+            Some(duration_to_ms(metrics.rttvar)),
+            Some(duration_to_ms(metrics.max_ack_delay)),
+            Some(saturating_u64(metrics.pto_count)),
+            Some(saturating_u64(metrics.cwnd)),
+            Some(saturating_u64(metrics.bytes_in_flight)),
+            metrics.ssthresh.map(saturating_u64),
+            None,
+            None,
+            None,
+        ))?;
+    }
+    Ok(())
+}
+
+pub fn congestion_state_updated(
+    qlog: &mut Option<NeqoQlog>,
+    old: Option<CongestionState>,
+    new: CongestionState,
+) -> Res<()> {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Recovery) {
+        qlog.stream().add_event(Event::congestion_state_updated(
+            old.map(|s| s.to_qlog_string().to_owned()),
+            new.to_qlog_string().to_owned(),
+        ))?;
+    }
+    Ok(())
+}
+
+/// Log that the loss recovery timer was set to `timeout` or, if that is `None`, cancelled.
+pub fn loss_timer_updated(
+    qlog: &mut Option<NeqoQlog>,
+    timeout: Option<Instant>,
+    now: Instant,
+) -> Res<()> {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Recovery) {
+        let event = if let Some(t) = timeout {
+            Event::loss_timer_set(
+                None,
+                Some(duration_to_ms(t.saturating_duration_since(now)).to_string()),
+            )
+        } else {
+            Event::loss_timer_cancelled()
+        };
+        qlog.stream().add_event(event)?;
+    }
+    Ok(())
+}
+
 fn connection_started(qlog: &mut Option<NeqoQlog>, path: &Path) -> Res<()> {
-    if let Some(qlog) = qlog {
+    if let Some(qlog) = NeqoQlog::recording(qlog, QlogCategory::Connectivity) {
         qlog.stream().add_event(Event::connection_started(
             if path.local_address().ip().is_ipv4() {
                 "ipv4".into()
@@ -182,11 +288,18 @@ fn frame_to_qlogframe(frame: &Frame) -> QuicFrame {
             ack_delay,
             first_ack_range,
             ack_ranges,
+            ecn_count,
         } => {
             let ack_ranges =
                 Frame::decode_ack_frame(*largest_acknowledged, *first_ack_range, ack_ranges).ok();
 
-            QuicFrame::ack(Some(ack_delay.to_string()), ack_ranges, None, None, None)
+            QuicFrame::ack(
+                Some(ack_delay.to_string()),
+                ack_ranges,
+                ecn_count.map(|c| c.ect1.to_string()),
+                ecn_count.map(|c| c.ect0.to_string()),
+                ecn_count.map(|c| c.ce.to_string()),
+            )
         }
         Frame::ResetStream {
             stream_id,
@@ -286,9 +399,18 @@ fn frame_to_qlogframe(frame: &Frame) -> QuicFrame {
             Some(frame_type.to_string()),
         ),
         Frame::HandshakeDone => QuicFrame::handshake_done(),
+        Frame::Datagram { .. } => QuicFrame::unknown(frame.get_type()),
     }
 }
 
+fn duration_to_ms(d: Duration) -> u64 {
+    u64::try_from(d.as_millis()).unwrap_or(u64::max_value())
+}
+
+fn saturating_u64(v: usize) -> u64 {
+    u64::try_from(v).unwrap_or(u64::max_value())
+}
+
 fn to_qlog_pkt_type(ptype: PacketType) -> qlog::PacketType {
     match ptype {
         PacketType::Initial => qlog::PacketType::Initial,
@@ -0,0 +1,93 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Queue of unreliable datagrams (DATAGRAM frames) waiting to be sent.
+
+use crate::frame::Frame;
+use neqo_common::{qdebug, Encoder};
+use std::collections::VecDeque;
+
+/// The number of datagrams that can wait to be sent.  When the queue is full
+/// the oldest datagram is dropped.
+const MAX_QUEUED_DATAGRAMS: usize = 10;
+
+#[derive(Debug, Default)]
+pub(crate) struct QuicDatagrams {
+    datagrams: VecDeque<Vec<u8>>,
+}
+
+impl QuicDatagrams {
+    /// The number of bytes a DATAGRAM frame adds to a payload of `len` bytes.
+    pub fn overhead(len: usize) -> usize {
+        1 + Encoder::varint_len(len as u64)
+    }
+
+    pub fn add(&mut self, data: &[u8]) {
+        if self.datagrams.len() == MAX_QUEUED_DATAGRAMS {
+            qdebug!("Datagram queue full, dropping the oldest datagram");
+            self.datagrams.pop_front();
+        }
+        self.datagrams.push_back(data.to_vec());
+    }
+
+    /// Get a DATAGRAM frame for the next datagram if it fits in `remaining`.
+    /// A datagram that doesn't fit waits for the next packet, unless it can never
+    /// fit in a packet of `max_space`, in which case it is dropped.
+    pub fn get_frame(&mut self, remaining: usize, max_space: usize) -> Option<Frame> {
+        loop {
+            let len = self.datagrams.front()?.len();
+            let needed = len + Self::overhead(len);
+            if needed <= remaining {
+                let data = self.datagrams.pop_front().unwrap();
+                return Some(Frame::Datagram { data, fill: false });
+            }
+            if needed <= max_space {
+                return None;
+            }
+            qdebug!("Datagram of {} bytes doesn't fit in a packet", len);
+            self.datagrams.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QuicDatagrams, MAX_QUEUED_DATAGRAMS};
+    use crate::frame::Frame;
+
+    #[test]
+    fn queue_limit() {
+        let mut dgrams = QuicDatagrams::default();
+        for i in 0..=MAX_QUEUED_DATAGRAMS {
+            dgrams.add(&[i as u8]);
+        }
+        // The first datagram was dropped.
+        assert_eq!(
+            dgrams.get_frame(100, 100),
+            Some(Frame::Datagram {
+                data: vec![1],
+                fill: false
+            })
+        );
+    }
+
+    #[test]
+    fn too_large() {
+        let mut dgrams = QuicDatagrams::default();
+        dgrams.add(&[0; 10]);
+        dgrams.add(&[1]);
+        // The first doesn't fit in this packet, but it might fit in the next.
+        assert_eq!(dgrams.get_frame(5, 20), None);
+        // It doesn't fit in any packet, so the second is sent instead.
+        assert_eq!(
+            dgrams.get_frame(5, 5),
+            Some(Frame::Datagram {
+                data: vec![1],
+                fill: false
+            })
+        );
+    }
+}
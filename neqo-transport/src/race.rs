@@ -0,0 +1,353 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Racing connection attempts, in the style of Happy Eyeballs (RFC 8305).
+//
+// A client that has several addresses for a server, or several versions that
+// it could use, makes a connection for each.  Attempts that use the same
+// addresses are told apart by the version in long header packets, which is
+// all that the server sends until an attempt wins.  Attempts are started in order of
+// preference, each one a short delay after the one before or as soon as all of
+// the earlier attempts have failed.  The first attempt to hear back from the
+// server is kept and the others are closed.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use neqo_common::{matches, qdebug, qinfo, Datagram, Role};
+
+use crate::connection::{Connection, Output, State};
+use crate::packet::{QuicVersion, PACKET_BIT_LONG};
+
+/// The delay between starting attempts that RFC 8305 recommends.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// A set of client connections that race to connect to a server.
+/// Datagrams are passed to this in place of a `Connection` until there is a
+/// winner, which can then be taken and used.
+#[derive(Debug)]
+pub struct ConnectionRace {
+    /// The attempts, in order of preference.  Only the first `started` are running.
+    attempts: Vec<Connection>,
+    started: usize,
+    delay: Duration,
+    /// When the next attempt starts, even if the others haven't failed.
+    next_start: Option<Instant>,
+    winner: Option<Connection>,
+    /// Datagrams that close the attempts that lost.
+    closing: VecDeque<Datagram>,
+}
+
+impl ConnectionRace {
+    /// Race `attempts`, which are client connections that haven't been used yet.
+    /// Each attempt needs a different combination of local address, remote
+    /// address, and version, as that is how datagrams are matched to attempts.
+    #[must_use]
+    pub fn new(attempts: Vec<Connection>, delay: Duration) -> Self {
+        debug_assert!(!attempts.is_empty());
+        debug_assert!(attempts
+            .iter()
+            .all(|c| c.role() == Role::Client && *c.state() == State::Init));
+        let key = |c: &Connection| {
+            c.path()
+                .map(|p| (*p.local_address(), *p.remote_address(), c.quic_version()))
+        };
+        debug_assert!(attempts
+            .iter()
+            .enumerate()
+            .all(|(i, c)| attempts[..i].iter().all(|o| key(o) != key(c))));
+        Self {
+            attempts,
+            started: 0,
+            delay,
+            next_start: None,
+            winner: None,
+            closing: VecDeque::new(),
+        }
+    }
+
+    /// The attempt that won, once there is one.
+    #[must_use]
+    pub fn winner(&self) -> Option<&Connection> {
+        self.winner.as_ref()
+    }
+
+    /// Take the attempt that won.  This returns `None` until the datagrams
+    /// that close the other attempts have been taken from `process_output`.
+    pub fn take_winner(&mut self) -> Option<Connection> {
+        if self.closing.is_empty() {
+            self.winner.take()
+        } else {
+            None
+        }
+    }
+
+    /// Whether every attempt failed.
+    #[must_use]
+    pub fn failed(&self) -> bool {
+        self.winner.is_none()
+            && !self.attempts.is_empty()
+            && self.started == self.attempts.len()
+            && self.attempts.iter().all(|c| c.state().closed())
+    }
+
+    /// An attempt has won once it hears from the server, as that shows that
+    /// the server can be reached with that address and version.
+    fn reached_server(conn: &Connection) -> bool {
+        matches!(conn.state(), State::Handshaking | State::Connected | State::Confirmed)
+    }
+
+    fn start_attempts(&mut self, now: Instant) {
+        while self.started < self.attempts.len() {
+            let due = self.next_start.map_or(true, |t| t <= now);
+            let stalled = self.attempts[..self.started]
+                .iter()
+                .all(|c| c.state().closed());
+            if !due && !stalled {
+                break;
+            }
+            qinfo!("Starting connection attempt {}", self.started);
+            self.started += 1;
+            self.next_start = Some(now + self.delay);
+        }
+    }
+
+    fn pick_winner(&mut self, now: Instant) {
+        let idx = if let Some(idx) = self.attempts[..self.started]
+            .iter()
+            .position(Self::reached_server)
+        {
+            idx
+        } else {
+            return;
+        };
+        qinfo!("Connection attempt {} won", idx);
+        for (i, mut conn) in self.attempts.drain(..).enumerate() {
+            if i == idx {
+                self.winner = Some(conn);
+            } else if i < self.started && !conn.state().closed() {
+                conn.close(now, 0, "Connection attempt abandoned");
+                if let Some(d) = conn.process_output(now).dgram() {
+                    self.closing.push_back(d);
+                }
+            }
+        }
+        self.started = 0;
+    }
+
+    /// The version of a datagram that starts with a long header packet.
+    /// This is zero for Version Negotiation.
+    fn long_header_version(d: &Datagram) -> Option<u32> {
+        if d.len() < 5 || d[0] & PACKET_BIT_LONG == 0 {
+            None
+        } else {
+            Some(u32::from_be_bytes([d[1], d[2], d[3], d[4]]))
+        }
+    }
+
+    /// Whether `d` could be for an attempt that uses `version`.  Version
+    /// Negotiation is for every attempt on the path, each of which either
+    /// ignores it or fails.
+    fn version_matches(d: &Datagram, version: QuicVersion) -> bool {
+        Self::long_header_version(d).map_or(true, |v| v == 0 || v == version.as_u32())
+    }
+
+    /// Pass a datagram to the attempts that it is for.
+    pub fn process_input(&mut self, d: Datagram, now: Instant) {
+        if let Some(w) = &mut self.winner {
+            w.process_input(d, now);
+            return;
+        }
+        let vn = Self::long_header_version(&d) == Some(0);
+        let mut attempts = self.attempts[..self.started].iter_mut().filter(|c| {
+            c.path().map_or(false, |p| p.received_on(&d))
+                && Self::version_matches(&d, c.quic_version())
+        });
+        let first = if let Some(c) = attempts.next() {
+            c
+        } else {
+            qdebug!("Dropping datagram that matches no connection attempt");
+            return;
+        };
+        if vn {
+            for conn in attempts {
+                conn.process_input(d.clone(), now);
+            }
+        }
+        first.process_input(d, now);
+        self.pick_winner(now);
+    }
+
+    /// Get the next datagram to send for any of the attempts, or how long
+    /// to wait.  This returns `Output::None` if all of the attempts failed.
+    pub fn process_output(&mut self, now: Instant) -> Output {
+        if let Some(d) = self.closing.pop_front() {
+            return Output::Datagram(d);
+        }
+        if let Some(w) = &mut self.winner {
+            return w.process_output(now);
+        }
+
+        self.start_attempts(now);
+        let mut wait = if self.started < self.attempts.len() {
+            self.next_start.map(|t| t.saturating_duration_since(now))
+        } else {
+            None
+        };
+        for conn in &mut self.attempts[..self.started] {
+            match conn.process_output(now) {
+                Output::Datagram(d) => return Output::Datagram(d),
+                Output::Callback(t) => wait = Some(wait.map_or(t, |w| min(w, t))),
+                Output::None => {}
+            }
+        }
+        wait.map_or(Output::None, Output::Callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionRace, CONNECTION_ATTEMPT_DELAY};
+    use crate::connection::{Connection, FixedConnectionIdManager, Output};
+    use crate::packet::QuicVersion;
+    use neqo_common::Datagram;
+    use std::cell::RefCell;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::time::Instant;
+    use test_fixture::{self, fixture_init, now};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn client_version(local: &str, remote: &str, version: QuicVersion) -> Connection {
+        fixture_init();
+        Connection::new_client(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0).unwrap())),
+            addr(local),
+            addr(remote),
+            version,
+        )
+        .unwrap()
+    }
+
+    fn client(local: &str, remote: &str) -> Connection {
+        client_version(local, remote, QuicVersion::default())
+    }
+
+    fn server() -> Connection {
+        Connection::new_server(
+            test_fixture::DEFAULT_KEYS,
+            test_fixture::DEFAULT_ALPN,
+            &test_fixture::anti_replay(),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(5).unwrap())),
+            QuicVersion::default(),
+        )
+        .unwrap()
+    }
+
+    /// Two attempts, where the first goes to an address that doesn't respond.
+    fn race() -> ConnectionRace {
+        ConnectionRace::new(
+            vec![
+                client("[::1]:1001", "[2001:db8::1]:443"),
+                client("127.0.0.1:1002", "127.0.0.1:443"),
+            ],
+            CONNECTION_ATTEMPT_DELAY,
+        )
+    }
+
+    /// Collect what the race sends at `now`.
+    fn output(race: &mut ConnectionRace, now: Instant) -> Vec<Datagram> {
+        let mut dgrams = Vec::new();
+        while let Output::Datagram(d) = race.process_output(now) {
+            dgrams.push(d);
+        }
+        dgrams
+    }
+
+    #[test]
+    fn second_attempt_wins() {
+        let unreachable = addr("[2001:db8::1]:443");
+        let mut race = race();
+        let first = output(&mut race, now());
+        assert!(!first.is_empty());
+        assert!(first.iter().all(|d| d.destination() == unreachable));
+        assert!(race.winner().is_none());
+
+        // The second attempt starts after the delay.
+        let now = now() + CONNECTION_ATTEMPT_DELAY;
+        let initial = output(&mut race, now)
+            .into_iter()
+            .find(|d| d.destination() == addr("127.0.0.1:443"))
+            .unwrap();
+        let mut server = server();
+        let response = server.process(Some(initial), now).dgram();
+        race.process_input(response.unwrap(), now);
+        assert!(race.winner().is_some());
+
+        // The first attempt is closed before the winner can be taken.
+        assert!(race.take_winner().is_none());
+        let closing = race.process_output(now).dgram().unwrap();
+        assert_eq!(closing.destination(), unreachable);
+        let winner = race.take_winner().unwrap();
+        assert_eq!(
+            *winner.path().unwrap().remote_address(),
+            addr("127.0.0.1:443")
+        );
+        assert!(!race.failed());
+    }
+
+    #[test]
+    fn version_race() {
+        // Both attempts use the same addresses.
+        let mut race = ConnectionRace::new(
+            vec![
+                client_version("127.0.0.1:1001", "127.0.0.1:443", QuicVersion::Version1),
+                client_version("127.0.0.1:1001", "127.0.0.1:443", QuicVersion::Draft29),
+            ],
+            CONNECTION_ATTEMPT_DELAY,
+        );
+        let first = output(&mut race, now());
+        assert!(!first.is_empty());
+        assert!(first.iter().all(|d| {
+            ConnectionRace::long_header_version(d) == Some(QuicVersion::Version1.as_u32())
+        }));
+
+        // The server only answers the second, which has to win.
+        let now = now() + CONNECTION_ATTEMPT_DELAY;
+        let initial = output(&mut race, now)
+            .into_iter()
+            .find(|d| ConnectionRace::long_header_version(d) == Some(QuicVersion::Draft29.as_u32()))
+            .unwrap();
+        let mut server = server();
+        let response = server.process(Some(initial), now).dgram();
+        race.process_input(response.unwrap(), now);
+        assert_eq!(
+            race.winner().map(Connection::quic_version),
+            Some(QuicVersion::Draft29)
+        );
+    }
+
+    #[test]
+    fn all_attempts_fail() {
+        let mut race = race();
+        let mut now = now();
+        loop {
+            match race.process_output(now) {
+                Output::Datagram(_) => {}
+                Output::Callback(t) => now += t,
+                Output::None => break,
+            }
+        }
+        assert!(race.failed());
+        assert!(race.winner().is_none());
+    }
+}
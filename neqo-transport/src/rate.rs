@@ -0,0 +1,285 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Estimating the rate at which the path delivers data.
+//
+// Each packet records how much had been delivered when it was sent.  When a
+// packet is acknowledged, the data delivered since then is divided by the
+// longer of the time taken to send it and the time taken to acknowledge it.
+// This follows draft-cheng-iccrg-delivery-rate-estimation.
+#![deny(clippy::pedantic)]
+
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use crate::packet::PacketNumber;
+use crate::tracking::{PNSpace, SentPacket};
+
+/// Each new sample contributes `1 / SMOOTHING_WEIGHT` to the smoothed rate.
+const SMOOTHING_WEIGHT: u64 = 8;
+
+/// The delivery state of the connection at the time a packet was sent.
+#[derive(Debug, Clone, Copy)]
+struct SendState {
+    delivered: u64,
+    delivered_time: Instant,
+    first_sent_time: Instant,
+    app_limited: bool,
+}
+
+/// A delivery rate sample, which is taken each time packets are acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateSample {
+    /// The total bytes delivered, including those that were just acknowledged.
+    pub delivered: u64,
+    /// The total bytes delivered when the newest acknowledged packet was sent.
+    pub prior_delivered: u64,
+    /// The bytes that were just acknowledged.
+    pub acked_bytes: usize,
+    /// The time over which `delivered - prior_delivered` bytes were delivered.
+    pub interval: Duration,
+    /// The round trip time of the newest acknowledged packet.
+    pub rtt: Duration,
+    /// Whether the application didn't have enough to send to use the
+    /// available capacity when the newest acknowledged packet was sent.
+    /// Samples like this only show a lower bound on the rate.
+    pub app_limited: bool,
+}
+
+impl RateSample {
+    /// The delivery rate, in bytes per second.  This is `None` when the
+    /// interval is too short to measure.
+    #[must_use]
+    pub fn rate(&self) -> Option<u64> {
+        let interval_us = u64::try_from(self.interval.as_micros()).unwrap_or(u64::max_value());
+        if interval_us == 0 {
+            None
+        } else {
+            Some((self.delivered - self.prior_delivered).saturating_mul(1_000_000) / interval_us)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DeliveryRate {
+    delivered: u64,
+    delivered_time: Option<Instant>,
+    first_sent_time: Option<Instant>,
+    /// While this is non-zero, packets are sent while application limited.
+    /// This ends once this many bytes have been delivered.
+    app_limited_until: u64,
+    sent: BTreeMap<(PNSpace, PacketNumber), SendState>,
+
+    /// The rate from the latest sample that could be measured.
+    rate: u64,
+    rate_app_limited: bool,
+    smoothed_rate: u64,
+}
+
+impl DeliveryRate {
+    /// The total number of bytes delivered.
+    #[must_use]
+    pub fn delivered(&self) -> u64 {
+        self.delivered
+    }
+
+    /// The delivery rate from the latest sample, in bytes per second.
+    #[must_use]
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Whether the latest sample was taken while application limited.
+    #[must_use]
+    pub fn app_limited(&self) -> bool {
+        self.rate_app_limited
+    }
+
+    /// A smoothed delivery rate, in bytes per second.  Application-limited
+    /// samples only increase this, as they underestimate what the path can do.
+    #[must_use]
+    pub fn smoothed_rate(&self) -> u64 {
+        self.smoothed_rate
+    }
+
+    /// Record the delivery state for a packet that counts toward bytes in flight.
+    /// `bytes_in_flight` is the amount in flight before this packet.
+    pub fn on_packet_sent(&mut self, pkt: &SentPacket, bytes_in_flight: usize) {
+        if bytes_in_flight == 0 {
+            // Nothing is in flight, so the time spent idle shouldn't
+            // count when sampling the delivery rate.
+            self.first_sent_time = Some(pkt.time_sent);
+            self.delivered_time = Some(pkt.time_sent);
+        }
+        let st = SendState {
+            delivered: self.delivered,
+            delivered_time: *self.delivered_time.get_or_insert(pkt.time_sent),
+            first_sent_time: *self.first_sent_time.get_or_insert(pkt.time_sent),
+            app_limited: self.app_limited_until != 0,
+        };
+        self.sent.insert((PNSpace::from(pkt.pt), pkt.pn), st);
+    }
+
+    /// Note that there was nothing to send, even though the congestion window
+    /// had space.  Samples are application limited until what is in flight
+    /// now has been acknowledged.
+    pub fn on_app_limited(&mut self, bytes_in_flight: usize) {
+        self.app_limited_until = max(self.delivered + u64::try_from(bytes_in_flight).unwrap(), 1);
+    }
+
+    /// Take a sample for newly acknowledged packets.  This returns `None` if
+    /// none of the packets were being tracked.
+    pub fn on_packets_acked(
+        &mut self,
+        acked_pkts: &[SentPacket],
+        now: Instant,
+    ) -> Option<RateSample> {
+        let mut newest: Option<(Instant, SendState)> = None;
+        let mut acked_bytes = 0;
+        for pkt in acked_pkts {
+            if let Some(st) = self.sent.remove(&(PNSpace::from(pkt.pt), pkt.pn)) {
+                acked_bytes += pkt.size;
+                if newest.map_or(true, |(t, _)| pkt.time_sent > t) {
+                    newest = Some((pkt.time_sent, st));
+                }
+            }
+        }
+        let (time_sent, st) = newest?;
+        self.delivered += u64::try_from(acked_bytes).unwrap();
+        self.delivered_time = Some(now);
+        self.first_sent_time = Some(time_sent);
+        if self.app_limited_until != 0 && self.delivered > self.app_limited_until {
+            self.app_limited_until = 0;
+        }
+
+        // The delivery rate is limited by the slower of the send and ack rates.
+        let sample = RateSample {
+            delivered: self.delivered,
+            prior_delivered: st.delivered,
+            acked_bytes,
+            interval: max(
+                time_sent.saturating_duration_since(st.first_sent_time),
+                now.saturating_duration_since(st.delivered_time),
+            ),
+            rtt: now.saturating_duration_since(time_sent),
+            app_limited: st.app_limited,
+        };
+        if let Some(rate) = sample.rate() {
+            self.update_rate(rate, sample.app_limited);
+        }
+        Some(sample)
+    }
+
+    fn update_rate(&mut self, rate: u64, app_limited: bool) {
+        self.rate = rate;
+        self.rate_app_limited = app_limited;
+        if self.smoothed_rate == 0 {
+            self.smoothed_rate = rate;
+        } else if !app_limited || rate > self.smoothed_rate {
+            self.smoothed_rate =
+                (self.smoothed_rate.saturating_mul(SMOOTHING_WEIGHT - 1) + rate) / SMOOTHING_WEIGHT;
+        }
+    }
+
+    /// Forget about packets that won't be acknowledged.
+    pub fn on_packets_lost(&mut self, lost_pkts: &[SentPacket]) {
+        for pkt in lost_pkts {
+            self.discard(pkt);
+        }
+    }
+
+    pub fn discard(&mut self, pkt: &SentPacket) {
+        self.sent.remove(&(PNSpace::from(pkt.pt), pkt.pn));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeliveryRate;
+    use crate::packet::PacketType;
+    use crate::tracking::SentPacket;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+    use test_fixture::now;
+
+    const MSS: usize = 1000;
+    const RTT: Duration = Duration::from_millis(100);
+
+    fn sent(pn: u64, t: Instant) -> SentPacket {
+        SentPacket::new(PacketType::Short, pn, t, true, Rc::default(), MSS, true)
+    }
+
+    /// Send `count` packets at `t`, then acknowledge them all one RTT later.
+    fn round(dr: &mut DeliveryRate, pn: &mut u64, count: usize, t: Instant) -> Instant {
+        let mut pkts = Vec::new();
+        for i in 0..count {
+            let pkt = sent(*pn, t);
+            dr.on_packet_sent(&pkt, i * MSS);
+            pkts.push(pkt);
+            *pn += 1;
+        }
+        let ack_time = t + RTT;
+        let sample = dr.on_packets_acked(&pkts, ack_time).unwrap();
+        assert_eq!(sample.acked_bytes, count * MSS);
+        assert_eq!(sample.rtt, RTT);
+        ack_time
+    }
+
+    #[test]
+    fn rate_sample() {
+        let mut dr = DeliveryRate::default();
+        let mut pn = 0;
+        round(&mut dr, &mut pn, 10, now());
+        // 10 packets over one RTT.
+        assert_eq!(dr.rate(), 100_000);
+        assert_eq!(dr.smoothed_rate(), 100_000);
+        assert_eq!(dr.delivered(), 10_000);
+        assert!(!dr.app_limited());
+    }
+
+    #[test]
+    fn smoothed_rate() {
+        let mut dr = DeliveryRate::default();
+        let mut pn = 0;
+        let t = round(&mut dr, &mut pn, 10, now());
+        round(&mut dr, &mut pn, 18, t);
+        assert_eq!(dr.rate(), 180_000);
+        assert_eq!(dr.smoothed_rate(), 110_000);
+    }
+
+    #[test]
+    fn app_limited() {
+        let mut dr = DeliveryRate::default();
+        let mut pn = 0;
+        let t = round(&mut dr, &mut pn, 10, now());
+
+        // The application runs out of data, so the next round is slower.
+        dr.on_app_limited(0);
+        let t = round(&mut dr, &mut pn, 2, t);
+        assert_eq!(dr.rate(), 20_000);
+        assert!(dr.app_limited());
+        assert_eq!(dr.smoothed_rate(), 100_000);
+
+        // Once the application-limited packets are acknowledged,
+        // samples count again.
+        round(&mut dr, &mut pn, 2, t);
+        assert!(!dr.app_limited());
+        assert_eq!(dr.smoothed_rate(), 90_000);
+    }
+
+    #[test]
+    fn lost_packets_are_forgotten() {
+        let mut dr = DeliveryRate::default();
+        let pkt = sent(0, now());
+        dr.on_packet_sent(&pkt, 0);
+        dr.on_packets_lost(&[pkt.clone()]);
+        assert!(dr.on_packets_acked(&[pkt], now() + RTT).is_none());
+        assert_eq!(dr.rate(), 0);
+        assert_eq!(dr.delivered(), 0);
+    }
+}
@@ -9,18 +9,21 @@
 #![deny(clippy::pedantic)]
 
 use std::cmp::{max, min};
-use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::RangeInclusive;
 use std::time::{Duration, Instant};
 
 use smallvec::{smallvec, SmallVec};
 
 use neqo_common::{qdebug, qinfo, qtrace, qwarn};
 
-use crate::cc::CongestionControl;
+use crate::cc::{CongestionControl, CongestionControlAlgorithm, CongestionState};
 use crate::crypto::CryptoRecoveryToken;
 use crate::flow_mgr::FlowControlRecoveryToken;
+use crate::rate::DeliveryRate;
 use crate::send_stream::StreamRecoveryToken;
-use crate::tracking::{AckToken, PNSpace, SentPacket};
+use crate::tracking::{AckToken, PNSpace, SentPacket, DEFAULT_ACK_DELAY_EXPONENT};
 use crate::LOCAL_IDLE_TIMEOUT;
 
 pub const GRANULARITY: Duration = Duration::from_millis(20);
@@ -53,6 +56,8 @@ struct RttVals {
     rttvar: Duration,
     min_rtt: Duration,
     max_ack_delay: Duration,
+    /// The exponent the peer uses to encode the ACK delay for application data.
+    ack_delay_exponent: u64,
 }
 
 impl RttVals {
@@ -159,6 +164,68 @@ impl SendProfile {
     }
 }
 
+/// Sent packets, in order of packet number.  Packets are almost always added
+/// at the end and removed from the start, which a `VecDeque` does cheaply.
+#[derive(Debug, Default)]
+struct SentPackets {
+    packets: VecDeque<SentPacket>,
+}
+
+impl SentPackets {
+    /// The index of the first packet for which `f` returns `false`.
+    /// `f` has to return `true` for a prefix of the packets and `false` after that.
+    fn partition_point(&self, f: impl Fn(&SentPacket) -> bool) -> usize {
+        let (mut lo, mut hi) = (0, self.packets.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if f(&self.packets[mid]) {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    pub fn insert(&mut self, sent: SentPacket) {
+        if self.packets.back().map_or(true, |p| p.pn < sent.pn) {
+            self.packets.push_back(sent);
+        } else {
+            let i = self.partition_point(|p| p.pn < sent.pn);
+            debug_assert_ne!(self.packets[i].pn, sent.pn, "packet sent twice");
+            self.packets.insert(i, sent);
+        }
+    }
+
+    pub fn remove(&mut self, pn: u64) -> Option<SentPacket> {
+        let i = self.partition_point(|p| p.pn < pn);
+        if self.packets.get(i).map_or(false, |p| p.pn == pn) {
+            self.packets.remove(i)
+        } else {
+            None
+        }
+    }
+
+    /// Move the packets with numbers in `range` to the end of `taken`,
+    /// in ascending order.
+    pub fn take_range(&mut self, range: RangeInclusive<u64>, taken: &mut Vec<SentPacket>) {
+        let start = self.partition_point(|p| p.pn < *range.start());
+        let end = self.partition_point(|p| p.pn <= *range.end());
+        if start < end {
+            taken.extend(self.packets.drain(start..end));
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut SentPacket> {
+        self.packets.iter_mut()
+    }
+
+    /// Remove all packets.
+    pub fn take(&mut self) -> impl Iterator<Item = SentPacket> {
+        mem::take(&mut self.packets).into_iter()
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct LossRecoverySpace {
     space: PNSpace,
@@ -172,7 +239,7 @@ pub(crate) struct LossRecoverySpace {
     /// This might be less than the number of ACK-eliciting packets,
     /// because PTO packets don't count.
     in_flight_outstanding: u64,
-    sent_packets: BTreeMap<u64, SentPacket>,
+    sent_packets: SentPackets,
     /// The time that the first out-of-order packet was sent.
     /// This is `None` if there were no out-of-order packets detected.
     /// When set to `Some(T)`, time-based loss detection should be enabled.
@@ -187,7 +254,7 @@ impl LossRecoverySpace {
             largest_acked_sent_time: None,
             pto_base_time: None,
             in_flight_outstanding: 0,
-            sent_packets: BTreeMap::default(),
+            sent_packets: SentPackets::default(),
             first_ooo_time: None,
         }
     }
@@ -212,9 +279,9 @@ impl LossRecoverySpace {
     pub fn pto_packets(&mut self, count: usize) -> impl Iterator<Item = &SentPacket> {
         self.sent_packets
             .iter_mut()
-            .filter_map(|(pn, sent)| {
+            .filter_map(|sent| {
                 if sent.pto() {
-                    qtrace!("PTO: marking packet {} lost ", pn);
+                    qtrace!("PTO: marking packet {} lost ", sent.pn);
                     Some(&*sent)
                 } else {
                     None
@@ -223,6 +290,11 @@ impl LossRecoverySpace {
             .take(count)
     }
 
+    /// The time that the last ack-eliciting packet was sent, even if nothing is in flight.
+    pub fn last_ack_eliciting(&self) -> Option<Instant> {
+        self.pto_base_time
+    }
+
     pub fn pto_base_time(&self) -> Option<Instant> {
         if self.in_flight_outstanding() {
             debug_assert!(self.pto_base_time.is_some());
@@ -239,40 +311,43 @@ impl LossRecoverySpace {
                 self.in_flight_outstanding += 1;
             }
         }
-        self.sent_packets.insert(packet_number, sent_packet);
+        debug_assert_eq!(packet_number, sent_packet.pn);
+        self.sent_packets.insert(sent_packet);
     }
 
-    pub fn remove_packet(&mut self, pn: u64) -> Option<SentPacket> {
-        if let Some(sent) = self.sent_packets.remove(&pn) {
-            if sent.cc_in_flight() {
-                debug_assert!(self.in_flight_outstanding > 0);
-                self.in_flight_outstanding -= 1;
-            }
-            Some(sent)
-        } else {
-            None
+    fn packet_removed(&mut self, sent: &SentPacket) {
+        if sent.cc_in_flight() {
+            debug_assert!(self.in_flight_outstanding > 0);
+            self.in_flight_outstanding -= 1;
         }
     }
 
+    pub fn remove_packet(&mut self, pn: u64) -> Option<SentPacket> {
+        let sent = self.sent_packets.remove(pn)?;
+        self.packet_removed(&sent);
+        Some(sent)
+    }
+
     // Remove all the acked packets. Returns them in ascending order -- largest
     // (i.e. highest PN) acked packet is last.
+    // This only looks at packets that are tracked, so the cost doesn't depend on
+    // how many packet numbers the ranges cover.
     fn remove_acked(&mut self, acked_ranges: Vec<(u64, u64)>) -> (Vec<SentPacket>, bool) {
-        let mut acked_packets = BTreeMap::new();
+        let mut acked_packets = Vec::new();
         let mut eliciting = false;
-        for (end, start) in acked_ranges {
+        // The ranges are in descending order, so go backwards.
+        for (end, start) in acked_ranges.into_iter().rev() {
             // ^^ Notabug: see Frame::decode_ack_frame()
-            for pn in start..=end {
-                if let Some(sent) = self.remove_packet(pn) {
-                    qdebug!("acked={}", pn);
-                    eliciting |= sent.ack_eliciting();
-                    acked_packets.insert(pn, sent);
-                }
+            let first = acked_packets.len();
+            self.sent_packets
+                .take_range(start..=end, &mut acked_packets);
+            for sent in &acked_packets[first..] {
+                qdebug!("acked={}", sent.pn);
+                eliciting |= sent.ack_eliciting();
+                self.packet_removed(sent);
             }
         }
-        (
-            acked_packets.into_iter().map(|(_k, v)| v).collect(),
-            eliciting,
-        )
+        (acked_packets, eliciting)
     }
 
     /// Remove all tracked packets from the space.
@@ -280,9 +355,7 @@ impl LossRecoverySpace {
     /// and when keys are dropped.
     fn remove_ignored(&mut self) -> impl Iterator<Item = SentPacket> {
         self.in_flight_outstanding = 0;
-        std::mem::take(&mut self.sent_packets)
-            .into_iter()
-            .map(|(_, v)| v)
+        self.sent_packets.take()
     }
 
     pub fn detect_lost_packets(
@@ -304,18 +377,16 @@ impl LossRecoverySpace {
 
         let largest_acked = self.largest_acked;
 
-        // Lost for retrans/CC purposes
-        let mut lost_pns = SmallVec::<[_; 8]>::new();
-
         // Lost for we-can-actually-forget-about-it purposes
         let mut really_lost_pns = SmallVec::<[_; 8]>::new();
 
-        for (pn, packet) in self
+        for packet in self
             .sent_packets
             .iter_mut()
-            // BTreeMap iterates in order of ascending PN
-            .take_while(|(&k, _)| Some(k) < largest_acked)
+            // Packets are in order of ascending PN
+            .take_while(|p| Some(p.pn) < largest_acked)
         {
+            let pn = packet.pn;
             if packet.time_sent <= lost_deadline {
                 qdebug!(
                     "lost={}, time sent {:?} is before lost_deadline {:?}",
@@ -323,7 +394,7 @@ impl LossRecoverySpace {
                     packet.time_sent,
                     lost_deadline
                 );
-            } else if largest_acked >= Some(*pn + PACKET_THRESHOLD) {
+            } else if largest_acked >= Some(pn + PACKET_THRESHOLD) {
                 qdebug!(
                     "lost={}, is >= {} from largest acked {:?}",
                     pn,
@@ -337,17 +408,16 @@ impl LossRecoverySpace {
             };
 
             if packet.declare_lost(now) {
-                lost_pns.push(*pn);
+                // Lost for retrans/CC purposes
+                lost_packets.push(packet.clone());
             } else if packet.expired(now, loss_delay * 2) {
-                really_lost_pns.push(*pn);
+                really_lost_pns.push(pn);
             }
         }
 
         for pn in really_lost_pns {
             self.remove_packet(pn).expect("lost packet missing");
         }
-
-        lost_packets.extend(lost_pns.iter().map(|pn| self.sent_packets[pn].clone()));
     }
 }
 
@@ -448,6 +518,20 @@ impl PtoState {
     }
 }
 
+/// A snapshot of the values that are reported in qlog `metrics_updated` events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RecoveryMetrics {
+    pub min_rtt: Option<Duration>,
+    pub smoothed_rtt: Option<Duration>,
+    pub latest_rtt: Duration,
+    pub rttvar: Duration,
+    pub max_ack_delay: Duration,
+    pub pto_count: usize,
+    pub cwnd: usize,
+    pub bytes_in_flight: usize,
+    pub ssthresh: Option<usize>,
+}
+
 #[derive(Debug)]
 pub(crate) struct LossRecovery {
     pto_state: Option<PtoState>,
@@ -455,6 +539,14 @@ pub(crate) struct LossRecovery {
     cc: CongestionControl,
 
     spaces: LossRecoverySpaces,
+    /// The loss recovery timer, as last reported by `timeout_changed`.
+    reported_timeout: Option<Instant>,
+    /// A server that can't send because of the anti-amplification limit
+    /// doesn't arm the PTO timer.
+    amplification_blocked: bool,
+    /// A client arms the PTO timer during the handshake, even if nothing is in flight,
+    /// so that a server that can't send is given more credit.
+    handshake_probes: bool,
 }
 
 impl LossRecovery {
@@ -464,11 +556,15 @@ impl LossRecovery {
                 min_rtt: Duration::from_secs(u64::max_value()),
                 max_ack_delay: MAX_ACK_DELAY,
                 latest_rtt: INITIAL_RTT,
+                ack_delay_exponent: DEFAULT_ACK_DELAY_EXPONENT,
                 ..RttVals::default()
             },
             pto_state: None,
             cc: CongestionControl::default(),
             spaces: LossRecoverySpaces::new(),
+            reported_timeout: None,
+            amplification_blocked: false,
+            handshake_probes: false,
         }
     }
 
@@ -486,6 +582,49 @@ impl LossRecovery {
         self.rtt_vals.rtt()
     }
 
+    /// Replace the congestion controller.  This has to happen before any packets are sent.
+    pub fn set_congestion_control(&mut self, algorithm: CongestionControlAlgorithm) {
+        debug_assert_eq!(self.cc.bytes_in_flight(), 0);
+        self.cc = CongestionControl::new(algorithm);
+    }
+
+    /// Suspend the PTO timer while the anti-amplification limit stops the server from
+    /// sending.  The timer is armed again once the client sends more.
+    pub fn set_amplification_blocked(&mut self, blocked: bool) {
+        self.amplification_blocked = blocked;
+    }
+
+    /// Keep the PTO timer for the Initial and Handshake spaces armed, even when
+    /// nothing is in flight.  A client needs this until it knows that the server
+    /// has validated its address.
+    pub fn set_handshake_probes(&mut self, probes: bool) {
+        self.handshake_probes = probes;
+    }
+
+    /// Use the longest ACK delay that the peer says it uses.
+    pub fn set_peer_max_ack_delay(&mut self, value: Duration) {
+        self.rtt_vals.max_ack_delay = value;
+    }
+
+    /// Use the exponent that the peer encodes the ACK delay with.
+    pub fn set_peer_ack_delay_exponent(&mut self, exponent: u64) {
+        self.rtt_vals.ack_delay_exponent = exponent;
+    }
+
+    /// Turn the ACK Delay field of an ACK frame into a duration.
+    pub fn ack_delay(&self, pn_space: PNSpace, encoded: u64) -> Duration {
+        let exponent = if pn_space == PNSpace::ApplicationData {
+            self.rtt_vals.ack_delay_exponent
+        } else {
+            DEFAULT_ACK_DELAY_EXPONENT
+        };
+        Duration::from_micros(
+            encoded
+                .checked_mul(1 << exponent)
+                .unwrap_or_else(u64::max_value),
+        )
+    }
+
     pub fn set_initial_rtt(&mut self, value: Duration) {
         debug_assert!(self.rtt_vals.smoothed_rtt.is_none());
         self.rtt_vals.latest_rtt = value
@@ -495,6 +634,49 @@ impl LossRecovery {
         self.cc.cwnd_avail()
     }
 
+    pub fn delivery_rate(&self) -> &DeliveryRate {
+        self.cc.delivery_rate()
+    }
+
+    /// Tell the congestion controller that the peer saw ECN-CE marks.
+    pub fn on_ecn_ce(&mut self, now: Instant, sent_time: Instant) {
+        self.cc.on_ecn_ce(now, sent_time);
+    }
+
+    /// Tell the congestion controller that the application had nothing to send.
+    pub fn on_app_limited(&mut self) {
+        self.cc.on_app_limited();
+    }
+
+    /// Gather the current RTT and congestion control values for logging.
+    pub fn metrics(&self) -> RecoveryMetrics {
+        let ssthresh = self.cc.ssthresh();
+        RecoveryMetrics {
+            // `min_rtt` has no meaningful value until there is an RTT sample.
+            min_rtt: self.rtt_vals.smoothed_rtt.map(|_| self.rtt_vals.min_rtt),
+            smoothed_rtt: self.rtt_vals.smoothed_rtt,
+            latest_rtt: self.rtt_vals.latest_rtt,
+            rttvar: self.rtt_vals.rttvar,
+            max_ack_delay: self.rtt_vals.max_ack_delay,
+            pto_count: self.pto_state.as_ref().map_or(0, |p| p.count),
+            cwnd: self.cc.cwnd(),
+            bytes_in_flight: self.cc.bytes_in_flight(),
+            ssthresh: if ssthresh == usize::max_value() {
+                None
+            } else {
+                Some(ssthresh)
+            },
+        }
+    }
+
+    /// If the congestion controller has changed state since the last call,
+    /// return the old (if previously reported) and new states.
+    pub fn congestion_state_change(
+        &mut self,
+    ) -> Option<(Option<CongestionState>, CongestionState)> {
+        self.cc.take_state_change()
+    }
+
     pub fn largest_acknowledged_pn(&self, pn_space: PNSpace) -> Option<u64> {
         self.spaces.get(pn_space).and_then(|sp| sp.largest_acked)
     }
@@ -571,7 +753,7 @@ impl LossRecovery {
                 self.rtt_vals.update_rtt(latest_rtt, ack_delay);
             }
         }
-        self.cc.on_packets_acked(&acked_packets);
+        self.cc.on_packets_acked(&acked_packets, now);
 
         let loss_delay = self.loss_delay();
         let mut lost_packets = Vec::new();
@@ -645,6 +827,17 @@ impl LossRecovery {
         }
     }
 
+    /// Check whether a value from `next_timeout` differs from the one that was
+    /// provided on the last call, so that only changes to the timer are logged.
+    pub fn timeout_changed(&mut self, timeout: Option<Instant>) -> bool {
+        if self.reported_timeout == timeout {
+            false
+        } else {
+            self.reported_timeout = timeout;
+            true
+        }
+    }
+
     /// Find when the earliest sent packet should be considered lost.
     fn earliest_loss_time(&self) -> Option<Instant> {
         self.spaces
@@ -663,8 +856,16 @@ impl LossRecovery {
 
     // Calculate PTO time for the given space.
     fn pto_time(&self, pn_space: PNSpace) -> Option<Instant> {
+        if self.amplification_blocked {
+            return None;
+        }
         if let Some(space) = self.spaces.get(pn_space) {
-            space.pto_base_time().map(|t| {
+            let base = if self.handshake_probes && pn_space != PNSpace::ApplicationData {
+                space.last_ack_eliciting()
+            } else {
+                space.pto_base_time()
+            };
+            base.map(|t| {
                 t + self
                     .rtt_vals
                     .pto(pn_space)
@@ -780,7 +981,9 @@ impl ::std::fmt::Display for LossRecovery {
 
 #[cfg(test)]
 mod tests {
-    use super::{LossRecovery, LossRecoverySpace, PNSpace, SentPacket};
+    use super::{LossRecovery, LossRecoverySpace, PNSpace, SentPacket, SentPackets};
+    use crate::cc::CongestionState;
+    use crate::packet::PacketType;
     use std::convert::TryInto;
     use std::rc::Rc;
     use std::time::{Duration, Instant};
@@ -860,7 +1063,15 @@ mod tests {
             lr.on_packet_sent(
                 PNSpace::ApplicationData,
                 pn,
-                SentPacket::new(pn_time(pn), true, Rc::default(), ON_SENT_SIZE, true),
+                SentPacket::new(
+                    PacketType::Short,
+                    pn,
+                    pn_time(pn),
+                    true,
+                    Rc::default(),
+                    ON_SENT_SIZE,
+                    true,
+                ),
             );
         }
     }
@@ -888,6 +1099,42 @@ mod tests {
         assert_no_sent_times(&lr);
     }
 
+    #[test]
+    fn metrics() {
+        let mut lr = LossRecovery::new();
+        let m = lr.metrics();
+        assert!(m.min_rtt.is_none());
+        assert!(m.smoothed_rtt.is_none());
+        assert!(m.ssthresh.is_none());
+        assert_eq!(m.bytes_in_flight, 0);
+
+        lr.start_pacer(now());
+        pace(&mut lr, 2);
+        assert_eq!(lr.metrics().bytes_in_flight, 2 * ON_SENT_SIZE);
+        let rtt = ms!(100);
+        ack(&mut lr, 0, rtt);
+        let m = lr.metrics();
+        assert_eq!(m.min_rtt, Some(rtt));
+        assert_eq!(m.smoothed_rtt, Some(rtt));
+        assert_eq!(m.bytes_in_flight, ON_SENT_SIZE);
+
+        // The first change is reported, but not repeated.
+        assert_eq!(
+            lr.congestion_state_change(),
+            Some((None, CongestionState::SlowStart))
+        );
+        assert!(lr.congestion_state_change().is_none());
+    }
+
+    #[test]
+    fn timeout_changed() {
+        let mut lr = LossRecovery::new();
+        assert!(!lr.timeout_changed(None));
+        assert!(lr.timeout_changed(Some(now())));
+        assert!(!lr.timeout_changed(Some(now())));
+        assert!(lr.timeout_changed(None));
+    }
+
     /// An initial RTT for using with `setup_lr`.
     const INITIAL_RTT: Duration = ms!(80);
     const INITIAL_RTTVAR: Duration = ms!(40);
@@ -919,6 +1166,30 @@ mod tests {
         assert_no_sent_times(&lr);
     }
 
+    #[test]
+    fn ack_delay_exponent() {
+        let mut lr = LossRecovery::new();
+        for space in PNSpace::iter() {
+            assert_eq!(lr.ack_delay(*space, 3), Duration::from_micros(24));
+        }
+
+        lr.set_peer_ack_delay_exponent(20);
+        assert_eq!(lr.ack_delay(PNSpace::Initial, 3), Duration::from_micros(24));
+        assert_eq!(
+            lr.ack_delay(PNSpace::Handshake, 3),
+            Duration::from_micros(24)
+        );
+        assert_eq!(
+            lr.ack_delay(PNSpace::ApplicationData, 3),
+            Duration::from_micros(3 << 20)
+        );
+        // Values that are too large are capped.
+        assert_eq!(
+            lr.ack_delay(PNSpace::ApplicationData, 1 << 61),
+            Duration::from_micros(u64::max_value())
+        );
+    }
+
     // The ack delay is ignored when it would cause a sample to be less than min_rtt.
     #[test]
     fn ack_delay_ignored() {
@@ -981,12 +1252,22 @@ mod tests {
         lr.on_packet_sent(
             PNSpace::ApplicationData,
             0,
-            SentPacket::new(pn_time(0), true, Rc::default(), ON_SENT_SIZE, true),
+            SentPacket::new(
+                PacketType::Short,
+                0,
+                pn_time(0),
+                true,
+                Rc::default(),
+                ON_SENT_SIZE,
+                true,
+            ),
         );
         lr.on_packet_sent(
             PNSpace::ApplicationData,
             1,
             SentPacket::new(
+                PacketType::Short,
+                1,
                 pn_time(0) + INITIAL_RTT / 4,
                 true,
                 Rc::default(),
@@ -1084,6 +1365,97 @@ mod tests {
         );
     }
 
+    fn sent(pn: u64) -> SentPacket {
+        SentPacket::new(
+            PacketType::Short,
+            pn,
+            pn_time(pn),
+            true,
+            Rc::default(),
+            ON_SENT_SIZE,
+            true,
+        )
+    }
+
+    fn pns(packets: &[SentPacket]) -> Vec<u64> {
+        packets.iter().map(|p| p.pn).collect()
+    }
+
+    #[test]
+    fn sent_packets_order() {
+        let mut sp = SentPackets::default();
+        for pn in &[1, 5, 3, 7, 0] {
+            sp.insert(sent(*pn));
+        }
+        assert_eq!(
+            sp.iter_mut().map(|p| p.pn).collect::<Vec<_>>(),
+            vec![0, 1, 3, 5, 7]
+        );
+        assert_eq!(sp.remove(3).map(|p| p.pn), Some(3));
+        assert!(sp.remove(3).is_none());
+        assert!(sp.remove(4).is_none());
+        assert!(sp.remove(8).is_none());
+        assert_eq!(pns(&sp.take().collect::<Vec<_>>()), vec![0, 1, 5, 7]);
+        assert!(sp.remove(0).is_none());
+    }
+
+    #[test]
+    fn sent_packets_take_range() {
+        let mut sp = SentPackets::default();
+        for pn in 0..10 {
+            sp.insert(sent(pn * 2));
+        }
+        let mut taken = Vec::new();
+        sp.take_range(3..=8, &mut taken);
+        assert_eq!(pns(&taken), vec![4, 6, 8]);
+        // Nothing in this range is left.
+        sp.take_range(3..=8, &mut taken);
+        assert_eq!(pns(&taken), vec![4, 6, 8]);
+        sp.take_range(17..=100, &mut taken);
+        assert_eq!(pns(&taken), vec![4, 6, 8, 18]);
+        sp.take_range(0..=0, &mut taken);
+        assert_eq!(pns(&taken), vec![4, 6, 8, 18, 0]);
+        assert_eq!(pns(&sp.take().collect::<Vec<_>>()), vec![2, 10, 12, 14, 16]);
+    }
+
+    #[test]
+    fn ack_huge_range() {
+        let mut lr = LossRecovery::new();
+        lr.start_pacer(now());
+        pace(&mut lr, 3);
+        // This covers far more packet numbers than could be checked one at a time.
+        let (acked, lost) = lr.on_ack_received(
+            PNSpace::ApplicationData,
+            (1 << 62) - 1,
+            vec![((1 << 62) - 1, 0)],
+            ACK_DELAY,
+            pn_time(2) + ms!(100),
+        );
+        assert_eq!(
+            acked.iter().map(|p| p.pn).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert!(lost.is_empty());
+    }
+
+    #[test]
+    fn ack_ranges_ascending() {
+        let mut lr = LossRecovery::new();
+        lr.start_pacer(now());
+        pace(&mut lr, 6);
+        let (acked, _) = lr.on_ack_received(
+            PNSpace::ApplicationData,
+            5,
+            vec![(5, 4), (2, 1)],
+            ACK_DELAY,
+            pn_time(5) + ms!(100),
+        );
+        assert_eq!(
+            acked.iter().map(|p| p.pn).collect::<Vec<_>>(),
+            vec![1, 2, 4, 5]
+        );
+    }
+
     #[test]
     fn drop_spaces() {
         let mut lr = LossRecovery::new();
@@ -1091,23 +1463,52 @@ mod tests {
         lr.on_packet_sent(
             PNSpace::Initial,
             0,
-            SentPacket::new(pn_time(0), true, Rc::default(), ON_SENT_SIZE, true),
+            SentPacket::new(
+                PacketType::Initial,
+                0,
+                pn_time(0),
+                true,
+                Rc::default(),
+                ON_SENT_SIZE,
+                true,
+            ),
         );
         lr.on_packet_sent(
             PNSpace::Handshake,
             0,
-            SentPacket::new(pn_time(1), true, Rc::default(), ON_SENT_SIZE, true),
+            SentPacket::new(
+                PacketType::Handshake,
+                0,
+                pn_time(1),
+                true,
+                Rc::default(),
+                ON_SENT_SIZE,
+                true,
+            ),
         );
         lr.on_packet_sent(
             PNSpace::ApplicationData,
             0,
-            SentPacket::new(pn_time(2), true, Rc::default(), ON_SENT_SIZE, true),
+            SentPacket::new(
+                PacketType::Short,
+                0,
+                pn_time(2),
+                true,
+                Rc::default(),
+                ON_SENT_SIZE,
+                true,
+            ),
         );
 
         // Now put all spaces on the LR timer so we can see them.
-        let pkt = SentPacket::new(pn_time(3), true, Rc::default(), ON_SENT_SIZE, true);
         for sp in PNSpace::iter() {
-            lr.on_packet_sent(*sp, 1, pkt.clone());
+            let pt = match sp {
+                PNSpace::Initial => PacketType::Initial,
+                PNSpace::Handshake => PacketType::Handshake,
+                PNSpace::ApplicationData => PacketType::Short,
+            };
+            let pkt = SentPacket::new(pt, 1, pn_time(3), true, Rc::default(), ON_SENT_SIZE, true);
+            lr.on_packet_sent(*sp, 1, pkt);
             lr.on_ack_received(*sp, 1, vec![(1, 1)], Duration::from_secs(0), pn_time(3));
             let mut lost = Vec::new();
             lr.spaces
@@ -1128,7 +1529,15 @@ mod tests {
         lr.on_packet_sent(
             PNSpace::Initial,
             0,
-            SentPacket::new(pn_time(3), true, Rc::default(), ON_SENT_SIZE, true),
+            SentPacket::new(
+                PacketType::Initial,
+                0,
+                pn_time(3),
+                true,
+                Rc::default(),
+                ON_SENT_SIZE,
+                true,
+            ),
         );
         assert_sent_times(&lr, None, None, Some(pn_time(2)));
     }
@@ -14,6 +14,7 @@ use std::convert::TryFrom;
 use std::mem;
 use std::ops::Bound::{Included, Unbounded};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use smallvec::SmallVec;
 
@@ -21,7 +22,7 @@ use crate::events::ConnectionEvents;
 use crate::flow_mgr::FlowMgr;
 use crate::stream_id::StreamId;
 use crate::{AppError, Error, Res};
-use neqo_common::{matches, qtrace};
+use neqo_common::{matches, qdebug, qtrace};
 
 pub const RX_STREAM_DATA_WINDOW: u64 = 0xFFFF; // 64 KiB
 
@@ -205,7 +206,7 @@ impl RxStreamOrderer {
     }
 
     /// Bytes read by the application.
-    fn retired(&self) -> u64 {
+    pub fn retired(&self) -> u64 {
         self.retired
     }
 
@@ -341,6 +342,54 @@ impl RecvStreamState {
     }
 }
 
+/// The receive window grows if the application reads data faster than this
+/// many round trips for each window update.
+const WINDOW_UPDATE_RTTS: u32 = 2;
+
+/// The information needed to grow the receive window of a stream.
+/// If the window is extended twice within `WINDOW_UPDATE_RTTS` round trips,
+/// the sender is probably limited by the window, so it is doubled.
+#[derive(Debug)]
+struct WindowTuning {
+    /// The largest the window can get.
+    max_window: u64,
+    /// When data last arrived, and the round trip time then.
+    last_rx: Option<(Instant, Duration)>,
+    /// When the window was last extended.
+    last_update: Option<Instant>,
+}
+
+impl WindowTuning {
+    fn new(max_window: u64) -> Self {
+        Self {
+            max_window,
+            last_rx: None,
+            last_update: None,
+        }
+    }
+
+    /// Called when the window is about to be extended.  This returns
+    /// the new size of the window.
+    fn on_update(&mut self, window: u64) -> u64 {
+        let (now, rtt) = if let Some(rx) = self.last_rx {
+            rx
+        } else {
+            return window;
+        };
+        let fast = self.last_update.map_or(false, |t| {
+            now.saturating_duration_since(t) < rtt * WINDOW_UPDATE_RTTS
+        });
+        self.last_update = Some(now);
+        if fast && window < self.max_window {
+            let grown = min(window.saturating_mul(2), self.max_window);
+            qdebug!("Stream RX window grows from {} to {}", window, grown);
+            grown
+        } else {
+            window
+        }
+    }
+}
+
 /// Implement a QUIC receive stream.
 #[derive(Debug)]
 pub struct RecvStream {
@@ -348,6 +397,7 @@ pub struct RecvStream {
     state: RecvStreamState,
     flow_mgr: Rc<RefCell<FlowMgr>>,
     conn_events: ConnectionEvents,
+    tuning: WindowTuning,
 }
 
 impl RecvStream {
@@ -362,9 +412,22 @@ impl RecvStream {
             state: RecvStreamState::new(max_stream_data),
             flow_mgr,
             conn_events,
+            tuning: WindowTuning::new(max_stream_data),
         }
     }
 
+    /// Let the receive window grow up to `max_window` if the application
+    /// reads quickly enough.  By default, the window doesn't grow.
+    pub fn set_max_window(&mut self, max_window: u64) {
+        self.tuning.max_window = max_window;
+    }
+
+    /// Note that data arrived at `now`, when the round trip time was `rtt`.
+    /// This is used to work out how quickly the window is used.
+    pub fn record_rx(&mut self, now: Instant, rtt: Duration) {
+        self.tuning.last_rx = Some((now, rtt));
+    }
+
     fn set_state(&mut self, new_state: RecvStreamState) {
         debug_assert_ne!(
             mem::discriminant(&self.state),
@@ -484,10 +547,12 @@ impl RecvStream {
             // highest seen offset somehow? RTT maybe?
             let maybe_new_max = recv_buf.retired() + *max_bytes;
             if maybe_new_max > (*max_bytes / 2) + *max_stream_data {
-                *max_stream_data = maybe_new_max;
+                *max_bytes = self.tuning.on_update(*max_bytes);
+                let new_max = recv_buf.retired() + *max_bytes;
+                *max_stream_data = new_max;
                 self.flow_mgr
                     .borrow_mut()
-                    .max_stream_data(self.stream_id, maybe_new_max)
+                    .max_stream_data(self.stream_id, new_max)
             }
         }
     }
@@ -496,6 +561,28 @@ impl RecvStream {
         self.state.max_stream_data()
     }
 
+    /// Let the peer send up to `max_stream_data` bytes on the stream.
+    /// This can only increase the limit, and only while the stream is
+    /// receiving data.
+    pub fn set_max_stream_data(&mut self, max_stream_data: u64) -> Res<()> {
+        if let RecvStreamState::Recv {
+            max_stream_data: current,
+            ..
+        } = &mut self.state
+        {
+            if max_stream_data <= *current {
+                return Err(Error::InvalidInput);
+            }
+            *current = max_stream_data;
+            self.flow_mgr
+                .borrow_mut()
+                .max_stream_data(self.stream_id, max_stream_data);
+            Ok(())
+        } else {
+            Err(Error::InvalidInput)
+        }
+    }
+
     pub fn is_terminal(&self) -> bool {
         matches!(
             self.state,
@@ -840,4 +927,54 @@ mod tests {
         flow_mgr.borrow_mut().max_stream_data(67.into(), 100);
         assert!(matches!(s.flow_mgr.borrow_mut().next().unwrap(), Frame::MaxStreamData{..}));
     }
+
+    const WINDOW: u64 = 1000;
+    const RTT: Duration = Duration::from_millis(100);
+
+    /// Receive and read a window of data at `now`, returning the new limit.
+    fn consume_window(s: &mut RecvStream, now: Instant) -> u64 {
+        let offset = s.state.recv_buf().unwrap().retired();
+        let window = s.max_stream_data().unwrap() - offset;
+        s.inbound_stream_frame(false, offset, vec![0; usize::try_from(window).unwrap()])
+            .unwrap();
+        s.record_rx(now, RTT);
+        let mut buf = vec![0; usize::try_from(window).unwrap()];
+        s.read(&mut buf).unwrap();
+        s.max_stream_data().unwrap()
+    }
+
+    fn tuned_stream(max_window: u64) -> RecvStream {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        let mut s = RecvStream::new(67.into(), WINDOW, flow_mgr, ConnectionEvents::default());
+        s.set_max_window(max_window);
+        s
+    }
+
+    #[test]
+    fn window_grows() {
+        let mut s = tuned_stream(4 * WINDOW);
+        let now = test_fixture::now();
+        assert_eq!(consume_window(&mut s, now), 2 * WINDOW);
+        // The window was used again within two round trips, so it doubles.
+        assert_eq!(consume_window(&mut s, now + RTT), 4 * WINDOW);
+        assert_eq!(consume_window(&mut s, now + RTT * 2), 8 * WINDOW);
+        // That is the limit.
+        assert_eq!(consume_window(&mut s, now + RTT * 3), 12 * WINDOW);
+    }
+
+    #[test]
+    fn window_slow_reader() {
+        let mut s = tuned_stream(4 * WINDOW);
+        let now = test_fixture::now();
+        assert_eq!(consume_window(&mut s, now), 2 * WINDOW);
+        assert_eq!(consume_window(&mut s, now + RTT * 2), 3 * WINDOW);
+    }
+
+    #[test]
+    fn window_not_tuned() {
+        let mut s = tuned_stream(WINDOW);
+        let now = test_fixture::now();
+        assert_eq!(consume_window(&mut s, now), 2 * WINDOW);
+        assert_eq!(consume_window(&mut s, now), 3 * WINDOW);
+    }
 }
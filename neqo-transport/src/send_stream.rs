@@ -12,6 +12,7 @@ use std::collections::{hash_map::IterMut, BTreeMap, HashMap, VecDeque};
 use std::convert::{TryFrom, TryInto};
 use std::mem;
 use std::rc::Rc;
+use std::time::Instant;
 
 use smallvec::SmallVec;
 
@@ -272,33 +273,69 @@ impl RangeTracker {
 }
 
 /// Buffer to contain queued bytes and track their state.
+///
+/// The bytes are held in chunks.  Small writes are copied into the last chunk;
+/// an owned buffer is kept as its own chunk until it is acknowledged, so that
+/// large writes don't need to be copied.
 #[derive(Debug, Default, PartialEq)]
 pub struct TxBuffer {
-    retired: u64,           // contig acked bytes, no longer in buffer
-    send_buf: VecDeque<u8>, // buffer of not-acked bytes
-    ranges: RangeTracker,   // ranges in buffer that have been sent or acked
+    retired: u64,              // contig acked bytes, no longer in buffer
+    chunks: VecDeque<Vec<u8>>, // buffer of not-acked bytes
+    chunk_retired: usize,      // acked bytes at the start of the first chunk
+    buffered: usize,           // bytes in the buffer, less `chunk_retired`
+    copy_to_last: bool,        // whether the last chunk can take copies
+    ranges: RangeTracker,      // ranges in buffer that have been sent or acked
 }
 
 impl TxBuffer {
     const BUFFER_SIZE: usize = 0xFFFF; // 64 KiB
+    /// The smallest chunk that is made for copies.
+    const COPY_CHUNK_SIZE: usize = 0x1000;
+    /// Owned buffers that are smaller than this are copied.
+    const MIN_OWNED_SIZE: usize = 0x400;
 
     pub fn new() -> Self {
-        Self {
-            send_buf: VecDeque::with_capacity(Self::BUFFER_SIZE),
-            ..Self::default()
-        }
+        Self::default()
     }
 
     /// Attempt to add some or all of the passed-in buffer to the TxBuffer.
     pub fn send(&mut self, buf: &[u8]) -> usize {
-        let can_buffer = min(Self::BUFFER_SIZE - self.buffered(), buf.len());
+        let can_buffer = min(self.avail(), buf.len());
         if can_buffer > 0 {
-            self.send_buf.extend(&buf[..can_buffer]);
-            assert!(self.send_buf.len() <= Self::BUFFER_SIZE);
+            let buf = &buf[..can_buffer];
+            let fits = self.copy_to_last
+                && self
+                    .chunks
+                    .back()
+                    .map_or(false, |last| last.capacity() - last.len() >= buf.len());
+            if fits {
+                // Don't let the chunk grow, as that would copy it again.
+                self.chunks.back_mut().unwrap().extend_from_slice(buf);
+            } else {
+                let mut chunk = Vec::with_capacity(max(Self::COPY_CHUNK_SIZE, buf.len()));
+                chunk.extend_from_slice(buf);
+                self.chunks.push_back(chunk);
+                self.copy_to_last = true;
+            }
+            self.buffered += can_buffer;
+            assert!(self.buffered <= Self::BUFFER_SIZE);
         }
         can_buffer
     }
 
+    /// Add all of `buf`, which has to fit.  Unless it is small, the
+    /// buffer is kept as it is, rather than being copied.
+    pub fn send_owned(&mut self, buf: Vec<u8>) {
+        debug_assert!(buf.len() <= self.avail());
+        if buf.len() < Self::MIN_OWNED_SIZE {
+            self.send(&buf);
+        } else {
+            self.buffered += buf.len();
+            self.chunks.push_back(buf);
+            self.copy_to_last = false;
+        }
+    }
+
     pub fn next_bytes(&self) -> Option<(u64, &[u8])> {
         let (start, maybe_len) = self.ranges.first_unmarked_range();
 
@@ -308,18 +345,20 @@ impl TxBuffer {
 
         // Convert from ranges-relative-to-zero to
         // ranges-relative-to-buffer-start
-        let buff_off = usize::try_from(start - self.retired).unwrap();
-
-        // Deque returns two slices. Create a subslice from whichever
-        // one contains the first unmarked data.
-        let slc = if buff_off < self.send_buf.as_slices().0.len() {
-            &self.send_buf.as_slices().0[buff_off..]
-        } else {
-            &self.send_buf.as_slices().1[buff_off - self.send_buf.as_slices().0.len()..]
+        let mut buff_off = usize::try_from(start - self.retired).unwrap() + self.chunk_retired;
+
+        // Create a subslice from whichever chunk contains the first unmarked data.
+        let mut chunks = self.chunks.iter();
+        let slc = loop {
+            let chunk = chunks.next().unwrap();
+            if buff_off < chunk.len() {
+                break &chunk[buff_off..];
+            }
+            buff_off -= chunk.len();
         };
 
         let len = if let Some(range_len) = maybe_len {
-            // Truncate if range crosses deque slices
+            // Truncate if range crosses chunks
             min(usize::try_from(range_len).unwrap(), slc.len())
         } else {
             slc.len()
@@ -341,14 +380,23 @@ impl TxBuffer {
         // We can drop contig acked range from the buffer
         let new_retirable = self.ranges.acked_from_zero() - self.retired;
         debug_assert!(new_retirable <= self.buffered() as u64);
-        let keep_len =
-            self.buffered() - usize::try_from(new_retirable).expect("should fit in usize");
+        let new_retirable = usize::try_from(new_retirable).expect("should fit in usize");
 
-        // Truncate front
-        self.send_buf.rotate_left(self.buffered() - keep_len);
-        self.send_buf.truncate(keep_len);
+        // Drop the chunks that are all acked.
+        self.chunk_retired += new_retirable;
+        while let Some(first) = self.chunks.front() {
+            if self.chunk_retired < first.len() {
+                break;
+            }
+            self.chunk_retired -= first.len();
+            self.chunks.pop_front();
+        }
+        if self.chunks.is_empty() {
+            self.copy_to_last = false;
+        }
 
-        self.retired += new_retirable;
+        self.buffered -= new_retirable;
+        self.retired += new_retirable as u64;
     }
 
     pub fn mark_as_lost(&mut self, offset: u64, len: usize) {
@@ -360,7 +408,7 @@ impl TxBuffer {
     }
 
     fn buffered(&self) -> usize {
-        self.send_buf.len()
+        self.buffered
     }
 
     fn avail(&self) -> usize {
@@ -370,6 +418,12 @@ impl TxBuffer {
     pub fn highest_sent(&self) -> u64 {
         self.ranges.highest_offset()
     }
+
+    /// Bytes that are buffered, but have never been sent.
+    fn unsent(&self) -> usize {
+        let sent = max(self.highest_sent(), self.retired);
+        usize::try_from(self.data_limit() - sent).unwrap()
+    }
 }
 
 /// QUIC sending stream states, based on -transport 3.1.
@@ -449,6 +503,8 @@ pub struct SendStream {
     state: SendStreamState,
     flow_mgr: Rc<RefCell<FlowMgr>>,
     conn_events: ConnectionEvents,
+    /// When the stream is reset if it hasn't sent everything, and the error to use.
+    expiry: Option<(Instant, AppError)>,
 }
 
 impl SendStream {
@@ -464,6 +520,7 @@ impl SendStream {
             state: SendStreamState::Ready,
             flow_mgr,
             conn_events,
+            expiry: None,
         };
         if ss.avail() > 0 {
             ss.conn_events.send_stream_writable(stream_id);
@@ -580,6 +637,11 @@ impl SendStream {
         .unwrap()
     }
 
+    /// Bytes that have been written to the stream but not yet sent.
+    pub fn unsent(&self) -> usize {
+        self.state.tx_buf().map_or(0, TxBuffer::unsent)
+    }
+
     pub fn max_stream_data(&self) -> u64 {
         self.max_stream_data
     }
@@ -605,6 +667,45 @@ impl SendStream {
         };
     }
 
+    /// Reset the stream with `err` unless all of its data, and the FIN, have been
+    /// sent by `deadline`.  Data that is lost after the deadline is not sent again,
+    /// as the stream is reset instead.
+    pub fn set_expiry(&mut self, deadline: Instant, err: AppError) {
+        self.expiry = Some((deadline, err));
+    }
+
+    /// When the stream expires, if it still has something to send.
+    pub fn expiry(&self) -> Option<Instant> {
+        if self.all_sent() {
+            None
+        } else {
+            self.expiry.map(|(t, _)| t)
+        }
+    }
+
+    /// Reset the stream if the deadline has passed and it still has something to send.
+    pub fn expire(&mut self, now: Instant) {
+        if let Some((deadline, err)) = self.expiry {
+            if deadline <= now && !self.all_sent() {
+                qinfo!("Stream {} expired", self.stream_id.as_u64());
+                self.expiry = None;
+                self.reset(err);
+            }
+        }
+    }
+
+    /// Whether everything on the stream has been sent at least once, or
+    /// there is nothing more to send.
+    fn all_sent(&self) -> bool {
+        matches!(
+            self.state,
+            SendStreamState::DataSent { fin_sent: true, .. }
+                | SendStreamState::DataRecvd { .. }
+                | SendStreamState::ResetSent
+                | SendStreamState::ResetRecvd
+        )
+    }
+
     pub fn is_terminal(&self) -> bool {
         matches!(self.state, SendStreamState::DataRecvd { .. } | SendStreamState::ResetRecvd)
     }
@@ -617,6 +718,30 @@ impl SendStream {
         self.send_internal(buf, true)
     }
 
+    /// Send the data in `buf` without copying it, if all of it can be sent.
+    /// Then `buf` is left empty, and is kept until the data is acknowledged.
+    /// Otherwise, as much as can be sent is copied out of `buf`, as for `send`.
+    pub fn send_owned(&mut self, buf: &mut Vec<u8>) -> Res<usize> {
+        self.start_send(buf)?;
+        if self.avail() < buf.len() {
+            let sent = self.send_internal(buf, false)?;
+            buf.drain(..sent);
+            return Ok(sent);
+        }
+
+        let sent = buf.len();
+        match &mut self.state {
+            SendStreamState::Send { send_buf } => send_buf.send_owned(mem::take(buf)),
+            _ => unreachable!(),
+        }
+
+        self.flow_mgr
+            .borrow_mut()
+            .conn_increase_credit_used(sent as u64);
+
+        Ok(sent)
+    }
+
     fn send_blocked(&mut self, len: u64) {
         if self.credit_avail() < len {
             self.flow_mgr
@@ -629,7 +754,8 @@ impl SendStream {
         }
     }
 
-    fn send_internal(&mut self, buf: &[u8], atomic: bool) -> Res<usize> {
+    /// Check that `buf` can be added to the stream, opening it if necessary.
+    fn start_send(&mut self, buf: &[u8]) -> Res<()> {
         if buf.is_empty() {
             qerror!("zero-length send on stream {}", self.stream_id.as_u64());
             return Err(Error::InvalidInput);
@@ -644,6 +770,11 @@ impl SendStream {
         if !matches!(self.state, SendStreamState::Send{..}) {
             return Err(Error::FinalSizeError);
         }
+        Ok(())
+    }
+
+    fn send_internal(&mut self, buf: &[u8], atomic: bool) -> Res<usize> {
+        self.start_send(buf)?;
 
         let buf = if buf.is_empty() || (self.avail() == 0) {
             return Ok(0);
@@ -770,6 +901,18 @@ impl SendStreams {
         self.0.retain(|_, stream| !stream.is_terminal())
     }
 
+    /// The next time that a stream expires.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.0.values().filter_map(SendStream::expiry).min()
+    }
+
+    /// Reset any streams that have expired.
+    pub fn expire(&mut self, now: Instant) {
+        for stream in self.0.values_mut() {
+            stream.expire(now);
+        }
+    }
+
     pub(crate) fn get_frame(
         &mut self,
         space: PNSpace,
@@ -840,6 +983,8 @@ mod tests {
     use neqo_common::matches;
 
     use crate::events::ConnectionEvent;
+    use std::time::Duration;
+    use test_fixture::now;
 
     #[test]
     fn test_mark_range() {
@@ -1077,6 +1222,58 @@ mod tests {
         assert_eq!(res, None);
     }
 
+    #[test]
+    fn tx_buffer_owned() {
+        let mut tx = TxBuffer::new();
+        assert_eq!(tx.send(&[1; 10]), 10);
+        let owned = vec![2; 5000];
+        let ptr = owned.as_ptr();
+        tx.send_owned(owned);
+        // Small writes are copied after the owned buffer.
+        tx.send_owned(vec![3; 10]);
+        assert_eq!(tx.buffered(), 5020);
+        assert_eq!(tx.chunks.len(), 3);
+
+        let (offset, data) = tx.next_bytes().unwrap();
+        assert_eq!((offset, data.len()), (0, 10));
+        tx.mark_as_sent(0, 10);
+        let (offset, data) = tx.next_bytes().unwrap();
+        assert_eq!((offset, data.len()), (10, 5000));
+        // The owned buffer isn't copied.
+        assert_eq!(data.as_ptr(), ptr);
+        tx.mark_as_sent(10, 5000);
+        assert!(matches!(tx.next_bytes(), Some((5010, x)) if x == [3; 10]));
+
+        // Chunks are dropped once all of their data is acknowledged.
+        tx.mark_as_acked(0, 1000);
+        assert_eq!(tx.chunks.len(), 2);
+        tx.mark_as_acked(1000, 4010);
+        assert_eq!(tx.chunks.len(), 1);
+        assert_eq!(tx.buffered(), 10);
+    }
+
+    #[test]
+    fn send_owned() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(3000);
+        let mut s = SendStream::new(4.into(), 2000, flow_mgr, ConnectionEvents::default());
+
+        // A buffer that can be sent is taken as it is.
+        let mut data = vec![1; 1500];
+        let ptr = data.as_ptr();
+        assert_eq!(s.send_owned(&mut data).unwrap(), 1500);
+        assert!(data.is_empty());
+        s.mark_as_sent(0, 10, false);
+        let (_, sent) = s.state.tx_buf().unwrap().next_bytes().unwrap();
+        assert_eq!(sent.as_ptr(), ptr.wrapping_add(10));
+
+        // Otherwise the part that can be sent is copied.
+        let mut data = vec![2; 1000];
+        assert_eq!(s.send_owned(&mut data).unwrap(), 500);
+        assert_eq!(data.len(), 500);
+        assert_eq!(s.avail(), 0);
+    }
+
     #[test]
     fn send_stream_writable_event_gen() {
         let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
@@ -1203,6 +1400,72 @@ mod tests {
         assert!(matches!(f5_token, Some(RecoveryToken::Stream(x)) if x.fin));
     }
 
+    #[test]
+    fn send_stream_expiry() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(100);
+        let conn_events = ConnectionEvents::default();
+        let deadline = now() + Duration::from_millis(10);
+
+        let mut s = SendStream::new(0.into(), 100, Rc::clone(&flow_mgr), conn_events);
+        s.send(&[0; 10]).unwrap();
+        s.set_expiry(deadline, 7);
+        let mut ss = SendStreams::default();
+        ss.insert(0.into(), s);
+        assert_eq!(ss.next_expiry(), Some(deadline));
+
+        // Some of the data is sent, but not all of it.
+        let _ = ss.get_frame(PNSpace::ApplicationData, 6).unwrap();
+        ss.expire(deadline - Duration::from_millis(1));
+        assert!(flow_mgr.borrow_mut().next().is_none());
+
+        ss.expire(deadline);
+        assert_eq!(
+            flow_mgr.borrow_mut().next(),
+            Some(Frame::ResetStream {
+                stream_id: 0.into(),
+                application_error_code: 7,
+                final_size: 4,
+            })
+        );
+        assert!(ss.get_frame(PNSpace::ApplicationData, 100).is_none());
+        assert_eq!(ss.next_expiry(), None);
+    }
+
+    #[test]
+    fn send_stream_expiry_all_sent() {
+        let flow_mgr = Rc::new(RefCell::new(FlowMgr::default()));
+        flow_mgr.borrow_mut().conn_increase_max_credit(100);
+        let conn_events = ConnectionEvents::default();
+        let deadline = now() + Duration::from_millis(10);
+
+        let mut s = SendStream::new(0.into(), 100, Rc::clone(&flow_mgr), conn_events);
+        s.send(&[0; 10]).unwrap();
+        s.close();
+        s.set_expiry(deadline, 7);
+        let mut ss = SendStreams::default();
+        ss.insert(0.into(), s);
+
+        // Once everything has been sent, there is nothing to expire.
+        let (_, token) = ss.get_frame(PNSpace::ApplicationData, 100).unwrap();
+        assert_eq!(ss.next_expiry(), None);
+        ss.expire(deadline);
+        assert!(flow_mgr.borrow_mut().next().is_none());
+
+        // A loss after the deadline resets the stream rather than resending.
+        if let Some(RecoveryToken::Stream(token)) = token {
+            ss.lost(&token);
+        } else {
+            panic!("not a stream token");
+        }
+        assert_eq!(ss.next_expiry(), Some(deadline));
+        ss.expire(deadline);
+        assert!(matches!(
+            flow_mgr.borrow_mut().next(),
+            Some(Frame::ResetStream { final_size: 10, .. })
+        ));
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     // Verify lost frames handle fin properly with zero length fin
@@ -7,30 +7,37 @@
 // This file implements a server that can handle multiple connections.
 
 use neqo_common::{
-    self as common, hex, matches, qdebug, qerror, qinfo, qlog::NeqoQlog, qtrace, qwarn,
-    timer::Timer, Datagram, Decoder, Encoder, Role,
+    hex, matches, qdebug, qerror, qinfo,
+    qlog::{NeqoQlog, QlogCategory},
+    qtrace, qwarn,
+    timer::Timer,
+    Datagram, Decoder, Encoder, Role,
 };
 use neqo_crypto::{
+    aead::Aead,
     constants::{TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3},
+    ech, hkdf, random,
     selfencrypt::SelfEncrypt,
-    AntiReplay,
+    AntiReplay, PrivateKey, PublicKey, SymKey,
 };
 
+use crate::cc::CongestionControlAlgorithm;
 use crate::cid::{ConnectionId, ConnectionIdDecoder, ConnectionIdManager, ConnectionIdRef};
 use crate::connection::{Connection, Output, State};
 use crate::packet::{PacketBuilder, PacketType, PublicPacket};
-use crate::{QuicVersion, Res};
+use crate::tparams::{self, PreferredAddress, TransportParameter};
+use crate::{AppError, Error, QuicVersion, Res};
 
 use std::cell::RefCell;
+use std::cmp::min;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
-use std::fs::OpenOptions;
 use std::mem;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
 use std::rc::{Rc, Weak};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub enum InitialResult {
     Accept,
@@ -40,9 +47,25 @@ pub enum InitialResult {
 
 /// MIN_INITIAL_PACKET_SIZE is the smallest packet that can be used to establish
 /// a new connection across all QUIC versions this server supports.
-const MIN_INITIAL_PACKET_SIZE: usize = 1200;
+pub(crate) const MIN_INITIAL_PACKET_SIZE: usize = 1200;
 const TIMER_GRANULARITY: Duration = Duration::from_millis(10);
 const TIMER_CAPACITY: usize = 16384;
+/// The smallest stateless reset: 5 unpredictable bytes and a token.
+const MIN_STATELESS_RESET_SIZE: usize = 21;
+/// The largest stateless reset.  This is big enough to look like a short header
+/// packet with a connection ID of up to 20 bytes.
+const MAX_STATELESS_RESET_SIZE: usize = 42;
+/// How many stateless responses an address can get at once.
+const STATELESS_RESPONSE_BURST: usize = 10;
+/// How long it takes for an address to earn another stateless response.
+const STATELESS_RESPONSE_INTERVAL: Duration = Duration::from_millis(100);
+/// The most addresses that stateless responses are counted for.
+const MAX_RESPONSE_BUCKETS: usize = 4096;
+/// The first byte of a token says whether it came from a Retry or a NEW_TOKEN frame.
+const TOKEN_IDENTIFIER_RETRY: u8 = 0x52;
+const TOKEN_IDENTIFIER_NEW_TOKEN: u8 = 0x4e;
+/// How long a token from a NEW_TOKEN frame can be used for.
+const NEW_TOKEN_EXPIRATION: Duration = Duration::from_secs(24 * 60 * 60);
 
 type StateRef = Rc<RefCell<ServerConnectionState>>;
 type CidMgr = Rc<RefCell<dyn ConnectionIdManager>>;
@@ -80,8 +103,10 @@ struct RetryToken {
     require_retry: bool,
     /// A self-encryption object used for protecting Retry tokens.
     self_encrypt: SelfEncrypt,
-    /// When this object was created.
+    /// When this object was created, and the wall clock time at that point.
+    /// Expiry times in tokens use the wall clock, so that other servers can check them.
     start_time: Instant,
+    start_wall_time: SystemTime,
 }
 
 impl RetryToken {
@@ -90,14 +115,27 @@ impl RetryToken {
             require_retry: false,
             self_encrypt: SelfEncrypt::new(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256)?,
             start_time: now,
+            start_wall_time: SystemTime::now(),
         })
     }
 
-    fn encode_peer_address(peer_address: SocketAddr) -> Vec<u8> {
+    /// Protect tokens with `key` rather than a random key.
+    fn set_key(&mut self, key: SymKey) {
+        self.self_encrypt = SelfEncrypt::with_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, key);
+    }
+
+    /// The wall clock time in milliseconds since the epoch.
+    fn wall_time_millis(&self, t: Instant) -> Option<u64> {
+        let wall = self.start_wall_time + t.checked_duration_since(self.start_time)?;
+        u64::try_from(wall.duration_since(UNIX_EPOCH).ok()?.as_millis()).ok()
+    }
+
+    fn encode_aad(identifier: u8, peer_address: SocketAddr) -> Vec<u8> {
         // Let's be "clever" by putting the peer's address in the AAD.
         // We don't need to encode these into the token as they should be
         // available when we need to check the token.
         let mut encoded_address = Encoder::default();
+        encoded_address.encode_byte(identifier);
         match peer_address.ip() {
             IpAddr::V4(a) => {
                 encoded_address.encode_byte(4);
@@ -108,10 +146,31 @@ impl RetryToken {
                 encoded_address.encode(&a.octets());
             }
         }
-        encoded_address.encode_uint(2, peer_address.port());
+        // A client uses a NEW_TOKEN token on a later connection, which is
+        // likely to come from a different port.
+        if identifier == TOKEN_IDENTIFIER_RETRY {
+            encoded_address.encode_uint(2, peer_address.port());
+        }
         encoded_address.into()
     }
 
+    fn seal(
+        &self,
+        identifier: u8,
+        peer_address: SocketAddr,
+        expiry: Instant,
+        data: &[u8],
+    ) -> Res<Vec<u8>> {
+        let mut token = Encoder::default();
+        let end_millis = self.wall_time_millis(expiry).ok_or(Error::InternalError)?;
+        token.encode_uint(8, end_millis);
+        token.encode(data);
+        let aad = Self::encode_aad(identifier, peer_address);
+        let mut sealed = vec![identifier];
+        sealed.extend_from_slice(&self.self_encrypt.seal(&aad, &token)?);
+        Ok(sealed)
+    }
+
     /// This generates a token for use with Retry.
     pub fn generate_token(
         &mut self,
@@ -122,44 +181,46 @@ impl RetryToken {
         const EXPIRATION: Duration = Duration::from_secs(5);
 
         // TODO(mt) rotate keys on a fixed schedule.
-        let mut token = Encoder::default();
-        let end = now + EXPIRATION;
-        let end_millis = u32::try_from(end.duration_since(self.start_time).as_millis())?;
-        token.encode_uint(4, end_millis);
-        token.encode(dcid);
-        let peer_addr = Self::encode_peer_address(peer_address);
-        Ok(self.self_encrypt.seal(&peer_addr, &token)?)
+        self.seal(TOKEN_IDENTIFIER_RETRY, peer_address, now + EXPIRATION, dcid)
+    }
+
+    /// This generates a token for a NEW_TOKEN frame, which lets a client
+    /// skip the Retry on a later connection from the same address.
+    pub fn generate_new_token(&self, peer_address: SocketAddr, now: Instant) -> Res<Vec<u8>> {
+        self.seal(
+            TOKEN_IDENTIFIER_NEW_TOKEN,
+            peer_address,
+            now + NEW_TOKEN_EXPIRATION,
+            &[],
+        )
     }
 
     pub fn set_retry_required(&mut self, retry: bool) {
         self.require_retry = retry;
     }
 
-    /// Decrypts `token` and returns the connection Id it contains.
-    /// Returns `None` if the date is invalid in any way (such as it being expired or garbled).
+    /// Decrypts `token` and returns the data it contains, which is the
+    /// connection ID for a Retry token.  Returns `None` if the token is
+    /// invalid in any way (such as it being expired or garbled).
     fn decrypt_token(
         &self,
         token: &[u8],
         peer_address: SocketAddr,
         now: Instant,
-    ) -> Option<ConnectionId> {
-        let peer_addr = Self::encode_peer_address(peer_address);
-        let data = if let Ok(d) = self.self_encrypt.open(&peer_addr, token) {
+    ) -> Option<Vec<u8>> {
+        let (identifier, sealed) = token.split_first()?;
+        let aad = Self::encode_aad(*identifier, peer_address);
+        let data = if let Ok(d) = self.self_encrypt.open(&aad, sealed) {
             d
         } else {
             return None;
         };
         let mut dec = Decoder::new(&data);
-        match dec.decode_uint(4) {
-            Some(d) => {
-                let end = self.start_time + Duration::from_millis(d);
-                if end < now {
-                    return None;
-                }
-            }
-            _ => return None,
+        let end_millis = dec.decode_uint(8)?;
+        if end_millis < self.wall_time_millis(now)? {
+            return None;
         }
-        Some(ConnectionId::from(dec.decode_remainder()))
+        Some(dec.decode_remainder().to_vec())
     }
 
     pub fn validate(
@@ -168,20 +229,63 @@ impl RetryToken {
         peer_address: SocketAddr,
         now: Instant,
     ) -> RetryTokenResult {
-        if token.is_empty() {
-            if self.require_retry {
-                RetryTokenResult::Validate
-            } else {
+        match token.first() {
+            Some(&TOKEN_IDENTIFIER_RETRY) => {
+                if let Some(cid) = self.decrypt_token(token, peer_address, now) {
+                    RetryTokenResult::Valid(ConnectionId::from(&cid[..]))
+                } else {
+                    RetryTokenResult::Invalid
+                }
+            }
+            // A NEW_TOKEN token shows that the client has used this address
+            // before, so it doesn't need a Retry.  A token that can't be used
+            // might be from another server, so it is ignored rather than rejected.
+            Some(&TOKEN_IDENTIFIER_NEW_TOKEN)
+                if self.decrypt_token(token, peer_address, now).is_some() =>
+            {
                 RetryTokenResult::Pass
             }
-        } else if let Some(cid) = self.decrypt_token(token, peer_address, now) {
-            RetryTokenResult::Valid(cid)
-        } else {
-            RetryTokenResult::Invalid
+            _ => {
+                if self.require_retry {
+                    RetryTokenResult::Validate
+                } else {
+                    RetryTokenResult::Pass
+                }
+            }
         }
     }
 }
 
+/// Stateless reset tokens are a MAC of the connection ID, so a server can make
+/// the token for a connection that it has no state for.
+struct StatelessResetTokens {
+    aead: Aead,
+}
+
+impl StatelessResetTokens {
+    fn new(key: &SymKey) -> Res<Self> {
+        Ok(Self {
+            aead: Aead::new(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, key, "neqo reset")?,
+        })
+    }
+
+    fn token(&self, cid: &[u8]) -> Res<[u8; 16]> {
+        let mut token = [0; 16];
+        self.aead.encrypt(0, cid, &[], &mut token)?;
+        Ok(token)
+    }
+}
+
+/// The keys and settings that new connections use for encrypted client hello (ECH).
+struct EchConfig {
+    config: u8,
+    public_name: String,
+    sk: PrivateKey,
+    pk: PublicKey,
+    /// The encoded configuration, which clients need.
+    encoded: Vec<u8>,
+}
+
 /// A `AttemptKey` is used to disambiguate connection attempts.
 /// Multiple connection attempts with the same key won't produce multiple connections.
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -211,6 +315,83 @@ impl InitialDetails {
     }
 }
 
+/// A token bucket for each source address, which limits how many stateless
+/// responses (Version Negotiation, Retry and stateless reset) are sent to it.
+/// These responses go to addresses that haven't been validated, so without a
+/// limit the server could be used to reflect packets at a spoofed address.
+struct ResponseLimiter {
+    /// How many responses an address can get at once.
+    burst: usize,
+    /// How long it takes an address to earn another response.
+    interval: Duration,
+    /// The responses left for each address, and when they were last counted.
+    /// Addresses that have all `burst` responses left aren't listed.
+    buckets: HashMap<IpAddr, (usize, Instant)>,
+}
+
+impl ResponseLimiter {
+    fn new(burst: usize, interval: Duration) -> Self {
+        Self {
+            burst,
+            interval,
+            buckets: HashMap::default(),
+        }
+    }
+
+    /// Add the responses earned since `since` to `tokens`.
+    fn refill(&self, tokens: usize, since: Instant, now: Instant) -> (usize, Instant) {
+        let elapsed = now.saturating_duration_since(since).as_nanos();
+        let earned = if self.interval == Duration::from_secs(0) {
+            self.burst
+        } else {
+            usize::try_from(elapsed / self.interval.as_nanos()).unwrap_or(usize::max_value())
+        };
+        if earned >= self.burst - tokens {
+            (self.burst, now)
+        } else {
+            // Keep the time spent earning the next response.
+            let spent = u64::try_from(self.interval.as_nanos() * earned as u128).unwrap();
+            (tokens + earned, since + Duration::from_nanos(spent))
+        }
+    }
+
+    /// Take a response for `addr`.  This returns false if there are none left.
+    fn allow(&mut self, addr: IpAddr, now: Instant) -> bool {
+        if self.burst == 0 {
+            return false;
+        }
+        if !self.buckets.contains_key(&addr) && self.buckets.len() >= MAX_RESPONSE_BUCKETS {
+            // A bucket that has filled up again is no different to having no bucket.
+            let full = mem::take(&mut self.buckets)
+                .into_iter()
+                .filter(|(_, (tokens, since))| self.refill(*tokens, *since, now).0 < self.burst)
+                .collect();
+            self.buckets = full;
+            if self.buckets.len() >= MAX_RESPONSE_BUCKETS {
+                // Make room by dropping the bucket that was counted longest ago.
+                let oldest = self
+                    .buckets
+                    .iter()
+                    .min_by_key(|(_, (_, since))| *since)
+                    .map(|(a, _)| *a)
+                    .unwrap();
+                self.buckets.remove(&oldest);
+            }
+        }
+        let (tokens, since) = match self.buckets.get(&addr) {
+            Some((tokens, since)) => self.refill(*tokens, *since, now),
+            None => (self.burst, now),
+        };
+        if tokens == 0 {
+            self.buckets.insert(addr, (tokens, since));
+            false
+        } else {
+            self.buckets.insert(addr, (tokens - 1, since));
+            true
+        }
+    }
+}
+
 pub struct Server {
     /// The names of certificates.
     certs: Vec<String>,
@@ -236,6 +417,28 @@ pub struct Server {
     retry: RetryToken,
     /// Directory to create qlog traces in
     qlog_dir: Option<PathBuf>,
+    qlog_categories: Vec<QlogCategory>,
+    /// The congestion control algorithm for new connections.
+    cc_algorithm: CongestionControlAlgorithm,
+    /// The largest DATAGRAM frame that new connections accept, 0 to disable datagrams.
+    max_datagram_frame_size: u64,
+    /// How long new connections have to complete the handshake, if limited.
+    handshake_timeout: Option<Duration>,
+    /// Whether new connections accept 0-RTT.
+    allow_0rtt: bool,
+    /// Stateless reset tokens, if the server has a key.
+    reset_tokens: Option<Rc<StatelessResetTokens>>,
+    /// The addresses that new connections advertise as preferred.
+    preferred_address: (Option<SocketAddrV4>, Option<SocketAddrV6>),
+    /// Limits the stateless responses sent to each address.
+    response_limiter: ResponseLimiter,
+    /// The ECH configuration for new connections, if ECH is enabled.
+    ech_config: Option<EchConfig>,
+    /// Whether the server is shutting down, and so refuses new connections.
+    shutting_down: bool,
+    /// When the connections that are still open will be closed for a
+    /// shutdown, and the application error code that they are closed with.
+    shutdown_close: Option<(Instant, AppError)>,
 }
 
 impl Server {
@@ -245,7 +448,8 @@ impl Server {
     /// connection IDs produced by the manager cannot be zero-length.
     /// `certs` is a list of the certificates that should be configured.
     /// `protocols` is the preference list of ALPN values.
-    /// `anti_replay` is an anti-replay context.
+    /// `anti_replay` is an anti-replay context.  Its window limits how old a
+    /// ClientHello with early data can be; 0-RTT outside the window is rejected.
     pub fn new(
         now: Instant,
         certs: &[impl AsRef<str>],
@@ -265,18 +469,169 @@ impl Server {
             timers: Timer::new(now, TIMER_GRANULARITY, TIMER_CAPACITY),
             retry: RetryToken::new(now)?,
             qlog_dir: None,
+            qlog_categories: QlogCategory::ALL.to_vec(),
+            cc_algorithm: CongestionControlAlgorithm::default(),
+            max_datagram_frame_size: 0,
+            handshake_timeout: None,
+            allow_0rtt: true,
+            reset_tokens: None,
+            preferred_address: (None, None),
+            response_limiter: ResponseLimiter::new(
+                STATELESS_RESPONSE_BURST,
+                STATELESS_RESPONSE_INTERVAL,
+            ),
+            ech_config: None,
+            shutting_down: false,
+            shutdown_close: None,
         })
     }
 
+    /// Derive the keys for Retry and NEW_TOKEN tokens and stateless reset tokens
+    /// from `key`, instead of using random keys.  Servers that share a key accept
+    /// tokens from each other, and a server that restarts with the same key can
+    /// send stateless resets for connections that it has lost.  Stateless resets
+    /// are only sent by a server that has a key.
+    pub fn set_server_key(&mut self, key: &[u8]) -> Res<()> {
+        let master = hkdf::import_key(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, key)?;
+        let prk = hkdf::extract(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, None, &master)?;
+        let retry_key =
+            hkdf::expand_label(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, &prk, &[], "retry")?;
+        self.retry.set_key(retry_key);
+        let reset_key =
+            hkdf::expand_label(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, &prk, &[], "reset")?;
+        self.reset_tokens = Some(Rc::new(StatelessResetTokens::new(&reset_key)?));
+        Ok(())
+    }
+
     /// Set or clear directory to create logs of connection events in QLOG format.
     pub fn set_qlog_dir(&mut self, dir: Option<PathBuf>) {
         self.qlog_dir = dir;
     }
 
+    /// Set the categories of events that qlog traces for new connections record.
+    /// All categories are recorded by default.
+    pub fn set_qlog_categories(&mut self, categories: &[QlogCategory]) {
+        self.qlog_categories = categories.to_vec();
+    }
+
+    /// Set the congestion control algorithm that new connections use.
+    pub fn set_congestion_control(&mut self, algorithm: CongestionControlAlgorithm) {
+        self.cc_algorithm = algorithm;
+    }
+
     pub fn set_retry_required(&mut self, require_retry: bool) {
         self.retry.set_retry_required(require_retry);
     }
 
+    /// Accept or reject 0-RTT on new connections.  A server that rejects 0-RTT
+    /// also sends session tickets that do not allow it.
+    pub fn set_allow_0rtt(&mut self, allow: bool) {
+        self.allow_0rtt = allow;
+    }
+
+    /// Enable encrypted client hello (ECH) on new connections, with a
+    /// configuration that has the identifier `config` and names `public_name`.
+    /// The keys come from `neqo_crypto::ech::generate_keys`.  Clients need the
+    /// configuration from `ech_config()`.
+    pub fn enable_ech(
+        &mut self,
+        config: u8,
+        public_name: &str,
+        sk: PrivateKey,
+        pk: PublicKey,
+    ) -> Res<()> {
+        let encoded = ech::encode_config(config, public_name, &pk)?;
+        self.ech_config = Some(EchConfig {
+            config,
+            public_name: String::from(public_name),
+            sk,
+            pk,
+            encoded,
+        });
+        Ok(())
+    }
+
+    /// The ECH configuration that clients use, which is empty until
+    /// `enable_ech` is called.
+    pub fn ech_config(&self) -> &[u8] {
+        match &self.ech_config {
+            Some(cfg) => &cfg.encoded,
+            None => &[],
+        }
+    }
+
+    /// Advertise preferred addresses on new connections, so that clients move to
+    /// them after the handshake.  The server has to receive datagrams sent to
+    /// these addresses.
+    pub fn set_preferred_address(&mut self, v4: Option<SocketAddrV4>, v6: Option<SocketAddrV6>) {
+        self.preferred_address = (v4, v6);
+    }
+
+    /// Accept datagrams on new connections, in DATAGRAM frames of up to `size` bytes.
+    pub fn set_max_datagram_frame_size(&mut self, size: u64) {
+        self.max_datagram_frame_size = size;
+    }
+
+    /// Close new connections that don't complete the handshake within `timeout`.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = Some(timeout);
+    }
+
+    /// Limit the Version Negotiation, Retry and stateless reset packets that
+    /// are sent to each IP address.  An address can get `burst` of these at
+    /// once, then one more for every `interval` that passes.  A `burst` of 0
+    /// stops them all.
+    pub fn set_stateless_response_limit(&mut self, burst: usize, interval: Duration) {
+        self.response_limiter = ResponseLimiter::new(burst, interval);
+    }
+
+    /// Start to shut down.  New connections are refused from now on.  The
+    /// connections that are still open after `grace_period` are closed with
+    /// `app_error`.  Check `shutdown_complete` to find out when they have all
+    /// closed.
+    pub fn shutdown(&mut self, now: Instant, grace_period: Duration, app_error: AppError) {
+        qinfo!([self], "Shutting down, grace period {:?}", grace_period);
+        self.shutting_down = true;
+        self.shutdown_close = Some((now + grace_period, app_error));
+    }
+
+    /// Whether `shutdown` was called and every connection has closed since.
+    #[must_use]
+    pub fn shutdown_complete(&self) -> bool {
+        self.shutting_down && self.connections.borrow().is_empty()
+    }
+
+    /// Close the connections that are left once the grace period for a shutdown ends.
+    fn close_for_shutdown(&mut self, now: Instant) {
+        let app_error = match self.shutdown_close {
+            Some((t, app_error)) if t <= now => app_error,
+            _ => return,
+        };
+        self.shutdown_close = None;
+        let open: HashSet<_> = self
+            .connections
+            .borrow()
+            .values()
+            .map(|c| ActiveConnectionRef { c: Rc::clone(c) })
+            .collect();
+        qinfo!([self], "Closing {} connections for shutdown", open.len());
+        for mut c in open {
+            if !c.borrow().state().closed() {
+                c.borrow_mut().close(now, app_error, "Server shutting down");
+            }
+            self.waiting.push_back(c.connection());
+        }
+    }
+
+    /// Whether a stateless response can be sent to the source of `dgram`.
+    fn allow_stateless_response(&mut self, dgram: &Datagram, now: Instant) -> bool {
+        let allow = self.response_limiter.allow(dgram.source().ip(), now);
+        if !allow {
+            qdebug!([self], "Too many stateless responses to {}", dgram.source());
+        }
+        allow
+    }
+
     fn remove_timer(&mut self, c: &StateRef) {
         let last = c.borrow().last_timer;
         self.timers.remove(last, |t| Rc::ptr_eq(t, c));
@@ -351,6 +706,9 @@ impl Server {
                 self.connection_attempt(initial, dgram, Some(orig_dcid), now)
             }
             RetryTokenResult::Validate => {
+                if self.shutting_down || !self.allow_stateless_response(&dgram, now) {
+                    return None;
+                }
                 qinfo!([self], "Send retry for {:?}", initial.dst_cid);
 
                 let res = self
@@ -400,58 +758,45 @@ impl Server {
             );
             let c = Rc::clone(c);
             self.process_connection(c, Some(dgram), now)
+        } else if self.shutting_down {
+            qdebug!(
+                [self],
+                "Refusing connection {:?} while shutting down",
+                attempt_key
+            );
+            None
         } else {
             self.accept_connection(attempt_key, initial, dgram, orig_dcid, now)
         }
     }
 
     fn create_qlog_trace(&self, attempt_key: &AttemptKey) -> Option<NeqoQlog> {
-        if let Some(qlog_dir) = &self.qlog_dir {
-            let mut qlog_path = qlog_dir.to_path_buf();
-
-            // TODO(mt) - the original DCID is not really unique, which means that attackers
-            // can cause us to overwrite our own logs.  That's not ideal.
-            qlog_path.push(format!("{}.qlog", attempt_key.odcid));
-
-            match OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&qlog_path)
-            {
-                Ok(f) => {
-                    qinfo!("Qlog output to {}", qlog_path.display());
-
-                    let streamer = ::qlog::QlogStreamer::new(
-                        qlog::QLOG_VERSION.to_string(),
-                        Some("Neqo server qlog".to_string()),
-                        Some("Neqo server qlog".to_string()),
-                        None,
-                        std::time::Instant::now(),
-                        common::qlog::new_trace(Role::Server),
-                        Box::new(f),
-                    );
-                    let n_qlog = NeqoQlog::new(streamer, qlog_path);
-                    match n_qlog {
-                        Ok(nql) => Some(nql),
-                        Err(e) => {
-                            // Keep going but w/o qlogging
-                            qerror!("NeqoQlog error: {}", e);
-                            None
-                        }
-                    }
-                }
-                Err(e) => {
-                    qerror!(
-                        "Could not open file {} for qlog output: {}",
-                        qlog_path.display(),
-                        e
-                    );
-                    None
-                }
+        let mut qlog_path = self.qlog_dir.as_ref()?.to_path_buf();
+
+        // TODO(mt) - the original DCID is not really unique, which means that attackers
+        // can cause us to overwrite our own logs.  That's not ideal.
+        qlog_path.push(format!("{}.qlog", attempt_key.odcid));
+
+        match NeqoQlog::with_file(
+            qlog_path.clone(),
+            Role::Server,
+            Some("Neqo server qlog".to_string()),
+            Some("Neqo server qlog".to_string()),
+        ) {
+            Ok(mut nql) => {
+                qinfo!("Qlog output to {}", qlog_path.display());
+                nql.set_categories(&self.qlog_categories);
+                Some(nql)
+            }
+            Err(e) => {
+                // Keep going but w/o qlogging
+                qerror!(
+                    "Could not write qlog output to {}: {}",
+                    qlog_path.display(),
+                    e
+                );
+                None
             }
-        } else {
-            None
         }
     }
 
@@ -472,6 +817,7 @@ impl Server {
             cid_manager: Rc::clone(&self.cid_manager),
             connections: Rc::clone(&self.connections),
             saved_cids: Vec::new(),
+            reset_tokens: self.reset_tokens.clone(),
         }));
 
         let sconn = Connection::new_server(
@@ -480,7 +826,44 @@ impl Server {
             &self.anti_replay,
             Rc::clone(&cid_mgr) as _,
             initial.quic_version,
-        );
+        )
+        .and_then(|mut c| {
+            c.set_congestion_control(self.cc_algorithm)?;
+            if !self.allow_0rtt {
+                c.disable_0rtt()?;
+            }
+            if self.reset_tokens.is_some() {
+                // The only connection ID so far is the one the connection uses in the handshake.
+                let mgr = cid_mgr.borrow();
+                if let Some(cid) = mgr.saved_cids.first() {
+                    c.set_local_tparam(
+                        tparams::STATELESS_RESET_TOKEN,
+                        TransportParameter::Bytes(mgr.reset_token(cid).to_vec()),
+                    )?;
+                }
+            }
+            let (v4, v6) = self.preferred_address;
+            if v4.is_some() || v6.is_some() {
+                // This connection ID is routed like any other.
+                let cid = cid_mgr.borrow_mut().generate_cid();
+                let reset_token = cid_mgr.borrow().reset_token(&cid);
+                c.set_preferred_address(PreferredAddress::new(v4, v6, cid, reset_token))?;
+            }
+            c.send_new_token(self.retry.generate_new_token(dgram.source(), now)?)?;
+            if let Some(timeout) = self.handshake_timeout {
+                c.set_handshake_timeout(timeout)?;
+            }
+            if let Some(cfg) = &self.ech_config {
+                c.server_enable_ech(cfg.config, &cfg.public_name, &cfg.sk, &cfg.pk)?;
+            }
+            if self.max_datagram_frame_size > 0 {
+                c.set_local_tparam(
+                    tparams::MAX_DATAGRAM_FRAME_SIZE,
+                    TransportParameter::Integer(self.max_datagram_frame_size),
+                )?;
+            }
+            Ok(c)
+        });
 
         if let Ok(mut c) = sconn {
             if let Some(odcid) = orig_dcid {
@@ -523,9 +906,8 @@ impl Server {
         }
 
         if packet.packet_type() == PacketType::Short {
-            // TODO send a stateless reset here.
             qtrace!([self], "Short header packet for an unknown connection");
-            return None;
+            return self.stateless_reset(packet.dcid(), &dgram, now);
         }
 
         if dgram.len() < MIN_INITIAL_PACKET_SIZE {
@@ -539,6 +921,9 @@ impl Server {
                 self.handle_initial(initial, dgram, now)
             }
             PacketType::OtherVersion => {
+                if !self.allow_stateless_response(&dgram, now) {
+                    return None;
+                }
                 let vn = PacketBuilder::version_negotiation(packet.scid(), packet.dcid());
                 Some(Datagram::new(dgram.destination(), dgram.source(), vn))
             }
@@ -549,6 +934,31 @@ impl Server {
         }
     }
 
+    /// Make a stateless reset in response to a short header packet for an unknown
+    /// connection.  The reset is smaller than the packet, so that two endpoints
+    /// can't send resets to each other forever.
+    fn stateless_reset(
+        &mut self,
+        dcid: &ConnectionIdRef,
+        dgram: &Datagram,
+        now: Instant,
+    ) -> Option<Datagram> {
+        if self.reset_tokens.is_none() || dgram.len() <= MIN_STATELESS_RESET_SIZE {
+            return None;
+        }
+        if !self.allow_stateless_response(dgram, now) {
+            return None;
+        }
+        let token = self.reset_tokens.as_ref()?.token(&dcid[..]).ok()?;
+        let len = min(dgram.len() - 1, MAX_STATELESS_RESET_SIZE);
+        let mut reset = random(len - token.len());
+        // This has to look like a short header packet.
+        reset[0] = (reset[0] & 0x3f) | 0x40;
+        reset.extend_from_slice(&token);
+        qdebug!([self], "Send stateless reset for {}", hex(&dcid[..]));
+        Some(Datagram::new(dgram.destination(), dgram.source(), reset))
+    }
+
     /// Iterate through the pending connections looking for any that might want
     /// to send a datagram.  Stop at the first one that does.
     fn process_next_output(&mut self, now: Instant) -> Option<Datagram> {
@@ -569,13 +979,19 @@ impl Server {
 
     fn next_time(&mut self, now: Instant) -> Option<Duration> {
         if self.waiting.is_empty() {
-            self.timers.next_time().map(|x| x - now)
+            let close = self.shutdown_close.map(|(t, _)| t);
+            let next = match (self.timers.next_time(), close) {
+                (Some(t), Some(c)) => Some(min(t, c)),
+                (t, c) => t.or(c),
+            };
+            next.map(|x| x - now)
         } else {
             Some(Duration::new(0, 0))
         }
     }
 
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
+        self.close_for_shutdown(now);
         let out = if let Some(d) = dgram {
             self.process_input(d, now)
         } else {
@@ -650,6 +1066,8 @@ struct ServerConnectionIdManager {
     connections: ConnectionTableRef,
     cid_manager: CidMgr,
     saved_cids: Vec<ConnectionId>,
+    /// Makes the stateless reset tokens for new connection IDs, if the server has a key.
+    reset_tokens: Option<Rc<StatelessResetTokens>>,
 }
 
 impl ServerConnectionIdManager {
@@ -689,6 +1107,13 @@ impl ConnectionIdManager for ServerConnectionIdManager {
     fn as_decoder(&self) -> &dyn ConnectionIdDecoder {
         self
     }
+
+    fn reset_token(&self, cid: &[u8]) -> [u8; 16] {
+        match self.reset_tokens.as_ref().map(|t| t.token(cid)) {
+            Some(Ok(token)) => token,
+            _ => self.cid_manager.borrow().reset_token(cid),
+        }
+    }
 }
 
 impl ::std::fmt::Display for Server {
@@ -21,16 +21,25 @@ pub struct Stats {
     pub dups_rx: usize,
     /// Dropped datagrams, or parts thereof
     pub dropped_rx: usize,
+    /// Packets that failed authentication
+    pub undecryptable_rx: usize,
     /// resumption used
     pub resumed: bool,
+    /// The latest delivery rate sample, in bytes per second
+    pub delivery_rate: u64,
+    /// Whether the latest delivery rate sample was limited by the application
+    /// rather than the path
+    pub app_limited: bool,
+    /// The smoothed delivery rate, in bytes per second
+    pub smoothed_delivery_rate: u64,
 }
 
 impl Stats {
-    pub fn init(&mut self, conn_info: String) {
+    pub(crate) fn init(&mut self, conn_info: String) {
         self.conn_display_info = conn_info;
     }
 
-    pub fn pkt_dropped(&mut self, reason: impl AsRef<str>) {
+    pub(crate) fn pkt_dropped(&mut self, reason: impl AsRef<str>) {
         self.dropped_rx += 1;
         qwarn!(
             [self.conn_display_info],
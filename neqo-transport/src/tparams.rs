@@ -7,19 +7,122 @@
 // Transport parameters. See -transport section 7.3.
 
 #![allow(dead_code)]
+use crate::cid::{ConnectionId, MAX_CONNECTION_ID_LEN};
 use crate::{Error, Res};
 use neqo_common::{hex, matches, qdebug, qinfo, qtrace, Decoder, Encoder};
-use neqo_crypto::constants::{TLS_HS_CLIENT_HELLO, TLS_HS_ENCRYPTED_EXTENSIONS};
+use neqo_crypto::constants::{Extension, TLS_HS_CLIENT_HELLO, TLS_HS_ENCRYPTED_EXTENSIONS};
 use neqo_crypto::ext::{ExtensionHandler, ExtensionHandlerResult, ExtensionWriterResult};
 use neqo_crypto::{HandshakeMessage, ZeroRttCheckResult, ZeroRttChecker};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::rc::Rc;
 
-struct PreferredAddress {
-    // TODO(ekr@rtfm.com): Implement.
+/// The value of the preferred_address transport parameter: an alternative
+/// server address, with a connection ID and stateless reset token to use there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreferredAddress {
+    v4: Option<SocketAddrV4>,
+    v6: Option<SocketAddrV6>,
+    cid: ConnectionId,
+    reset_token: [u8; 16],
+}
+
+impl PreferredAddress {
+    /// # Panics
+    /// If there is no address or the connection ID is empty.
+    pub fn new(
+        v4: Option<SocketAddrV4>,
+        v6: Option<SocketAddrV6>,
+        cid: ConnectionId,
+        reset_token: [u8; 16],
+    ) -> Self {
+        assert!(v4.is_some() || v6.is_some());
+        assert!(!cid.is_empty());
+        Self {
+            v4,
+            v6,
+            cid,
+            reset_token,
+        }
+    }
+
+    /// The preferred address in the same family as `addr`, if there is one.
+    pub fn address_for(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        match addr {
+            SocketAddr::V4(_) => self.v4.map(SocketAddr::V4),
+            SocketAddr::V6(_) => self.v6.map(SocketAddr::V6),
+        }
+    }
+
+    /// Whether `addr` is one of the preferred addresses.
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.address_for(addr).as_ref() == Some(addr)
+    }
+
+    pub fn cid(&self) -> &ConnectionId {
+        &self.cid
+    }
+
+    pub fn reset_token(&self) -> &[u8; 16] {
+        &self.reset_token
+    }
+
+    /// Encode the value of the transport parameter.  An absent address is all zeros.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut enc = Encoder::default();
+        let v4 = self
+            .v4
+            .unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+        enc.encode(&v4.ip().octets());
+        enc.encode_uint(2, v4.port());
+        let v6 = self
+            .v6
+            .unwrap_or_else(|| SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0));
+        enc.encode(&v6.ip().octets());
+        enc.encode_uint(2, v6.port());
+        enc.encode_vec(1, &self.cid);
+        enc.encode(&self.reset_token);
+        enc.into()
+    }
+
+    /// Decode the value of the transport parameter.
+    pub fn decode(buf: &[u8]) -> Res<Self> {
+        let mut dec = Decoder::from(buf);
+        let v4_ip = <[u8; 4]>::try_from(dec.decode(4).ok_or(Error::NoMoreData)?).unwrap();
+        let v4_port = u16::try_from(dec.decode_uint(2).ok_or(Error::NoMoreData)?).unwrap();
+        let v4 = SocketAddrV4::new(Ipv4Addr::from(v4_ip), v4_port);
+        let v6_ip = <[u8; 16]>::try_from(dec.decode(16).ok_or(Error::NoMoreData)?).unwrap();
+        let v6_port = u16::try_from(dec.decode_uint(2).ok_or(Error::NoMoreData)?).unwrap();
+        let v6 = SocketAddrV6::new(Ipv6Addr::from(v6_ip), v6_port, 0, 0);
+        let cid = dec.decode_vec(1).ok_or(Error::NoMoreData)?;
+        if cid.is_empty() || cid.len() > MAX_CONNECTION_ID_LEN {
+            return Err(Error::TransportParameterError);
+        }
+        let reset_token = <[u8; 16]>::try_from(dec.decode(16).ok_or(Error::NoMoreData)?).unwrap();
+        if dec.remaining() > 0 {
+            return Err(Error::TooMuchData);
+        }
+        let v4 = Some(v4).filter(|a| !a.ip().is_unspecified() && a.port() != 0);
+        let v6 = Some(v6).filter(|a| !a.ip().is_unspecified() && a.port() != 0);
+        if v4.is_none() && v6.is_none() {
+            return Err(Error::TransportParameterError);
+        }
+        Ok(Self {
+            v4,
+            v6,
+            cid: ConnectionId::from(cid),
+            reset_token,
+        })
+    }
 }
 
+/// The TLS extension that carries transport parameters (RFC 9001, Section 8.2).
+pub(crate) const TLS_EXT_QUIC_TRANSPORT_PARAMETERS: Extension = 0x39;
+/// The codepoint for the extension that drafts used, before it was assigned.
+pub(crate) const TLS_EXT_QUIC_TRANSPORT_PARAMETERS_DRAFT: Extension = 0xffa5;
+
 pub type TransportParameterId = u64;
 macro_rules! tpids {
         { $($n:ident = $v:expr),+ $(,)? } => {
@@ -44,6 +147,7 @@ tpids! {
     ACTIVE_CONNECTION_ID_LIMIT = 0x0e,
     INITIAL_SOURCE_CONNECTION_ID = 0x0f,
     RETRY_SOURCE_CONNECTION_ID = 0x10,
+    MAX_DATAGRAM_FRAME_SIZE = 0x20,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -62,9 +166,8 @@ impl TransportParameter {
                 enc.encode_vvec(a);
             }
             Self::Integer(a) => {
-                enc.encode_vvec_with(|enc_inner| {
-                    enc_inner.encode_varint(*a);
-                });
+                enc.encode_varint(u64::try_from(Encoder::varint_len(*a)).unwrap());
+                enc.encode_varint(*a);
             }
             Self::Empty => {
                 enc.encode_varint(0_u64);
@@ -72,17 +175,31 @@ impl TransportParameter {
         };
     }
 
-    fn decode(dec: &mut Decoder) -> Res<Option<(TransportParameterId, Self)>> {
-        let tp = match dec.decode_varint() {
-            Some(v) => v,
-            _ => return Err(Error::NoMoreData),
-        };
-        let content = match dec.decode_vvec() {
-            Some(v) => v,
-            _ => return Err(Error::NoMoreData),
+    /// The number of bytes that `encode` will write for this parameter.
+    fn encoded_len(&self, tp: TransportParameterId) -> usize {
+        let value = match self {
+            Self::Bytes(a) => a.len(),
+            Self::Integer(a) => Encoder::varint_len(*a),
+            Self::Empty => 0,
         };
-        qtrace!("TP {:x} length {:x}", tp, content.len());
-        let mut d = Decoder::from(content);
+        Encoder::varint_len(tp) + Encoder::varint_len(u64::try_from(value).unwrap()) + value
+    }
+
+    /// Decode an integer value.  This fails if the parameter is empty or if it is
+    /// shorter than the varint needs.  Anything after the varint is caught by
+    /// the check at the end of `decode`.
+    fn decode_integer(d: &mut Decoder) -> Res<u64> {
+        d.decode_varint().ok_or(Error::TransportParameterError)
+    }
+
+    /// Decode one transport parameter.  Any problem with the encoding, including a
+    /// length that runs past the end of the data, is a `TransportParameterError`.
+    fn decode(dec: &mut Decoder) -> Res<Option<(TransportParameterId, Self)>> {
+        let tp = dec.decode_varint().ok_or(Error::TransportParameterError)?;
+        let mut d = dec
+            .decode_vvec_decoder()
+            .ok_or(Error::TransportParameterError)?;
+        qtrace!("TP {:x} length {:x}", tp, d.remaining());
         let value = match tp {
             ORIGINAL_DESTINATION_CONNECTION_ID
             | INITIAL_SOURCE_CONNECTION_ID
@@ -93,32 +210,37 @@ impl TransportParameter {
                 }
                 Self::Bytes(d.decode_remainder().to_vec())
             }
+            PREFERRED_ADDRESS => {
+                let v = d.decode_remainder();
+                PreferredAddress::decode(v)?;
+                Self::Bytes(v.to_vec())
+            }
             IDLE_TIMEOUT
             | INITIAL_MAX_DATA
             | INITIAL_MAX_STREAM_DATA_BIDI_LOCAL
             | INITIAL_MAX_STREAM_DATA_BIDI_REMOTE
             | INITIAL_MAX_STREAM_DATA_UNI
-            | MAX_ACK_DELAY => match d.decode_varint() {
-                Some(v) => Self::Integer(v),
-                None => return Err(Error::TransportParameterError),
-            },
+            | MAX_ACK_DELAY
+            | MAX_DATAGRAM_FRAME_SIZE => Self::Integer(Self::decode_integer(&mut d)?),
 
-            INITIAL_MAX_STREAMS_BIDI | INITIAL_MAX_STREAMS_UNI => match d.decode_varint() {
-                Some(v) if v <= (1 << 60) => Self::Integer(v),
-                _ => return Err(Error::StreamLimitError),
-            },
+            INITIAL_MAX_STREAMS_BIDI | INITIAL_MAX_STREAMS_UNI => {
+                match Self::decode_integer(&mut d)? {
+                    v if v <= (1 << 60) => Self::Integer(v),
+                    _ => return Err(Error::StreamLimitError),
+                }
+            }
 
-            MAX_UDP_PAYLOAD_SIZE => match d.decode_varint() {
-                Some(v) if v >= 1200 => Self::Integer(v),
+            MAX_UDP_PAYLOAD_SIZE => match Self::decode_integer(&mut d)? {
+                v if v >= 1200 => Self::Integer(v),
                 _ => return Err(Error::TransportParameterError),
             },
 
-            ACK_DELAY_EXPONENT => match d.decode_varint() {
-                Some(v) if v <= 20 => Self::Integer(v),
+            ACK_DELAY_EXPONENT => match Self::decode_integer(&mut d)? {
+                v if v <= 20 => Self::Integer(v),
                 _ => return Err(Error::TransportParameterError),
             },
-            ACTIVE_CONNECTION_ID_LIMIT => match d.decode_varint() {
-                Some(v) if v >= 2 => Self::Integer(v),
+            ACTIVE_CONNECTION_ID_LIMIT => match Self::decode_integer(&mut d)? {
+                v if v >= 2 => Self::Integer(v),
                 _ => return Err(Error::TransportParameterError),
             },
 
@@ -127,7 +249,7 @@ impl TransportParameter {
             _ => return Ok(None),
         };
         if d.remaining() > 0 {
-            return Err(Error::TooMuchData);
+            return Err(Error::TransportParameterError);
         }
         qdebug!("TP decoded; type 0x{:02x} val {:?}", tp, value);
         Ok(Some((tp, value)))
@@ -174,6 +296,14 @@ impl TransportParameters {
         }
     }
 
+    /// The number of bytes that `encode` will write.
+    pub(crate) fn encoded_len(&self) -> usize {
+        self.params
+            .iter()
+            .map(|(tipe, tp)| tp.encoded_len(*tipe))
+            .sum()
+    }
+
     // Get an integer type or a default.
     pub fn get_integer(&self, tp: TransportParameterId) -> u64 {
         let default = match tp {
@@ -183,7 +313,8 @@ impl TransportParameters {
             | INITIAL_MAX_STREAM_DATA_BIDI_REMOTE
             | INITIAL_MAX_STREAM_DATA_UNI
             | INITIAL_MAX_STREAMS_BIDI
-            | INITIAL_MAX_STREAMS_UNI => 0,
+            | INITIAL_MAX_STREAMS_UNI
+            | MAX_DATAGRAM_FRAME_SIZE => 0,
             MAX_UDP_PAYLOAD_SIZE => 65527,
             ACK_DELAY_EXPONENT => 3,
             MAX_ACK_DELAY => 25,
@@ -210,7 +341,8 @@ impl TransportParameters {
             | MAX_UDP_PAYLOAD_SIZE
             | ACK_DELAY_EXPONENT
             | MAX_ACK_DELAY
-            | ACTIVE_CONNECTION_ID_LIMIT => {
+            | ACTIVE_CONNECTION_ID_LIMIT
+            | MAX_DATAGRAM_FRAME_SIZE => {
                 self.set(tp, TransportParameter::Integer(value));
             }
             _ => panic!("Transport parameter not known"),
@@ -222,7 +354,8 @@ impl TransportParameters {
             ORIGINAL_DESTINATION_CONNECTION_ID
             | INITIAL_SOURCE_CONNECTION_ID
             | RETRY_SOURCE_CONNECTION_ID
-            | STATELESS_RESET_TOKEN => {}
+            | STATELESS_RESET_TOKEN
+            | PREFERRED_ADDRESS => {}
             _ => panic!("Transport parameter not known or not type bytes"),
         }
 
@@ -238,7 +371,8 @@ impl TransportParameters {
             ORIGINAL_DESTINATION_CONNECTION_ID
             | INITIAL_SOURCE_CONNECTION_ID
             | RETRY_SOURCE_CONNECTION_ID
-            | STATELESS_RESET_TOKEN => {
+            | STATELESS_RESET_TOKEN
+            | PREFERRED_ADDRESS => {
                 self.set(tp, TransportParameter::Bytes(value));
             }
             _ => panic!("Transport parameter not known or not type bytes"),
@@ -278,6 +412,7 @@ impl TransportParameters {
                     | INITIAL_SOURCE_CONNECTION_ID
                     | RETRY_SOURCE_CONNECTION_ID
                     | STATELESS_RESET_TOKEN
+                    | PREFERRED_ADDRESS
                     | IDLE_TIMEOUT
                     | ACK_DELAY_EXPONENT
                     | MAX_ACK_DELAY
@@ -307,14 +442,14 @@ impl TransportParameters {
 }
 
 #[derive(Default, Debug)]
-pub struct TransportParametersHandler {
+pub(crate) struct TransportParametersHandler {
     pub(crate) local: TransportParameters,
     pub(crate) remote: Option<TransportParameters>,
     pub(crate) remote_0rtt: Option<TransportParameters>,
 }
 
 impl TransportParametersHandler {
-    pub fn remote(&self) -> &TransportParameters {
+    pub(crate) fn remote(&self) -> &TransportParameters {
         match (self.remote.as_ref(), self.remote_0rtt.as_ref()) {
             (Some(tp), _) | (_, Some(tp)) => tp,
             _ => panic!("no transport parameters from peer"),
@@ -331,9 +466,11 @@ impl ExtensionHandler for TransportParametersHandler {
         qdebug!("Writing transport parameters, msg={:?}", msg);
 
         // TODO(ekr@rtfm.com): Modify to avoid a copy.
-        let mut enc = Encoder::default();
+        let len = self.local.encoded_len();
+        assert!(len <= d.len());
+        let mut enc = Encoder::with_capacity(len);
         self.local.encode(&mut enc);
-        assert!(enc.len() <= d.len());
+        debug_assert_eq!(enc.len(), len);
         d[..enc.len()].copy_from_slice(&enc);
         ExtensionWriterResult::Write(enc.len())
     }
@@ -379,13 +516,12 @@ impl ZeroRttChecker for TpZeroRttChecker {
             return ZeroRttCheckResult::Reject;
         }
         let mut dec = Decoder::from(token);
-        let tpslice = if let Some(v) = dec.decode_vvec() {
+        let mut dec_tp = if let Some(v) = dec.decode_vvec_decoder() {
             v
         } else {
             qinfo!("0-RTT: token code error");
             return ZeroRttCheckResult::Fail;
         };
-        let mut dec_tp = Decoder::from(tpslice);
         let remembered = if let Ok(v) = TransportParameters::decode(&mut dec_tp) {
             v
         } else {
@@ -445,6 +581,168 @@ mod tests {
         let tps2 = TransportParameters::decode(&mut enc.as_decoder()).expect("Couldn't decode");
     }
 
+    #[test]
+    fn preferred_address() {
+        let pa = PreferredAddress::new(
+            Some(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 443)),
+            None,
+            ConnectionId::from(&[1, 2, 3, 4][..]),
+            [9; 16],
+        );
+        let mut tps = TransportParameters::default();
+        tps.set_bytes(PREFERRED_ADDRESS, pa.encode());
+
+        let mut enc = Encoder::default();
+        tps.encode(&mut enc);
+        let tps2 = TransportParameters::decode(&mut enc.as_decoder()).expect("Couldn't decode");
+        let pa2 = PreferredAddress::decode(tps2.get_bytes(PREFERRED_ADDRESS).unwrap()).unwrap();
+        assert_eq!(pa, pa2);
+        let v4: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        assert_eq!(pa2.address_for(&"10.0.0.1:1".parse().unwrap()), Some(v4));
+        assert_eq!(pa2.address_for(&"[::1]:1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn preferred_address_bad() {
+        let pa = PreferredAddress::new(
+            None,
+            Some(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 443, 0, 0)),
+            ConnectionId::from(&[1, 2, 3, 4][..]),
+            [9; 16],
+        );
+        let mut buf = pa.encode();
+        assert!(PreferredAddress::decode(&buf).is_ok());
+        // A zero port means that there is no address.
+        let mut no_address = buf.clone();
+        no_address[22] = 0;
+        no_address[23] = 0;
+        assert_eq!(
+            PreferredAddress::decode(&no_address),
+            Err(Error::TransportParameterError)
+        );
+        // A zero-length connection ID.
+        let mut no_cid = buf[..24].to_vec();
+        no_cid.push(0);
+        no_cid.extend_from_slice(&[9; 16]);
+        assert_eq!(
+            PreferredAddress::decode(&no_cid),
+            Err(Error::TransportParameterError)
+        );
+        buf.push(0);
+        assert_eq!(PreferredAddress::decode(&buf), Err(Error::TooMuchData));
+    }
+
+    #[test]
+    fn encoded_len() {
+        let mut tps = TransportParameters::default();
+        assert_eq!(tps.encoded_len(), 0);
+        tps.set_integer(IDLE_TIMEOUT, 30_000);
+        tps.set_integer(INITIAL_MAX_DATA, 1 << 40);
+        tps.set_integer(MAX_DATAGRAM_FRAME_SIZE, 0);
+        tps.set_empty(DISABLE_MIGRATION);
+        tps.set_bytes(INITIAL_SOURCE_CONNECTION_ID, vec![0; 20]);
+        // Both the type and the length of this need two bytes.
+        tps.set(0x5555, TransportParameter::Bytes(vec![0; 100]));
+
+        let mut enc = Encoder::default();
+        tps.encode(&mut enc);
+        assert_eq!(tps.encoded_len(), enc.len());
+    }
+
+    /// Decode a single transport parameter with the given type and raw value.
+    fn decode_raw(tp: TransportParameterId, value: &[u8]) -> Res<TransportParameters> {
+        let mut enc = Encoder::default();
+        enc.encode_varint(tp);
+        enc.encode_vvec(value);
+        TransportParameters::decode(&mut enc.as_decoder())
+    }
+
+    const INTEGER_TPS: &[TransportParameterId] = &[
+        IDLE_TIMEOUT,
+        MAX_UDP_PAYLOAD_SIZE,
+        INITIAL_MAX_DATA,
+        INITIAL_MAX_STREAM_DATA_BIDI_LOCAL,
+        INITIAL_MAX_STREAM_DATA_BIDI_REMOTE,
+        INITIAL_MAX_STREAM_DATA_UNI,
+        INITIAL_MAX_STREAMS_BIDI,
+        INITIAL_MAX_STREAMS_UNI,
+        ACK_DELAY_EXPONENT,
+        MAX_ACK_DELAY,
+        ACTIVE_CONNECTION_ID_LIMIT,
+        MAX_DATAGRAM_FRAME_SIZE,
+    ];
+
+    #[test]
+    fn zero_length_integer() {
+        for &tp in INTEGER_TPS {
+            assert_eq!(decode_raw(tp, &[]), Err(Error::TransportParameterError));
+        }
+    }
+
+    #[test]
+    fn integer_longer_than_length() {
+        // The varint needs two bytes, but the parameter only has one.
+        for &tp in INTEGER_TPS {
+            let mut enc = Encoder::default();
+            enc.encode_varint(tp);
+            enc.encode_varint(1_u64);
+            enc.encode(&[0x44, 0xb0]);
+            assert_eq!(
+                TransportParameters::decode(&mut enc.as_decoder()),
+                Err(Error::TransportParameterError)
+            );
+        }
+    }
+
+    #[test]
+    fn integer_with_extra_data() {
+        for &tp in INTEGER_TPS {
+            assert_eq!(
+                decode_raw(tp, &[0x44, 0xb0, 0x00]),
+                Err(Error::TransportParameterError)
+            );
+        }
+        assert_eq!(
+            decode_raw(DISABLE_MIGRATION, &[0]),
+            Err(Error::TransportParameterError)
+        );
+    }
+
+    #[test]
+    fn length_past_end() {
+        for &tp in &[INITIAL_MAX_DATA, INITIAL_SOURCE_CONNECTION_ID, 0x1f] {
+            let mut enc = Encoder::default();
+            enc.encode_varint(tp);
+            enc.encode_varint(10_u64);
+            enc.encode(&[0; 9]);
+            assert_eq!(
+                TransportParameters::decode(&mut enc.as_decoder()),
+                Err(Error::TransportParameterError)
+            );
+        }
+
+        // The largest length that can be encoded.
+        let mut enc = Encoder::default();
+        enc.encode_varint(INITIAL_MAX_DATA);
+        enc.encode_varint((1_u64 << 62) - 1);
+        enc.encode(&[0; 9]);
+        assert_eq!(
+            TransportParameters::decode(&mut enc.as_decoder()),
+            Err(Error::TransportParameterError)
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        // A type that needs two bytes, and one with no length.
+        for buf in &[&[0x40][..], &[0x04][..], &[0x04, 0x40][..]] {
+            assert_eq!(
+                TransportParameters::decode(&mut Decoder::from(*buf)),
+                Err(Error::TransportParameterError)
+            );
+        }
+    }
+
     #[test]
     fn compatible_0rtt_ignored_values() {
         let mut tps_a = TransportParameters::default();
@@ -16,6 +16,7 @@ use std::time::{Duration, Instant};
 use neqo_common::{qdebug, qinfo, qtrace, qwarn};
 use neqo_crypto::{Epoch, TLS_EPOCH_HANDSHAKE, TLS_EPOCH_INITIAL};
 
+use crate::ecn::EcnCount;
 use crate::frame::{AckRange, Frame};
 use crate::packet::{PacketNumber, PacketType};
 use crate::recovery::RecoveryToken;
@@ -65,6 +66,8 @@ impl From<PacketType> for PNSpace {
 
 #[derive(Debug, Clone)]
 pub struct SentPacket {
+    pub pt: PacketType,
+    pub pn: PacketNumber,
     ack_eliciting: bool,
     pub time_sent: Instant,
     pub tokens: Rc<Vec<RecoveryToken>>,
@@ -79,6 +82,8 @@ pub struct SentPacket {
 
 impl SentPacket {
     pub fn new(
+        pt: PacketType,
+        pn: PacketNumber,
         time_sent: Instant,
         ack_eliciting: bool,
         tokens: Rc<Vec<RecoveryToken>>,
@@ -86,6 +91,8 @@ impl SentPacket {
         in_flight: bool,
     ) -> Self {
         Self {
+            pt,
+            pn,
             time_sent,
             ack_eliciting,
             tokens,
@@ -243,6 +250,10 @@ impl ::std::fmt::Display for PacketRange {
 /// The ACK delay we use.
 pub const ACK_DELAY: Duration = Duration::from_millis(20); // 20ms
 pub const MAX_UNACKED_PKTS: u64 = 1;
+/// The `ack_delay_exponent` that is used if none is set.  ACK frames in Initial and
+/// Handshake packets always use this, as the transport parameters might not
+/// be known when those packets are sent or received.
+pub const DEFAULT_ACK_DELAY_EXPONENT: u64 = 3;
 const MAX_TRACKED_RANGES: usize = 32;
 const MAX_ACKS_PER_FRAME: usize = 32;
 
@@ -266,6 +277,14 @@ pub struct RecvdPackets {
     // The time that we should be sending an ACK.
     ack_time: Option<Instant>,
     pkts_since_last_ack: u64,
+    /// How long to delay an ACK for, in the application data space.
+    ack_delay: Duration,
+    /// The number of ACK-eliciting packets that cause an ACK to be sent without delay.
+    ack_every: u64,
+    /// The ACK Delay field is in units of 2 to the power of this many microseconds.
+    ack_delay_exponent: u64,
+    /// The ECN codepoints of the packets that were received.
+    ecn_count: EcnCount,
 }
 
 impl RecvdPackets {
@@ -278,6 +297,10 @@ impl RecvdPackets {
             largest_pn_time: None,
             ack_time: None,
             pkts_since_last_ack: 0,
+            ack_delay: ACK_DELAY,
+            ack_every: MAX_UNACKED_PKTS + 1,
+            ack_delay_exponent: DEFAULT_ACK_DELAY_EXPONENT,
+            ecn_count: EcnCount::default(),
         }
     }
 
@@ -346,15 +369,15 @@ impl RecvdPackets {
             // Send ACK right away if out-of-order
             // On the first in-order ack-eliciting packet since sending an ACK,
             // set a delay.
-            // Count packets until we exceed MAX_UNACKED_PKTS, then remove the
+            // Count packets until we reach `ack_every`, then remove the
             // delay.
             if pn != next_in_order_pn {
                 self.ack_time = Some(now);
             } else if self.space == PNSpace::ApplicationData {
-                match &mut self.pkts_since_last_ack {
+                match self.pkts_since_last_ack {
                     0 => unreachable!(),
-                    1 => self.ack_time = Some(now + ACK_DELAY),
-                    x if *x > MAX_UNACKED_PKTS => self.ack_time = Some(now),
+                    x if x >= self.ack_every => self.ack_time = Some(now),
+                    1 => self.ack_time = Some(now + self.ack_delay),
                     _ => debug_assert!(self.ack_time.is_some()),
                 }
             } else {
@@ -364,18 +387,27 @@ impl RecvdPackets {
         }
     }
 
+    /// Count the ECN codepoint of a packet that was received.
+    pub fn count_ecn(&mut self, ecn: u8) {
+        self.ecn_count.add(ecn);
+    }
+
+    /// The largest packet number that was received, if any were.
+    pub fn largest_pn(&self) -> Option<PacketNumber> {
+        self.ranges.front().map(|r| r.largest)
+    }
+
     /// Check if the packet is a duplicate.
     pub fn is_duplicate(&self, pn: PacketNumber) -> bool {
         if pn < self.min_tracked {
             return true;
         }
-        // TODO(mt) consider a binary search or early exit.
-        for range in &self.ranges {
-            if range.contains(pn) {
-                return true;
-            }
-        }
-        false
+        // Ranges are in descending order, so stop at the first range that
+        // isn't entirely above `pn`.
+        self.ranges
+            .iter()
+            .find(|r| r.smallest <= pn)
+            .map_or(false, |r| r.contains(pn))
     }
 
     /// Mark the given range as having been acknowledged.
@@ -403,7 +435,11 @@ impl RecvdPackets {
     ///
     /// We don't send ranges that have been acknowledged, but they still need
     /// to be tracked so that duplicates can be detected.
-    fn get_frame(&mut self, now: Instant) -> Option<(Frame, Option<RecoveryToken>)> {
+    fn get_frame(
+        &mut self,
+        now: Instant,
+        remaining: usize,
+    ) -> Option<(Frame, Option<RecoveryToken>)> {
         // Check that we aren't delaying ACKs.
         if !self.ack_now(now) {
             return None;
@@ -424,7 +460,7 @@ impl RecvdPackets {
             Some(v) => v,
             _ => return None, // Nothing to send.
         };
-        let mut ack_ranges = Vec::new();
+        let mut ack_ranges = Vec::with_capacity(ranges.len() - 1);
         let mut last = first.smallest;
 
         for range in iter {
@@ -437,20 +473,28 @@ impl RecvdPackets {
             last = range.smallest;
         }
 
-        // We've sent an ACK, reset the timer.
-        self.ack_time = None;
-        self.pkts_since_last_ack = 0;
-
         let ack_delay = now.duration_since(self.largest_pn_time.unwrap());
-        // We use the default exponent so
-        // ack_delay is in multiples of 8 microseconds.
-        if let Ok(delay) = (ack_delay.as_micros() / 8).try_into() {
+        if let Ok(delay) = (ack_delay.as_micros() >> self.ack_delay_exponent).try_into() {
             let ack = Frame::Ack {
                 largest_acknowledged: first.largest,
                 ack_delay: delay,
                 first_ack_range: first.len() - 1,
                 ack_ranges,
+                ecn_count: if self.ecn_count.is_empty() {
+                    None
+                } else {
+                    Some(self.ecn_count)
+                },
             };
+            if ack.encoded_len() > remaining {
+                qtrace!([self], "ACK frame doesn't fit in remaining {}", remaining);
+                return None;
+            }
+
+            // We've sent an ACK, reset the timer.
+            self.ack_time = None;
+            self.pkts_since_last_ack = 0;
+
             let token = RecoveryToken::Ack(AckToken {
                 space: self.space,
                 ranges,
@@ -519,6 +563,28 @@ impl AckTracker {
         }
     }
 
+    /// Set how long ACKs for application data can be delayed.
+    pub fn set_ack_delay(&mut self, delay: Duration) {
+        if let Some(space) = self.get_mut(PNSpace::ApplicationData) {
+            space.ack_delay = delay;
+        }
+    }
+
+    /// Set the exponent that is used to encode the ACK delay for application data.
+    pub fn set_ack_delay_exponent(&mut self, exponent: u64) {
+        if let Some(space) = self.get_mut(PNSpace::ApplicationData) {
+            space.ack_delay_exponent = exponent;
+        }
+    }
+
+    /// Set how many ACK-eliciting packets can arrive before an ACK is sent without delay.
+    pub fn set_ack_every(&mut self, packets: u64) {
+        debug_assert!(packets > 0);
+        if let Some(space) = self.get_mut(PNSpace::ApplicationData) {
+            space.ack_every = packets;
+        }
+    }
+
     pub fn acked(&mut self, token: &AckToken) {
         if let Some(space) = self.get_mut(token.space) {
             space.acknowledged(&token.ranges);
@@ -529,9 +595,10 @@ impl AckTracker {
         &mut self,
         now: Instant,
         pn_space: PNSpace,
+        remaining: usize,
     ) -> Option<(Frame, Option<RecoveryToken>)> {
         self.get_mut(pn_space)
-            .and_then(|space| space.get_frame(now))
+            .and_then(|space| space.get_frame(now, remaining))
     }
 }
 
@@ -550,10 +617,11 @@ impl Default for AckTracker {
 #[cfg(test)]
 mod tests {
     use super::{
-        AckTracker, Duration, Instant, PNSpace, RecoveryToken, RecvdPackets, ACK_DELAY,
-        MAX_TRACKED_RANGES, MAX_UNACKED_PKTS,
+        AckTracker, Duration, EcnCount, Frame, Instant, PNSpace, RecoveryToken, RecvdPackets,
+        ACK_DELAY, MAX_TRACKED_RANGES, MAX_UNACKED_PKTS,
     };
     use lazy_static::lazy_static;
+    use neqo_common::{matches, IPTOS_DEFAULT, IPTOS_ECN_ECT0};
     use std::collections::HashSet;
 
     lazy_static! {
@@ -632,6 +700,23 @@ mod tests {
         assert!(rp.is_duplicate(2));
     }
 
+    #[test]
+    fn duplicates_between_ranges() {
+        let mut rp = RecvdPackets::new(PNSpace::ApplicationData);
+        assert_eq!(rp.largest_pn(), None);
+        for pn in &[20, 21, 15, 10, 11, 12] {
+            rp.set_received(*NOW, *pn, true);
+        }
+        assert_eq!(rp.ranges.len(), 3);
+        assert_eq!(rp.largest_pn(), Some(21));
+        for pn in &[10, 11, 12, 15, 20, 21] {
+            assert!(rp.is_duplicate(*pn));
+        }
+        for pn in &[9, 13, 14, 16, 19, 22, 100] {
+            assert!(!rp.is_duplicate(*pn));
+        }
+    }
+
     #[test]
     fn ack_delay() {
         // Only application data packets are delayed.
@@ -653,6 +738,30 @@ mod tests {
         assert!(rp.ack_now(*NOW));
     }
 
+    #[test]
+    fn configured_ack_delay() {
+        const DELAY: Duration = Duration::from_millis(3);
+        let mut tracker = AckTracker::default();
+        tracker.set_ack_delay(DELAY);
+        tracker.set_ack_every(3);
+        let rp = tracker.get_mut(PNSpace::ApplicationData).unwrap();
+        for pn in 0..2 {
+            rp.set_received(*NOW, pn, true);
+            assert_eq!(Some(*NOW + DELAY), rp.ack_time());
+        }
+        rp.set_received(*NOW, 2, true);
+        assert_eq!(Some(*NOW), rp.ack_time());
+    }
+
+    #[test]
+    fn ack_every_packet() {
+        let mut tracker = AckTracker::default();
+        tracker.set_ack_every(1);
+        let rp = tracker.get_mut(PNSpace::ApplicationData).unwrap();
+        rp.set_received(*NOW, 0, true);
+        assert_eq!(Some(*NOW), rp.ack_time());
+    }
+
     #[test]
     fn no_ack_delay() {
         for space in &[PNSpace::Initial, PNSpace::Handshake] {
@@ -725,6 +834,78 @@ mod tests {
         tracker.drop_space(PNSpace::Handshake);
     }
 
+    #[test]
+    fn ack_delay_exponent() {
+        const DELAY: Duration = Duration::from_micros(1000);
+        let mut tracker = AckTracker::default();
+        tracker.set_ack_delay_exponent(0);
+        for space in PNSpace::iter() {
+            tracker.get_mut(*space).unwrap().set_received(*NOW, 0, true);
+        }
+        tracker.get_mut(PNSpace::ApplicationData).unwrap().ack_time = Some(*NOW);
+
+        // Only application data uses the exponent that was set.
+        for (space, expected) in &[
+            (PNSpace::Initial, 125),
+            (PNSpace::Handshake, 125),
+            (PNSpace::ApplicationData, 1000),
+        ] {
+            let (ack, _) = tracker.get_frame(*NOW + DELAY, *space, 100).unwrap();
+            if let Frame::Ack { ack_delay, .. } = ack {
+                assert_eq!(ack_delay, *expected);
+            } else {
+                panic!("not an ACK");
+            }
+        }
+    }
+
+    #[test]
+    fn ack_frame_must_fit() {
+        let mut tracker = AckTracker::default();
+        tracker
+            .get_mut(PNSpace::Initial)
+            .unwrap()
+            .set_received(*NOW, 0, true);
+        // An ACK of a single packet needs 5 bytes.
+        assert!(tracker.get_frame(*NOW, PNSpace::Initial, 4).is_none());
+        // The ACK is still needed.
+        assert!(tracker.ack_time(*NOW - Duration::from_millis(1)).is_some());
+        let (ack, _) = tracker.get_frame(*NOW, PNSpace::Initial, 5).unwrap();
+        assert_eq!(ack.encoded_len(), 5);
+        assert!(tracker.ack_time(*NOW - Duration::from_millis(1)).is_none());
+    }
+
+    #[test]
+    fn ack_ecn() {
+        let mut tracker = AckTracker::default();
+        let rp = tracker.get_mut(PNSpace::Initial).unwrap();
+        rp.set_received(*NOW, 0, true);
+        rp.count_ecn(IPTOS_DEFAULT);
+        let (ack, _) = tracker.get_frame(*NOW, PNSpace::Initial, 100).unwrap();
+        assert!(matches!(
+            ack,
+            Frame::Ack {
+                ecn_count: None,
+                ..
+            }
+        ));
+
+        let rp = tracker.get_mut(PNSpace::Initial).unwrap();
+        rp.set_received(*NOW, 1, true);
+        rp.count_ecn(IPTOS_ECN_ECT0);
+        let (ack, _) = tracker.get_frame(*NOW, PNSpace::Initial, 100).unwrap();
+        let expected = EcnCount {
+            ect0: 1,
+            ect1: 0,
+            ce: 0,
+        };
+        if let Frame::Ack { ecn_count, .. } = ack {
+            assert_eq!(ecn_count, Some(expected));
+        } else {
+            panic!("not an ACK");
+        }
+    }
+
     #[test]
     fn drop_spaces() {
         let mut tracker = AckTracker::default();
@@ -734,7 +915,7 @@ mod tests {
             .set_received(*NOW, 0, true);
         // The reference time for `ack_time` has to be in the past or we filter out the timer.
         assert!(tracker.ack_time(*NOW - Duration::from_millis(1)).is_some());
-        let (_ack, token) = tracker.get_frame(*NOW, PNSpace::Initial).unwrap();
+        let (_ack, token) = tracker.get_frame(*NOW, PNSpace::Initial, 100).unwrap();
         assert!(token.is_some());
 
         // Mark another packet as received so we have cause to send another ACK in that space.
@@ -749,7 +930,7 @@ mod tests {
 
         assert!(tracker.get_mut(PNSpace::Initial).is_none());
         assert!(tracker.ack_time(*NOW - Duration::from_millis(1)).is_none());
-        assert!(tracker.get_frame(*NOW, PNSpace::Initial).is_none());
+        assert!(tracker.get_frame(*NOW, PNSpace::Initial, 100).is_none());
         if let RecoveryToken::Ack(tok) = token.as_ref().unwrap() {
             tracker.acked(tok); // Should be a noop.
         } else {
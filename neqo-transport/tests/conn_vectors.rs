@@ -7,12 +7,14 @@
 // Tests with the test vectors from the spec.
 #![deny(clippy::pedantic)]
 
-use neqo_common::{Datagram, Encoder};
+use neqo_common::{matches, Datagram, Encoder, Role};
 use neqo_transport::{Connection, FixedConnectionIdManager, QuicVersion, State};
-use test_fixture::{self, loopback, now};
+use qlog::{EventData, EventField};
+use test_fixture::{self, loopback, now, replay::Trace};
 
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::Duration;
 
 const INITIAL_PACKET_27: &str = "c0ff00001b088394c8f03e5157080000\
                               449e3b343aa8535064a4268a0d9d7b1c\
@@ -172,7 +174,7 @@ fn make_server(quic_version: QuicVersion) -> Connection {
         test_fixture::DEFAULT_KEYS,
         test_fixture::DEFAULT_ALPN,
         &test_fixture::anti_replay(),
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(5).unwrap())),
         quic_version,
     )
     .expect("create a default server")
@@ -198,3 +200,57 @@ fn process_client_initial_27() {
 fn process_client_initial_29() {
     process_client_initial(QuicVersion::Draft29, &INITIAL_PACKET_29);
 }
+
+#[test]
+fn replay_client_initial() {
+    let initial: Vec<u8> = Encoder::from_hex(INITIAL_PACKET_29).into();
+    let trace = Trace::parse(&format!(
+        "# The client Initial, then the same again.\n\
+         0 {}\n\
+         12.5 {}\n",
+        INITIAL_PACKET_29, INITIAL_PACKET_29
+    ));
+
+    let mut server = make_server(QuicVersion::Draft29);
+    let steps = trace.replay(&mut server);
+    assert_eq!(*server.state(), State::Handshaking);
+    assert_eq!(steps[0].input, Some(0));
+    assert_eq!(steps[0].time, Duration::from_millis(0));
+    assert!(!steps[0].output.is_empty());
+    let last = steps.last().unwrap();
+    assert_eq!(last.input, Some(1));
+    assert_eq!(last.time, Duration::from_micros(12_500));
+
+    // The replay as qlog starts with the first datagram and what was sent in response.
+    let qlog = trace.qlog(Role::Server, &steps);
+    let received = &qlog.events[0];
+    assert!(matches!(&received[0], EventField::RelativeTime(t) if t == "0"));
+    if let EventField::Data(EventData::DatagramsReceived { byte_length, .. }) = &received[3] {
+        assert_eq!(*byte_length, Some(initial.len() as u64));
+    } else {
+        panic!("expected datagrams_received first");
+    }
+    assert!(matches!(
+        qlog.events[1][3],
+        EventField::Data(EventData::DatagramsSent { .. })
+    ));
+
+    // Nothing more comes from the client, so if the replay runs for
+    // longer, the server retransmits when its timer goes off.
+    let mut longer = Trace::new();
+    longer
+        .push(Duration::from_millis(0), &initial)
+        .until(Duration::from_secs(1));
+    let steps = longer.replay(&mut make_server(QuicVersion::Draft29));
+    assert!(steps
+        .iter()
+        .any(|s| s.input.is_none() && !s.output.is_empty()));
+
+    // A replay runs the same way each time.
+    let again = longer.replay(&mut make_server(QuicVersion::Draft29));
+    assert_eq!(steps.len(), again.len());
+    for (a, b) in steps.iter().zip(&again) {
+        assert_eq!(a.time, b.time);
+        assert_eq!(a.output.len(), b.output.len());
+    }
+}
@@ -43,3 +43,35 @@ fn truncate_long_packet() {
     assert!(dgram.is_some());
     assert!(server.state().connected());
 }
+
+#[test]
+fn prelude_stream() {
+    use neqo_transport::prelude::*;
+
+    let (mut client, mut server) = test_fixture::connect();
+    let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+    assert_eq!(client.stream_send(stream_id, b"hello").unwrap(), 5);
+    client.stream_close_send(stream_id).unwrap();
+    let dgram = client.process(None, now()).dgram();
+    server.process_input(dgram.unwrap(), now());
+
+    let mut new_stream = false;
+    let mut buf = [0; 16];
+    while let Some(event) = server.next_event() {
+        match event {
+            ConnectionEvent::NewStream { stream_id: id } => {
+                assert_eq!(id, StreamId::from(stream_id));
+                new_stream = true;
+            }
+            ConnectionEvent::RecvStreamReadable { stream_id: id } => {
+                assert_eq!(server.stream_recv(id, &mut buf).unwrap(), (5, true));
+                assert_eq!(&buf[..5], b"hello");
+            }
+            _ => {}
+        }
+    }
+    assert!(new_stream);
+    assert_eq!(*server.state(), State::Confirmed);
+    let stats: &Stats = server.stats();
+    assert!(stats.packets_rx > 0);
+}
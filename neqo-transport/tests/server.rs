@@ -11,14 +11,14 @@ use neqo_common::{hex_with_len, matches, qdebug, qtrace, Datagram, Decoder, Enco
 use neqo_crypto::{
     aead::Aead,
     constants::{TLS_AES_128_GCM_SHA256, TLS_VERSION_1_3},
-    hkdf,
+    ech, hkdf,
     hp::HpKey,
     AuthenticationStatus,
 };
 use neqo_transport::{
     server::{ActiveConnectionRef, Server},
-    Connection, ConnectionError, Error, FixedConnectionIdManager, Output, QuicVersion, State,
-    StreamType,
+    Connection, ConnectionError, Error, FixedConnectionIdManager, Output,
+    QuicLbConnectionIdManager, QuicVersion, State, StreamType, ZeroRttState,
 };
 use test_fixture::{self, assertions, default_client, now};
 
@@ -36,7 +36,7 @@ fn default_server() -> Server {
         test_fixture::DEFAULT_KEYS,
         test_fixture::DEFAULT_ALPN,
         test_fixture::anti_replay(),
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(9))),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(9).unwrap())),
     )
     .expect("should create a server")
 }
@@ -112,6 +112,49 @@ fn single_client() {
     connect(&mut client, &mut server);
 }
 
+#[test]
+fn encrypted_client_hello() {
+    let mut server = default_server();
+    let (sk, pk) = ech::generate_keys().expect("should make ECH keys");
+    server
+        .enable_ech(1, "public.example", sk, pk)
+        .expect("should enable ECH");
+    let mut client = default_client();
+    client
+        .client_enable_ech(server.ech_config())
+        .expect("should enable ECH");
+
+    let server_instance = complete_connection(&mut client, &mut server, None);
+    assert!(client.tls_info().unwrap().ech_accepted());
+    assert!(server_instance.borrow().tls_info().unwrap().ech_accepted());
+}
+
+#[test]
+fn quic_lb_connection_ids() {
+    let mut server = Server::new(
+        now(),
+        test_fixture::DEFAULT_KEYS,
+        test_fixture::DEFAULT_ALPN,
+        test_fixture::anti_replay(),
+        Rc::new(RefCell::new(QuicLbConnectionIdManager::new(0, &[1, 2], 8))),
+    )
+    .expect("should create a server");
+    let mut client = default_client();
+    let mut server_conn = connect(&mut client, &mut server);
+
+    // Data sent on the established connection is routed to it.
+    let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+    client.stream_send(stream_id, &[1, 2, 3]).unwrap();
+    let dgram = client.process(None, now()).dgram();
+    server.process(dgram, now());
+    let mut buf = [0; 3];
+    let (len, _) = server_conn
+        .borrow_mut()
+        .stream_recv(stream_id, &mut buf)
+        .unwrap();
+    assert_eq!(len, 3);
+}
+
 #[test]
 fn duplicate_initial() {
     let mut server = default_server();
@@ -251,6 +294,122 @@ fn retry_basic() {
     connected_server(&mut server);
 }
 
+const SERVER_KEY: &[u8] = &[0x5e; 32];
+
+fn keyed_server() -> Server {
+    let mut server = default_server();
+    server.set_server_key(SERVER_KEY).unwrap();
+    server
+}
+
+// A Retry token from one server is accepted by another with the same key.
+#[test]
+fn retry_shared_key() {
+    let mut server = keyed_server();
+    server.set_retry_required(true);
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(&dgram.as_ref().unwrap());
+
+    let mut other_server = keyed_server();
+    other_server.set_retry_required(true);
+    let dgram = client.process(dgram, now()).dgram(); // Initial w/token
+    let dgram = other_server.process(dgram, now()).dgram(); // Initial, HS
+    assert!(dgram.is_some());
+    complete_connection(&mut client, &mut other_server, dgram);
+}
+
+#[test]
+fn retry_different_key() {
+    let mut server = default_server();
+    server.set_retry_required(true);
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram(); // Initial
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(&dgram.as_ref().unwrap());
+
+    let mut other_server = keyed_server();
+    other_server.set_retry_required(true);
+    let dgram = client.process(dgram, now()).dgram(); // Initial w/token
+    let dgram = other_server.process(dgram, now()).dgram();
+    assert!(dgram.is_none()); // The token is invalid.
+}
+
+// A token from a NEW_TOKEN frame means that the client doesn't need a Retry,
+// even from a server that restarted with the same key.
+#[test]
+fn new_token_skips_retry() {
+    let mut server = keyed_server();
+    let mut client = default_client();
+    complete_connection(&mut client, &mut server, None);
+    let token = client.new_token().expect("should get a token").to_vec();
+
+    let mut server = keyed_server();
+    server.set_retry_required(true);
+    let mut client = default_client();
+    client.set_initial_token(token).unwrap();
+    let dgram = client.process(None, now()).dgram(); // Initial w/token
+    let dgram = server.process(dgram, now()).dgram(); // Initial, HS
+    assertions::assert_initial(dgram.as_ref().unwrap());
+    complete_connection(&mut client, &mut server, dgram);
+}
+
+// A NEW_TOKEN token that a server can't use is ignored, not rejected.
+#[test]
+fn new_token_different_key() {
+    let mut server = default_server();
+    let mut client = default_client();
+    complete_connection(&mut client, &mut server, None);
+    let token = client.new_token().expect("should get a token").to_vec();
+
+    let mut server = keyed_server();
+    server.set_retry_required(true);
+    let mut client = default_client();
+    client.set_initial_token(token).unwrap();
+    let dgram = client.process(None, now()).dgram(); // Initial w/token
+    let dgram = server.process(dgram, now()).dgram(); // Retry
+    assertions::assert_retry(dgram.as_ref().unwrap());
+}
+
+// A server that restarts with the same key resets connections it has lost.
+#[test]
+fn stateless_reset_after_restart() {
+    let mut server = keyed_server();
+    let mut client = default_client();
+    connect(&mut client, &mut server);
+
+    let mut server = keyed_server();
+    let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+    client.stream_send(stream_id, &[1, 2, 3]).unwrap();
+    let dgram = client.process(None, now()).dgram();
+    let reset = server.process(dgram, now()).dgram();
+    assert!(reset.is_some());
+    client.process_input(reset.unwrap(), now());
+    assert!(matches!(
+        client.state(),
+        State::Draining {
+            error: ConnectionError::Transport(Error::StatelessReset),
+            ..
+        }
+    ));
+}
+
+#[test]
+fn no_stateless_reset_without_key() {
+    let mut server = default_server();
+    let mut client = default_client();
+    connect(&mut client, &mut server);
+
+    let mut server = default_server();
+    let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+    client.stream_send(stream_id, &[1, 2, 3]).unwrap();
+    let dgram = client.process(None, now()).dgram();
+    assert!(server.process(dgram, now()).dgram().is_none());
+}
+
 // attempt a retry with 0-RTT, and have 0-RTT packets sent with the second ClientHello
 #[test]
 fn retry_0rtt() {
@@ -302,6 +461,40 @@ fn retry_0rtt() {
     assert!(client.tls_info().unwrap().resumed());
 }
 
+#[test]
+fn zero_rtt_disabled() {
+    let mut server = default_server();
+    let mut client = default_client();
+
+    let mut server_conn = connect(&mut client, &mut server);
+    server_conn
+        .borrow_mut()
+        .send_ticket(now(), &[])
+        .expect("ticket should go out");
+    let dgram = server.process(None, now()).dgram();
+    client.process_input(dgram.unwrap(), now()); // Consume ticket, ignore output.
+    let token = client.resumption_token().expect("should get token");
+    assert_eq!(server.active_connections().len(), 1);
+
+    // The ticket allows 0-RTT, but the server no longer accepts it.
+    server.set_allow_0rtt(false);
+    let mut client = default_client();
+    client
+        .set_resumption_token(now(), &token)
+        .expect("should set token");
+
+    let client_stream = client.stream_create(StreamType::UniDi).unwrap();
+    client.stream_send(client_stream, &[1, 2, 3]).unwrap();
+
+    let dgram = client.process(None, now()).dgram(); // Initial w/0-RTT
+    assert!(dgram.is_some());
+    assertions::assert_coalesced_0rtt(dgram.as_ref().unwrap());
+
+    complete_connection(&mut client, &mut server, dgram);
+    assert_eq!(*client.zero_rtt_state(), ZeroRttState::Rejected);
+    assert!(client.tls_info().unwrap().resumed());
+}
+
 #[test]
 fn retry_different_ip() {
     let mut server = default_server();
@@ -632,7 +825,7 @@ fn mitm_retry() {
     assert!(matches!(
         *client.state(),
         State::Closing{
-            error: ConnectionError::Transport(Error::ProtocolViolation),
+            error: ConnectionError::Transport(Error::TransportParameterError),
             ..
         }
     ));
@@ -754,6 +947,121 @@ fn version_negotiation() {
     assert_eq!(client.state(), &State::WaitInitial);
 }
 
+#[test]
+fn version_negotiation_limited() {
+    let mut server = default_server();
+    server.set_stateless_response_limit(2, Duration::from_secs(1));
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+    let mut input = dgram.to_vec();
+    input[1] ^= 0x12;
+    let damaged = |src| Datagram::new(src, dgram.destination(), input.clone());
+
+    // Only two Version Negotiation packets go to the client at once.
+    for _ in 0..2 {
+        assert!(server
+            .process(Some(damaged(dgram.source())), now())
+            .dgram()
+            .is_some());
+    }
+    assert!(server
+        .process(Some(damaged(dgram.source())), now())
+        .dgram()
+        .is_none());
+
+    // Another address isn't affected.
+    let other = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 443);
+    assert!(server
+        .process(Some(damaged(other)), now())
+        .dgram()
+        .is_some());
+
+    // The client can get another after a while.
+    let later = now() + Duration::from_secs(1);
+    assert!(server
+        .process(Some(damaged(dgram.source())), later)
+        .dgram()
+        .is_some());
+    assert!(server
+        .process(Some(damaged(dgram.source())), later)
+        .dgram()
+        .is_none());
+}
+
+#[test]
+fn shutdown() {
+    let mut server = default_server();
+    let mut client = default_client();
+    connect(&mut client, &mut server);
+
+    server.shutdown(now(), Duration::from_secs(1), 0);
+    assert!(!server.shutdown_complete());
+
+    // New connections are refused.
+    let mut refused = default_client();
+    let dgram = refused.process(None, now()).dgram();
+    assert!(dgram.is_some());
+    assert!(server.process(dgram, now()).dgram().is_none());
+
+    // The connection is closed once the grace period ends.
+    let mut t = now() + Duration::from_secs(1);
+    while let Some(d) = server.process(None, t).dgram() {
+        client.process_input(d, t);
+    }
+    assert!(matches!(
+        client.state(),
+        State::Draining {
+            error: ConnectionError::Application(0),
+            ..
+        }
+    ));
+
+    // The shutdown is complete once the server connection is closed.
+    while !server.shutdown_complete() {
+        match server.process(None, t) {
+            Output::Datagram(_) => {}
+            Output::Callback(d) => t += d,
+            Output::None => panic!("the connection is still open"),
+        }
+    }
+    assert_eq!(server.process(None, t), Output::None);
+}
+
+#[test]
+fn version_negotiation_limit_full() {
+    let mut server = default_server();
+    server.set_stateless_response_limit(1, Duration::from_secs(3600));
+    let mut client = default_client();
+
+    let dgram = client.process(None, now()).dgram().expect("a datagram");
+    let mut input = dgram.to_vec();
+    input[1] ^= 0x12;
+    let addr = |i: u16| {
+        let [a, b] = i.to_be_bytes();
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, a, b)), 443)
+    };
+    let mut send = |i: u16, t: u64| {
+        let d = Datagram::new(addr(i), dgram.destination(), input.clone());
+        server
+            .process(Some(d), now() + Duration::from_millis(t))
+            .dgram()
+            .is_some()
+    };
+
+    // Fill the table, which counts 4096 addresses.
+    for i in 0..4096 {
+        assert!(send(i, u64::from(i)));
+    }
+    assert!(!send(4095, 4096));
+
+    // A new address still gets a response and the oldest address is forgotten.
+    assert!(send(4096, 4097));
+    assert!(send(0, 4098));
+    assert!(!send(4095, 4099));
+    assert!(!send(4096, 4100));
+}
+
 #[test]
 fn closed() {
     // Let a server connection idle and it should be removed.
@@ -0,0 +1,127 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::pedantic)]
+
+use neqo_transport::State;
+use std::time::Duration;
+use test_fixture::{
+    boxed,
+    sim::{ConnectionNode, Delay, Drop, ReachState, ReceiveData, SendData, Simulator, TailDrop},
+};
+
+const DELAY: Duration = Duration::from_millis(50);
+const TRANSFER_AMOUNT: usize = 1 << 18;
+
+fn transfer(name: &str) -> Simulator {
+    Simulator::new(
+        name,
+        boxed![
+            ConnectionNode::default_client(boxed![SendData::new(TRANSFER_AMOUNT)]),
+            Delay::fixed(DELAY),
+            ConnectionNode::default_server(boxed![ReceiveData::new(TRANSFER_AMOUNT)]),
+            Delay::fixed(DELAY),
+        ],
+    )
+}
+
+#[test]
+fn connect_direct() {
+    let sim = Simulator::new(
+        "connect_direct",
+        boxed![
+            ConnectionNode::default_client(boxed![ReachState::new(State::Confirmed)]),
+            ConnectionNode::default_server(boxed![ReachState::new(State::Confirmed)]),
+        ],
+    );
+    sim.run();
+}
+
+#[test]
+fn connect_delayed() {
+    let sim = Simulator::new(
+        "connect_delayed",
+        boxed![
+            ConnectionNode::default_client(boxed![ReachState::new(State::Confirmed)]),
+            Delay::fixed(DELAY),
+            ConnectionNode::default_server(boxed![ReachState::new(State::Confirmed)]),
+            Delay::fixed(DELAY),
+        ],
+    );
+    // The client is confirmed after two round trips.
+    assert!(sim.run() >= DELAY * 4);
+}
+
+#[test]
+fn transfer_delayed() {
+    transfer("transfer_delayed").run();
+}
+
+#[test]
+fn transfer_lossy() {
+    let sim = Simulator::new(
+        "transfer_lossy",
+        boxed![
+            ConnectionNode::default_client(boxed![SendData::new(TRANSFER_AMOUNT)]),
+            Drop::percentage(5),
+            Delay::fixed(DELAY),
+            ConnectionNode::default_server(boxed![ReceiveData::new(TRANSFER_AMOUNT)]),
+            Drop::percentage(5),
+            Delay::fixed(DELAY),
+        ],
+    );
+    sim.run();
+}
+
+#[test]
+fn transfer_reordered() {
+    let sim = Simulator::new(
+        "transfer_reordered",
+        boxed![
+            ConnectionNode::default_client(boxed![SendData::new(TRANSFER_AMOUNT)]),
+            Delay::new(DELAY..DELAY * 2),
+            ConnectionNode::default_server(boxed![ReceiveData::new(TRANSFER_AMOUNT)]),
+            Delay::new(DELAY..DELAY * 2),
+        ],
+    );
+    sim.run();
+}
+
+#[test]
+fn transfer_taildrop() {
+    let sim = Simulator::new(
+        "transfer_taildrop",
+        boxed![
+            ConnectionNode::default_client(boxed![SendData::new(TRANSFER_AMOUNT)]),
+            TailDrop::new(1_000_000, 32_768, DELAY),
+            ConnectionNode::default_server(boxed![ReceiveData::new(TRANSFER_AMOUNT)]),
+            TailDrop::new(1_000_000, 32_768, DELAY),
+        ],
+    );
+    // The link can't carry the data any faster than its rate allows.
+    assert!(sim.run() > Duration::from_millis(250));
+}
+
+/// With the same seed, the same thing happens.
+#[test]
+fn repeatable() {
+    let run = |seed| {
+        let mut sim = Simulator::new(
+            "repeatable",
+            boxed![
+                ConnectionNode::default_client(boxed![SendData::new(TRANSFER_AMOUNT)]),
+                Drop::percentage(10),
+                Delay::new(DELAY..DELAY * 2),
+                ConnectionNode::default_server(boxed![ReceiveData::new(TRANSFER_AMOUNT)]),
+                Delay::new(DELAY..DELAY * 2),
+            ],
+        );
+        sim.seed(seed);
+        sim.run()
+    };
+    assert_eq!(run(1), run(1));
+}
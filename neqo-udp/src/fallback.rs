@@ -0,0 +1,61 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// One datagram per call, using only what the standard library offers.
+
+use neqo_common::{qwarn, Datagram};
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::RecvBuf;
+
+pub const BATCH_SIZE: usize = 1;
+
+pub struct State {
+    connected: bool,
+}
+
+pub fn configure(_socket: &UdpSocket, _local_addr: &SocketAddr, connected: bool) -> State {
+    State { connected }
+}
+
+pub fn send(socket: &UdpSocket, state: &State, dgrams: &[Datagram]) -> io::Result<usize> {
+    let mut count = 0;
+    for d in dgrams {
+        let res = if state.connected {
+            socket.send(d)
+        } else {
+            socket.send_to(d, d.destination())
+        };
+        match res {
+            Ok(sent) => {
+                if sent != d.len() {
+                    qwarn!("Unable to send all {} bytes of datagram", d.len());
+                }
+                count += 1;
+            }
+            Err(e) if count == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(count)
+}
+
+pub fn recv(
+    socket: &UdpSocket,
+    _state: &State,
+    local_addr: SocketAddr,
+    buf: &mut RecvBuf,
+) -> io::Result<Vec<Datagram>> {
+    let buf = &mut buf.bufs[0];
+    let (sz, remote_addr) = socket.recv_from(&mut buf[..])?;
+    if sz == buf.len() {
+        qwarn!("Discarding datagram that might be truncated");
+        return Ok(Vec::new());
+    }
+    Ok(vec![Datagram::new(remote_addr, local_addr, &buf[..sz])])
+}
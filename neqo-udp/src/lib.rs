@@ -0,0 +1,266 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::pedantic)]
+
+// UDP I/O for the client and server programs.
+//
+// On Linux, datagrams are sent with sendmmsg and received with recvmmsg, a
+// batch at a time.  Runs of datagrams that go to the same place are sent as
+// one UDP GSO (generic segmentation offload) message and the kernel is asked
+// to coalesce received datagrams with UDP GRO, where the kernel supports it.
+// The TOS byte (which carries ECN), the TTL and the destination address of
+// each received datagram are read from ancillary data.
+//
+// Other platforms send and receive one datagram per call and don't get any
+// of the ancillary data.
+
+#[cfg(not(target_os = "linux"))]
+mod fallback;
+#[cfg(target_os = "linux")]
+mod linux;
+
+#[cfg(not(target_os = "linux"))]
+use fallback as sys;
+#[cfg(target_os = "linux")]
+use linux as sys;
+
+use neqo_common::Datagram;
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// The largest UDP payload that is possible.  With GRO, a single read can
+/// return this much, which is then split into the original datagrams.
+pub const MAX_RECV_SIZE: usize = 0xffff;
+/// An upper bound on the size of a GSO message, which leaves some room for
+/// the IP and UDP headers.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const MAX_GSO_SIZE: usize = 0xffff - 48;
+
+/// Buffers for receiving datagrams.  These are big, so make one and keep it.
+pub struct RecvBuf {
+    bufs: Vec<Vec<u8>>,
+}
+
+impl RecvBuf {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            bufs: vec![vec![0; MAX_RECV_SIZE]; sys::BATCH_SIZE],
+        }
+    }
+}
+
+impl Default for RecvBuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The number of datagrams at the start of `dgrams` that can be sent as one
+/// GSO message.  These need to have the same addresses and TOS and the same
+/// size, though the last can be shorter than the others.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn gso_run(dgrams: &[Datagram], max_segments: usize) -> usize {
+    let first = match dgrams.first() {
+        Some(d) => d,
+        None => return 0,
+    };
+    let size = first.len();
+    let mut total = size;
+    let mut count = 1;
+    for d in &dgrams[1..] {
+        if count >= max_segments
+            || d.len() > size
+            || total + d.len() > MAX_GSO_SIZE
+            || d.source() != first.source()
+            || d.destination() != first.destination()
+            || d.tos() != first.tos()
+        {
+            break;
+        }
+        count += 1;
+        total += d.len();
+        if d.len() < size {
+            break;
+        }
+    }
+    count
+}
+
+/// A UDP socket that sends and receives datagrams in batches.
+pub struct Socket {
+    socket: UdpSocket,
+    local_addr: SocketAddr,
+    state: sys::State,
+}
+
+impl Socket {
+    /// Bind a new socket to `addr`.
+    ///
+    /// # Errors
+    ///
+    /// When the socket can't be created or bound.
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::new(UdpSocket::bind(addr)?)
+    }
+
+    /// Use a socket that is already bound, and maybe connected.  Any
+    /// changes to the socket, like calling `connect`, need to happen before
+    /// this as the local address is only read once.
+    ///
+    /// # Errors
+    ///
+    /// When the local address of the socket can't be read.
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        let local_addr = socket.local_addr()?;
+        let connected = socket.peer_addr().is_ok();
+        let state = sys::configure(&socket, &local_addr, connected);
+        Ok(Self {
+            socket,
+            local_addr,
+            state,
+        })
+    }
+
+    /// The underlying socket, for setting timeouts and the like.
+    #[must_use]
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    #[must_use]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Send as many of `dgrams` as possible, in order.  All of them
+    /// are sent from this socket, whatever their source address; on Linux
+    /// the source address is used to pick the local address of a socket that
+    /// is bound to an unspecified address.
+    ///
+    /// This returns the number of datagrams that were sent, which can be
+    /// less than the number that were provided.
+    ///
+    /// # Errors
+    ///
+    /// When nothing could be sent, including `WouldBlock` for a
+    /// non-blocking socket.
+    pub fn send(&self, dgrams: &[Datagram]) -> io::Result<usize> {
+        if dgrams.is_empty() {
+            return Ok(0);
+        }
+        sys::send(&self.socket, &self.state, dgrams)
+    }
+
+    /// Receive a batch of datagrams, waiting for the first if the socket is
+    /// blocking.  This can return an empty list if the only datagrams that
+    /// arrived were truncated.
+    ///
+    /// # Errors
+    ///
+    /// When nothing could be received, including `WouldBlock` for a
+    /// non-blocking socket.
+    pub fn recv(&self, buf: &mut RecvBuf) -> io::Result<Vec<Datagram>> {
+        sys::recv(&self.socket, &self.state, self.local_addr, buf)
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl ::std::fmt::Debug for Socket {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Socket {}", self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{gso_run, RecvBuf, Socket, MAX_GSO_SIZE};
+    use neqo_common::Datagram;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn dgram(dst: u16, len: usize) -> Datagram {
+        Datagram::new(addr(1), addr(dst), vec![0; len])
+    }
+
+    #[test]
+    fn runs() {
+        let dgrams = [
+            dgram(2, 100),
+            dgram(2, 100),
+            dgram(2, 50),
+            // After a short datagram, a new run starts.
+            dgram(2, 100),
+            // As it does for a new destination.
+            dgram(3, 100),
+            // Or when a datagram is bigger than the first.
+            dgram(3, 120),
+        ];
+        assert_eq!(gso_run(&dgrams, 64), 3);
+        assert_eq!(gso_run(&dgrams[3..], 64), 1);
+        assert_eq!(gso_run(&dgrams[4..], 64), 1);
+        assert_eq!(gso_run(&dgrams[5..], 64), 1);
+        assert_eq!(gso_run(&[], 64), 0);
+
+        // No GSO if only one segment is allowed.
+        assert_eq!(gso_run(&dgrams, 1), 1);
+        assert_eq!(gso_run(&dgrams, 2), 2);
+
+        let big = vec![dgram(2, 1500); 64];
+        assert_eq!(gso_run(&big, 64), MAX_GSO_SIZE / 1500);
+    }
+
+    #[test]
+    fn send_recv() {
+        let rx = Socket::bind(addr(0)).unwrap();
+        rx.socket()
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let tx = Socket::bind(addr(0)).unwrap();
+
+        let sizes = [1200, 1200, 1200, 1000, 20];
+        let sent: Vec<_> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| {
+                #[allow(clippy::cast_possible_truncation)]
+                let d = vec![i as u8; len];
+                Datagram::new(tx.local_addr(), rx.local_addr(), d)
+            })
+            .collect();
+        let mut count = 0;
+        while count < sent.len() {
+            count += tx.send(&sent[count..]).unwrap();
+        }
+
+        let mut buf = RecvBuf::new();
+        let mut received = Vec::new();
+        while received.len() < sent.len() {
+            received.extend(rx.recv(&mut buf).unwrap());
+        }
+        assert_eq!(received.len(), sent.len());
+        for (r, s) in received.iter().zip(&sent) {
+            assert_eq!(r.source(), tx.local_addr());
+            assert_eq!(r.destination(), rx.local_addr());
+            assert_eq!(&r[..], &s[..]);
+        }
+    }
+}
@@ -0,0 +1,406 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Batched I/O with sendmmsg and recvmmsg, using GSO, GRO and ancillary data.
+
+// The types in libc differ between glibc and musl, so there are lots of casts.
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss,
+    clippy::cast_ptr_alignment
+)]
+
+use neqo_common::{qinfo, qwarn, Datagram, IPTOS_DEFAULT};
+
+use libc::{c_int, c_void, cmsghdr, iovec, mmsghdr, msghdr, sockaddr_storage, socklen_t};
+use std::cell::Cell;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use crate::{gso_run, RecvBuf};
+
+/// The number of messages in each call to sendmmsg or recvmmsg.
+pub const BATCH_SIZE: usize = 32;
+/// The kernel doesn't take more segments than this in one GSO message.
+const MAX_GSO_SEGMENTS: usize = 64;
+
+// These aren't in all versions of libc.
+const UDP_SEGMENT: c_int = 103;
+const UDP_GRO: c_int = 104;
+
+/// Space for the control messages of one datagram, in words so that it is
+/// aligned properly for `cmsghdr`.
+const CONTROL_WORDS: usize = 16;
+type ControlBuf = [u64; CONTROL_WORDS];
+
+pub struct State {
+    connected: bool,
+    v6: bool,
+    /// Whether the destination address is read from `IP_PKTINFO` or
+    /// `IPV6_PKTINFO`, which is only needed for an unspecified address.
+    pktinfo: bool,
+    /// GSO is turned off if the kernel turns out not to support it.
+    gso: Cell<bool>,
+}
+
+fn setsockopt(socket: &UdpSocket, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    let rv = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const c_int as *const c_void,
+            mem::size_of::<c_int>() as socklen_t,
+        )
+    };
+    if rv == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn gso_supported(socket: &UdpSocket) -> bool {
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+    let rv = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_UDP,
+            UDP_SEGMENT,
+            &mut value as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    rv == 0
+}
+
+pub fn configure(socket: &UdpSocket, local_addr: &SocketAddr, connected: bool) -> State {
+    // Any of these can fail, which only means that the information is missing.
+    let v6 = local_addr.is_ipv6();
+    // An IPv6 socket can also receive IPv4, which uses the IPv4 options.
+    let _ = setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1);
+    let _ = setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVTTL, 1);
+    let mut pktinfo = local_addr.ip().is_unspecified();
+    if v6 {
+        let _ = setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1);
+        let _ = setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1);
+        pktinfo =
+            pktinfo && setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1).is_ok();
+    } else {
+        pktinfo = pktinfo && setsockopt(socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1).is_ok();
+    }
+    let gro = setsockopt(socket, libc::IPPROTO_UDP, UDP_GRO, 1).is_ok();
+    let gso = gso_supported(socket);
+    qinfo!(
+        "UDP socket {}: GSO {}, GRO {}, packet info {}",
+        local_addr,
+        gso,
+        gro,
+        pktinfo
+    );
+    State {
+        connected,
+        v6,
+        pktinfo,
+        gso: Cell::new(gso),
+    }
+}
+
+/// Whether this is an IPv4 address, or an IPv4-mapped IPv6 address.
+fn is_v4(addr: &SocketAddr) -> bool {
+    match addr {
+        SocketAddr::V4(..) => true,
+        SocketAddr::V6(a) => {
+            let s = a.ip().segments();
+            s[..5] == [0; 5] && s[5] == 0xffff
+        }
+    }
+}
+
+fn encode_sockaddr(addr: &SocketAddr, storage: &mut sockaddr_storage) -> socklen_t {
+    match addr {
+        SocketAddr::V4(a) => {
+            let sin = unsafe { &mut *(storage as *mut sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+            mem::size_of::<libc::sockaddr_in>() as socklen_t
+        }
+        SocketAddr::V6(a) => {
+            let sin6 =
+                unsafe { &mut *(storage as *mut sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as socklen_t
+        }
+    }
+}
+
+fn decode_sockaddr(storage: &sockaddr_storage) -> Option<SocketAddr> {
+    match c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const sockaddr_storage as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 =
+                unsafe { &*(storage as *const sockaddr_storage as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Writes control messages to the control buffer of a `msghdr`.
+struct CmsgWriter {
+    hdr: *mut msghdr,
+    cmsg: *mut cmsghdr,
+    len: usize,
+}
+
+impl CmsgWriter {
+    /// `hdr` has to point to a zeroed `ControlBuf`.
+    unsafe fn new(hdr: *mut msghdr) -> Self {
+        (*hdr).msg_controllen = mem::size_of::<ControlBuf>() as _;
+        Self {
+            hdr,
+            cmsg: libc::CMSG_FIRSTHDR(hdr),
+            len: 0,
+        }
+    }
+
+    unsafe fn push<T: Copy>(&mut self, level: c_int, ty: c_int, value: T) {
+        let space = libc::CMSG_SPACE(mem::size_of::<T>() as _) as usize;
+        assert!(!self.cmsg.is_null() && self.len + space <= mem::size_of::<ControlBuf>());
+        (*self.cmsg).cmsg_level = level;
+        (*self.cmsg).cmsg_type = ty;
+        (*self.cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<T>() as _) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(self.cmsg) as *mut T, value);
+        self.len += space;
+        self.cmsg = libc::CMSG_NXTHDR(self.hdr, self.cmsg);
+    }
+
+    unsafe fn finish(self) {
+        (*self.hdr).msg_controllen = self.len as _;
+        if self.len == 0 {
+            (*self.hdr).msg_control = ptr::null_mut();
+        }
+    }
+}
+
+/// The information about a received datagram that comes from control messages.
+#[derive(Default)]
+struct RecvMeta {
+    tos: u8,
+    ttl: Option<u8>,
+    dst: Option<IpAddr>,
+    segment_size: Option<usize>,
+}
+
+unsafe fn read_cmsgs(hdr: &msghdr) -> RecvMeta {
+    unsafe fn read<T: Copy>(data: *const u8) -> T {
+        ptr::read_unaligned(data as *const T)
+    }
+
+    let mut meta = RecvMeta::default();
+    let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+    while !cmsg.is_null() {
+        let data = libc::CMSG_DATA(cmsg);
+        match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+            // This is the only one that is a single byte.
+            (libc::IPPROTO_IP, libc::IP_TOS) => meta.tos = *data,
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => meta.tos = read::<c_int>(data) as u8,
+            (libc::IPPROTO_IP, libc::IP_TTL) | (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                meta.ttl = Some(read::<c_int>(data) as u8);
+            }
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = read::<libc::in_pktinfo>(data);
+                meta.dst = Some(IpAddr::V4(Ipv4Addr::from(
+                    info.ipi_addr.s_addr.to_ne_bytes(),
+                )));
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info = read::<libc::in6_pktinfo>(data);
+                meta.dst = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+            }
+            (libc::IPPROTO_UDP, UDP_GRO) => {
+                meta.segment_size = Some(read::<c_int>(data) as usize);
+            }
+            _ => {}
+        }
+        cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+    }
+    meta
+}
+
+pub fn send(socket: &UdpSocket, state: &State, dgrams: &[Datagram]) -> io::Result<usize> {
+    match send_batch(socket, state, dgrams) {
+        // This is what happens if the device can't do the checksum
+        // offload that GSO depends on.
+        Err(ref e) if e.raw_os_error() == Some(libc::EIO) && state.gso.get() => {
+            qinfo!("Disabling GSO for UDP socket after error: {}", e);
+            state.gso.set(false);
+            send_batch(socket, state, dgrams)
+        }
+        res => res,
+    }
+}
+
+fn send_batch(socket: &UdpSocket, state: &State, dgrams: &[Datagram]) -> io::Result<usize> {
+    let max_segments = if state.gso.get() { MAX_GSO_SEGMENTS } else { 1 };
+    let mut runs = Vec::with_capacity(BATCH_SIZE);
+    let mut end = 0;
+    while runs.len() < BATCH_SIZE && end < dgrams.len() {
+        let count = gso_run(&dgrams[end..], max_segments);
+        runs.push(end..end + count);
+        end += count;
+    }
+    // The kernel doesn't write to these, so they can point at the datagrams.
+    let iovs: Vec<iovec> = dgrams[..end]
+        .iter()
+        .map(|d| iovec {
+            iov_base: d.as_ptr() as *mut c_void,
+            iov_len: d.len(),
+        })
+        .collect();
+
+    let mut hdrs: [mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut names: [sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut controls = [[0_u64; CONTROL_WORDS]; BATCH_SIZE];
+    for (i, run) in runs.iter().enumerate() {
+        let first = &dgrams[run.start];
+        let hdr = &mut hdrs[i].msg_hdr;
+        if !state.connected {
+            hdr.msg_namelen = encode_sockaddr(&first.destination(), &mut names[i]);
+            hdr.msg_name = &mut names[i] as *mut sockaddr_storage as *mut c_void;
+        }
+        hdr.msg_iov = iovs[run.clone()].as_ptr() as *mut iovec;
+        hdr.msg_iovlen = run.len() as _;
+        hdr.msg_control = controls[i].as_mut_ptr() as *mut c_void;
+
+        unsafe {
+            let mut cmsgs = CmsgWriter::new(hdr);
+            if first.tos() != IPTOS_DEFAULT {
+                let tos = c_int::from(first.tos());
+                if is_v4(&first.destination()) {
+                    cmsgs.push(libc::IPPROTO_IP, libc::IP_TOS, tos);
+                } else {
+                    cmsgs.push(libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos);
+                }
+            }
+            if run.len() > 1 {
+                cmsgs.push(libc::IPPROTO_UDP, UDP_SEGMENT, first.len() as u16);
+            }
+            if state.pktinfo {
+                match (state.v6, first.source().ip()) {
+                    (false, IpAddr::V4(ip)) if !ip.is_unspecified() => {
+                        let mut info: libc::in_pktinfo = mem::zeroed();
+                        info.ipi_spec_dst.s_addr = u32::from_ne_bytes(ip.octets());
+                        cmsgs.push(libc::IPPROTO_IP, libc::IP_PKTINFO, info);
+                    }
+                    (true, IpAddr::V6(ip)) if !ip.is_unspecified() => {
+                        let mut info: libc::in6_pktinfo = mem::zeroed();
+                        info.ipi6_addr.s6_addr = ip.octets();
+                        cmsgs.push(libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info);
+                    }
+                    _ => {}
+                }
+            }
+            cmsgs.finish();
+        }
+    }
+
+    let rv = unsafe { libc::sendmmsg(socket.as_raw_fd(), hdrs.as_mut_ptr(), runs.len() as _, 0) };
+    if rv < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(runs[..rv as usize].iter().map(ExactSizeIterator::len).sum())
+}
+
+pub fn recv(
+    socket: &UdpSocket,
+    state: &State,
+    local_addr: SocketAddr,
+    buf: &mut RecvBuf,
+) -> io::Result<Vec<Datagram>> {
+    let mut iovs: Vec<iovec> = buf
+        .bufs
+        .iter_mut()
+        .map(|b| iovec {
+            iov_base: b.as_mut_ptr() as *mut c_void,
+            iov_len: b.len(),
+        })
+        .collect();
+    let mut hdrs: [mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut names: [sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut controls = [[0_u64; CONTROL_WORDS]; BATCH_SIZE];
+    for (i, hdr) in hdrs.iter_mut().enumerate() {
+        let hdr = &mut hdr.msg_hdr;
+        hdr.msg_name = &mut names[i] as *mut sockaddr_storage as *mut c_void;
+        hdr.msg_namelen = mem::size_of::<sockaddr_storage>() as socklen_t;
+        hdr.msg_iov = &mut iovs[i] as *mut iovec;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = controls[i].as_mut_ptr() as *mut c_void;
+        hdr.msg_controllen = mem::size_of::<ControlBuf>() as _;
+    }
+
+    // With MSG_WAITFORONE, this only blocks until the first datagram arrives.
+    let rv = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            hdrs.as_mut_ptr(),
+            BATCH_SIZE as _,
+            libc::MSG_WAITFORONE as _,
+            ptr::null_mut(),
+        )
+    };
+    if rv < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut dgrams = Vec::new();
+    for (i, hdr) in hdrs[..rv as usize].iter().enumerate() {
+        if hdr.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+            qwarn!("Discarding truncated datagram");
+            continue;
+        }
+        let src = match decode_sockaddr(&names[i]) {
+            Some(a) => a,
+            None => continue,
+        };
+        let meta = unsafe { read_cmsgs(&hdr.msg_hdr) };
+        let dst = match meta.dst {
+            Some(ip) if state.pktinfo => SocketAddr::new(ip, local_addr.port()),
+            _ => local_addr,
+        };
+        let data = &buf.bufs[i][..hdr.msg_len as usize];
+        // With GRO, this is several datagrams of the same size, except the last.
+        let segment_size = meta.segment_size.filter(|&s| s > 0).unwrap_or(data.len());
+        if segment_size == 0 {
+            continue;
+        }
+        for d in data.chunks(segment_size) {
+            dgrams.push(Datagram::new_with_tos_ttl(src, dst, meta.tos, meta.ttl, d));
+        }
+    }
+    Ok(dgrams)
+}
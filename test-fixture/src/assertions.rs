@@ -33,3 +33,7 @@ pub fn assert_coalesced_0rtt(payload: &[u8]) {
 pub fn assert_retry(payload: &[u8]) {
     assert_eq!(payload[0] & 0b1111_0000, 0b1111_0000);
 }
+
+pub fn assert_initial(payload: &[u8]) {
+    assert_eq!(payload[0] & 0b1111_0000, 0b1100_0000);
+}
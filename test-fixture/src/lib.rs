@@ -22,6 +22,8 @@ use std::time::{Duration, Instant};
 use lazy_static::lazy_static;
 
 pub mod assertions;
+pub mod replay;
+pub mod sim;
 
 /// The path for the database used in tests.
 pub const NSS_DB_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/db");
@@ -79,7 +81,7 @@ pub fn default_client() -> Connection {
     Connection::new_client(
         DEFAULT_SERVER_NAME,
         DEFAULT_ALPN,
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(3).unwrap())),
         loopback(),
         loopback(),
         QuicVersion::default(),
@@ -96,7 +98,7 @@ pub fn default_server() -> Connection {
         DEFAULT_KEYS,
         DEFAULT_ALPN,
         &anti_replay(),
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(5).unwrap())),
         QuicVersion::default(),
     )
     .expect("create a default server")
@@ -144,7 +146,7 @@ pub fn default_http3_client() -> Http3Client {
     Http3Client::new(
         DEFAULT_SERVER_NAME,
         DEFAULT_ALPN,
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(3).unwrap())),
         loopback(),
         loopback(),
         QpackSettings {
@@ -166,7 +168,7 @@ pub fn default_http3_server() -> Http3Server {
         DEFAULT_KEYS,
         DEFAULT_ALPN,
         anti_replay(),
-        Rc::new(RefCell::new(FixedConnectionIdManager::new(5))),
+        Rc::new(RefCell::new(FixedConnectionIdManager::new(5).unwrap())),
         QpackSettings {
             max_table_size_encoder: 100,
             max_table_size_decoder: 100,
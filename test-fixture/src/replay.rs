@@ -0,0 +1,195 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Replaying recorded datagrams into a connection.
+//
+// A `Trace` lists the datagrams that a connection received and when they
+// arrived, such as those taken from the qlog of an interop failure.  Replaying
+// the trace passes each datagram to a `Connection` at the recorded time and
+// runs any timers that go off in between, so that a bug report from another
+// stack becomes a test that always runs the same way.
+//
+// What happened can be turned into a qlog trace, so that it can be compared
+// with the qlog that the datagrams came from.
+//
+// A connection can only read the packets that it has keys for.  That is always
+// the case for Initial packets, but later packets depend on the keys that were
+// used when the trace was recorded.
+
+use crate::loopback;
+use neqo_common::{qlog::new_trace, qtrace, Datagram, Encoder, Role};
+use neqo_transport::{Connection, ConnectionEvent, Output};
+use qlog::event::Event;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+/// What happened when the connection ran at one point in a replay.
+#[derive(Debug)]
+pub struct Step {
+    /// The time of this step, relative to the start of the trace.
+    pub time: Duration,
+    /// The index of the datagram that was received, or `None` if
+    /// the connection ran because a timer went off.
+    pub input: Option<usize>,
+    /// The datagrams that the connection sent.
+    pub output: Vec<Datagram>,
+    /// The events that the connection produced.
+    pub events: Vec<ConnectionEvent>,
+}
+
+#[derive(Debug, Default)]
+pub struct Trace {
+    datagrams: Vec<(Duration, Vec<u8>)>,
+    end: Duration,
+}
+
+impl Trace {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a trace.  Each line has the time that a datagram arrived, in
+    /// milliseconds since the start of the trace as qlog records it, then the
+    /// datagram in hex.  Empty lines and lines that start with `#` are skipped.
+    ///
+    /// # Panics
+    /// If a line can't be parsed.
+    #[must_use]
+    pub fn parse(s: &str) -> Self {
+        let mut trace = Self::new();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let time = parse_millis(parts.next().unwrap());
+            let dgram = parts
+                .next()
+                .unwrap_or_else(|| panic!("no datagram: {}", line));
+            assert!(parts.next().is_none(), "extra data: {}", line);
+            let dgram: Vec<u8> = Encoder::from_hex(dgram).into();
+            trace.push(time, dgram);
+        }
+        trace
+    }
+
+    /// Add a datagram that arrives at `time`.  Datagrams have to be added in order.
+    ///
+    /// # Panics
+    /// If `time` is before the last datagram.
+    pub fn push(&mut self, time: Duration, dgram: impl AsRef<[u8]>) -> &mut Self {
+        assert!(
+            self.datagrams.last().map_or(true, |(t, _)| *t <= time),
+            "datagrams in a trace have to be in time order"
+        );
+        self.datagrams.push((time, dgram.as_ref().to_vec()));
+        self.end = self.end.max(time);
+        self
+    }
+
+    /// Keep running timers until `time`, even after the last datagram.
+    pub fn until(&mut self, time: Duration) -> &mut Self {
+        self.end = self.end.max(time);
+        self
+    }
+
+    /// Replay the trace into `c`, starting at `crate::now()`.
+    pub fn replay(&self, c: &mut Connection) -> Vec<Step> {
+        let start = crate::now();
+        let mut steps = Vec::new();
+        let mut timer = None;
+        let arrivals = self
+            .datagrams
+            .iter()
+            .enumerate()
+            .map(|(i, (t, d))| (*t, Some((i, d))));
+        for (time, input) in arrivals.chain(Some((self.end, None))) {
+            // Run any timers that go off before the datagram arrives.
+            while let Some(t) = timer.filter(|t| *t <= start + time) {
+                timer = Self::step(c, None, start, t, &mut steps);
+            }
+            if let Some((i, d)) = input {
+                qtrace!("replay datagram {} at {:?}", i, time);
+                let dgram = Datagram::new(loopback(), loopback(), d.clone());
+                timer = Self::step(c, Some((i, dgram)), start, start + time, &mut steps);
+            }
+        }
+        steps
+    }
+
+    /// The steps of a replay as a qlog trace for `role`.  Each datagram that
+    /// arrived is a `datagrams_received` event, and the datagrams that are sent
+    /// in a step are a `datagrams_sent` event, at the time of the step.
+    ///
+    /// # Panics
+    /// If `steps` come from replaying a different trace.
+    #[must_use]
+    pub fn qlog(&self, role: Role, steps: &[Step]) -> qlog::Trace {
+        let mut trace = new_trace(role);
+        for step in steps {
+            if let Some(i) = step.input {
+                let len = u64::try_from(self.datagrams[i].1.len()).unwrap();
+                trace.push_event(step.time, Event::datagrams_received(Some(1), Some(len)));
+            }
+            if !step.output.is_empty() {
+                let count = u64::try_from(step.output.len()).unwrap();
+                let len: usize = step.output.iter().map(|d| d.len()).sum();
+                let len = u64::try_from(len).unwrap();
+                trace.push_event(step.time, Event::datagrams_sent(Some(count), Some(len)));
+            }
+        }
+        trace
+    }
+
+    /// Run the connection once, then return when it next needs to run.
+    fn step(
+        c: &mut Connection,
+        input: Option<(usize, Datagram)>,
+        start: Instant,
+        now: Instant,
+        steps: &mut Vec<Step>,
+    ) -> Option<Instant> {
+        let (index, dgram) = input.map_or((None, None), |(i, d)| (Some(i), Some(d)));
+        let mut output = Vec::new();
+        let mut out = c.process(dgram, now);
+        let timer = loop {
+            match out {
+                Output::Datagram(d) => output.push(d),
+                Output::Callback(delay) => break Some(now + delay),
+                Output::None => break None,
+            }
+            out = c.process_output(now);
+        };
+        let events = std::iter::from_fn(|| c.next_event()).collect();
+        steps.push(Step {
+            time: now - start,
+            input: index,
+            output,
+            events,
+        });
+        timer
+    }
+}
+
+/// Parse a time in milliseconds, which can have a fractional part.
+fn parse_millis(s: &str) -> Duration {
+    let mut parts = s.splitn(2, '.');
+    let whole: u64 = parts
+        .next()
+        .unwrap()
+        .parse()
+        .expect("a time in milliseconds");
+    let frac = parts.next().unwrap_or("");
+    assert!(
+        frac.len() <= 3 && frac.chars().all(|c| c.is_ascii_digit()),
+        "times can't be more precise than microseconds: {}",
+        s
+    );
+    // Pad the fraction out to microseconds.
+    let micros: u64 = format!("{:0<3}", frac).parse().unwrap();
+    Duration::from_millis(whole) + Duration::from_micros(micros)
+}
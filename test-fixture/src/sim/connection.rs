@@ -0,0 +1,224 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{Node, Random};
+use neqo_common::{qdebug, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_transport::{Connection, ConnectionEvent, Output, State, StreamType};
+use std::cmp::min;
+use std::fmt::{self, Debug};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GoalStatus {
+    /// The goal has been reached.
+    Done,
+    /// The goal is still waiting on something.
+    Waiting,
+}
+
+/// Something that a `ConnectionNode` needs to do before the simulation ends.
+pub trait ConnectionGoal: Debug {
+    fn init(&mut self, _c: &mut Connection, _now: Instant) {}
+    /// Do any work, such as sending data.  This is called each time the node runs.
+    fn process(&mut self, _c: &mut Connection, _now: Instant) -> GoalStatus {
+        GoalStatus::Waiting
+    }
+    /// Handle an event from the connection.
+    fn handle_event(
+        &mut self,
+        _c: &mut Connection,
+        _e: &ConnectionEvent,
+        _now: Instant,
+    ) -> GoalStatus {
+        GoalStatus::Waiting
+    }
+}
+
+/// A node that runs a `Connection` until all of its goals are reached.
+/// Authentication of the server certificate is done automatically.
+pub struct ConnectionNode {
+    c: Connection,
+    goals: Vec<Box<dyn ConnectionGoal>>,
+}
+
+impl ConnectionNode {
+    #[must_use]
+    pub fn new(c: Connection, goals: impl IntoIterator<Item = Box<dyn ConnectionGoal>>) -> Self {
+        Self {
+            c,
+            goals: goals.into_iter().collect(),
+        }
+    }
+
+    /// A client, created with `default_client()`.
+    #[must_use]
+    pub fn default_client(goals: impl IntoIterator<Item = Box<dyn ConnectionGoal>>) -> Self {
+        Self::new(crate::default_client(), goals)
+    }
+
+    /// A server, created with `default_server()`.
+    #[must_use]
+    pub fn default_server(goals: impl IntoIterator<Item = Box<dyn ConnectionGoal>>) -> Self {
+        Self::new(crate::default_server(), goals)
+    }
+
+    #[must_use]
+    pub fn connection(&self) -> &Connection {
+        &self.c
+    }
+
+    fn run_goals(
+        &mut self,
+        mut f: impl FnMut(&mut dyn ConnectionGoal, &mut Connection) -> GoalStatus,
+    ) {
+        let mut i = 0;
+        while i < self.goals.len() {
+            if f(&mut *self.goals[i], &mut self.c) == GoalStatus::Done {
+                qdebug!([self.c], "goal done: {:?}", self.goals[i]);
+                self.goals.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+impl Node for ConnectionNode {
+    fn init(&mut self, _rng: Random, now: Instant) {
+        let c = &mut self.c;
+        for g in &mut self.goals {
+            g.init(c, now);
+        }
+    }
+
+    fn process(&mut self, d: Option<Datagram>, now: Instant) -> Output {
+        if let Some(d) = d {
+            self.c.process_input(d, now);
+        }
+        while let Some(e) = self.c.next_event() {
+            if e == ConnectionEvent::AuthenticationNeeded {
+                self.c.authenticated(AuthenticationStatus::Ok, now);
+            }
+            self.run_goals(|g, c| g.handle_event(c, &e, now));
+        }
+        self.run_goals(|g, c| g.process(c, now));
+        self.c.process_output(now)
+    }
+
+    fn done(&self) -> bool {
+        self.goals.is_empty()
+    }
+}
+
+impl Debug for ConnectionNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {:?}", self.c.role(), self.c.state())
+    }
+}
+
+/// Wait until the connection reaches a particular state.
+#[derive(Debug)]
+pub struct ReachState {
+    target: State,
+}
+
+impl ReachState {
+    #[must_use]
+    pub fn new(target: State) -> Self {
+        Self { target }
+    }
+}
+
+impl ConnectionGoal for ReachState {
+    fn process(&mut self, c: &mut Connection, _now: Instant) -> GoalStatus {
+        if *c.state() == self.target {
+            GoalStatus::Done
+        } else {
+            GoalStatus::Waiting
+        }
+    }
+}
+
+/// Send an amount of data on a new unidirectional stream, then close it.
+#[derive(Debug)]
+pub struct SendData {
+    remaining: usize,
+    stream_id: Option<u64>,
+}
+
+impl SendData {
+    #[must_use]
+    pub fn new(amount: usize) -> Self {
+        Self {
+            remaining: amount,
+            stream_id: None,
+        }
+    }
+}
+
+impl ConnectionGoal for SendData {
+    fn process(&mut self, c: &mut Connection, _now: Instant) -> GoalStatus {
+        if self.stream_id.is_none() {
+            if !c.state().connected() {
+                return GoalStatus::Waiting;
+            }
+            self.stream_id = Some(c.stream_create(StreamType::UniDi).unwrap());
+        }
+        let stream_id = self.stream_id.unwrap();
+        let buf = [0x5a; 4096];
+        while self.remaining > 0 {
+            let sent = c
+                .stream_send(stream_id, &buf[..min(self.remaining, buf.len())])
+                .unwrap();
+            if sent == 0 {
+                return GoalStatus::Waiting;
+            }
+            self.remaining -= sent;
+        }
+        c.stream_close_send(stream_id).unwrap();
+        GoalStatus::Done
+    }
+}
+
+/// Receive an amount of data, on any stream.
+#[derive(Debug)]
+pub struct ReceiveData {
+    remaining: usize,
+}
+
+impl ReceiveData {
+    #[must_use]
+    pub fn new(amount: usize) -> Self {
+        Self { remaining: amount }
+    }
+}
+
+impl ConnectionGoal for ReceiveData {
+    fn handle_event(
+        &mut self,
+        c: &mut Connection,
+        e: &ConnectionEvent,
+        _now: Instant,
+    ) -> GoalStatus {
+        if let ConnectionEvent::RecvStreamReadable { stream_id } = e {
+            let mut buf = [0; 4096];
+            loop {
+                let (received, _) = c.stream_recv(*stream_id, &mut buf).unwrap();
+                if received == 0 {
+                    break;
+                }
+                assert!(received <= self.remaining, "received too much data");
+                self.remaining -= received;
+            }
+        }
+        if self.remaining == 0 {
+            GoalStatus::Done
+        } else {
+            GoalStatus::Waiting
+        }
+    }
+}
@@ -0,0 +1,68 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{Node, Random};
+use neqo_common::Datagram;
+use neqo_transport::Output;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Delay each datagram by an amount picked from a range.  When the range isn't
+/// empty, datagrams can overtake each other, so this also reorders.
+#[derive(Debug)]
+pub struct Delay {
+    range: Range<Duration>,
+    rng: Random,
+    queue: BTreeMap<Instant, Datagram>,
+}
+
+impl Delay {
+    #[must_use]
+    pub fn new(range: Range<Duration>) -> Self {
+        Self {
+            range,
+            rng: Random::new(0),
+            queue: BTreeMap::default(),
+        }
+    }
+
+    /// A fixed delay, which won't reorder datagrams.
+    #[must_use]
+    pub fn fixed(delay: Duration) -> Self {
+        Self::new(delay..delay)
+    }
+
+    fn insert(&mut self, d: Datagram, now: Instant) {
+        let mut t = now + self.rng.duration(&self.range);
+        // Nudge the time so that datagrams with the same delay are all kept.
+        while self.queue.contains_key(&t) {
+            t += Duration::from_nanos(1);
+        }
+        self.queue.insert(t, d);
+    }
+}
+
+impl Node for Delay {
+    fn init(&mut self, rng: Random, _now: Instant) {
+        self.rng = rng;
+    }
+
+    fn process(&mut self, d: Option<Datagram>, now: Instant) -> Output {
+        if let Some(d) = d {
+            self.insert(d, now);
+        }
+        if let Some(&t) = self.queue.keys().next() {
+            if t <= now {
+                Output::Datagram(self.queue.remove(&t).unwrap())
+            } else {
+                Output::Callback(t - now)
+            }
+        } else {
+            Output::None
+        }
+    }
+}
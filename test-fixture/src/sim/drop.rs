@@ -0,0 +1,57 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::{Node, Random};
+use neqo_common::{qtrace, Datagram};
+use neqo_transport::Output;
+use std::time::Instant;
+
+/// Drop datagrams at random.
+#[derive(Debug)]
+pub struct Drop {
+    /// The chance of dropping a datagram, as a percentage.
+    percentage: u8,
+    rng: Random,
+    dropped: usize,
+}
+
+impl Drop {
+    /// # Panics
+    /// If `percentage` is more than 100.
+    #[must_use]
+    pub fn percentage(percentage: u8) -> Self {
+        assert!(percentage <= 100);
+        Self {
+            percentage,
+            rng: Random::new(0),
+            dropped: 0,
+        }
+    }
+
+    /// The number of datagrams that have been dropped.
+    #[must_use]
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl Node for Drop {
+    fn init(&mut self, rng: Random, _now: Instant) {
+        self.rng = rng;
+    }
+
+    fn process(&mut self, d: Option<Datagram>, _now: Instant) -> Output {
+        match d {
+            Some(d) if self.rng.below(100) < u64::from(self.percentage) => {
+                qtrace!("drop {} byte datagram", d.len());
+                self.dropped += 1;
+                Output::None
+            }
+            Some(d) => Output::Datagram(d),
+            None => Output::None,
+        }
+    }
+}
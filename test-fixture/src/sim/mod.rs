@@ -0,0 +1,185 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![allow(clippy::module_name_repetitions)]
+
+// A deterministic network simulator.  A `Simulator` runs a number of nodes in
+// a loop: datagrams from each node are passed to the next, and the datagrams
+// from the last node go back to the first.  A simple simulation has a client,
+// some network elements for the path to the server, the server, and then
+// network elements for the path back.  Time is virtual, so simulations run as
+// fast as the nodes allow, and a seeded generator makes them repeatable.
+
+mod connection;
+mod delay;
+mod drop;
+mod rng;
+mod taildrop;
+
+pub use self::connection::{
+    ConnectionGoal, ConnectionNode, GoalStatus, ReachState, ReceiveData, SendData,
+};
+pub use self::delay::Delay;
+pub use self::drop::Drop;
+pub use self::rng::Random;
+pub use self::taildrop::TailDrop;
+
+use neqo_common::{qdebug, qinfo, qtrace, Datagram};
+use neqo_transport::Output;
+use std::env;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+/// The environment variable that overrides the seed of all simulations.
+const SEED_ENV: &str = "SIMULATION_SEED";
+const DEFAULT_SEED: u64 = 0x5eed;
+
+/// Stop a simulation that runs for longer than this.  Idle timeouts ensure that
+/// connections don't wait forever, so this is only hit if something loops.
+const MAX_ITERATIONS: usize = 1_000_000;
+
+/// A `Node` is part of a simulation: a connection or a network element.
+pub trait Node: Debug {
+    /// Called once before the simulation starts.  Nodes that make random
+    /// choices need to use `rng` for those.
+    fn init(&mut self, _rng: Random, _now: Instant) {}
+    /// Handle a datagram, if there is one, and produce output.  `Output::Callback`
+    /// means that the node wants to run again at that time, and `Output::None`
+    /// that the node only needs to run again when there is a datagram for it.
+    fn process(&mut self, d: Option<Datagram>, now: Instant) -> Output;
+    /// Whether the node is done.  A simulation ends when all nodes are done.
+    fn done(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NodeState {
+    /// The node might have more output.
+    Active,
+    /// The node has something to do at the given time.
+    Waiting(Instant),
+    /// The node is waiting for a datagram.
+    Idle,
+}
+
+#[derive(Debug)]
+struct NodeHolder {
+    node: Box<dyn Node>,
+    state: NodeState,
+}
+
+impl NodeHolder {
+    fn ready(&self, now: Instant) -> bool {
+        match self.state {
+            NodeState::Active => true,
+            NodeState::Waiting(t) => t <= now,
+            NodeState::Idle => false,
+        }
+    }
+}
+
+/// Make a vector of boxed nodes, for `Simulator::new()`.
+#[macro_export]
+macro_rules! boxed {
+    [$($v:expr),+ $(,)?] => {
+        vec![ $( Box::new($v) as _ ),+ ]
+    };
+}
+
+#[derive(Debug)]
+pub struct Simulator {
+    name: String,
+    nodes: Vec<NodeHolder>,
+    rng: Random,
+}
+
+impl Simulator {
+    /// Create a simulation.  The seed comes from the `SIMULATION_SEED`
+    /// environment variable, if it is set.
+    ///
+    /// # Panics
+    /// If `SIMULATION_SEED` isn't a number.
+    #[must_use]
+    pub fn new(name: impl AsRef<str>, nodes: impl IntoIterator<Item = Box<dyn Node>>) -> Self {
+        let seed = env::var(SEED_ENV).map_or(DEFAULT_SEED, |s| {
+            s.parse().expect("SIMULATION_SEED needs to be a number")
+        });
+        Self {
+            name: String::from(name.as_ref()),
+            nodes: nodes
+                .into_iter()
+                .map(|node| NodeHolder {
+                    node,
+                    state: NodeState::Active,
+                })
+                .collect(),
+            rng: Random::new(seed),
+        }
+    }
+
+    /// Set the seed for the simulation.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Random::new(seed);
+    }
+
+    /// The earliest time that a waiting node needs to run.
+    fn next_time(&self) -> Option<Instant> {
+        self.nodes
+            .iter()
+            .filter_map(|n| match n.state {
+                NodeState::Waiting(t) => Some(t),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Run the simulation until all of the nodes are done.  This returns the
+    /// amount of simulated time that passed.
+    ///
+    /// # Panics
+    /// If the simulation stalls, with no node able to make progress.
+    pub fn run(mut self) -> Duration {
+        let start = crate::now();
+        let mut now = start;
+        for n in &mut self.nodes {
+            n.node.init(self.rng.fork(), now);
+        }
+
+        let mut dgram = None;
+        for _ in 0..MAX_ITERATIONS {
+            for n in &mut self.nodes {
+                if dgram.is_none() && !n.ready(now) {
+                    continue;
+                }
+                qtrace!([self.name], "processing {:?}: {:?}", n.node, dgram);
+                n.state = match n.node.process(dgram.take(), now) {
+                    Output::Datagram(d) => {
+                        dgram = Some(d);
+                        NodeState::Active
+                    }
+                    Output::Callback(delay) => NodeState::Waiting(now + delay),
+                    Output::None => NodeState::Idle,
+                };
+            }
+
+            if self.nodes.iter().all(|n| n.node.done()) {
+                let elapsed = now - start;
+                qinfo!([self.name], "done after {:?}", elapsed);
+                return elapsed;
+            }
+
+            if dgram.is_none() && self.nodes.iter().all(|n| !n.ready(now)) {
+                let next = self
+                    .next_time()
+                    .unwrap_or_else(|| panic!("{}: simulation stalled", self.name));
+                qdebug!([self.name], "advancing time by {:?}", next - now);
+                now = next;
+            }
+        }
+        panic!("{}: simulation didn't finish", self.name);
+    }
+}
@@ -0,0 +1,86 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::time::Duration;
+
+/// A small, seedable pseudo-random number generator (SplitMix64).  This is not
+/// suitable for anything other than simulations, where being able to repeat a
+/// run exactly is what matters.
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Generate a value in the range `[0, n)`.  The bias from taking the
+    /// remainder doesn't matter for the small values used here.
+    ///
+    /// # Panics
+    /// When `n` is zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        assert_ne!(n, 0);
+        self.random() % n
+    }
+
+    /// Pick a `Duration` from the range `[range.start, range.end)`.  An empty
+    /// range always produces `range.start`.
+    pub fn duration(&mut self, range: &Range<Duration>) -> Duration {
+        if range.end <= range.start {
+            return range.start;
+        }
+        let span = u64::try_from((range.end - range.start).as_nanos()).unwrap_or(u64::max_value());
+        range.start + Duration::from_nanos(self.below(span))
+    }
+
+    /// Make a new generator, seeded from this one, so that each node in a
+    /// simulation has its own independent sequence.
+    pub fn fork(&mut self) -> Self {
+        Self::new(self.random())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Random;
+    use std::time::Duration;
+
+    #[test]
+    fn repeatable() {
+        let mut a = Random::new(7);
+        let mut b = Random::new(7);
+        for _ in 0..10 {
+            assert_eq!(a.random(), b.random());
+        }
+        assert_ne!(Random::new(8).random(), Random::new(7).random());
+    }
+
+    #[test]
+    fn duration_in_range() {
+        let mut rng = Random::new(1);
+        let range = Duration::from_millis(10)..Duration::from_millis(20);
+        for _ in 0..100 {
+            let d = rng.duration(&range);
+            assert!(range.contains(&d));
+        }
+        let empty = Duration::from_millis(5)..Duration::from_millis(5);
+        assert_eq!(rng.duration(&empty), Duration::from_millis(5));
+    }
+}
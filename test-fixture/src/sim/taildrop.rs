@@ -0,0 +1,95 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::Node;
+use neqo_common::{qtrace, Datagram};
+use neqo_transport::Output;
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+/// A link with limited bandwidth and a fixed delay.  Datagrams wait in a
+/// queue of limited size before being sent; any that don't fit are dropped.
+#[derive(Debug)]
+pub struct TailDrop {
+    /// The rate of the link, in bytes per second.
+    rate: u64,
+    /// The number of bytes that can be queued.
+    capacity: usize,
+    /// The delay across the link, after a datagram has been sent.
+    delay: Duration,
+    /// The time at which the link will be free to send another datagram.
+    free: Option<Instant>,
+    /// Datagrams that are queued or on the link, in order, with the time that
+    /// they start being sent and the time they arrive.
+    queue: VecDeque<(Instant, Instant, Datagram)>,
+    dropped: usize,
+}
+
+impl TailDrop {
+    /// # Panics
+    /// If `rate` is zero.
+    #[must_use]
+    pub fn new(rate: u64, capacity: usize, delay: Duration) -> Self {
+        assert_ne!(rate, 0);
+        Self {
+            rate,
+            capacity,
+            delay,
+            free: None,
+            queue: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// The number of datagrams that have been dropped.
+    #[must_use]
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    fn send_time(&self, len: usize) -> Duration {
+        let nanos = u128::try_from(len).unwrap() * 1_000_000_000 / u128::from(self.rate);
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::max_value()))
+    }
+
+    /// The number of bytes that are waiting to be sent.
+    fn queued(&self, now: Instant) -> usize {
+        self.queue
+            .iter()
+            .filter(|(start, ..)| *start > now)
+            .map(|(.., d)| d.len())
+            .sum()
+    }
+
+    fn insert(&mut self, d: Datagram, now: Instant) {
+        if self.queued(now) + d.len() > self.capacity {
+            qtrace!("tail drop {} byte datagram", d.len());
+            self.dropped += 1;
+            return;
+        }
+        let start = self.free.map_or(now, |f| max(f, now));
+        let done = start + self.send_time(d.len());
+        self.free = Some(done);
+        self.queue.push_back((start, done + self.delay, d));
+    }
+}
+
+impl Node for TailDrop {
+    fn process(&mut self, d: Option<Datagram>, now: Instant) -> Output {
+        if let Some(d) = d {
+            self.insert(d, now);
+        }
+        match self.queue.front() {
+            Some((_, arrival, _)) if *arrival <= now => {
+                Output::Datagram(self.queue.pop_front().unwrap().2)
+            }
+            Some((_, arrival, _)) => Output::Callback(*arrival - now),
+            None => Output::None,
+        }
+    }
+}